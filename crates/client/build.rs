@@ -7,6 +7,9 @@ fn main() -> Result<()> {
 
     cbindgen::Builder::new()
         .with_crate(crate_dir)
+        // GdpName and the header live in the protocol crate
+        .with_parse_deps(true)
+        .with_parse_include(&["gdp-proto"])
         .with_language(cbindgen::Language::Cxx)
         .generate()?
         .write_to_file("gdp_client.hpp");
@@ -0,0 +1,50 @@
+// Prints how often each arm of a node's pipeline branches was taken, busiest first, to pick the
+// order to check them in for a production build. Run it once the node has carried a workload:
+//
+//     cargo run --example branch_report -- 7001
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use gdp_client::{ClientCommand, ClientCommands, ClientResponse, ClientResponses};
+
+fn main() -> Result<()> {
+    let control_port: u16 = std::env::args()
+        .nth(1)
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(|| anyhow!("usage: <control port>"))?;
+
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let command = ClientCommands {
+        messages: vec![ClientCommand::DumpBranches],
+    };
+    socket.send_to(
+        &bincode::serialize(&command)?,
+        (Ipv4Addr::LOCALHOST, control_port),
+    )?;
+    let mut buf = [0; 1 << 16];
+    let size = socket
+        .recv(&mut buf)
+        .context("no answer from the control port")?;
+    let ClientResponses { mut messages } = bincode::deserialize(&buf[..size])?;
+    let branches = match messages.pop() {
+        Some(ClientResponse::Branches { branches }) => branches,
+        Some(ClientResponse::Error { msg }) => bail!(msg.into_owned()),
+        _ => bail!("unexpected answer from the control port"),
+    };
+
+    for branch in branches {
+        let total = branch.arms.iter().map(|(_, hits)| hits).sum::<u64>().max(1);
+        println!("{} on {}:", branch.branch, branch.nic);
+        for (arm, hits) in &branch.arms {
+            let share = 100.0 * *hits as f64 / total as f64;
+            println!("  {:>16} {:>12} {:>6.2}%", arm, hits, share);
+        }
+        if let Some(order) = branch.adaptive_order {
+            println!("  ranked by traffic: {}", order.join(", "));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use gdp_client::{GdpName, NAME_LEN};
+
+// shared by the examples; not every example uses every helper
+#[allow(dead_code)]
+pub fn parse_name(hex: &str) -> Result<GdpName> {
+    ensure!(
+        hex.len() == 2 * NAME_LEN && hex.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        "GdpNames are {} hex characters",
+        2 * NAME_LEN
+    );
+    let mut name = [0u8; NAME_LEN];
+    for (i, byte) in name.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).context("invalid GdpName")?;
+    }
+    Ok(name)
+}
+
+#[allow(dead_code)]
+pub fn format_name(name: &GdpName) -> String {
+    name.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[allow(dead_code)]
+/// Reads `<sidecar ip> <local port>` followed by `extra.len()` more arguments
+pub fn parse_args(extra: &[&str]) -> Result<(Ipv4Addr, u16, Vec<String>)> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let usage = || {
+        anyhow!(
+            "usage: <sidecar ip> <local port> {}",
+            extra
+                .iter()
+                .map(|arg| format!("<{}>", arg))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    };
+    if args.len() != 2 + extra.len() {
+        return Err(usage());
+    }
+    let sidecar_ip = args[0].parse().map_err(|_| usage())?;
+    let port = args[1].parse().map_err(|_| usage())?;
+    Ok((sidecar_ip, port, args[2..].to_vec()))
+}
@@ -0,0 +1,52 @@
+// Sends a message straight to a switch, without a sidecar, and prints whatever comes back.
+// We register with the RIB as reached at our IP, so we listen on the RIB port like a switch:
+//
+//     cargo run --example direct_send -- 10.100.1.10 <switch GdpName> 10.100.1.20 <destination GdpName>
+//
+// The switch must use the example dTLS key and not require sessions.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use gdp_client::{ended, is_timeout, nacked, DirectClient, FinKind, RIB_PORT};
+
+mod common;
+
+fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let usage = || anyhow!("usage: <switch ip> <switch GdpName> <our ip> <destination GdpName>");
+    let (switch_ip, switch_name, our_ip, dest) = match &args[..] {
+        [switch_ip, switch_name, our_ip, dest] => (
+            switch_ip.parse::<Ipv4Addr>().map_err(|_| usage())?,
+            common::parse_name(switch_name)?,
+            our_ip.parse::<Ipv4Addr>().map_err(|_| usage())?,
+            common::parse_name(dest)?,
+        ),
+        _ => return Err(usage()),
+    };
+
+    let mut client = DirectClient::new(
+        SocketAddrV4::new(our_ip, RIB_PORT),
+        switch_ip,
+        switch_name,
+        // a throwaway identity; real programs keep their key
+        rand::random(),
+    )?;
+    println!("we are {}", common::format_name(&client.gdp_name()));
+    client.announce(our_ip)?;
+
+    client.send_packet(dest, b"hello from outside the sidecar")?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    match client.recv_from() {
+        Ok((src, payload)) => println!(
+            "{}: {}",
+            common::format_name(&src),
+            String::from_utf8_lossy(&payload)
+        ),
+        Err(err) if is_timeout(&err) => println!("no answer"),
+        Err(err) if nacked(&err).is_some() || ended(&err).is_some() => println!("{:#}", err),
+        Err(err) => return Err(err),
+    }
+    client.end_flow(dest, FinKind::Close)
+}
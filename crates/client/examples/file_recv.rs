@@ -0,0 +1,93 @@
+// Receives files sent by file_send and writes them to the current directory.
+//
+//     cargo run --example file_recv -- 172.18.0.255 27184
+
+use std::fs;
+
+use anyhow::{ensure, Result};
+use gdp_client::{ended, FinKind, GdpClient};
+
+mod common;
+
+/// Files announced as larger than this are refused, as their whole length is allocated up front
+const MAX_FILE_LEN: usize = 1 << 30;
+
+struct Transfer {
+    name: String,
+    contents: Vec<u8>,
+    received: usize,
+}
+
+fn offset_of(payload: &[u8]) -> Result<usize> {
+    ensure!(payload.len() >= 9, "truncated packet");
+    Ok(u64::from_be_bytes(payload[1..9].try_into()?) as usize)
+}
+
+fn main() -> Result<()> {
+    let (sidecar_ip, port, _) = common::parse_args(&[])?;
+    let mut client = GdpClient::new(sidecar_ip, port)?;
+    let mut transfer: Option<Transfer> = None;
+
+    loop {
+        let (src, payload) = match client.recv_from() {
+            Ok(packet) => packet,
+            Err(err) => match ended(&err) {
+                // a sender that gave up will not send the rest of its file
+                Some(end) if end.kind == FinKind::Reset => {
+                    if let Some(current) = transfer.take() {
+                        println!("{} was abandoned by its sender", current.name);
+                    }
+                    continue;
+                }
+                Some(_) => continue,
+                None => return Err(err),
+            },
+        };
+        match payload.first() {
+            Some(b'H') => {
+                let len = offset_of(&payload)?;
+                let name = String::from_utf8_lossy(&payload[9..]).into_owned();
+                if len > MAX_FILE_LEN {
+                    println!(
+                        "refusing {} ({} bytes) from {}: larger than {} bytes",
+                        name,
+                        len,
+                        common::format_name(&src),
+                        MAX_FILE_LEN
+                    );
+                    transfer = None;
+                    continue;
+                }
+                println!(
+                    "receiving {} ({} bytes) from {}",
+                    name,
+                    len,
+                    common::format_name(&src)
+                );
+                transfer = Some(Transfer {
+                    name,
+                    contents: vec![0; len],
+                    received: 0,
+                });
+            }
+            Some(b'C') => {
+                let offset = offset_of(&payload)?;
+                let data = &payload[9..];
+                if let Some(ref mut current) = transfer {
+                    ensure!(
+                        offset + data.len() <= current.contents.len(),
+                        "chunk out of range"
+                    );
+                    current.contents[offset..offset + data.len()].copy_from_slice(data);
+                    current.received += data.len();
+                    if current.received >= current.contents.len() {
+                        fs::write(&current.name, &current.contents)?;
+                        println!("wrote {}", current.name);
+                        transfer = None;
+                    }
+                }
+            }
+            _ => println!("ignoring unknown packet"),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Sends a file to file_recv in fixed-size chunks, each tagged with its offset so
+// that the receiver can write it in place regardless of arrival order.
+//
+//     cargo run --example file_send -- 172.18.0.255 27183 <receiver GdpName> <path>
+
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use gdp_client::{FinKind, GdpClient};
+
+mod common;
+
+const CHUNK_SIZE: usize = 1024;
+
+fn main() -> Result<()> {
+    let (sidecar_ip, port, args) = common::parse_args(&["receiver", "path"])?;
+    let receiver = common::parse_name(&args[0])?;
+    let path = Path::new(&args[1]);
+    let contents = fs::read(path).context("failed to read file")?;
+    let file_name = path
+        .file_name()
+        .context("path has no file name")?
+        .to_string_lossy();
+
+    let client = GdpClient::new(sidecar_ip, port)?;
+
+    // header: b'H', total length, then the file name
+    let mut header = vec![b'H'];
+    header.extend((contents.len() as u64).to_be_bytes());
+    header.extend(file_name.as_bytes());
+    client.send_packet(receiver, &header)?;
+
+    // chunks: b'C', offset, then data
+    for (i, chunk) in contents.chunks(CHUNK_SIZE).enumerate() {
+        let mut packet = vec![b'C'];
+        packet.extend(((i * CHUNK_SIZE) as u64).to_be_bytes());
+        packet.extend(chunk);
+        client.send_packet(receiver, &packet)?;
+        // these chunks are plain packets, outside the credit windows of put_paced (see
+        // stream.rs), so they are spaced out to spare the receiver's socket
+        sleep(Duration::from_micros(200));
+    }
+    // the switches on the way can forget the flow now, rather than when it times out
+    client.end_flow(receiver, FinKind::Close)?;
+    println!("sent {} ({} bytes)", file_name, contents.len());
+    Ok(())
+}
@@ -0,0 +1,31 @@
+// Logs every packet delivered to our GdpName, e.g. readings from temperature_sensor.
+//
+//     cargo run --example subscriber_logger -- 172.18.0.255 27184
+
+use anyhow::Result;
+use gdp_client::{ended, GdpClient};
+
+mod common;
+
+fn main() -> Result<()> {
+    let (sidecar_ip, port, _) = common::parse_args(&[])?;
+    let mut client = GdpClient::new(sidecar_ip, port)?;
+
+    loop {
+        let (src, payload) = match client.recv_from() {
+            Ok(packet) => packet,
+            Err(err) => match ended(&err) {
+                Some(end) => {
+                    println!("{}: (ended)", common::format_name(&end.src));
+                    continue;
+                }
+                None => return Err(err),
+            },
+        };
+        println!(
+            "{}: {}",
+            common::format_name(&src),
+            String::from_utf8_lossy(&payload)
+        );
+    }
+}
@@ -0,0 +1,83 @@
+// Compares the routing tables of switches, to find where they disagree.
+//
+// On each switch, save its table from its control port:
+//
+//     cargo run --example table_audit -- export 7001 switch-a.table
+//
+// then, anywhere, print how the second table differs from the first:
+//
+//     cargo run --example table_audit -- diff switch-a.table switch-b.table
+
+use std::fs;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use gdp_client::{
+    diff_tables, ClientCommand, ClientCommands, ClientResponse, ClientResponses, TableExport,
+};
+
+mod common;
+
+fn export(control_port: u16, path: &str) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let command = ClientCommands {
+        messages: vec![ClientCommand::ExportTable],
+    };
+    socket.send_to(
+        &bincode::serialize(&command)?,
+        (Ipv4Addr::LOCALHOST, control_port),
+    )?;
+    let mut buf = [0; 1 << 16];
+    let size = socket
+        .recv(&mut buf)
+        .context("no answer from the control port")?;
+    let ClientResponses { mut messages } = bincode::deserialize(&buf[..size])?;
+    let export = match messages.pop() {
+        Some(ClientResponse::Table { export }) => export,
+        Some(ClientResponse::Error { msg }) => bail!(msg.into_owned()),
+        _ => bail!("unexpected answer from the control port"),
+    };
+    println!(
+        "{} routes, digest {}",
+        export.routes.len(),
+        common::format_name(&export.digest)
+    );
+    fs::write(path, bincode::serialize(&export)?)?;
+    Ok(())
+}
+
+fn load(path: &str) -> Result<TableExport> {
+    let export: TableExport = bincode::deserialize(&fs::read(path)?)?;
+    ensure!(
+        export.is_consistent(),
+        "{} does not match its digest; it was altered or cut short",
+        path
+    );
+    Ok(export)
+}
+
+fn diff(before: &str, after: &str) -> Result<()> {
+    let (before, after) = (load(before)?, load(after)?);
+    let diff = diff_tables(&before, &after);
+    if diff.is_empty() {
+        println!(
+            "the tables agree (digest {})",
+            common::format_name(&before.digest)
+        );
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let usage = || anyhow!("usage: export <control port> <file> | diff <file> <file>");
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["export", port, path] => export(port.parse().map_err(|_| usage())?, path),
+        ["diff", before, after] => diff(before, after),
+        _ => Err(usage()),
+    }
+}
@@ -0,0 +1,28 @@
+// Publishes a (simulated) temperature reading to a subscriber once per second.
+// The sidecar registers our GdpName with the RIB and resolves the subscriber's.
+//
+//     cargo run --example temperature_sensor -- 172.18.0.255 27183 <subscriber GdpName>
+
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use gdp_client::GdpClient;
+
+mod common;
+
+fn main() -> Result<()> {
+    let (sidecar_ip, port, args) = common::parse_args(&["subscriber"])?;
+    let subscriber = common::parse_name(&args[0])?;
+    let client = GdpClient::new(sidecar_ip, port)?;
+
+    for tick in 0u64.. {
+        let celsius = 20.0 + 2.0 * (tick as f64 / 30.0).sin();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let reading = format!("time={} celsius={:.2}", timestamp, celsius);
+        client.send_packet(subscriber, reading.as_bytes())?;
+        println!("published {}", reading);
+        sleep(Duration::from_secs(1));
+    }
+    Ok(())
+}
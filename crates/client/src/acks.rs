@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use gdp_proto::{content_hash, GdpName, NackReason};
+
+/*
+   Puts are delivered reliably: their destination answers each with an Ack, and the sender sends
+   it again until one arrives:
+   - a Put is numbered like any packet in its flow, and keeps its number when it is sent again,
+     so that the Ack names the Put it is for and the destination delivers it only once
+   - an Ack carries the content hash of its Put's data as its own data, which only those who saw
+     the Put know, as it is encrypted on every hop. An Ack that carries another is ignored, so
+     that no one can have a Put taken for delivered by guessing its number
+   - a Put is sent again after PUT_TIMEOUT without an Ack, PUT_ATTEMPTS times in all, and then
+     given up on. A NACK settles it at once, as a switch on the way found no way to deliver it
+   - sending again happens while the client receives (`recv_from`, `wait_for_put`); a client
+     that only sends calls `retransmit_puts` now and then
+   - the destination acknowledges every copy of a Put it receives, as the Ack for an earlier one
+     may be what was lost, but delivers only the first of the last RECENT_PUTS Puts it received
+   The status of a settled Put is reported once, and forgotten beyond the last MAX_SETTLED.
+*/
+
+/// How long to wait for the Ack of a Put before sending it again
+pub const PUT_TIMEOUT: Duration = Duration::from_millis(200);
+/// How many times a Put is sent at most before it is given up on
+const PUT_ATTEMPTS: u32 = 5;
+/// Settled Puts whose status is kept until the application asks for it
+const MAX_SETTLED: usize = 4096;
+/// Puts received whose copies are recognized as such
+const RECENT_PUTS: usize = 4096;
+
+/// A Put's destination and number in its flow
+type PutKey = (GdpName, u32);
+
+/// How a Put fared, as `GdpClient::put_status` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutStatus {
+    /// Not acknowledged yet, after this many sends
+    Pending {
+        attempts: u32,
+    },
+    Delivered,
+    /// A switch on the way NACKed it, for this reason if it gave one
+    Nacked(Option<NackReason>),
+    /// Not acknowledged after PUT_ATTEMPTS sends
+    TimedOut,
+}
+
+struct Outstanding {
+    payload: Box<[u8]>,
+    /// What the Ack must carry
+    proof: GdpName,
+    sent: Instant,
+    attempts: u32,
+}
+
+/// The Puts a client sent, until their status is reported
+#[derive(Default)]
+pub struct OutstandingPuts {
+    pending: HashMap<PutKey, Outstanding>,
+    settled: HashMap<PutKey, PutStatus>,
+    /// The keys of `settled`, oldest first
+    settled_order: VecDeque<PutKey>,
+}
+
+impl OutstandingPuts {
+    pub fn sent(&mut self, dst: GdpName, seqno: u32, payload: &[u8]) {
+        self.pending.insert(
+            (dst, seqno),
+            Outstanding {
+                payload: payload.into(),
+                proof: content_hash(payload),
+                sent: Instant::now(),
+                attempts: 1,
+            },
+        );
+    }
+
+    fn settle(&mut self, key: PutKey, status: PutStatus) -> bool {
+        if self.pending.remove(&key).is_none() {
+            return false;
+        }
+        if self.settled_order.len() == MAX_SETTLED {
+            if let Some(oldest) = self.settled_order.pop_front() {
+                self.settled.remove(&oldest);
+            }
+        }
+        self.settled.insert(key, status);
+        self.settled_order.push_back(key);
+        true
+    }
+
+    /// Record an Ack from `dst` that carries `proof`, returning whether it was for a Put still
+    /// waiting for one, and proved it received it
+    pub fn acked(&mut self, dst: GdpName, seqno: u32, proof: &[u8]) -> bool {
+        let proved = self
+            .pending
+            .get(&(dst, seqno))
+            .map_or(false, |put| put.proof[..] == *proof);
+        proved && self.settle((dst, seqno), PutStatus::Delivered)
+    }
+
+    /// Record a NACK of the packet numbered `seqno` to `dst`, returning whether it was a Put
+    /// still waiting for its Ack
+    pub fn nacked(&mut self, dst: GdpName, seqno: u32, reason: Option<NackReason>) -> bool {
+        self.settle((dst, seqno), PutStatus::Nacked(reason))
+    }
+
+    /// The Puts to send again, as their destination, number and payload. Those that were sent
+    /// PUT_ATTEMPTS times are given up on instead
+    pub fn due(&mut self) -> Vec<(GdpName, u32, Box<[u8]>)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut timed_out = Vec::new();
+        for (&(dst, seqno), put) in self.pending.iter_mut() {
+            if now.duration_since(put.sent) < PUT_TIMEOUT {
+                continue;
+            }
+            if put.attempts >= PUT_ATTEMPTS {
+                timed_out.push((dst, seqno));
+                continue;
+            }
+            put.attempts += 1;
+            put.sent = now;
+            due.push((dst, seqno, put.payload.clone()));
+        }
+        for key in timed_out {
+            self.settle(key, PutStatus::TimedOut);
+        }
+        due
+    }
+
+    /// The status of the Put numbered `seqno` to `dst`, or None if it is not known. A settled
+    /// Put is forgotten once its status is reported
+    pub fn status(&mut self, dst: GdpName, seqno: u32) -> Option<PutStatus> {
+        let key = (dst, seqno);
+        if let Some(put) = self.pending.get(&key) {
+            return Some(PutStatus::Pending {
+                attempts: put.attempts,
+            });
+        }
+        let status = self.settled.remove(&key)?;
+        self.settled_order.retain(|settled| *settled != key);
+        Some(status)
+    }
+}
+
+/// The Puts a client received lately, by their source and number
+#[derive(Default)]
+pub struct RecentPuts {
+    keys: HashSet<PutKey>,
+    /// The entries of `keys`, oldest first
+    order: VecDeque<PutKey>,
+}
+
+impl RecentPuts {
+    /// Record a Put from `src`, returning whether it is the first copy of it
+    pub fn first_copy(&mut self, src: GdpName, seqno: u32) -> bool {
+        let key = (src, seqno);
+        if !self.keys.insert(key) {
+            return false;
+        }
+        if self.order.len() == RECENT_PUTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DST: GdpName = [7; 32];
+
+    /// Have the Put numbered `seqno` look as if it was last sent a PUT_TIMEOUT ago
+    fn age(puts: &mut OutstandingPuts, seqno: u32) {
+        puts.pending.get_mut(&(DST, seqno)).unwrap().sent -= PUT_TIMEOUT;
+    }
+
+    #[test]
+    fn acks_settle_only_the_puts_they_prove() {
+        let mut puts = OutstandingPuts::default();
+        puts.sent(DST, 1, b"hello");
+        assert!(!puts.acked(DST, 1, &[]));
+        assert!(!puts.acked(DST, 1, &content_hash(b"other")));
+        assert!(!puts.acked([8; 32], 1, &content_hash(b"hello")));
+        assert_eq!(
+            puts.status(DST, 1),
+            Some(PutStatus::Pending { attempts: 1 })
+        );
+
+        assert!(puts.acked(DST, 1, &content_hash(b"hello")));
+        // a copy of the Ack finds nothing left to settle
+        assert!(!puts.acked(DST, 1, &content_hash(b"hello")));
+        assert_eq!(puts.status(DST, 1), Some(PutStatus::Delivered));
+        // reported once
+        assert_eq!(puts.status(DST, 1), None);
+    }
+
+    #[test]
+    fn nacks_settle_puts_at_once() {
+        let mut puts = OutstandingPuts::default();
+        puts.sent(DST, 1, b"hello");
+        assert!(puts.nacked(DST, 1, Some(NackReason::NoRoute)));
+        assert!(!puts.nacked(DST, 2, None));
+        assert_eq!(
+            puts.status(DST, 1),
+            Some(PutStatus::Nacked(Some(NackReason::NoRoute)))
+        );
+    }
+
+    #[test]
+    fn puts_are_sent_again_until_they_time_out() {
+        let mut puts = OutstandingPuts::default();
+        puts.sent(DST, 1, b"hello");
+        assert!(puts.due().is_empty());
+        for attempts in 2..=PUT_ATTEMPTS {
+            age(&mut puts, 1);
+            let due = puts.due();
+            assert_eq!(due.len(), 1);
+            assert_eq!((due[0].0, due[0].1, &due[0].2[..]), (DST, 1, &b"hello"[..]));
+            assert_eq!(puts.status(DST, 1), Some(PutStatus::Pending { attempts }));
+        }
+        age(&mut puts, 1);
+        assert!(puts.due().is_empty());
+        assert_eq!(puts.status(DST, 1), Some(PutStatus::TimedOut));
+    }
+
+    #[test]
+    fn forgets_the_oldest_settled_puts() {
+        let mut puts = OutstandingPuts::default();
+        for seqno in 0..=MAX_SETTLED as u32 {
+            puts.sent(DST, seqno, &[]);
+            assert!(puts.acked(DST, seqno, &content_hash(&[])));
+        }
+        assert_eq!(puts.status(DST, 0), None);
+        assert_eq!(puts.status(DST, 1), Some(PutStatus::Delivered));
+        assert_eq!(puts.settled.len(), puts.settled_order.len());
+    }
+
+    #[test]
+    fn recognizes_copies_of_the_latest_puts() {
+        let mut recent = RecentPuts::default();
+        assert!(recent.first_copy(DST, 1));
+        assert!(!recent.first_copy(DST, 1));
+        assert!(recent.first_copy([8; 32], 1));
+        for seqno in 2..=RECENT_PUTS as u32 {
+            assert!(recent.first_copy(DST, seqno));
+        }
+        // the oldest was forgotten to make room, and its copy taken for a new Put
+        assert!(recent.first_copy(DST, 1));
+        assert!(!recent.first_copy(DST, RECENT_PUTS as u32));
+    }
+}
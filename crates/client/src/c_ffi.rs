@@ -5,9 +5,9 @@ use std::ptr::slice_from_raw_parts;
 use std::str::FromStr;
 
 use anyhow::{Error, Result};
+use gdp_proto::GdpName;
 
 use crate::core::GdpClient;
-use crate::structs::GdpName;
 
 struct CGdpClient(GdpClient);
 
@@ -42,6 +42,16 @@ impl CGdpClient {
         }
     }
 
+    #[no_mangle]
+    pub unsafe extern "C" fn pin_name(&self, name: *const GdpName, pub_key: *const [u8; 32]) {
+        self.0.pin_name(*name, *pub_key)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn set_trust_on_first_use(&self, enabled: bool) {
+        self.0.set_trust_on_first_use(enabled)
+    }
+
     #[no_mangle]
     pub unsafe extern "C" fn recv_from(&mut self, src: *mut GdpName, buf: *mut *mut u8) -> i8 {
         self.0
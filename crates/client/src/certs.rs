@@ -0,0 +1,78 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use serde::Serialize;
+use signatory::ed25519::{Signature, SigningKey, VerifyingKey, ALGORITHM_ID};
+use signatory::pkcs8::{FromPrivateKey, PrivateKeyInfo};
+use signatory::signature::{Signer, Verifier};
+
+use crate::{CertContents, CertDest, Certificate, GdpMeta, RtCert, SerializableSignature};
+
+/*
+   The certificates that clients talking straight to a switch attach to their packets, and
+   register with the RIB, are gdp-proto's, as the switches' are. They are signed and checked here
+   as the router's certificates.rs does: over their bincode, with the Ed25519 key of their owner.
+*/
+
+/// How long the certificates we sign are valid for, as for those that sidecars sign
+const CERT_LIFETIME_SECS: u64 = 4 * 60 * 60;
+
+/// The signing key of `private_key`, read as the routers read theirs
+fn signing_key(private_key: [u8; 32]) -> Result<SigningKey> {
+    Ok(SigningKey::from_pkcs8_private_key_info(
+        PrivateKeyInfo::new(ALGORITHM_ID, &private_key),
+    )?)
+}
+
+/// The metadata of whoever holds `private_key`
+pub fn meta_of_key(private_key: [u8; 32]) -> Result<GdpMeta> {
+    Ok(GdpMeta {
+        pub_key: signing_key(private_key)?.verifying_key().to_bytes(),
+    })
+}
+
+/// Sign the bincode of `data` with `private_key`
+pub(crate) fn sign_data<T: Serialize>(
+    data: &T,
+    private_key: [u8; 32],
+) -> Result<SerializableSignature> {
+    let signature: [u8; 64] = signing_key(private_key)?
+        .sign(&bincode::serialize(data)?)
+        .to_bytes();
+    Ok(signature.into())
+}
+
+/// A route certificate from the holder of `private_key` to `proxy`, signed by it
+pub fn route_certificate(
+    private_key: [u8; 32],
+    proxy: CertDest,
+    bidirectional: bool,
+) -> Result<Certificate> {
+    let contents = CertContents::RtCert(RtCert {
+        base: meta_of_key(private_key)?.hash(),
+        proxy,
+        expiration_time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+            + CERT_LIFETIME_SECS,
+        bidirectional,
+    });
+    let signature = sign_data(&contents, private_key)?;
+    Ok(Certificate {
+        contents,
+        signature,
+    })
+}
+
+/// Check that `cert` is owned by `meta`'s name and signed with its key
+pub fn verify_certificate(cert: &Certificate, meta: &GdpMeta) -> Result<()> {
+    ensure!(
+        *cert.contents.owner() == meta.hash(),
+        "certificate of {:?} checked against the key of {:?}",
+        cert.contents.owner(),
+        meta.hash()
+    );
+    VerifyingKey::from_bytes(&meta.pub_key)?.verify(
+        &bincode::serialize(&cert.contents)?,
+        &Signature::new(cert.signature.into()),
+    )?;
+    Ok(())
+}
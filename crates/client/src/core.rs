@@ -1,12 +1,22 @@
-use std::mem::{size_of, transmute};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::mem::size_of;
 use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
 use std::ptr::slice_from_raw_parts;
+use std::time::Duration;
+use std::{fmt, thread};
 
 use anyhow::{bail, ensure, Context, Result};
 
+use crate::acks::{OutstandingPuts, PutStatus, RecentPuts, PUT_TIMEOUT};
+use crate::pinning::NamePins;
 use crate::{
-    ClientCommand, ClientCommands, ClientResponse, ClientResponses, GdpAction, GdpHeader, GdpName,
-    MAGIC_NUMBERS,
+    content_hash, new_trace_id, status_name, verify_content_hash, write_extensions,
+    AdmissionDecision, AdmissionRequest, ClientCommand, ClientCommands, ClientResponse,
+    ClientResponses, FinKind, FinPayload, FlowReport, FlowSequences, GdpAction, GdpHeader, GdpName,
+    HeaderExtension, NackPayload, NackReason, SequenceNumber, StatusObject, SwitchStatus,
+    MAGIC_NUMBERS, NAME_LEN,
 };
 
 // https://stackoverflow.com/questions/28127165/how-to-convert-struct-to-u8
@@ -14,10 +24,97 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     &*slice_from_raw_parts((p as *const T) as *const u8, size_of::<T>())
 }
 
+/// How long to wait for the sidecar to answer a name resolution
+const RESOLVE_TIMEOUT: Duration = Duration::from_millis(200);
+/// The sidecar fetches unknown bindings from the RIB in the background, so we ask a few times
+const RESOLVE_ATTEMPTS: usize = 5;
+/// How long to wait for our switch to answer an admission request
+const ADMISSION_TIMEOUT: Duration = Duration::from_millis(100);
+/// The switch answers Pending while it probes the path, which takes a few tens of milliseconds
+const ADMISSION_ATTEMPTS: usize = 10;
+/// How long to wait for a switch to answer a Get for its status
+const STATUS_TIMEOUT: Duration = Duration::from_millis(200);
+/// A Get and its answer are not retransmitted on the way, so we ask a few times
+const STATUS_ATTEMPTS: usize = 3;
+
+/// Whether a receive failed only because the read timeout elapsed
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(ErrorKind::WouldBlock | ErrorKind::TimedOut)
+    )
+}
+
+/// A packet we sent that a switch NACKed back, as `recv_from` fails with
+#[derive(Debug)]
+pub struct Nacked {
+    pub dst: GdpName,
+    pub trace_id: u64,
+    /// Why the switch NACKed it, or None for a switch older than NACK reasons
+    pub reason: Option<NackReason>,
+}
+
+impl fmt::Display for Nacked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "packet {:016x} to {:02x?} was NACKed ({:?})",
+            self.trace_id,
+            &self.dst[..4],
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for Nacked {}
+
+/// The NACK that a receive failed with, if it failed with one
+pub fn nacked(err: &anyhow::Error) -> Option<&Nacked> {
+    err.downcast_ref::<Nacked>()
+}
+
+/// A peer ending its flow to us, as `recv_from` fails with. Whatever was kept for the peer can
+/// be dropped; it starts a new flow if it sends again
+#[derive(Debug)]
+pub struct Ended {
+    pub src: GdpName,
+    pub kind: FinKind,
+}
+
+impl fmt::Display for Ended {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x?} ended its flow ({:?})",
+            &self.src[..4],
+            self.kind
+        )
+    }
+}
+
+impl std::error::Error for Ended {}
+
+/// The end of a flow that a receive failed with, if it failed with one
+pub fn ended(err: &anyhow::Error) -> Option<&Ended> {
+    err.downcast_ref::<Ended>()
+}
+
 pub struct GdpClient {
     socket: UdpSocket,
     sidecar_addr: SocketAddr,
     port: u16,
+    read_timeout: Cell<Option<Duration>>,
+    pins: RefCell<NamePins>,
+    /// Data packets that arrived while we were waiting for a control response or admission decision
+    backlog: RefCell<VecDeque<(GdpHeader, Box<[u8]>)>>,
+    /// Admission decisions by the trace ID of the request they answer
+    decisions: RefCell<HashMap<u64, AdmissionDecision>>,
+    /// Numbers the packets we send to each destination
+    sequences: RefCell<FlowSequences>,
+    /// The Puts we sent, until their status is reported (see acks.rs)
+    puts: RefCell<OutstandingPuts>,
+    /// The Puts we received, to deliver each only once
+    received_puts: RefCell<RecentPuts>,
 }
 
 impl GdpClient {
@@ -31,6 +128,13 @@ impl GdpClient {
             socket,
             port: 0,
             sidecar_addr: SocketAddr::new(sidecar_ip.into(), 25000),
+            read_timeout: Cell::new(None),
+            pins: Default::default(),
+            backlog: Default::default(),
+            decisions: Default::default(),
+            sequences: Default::default(),
+            puts: Default::default(),
+            received_puts: Default::default(),
         };
         client.listen_on_port(recv_port)?;
         let payload = loop {
@@ -47,47 +151,406 @@ impl GdpClient {
         Ok(client)
     }
 
+    /// Only send to `name` while the RIB binds it to `pub_key`
+    pub fn pin_name(&self, name: GdpName, pub_key: [u8; 32]) {
+        self.pins.borrow_mut().pin(name, pub_key);
+    }
+
+    /// Pin every destination to the key it is bound to the first time we send to it
+    pub fn set_trust_on_first_use(&self, enabled: bool) {
+        self.pins.borrow_mut().set_trust_on_first_use(enabled);
+    }
+
+    /// Sends refused so far because the RIB bound a pinned name to the wrong key
+    pub fn pin_violations(&self) -> u64 {
+        self.pins.borrow().violations()
+    }
+
     pub fn send_packet(&self, dest: GdpName, payload: &[u8]) -> Result<()> {
+        self.send_forward(dest, &[], payload)?;
+        Ok(())
+    }
+
+    /// Send `payload` to `dest` until it acknowledges it, returning the number to ask
+    /// `put_status` about. The Put is sent again while we receive (see acks.rs)
+    pub fn put(&self, dest: GdpName, payload: &[u8]) -> Result<u32> {
+        if self.pins.borrow().needs_check(&dest) {
+            self.check_pin(dest)?;
+        }
+        let seqno = self.sequences.borrow_mut().next(dest);
+        self.send_numbered(GdpAction::Put, dest, &[], payload, seqno)?;
+        self.puts.borrow_mut().sent(dest, seqno, payload);
+        Ok(seqno)
+    }
+
+    /// How the Put numbered `seqno` to `dest` fared so far, or None if it is not known
+    pub fn put_status(&self, dest: GdpName, seqno: u32) -> Option<PutStatus> {
+        self.puts.borrow_mut().status(dest, seqno)
+    }
+
+    /// Send again the Puts that were not acknowledged in time
+    pub fn retransmit_puts(&self) -> Result<()> {
+        let due = self.puts.borrow_mut().due();
+        for (dest, seqno, payload) in due {
+            self.send_numbered(GdpAction::Put, dest, &[], &payload, seqno)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the Put numbered `seqno` to `dest` is delivered or given up on, sending it
+    /// again as needed. Data that arrives in the meantime is kept for `recv_from`
+    pub fn wait_for_put(&self, dest: GdpName, seqno: u32) -> Result<PutStatus> {
+        self.socket.set_read_timeout(Some(PUT_TIMEOUT))?;
+        let status = self.recv_put_status(dest, seqno);
+        self.socket.set_read_timeout(self.read_timeout.get())?;
+        status
+    }
+
+    /// Tell `dest`, and the switches on the way, that we are done with our flow to it. A Close
+    /// still lets what we already sent arrive; a Reset has the switches drop what they hold of it
+    pub fn end_flow(&self, dest: GdpName, kind: FinKind) -> Result<()> {
+        self.send_gdp(GdpAction::Fin, dest, &[], &FinPayload { kind }.to_bytes())?;
+        Ok(())
+    }
+
+    /// Ask our switch whether the path to `dest` can carry a flow at the requested rate.
+    /// Send at no more than the rate of an Admit or Downgrade, and not at all after a Reject.
+    pub fn request_admission(
+        &self,
+        dest: GdpName,
+        request: AdmissionRequest,
+    ) -> Result<AdmissionDecision> {
+        for _ in 0..ADMISSION_ATTEMPTS {
+            // answers to earlier attempts are superseded by the one to this attempt
+            self.decisions.borrow_mut().clear();
+            let trace_id = self.send_forward(dest, &[request.to_extension()], &[])?;
+            match self.wait_for_decision(trace_id)? {
+                Some(AdmissionDecision::Pending) | None => thread::sleep(ADMISSION_TIMEOUT),
+                Some(decision) => return Ok(decision),
+            }
+        }
+        bail!(
+            "the switch did not decide on admitting a flow to {:02x?}",
+            &dest[..4]
+        )
+    }
+
+    /// Ask the switch named `switch` for one of its status objects. Only the switch our sidecar
+    /// sends to answers: status names are not in the RIB
+    pub fn get_status(&self, switch: GdpName, object: StatusObject) -> Result<SwitchStatus> {
+        let name = status_name(&switch, object);
+        for _ in 0..STATUS_ATTEMPTS {
+            self.send_gdp(GdpAction::Get, name, &[], &[])?;
+            if let Some(status) = self.wait_for_status(name)? {
+                return Ok(status);
+            }
+        }
+        bail!(
+            "the switch {:02x?} did not answer for its {:?} status",
+            &switch[..4],
+            object
+        )
+    }
+
+    /// Tell our sidecar how a flow is faring, for it to export with its statistics
+    pub fn report_flow(&self, report: FlowReport) -> Result<()> {
+        self.send_commands(vec![ClientCommand::ReportFlow { report }])
+    }
+
+    /// Tell our sidecar how a paced Put is faring, and how often it stalled on the receiver's pace
+    pub fn report_paced_flow(&self, report: FlowReport, paced_stalls: u64) -> Result<()> {
+        self.send_commands(vec![ClientCommand::ReportPacedFlow {
+            report,
+            paced_stalls,
+        }])
+    }
+
+    /// Returns the trace ID the packet was sent with
+    fn send_forward(
+        &self,
+        dest: GdpName,
+        extensions: &[HeaderExtension],
+        payload: &[u8],
+    ) -> Result<u64> {
+        self.send_gdp(GdpAction::Forward, dest, extensions, payload)
+    }
+
+    fn send_gdp(
+        &self,
+        action: GdpAction,
+        dest: GdpName,
+        extensions: &[HeaderExtension],
+        payload: &[u8],
+    ) -> Result<u64> {
+        if self.pins.borrow().needs_check(&dest) {
+            self.check_pin(dest)?;
+        }
+        let seqno = self.sequences.borrow_mut().next(dest);
+        self.send_numbered(action, dest, extensions, payload, seqno)
+    }
+
+    /// Sends as the packet numbered `seqno` in our flow to `dest`, which a Put keeps when it is
+    /// sent again and an Ack takes from its Put
+    fn send_numbered(
+        &self,
+        action: GdpAction,
+        dest: GdpName,
+        extensions: &[HeaderExtension],
+        payload: &[u8],
+        seqno: u32,
+    ) -> Result<u64> {
+        let mut extensions = extensions.to_vec();
+        extensions.push(SequenceNumber(seqno).to_extension());
+        let extensions = write_extensions(&extensions)?;
+        let trace_id = new_trace_id();
         let header = GdpHeader {
             field: MAGIC_NUMBERS.into(),
+            header_len: (GdpHeader::LEN + extensions.len() as u16).into(),
             ttl: 64,
-            action: GdpAction::Forward as u8,
-            src: [0; 32],
+            action: action as u8,
+            src: [0; NAME_LEN],
             dst: dest,
-            last_hop: [0; 32],
+            last_hop: [0; NAME_LEN],
+            content_hash: content_hash(payload),
             data_len: (payload.len() as u16).into(),
+            telemetry_len: 0.into(),
+            trace_id: trace_id.into(),
         };
 
-        self.send_header_and_data(&header, payload)
+        self.send_header_and_data(&header, &[&extensions[..], payload].concat())?;
+        Ok(trace_id)
+    }
+
+    /// How long `recv_from` waits for a packet before failing (see `is_timeout`), or forever if None
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(timeout)?;
+        self.read_timeout.set(timeout);
+        Ok(())
     }
 
     pub fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)> {
         loop {
-            let (header, payload) = self.recv_with_header()?;
+            self.retransmit_puts()?;
+            let backlogged = self.backlog.borrow_mut().pop_front();
+            let (header, payload) = match backlogged {
+                Some(packet) => packet,
+                None => self.recv_with_header()?,
+            };
             match GdpAction::try_from(header.action)? {
                 GdpAction::Control => self.process_control_payload(&payload)?,
-                GdpAction::Forward => return Ok((header.src, payload)),
+                GdpAction::Forward | GdpAction::Put => {
+                    let data_len = (u16::from(header.data_len) as usize).min(payload.len());
+                    verify_content_hash(&header.content_hash, &payload[..data_len])?;
+                    return Ok((header.src, payload));
+                }
+                GdpAction::Nack => {
+                    let data_len = (u16::from(header.data_len) as usize).min(payload.len());
+                    verify_content_hash(&header.content_hash, &payload[..data_len])?;
+                    return Err(Nacked {
+                        dst: header.dst,
+                        trace_id: u64::from(header.trace_id),
+                        reason: NackPayload::parse(&payload[..data_len]).map(|nack| nack.reason),
+                    }
+                    .into());
+                }
+                GdpAction::Fin => {
+                    let data_len = (u16::from(header.data_len) as usize).min(payload.len());
+                    verify_content_hash(&header.content_hash, &payload[..data_len])?;
+                    return Err(Ended {
+                        src: header.src,
+                        kind: FinPayload::parse(&payload[..data_len]).kind,
+                    }
+                    .into());
+                }
+                // answers that came after `get_status` gave up on them
+                GdpAction::GetReply => {}
                 action => bail!("unexpected packet action type: {:?}", action),
             };
         }
     }
 
+    fn check_pin(&self, name: GdpName) -> Result<()> {
+        for _ in 0..RESOLVE_ATTEMPTS {
+            self.send_commands(vec![ClientCommand::ResolveName { name }])?;
+            if let Some(pub_key) = self.wait_for_binding(name)? {
+                let checked = self.pins.borrow_mut().check(name, pub_key);
+                if let Err(err) = &checked {
+                    eprintln!("ALERT: {:#}", err);
+                }
+                return checked;
+            }
+            thread::sleep(RESOLVE_TIMEOUT);
+        }
+        bail!(
+            "refusing to send: no binding is known for pinned name {:02x?}",
+            &name[..4]
+        )
+    }
+
+    /// The key the sidecar reports for `name`, or None if it doesn't know it (or didn't answer in time)
+    fn wait_for_binding(&self, name: GdpName) -> Result<Option<[u8; 32]>> {
+        self.socket.set_read_timeout(Some(RESOLVE_TIMEOUT))?;
+        let binding = self.recv_binding(name);
+        self.socket.set_read_timeout(self.read_timeout.get())?;
+        match binding {
+            Err(err) if is_timeout(&err) => Ok(None),
+            binding => binding,
+        }
+    }
+
+    fn recv_binding(&self, name: GdpName) -> Result<Option<[u8; 32]>> {
+        loop {
+            let (header, payload) = self.recv_with_header()?;
+            if header.action != GdpAction::Control as u8 {
+                self.backlog.borrow_mut().push_back((header, payload));
+                continue;
+            }
+            let ClientResponses { messages } = bincode::deserialize(&*payload)?;
+            for msg in messages {
+                match msg {
+                    ClientResponse::Binding {
+                        name: bound,
+                        pub_key,
+                    } if bound == name => return Ok(pub_key),
+                    ClientResponse::Error { msg } => bail!(msg.into_owned()),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// The switch's answer to the admission request sent with `trace_id`, or None if it didn't
+    /// answer in time
+    fn wait_for_decision(&self, trace_id: u64) -> Result<Option<AdmissionDecision>> {
+        self.socket.set_read_timeout(Some(ADMISSION_TIMEOUT))?;
+        let decision = self.recv_decision(trace_id);
+        self.socket.set_read_timeout(self.read_timeout.get())?;
+        match decision {
+            Err(err) if is_timeout(&err) => Ok(None),
+            decision => decision.map(Some),
+        }
+    }
+
+    fn recv_decision(&self, trace_id: u64) -> Result<AdmissionDecision> {
+        loop {
+            if let Some(decision) = self.decisions.borrow_mut().remove(&trace_id) {
+                return Ok(decision);
+            }
+            if let Some(packet) = self.recv_packet()? {
+                self.backlog.borrow_mut().push_back(packet);
+            }
+        }
+    }
+
+    /// The status object that the switch answered for `name`, or None if it didn't answer in time
+    fn wait_for_status(&self, name: GdpName) -> Result<Option<SwitchStatus>> {
+        self.socket.set_read_timeout(Some(STATUS_TIMEOUT))?;
+        let status = self.recv_status(name);
+        self.socket.set_read_timeout(self.read_timeout.get())?;
+        match status {
+            Err(err) if is_timeout(&err) => Ok(None),
+            status => status.map(Some),
+        }
+    }
+
+    fn recv_status(&self, name: GdpName) -> Result<SwitchStatus> {
+        loop {
+            let (header, payload) = self.recv_with_header()?;
+            if header.action != GdpAction::GetReply as u8 || header.src != name {
+                self.backlog.borrow_mut().push_back((header, payload));
+                continue;
+            }
+            let data_len = (u16::from(header.data_len) as usize).min(payload.len());
+            verify_content_hash(&header.content_hash, &payload[..data_len])?;
+            return Ok(bincode::deserialize(&payload[..data_len])?);
+        }
+    }
+
+    fn recv_put_status(&self, dest: GdpName, seqno: u32) -> Result<PutStatus> {
+        loop {
+            self.retransmit_puts()?;
+            let status = self.puts.borrow_mut().status(dest, seqno);
+            match status {
+                Some(PutStatus::Pending { .. }) => {}
+                Some(status) => return Ok(status),
+                None => bail!("no Put numbered {} to {:02x?} is known", seqno, &dest[..4]),
+            }
+            match self.recv_packet() {
+                Ok(Some(packet)) => self.backlog.borrow_mut().push_back(packet),
+                Ok(None) => {}
+                Err(err) if is_timeout(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     fn recv_with_header(&self) -> Result<(GdpHeader, Box<[u8]>)> {
-        let mut buf = [0u8; 1 << 16];
         loop {
-            let (size, _) = self.socket.recv_from(&mut buf)?;
-            ensure!(size > 0, "socket closed unexpectedly");
-            // looks like a GDP packet?
-            if u16::from_be_bytes([buf[0], buf[1]]) != MAGIC_NUMBERS
-                || size < size_of::<GdpHeader>()
+            if let Some(packet) = self.recv_packet()? {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// The next packet, or None if it was set aside (an admission decision), taken care of (an
+    /// Ack, or a copy of a Put already received) or not a GDP packet
+    fn recv_packet(&self) -> Result<Option<(GdpHeader, Box<[u8]>)>> {
+        let mut buf = [0u8; 1 << 16];
+        let (size, _) = self.socket.recv_from(&mut buf)?;
+        ensure!(size > 0, "socket closed unexpectedly");
+        // ignore anything that doesn't look like a GDP packet
+        let (header, payload) = match GdpHeader::parse(&buf[..size]) {
+            Ok(parsed) => parsed,
+            Err(_) => return Ok(None),
+        };
+        let extensions = GdpHeader::parse_extensions(&buf[..size]).unwrap_or_default();
+        if let Some(decision) = AdmissionDecision::find(&extensions).ok().flatten() {
+            self.decisions
+                .borrow_mut()
+                .insert(u64::from(header.trace_id), decision);
+            return Ok(None);
+        }
+        // zero if the packet is not numbered, as no Put is
+        let seqno = SequenceNumber::find(&extensions)
+            .ok()
+            .flatten()
+            .map_or(0, |number| number.0);
+        if !self.settle_puts(&header, seqno, payload)? {
+            return Ok(None);
+        }
+        Ok(Some((header, payload.to_vec().into_boxed_slice())))
+    }
+
+    /// Acknowledge a Put, and record the Acks and NACKs of ours, returning whether the packet is
+    /// still for the application
+    fn settle_puts(&self, header: &GdpHeader, seqno: u32, payload: &[u8]) -> Result<bool> {
+        let data_len = (u16::from(header.data_len) as usize).min(payload.len());
+        match GdpAction::try_from(header.action) {
+            Ok(GdpAction::Ack) => {
+                self.puts
+                    .borrow_mut()
+                    .acked(header.src, seqno, &payload[..data_len]);
+                Ok(false)
+            }
+            Ok(GdpAction::Nack) => {
+                let reason = NackPayload::parse(&payload[..data_len]).map(|nack| nack.reason);
+                self.puts.borrow_mut().nacked(header.dst, seqno, reason);
+                Ok(true)
+            }
+            // a Put that fails its check is left for recv_from to fail with
+            Ok(GdpAction::Put)
+                if verify_content_hash(&header.content_hash, &payload[..data_len]).is_ok() =>
             {
-                continue;
+                // every copy, as the Ack of an earlier one may have been lost, proving that we
+                // received it with the hash of its data
+                let proof = content_hash(&payload[..data_len]);
+                self.send_numbered(GdpAction::Ack, header.src, &[], &proof, seqno)?;
+                Ok(self
+                    .received_puts
+                    .borrow_mut()
+                    .first_copy(header.src, seqno))
             }
-            let buf = &buf[..size];
-            let (header, payload) = buf.split_at(size_of::<GdpHeader>());
-            let header: [u8; size_of::<GdpHeader>()] = header.try_into().unwrap();
-            let header: GdpHeader = unsafe { transmute(header) };
-            return Ok((header, payload.to_vec().into_boxed_slice()));
+            _ => Ok(true),
         }
     }
 
@@ -103,16 +566,18 @@ impl GdpClient {
     }
 
     fn listen_on_port(&self, port: u16) -> Result<()> {
+        self.send_commands(vec![ClientCommand::SetPort { port }])
+    }
+
+    fn send_commands(&self, messages: Vec<ClientCommand>) -> Result<()> {
         let header = GdpHeader {
             field: MAGIC_NUMBERS.into(),
             action: GdpAction::Control as u8,
             ..Default::default()
         };
 
-        let data = bincode::serialize(&ClientCommands {
-            messages: vec![ClientCommand::SetPort { port }],
-        })
-        .context("failed to serialize commands for transmission")?;
+        let data = bincode::serialize(&ClientCommands { messages })
+            .context("failed to serialize commands for transmission")?;
 
         self.send_header_and_data(&header, &data)
     }
@@ -123,8 +588,57 @@ impl GdpClient {
             match msg {
                 ClientResponse::PortSet { port } => self.port = port,
                 ClientResponse::Error { msg } => bail!(msg.into_owned()),
+                // a late answer to a resolution we already gave up on
+                ClientResponse::Binding { .. } => {}
+                ClientResponse::FlowReported => {}
+                response => bail!("unexpected control response: {:?}", response),
             }
         }
         Ok(())
     }
 }
+
+/// What the streamed Get and the paced Put need of a client, so that they run over a sidecar or
+/// straight to a switch alike
+pub trait Endpoint {
+    fn send_packet(&self, dest: GdpName, payload: &[u8]) -> Result<()>;
+
+    fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+    /// The timeout that `set_read_timeout` last set
+    fn read_timeout(&self) -> Option<Duration>;
+
+    /// Export how a flow is faring, if the client has anywhere to export it to
+    fn report_flow(&self, report: FlowReport) -> Result<()>;
+
+    /// Export how a paced Put is faring, as `report_flow` does
+    fn report_paced_flow(&self, report: FlowReport, paced_stalls: u64) -> Result<()>;
+}
+
+impl Endpoint for GdpClient {
+    fn send_packet(&self, dest: GdpName, payload: &[u8]) -> Result<()> {
+        GdpClient::send_packet(self, dest, payload)
+    }
+
+    fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)> {
+        GdpClient::recv_from(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        GdpClient::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.get()
+    }
+
+    fn report_flow(&self, report: FlowReport) -> Result<()> {
+        GdpClient::report_flow(self, report)
+    }
+
+    fn report_paced_flow(&self, report: FlowReport, paced_stalls: u64) -> Result<()> {
+        GdpClient::report_paced_flow(self, report, paced_stalls)
+    }
+}
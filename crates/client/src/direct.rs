@@ -0,0 +1,603 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::ptr::slice_from_raw_parts;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::certs::{meta_of_key, route_certificate, sign_data, verify_certificate};
+use crate::core::{is_timeout, Ended, Endpoint, Nacked};
+use crate::{
+    content_hash, new_trace_id, verify_content_hash, write_extensions, CertDest, Certificate,
+    CertificateBlock, FinKind, FinPayload, FlowReport, FlowSequences, GdpAction, GdpHeader,
+    GdpMeta, GdpName, NackPayload, Registration, RegistrationContents, RegistrationPhase, RibQuery,
+    RibResponse, SequenceNumber, MAGIC_NUMBERS,
+};
+
+/*
+   Programs that cannot run behind a sidecar talk to a switch directly, over the dTLS-over-UDP
+   that switches use between themselves, from an ordinary UDP socket:
+   - packets go to the switch's RIB_PORT, encrypted under the static key: the `dtls_key` of the
+     switch's secrets.toml, or the example key if it has none. Switches whose sessions.toml
+     requires sessions refuse them. Nonces are a random prefix per client and a counter, as the
+     switches' are, so that the switch's replay windows accept them
+   - every packet carries a route certificate from the client to its switch, signed with the
+     client's key, as the sidecar attaches for the clients behind it
+   - `announce` registers the client with the RIB in a signed RibRegister, through the switch
+     (which learns the client's key on the way): where its packets go, and that it is reached
+     through the switch. Switches send to it on RIB_PORT, so a client that is to receive must be
+     bound to that port
+   - RIB queries go to the RIB itself, which answers the port they came from. A reply is only
+     taken if each of its certificates is signed by its owner
+   - packets under the static key are taken once: the client keeps a replay window per sender
+     id, as switches do
+   - Put and Get are the streamed ones of stream.rs, which run over a direct client as over a
+     sidecar's (without the flow reports, which only sidecars export)
+   - `send_crafted` and `recv_raw` leave the header to the caller, for tests of how switches
+     handle packets that ordinary clients never send. In plaintext mode, for switches that list
+     us as a plaintext_legacy peer and for nodes without the dTLS layer, GDP goes straight over
+     UDP
+*/
+
+pub const RIB_PORT: u16 = 31415;
+/// The key switches use when secrets.toml sets none
+pub const EXAMPLE_DTLS_KEY: [u8; 32] = *b"an example very very secret key.";
+/// Packets under the static key, rather than a session's
+const STATIC_SESSION: u32 = 0;
+/// The session and the nonce
+const DTLS_HEADER_LEN: usize = 4 + 12;
+/// Starts padded plaintexts; see the router's padding.rs
+const PADDING_MARKER: [u8; 2] = [0xa5, 0xa5];
+const PADDING_HEADER_LEN: usize = 4;
+/// How long to wait for the RIB to answer a query
+const RIB_TIMEOUT: Duration = Duration::from_secs(2);
+/// Our certificate to the switch is signed again once it has less than this left
+const CERT_RENEWAL_SECS: u64 = 60 * 60;
+/// How far behind the newest counter of its sender a packet may arrive, as switches allow
+const REPLAY_WINDOW: u64 = 128;
+/// The bit of a nonce's counter that classes the packet as control traffic, which is not part of
+/// the counter; see the router's dtls/replay.rs
+const CONTROL_CLASS: u64 = 1 << 63;
+
+// https://stackoverflow.com/questions/28127165/how-to-convert-struct-to-u8
+unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
+    &*slice_from_raw_parts((p as *const T) as *const u8, size_of::<T>())
+}
+
+/// Seen counters of one sender, as a bitmap of the REPLAY_WINDOW counters up to the newest
+#[derive(Default)]
+struct ReplayWindow {
+    newest: u64,
+    seen: u128,
+}
+
+impl ReplayWindow {
+    /// Record `counter`, false if it was seen before or is too old to tell
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter > self.newest {
+            let shift = counter - self.newest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.newest = counter;
+            return true;
+        }
+        let age = self.newest - counter;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+/// A client that sends to its switch itself, without a sidecar
+pub struct DirectClient {
+    socket: UdpSocket,
+    switch_addr: SocketAddr,
+    switch_name: GdpName,
+    private_key: [u8; 32],
+    meta: GdpMeta,
+    cipher: Aes256Gcm,
+    /// Send and receive GDP without the dTLS layer
+    plaintext: bool,
+    nonce_prefix: [u8; 4],
+    sent: Cell<u64>,
+    read_timeout: Cell<Option<Duration>>,
+    /// Our serialized certificate block to the switch, and when it expires
+    certificates: RefCell<(Vec<u8>, u64)>,
+    /// Data packets that arrived while we were waiting for the RIB
+    backlog: RefCell<VecDeque<(GdpHeader, Box<[u8]>)>>,
+    /// Numbers the packets we send to each destination
+    sequences: RefCell<FlowSequences>,
+    /// The counters seen from each sender id under the static key
+    replay_windows: RefCell<HashMap<[u8; 4], ReplayWindow>>,
+}
+
+impl DirectClient {
+    /// A client bound to `bind`, sending through the switch at `switch_ip` named `switch_name`,
+    /// as the holder of `private_key`
+    pub fn new(
+        bind: SocketAddrV4,
+        switch_ip: Ipv4Addr,
+        switch_name: GdpName,
+        private_key: [u8; 32],
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(bind).context("failed to bind socket")?;
+        let client = DirectClient {
+            socket,
+            switch_addr: SocketAddr::from((switch_ip, RIB_PORT)),
+            switch_name,
+            private_key,
+            meta: meta_of_key(private_key)?,
+            cipher: Aes256Gcm::new(Key::from_slice(&EXAMPLE_DTLS_KEY)),
+            plaintext: false,
+            nonce_prefix: rand::random(),
+            sent: Cell::new(0),
+            read_timeout: Cell::new(None),
+            certificates: RefCell::new((Vec::new(), 0)),
+            backlog: Default::default(),
+            sequences: Default::default(),
+            replay_windows: Default::default(),
+        };
+        client.renew_certificates()?;
+        Ok(client)
+    }
+
+    /// Encrypt under the switches' `dtls_key` rather than the example key
+    pub fn set_dtls_key(&mut self, key: [u8; 32]) {
+        self.cipher = Aes256Gcm::new(Key::from_slice(&key));
+    }
+
+    /// Send GDP straight over UDP, to a switch that takes us for a plaintext_legacy peer
+    pub fn set_plaintext(&mut self, plaintext: bool) {
+        self.plaintext = plaintext;
+    }
+
+    /// Our GdpName, the hash of our public key
+    pub fn gdp_name(&self) -> GdpName {
+        self.meta.hash()
+    }
+
+    /// Register us with the RIB, through our switch, as reached at `ip` (on RIB_PORT)
+    pub fn announce(&self, ip: Ipv4Addr) -> Result<()> {
+        let contents = RegistrationContents {
+            meta: self.meta,
+            certs: vec![
+                route_certificate(self.private_key, CertDest::GdpName(self.switch_name), true)?,
+                route_certificate(self.private_key, CertDest::IpAddr(ip), true)?,
+            ],
+            phase: RegistrationPhase::Active,
+            signed_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        let signature = sign_data(&contents, self.private_key)?;
+        let registration = Registration {
+            contents,
+            signature,
+        };
+        self.send_gdp(
+            GdpAction::RibRegister,
+            self.switch_name,
+            &bincode::serialize(&registration)?,
+            self.switch_addr,
+        )?;
+        Ok(())
+    }
+
+    /// Ask the RIB at `rib_ip` for what it knows of the names in `query`, and wait for its
+    /// answer. Fails if a certificate in the answer is not signed by its owner
+    pub fn query_rib(
+        &self,
+        rib_ip: Ipv4Addr,
+        rib_name: GdpName,
+        query: &RibQuery,
+    ) -> Result<RibResponse> {
+        let trace_id = self.send_gdp(
+            GdpAction::RibGet,
+            rib_name,
+            &bincode::serialize(query)?,
+            SocketAddr::from((rib_ip, RIB_PORT)),
+        )?;
+        let deadline = Instant::now() + RIB_TIMEOUT;
+        let reply = loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            ensure!(
+                !left.is_zero(),
+                "the RIB did not answer query {:016x}",
+                trace_id
+            );
+            self.socket.set_read_timeout(Some(left))?;
+            let received = self.recv_with_header();
+            self.socket.set_read_timeout(self.read_timeout.get())?;
+            let (header, payload) = match received {
+                Err(err) if is_timeout(&err) => continue,
+                received => received?,
+            };
+            if header.action == GdpAction::RibReply as u8 {
+                break data_of(&header, &payload)?.to_vec();
+            }
+            self.backlog.borrow_mut().push_back((header, payload));
+        };
+        let response = RibResponse::decode(&reply)?;
+        check_response(&response)?;
+        Ok(response)
+    }
+
+    /// The route certificates the RIB has for `name`: where it is, or whom it is reached through
+    pub fn resolve(
+        &self,
+        rib_ip: Ipv4Addr,
+        rib_name: GdpName,
+        name: GdpName,
+    ) -> Result<Vec<Certificate>> {
+        let query = RibQuery {
+            ips_for_names: vec![name],
+            next_hop_for_names: vec![name],
+            ..Default::default()
+        };
+        Ok(self.query_rib(rib_ip, rib_name, &query)?.certs)
+    }
+
+    pub fn send_packet(&self, dest: GdpName, payload: &[u8]) -> Result<()> {
+        self.send_gdp(GdpAction::Forward, dest, payload, self.switch_addr)?;
+        Ok(())
+    }
+
+    /// Tell `dest`, and the switches on the way, that we are done with our flow to it
+    pub fn end_flow(&self, dest: GdpName, kind: FinKind) -> Result<()> {
+        let payload = FinPayload { kind }.to_bytes();
+        self.send_gdp(GdpAction::Fin, dest, &payload, self.switch_addr)?;
+        Ok(())
+    }
+
+    /// How long `recv_from` waits for a packet before failing (see `is_timeout`), or forever if None
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(timeout)?;
+        self.read_timeout.set(timeout);
+        Ok(())
+    }
+
+    /// The next packet sent to us, as `GdpClient::recv_from` returns it
+    pub fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)> {
+        loop {
+            let (header, payload) = self.recv_raw()?;
+            let data = data_of(&header, &payload)?;
+            match GdpAction::try_from(header.action)? {
+                GdpAction::Forward => return Ok((header.src, data.into())),
+                GdpAction::Nack => {
+                    return Err(Nacked {
+                        dst: header.dst,
+                        trace_id: u64::from(header.trace_id),
+                        reason: NackPayload::parse(data).map(|nack| nack.reason),
+                    }
+                    .into())
+                }
+                GdpAction::Fin => {
+                    return Err(Ended {
+                        src: header.src,
+                        kind: FinPayload::parse(data).kind,
+                    }
+                    .into())
+                }
+                // late answers to queries we gave up on
+                GdpAction::RibReply => {}
+                action => bail!("unexpected packet action type: {:?}", action),
+            }
+        }
+    }
+
+    /// The next packet sent to us, whatever its action: its header, and everything after the
+    /// header (data, certificates and telemetry)
+    pub fn recv_raw(&self) -> Result<(GdpHeader, Box<[u8]>)> {
+        let backlogged = self.backlog.borrow_mut().pop_front();
+        match backlogged {
+            Some(packet) => Ok(packet),
+            None => self.recv_with_header(),
+        }
+    }
+
+    /// The header that we would send `data` to `dest` with, under a new trace ID
+    pub fn header(&self, action: GdpAction, dest: GdpName, data: &[u8]) -> Result<GdpHeader> {
+        Ok(GdpHeader {
+            field: MAGIC_NUMBERS.into(),
+            header_len: GdpHeader::LEN.into(),
+            ttl: 64,
+            action: action as u8,
+            src: self.gdp_name(),
+            dst: dest,
+            last_hop: self.gdp_name(),
+            content_hash: content_hash(data),
+            data_len: u16::try_from(data.len())
+                .context("payload too large for a packet")?
+                .into(),
+            telemetry_len: 0.into(),
+            trace_id: new_trace_id().into(),
+        })
+    }
+
+    /// Send `header`, then `extensions` and `data`, to the switch as they are. The header's
+    /// lengths and hash are not checked against them, so `header_len` must count the extensions
+    /// for a well-formed packet. Our certificate block follows if `attach_certificates`
+    pub fn send_crafted(
+        &self,
+        header: &GdpHeader,
+        extensions: &[u8],
+        data: &[u8],
+        attach_certificates: bool,
+    ) -> Result<()> {
+        self.renew_certificates()?;
+        let mut packet = unsafe { any_as_u8_slice(header) }.to_vec();
+        packet.extend(extensions);
+        packet.extend(data);
+        if attach_certificates {
+            packet.extend(&self.certificates.borrow().0);
+        }
+        self.send_datagram(&packet, self.switch_addr)
+    }
+
+    /// Sign our certificate to the switch again if it is close to expiring
+    fn renew_certificates(&self) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if self.certificates.borrow().1 > now + CERT_RENEWAL_SECS {
+            return Ok(());
+        }
+        let cert = route_certificate(self.private_key, CertDest::GdpName(self.switch_name), true)?;
+        let expiration_time = cert.contents.expiration_time();
+        let block = bincode::serialize(&CertificateBlock {
+            certificates: vec![cert],
+        })?;
+        *self.certificates.borrow_mut() = (block, expiration_time);
+        Ok(())
+    }
+
+    /// Returns the trace ID the packet was sent with
+    fn send_gdp(
+        &self,
+        action: GdpAction,
+        dest: GdpName,
+        data: &[u8],
+        to: SocketAddr,
+    ) -> Result<u64> {
+        self.renew_certificates()?;
+        let mut header = self.header(action, dest, data)?;
+        // numbered in the flow to `dest`, for switches to drop copies by (see the router's dedup.rs)
+        let extensions =
+            write_extensions(&[
+                SequenceNumber(self.sequences.borrow_mut().next(dest)).to_extension()
+            ])?;
+        header.header_len = (GdpHeader::LEN + extensions.len() as u16).into();
+        let mut packet = unsafe { any_as_u8_slice(&header) }.to_vec();
+        packet.extend(extensions);
+        packet.extend(data);
+        packet.extend(&self.certificates.borrow().0);
+        self.send_datagram(&packet, to)?;
+        Ok(u64::from(header.trace_id))
+    }
+
+    /// Send a GDP packet, encrypted unless we are in plaintext mode
+    fn send_datagram(&self, packet: &[u8], to: SocketAddr) -> Result<()> {
+        if self.plaintext {
+            let len = self.socket.send_to(packet, to)?;
+            ensure!(packet.len() == len, "sent only {} bytes", len);
+            return Ok(());
+        }
+        let counter = self.sent.get() + 1;
+        self.sent.set(counter);
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&self.nonce_prefix);
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        let encrypted = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), packet)
+            .map_err(|_| anyhow!("encrypt failed"))?;
+
+        let mut datagram = STATIC_SESSION.to_be_bytes().to_vec();
+        datagram.extend(nonce);
+        datagram.extend(encrypted);
+        let len = self.socket.send_to(&datagram, to)?;
+        ensure!(datagram.len() == len, "sent only {} bytes", len);
+        Ok(())
+    }
+
+    fn recv_with_header(&self) -> Result<(GdpHeader, Box<[u8]>)> {
+        loop {
+            if let Some(packet) = self.recv_packet()? {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// The next packet, or None if it was not a GDP packet under our key, or was one we had
+    /// already taken
+    fn recv_packet(&self) -> Result<Option<(GdpHeader, Box<[u8]>)>> {
+        let mut buf = [0u8; 1 << 16];
+        let (size, _) = self.socket.recv_from(&mut buf)?;
+        ensure!(size > 0, "socket closed unexpectedly");
+        if self.plaintext {
+            return Ok(GdpHeader::parse(&buf[..size])
+                .ok()
+                .map(|(header, payload)| (header, payload.into())));
+        }
+        if size < DTLS_HEADER_LEN || buf[..4] != STATIC_SESSION.to_be_bytes() {
+            return Ok(None);
+        }
+        let nonce = Nonce::from_slice(&buf[4..DTLS_HEADER_LEN]);
+        let plaintext = match self.cipher.decrypt(nonce, &buf[DTLS_HEADER_LEN..size]) {
+            Ok(plaintext) => unpad(plaintext)?,
+            Err(_) => return Ok(None),
+        };
+        // only once the packet is authenticated, so that forgeries cannot use up counters
+        if !self.accept_nonce(&buf[4..DTLS_HEADER_LEN]) {
+            return Ok(None);
+        }
+        let (header, payload) = match GdpHeader::parse(&plaintext) {
+            Ok(parsed) => parsed,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some((header, payload.into())))
+    }
+
+    /// Record the nonce of an authenticated packet, false if its counter was seen before from
+    /// the same sender
+    fn accept_nonce(&self, nonce: &[u8]) -> bool {
+        let mut sender = [0; 4];
+        let mut counter = [0; 8];
+        sender.copy_from_slice(&nonce[..4]);
+        counter.copy_from_slice(&nonce[4..12]);
+        let counter = u64::from_be_bytes(counter) & !CONTROL_CLASS;
+        self.replay_windows
+            .borrow_mut()
+            .entry(sender)
+            .or_default()
+            .accept(counter)
+    }
+}
+
+/// Check every certificate of `response` against the metadata of its owner, which the RIB sends
+/// along with it. Names are the hashes of their keys, so a reply cannot vouch for a name with
+/// a key other than its owner's
+fn check_response(response: &RibResponse) -> Result<()> {
+    let metas: HashMap<GdpName, GdpMeta> = response
+        .metas
+        .iter()
+        .map(|meta| (meta.hash(), *meta))
+        .collect();
+    for cert in response.certs.iter().chain(&response.migrating) {
+        let owner = cert.contents.owner();
+        let meta = metas
+            .get(owner)
+            .ok_or_else(|| anyhow!("reply carries a certificate of {:?} without its key", owner))?;
+        verify_certificate(cert, meta)
+            .with_context(|| format!("certificate of {:?} is not signed by it", owner))?;
+    }
+    Ok(())
+}
+
+/// The data of a packet, without the certificates and telemetry that follow it
+fn data_of<'a>(header: &GdpHeader, payload: &'a [u8]) -> Result<&'a [u8]> {
+    let data_len = u16::from(header.data_len) as usize;
+    ensure!(
+        data_len <= payload.len(),
+        "packet shorter than its data length"
+    );
+    let data = &payload[..data_len];
+    verify_content_hash(&header.content_hash, data)?;
+    Ok(data)
+}
+
+/// Strip the padding that a switch's padding.toml may have added
+fn unpad(mut plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    if plaintext.len() < 2 || plaintext[..2] != PADDING_MARKER {
+        return Ok(plaintext);
+    }
+    ensure!(
+        plaintext.len() >= PADDING_HEADER_LEN,
+        "padded payload is too short for its padding header"
+    );
+    let padding_len = u16::from_be_bytes([plaintext[2], plaintext[3]]) as usize;
+    ensure!(
+        PADDING_HEADER_LEN + padding_len <= plaintext.len(),
+        "padding of {} bytes is longer than the {}-byte payload",
+        padding_len,
+        plaintext.len()
+    );
+    plaintext.truncate(plaintext.len() - padding_len);
+    plaintext.drain(..PADDING_HEADER_LEN);
+    Ok(plaintext)
+}
+
+impl Endpoint for DirectClient {
+    fn send_packet(&self, dest: GdpName, payload: &[u8]) -> Result<()> {
+        DirectClient::send_packet(self, dest, payload)
+    }
+
+    fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)> {
+        DirectClient::recv_from(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        DirectClient::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.get()
+    }
+
+    fn report_flow(&self, _: FlowReport) -> Result<()> {
+        Ok(())
+    }
+
+    fn report_paced_flow(&self, _: FlowReport, _: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: [u8; 32] = [7; 32];
+    const OTHER_KEY: [u8; 32] = [8; 32];
+
+    fn located() -> Certificate {
+        route_certificate(
+            PRIVATE_KEY,
+            CertDest::IpAddr(Ipv4Addr::new(10, 0, 0, 1)),
+            true,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn takes_replies_signed_by_their_owners() {
+        let response = RibResponse {
+            metas: vec![meta_of_key(PRIVATE_KEY).unwrap()],
+            certs: vec![located()],
+            ..Default::default()
+        };
+        check_response(&response).unwrap();
+    }
+
+    #[test]
+    fn refuses_replies_with_forged_or_unkeyed_certificates() {
+        // signed with a key other than the one its owner's name is the hash of
+        let mut forged = located();
+        forged.signature = sign_data(&forged.contents, OTHER_KEY).unwrap();
+        let response = RibResponse {
+            metas: vec![
+                meta_of_key(PRIVATE_KEY).unwrap(),
+                meta_of_key(OTHER_KEY).unwrap(),
+            ],
+            certs: vec![forged],
+            ..Default::default()
+        };
+        assert!(check_response(&response).is_err());
+
+        // without the metadata of its owner
+        let response = RibResponse {
+            migrating: vec![located()],
+            ..Default::default()
+        };
+        assert!(check_response(&response).is_err());
+    }
+
+    #[test]
+    fn takes_each_counter_once() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+        // late, but within the window
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        assert!(window.accept(5 + REPLAY_WINDOW));
+        // now too old to tell
+        assert!(!window.accept(4));
+    }
+}
@@ -1,8 +1,34 @@
+mod acks;
 pub mod c_ffi;
-mod control;
+mod certs;
 mod core;
+mod direct;
+mod pinning;
 pub mod py_ffi;
-mod structs;
+mod stream;
+mod usage;
 
-pub use crate::control::{ClientCommand, ClientCommands, ClientResponse, ClientResponses};
-pub use crate::structs::{GdpAction, GdpHeader, GdpName, MAGIC_NUMBERS};
+// applications only depend on this crate, so the wire formats they use are re-exported
+pub use gdp_proto::{
+    content_hash, diff_tables, name_hash, new_trace_id, parse_extensions, status_name,
+    verify_content_hash, write_extensions, AdmissionDecision, AdmissionRequest, AttrCert,
+    BlockedPeer, BranchReport, CanaryMode, CanaryReport, CertContents, CertDest, Certificate,
+    CertificateBlock, ClientCommand, ClientCommands, ClientFlow, ClientResponse, ClientResponses,
+    FinKind, FinPayload, FlowReport, FlowSequences, GdpAction, GdpHeader, GdpMeta, GdpName,
+    HeaderExtension, NackPayload, NackReason, NameType, NodeInfo, PortInfo, ProbeResult,
+    PuntedPacket, Registration, RegistrationContents, RegistrationPhase, RibQuery, RibResponse,
+    RouteDump, RouteSource, RtCert, SecurityLabel, SequenceNumber, SerializableSignature,
+    SignedUsageReport, StatusObject, SwitchStatus, TableDiff, TableExport, TenantUsage,
+    TransportHint, TransportProfile, UsageReport, EXT_ADMISSION_DECISION, EXT_ADMISSION_REQUEST,
+    MAGIC_NUMBERS, NAME_LEN,
+};
+
+pub use crate::acks::PutStatus;
+pub use crate::certs::{meta_of_key, route_certificate, verify_certificate};
+pub use crate::core::{ended, is_timeout, nacked, Ended, Endpoint, GdpClient, Nacked};
+pub use crate::direct::{DirectClient, EXAMPLE_DTLS_KEY, RIB_PORT};
+pub use crate::stream::{
+    get_streamed, put_paced, PutReceiver, StreamMessage, StreamResponder, DEFAULT_CHUNK_SIZE,
+    DEFAULT_PUT_BUDGET, DEFAULT_WINDOW,
+};
+pub use crate::usage::{verify_usage_report, UsageAudit};
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use gdp_proto::GdpName;
+
+/// Bindings that matched their pin are trusted for this long before being checked again
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The public keys that destination names are expected to have, so that a compromised RIB
+/// can't redirect traffic for them to a key it controls
+#[derive(Default)]
+pub struct NamePins {
+    pins: HashMap<GdpName, [u8; 32]>,
+    /// Pin the first key we see for every name we send to, not just the configured ones
+    trust_on_first_use: bool,
+    checked: HashMap<GdpName, Instant>,
+    violations: u64,
+}
+
+impl NamePins {
+    pub fn pin(&mut self, name: GdpName, pub_key: [u8; 32]) {
+        self.pins.insert(name, pub_key);
+        self.checked.remove(&name);
+    }
+
+    pub fn set_trust_on_first_use(&mut self, enabled: bool) {
+        self.trust_on_first_use = enabled;
+    }
+
+    /// Whether the binding for `name` must be looked up before sending to it
+    pub fn needs_check(&self, name: &GdpName) -> bool {
+        if !self.trust_on_first_use && !self.pins.contains_key(name) {
+            return false;
+        }
+        self.checked
+            .get(name)
+            .map_or(true, |checked| checked.elapsed() > RECHECK_INTERVAL)
+    }
+
+    /// Compare the key that the RIB bound `name` to with our pin, pinning it if we trust on first use
+    pub fn check(&mut self, name: GdpName, pub_key: [u8; 32]) -> Result<()> {
+        match self.pins.get(&name) {
+            Some(pinned) if *pinned != pub_key => {
+                self.violations += 1;
+                self.checked.remove(&name);
+                bail!(
+                    "refusing to send: the RIB bound {:02x?} to a different key than it is pinned to",
+                    &name[..4]
+                );
+            }
+            Some(_) => {}
+            None if self.trust_on_first_use => {
+                self.pins.insert(name, pub_key);
+            }
+            None => {}
+        }
+        self.checked.insert(name, Instant::now());
+        Ok(())
+    }
+
+    /// Bindings seen so far that did not match their pin
+    pub fn violations(&self) -> u64 {
+        self.violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gdp_testutil::name;
+
+    use super::*;
+
+    #[test]
+    fn unpinned_names_are_not_checked() {
+        let mut pins = NamePins::default();
+        assert!(!pins.needs_check(&name(1)));
+        assert!(pins.check(name(1), [1; 32]).is_ok());
+        assert_eq!(pins.violations(), 0);
+    }
+
+    #[test]
+    fn pinned_names_are_checked_until_they_match() {
+        let mut pins = NamePins::default();
+        pins.pin(name(1), [1; 32]);
+        assert!(pins.needs_check(&name(1)));
+
+        assert!(pins.check(name(1), [2; 32]).is_err());
+        assert_eq!(pins.violations(), 1);
+        assert!(pins.needs_check(&name(1)));
+
+        assert!(pins.check(name(1), [1; 32]).is_ok());
+        assert!(!pins.needs_check(&name(1)));
+        assert_eq!(pins.violations(), 1);
+    }
+
+    #[test]
+    fn repinning_forces_a_new_check() {
+        let mut pins = NamePins::default();
+        pins.pin(name(1), [1; 32]);
+        pins.check(name(1), [1; 32]).unwrap();
+        pins.pin(name(1), [2; 32]);
+        assert!(pins.needs_check(&name(1)));
+        assert!(pins.check(name(1), [1; 32]).is_err());
+    }
+
+    #[test]
+    fn trust_on_first_use_pins_the_first_key() {
+        let mut pins = NamePins::default();
+        pins.set_trust_on_first_use(true);
+        assert!(pins.needs_check(&name(1)));
+        pins.check(name(1), [1; 32]).unwrap();
+        assert!(pins.check(name(1), [2; 32]).is_err());
+        assert_eq!(pins.violations(), 1);
+    }
+}
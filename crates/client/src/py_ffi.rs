@@ -2,11 +2,11 @@ use std::net::Ipv4Addr;
 use std::str::FromStr;
 
 use anyhow::{Context, Error};
+use gdp_proto::GdpName;
 use pyo3::types::PyModule;
 use pyo3::{create_exception, pyclass, pymethods, pymodule, PyErr, PyResult, Python};
 
 use crate::core::GdpClient;
-use crate::structs::GdpName;
 
 #[pyclass]
 struct PyGdpClient(GdpClient);
@@ -43,6 +43,14 @@ impl PyGdpClient {
             .map_err(py_err)
     }
 
+    fn pin_name(&self, name: GdpName, pub_key: [u8; 32]) {
+        self.0.pin_name(name, pub_key)
+    }
+
+    fn set_trust_on_first_use(&self, enabled: bool) {
+        self.0.set_trust_on_first_use(enabled)
+    }
+
     fn recv_from(&mut self) -> PyResult<(GdpName, Vec<u8>)> {
         self.0
             .recv_from()
@@ -0,0 +1,1140 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Result};
+use gdp_proto::{FlowReport, GdpName};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{ended, is_timeout, Endpoint};
+
+/*
+   Streamed Get: large objects are sent as a sequence of chunks, paced by the receiver.
+   - the receiver grants credit for `window` chunks past the last one it has received in order,
+     first in its Get and then in Acks sent as it consumes the chunks
+   - the responder never has more chunks outstanding than the receiver has granted,
+     so a slow receiver slows down the responder instead of overflowing the network
+   - chunks are only accepted in order; after a timeout the receiver asks the responder
+     to go back to the first chunk it is missing
+   - the responder serves at most MAX_STREAMS streams at once, refusing the Gets of new ones
+   - the receiver reports its window, RTT and losses to its sidecar every FLOW_REPORT_INTERVAL.
+     RTTs are sampled from a Get or Ack to the first chunk that it grants, except across
+     retransmissions, where the chunk could answer either request
+
+   Paced Put: large objects are stored the other way round, paced by the receiver (the datastore).
+   - the sender sends chunks in order and keeps at most `pace` of them unacknowledged, so it sends
+     `pace` chunks per RTT. Until the first PutAck it assumes INITIAL_PACE
+   - the receiver acks as it stores the chunks, with the pace in each PutAck, once half of the
+     pace it last told the sender has arrived (half of INITIAL_PACE before its first ack). The
+     pace is the receiver's budget of chunks in flight, shared among the Puts it has in progress,
+     which the application can lower while it is falling behind
+   - the receiver takes at most MAX_PUTS Puts at once, of at most MAX_PUT_CHUNKS chunks
+   - once CHUNK_TIMEOUT passes without an ack of a new chunk, the sender goes back to the first
+     chunk that was not acknowledged. Other packets, and acks of nothing new, do not put it off
+   - the sender reports its flow like a streamed Get (see ReportPacedFlow), also counting each
+     time it stalled with chunks left to send because the pace was in flight. Stalls that rise
+     while the losses do not mean the receiver, not the network, is what limits the Put
+*/
+
+/// Chunks are sized so that a chunk plus the GDP headers and certificates fits in a packet
+pub const DEFAULT_CHUNK_SIZE: usize = 1024;
+/// How many chunks a receiver lets the responder send ahead by default
+pub const DEFAULT_WINDOW: u32 = 16;
+/// How long the receiver waits for the next chunk before asking for a retransmission
+const CHUNK_TIMEOUT: Duration = Duration::from_millis(500);
+/// Consecutive timeouts after which the receiver gives up
+const MAX_TIMEOUTS: usize = 8;
+/// Streams the receiver has gone quiet on for this long are forgotten by the responder
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Streams a responder serves at once, beyond which new ones are refused
+const MAX_STREAMS: usize = 1024;
+const FLOW_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// How many chunks of a Put the sender has in flight before the receiver tells it its pace
+const INITIAL_PACE: u32 = 4;
+/// How many chunks a receiver lets be in flight towards it by default, across all of its Puts
+pub const DEFAULT_PUT_BUDGET: u32 = 64;
+/// Puts with more chunks than this are refused: 64 MiB of DEFAULT_CHUNK_SIZE chunks
+const MAX_PUT_CHUNKS: u32 = 1 << 16;
+/// Puts a receiver has in progress at once, beyond which new ones are refused
+const MAX_PUTS: usize = 1024;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum StreamMessage {
+    /// Ask for `object`, granting credit for its first `window` chunks
+    Get {
+        stream_id: u32,
+        object: GdpName,
+        window: u32,
+    },
+    Chunk {
+        stream_id: u32,
+        seq: u32,
+        /// Number of chunks in the object
+        total: u32,
+        data: Vec<u8>,
+    },
+    /// Every chunk before `next_seq` has been received; chunks up to `next_seq + window` may be sent.
+    /// With `retransmit`, the responder resends from `next_seq`.
+    Ack {
+        stream_id: u32,
+        next_seq: u32,
+        window: u32,
+        retransmit: bool,
+    },
+    NotFound {
+        stream_id: u32,
+    },
+    /// Chunk `seq` of `object`, sent to be stored
+    PutChunk {
+        stream_id: u32,
+        object: GdpName,
+        seq: u32,
+        /// Number of chunks in the object
+        total: u32,
+        data: Vec<u8>,
+    },
+    /// Every chunk of the Put before `next_seq` has been received; at most `pace` chunks may be
+    /// unacknowledged from now on
+    PutAck {
+        stream_id: u32,
+        next_seq: u32,
+        pace: u32,
+    },
+}
+
+impl StreamMessage {
+    /// None if the payload is some other kind of message
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        bincode::deserialize(payload).ok()
+    }
+
+    fn send(&self, client: &impl Endpoint, dest: GdpName) -> Result<()> {
+        client.send_packet(dest, &bincode::serialize(self)?)
+    }
+}
+
+struct OutgoingStream {
+    object: Arc<[u8]>,
+    next_to_send: u32,
+    /// Chunks before this may be sent
+    credit_limit: u32,
+    last_heard: Instant,
+}
+
+/// Serves streamed Gets on behalf of an application that stores objects (e.g. a datastore)
+pub struct StreamResponder {
+    chunk_size: usize,
+    streams: HashMap<(GdpName, u32), OutgoingStream>,
+}
+
+impl StreamResponder {
+    pub fn new(chunk_size: usize) -> Self {
+        StreamResponder {
+            chunk_size,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Forget the streams of `src`, which ended its flow to us (see `ended`), rather than wait
+    /// for them to go idle
+    pub fn forget(&mut self, src: &GdpName) {
+        self.streams.retain(|(peer, _), _| peer != src);
+    }
+
+    /// Handle a stream message from `src`, looking up requested objects with `lookup`
+    pub fn handle(
+        &mut self,
+        client: &impl Endpoint,
+        src: GdpName,
+        message: StreamMessage,
+        lookup: impl FnOnce(&GdpName) -> Option<Arc<[u8]>>,
+    ) -> Result<()> {
+        self.streams
+            .retain(|_, stream| stream.last_heard.elapsed() < STREAM_IDLE_TIMEOUT);
+
+        let stream_id = match message {
+            StreamMessage::Get {
+                stream_id,
+                object,
+                window,
+            } => {
+                ensure!(
+                    self.streams.len() < MAX_STREAMS
+                        || self.streams.contains_key(&(src, stream_id)),
+                    "too many streams in progress"
+                );
+                let object = match lookup(&object) {
+                    Some(object) => object,
+                    None => return StreamMessage::NotFound { stream_id }.send(client, src),
+                };
+                self.streams.insert(
+                    (src, stream_id),
+                    OutgoingStream {
+                        object,
+                        next_to_send: 0,
+                        credit_limit: window,
+                        last_heard: Instant::now(),
+                    },
+                );
+                stream_id
+            }
+            StreamMessage::Ack {
+                stream_id,
+                next_seq,
+                window,
+                retransmit,
+            } => {
+                if self.total_chunks(&src, stream_id) == Some(next_seq) {
+                    self.streams.remove(&(src, stream_id));
+                    return Ok(());
+                }
+                let stream = match self.streams.get_mut(&(src, stream_id)) {
+                    Some(stream) => stream,
+                    // an ack for a stream that finished, or that we gave up on
+                    None => return Ok(()),
+                };
+                stream.credit_limit = stream.credit_limit.max(next_seq.saturating_add(window));
+                if retransmit {
+                    stream.next_to_send = next_seq;
+                }
+                stream.last_heard = Instant::now();
+                stream_id
+            }
+            _ => bail!("unexpected stream message from a receiver"),
+        };
+        self.send_granted(client, src, stream_id)
+    }
+
+    fn total_chunks(&self, src: &GdpName, stream_id: u32) -> Option<u32> {
+        let stream = self.streams.get(&(*src, stream_id))?;
+        Some(((stream.object.len() + self.chunk_size - 1) / self.chunk_size).max(1) as u32)
+    }
+
+    fn send_granted(
+        &mut self,
+        client: &impl Endpoint,
+        dest: GdpName,
+        stream_id: u32,
+    ) -> Result<()> {
+        let total = match self.total_chunks(&dest, stream_id) {
+            Some(total) => total,
+            None => return Ok(()),
+        };
+        let chunk_size = self.chunk_size;
+        let stream = self.streams.get_mut(&(dest, stream_id)).unwrap();
+        while stream.next_to_send < total.min(stream.credit_limit) {
+            let seq = stream.next_to_send;
+            let start = seq as usize * chunk_size;
+            let end = (start + chunk_size).min(stream.object.len());
+            StreamMessage::Chunk {
+                stream_id,
+                seq,
+                total,
+                data: stream.object[start..end].to_vec(),
+            }
+            .send(client, dest)?;
+            stream.next_to_send += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Default for StreamResponder {
+    fn default() -> Self {
+        StreamResponder::new(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+struct IncomingPut {
+    object: GdpName,
+    total: u32,
+    data: Vec<u8>,
+    next_seq: u32,
+    /// The `next_seq` and pace of our last ack: the sender may have that pace in flight past it
+    last_ack: (u32, u32),
+    last_heard: Instant,
+}
+
+/// Receives paced Puts on behalf of an application that stores objects (e.g. a datastore)
+pub struct PutReceiver {
+    /// Chunks that may be in flight towards us, shared among the Puts in progress
+    budget: u32,
+    puts: HashMap<(GdpName, u32), IncomingPut>,
+    /// Puts stored recently, with their number of chunks, to ack chunks the sender resends
+    /// because our last ack was lost
+    stored: HashMap<(GdpName, u32), (u32, Instant)>,
+}
+
+impl PutReceiver {
+    pub fn new(budget: u32) -> Self {
+        PutReceiver {
+            budget,
+            puts: HashMap::new(),
+            stored: HashMap::new(),
+        }
+    }
+
+    /// Change how many chunks may be in flight towards us, e.g. to slow senders down while
+    /// the application catches up. Senders learn of it with the next ack.
+    pub fn set_budget(&mut self, budget: u32) {
+        self.budget = budget;
+    }
+
+    /// The pace each sender is given
+    fn pace(&self) -> u32 {
+        (self.budget / self.puts.len().max(1) as u32).max(1)
+    }
+
+    /// Forget the Puts of `src`, which ended its flow to us (see `ended`), rather than wait for
+    /// them to go idle. Those it had not finished are dropped
+    pub fn forget(&mut self, src: &GdpName) {
+        self.puts.retain(|(peer, _), _| peer != src);
+        self.stored.retain(|(peer, _), _| peer != src);
+    }
+
+    /// Handle a stream message from `src`, passing each object to `store` once all of it arrived
+    pub fn handle(
+        &mut self,
+        client: &impl Endpoint,
+        src: GdpName,
+        message: StreamMessage,
+        store: impl FnOnce(GdpName, Vec<u8>) -> Result<()>,
+    ) -> Result<()> {
+        self.puts
+            .retain(|_, put| put.last_heard.elapsed() < STREAM_IDLE_TIMEOUT);
+        self.stored
+            .retain(|_, (_, stored)| stored.elapsed() < STREAM_IDLE_TIMEOUT);
+
+        let (stream_id, object, seq, total, data) = match message {
+            StreamMessage::PutChunk {
+                stream_id,
+                object,
+                seq,
+                total,
+                data,
+            } => (stream_id, object, seq, total, data),
+            _ => bail!("unexpected stream message from a sender"),
+        };
+        let key = (src, stream_id);
+        if let Some((total, _)) = self.stored.get(&key) {
+            return ack(client, src, stream_id, *total, self.pace());
+        }
+        ensure!(
+            data.len() <= DEFAULT_CHUNK_SIZE,
+            "chunk of {} bytes from {:02x?}",
+            data.len(),
+            &src[..4]
+        );
+        if !self.puts.contains_key(&key) {
+            ensure!(
+                total > 0 && total <= MAX_PUT_CHUNKS,
+                "Put of {} chunks from {:02x?}",
+                total,
+                &src[..4]
+            );
+            ensure!(self.puts.len() < MAX_PUTS, "too many Puts in progress");
+            self.puts.insert(
+                key,
+                IncomingPut {
+                    object,
+                    total,
+                    data: Vec::new(),
+                    next_seq: 0,
+                    last_ack: (0, INITIAL_PACE),
+                    last_heard: Instant::now(),
+                },
+            );
+        }
+        let pace = self.pace();
+        let put = self.puts.get_mut(&key).unwrap();
+        ensure!(
+            total == put.total,
+            "chunk of a Put of {} chunks from {:02x?} says {}",
+            put.total,
+            &src[..4],
+            total
+        );
+        put.last_heard = Instant::now();
+        if seq != put.next_seq {
+            // a chunk we already have means our ack was lost; one ahead is waited out until
+            // the sender times out and goes back
+            let next_seq = put.next_seq;
+            if seq < next_seq {
+                put.last_ack = (next_seq, pace);
+                return ack(client, src, stream_id, next_seq, pace);
+            }
+            return Ok(());
+        }
+        put.data.extend(data);
+        put.next_seq += 1;
+        let next_seq = put.next_seq;
+
+        if next_seq == put.total {
+            let put = self.puts.remove(&key).unwrap();
+            if self.stored.len() < MAX_PUTS {
+                self.stored.insert(key, (put.total, Instant::now()));
+            }
+            store(put.object, put.data)?;
+            return ack(client, src, stream_id, next_seq, pace);
+        }
+        // ack once half of what the sender may have in flight has arrived, so that it never
+        // runs dry
+        let (last_acked, last_pace) = put.last_ack;
+        if next_seq - last_acked >= (last_pace / 2).max(1) {
+            put.last_ack = (next_seq, pace);
+            ack(client, src, stream_id, next_seq, pace)?;
+        }
+        Ok(())
+    }
+}
+
+fn ack(
+    client: &impl Endpoint,
+    dest: GdpName,
+    stream_id: u32,
+    next_seq: u32,
+    pace: u32,
+) -> Result<()> {
+    StreamMessage::PutAck {
+        stream_id,
+        next_seq,
+        pace,
+    }
+    .send(client, dest)
+}
+
+impl Default for PutReceiver {
+    fn default() -> Self {
+        PutReceiver::new(DEFAULT_PUT_BUDGET)
+    }
+}
+
+/// What the receiver of a stream knows about how it is faring
+struct StreamTelemetry {
+    report: FlowReport,
+    /// For the sender of a paced Put, the times it stalled on the receiver's pace
+    paced_stalls: Option<u64>,
+    /// The chunk that completes the RTT sample in progress, and when it was asked for
+    probe: Option<(u32, Instant)>,
+    last_report: Instant,
+}
+
+impl StreamTelemetry {
+    fn new(responder: GdpName, stream_id: u32, window: u32) -> Self {
+        StreamTelemetry {
+            report: FlowReport {
+                peer: responder,
+                stream_id,
+                window,
+                srtt_us: None,
+                loss_events: 0,
+                delivered_bytes: 0,
+                finished: false,
+            },
+            paced_stalls: None,
+            probe: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn paced(receiver: GdpName, stream_id: u32, pace: u32) -> Self {
+        StreamTelemetry {
+            paced_stalls: Some(0),
+            ..StreamTelemetry::new(receiver, stream_id, pace)
+        }
+    }
+
+    fn asked_for(&mut self, seq: u32) {
+        if self.probe.is_none() {
+            self.probe = Some((seq, Instant::now()));
+        }
+    }
+
+    fn lost(&mut self) {
+        self.report.loss_events += 1;
+        self.probe = None;
+    }
+
+    fn received(&mut self, seq: u32, len: usize) {
+        self.report.delivered_bytes += len as u64;
+        if let Some((_, asked)) = self.probe.filter(|(probe_seq, _)| *probe_seq == seq) {
+            self.sample_rtt(asked);
+        }
+    }
+
+    fn stalled(&mut self) {
+        if let Some(stalls) = &mut self.paced_stalls {
+            *stalls += 1;
+        }
+    }
+
+    /// The receiver of a Put acknowledged every chunk before `next_seq`, and set its pace
+    fn acked(&mut self, next_seq: u32, pace: u32, bytes: usize) {
+        self.report.window = pace;
+        self.report.delivered_bytes += bytes as u64;
+        if let Some((_, sent)) = self.probe.filter(|(probe_seq, _)| *probe_seq < next_seq) {
+            self.sample_rtt(sent);
+        }
+    }
+
+    fn sample_rtt(&mut self, asked: Instant) {
+        let sample = asked.elapsed().as_micros() as u64;
+        // the usual smoothing, with a gain of 1/8
+        self.report.srtt_us = Some(match self.report.srtt_us {
+            Some(srtt) => srtt - srtt / 8 + sample / 8,
+            None => sample,
+        });
+        self.probe = None;
+    }
+
+    fn report(&mut self, client: &impl Endpoint, finished: bool) -> Result<()> {
+        if !finished && self.last_report.elapsed() < FLOW_REPORT_INTERVAL {
+            return Ok(());
+        }
+        self.report.finished = finished;
+        self.last_report = Instant::now();
+        match self.paced_stalls {
+            Some(stalls) => client.report_paced_flow(self.report, stalls),
+            None => client.report_flow(self.report),
+        }
+    }
+}
+
+/// Fetch `object` from `responder` with a streamed Get, letting it send at most `window` chunks ahead.
+/// Packets that are not part of the stream are discarded while it runs.
+pub fn get_streamed(
+    client: &mut impl Endpoint,
+    responder: GdpName,
+    object: GdpName,
+    window: u32,
+) -> Result<Vec<u8>> {
+    ensure!(window > 0, "the window must allow at least one chunk");
+    let stream_id =
+        SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() ^ std::process::id();
+    let read_timeout = client.read_timeout();
+    client.set_read_timeout(Some(CHUNK_TIMEOUT))?;
+    let received = receive_chunks(client, responder, stream_id, object, window);
+    client.set_read_timeout(read_timeout)?;
+    received
+}
+
+fn receive_chunks<C: Endpoint>(
+    client: &mut C,
+    responder: GdpName,
+    stream_id: u32,
+    object: GdpName,
+    window: u32,
+) -> Result<Vec<u8>> {
+    let get = StreamMessage::Get {
+        stream_id,
+        object,
+        window,
+    };
+    let mut telemetry = StreamTelemetry::new(responder, stream_id, window);
+    get.send(client, responder)?;
+    telemetry.asked_for(0);
+    // chunks before this have been granted to the responder
+    let mut granted = window;
+    let ack = |client: &C, next_seq: u32, retransmit: bool| {
+        StreamMessage::Ack {
+            stream_id,
+            next_seq,
+            window,
+            retransmit,
+        }
+        .send(client, responder)
+    };
+
+    let mut contents = Vec::new();
+    let mut next_seq = 0;
+    let mut timeouts = 0;
+    // grant more credit once half of the window has been consumed
+    let ack_every = (window / 2).max(1);
+    loop {
+        let (src, payload) = match client.recv_from() {
+            Ok(packet) => packet,
+            Err(err) if is_timeout(&err) => {
+                timeouts += 1;
+                ensure!(timeouts < MAX_TIMEOUTS, "responder stopped sending chunks");
+                telemetry.lost();
+                telemetry.report(client, false)?;
+                if next_seq == 0 {
+                    // the Get itself may have been lost
+                    get.send(client, responder)?;
+                } else {
+                    ack(client, next_seq, true)?;
+                    granted = granted.max(next_seq + window);
+                }
+                continue;
+            }
+            // other peers ending their flows to us are none of this stream's business
+            Err(err) if ended(&err).map_or(false, |end| end.src != responder) => continue,
+            Err(err) => return Err(err),
+        };
+        if src != responder {
+            continue;
+        }
+        match StreamMessage::parse(&payload) {
+            Some(StreamMessage::Chunk {
+                stream_id: id,
+                seq,
+                total,
+                data,
+            }) if id == stream_id => {
+                timeouts = 0;
+                if seq != next_seq {
+                    // out of order: wait for the retransmission
+                    continue;
+                }
+                telemetry.received(seq, data.len());
+                contents.extend(data);
+                next_seq += 1;
+                if next_seq == total {
+                    // lets the responder forget the stream right away
+                    ack(client, next_seq, false)?;
+                    telemetry.report(client, true)?;
+                    return Ok(contents);
+                }
+                if next_seq % ack_every == 0 {
+                    ack(client, next_seq, false)?;
+                    if next_seq + window > granted {
+                        telemetry.asked_for(granted);
+                        granted = next_seq + window;
+                    }
+                }
+                telemetry.report(client, false)?;
+            }
+            Some(StreamMessage::NotFound { stream_id: id }) if id == stream_id => {
+                bail!("responder does not have the object")
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Store `data` as `object` at `receiver` with a paced Put, sending as fast as the receiver
+/// lets it. Packets that are not part of the Put are discarded while it runs.
+pub fn put_paced(
+    client: &mut impl Endpoint,
+    receiver: GdpName,
+    object: GdpName,
+    data: &[u8],
+) -> Result<()> {
+    let stream_id =
+        SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() ^ std::process::id();
+    let read_timeout = client.read_timeout();
+    client.set_read_timeout(Some(CHUNK_TIMEOUT))?;
+    let sent = send_chunks(client, receiver, stream_id, object, data);
+    client.set_read_timeout(read_timeout)?;
+    sent
+}
+
+fn send_chunks<C: Endpoint>(
+    client: &mut C,
+    receiver: GdpName,
+    stream_id: u32,
+    object: GdpName,
+    data: &[u8],
+) -> Result<()> {
+    let total = ((data.len() + DEFAULT_CHUNK_SIZE - 1) / DEFAULT_CHUNK_SIZE).max(1) as u32;
+    let chunk_end = |seq: u32| (seq as usize * DEFAULT_CHUNK_SIZE).min(data.len());
+    let send = |client: &C, seq: u32| {
+        StreamMessage::PutChunk {
+            stream_id,
+            object,
+            seq,
+            total,
+            data: data[chunk_end(seq)..chunk_end(seq + 1)].to_vec(),
+        }
+        .send(client, receiver)
+    };
+    let mut telemetry = StreamTelemetry::paced(receiver, stream_id, INITIAL_PACE);
+
+    let mut pace = INITIAL_PACE;
+    // every chunk before this was acknowledged
+    let mut acked = 0;
+    let mut next_to_send = 0;
+    let mut timeouts = 0;
+    // when the receiver last acknowledged a chunk it had not before
+    let mut progress = Instant::now();
+    // whether this stall on the pace was counted already
+    let mut stalled = false;
+    loop {
+        while next_to_send < total && next_to_send < acked + pace {
+            send(client, next_to_send)?;
+            telemetry.asked_for(next_to_send);
+            next_to_send += 1;
+        }
+        if next_to_send < total && !stalled {
+            telemetry.stalled();
+            stalled = true;
+        }
+
+        let mut timed_out = false;
+        let put_ack = match client.recv_from() {
+            Ok((src, payload)) if src == receiver => match StreamMessage::parse(&payload) {
+                Some(StreamMessage::PutAck {
+                    stream_id: id,
+                    next_seq,
+                    pace,
+                }) if id == stream_id => Some((next_seq, pace)),
+                _ => None,
+            },
+            Ok(_) => None,
+            Err(err) if is_timeout(&err) => {
+                timed_out = true;
+                None
+            }
+            Err(err) if ended(&err).map_or(false, |end| end.src != receiver) => None,
+            Err(err) => return Err(err),
+        };
+        if let Some((next_seq, hint)) = put_ack {
+            pace = hint.max(1);
+            let next_seq = next_seq.min(total);
+            if next_seq > acked {
+                timeouts = 0;
+                progress = Instant::now();
+                telemetry.acked(next_seq, pace, chunk_end(next_seq) - chunk_end(acked));
+                acked = next_seq;
+                next_to_send = next_to_send.max(acked);
+                stalled = false;
+            }
+            if acked == total {
+                telemetry.report(client, true)?;
+                return Ok(());
+            }
+            telemetry.report(client, false)?;
+        }
+        // packets keep coming in while the receiver acks nothing new, which must not put the
+        // timeout off
+        if timed_out || progress.elapsed() >= CHUNK_TIMEOUT {
+            timeouts += 1;
+            ensure!(
+                timeouts < MAX_TIMEOUTS,
+                "receiver stopped acknowledging chunks"
+            );
+            telemetry.lost();
+            telemetry.report(client, false)?;
+            next_to_send = acked;
+            progress = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind};
+
+    use gdp_testutil::name;
+
+    use super::*;
+
+    /// Collects what is sent through it
+    #[derive(Default)]
+    struct Sink {
+        sent: RefCell<Vec<Vec<u8>>>,
+        reports: RefCell<Vec<FlowReport>>,
+        /// The paced stalls of each report of a Put
+        paced_stalls: RefCell<Vec<u64>>,
+        read_timeout: Cell<Option<Duration>>,
+    }
+
+    impl Sink {
+        fn take(&self) -> Vec<Vec<u8>> {
+            std::mem::take(&mut *self.sent.borrow_mut())
+        }
+    }
+
+    impl Endpoint for Sink {
+        fn send_packet(&self, _dest: GdpName, payload: &[u8]) -> Result<()> {
+            self.sent.borrow_mut().push(payload.to_vec());
+            Ok(())
+        }
+
+        fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)> {
+            unreachable!()
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+            self.read_timeout.set(timeout);
+            Ok(())
+        }
+
+        fn read_timeout(&self) -> Option<Duration> {
+            self.read_timeout.get()
+        }
+
+        fn report_flow(&self, report: FlowReport) -> Result<()> {
+            self.reports.borrow_mut().push(report);
+            Ok(())
+        }
+
+        fn report_paced_flow(&self, report: FlowReport, paced_stalls: u64) -> Result<()> {
+            self.paced_stalls.borrow_mut().push(paced_stalls);
+            self.report_flow(report)
+        }
+    }
+
+    /// A client whose peer is `serve`, which answers each message sent to it. Receiving times
+    /// out once the peer has nothing more to say
+    struct Loopback<F> {
+        own: Sink,
+        inbox: VecDeque<Vec<u8>>,
+        serve: F,
+    }
+
+    impl<F: FnMut(StreamMessage) -> Vec<Vec<u8>>> Loopback<F> {
+        fn new(serve: F) -> Self {
+            Loopback {
+                own: Sink::default(),
+                inbox: VecDeque::new(),
+                serve,
+            }
+        }
+    }
+
+    impl<F: FnMut(StreamMessage) -> Vec<Vec<u8>>> Endpoint for Loopback<F> {
+        fn send_packet(&self, dest: GdpName, payload: &[u8]) -> Result<()> {
+            self.own.send_packet(dest, payload)
+        }
+
+        fn recv_from(&mut self) -> Result<(GdpName, Box<[u8]>)> {
+            loop {
+                if let Some(payload) = self.inbox.pop_front() {
+                    return Ok((name(1), payload.into()));
+                }
+                let sent = self.own.take();
+                if sent.is_empty() {
+                    return Err(io::Error::from(ErrorKind::WouldBlock).into());
+                }
+                for payload in sent {
+                    let replies = (self.serve)(StreamMessage::parse(&payload).unwrap());
+                    self.inbox.extend(replies);
+                }
+            }
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+            self.own.set_read_timeout(timeout)
+        }
+
+        fn read_timeout(&self) -> Option<Duration> {
+            self.own.read_timeout()
+        }
+
+        fn report_flow(&self, report: FlowReport) -> Result<()> {
+            self.own.report_flow(report)
+        }
+
+        fn report_paced_flow(&self, report: FlowReport, paced_stalls: u64) -> Result<()> {
+            self.own.report_paced_flow(report, paced_stalls)
+        }
+    }
+
+    fn object(len: usize) -> Arc<[u8]> {
+        (0..len).map(|i| i as u8).collect::<Vec<_>>().into()
+    }
+
+    fn chunk_seqs(sent: &[Vec<u8>]) -> Vec<u32> {
+        sent.iter()
+            .filter_map(|payload| match StreamMessage::parse(payload) {
+                Some(StreamMessage::Chunk { seq, .. }) => Some(seq),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn responder_sends_only_granted_chunks() {
+        let mut responder = StreamResponder::new(4);
+        let client = Sink::default();
+        let get = StreamMessage::Get {
+            stream_id: 1,
+            object: name(9),
+            window: 2,
+        };
+        responder
+            .handle(&client, name(2), get, |_| Some(object(18)))
+            .unwrap();
+        assert_eq!(chunk_seqs(&client.take()), vec![0, 1]);
+
+        let ack = |next_seq, retransmit| StreamMessage::Ack {
+            stream_id: 1,
+            next_seq,
+            window: 2,
+            retransmit,
+        };
+        responder
+            .handle(&client, name(2), ack(1, false), |_| None)
+            .unwrap();
+        assert_eq!(chunk_seqs(&client.take()), vec![2]);
+        responder
+            .handle(&client, name(2), ack(1, true), |_| None)
+            .unwrap();
+        assert_eq!(chunk_seqs(&client.take()), vec![1, 2]);
+        responder
+            .handle(&client, name(2), ack(3, false), |_| None)
+            .unwrap();
+        assert_eq!(chunk_seqs(&client.take()), vec![3, 4]);
+
+        // acking the last chunk ends the stream
+        responder
+            .handle(&client, name(2), ack(5, false), |_| None)
+            .unwrap();
+        assert!(responder.streams.is_empty());
+    }
+
+    #[test]
+    fn streamed_get_fetches_the_object() {
+        let mut responder = StreamResponder::new(4);
+        let sink = Sink::default();
+        let mut client = Loopback::new(|message| {
+            responder
+                .handle(&sink, name(2), message, |_| Some(object(50)))
+                .unwrap();
+            sink.take()
+        });
+        let contents = get_streamed(&mut client, name(1), name(9), 4).unwrap();
+        assert_eq!(contents, &object(50)[..]);
+
+        let reports = client.own.reports.borrow();
+        let last = reports.last().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.delivered_bytes, 50);
+        assert_eq!(last.loss_events, 0);
+    }
+
+    #[test]
+    fn responder_refuses_streams_beyond_its_limit() {
+        let mut responder = StreamResponder::new(4);
+        let client = Sink::default();
+        let get = |stream_id| StreamMessage::Get {
+            stream_id,
+            object: name(9),
+            window: 1,
+        };
+        for stream_id in 0..MAX_STREAMS as u32 {
+            responder
+                .handle(&client, name(2), get(stream_id), |_| Some(object(18)))
+                .unwrap();
+        }
+        let new = responder.handle(&client, name(2), get(MAX_STREAMS as u32), |_| {
+            Some(object(18))
+        });
+        assert!(new.is_err());
+        assert_eq!(responder.streams.len(), MAX_STREAMS);
+        // a stream it serves already may still be asked for again
+        responder
+            .handle(&client, name(2), get(0), |_| Some(object(18)))
+            .unwrap();
+    }
+
+    #[test]
+    fn streams_leave_the_read_timeout_as_they_found_it() {
+        let mut responder = StreamResponder::new(4);
+        let sink = Sink::default();
+        let mut client = Loopback::new(|message| {
+            responder
+                .handle(&sink, name(2), message, |_| Some(object(50)))
+                .unwrap();
+            sink.take()
+        });
+        let timeout = Some(Duration::from_secs(3));
+        client.set_read_timeout(timeout).unwrap();
+        get_streamed(&mut client, name(1), name(9), 4).unwrap();
+        assert_eq!(client.read_timeout(), timeout);
+
+        let mut receiver = PutReceiver::new(8);
+        let mut client = Loopback::new(|message| {
+            receiver
+                .handle(&sink, name(2), message, |_, _| Ok(()))
+                .unwrap();
+            sink.take()
+        });
+        client.set_read_timeout(timeout).unwrap();
+        put_paced(&mut client, name(1), name(9), &object(50)).unwrap();
+        assert_eq!(client.read_timeout(), timeout);
+    }
+
+    #[test]
+    fn streamed_get_recovers_lost_chunks() {
+        let mut responder = StreamResponder::new(4);
+        let sink = Sink::default();
+        let mut dropped = false;
+        let mut client = Loopback::new(|message| {
+            responder
+                .handle(&sink, name(2), message, |_| Some(object(50)))
+                .unwrap();
+            let mut sent = sink.take();
+            if !dropped && chunk_seqs(&sent).contains(&3) {
+                sent.retain(|payload| chunk_seqs(&[payload.clone()]) != vec![3]);
+                dropped = true;
+            }
+            sent
+        });
+        let contents = get_streamed(&mut client, name(1), name(9), 4).unwrap();
+        assert_eq!(contents, &object(50)[..]);
+        assert_eq!(client.own.reports.borrow().last().unwrap().loss_events, 1);
+    }
+
+    #[test]
+    fn streamed_get_of_a_missing_object_fails() {
+        let mut responder = StreamResponder::new(4);
+        let sink = Sink::default();
+        let mut client = Loopback::new(|message| {
+            responder.handle(&sink, name(2), message, |_| None).unwrap();
+            sink.take()
+        });
+        assert!(get_streamed(&mut client, name(1), name(9), 4).is_err());
+    }
+
+    #[test]
+    fn paced_put_stores_the_object() {
+        let mut receiver = PutReceiver::new(8);
+        let sink = Sink::default();
+        let mut stored = None;
+        let data = object(10 * DEFAULT_CHUNK_SIZE + 1);
+        let mut client = Loopback::new(|message| {
+            receiver
+                .handle(&sink, name(2), message, |object, data| {
+                    stored = Some((object, data));
+                    Ok(())
+                })
+                .unwrap();
+            sink.take()
+        });
+        put_paced(&mut client, name(1), name(9), &data).unwrap();
+        let reports = client.own.reports.borrow();
+        assert!(reports.last().unwrap().finished);
+        drop(reports);
+        drop(client);
+        assert_eq!(stored, Some((name(9), data.to_vec())));
+    }
+
+    fn put_chunk(seq: u32, total: u32, len: usize) -> StreamMessage {
+        StreamMessage::PutChunk {
+            stream_id: 1,
+            object: name(9),
+            seq,
+            total,
+            data: vec![0; len],
+        }
+    }
+
+    fn put_acks(sent: &[Vec<u8>]) -> Vec<(u32, u32)> {
+        sent.iter()
+            .filter_map(|payload| match StreamMessage::parse(payload) {
+                Some(StreamMessage::PutAck { next_seq, pace, .. }) => Some((next_seq, pace)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn receiver_acks_before_the_initial_pace_runs_out() {
+        let mut receiver = PutReceiver::new(DEFAULT_PUT_BUDGET);
+        let client = Sink::default();
+        let mut acks = Vec::new();
+        for seq in 0..40 {
+            receiver
+                .handle(&client, name(2), put_chunk(seq, 100, 8), |_, _| Ok(()))
+                .unwrap();
+            acks.extend(put_acks(&client.take()));
+        }
+        // the sender starts at INITIAL_PACE, then gets the whole budget
+        assert_eq!(acks[0], (INITIAL_PACE / 2, DEFAULT_PUT_BUDGET));
+        assert_eq!(
+            acks[1],
+            (
+                INITIAL_PACE / 2 + DEFAULT_PUT_BUDGET / 2,
+                DEFAULT_PUT_BUDGET
+            )
+        );
+        assert_eq!(acks.len(), 2);
+    }
+
+    #[test]
+    fn receiver_acks_again_chunks_it_has() {
+        let mut receiver = PutReceiver::new(8);
+        let client = Sink::default();
+        for seq in 0..3 {
+            receiver
+                .handle(&client, name(2), put_chunk(seq, 3, 8), |_, _| Ok(()))
+                .unwrap();
+        }
+        client.take();
+        // our acks were lost, so the sender went back
+        receiver
+            .handle(&client, name(2), put_chunk(1, 3, 8), |_, _| Ok(()))
+            .unwrap();
+        assert_eq!(put_acks(&client.take()), vec![(3, 8)]);
+    }
+
+    #[test]
+    fn receiver_refuses_puts_beyond_its_limits() {
+        let mut receiver = PutReceiver::new(8);
+        let client = Sink::default();
+        let handle = |receiver: &mut PutReceiver, chunk| {
+            receiver.handle(&client, name(2), chunk, |_, _| Ok(()))
+        };
+        assert!(handle(&mut receiver, put_chunk(0, MAX_PUT_CHUNKS + 1, 8)).is_err());
+        assert!(handle(&mut receiver, put_chunk(0, 0, 8)).is_err());
+        assert!(handle(&mut receiver, put_chunk(0, 4, DEFAULT_CHUNK_SIZE + 1)).is_err());
+        assert!(receiver.puts.is_empty());
+
+        handle(&mut receiver, put_chunk(0, 4, 8)).unwrap();
+        // a chunk that disagrees on the size of the Put
+        assert!(handle(&mut receiver, put_chunk(1, MAX_PUT_CHUNKS, 8)).is_err());
+        assert_eq!(receiver.puts[&(name(2), 1)].next_seq, 1);
+    }
+
+    #[test]
+    fn paced_put_does_not_wait_for_the_first_ack() {
+        let mut receiver = PutReceiver::default();
+        let sink = Sink::default();
+        let data = object(40 * DEFAULT_CHUNK_SIZE);
+        let mut client = Loopback::new(|message| {
+            receiver
+                .handle(&sink, name(2), message, |_, _| Ok(()))
+                .unwrap();
+            sink.take()
+        });
+        put_paced(&mut client, name(1), name(9), &data).unwrap();
+        let reports = client.own.reports.borrow();
+        let last = reports.last().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.loss_events, 0);
+        assert_eq!(last.window, DEFAULT_PUT_BUDGET);
+        assert_eq!(last.delivered_bytes, data.len() as u64);
+    }
+
+    #[test]
+    fn paced_put_recovers_lost_chunks() {
+        let mut receiver = PutReceiver::new(8);
+        let sink = Sink::default();
+        let mut stored = None;
+        let data = object(20 * DEFAULT_CHUNK_SIZE);
+        let mut dropped = false;
+        let mut client = Loopback::new(|message| {
+            if !dropped && matches!(message, StreamMessage::PutChunk { seq: 5, .. }) {
+                dropped = true;
+                return vec![];
+            }
+            receiver
+                .handle(&sink, name(2), message, |object, data| {
+                    stored = Some((object, data));
+                    Ok(())
+                })
+                .unwrap();
+            sink.take()
+        });
+        put_paced(&mut client, name(1), name(9), &data).unwrap();
+        assert_eq!(client.own.reports.borrow().last().unwrap().loss_events, 1);
+        // the sender had chunks left each time it had the whole pace in flight
+        assert!(*client.own.paced_stalls.borrow().last().unwrap() > 0);
+        drop(client);
+        assert_eq!(stored, Some((name(9), data.to_vec())));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use anyhow::{ensure, Result};
+use gdp_proto::{content_hash, GdpName, SignedUsageReport};
+use signatory::ed25519::{Signature, VerifyingKey};
+use signatory::signature::Verifier;
+
+/// Check that `signed` was signed by the switch whose public key is `pub_key`,
+/// and that it has not been changed since
+pub fn verify_usage_report(signed: &SignedUsageReport, pub_key: [u8; 32]) -> Result<()> {
+    ensure!(
+        content_hash(&pub_key) == signed.report.switch,
+        "public key does not match the switch that made the report"
+    );
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&signed.signature.0);
+    signature[32..].copy_from_slice(&signed.signature.1);
+    let verifying_key = VerifyingKey::from_bytes(&pub_key)?;
+    Ok(verifying_key.verify(
+        &bincode::serialize(&signed.report)?,
+        &Signature::new(signature),
+    )?)
+}
+
+/// Verifies the usage reports of switches as they are fetched, and notes any that went missing
+#[derive(Default)]
+pub struct UsageAudit {
+    next_sequence: HashMap<GdpName, u64>,
+    missing: Vec<(GdpName, u64)>,
+}
+
+impl UsageAudit {
+    /// Verify a report, expected to be the one after the last accepted from the same switch.
+    /// Reports that were skipped over are recorded as missing.
+    pub fn accept(&mut self, signed: &SignedUsageReport, pub_key: [u8; 32]) -> Result<()> {
+        verify_usage_report(signed, pub_key)?;
+        let report = &signed.report;
+        let next = self
+            .next_sequence
+            .entry(report.switch)
+            .or_insert(report.sequence);
+        ensure!(
+            report.sequence >= *next,
+            "usage report {} was already accepted or skipped over",
+            report.sequence
+        );
+        self.missing
+            .extend((*next..report.sequence).map(|sequence| (report.switch, sequence)));
+        *next = report.sequence + 1;
+        Ok(())
+    }
+
+    /// The sequence number to ask a switch for reports after, if any of its reports were accepted
+    pub fn last_accepted(&self, switch: &GdpName) -> Option<u64> {
+        self.next_sequence.get(switch).map(|next| next - 1)
+    }
+
+    /// Reports that were skipped over, by switch and sequence number. Their usage is unaccounted
+    /// for, whether the switch lost them (e.g. by restarting) or withheld them.
+    pub fn missing(&self) -> &[(GdpName, u64)] {
+        &self.missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gdp_proto::UsageReport;
+    use gdp_testutil::name;
+    use signatory::ed25519::{SigningKey, ALGORITHM_ID};
+    use signatory::pkcs8::{FromPrivateKey, PrivateKeyInfo};
+    use signatory::signature::Signer;
+
+    use super::*;
+
+    const PRIVATE_KEY: [u8; 32] = [7; 32];
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_pkcs8_private_key_info(PrivateKeyInfo::new(ALGORITHM_ID, &PRIVATE_KEY))
+            .unwrap()
+    }
+
+    fn pub_key() -> [u8; 32] {
+        signing_key().verifying_key().to_bytes()
+    }
+
+    fn signed(sequence: u64) -> SignedUsageReport {
+        let report = UsageReport {
+            switch: content_hash(&pub_key()),
+            sequence,
+            period_start: 10 * sequence,
+            period_end: 10 * (sequence + 1),
+            tenants: Vec::new(),
+        };
+        let signature = signing_key()
+            .sign(&bincode::serialize(&report).unwrap())
+            .to_bytes();
+        let mut halves = ([0; 32], [0; 32]);
+        halves.0.copy_from_slice(&signature[..32]);
+        halves.1.copy_from_slice(&signature[32..]);
+        SignedUsageReport {
+            report,
+            signature: halves,
+        }
+    }
+
+    #[test]
+    fn reports_verify_only_as_signed() {
+        assert!(verify_usage_report(&signed(0), pub_key()).is_ok());
+
+        let mut changed = signed(0);
+        changed.report.period_end += 1;
+        assert!(verify_usage_report(&changed, pub_key()).is_err());
+
+        let mut other_switch = signed(0);
+        other_switch.report.switch = name(1);
+        assert!(verify_usage_report(&other_switch, pub_key()).is_err());
+        assert!(verify_usage_report(&signed(0), [1; 32]).is_err());
+    }
+
+    #[test]
+    fn audit_notes_skipped_reports() {
+        let mut audit = UsageAudit::default();
+        let switch = content_hash(&pub_key());
+        assert_eq!(audit.last_accepted(&switch), None);
+
+        audit.accept(&signed(3), pub_key()).unwrap();
+        audit.accept(&signed(4), pub_key()).unwrap();
+        audit.accept(&signed(7), pub_key()).unwrap();
+        assert_eq!(audit.last_accepted(&switch), Some(7));
+        assert_eq!(audit.missing(), &[(switch, 5), (switch, 6)]);
+
+        // a report accepted or skipped over already is refused
+        assert!(audit.accept(&signed(7), pub_key()).is_err());
+        assert!(audit.accept(&signed(5), pub_key()).is_err());
+        assert_eq!(audit.last_accepted(&switch), Some(7));
+    }
+}
@@ -0,0 +1,484 @@
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use gdp_client::{
+    is_timeout, verify_content_hash, write_extensions, CertContents, CertDest, DirectClient,
+    FinKind, FinPayload, GdpAction, GdpHeader, GdpName, HeaderExtension, NackPayload, NackReason,
+    RibQuery,
+};
+
+/// An extension kind that no version of the protocol uses, which switches must skip
+const UNKNOWN_EXTENSION: u8 = 0xee;
+/// An action that no version of the protocol uses
+const UNKNOWN_ACTION: u8 = 0xfe;
+const PROBE_DATA: &[u8] = b"gdp conformance probe";
+
+/// What the cases run against
+pub struct Target {
+    pub client: DirectClient,
+    /// A second client at another address, for the cases whose packets must be delivered
+    pub peer: Option<DirectClient>,
+    pub our_ip: Ipv4Addr,
+    pub switch_name: GdpName,
+    /// The IP and name of the RIB that the target registers with
+    pub rib: Option<(Ipv4Addr, GdpName)>,
+    /// How long to wait for an answer before taking it that there is none
+    pub wait: Duration,
+}
+
+pub enum Verdict {
+    /// What the target did, as the protocol requires
+    Pass(String),
+    /// What the run was not given for the case to be tried
+    Skip(&'static str),
+}
+
+pub struct Case {
+    pub name: &'static str,
+    pub run: fn(&Target) -> Result<Verdict>,
+}
+
+/// Every case, in the order they are run and reported. A case that fails with an error is
+/// non-conformant; the error says how
+pub const CASES: &[Case] = &[
+    Case {
+        name: "nack/no-route",
+        run: no_route,
+    },
+    Case {
+        name: "nack/loop",
+        run: looping,
+    },
+    Case {
+        name: "nack/bad-cert",
+        run: bad_cert,
+    },
+    Case {
+        name: "ttl/expired",
+        run: ttl_expired,
+    },
+    Case {
+        name: "ttl/zero",
+        run: ttl_zero,
+    },
+    Case {
+        name: "header/unknown-extension",
+        run: unknown_extension,
+    },
+    Case {
+        name: "header/bad-magic",
+        run: bad_magic,
+    },
+    Case {
+        name: "header/bad-length",
+        run: bad_header_len,
+    },
+    Case {
+        name: "action/unknown",
+        run: unknown_action,
+    },
+    Case {
+        name: "deliver/forward",
+        run: deliver_forward,
+    },
+    Case {
+        name: "deliver/unknown-extension",
+        run: deliver_unknown_extension,
+    },
+    Case {
+        name: "deliver/fin",
+        run: deliver_fin,
+    },
+    Case {
+        name: "rib/registered",
+        run: rib_registered,
+    },
+    Case {
+        name: "rib/metas",
+        run: rib_metas,
+    },
+    Case {
+        name: "rib/miss",
+        run: rib_miss,
+    },
+];
+
+/// The first packet to reach `client` with `trace_id` within `wait`; any others are skipped
+fn answer_to(
+    client: &DirectClient,
+    trace_id: u64,
+    wait: Duration,
+) -> Result<Option<(GdpHeader, Box<[u8]>)>> {
+    let deadline = Instant::now() + wait;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        client.set_read_timeout(Some(left))?;
+        match client.recv_raw() {
+            Ok((header, payload)) if u64::from(header.trace_id) == trace_id => {
+                return Ok(Some((header, payload)))
+            }
+            Ok(_) => {}
+            Err(err) if is_timeout(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The data of an answer, checked against its content hash
+fn data_of<'a>(header: &GdpHeader, payload: &'a [u8]) -> Result<&'a [u8]> {
+    let data_len = u16::from(header.data_len) as usize;
+    ensure!(
+        data_len <= payload.len(),
+        "answer shorter than its data length"
+    );
+    let data = &payload[..data_len];
+    verify_content_hash(&header.content_hash, data)?;
+    Ok(data)
+}
+
+fn action_name(action: u8) -> String {
+    GdpAction::try_from(action)
+        .map_or_else(|_| format!("action {}", action), |a| format!("{:?}", a))
+}
+
+/// Send `header` with `extensions` and `data`, and check that it is NACKed for `reason`
+fn expect_nack(
+    target: &Target,
+    header: GdpHeader,
+    extensions: &[u8],
+    data: &[u8],
+    certificates: bool,
+    reason: NackReason,
+) -> Result<Verdict> {
+    let trace_id = u64::from(header.trace_id);
+    target
+        .client
+        .send_crafted(&header, extensions, data, certificates)?;
+    let (nack, payload) = answer_to(&target.client, trace_id, target.wait)?.ok_or_else(|| {
+        anyhow!(
+            "no answer within {:?}, expected a {:?} NACK",
+            target.wait,
+            reason
+        )
+    })?;
+    ensure!(
+        nack.action == GdpAction::Nack as u8,
+        "answered with {}, expected a {:?} NACK",
+        action_name(nack.action),
+        reason
+    );
+    ensure!(
+        nack.dst == header.dst,
+        "the NACK names another destination than the packet's"
+    );
+    let got = NackPayload::parse(data_of(&nack, &payload)?)
+        .ok_or_else(|| anyhow!("NACKed without a reason, expected {:?}", reason))?
+        .reason;
+    ensure!(
+        got == reason,
+        "NACKed with {:?}, expected {:?}",
+        got,
+        reason
+    );
+    Ok(Verdict::Pass(format!(
+        "NACKed with {:?}, trace ID {:016x} kept",
+        reason, trace_id
+    )))
+}
+
+/// Send `header` and `extensions`, check that nothing comes back, then that the target still
+/// answers a well-formed packet. Returns whether it was NACKed rather than dropped, if `nack_ok`
+fn expect_dropped(
+    target: &Target,
+    header: GdpHeader,
+    extensions: &[u8],
+    nack_ok: bool,
+) -> Result<bool> {
+    let trace_id = u64::from(header.trace_id);
+    target
+        .client
+        .send_crafted(&header, extensions, PROBE_DATA, true)?;
+    let nacked = match answer_to(&target.client, trace_id, target.wait)? {
+        None => false,
+        Some((answer, _)) if nack_ok && answer.action == GdpAction::Nack as u8 => true,
+        Some((answer, _)) => bail!(
+            "answered with {}, expected the packet to be dropped",
+            action_name(answer.action)
+        ),
+    };
+    expect_nack(
+        target,
+        expiring(target)?,
+        &[],
+        PROBE_DATA,
+        true,
+        NackReason::TtlExpired,
+    )
+    .map_err(|err| err.context("the target stopped answering afterwards"))?;
+    Ok(nacked)
+}
+
+/// A data packet to ourselves, which the target has a route for once we announced, that expires
+/// at the target
+fn expiring(target: &Target) -> Result<GdpHeader> {
+    let mut header =
+        target
+            .client
+            .header(GdpAction::Forward, target.client.gdp_name(), PROBE_DATA)?;
+    header.ttl = 1;
+    Ok(header)
+}
+
+fn unknown_extension_bytes() -> Result<Vec<u8>> {
+    write_extensions(&[HeaderExtension {
+        kind: UNKNOWN_EXTENSION,
+        value: b"skip me".to_vec(),
+    }])
+}
+
+fn no_route(target: &Target) -> Result<Verdict> {
+    let header = target
+        .client
+        .header(GdpAction::Forward, rand::random(), PROBE_DATA)?;
+    expect_nack(target, header, &[], PROBE_DATA, true, NackReason::NoRoute)
+}
+
+fn looping(target: &Target) -> Result<Verdict> {
+    // we are reached at the address the packet came from, so the target would send it back
+    let header = target
+        .client
+        .header(GdpAction::Forward, target.client.gdp_name(), PROBE_DATA)?;
+    expect_nack(target, header, &[], PROBE_DATA, true, NackReason::NoRoute)
+}
+
+fn bad_cert(target: &Target) -> Result<Verdict> {
+    let header = target
+        .client
+        .header(GdpAction::Forward, rand::random(), PROBE_DATA)?;
+    expect_nack(target, header, &[], PROBE_DATA, false, NackReason::BadCert)
+}
+
+fn ttl_expired(target: &Target) -> Result<Verdict> {
+    expect_nack(
+        target,
+        expiring(target)?,
+        &[],
+        PROBE_DATA,
+        true,
+        NackReason::TtlExpired,
+    )
+}
+
+fn ttl_zero(target: &Target) -> Result<Verdict> {
+    let mut header = expiring(target)?;
+    header.ttl = 0;
+    expect_nack(
+        target,
+        header,
+        &[],
+        PROBE_DATA,
+        true,
+        NackReason::TtlExpired,
+    )
+}
+
+fn unknown_extension(target: &Target) -> Result<Verdict> {
+    let extensions = unknown_extension_bytes()?;
+    let mut header = expiring(target)?;
+    header.header_len = (GdpHeader::LEN + extensions.len() as u16).into();
+    expect_nack(
+        target,
+        header,
+        &extensions,
+        PROBE_DATA,
+        true,
+        NackReason::TtlExpired,
+    )?;
+    Ok(Verdict::Pass(format!(
+        "extension {} skipped, the packet handled as without it",
+        UNKNOWN_EXTENSION
+    )))
+}
+
+fn bad_magic(target: &Target) -> Result<Verdict> {
+    let mut header = expiring(target)?;
+    header.field = 0.into();
+    expect_dropped(target, header, &[], false)?;
+    Ok(Verdict::Pass(
+        "dropped, and the target still answers".into(),
+    ))
+}
+
+fn bad_header_len(target: &Target) -> Result<Verdict> {
+    let mut header = expiring(target)?;
+    header.header_len = u16::MAX.into();
+    expect_dropped(target, header, &[], false)?;
+    Ok(Verdict::Pass(
+        "dropped, and the target still answers".into(),
+    ))
+}
+
+fn unknown_action(target: &Target) -> Result<Verdict> {
+    let mut header =
+        target
+            .client
+            .header(GdpAction::Forward, target.client.gdp_name(), PROBE_DATA)?;
+    header.action = UNKNOWN_ACTION;
+    let detail = match expect_dropped(target, header, &[], true)? {
+        // switches may be configured to NACK what they do not understand
+        true => "NACKed, and the target still answers",
+        false => "dropped, and the target still answers",
+    };
+    Ok(Verdict::Pass(detail.into()))
+}
+
+/// Send `header`, `extensions` and `data` to the peer, and check that it arrives as it was sent,
+/// one hop further on
+fn expect_delivered(
+    target: &Target,
+    peer: &DirectClient,
+    header: GdpHeader,
+    extensions: &[u8],
+    data: &[u8],
+) -> Result<GdpHeader> {
+    let trace_id = u64::from(header.trace_id);
+    target
+        .client
+        .send_crafted(&header, extensions, data, true)?;
+    let (delivered, payload) = answer_to(peer, trace_id, target.wait)?
+        .ok_or_else(|| anyhow!("not delivered within {:?}", target.wait))?;
+    ensure!(
+        delivered.action == header.action,
+        "delivered as {}, sent as {}",
+        action_name(delivered.action),
+        action_name(header.action)
+    );
+    ensure!(delivered.src == header.src, "delivered with another source");
+    ensure!(
+        data_of(&delivered, &payload)? == data,
+        "delivered with other data"
+    );
+    ensure!(
+        delivered.ttl == header.ttl - 1,
+        "delivered with TTL {}, sent with {} across one switch",
+        delivered.ttl,
+        header.ttl
+    );
+    ensure!(
+        delivered.last_hop == target.switch_name,
+        "the last hop of the delivered packet is not the switch"
+    );
+    Ok(delivered)
+}
+
+fn deliver_forward(target: &Target) -> Result<Verdict> {
+    let peer = match &target.peer {
+        Some(peer) => peer,
+        None => return Ok(Verdict::Skip("needs --peer-ip")),
+    };
+    let header = target
+        .client
+        .header(GdpAction::Forward, peer.gdp_name(), PROBE_DATA)?;
+    expect_delivered(target, peer, header, &[], PROBE_DATA)?;
+    Ok(Verdict::Pass(
+        "delivered intact, TTL decremented, last hop set".into(),
+    ))
+}
+
+fn deliver_unknown_extension(target: &Target) -> Result<Verdict> {
+    let peer = match &target.peer {
+        Some(peer) => peer,
+        None => return Ok(Verdict::Skip("needs --peer-ip")),
+    };
+    let extensions = unknown_extension_bytes()?;
+    let mut header = target
+        .client
+        .header(GdpAction::Forward, peer.gdp_name(), PROBE_DATA)?;
+    header.header_len = (GdpHeader::LEN + extensions.len() as u16).into();
+    expect_delivered(target, peer, header, &extensions, PROBE_DATA)?;
+    Ok(Verdict::Pass("delivered with its data intact".into()))
+}
+
+fn deliver_fin(target: &Target) -> Result<Verdict> {
+    let peer = match &target.peer {
+        Some(peer) => peer,
+        None => return Ok(Verdict::Skip("needs --peer-ip")),
+    };
+    let payload = FinPayload {
+        kind: FinKind::Close,
+    }
+    .to_bytes();
+    let header = target
+        .client
+        .header(GdpAction::Fin, peer.gdp_name(), &payload)?;
+    expect_delivered(target, peer, header, &[], &payload)?;
+    Ok(Verdict::Pass("delivered to the end of the flow".into()))
+}
+
+fn rib_registered(target: &Target) -> Result<Verdict> {
+    let (rib_ip, rib_name) = match target.rib {
+        Some(rib) => rib,
+        None => return Ok(Verdict::Skip("needs --rib-ip and --rib-name")),
+    };
+    let ours = target.client.gdp_name();
+    let certs = target.client.resolve(rib_ip, rib_name, ours)?;
+    let registered = certs.iter().any(|cert| match &cert.contents {
+        CertContents::RtCert(cert) => {
+            cert.base == ours && cert.proxy == CertDest::IpAddr(target.our_ip)
+        }
+        CertContents::AttrCert(_) => false,
+    });
+    ensure!(
+        registered,
+        "the RIB has no route certificate to {} for us ({} certificates)",
+        target.our_ip,
+        certs.len()
+    );
+    Ok(Verdict::Pass(
+        "our registration reached the RIB through the target".into(),
+    ))
+}
+
+fn rib_metas(target: &Target) -> Result<Verdict> {
+    let (rib_ip, rib_name) = match target.rib {
+        Some(rib) => rib,
+        None => return Ok(Verdict::Skip("needs --rib-ip and --rib-name")),
+    };
+    let ours = target.client.gdp_name();
+    let query = RibQuery {
+        metas_for_names: vec![ours],
+        ..Default::default()
+    };
+    let response = target.client.query_rib(rib_ip, rib_name, &query)?;
+    ensure!(
+        response.metas.iter().any(|meta| meta.hash() == ours),
+        "the RIB has no key for us"
+    );
+    Ok(Verdict::Pass("the RIB has our key".into()))
+}
+
+fn rib_miss(target: &Target) -> Result<Verdict> {
+    let (rib_ip, rib_name) = match target.rib {
+        Some(rib) => rib,
+        None => return Ok(Verdict::Skip("needs --rib-ip and --rib-name")),
+    };
+    let unknown: GdpName = rand::random();
+    let query = RibQuery {
+        ips_for_names: vec![unknown],
+        ..Default::default()
+    };
+    let response = target.client.query_rib(rib_ip, rib_name, &query)?;
+    ensure!(
+        response.misses.contains(&unknown),
+        "the RIB does not report an unknown name as a miss"
+    );
+    ensure!(
+        response.certs.is_empty(),
+        "the RIB answered with certificates for an unknown name"
+    );
+    Ok(Verdict::Pass("reported as a miss".into()))
+}
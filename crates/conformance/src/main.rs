@@ -0,0 +1,175 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::process;
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::{clap_app, ArgMatches};
+use gdp_client::{DirectClient, GdpName, NAME_LEN, RIB_PORT};
+
+use crate::cases::{Target, Verdict, CASES};
+
+mod cases;
+
+/*
+   Checks a switch against the protocol as this crate's switch implements it, so that other
+   implementations (the C router, students' switches) can be held to the same behaviour:
+   - the suite attaches to the target as a direct client (see the client's direct.rs), from an
+     ordinary socket, and registers itself through the target (a signed RibRegister) so that the
+     target has a route back to it
+   - each case sends a crafted packet and checks what comes back, or that nothing does: the
+     NACKs and their reasons, TTL handling, the handling of malformed and unknown packets, and,
+     given a second address to receive on and the target's RIB, delivery and the RIB's answers
+   - the report lists every case as passed, failed (saying how) or skipped (saying what it
+     needs), and the suite exits non-zero if any case failed
+   - only the target's own behaviour is tried: a switch in the middle of a chaos drill, or with
+     an unknown-action policy in actions.toml other than dropping or NACKing, fails cases that
+     it would otherwise pass
+*/
+
+/// How long the target gets to learn our registration before the cases run
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+fn parse_name(hex: &str) -> Result<GdpName> {
+    ensure!(
+        hex.len() == 2 * NAME_LEN && hex.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        "GdpNames are {} hex characters",
+        2 * NAME_LEN
+    );
+    let mut name = [0u8; NAME_LEN];
+    for (i, byte) in name.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).context("invalid GdpName")?;
+    }
+    Ok(name)
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    parse_name(hex).context("dTLS keys are 64 hex characters")
+}
+
+fn ip_arg(args: &ArgMatches, name: &str) -> Result<Option<Ipv4Addr>> {
+    args.value_of(name)
+        .map(|ip| {
+            ip.parse()
+                .with_context(|| format!("invalid --{}", name.replace('_', "-")))
+        })
+        .transpose()
+}
+
+/// A client of the target at `ip`, registered with the target's RIB as reached there
+fn attach(
+    args: &ArgMatches,
+    ip: Ipv4Addr,
+    switch_ip: Ipv4Addr,
+    switch_name: GdpName,
+) -> Result<DirectClient> {
+    let mut client = DirectClient::new(
+        SocketAddrV4::new(ip, RIB_PORT),
+        switch_ip,
+        switch_name,
+        // a fresh identity for every run, so that nothing is left over from earlier ones
+        rand::random(),
+    )?;
+    if let Some(key) = args.value_of("dtls_key") {
+        client.set_dtls_key(parse_key(key)?);
+    }
+    client.set_plaintext(args.is_present("plaintext"));
+    client.announce(ip)?;
+    Ok(client)
+}
+
+fn main() -> Result<()> {
+    let args = clap_app!(gdp_conformance =>
+        (@arg switch_ip: -s --("switch-ip") +takes_value +required "The IP of the switch to check")
+        (@arg switch_name: -n --("switch-name") +takes_value +required "The GdpName of the switch, in hex")
+        (@arg local_ip: -l --("local-ip") +takes_value +required "Our IP, which the switch sends to on the RIB port")
+        (@arg peer_ip: --("peer-ip") +takes_value "A second IP of ours, to check delivery to")
+        (@arg rib_ip: --("rib-ip") +takes_value "The IP of the switch's RIB, to check its answers")
+        (@arg rib_name: --("rib-name") +takes_value "The GdpName of the switch's RIB, in hex")
+        (@arg dtls_key: -k --("dtls-key") +takes_value "The switch's dTLS key, in hex (default: the example key)")
+        (@arg plaintext: --plaintext "Send GDP straight over UDP, for switches without the dTLS layer")
+        (@arg wait: -w --wait +takes_value "How long to wait for an answer, in ms (default: 3000)")
+    )
+    .get_matches();
+
+    // required, so clap has made sure they are there
+    let switch_ip = ip_arg(&args, "switch_ip")?.unwrap();
+    let switch_name = parse_name(args.value_of("switch_name").unwrap())?;
+    let local_ip = ip_arg(&args, "local_ip")?.unwrap();
+    let rib = match (ip_arg(&args, "rib_ip")?, args.value_of("rib_name")) {
+        (Some(ip), Some(name)) => Some((ip, parse_name(name)?)),
+        (None, None) => None,
+        _ => bail!("--rib-ip and --rib-name go together"),
+    };
+    let wait = match args.value_of("wait") {
+        Some(ms) => Duration::from_millis(ms.parse().context("invalid --wait")?),
+        None => Duration::from_secs(3),
+    };
+
+    let client = attach(&args, local_ip, switch_ip, switch_name)?;
+    let peer = ip_arg(&args, "peer_ip")?
+        .map(|ip| attach(&args, ip, switch_ip, switch_name))
+        .transpose()?;
+    let target = Target {
+        client,
+        peer,
+        our_ip: local_ip,
+        switch_name,
+        rib,
+        wait,
+    };
+    sleep(SETTLE_TIME);
+
+    println!("conformance of the switch at {}:", switch_ip);
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for case in CASES {
+        match (case.run)(&target) {
+            Ok(Verdict::Pass(detail)) => {
+                passed += 1;
+                println!("  PASS  {:<28} {}", case.name, detail);
+            }
+            Ok(Verdict::Skip(needs)) => {
+                skipped += 1;
+                println!("  SKIP  {:<28} {}", case.name, needs);
+            }
+            Err(err) => {
+                failed += 1;
+                println!("  FAIL  {:<28} {:#}", case.name, err);
+            }
+        }
+    }
+    println!(
+        "{} passed, {} failed, {} skipped of {} cases",
+        passed,
+        failed,
+        skipped,
+        CASES.len()
+    );
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_and_keys() {
+        let hex = "ab".repeat(NAME_LEN);
+        assert_eq!(parse_name(&hex).unwrap(), [0xab; NAME_LEN]);
+        assert_eq!(parse_key(&"07".repeat(32)).unwrap(), [7; 32]);
+    }
+
+    #[test]
+    fn refuses_what_is_not_hex() {
+        assert!(parse_name("ab").is_err());
+        assert!(parse_name(&"zz".repeat(NAME_LEN)).is_err());
+        // as long as a name, in bytes, but with characters that straddle byte pairs
+        let straddling = format!("é{}", "a".repeat(2 * NAME_LEN - 2));
+        assert_eq!(straddling.len(), 2 * NAME_LEN);
+        assert!(parse_name(&straddling).is_err());
+        assert!(parse_key(&straddling).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use std::{fs, thread};
+
+use anyhow::{ensure, Context, Result};
+use clap::clap_app;
+use gdp_client::{is_timeout, GdpClient, GdpName, NAME_LEN};
+use rumqttc::{matches, Client, Connection, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+
+/*
+   Bridges an MQTT broker and the GDP network, for devices that only speak MQTT:
+   - messages published on a topic that matches one of the `publish` filters are sent to the
+     GDP name of that filter, as GDP data packets from the gateway's own name
+   - GDP packets delivered to the gateway are published on the topic of the `deliver` entry for
+     their source, or on `<deliver_prefix>/<source name in hex>` if there is none
+   The gateway attaches to its sidecar like any other client, so it gets a name of its own, and
+   GDP publishers subscribe MQTT devices to their data by sending it to that name.
+*/
+
+/// How long a GDP receive waits before the gateway checks for MQTT messages again
+const GDP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait before reconnecting after the broker connection failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Requests buffered between the gateway and the MQTT event loop
+const MQTT_CHANNEL_CAPACITY: usize = 64;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct PublishRoute {
+    /// An MQTT topic filter, which may use the + and # wildcards
+    topic: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct DeliverRoute {
+    name: String,
+    topic: String,
+}
+
+#[derive(Deserialize)]
+struct GatewayConfig {
+    sidecar_ip: Ipv4Addr,
+    local_port: u16,
+    broker_host: String,
+    broker_port: u16,
+    client_id: String,
+    /// Packets from sources without a `deliver` entry are published under this prefix, or
+    /// dropped if there is none
+    deliver_prefix: Option<String>,
+    #[serde(default)]
+    publish: Vec<PublishRoute>,
+    #[serde(default)]
+    deliver: Vec<DeliverRoute>,
+}
+
+fn parse_name(hex: &str) -> Result<GdpName> {
+    ensure!(
+        hex.len() == 2 * NAME_LEN && hex.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        "GdpNames are {} hex characters",
+        2 * NAME_LEN
+    );
+    let mut name = [0u8; NAME_LEN];
+    for (i, byte) in name.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).context("invalid GdpName")?;
+    }
+    Ok(name)
+}
+
+fn format_name(name: &GdpName) -> String {
+    name.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where MQTT messages and GDP packets go, as configured
+struct Routes {
+    publish: Vec<(String, GdpName)>,
+    deliver: HashMap<GdpName, String>,
+    deliver_prefix: Option<String>,
+}
+
+impl Routes {
+    fn new(config: &GatewayConfig) -> Result<Self> {
+        let publish = config
+            .publish
+            .iter()
+            .map(|route| Ok((route.topic.clone(), parse_name(&route.name)?)))
+            .collect::<Result<_>>()?;
+        let deliver = config
+            .deliver
+            .iter()
+            .map(|route| Ok((parse_name(&route.name)?, route.topic.clone())))
+            .collect::<Result<_>>()?;
+        Ok(Routes {
+            publish,
+            deliver,
+            deliver_prefix: config.deliver_prefix.clone(),
+        })
+    }
+
+    /// The names that a message published on `topic` goes to, one per matching filter
+    fn names_for(&self, topic: &str) -> impl Iterator<Item = GdpName> + '_ {
+        let topic = topic.to_owned();
+        self.publish
+            .iter()
+            .filter(move |(filter, _)| matches(&topic, filter))
+            .map(|(_, name)| *name)
+    }
+
+    fn topic_for(&self, src: &GdpName) -> Option<String> {
+        match self.deliver.get(src) {
+            Some(topic) => Some(topic.clone()),
+            None => self
+                .deliver_prefix
+                .as_ref()
+                .map(|prefix| format!("{}/{}", prefix, format_name(src))),
+        }
+    }
+}
+
+/// Drives the broker connection, passing on every message published on our subscriptions.
+/// The broker forgets subscriptions with the session, so they are renewed on every connection.
+fn run_mqtt(
+    mut client: Client,
+    mut connection: Connection,
+    filters: Vec<String>,
+    messages: Sender<(String, Vec<u8>)>,
+) {
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("connected to the broker");
+                for filter in &filters {
+                    if let Err(err) = client.subscribe(filter.as_str(), QoS::AtLeastOnce) {
+                        eprintln!("failed to subscribe to {}: {}", filter, err);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if messages
+                    .send((publish.topic, publish.payload.to_vec()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("broker connection failed: {}", err);
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+fn run_gateway(
+    mut gdp: GdpClient,
+    mut mqtt: Client,
+    messages: Receiver<(String, Vec<u8>)>,
+    routes: Routes,
+) -> Result<()> {
+    gdp.set_read_timeout(Some(GDP_POLL_INTERVAL))?;
+    loop {
+        for (topic, payload) in messages.try_iter() {
+            for name in routes.names_for(&topic) {
+                if let Err(err) = gdp.send_packet(name, &payload) {
+                    eprintln!(
+                        "failed to send {} to {}: {:#}",
+                        topic,
+                        format_name(&name),
+                        err
+                    );
+                }
+            }
+        }
+        let (src, payload) = match gdp.recv_from() {
+            Ok(packet) => packet,
+            Err(err) if is_timeout(&err) => continue,
+            Err(err) => {
+                eprintln!("dropping GDP packet: {:#}", err);
+                continue;
+            }
+        };
+        let topic = match routes.topic_for(&src) {
+            Some(topic) => topic,
+            None => {
+                eprintln!("no topic for packets from {}", format_name(&src));
+                continue;
+            }
+        };
+        // while the broker is unreachable, packets are dropped rather than held up
+        if let Err(err) = mqtt.try_publish(&topic, QoS::AtLeastOnce, false, payload.into_vec()) {
+            eprintln!("failed to publish to {}: {}", topic, err);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = clap_app!(gdp_mqtt_gateway =>
+        (@arg config: -c --config +takes_value "The gateway configuration (default: mqtt.toml)")
+    )
+    .get_matches();
+    let path = args.value_of("config").unwrap_or("mqtt.toml");
+    let config: GatewayConfig = toml::from_str(&fs::read_to_string(path)?)?;
+    let routes = Routes::new(&config)?;
+
+    let mut options = MqttOptions::new(
+        config.client_id.clone(),
+        config.broker_host.clone(),
+        config.broker_port,
+    );
+    options.set_keep_alive(KEEP_ALIVE);
+    let (mqtt, connection) = Client::new(options, MQTT_CHANNEL_CAPACITY);
+    let (messages_tx, messages_rx) = mpsc::channel();
+    let filters = config
+        .publish
+        .iter()
+        .map(|route| route.topic.clone())
+        .collect();
+    let subscriber = mqtt.clone();
+    thread::spawn(move || run_mqtt(subscriber, connection, filters, messages_tx));
+
+    let gdp = GdpClient::new(config.sidecar_ip, config.local_port)?;
+    run_gateway(gdp, mqtt, messages_rx, routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_of(byte: u8) -> String {
+        format_name(&[byte; NAME_LEN])
+    }
+
+    fn config(
+        publish: &[(&str, u8)],
+        deliver: &[(u8, &str)],
+        prefix: Option<&str>,
+    ) -> GatewayConfig {
+        GatewayConfig {
+            sidecar_ip: Ipv4Addr::LOCALHOST,
+            local_port: 27185,
+            broker_host: "localhost".to_owned(),
+            broker_port: 1883,
+            client_id: "test".to_owned(),
+            deliver_prefix: prefix.map(str::to_owned),
+            publish: publish
+                .iter()
+                .map(|(topic, name)| PublishRoute {
+                    topic: (*topic).to_owned(),
+                    name: hex_of(*name),
+                })
+                .collect(),
+            deliver: deliver
+                .iter()
+                .map(|(name, topic)| DeliverRoute {
+                    name: hex_of(*name),
+                    topic: (*topic).to_owned(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn names_round_trip_through_hex() {
+        let name = [0xa5; NAME_LEN];
+        assert_eq!(parse_name(&format_name(&name)).unwrap(), name);
+        assert_eq!(
+            parse_name(&hex_of(0xAB).to_uppercase()).unwrap(),
+            [0xab; NAME_LEN]
+        );
+    }
+
+    #[test]
+    fn malformed_names_are_refused() {
+        let valid = hex_of(1);
+        assert!(parse_name(&valid[1..]).is_err());
+        assert!(parse_name(&format!("{}00", valid)).is_err());
+        assert!(parse_name(&format!("+{}", &valid[1..])).is_err());
+        assert!(parse_name(&format!("{}zz", &valid[2..])).is_err());
+        // a multi-byte character in place of two hex digits must not split a character
+        assert!(parse_name(&format!("0é{}", &valid[3..])).is_err());
+    }
+
+    #[test]
+    fn messages_go_to_every_matching_filter() {
+        let routes = Routes::new(&config(
+            &[("sensors/+/temperature", 1), ("sensors/#", 2), ("other", 3)],
+            &[],
+            None,
+        ))
+        .unwrap();
+        let names = |topic| routes.names_for(topic).collect::<Vec<_>>();
+        assert_eq!(
+            names("sensors/kitchen/temperature"),
+            vec![[1; NAME_LEN], [2; NAME_LEN]]
+        );
+        assert_eq!(names("sensors/kitchen/humidity"), vec![[2; NAME_LEN]]);
+        assert!(names("actuators/kitchen").is_empty());
+    }
+
+    #[test]
+    fn packets_are_published_on_their_source_topic() {
+        let routes = Routes::new(&config(&[], &[(1, "gdp/thermostat")], Some("gdp"))).unwrap();
+        assert_eq!(
+            routes.topic_for(&[1; NAME_LEN]).as_deref(),
+            Some("gdp/thermostat")
+        );
+        assert_eq!(
+            routes.topic_for(&[2; NAME_LEN]),
+            Some(format!("gdp/{}", hex_of(2)))
+        );
+        let routes = Routes::new(&config(&[], &[(1, "gdp/thermostat")], None)).unwrap();
+        assert_eq!(routes.topic_for(&[2; NAME_LEN]), None);
+    }
+
+    #[test]
+    fn routes_with_malformed_names_are_refused() {
+        let mut config = config(&[("sensors/#", 1)], &[], None);
+        config.publish[0].name.pop();
+        assert!(Routes::new(&config).is_err());
+    }
+
+    #[test]
+    fn the_sample_configuration_loads() {
+        let config: GatewayConfig = toml::from_str(include_str!("../mqtt.toml")).unwrap();
+        let routes = Routes::new(&config).unwrap();
+        assert_eq!(routes.names_for("sensors/attic/temperature").count(), 1);
+    }
+}
@@ -0,0 +1,113 @@
+use std::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::names::{name_hash, GdpName};
+
+/*
+   Certificates, as switches, RIBs and clients exchange them. A certificate is signed by the
+   owner of its name, with the Ed25519 key that the name is the hash of, over the bincode of its
+   contents. Signing and checking them is left to their users, which bring their own crypto
+*/
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct GdpMeta {
+    pub pub_key: [u8; 32],
+}
+
+impl GdpMeta {
+    pub fn hash(&self) -> GdpName {
+        name_hash(&self.pub_key)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Certificate {
+    pub contents: CertContents,
+    pub signature: SerializableSignature,
+}
+
+/// An Ed25519 signature, in two halves as serde only takes arrays of up to 32 elements
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerializableSignature([u8; 32], [u8; 32]);
+
+impl From<[u8; 64]> for SerializableSignature {
+    fn from(x: [u8; 64]) -> SerializableSignature {
+        let mut signature = SerializableSignature([0; 32], [0; 32]);
+        signature.0.copy_from_slice(&x[..32]);
+        signature.1.copy_from_slice(&x[32..]);
+        signature
+    }
+}
+
+impl From<SerializableSignature> for [u8; 64] {
+    fn from(x: SerializableSignature) -> [u8; 64] {
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&x.0);
+        signature[32..].copy_from_slice(&x.1);
+        signature
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum CertContents {
+    RtCert(RtCert),
+    AttrCert(AttrCert),
+}
+
+impl CertContents {
+    pub fn owner(&self) -> &GdpName {
+        match *self {
+            CertContents::RtCert(RtCert { ref base, .. }) => base,
+            CertContents::AttrCert(AttrCert { ref base, .. }) => base,
+        }
+    }
+
+    pub fn expiration_time(&self) -> u64 {
+        match *self {
+            CertContents::RtCert(RtCert {
+                expiration_time, ..
+            }) => expiration_time,
+            CertContents::AttrCert(AttrCert {
+                expiration_time, ..
+            }) => expiration_time,
+        }
+    }
+}
+
+/// `base` may be reached through `proxy`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RtCert {
+    pub base: GdpName,
+    pub proxy: CertDest,
+    pub expiration_time: u64,
+
+    /*
+        Whether we can send messages to the base via the proxy,
+        or if we should only accept messages *from* the proxy as being via the base
+    */
+    pub bidirectional: bool,
+}
+
+/// Tags (e.g. "camera", "lab=soda") that a GdpName advertises for service discovery
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AttrCert {
+    pub base: GdpName,
+    pub tags: Vec<String>,
+    pub expiration_time: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum CertDest {
+    GdpName(GdpName),
+    IpAddr(Ipv4Addr),
+}
+
+/// A block of certificates is laid out as bincode lays out the struct below, which is how
+/// clients and switches read it. It starts with the number of certificates as a little-endian
+/// u64, followed by each certificate, so that one can be appended by writing it at the end and
+/// counting it in the prefix.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CertificateBlock {
+    pub certificates: Vec<Certificate>,
+}
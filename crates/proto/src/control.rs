@@ -0,0 +1,377 @@
+use std::borrow::Cow;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use serde::{Deserialize, Serialize};
+
+use crate::names::GdpName;
+use crate::ops::TableExport;
+use crate::usage::SignedUsageReport;
+
+#[derive(Deserialize, Serialize)]
+pub struct ClientCommands {
+    pub messages: Vec<ClientCommand>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ClientResponses<'a> {
+    #[serde(borrow)]
+    pub messages: Vec<ClientResponse<'a>>,
+}
+
+/// New commands go at the end: bincode tags each variant with its position
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ClientCommand {
+    SetPort {
+        port: u16,
+    },
+    SetFlag {
+        name: String,
+        enabled: bool,
+    },
+    ListFlags,
+    Health,
+    /// Force traffic for `name` to `ip`, ahead of anything learned from the RIB.
+    /// Expires after `duration_secs`, or the switch's default if not given.
+    PinRoute {
+        name: GdpName,
+        ip: Ipv4Addr,
+        duration_secs: Option<u64>,
+    },
+    UnpinRoute {
+        name: GdpName,
+    },
+    DumpRoutes,
+    /// Drop everything forwarded to `ip`, as if that next hop had failed
+    FailNextHop {
+        ip: Ipv4Addr,
+        duration_secs: Option<u64>,
+    },
+    /// Silently drop all traffic addressed to `name`
+    BlackholeName {
+        name: GdpName,
+        duration_secs: Option<u64>,
+    },
+    /// Hold on to RIB replies for `delay_ms` before learning from them (0 to stop)
+    DelayRibReplies {
+        delay_ms: u64,
+    },
+    /// Undo every failure injected with the commands above
+    ClearChaos,
+    /// Look up the public key the RIB has bound to `name`, asking the RIB if we don't know it yet
+    ResolveName {
+        name: GdpName,
+    },
+    /// Remove up to `max` of the packets with unknown actions that were punted to the control plane
+    TakePunted {
+        max: usize,
+    },
+    /// The latest results of probing the routes of actively used names
+    DumpProbes,
+    /// Signed usage reports with a sequence number after `after_sequence` (all kept ones if None)
+    UsageReports {
+        after_sequence: Option<u64>,
+    },
+    /// Drop everything from `ip` before it is decrypted, for `duration_secs` or the default
+    BlockPeer {
+        ip: Ipv4Addr,
+        duration_secs: Option<u64>,
+    },
+    /// Lift any block on `ip` and forget its abuse score. With `exempt_secs`, abuse from it
+    /// does not get it blocked again for that long.
+    UnblockPeer {
+        ip: Ipv4Addr,
+        exempt_secs: Option<u64>,
+    },
+    DumpBlocklist,
+    /// Sent by clients to their sidecar, to be exported with its statistics
+    ReportFlow {
+        report: FlowReport,
+    },
+    /// The latest report on each flow of the sidecar's clients
+    DumpClientFlows,
+    /// The routes of DumpRoutes in canonical order, with their digest, to compare with other nodes
+    ExportTable,
+    /// How often each arm of the branches in the node's pipelines was taken
+    DumpBranches,
+    /// Send `percent` of the senders to `name` to `ip` as well as (Mirror) or instead of
+    /// (Divert) the name's route, for `duration_secs` or the default. Replaces any earlier rule
+    /// for the name, and its counts.
+    SetCanary {
+        name: GdpName,
+        ip: Ipv4Addr,
+        percent: f64,
+        mode: CanaryMode,
+        duration_secs: Option<u64>,
+    },
+    ClearCanary {
+        name: GdpName,
+    },
+    /// The canary rules in force, with where the traffic for their names went
+    DumpCanaries,
+    /// What the node is running: its build, configuration and keys
+    Info,
+    /// The sources blocked for abuse, and the hops they are blocked through
+    DumpBlockedSources,
+    /// ReportFlow for a paced Put, with the times the sending client had more to send but
+    /// waited because the receiver's pace was already in flight
+    ReportPacedFlow {
+        report: FlowReport,
+        paced_stalls: u64,
+    },
+}
+
+/// What a canary rule does with the packets of the senders it picks
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum CanaryMode {
+    /// Send a copy to the canary, and the packet along the name's route
+    Mirror,
+    /// Send the packet to the canary only
+    Divert,
+}
+
+/// Why a switch sends a name where it does
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum RouteSource {
+    /// Pinned by the operator, overriding anything learned
+    Pinned,
+    /// The name's own route, learned from the RIB or configured locally
+    Learned,
+    /// The route of a name that the RIB delegated this one to
+    Delegated,
+    /// Back along a flow that a local endpoint opened
+    ReturnFlow,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RouteDump {
+    pub name: GdpName,
+    pub ip: Ipv4Addr,
+    pub expiration_time: u64,
+    pub source: RouteSource,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProbeResult {
+    pub name: GdpName,
+    /// Where the route being probed pointed
+    pub locator: Ipv4Addr,
+    pub probes: u64,
+    pub failures: u64,
+    /// Whether the latest probe was answered, and the name was reachable from the locator
+    pub reachable: bool,
+    /// Round trip of the latest answered probe
+    pub rtt_us: Option<u64>,
+    pub probe_time: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BlockedPeer {
+    pub ip: Ipv4Addr,
+    pub expiration_time: u64,
+    /// How many times the peer was blocked for abuse, which lengthens the next block
+    pub offences: u32,
+    pub by_operator: bool,
+}
+
+/// A source blocked for abuse in the packets that came through the previous hop `via`; its
+/// packets through other hops still go through
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BlockedSource {
+    pub source: GdpName,
+    pub via: Ipv4Addr,
+    pub expiration_time: u64,
+    /// How many times the source was blocked through `via`, which lengthens the next block
+    pub offences: u32,
+}
+
+/// How a client flow is faring, as seen by the client: a slow flow with a short RTT and no
+/// losses is held up by an application rather than by the network
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct FlowReport {
+    /// The other end of the flow
+    pub peer: GdpName,
+    pub stream_id: u32,
+    /// How many packets the client lets be in flight
+    pub window: u32,
+    /// Smoothed round-trip time, None until the first sample
+    pub srtt_us: Option<u64>,
+    /// Times the client had to ask for a retransmission
+    pub loss_events: u64,
+    pub delivered_bytes: u64,
+    pub finished: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClientFlow {
+    pub client: SocketAddrV4,
+    pub report: FlowReport,
+    /// Milliseconds since the client last reported on the flow
+    pub age_ms: u64,
+}
+
+/// How often each arm of one branch in a node's pipelines was taken, summed over its cores
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct BranchReport {
+    /// What the branch decides, e.g. "action" for the dispatch on the GDP action
+    pub branch: String,
+    pub nic: String,
+    /// Every arm with its hits, busiest first: the order to check them in for this traffic
+    pub arms: Vec<(String, u64)>,
+    /// The arms busiest first, as the last pipeline to rank them ranked them, if they adapt to the
+    /// traffic
+    pub adaptive_order: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CanaryReport {
+    pub name: GdpName,
+    /// Where the picked senders' packets go
+    pub ip: Ipv4Addr,
+    pub percent: f64,
+    pub mode: CanaryMode,
+    pub expiration_time: u64,
+    /// Sent to the canary: the diverted packets, or the copies of the mirrored ones
+    pub canary_packets: u64,
+    pub canary_bytes: u64,
+    /// Sent along the name's route, mirrored or not
+    pub baseline_packets: u64,
+    pub baseline_bytes: u64,
+}
+
+/// What a node is running, as it logs at startup and answers the Info command with
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NodeInfo {
+    /// The router's version, and the commit it was built from if the build said
+    pub version: String,
+    /// Switch, RIB or sidecar
+    pub mode: String,
+    pub name: GdpName,
+    /// The cargo features the router was built with
+    pub build_features: Vec<String>,
+    /// The feature flags, with whether each is enabled now
+    pub flags: Vec<(String, bool)>,
+    /// The GDP header layout the node sends and accepts
+    pub wire_version: u8,
+    /// The cipher suites that sessions with the node may use
+    pub cipher_suites: Vec<String>,
+    pub ports: Vec<PortInfo>,
+    /// What each key is for, and the start of the SHA-256 of the key (in hex); never the key
+    pub key_fingerprints: Vec<(String, String)>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PortInfo {
+    pub name: String,
+    pub device: String,
+    pub cores: Vec<usize>,
+    pub rxd: usize,
+    pub txd: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PuntedPacket {
+    pub action: u8,
+    pub src: GdpName,
+    pub dst: GdpName,
+    /// The start of the GDP payload (data followed by certificates)
+    pub payload: Vec<u8>,
+}
+
+/// New responses go at the end: bincode tags each variant with its position
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ClientResponse<'a> {
+    PortSet {
+        port: u16,
+    },
+    FlagSet {
+        name: Cow<'a, str>,
+        enabled: bool,
+    },
+    Flags {
+        flags: Vec<(Cow<'a, str>, bool)>,
+    },
+    Health {
+        clock_synced: bool,
+        clock_offset_us: i64,
+        clock_error_us: u64,
+    },
+    RoutePinned {
+        name: GdpName,
+        ip: Ipv4Addr,
+        expiration_time: u64,
+    },
+    RouteUnpinned {
+        name: GdpName,
+        existed: bool,
+    },
+    Routes {
+        routes: Vec<RouteDump>,
+    },
+    Punted {
+        packets: Vec<PuntedPacket>,
+        /// Punted packets that were discarded because nobody collected them in time
+        dropped: u64,
+    },
+    /// The injected failure lasts until `expiration_time`, unless cleared first
+    ChaosInjected {
+        expiration_time: u64,
+    },
+    RibRepliesDelayed {
+        delay_ms: u64,
+    },
+    ChaosCleared,
+    Probes {
+        results: Vec<ProbeResult>,
+    },
+    /// `pub_key` is None while the binding is being fetched from the RIB
+    Binding {
+        name: GdpName,
+        pub_key: Option<[u8; 32]>,
+    },
+    Error {
+        msg: Cow<'a, str>,
+    },
+    /// Oldest first. `more` is set if there are further reports to ask for after the last one.
+    UsageReports {
+        reports: Vec<SignedUsageReport>,
+        more: bool,
+    },
+    PeerBlocked {
+        ip: Ipv4Addr,
+        expiration_time: u64,
+    },
+    PeerUnblocked {
+        ip: Ipv4Addr,
+        was_blocked: bool,
+    },
+    Blocklist {
+        peers: Vec<BlockedPeer>,
+    },
+    FlowReported,
+    ClientFlows {
+        flows: Vec<ClientFlow>,
+    },
+    Table {
+        export: TableExport,
+    },
+    Branches {
+        branches: Vec<BranchReport>,
+    },
+    CanarySet {
+        name: GdpName,
+        expiration_time: u64,
+    },
+    CanaryCleared {
+        name: GdpName,
+        existed: bool,
+    },
+    Canaries {
+        canaries: Vec<CanaryReport>,
+    },
+    Info {
+        info: NodeInfo,
+    },
+    BlockedSources {
+        sources: Vec<BlockedSource>,
+    },
+}
@@ -0,0 +1,311 @@
+use anyhow::{anyhow, ensure, Result};
+
+use crate::names::{GdpName, NAME_LEN};
+
+/*
+   The fixed header fields are final: anything added to the header from now on is an extension.
+   Extensions fill the bytes between the fixed fields and `header_len`, as TLVs: a kind byte,
+   a length byte, then that many bytes of value. Nodes skip the kinds they do not know, and
+   nodes older than extensions skip them all along with the rest of the header.
+*/
+
+/// A client asks its switch whether the path to the destination can carry a new flow
+pub const EXT_ADMISSION_REQUEST: u8 = 1;
+/// The switch's answer to an admission request
+pub const EXT_ADMISSION_DECISION: u8 = 2;
+/// The packet is one piece of a larger packet, split to fit the MTU of a link on the way
+pub const EXT_FRAGMENT: u8 = 3;
+/// How sensitive the packet is, set by its origin and kept on every hop
+pub const EXT_SECURITY_LABEL: u8 = 4;
+/// An edge switch vouches for the certificates it checked and removed from the packet
+pub const EXT_CERT_ATTESTATION: u8 = 5;
+/// The packet's number in its flow from source to destination, set by its origin and kept on
+/// every hop
+pub const EXT_SEQUENCE: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderExtension {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+/// The extensions in the bytes of a header that follow its fixed fields
+pub fn parse_extensions(extensions: &[u8]) -> Result<Vec<HeaderExtension>> {
+    let mut parsed = Vec::new();
+    let mut rest = extensions;
+    while !rest.is_empty() {
+        ensure!(rest.len() >= 2, "truncated header extension");
+        let (kind, len) = (rest[0], rest[1] as usize);
+        ensure!(
+            rest.len() >= 2 + len,
+            "header extension {} is longer than the header",
+            kind
+        );
+        parsed.push(HeaderExtension {
+            kind,
+            value: rest[2..2 + len].to_vec(),
+        });
+        rest = &rest[2 + len..];
+    }
+    Ok(parsed)
+}
+
+pub fn write_extensions(extensions: &[HeaderExtension]) -> Result<Vec<u8>> {
+    let mut written = Vec::new();
+    for extension in extensions {
+        ensure!(
+            extension.value.len() <= u8::MAX as usize,
+            "header extension {} is too long",
+            extension.kind
+        );
+        written.push(extension.kind);
+        written.push(extension.value.len() as u8);
+        written.extend(&extension.value);
+    }
+    Ok(written)
+}
+
+/// The rates a client wants for a new flow, in kbit/s
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdmissionRequest {
+    pub rate_kbps: u32,
+    /// The flow is not worth sending below this rate
+    pub min_rate_kbps: u32,
+}
+
+impl AdmissionRequest {
+    pub fn to_extension(self) -> HeaderExtension {
+        let mut value = self.rate_kbps.to_be_bytes().to_vec();
+        value.extend(self.min_rate_kbps.to_be_bytes());
+        HeaderExtension {
+            kind: EXT_ADMISSION_REQUEST,
+            value,
+        }
+    }
+
+    /// The request among `extensions`, if there is one
+    pub fn find(extensions: &[HeaderExtension]) -> Result<Option<Self>> {
+        let value = match extensions
+            .iter()
+            .find(|ext| ext.kind == EXT_ADMISSION_REQUEST)
+        {
+            Some(extension) => &extension.value,
+            None => return Ok(None),
+        };
+        ensure!(value.len() == 8, "bad admission request extension");
+        Ok(Some(AdmissionRequest {
+            rate_kbps: u32::from_be_bytes(value[..4].try_into()?),
+            min_rate_kbps: u32::from_be_bytes(value[4..].try_into()?),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionDecision {
+    /// The path is being probed; ask again shortly
+    Pending,
+    /// The path sustained the requested rate
+    Admit { rate_kbps: u32 },
+    /// The path sustained less than requested, but at least the minimum; send at this rate
+    Downgrade { rate_kbps: u32 },
+    /// The path did not sustain the minimum rate
+    Reject,
+}
+
+impl AdmissionDecision {
+    pub fn to_extension(self) -> HeaderExtension {
+        let (code, rate_kbps) = match self {
+            AdmissionDecision::Pending => (0u8, 0),
+            AdmissionDecision::Admit { rate_kbps } => (1, rate_kbps),
+            AdmissionDecision::Downgrade { rate_kbps } => (2, rate_kbps),
+            AdmissionDecision::Reject => (3, 0),
+        };
+        let mut value = vec![code];
+        value.extend(rate_kbps.to_be_bytes());
+        HeaderExtension {
+            kind: EXT_ADMISSION_DECISION,
+            value,
+        }
+    }
+
+    /// The decision among `extensions`, if there is one
+    pub fn find(extensions: &[HeaderExtension]) -> Result<Option<Self>> {
+        let value = match extensions
+            .iter()
+            .find(|ext| ext.kind == EXT_ADMISSION_DECISION)
+        {
+            Some(extension) => &extension.value,
+            None => return Ok(None),
+        };
+        ensure!(value.len() == 5, "bad admission decision extension");
+        let rate_kbps = u32::from_be_bytes(value[1..].try_into()?);
+        Ok(Some(match value[0] {
+            0 => AdmissionDecision::Pending,
+            1 => AdmissionDecision::Admit { rate_kbps },
+            2 => AdmissionDecision::Downgrade { rate_kbps },
+            3 => AdmissionDecision::Reject,
+            unknown => return Err(anyhow!("unknown admission decision {}", unknown)),
+        }))
+    }
+}
+
+/// Where a fragment's payload belongs in the payload of the packet it was split from. Every
+/// fragment carries the header of the whole packet (data and telemetry lengths, content hash),
+/// so a packet is whole again once its payload is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    /// The same for all fragments of a packet, and unique among the packets a node has in flight
+    pub id: u32,
+    /// Of the fragment's payload within the packet's, in bytes
+    pub offset: u16,
+    /// Whether fragments follow this one; false for the last
+    pub more: bool,
+}
+
+impl Fragment {
+    pub fn to_extension(self) -> HeaderExtension {
+        let mut value = self.id.to_be_bytes().to_vec();
+        value.extend(self.offset.to_be_bytes());
+        value.push(self.more as u8);
+        HeaderExtension {
+            kind: EXT_FRAGMENT,
+            value,
+        }
+    }
+
+    /// The fragment extension among `extensions`, if the packet is a fragment
+    pub fn find(extensions: &[HeaderExtension]) -> Result<Option<Self>> {
+        let value = match extensions.iter().find(|ext| ext.kind == EXT_FRAGMENT) {
+            Some(extension) => &extension.value,
+            None => return Ok(None),
+        };
+        ensure!(value.len() == 7, "bad fragment extension");
+        Ok(Some(Fragment {
+            id: u32::from_be_bytes(value[..4].try_into()?),
+            offset: u16::from_be_bytes(value[4..6].try_into()?),
+            more: value[6] & 1 != 0,
+        }))
+    }
+}
+
+/// A packet's number in its flow (src to dst), from 1 (see FlowSequences). Packets without one
+/// are unnumbered, which is told apart from a number by being zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceNumber(pub u32);
+
+impl SequenceNumber {
+    pub fn to_extension(self) -> HeaderExtension {
+        HeaderExtension {
+            kind: EXT_SEQUENCE,
+            value: self.0.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// The number among `extensions`, if the packet is numbered
+    pub fn find(extensions: &[HeaderExtension]) -> Result<Option<Self>> {
+        let value = match extensions.iter().find(|ext| ext.kind == EXT_SEQUENCE) {
+            Some(extension) => &extension.value,
+            None => return Ok(None),
+        };
+        ensure!(value.len() == 4, "bad sequence number extension");
+        Ok(Some(SequenceNumber(u32::from_be_bytes(
+            value[..].try_into()?,
+        ))))
+    }
+}
+
+/// A packet's classification: a level, and the compartments it belongs to. A label dominates
+/// another if its level is at least as high and it has every compartment of the other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SecurityLabel {
+    pub level: u8,
+    /// One bit per compartment
+    pub compartments: u32,
+}
+
+impl SecurityLabel {
+    /// The label of packets that carry none
+    pub const UNCLASSIFIED: SecurityLabel = SecurityLabel {
+        level: 0,
+        compartments: 0,
+    };
+
+    pub fn dominates(self, other: SecurityLabel) -> bool {
+        self.level >= other.level && other.compartments & !self.compartments == 0
+    }
+
+    pub fn to_extension(self) -> HeaderExtension {
+        let mut value = vec![self.level];
+        value.extend(self.compartments.to_be_bytes());
+        HeaderExtension {
+            kind: EXT_SECURITY_LABEL,
+            value,
+        }
+    }
+
+    /// The label among `extensions`, if the packet carries one
+    pub fn find(extensions: &[HeaderExtension]) -> Result<Option<Self>> {
+        let value = match extensions.iter().find(|ext| ext.kind == EXT_SECURITY_LABEL) {
+            Some(extension) => &extension.value,
+            None => return Ok(None),
+        };
+        ensure!(value.len() == 5, "bad security label extension");
+        Ok(Some(SecurityLabel {
+            level: value[0],
+            compartments: u32::from_be_bytes(value[1..].try_into()?),
+        }))
+    }
+}
+
+/// An edge switch's word that the certificate chain from `src` checked out, in place of the
+/// chain, until `expiration_time`. The signature is the attester's, over `signed_bytes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CertAttestation {
+    pub attester: GdpName,
+    pub src: GdpName,
+    /// In seconds since the Unix epoch, like the certificates'
+    pub expiration_time: u64,
+    pub signature: [u8; 64],
+}
+
+impl CertAttestation {
+    const SIGNED_LEN: usize = 2 * NAME_LEN + 8;
+
+    /// What the attester signs: every field but the signature
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.attester.to_vec();
+        bytes.extend(self.src);
+        bytes.extend(self.expiration_time.to_be_bytes());
+        bytes
+    }
+
+    pub fn to_extension(self) -> HeaderExtension {
+        let mut value = self.signed_bytes();
+        value.extend(self.signature);
+        HeaderExtension {
+            kind: EXT_CERT_ATTESTATION,
+            value,
+        }
+    }
+
+    /// The attestation among `extensions`, if the packet carries one
+    pub fn find(extensions: &[HeaderExtension]) -> Result<Option<Self>> {
+        let value = match extensions
+            .iter()
+            .find(|ext| ext.kind == EXT_CERT_ATTESTATION)
+        {
+            Some(extension) => &extension.value,
+            None => return Ok(None),
+        };
+        ensure!(
+            value.len() == Self::SIGNED_LEN + 64,
+            "bad certificate attestation extension"
+        );
+        Ok(Some(CertAttestation {
+            attester: value[..NAME_LEN].try_into()?,
+            src: value[NAME_LEN..2 * NAME_LEN].try_into()?,
+            expiration_time: u64::from_be_bytes(value[2 * NAME_LEN..Self::SIGNED_LEN].try_into()?),
+            signature: value[Self::SIGNED_LEN..].try_into()?,
+        }))
+    }
+}
@@ -0,0 +1,57 @@
+/*
+   An endpoint that is done with a flow says so with a Fin, rather than leaving the switches on
+   the way and its peer to time the flow out:
+   - the Fin goes from the endpoint to the flow's destination along the flow's route, and each
+     switch it passes forgets what it kept for the flow before passing it on. A switch with no
+     route for the destination drops the Fin rather than asking the RIB for one
+   - the payload starts with a kind byte. A Close ends a flow whose data has all been sent, so
+     packets of the flow that are already on their way are still delivered; a Reset abandons
+     the flow, and switches drop the packets of it they are still holding
+   - bytes after the kind are left for later fields, and are skipped by nodes that do not know
+     them. Kinds this version does not know are taken as a Close, which reclaims the least
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinKind {
+    /// The endpoint sent everything it meant to, and will not send more on the flow
+    Close,
+    /// The endpoint gave up on the flow; what is still in flight is not wanted
+    Reset,
+}
+
+impl FinKind {
+    pub fn code(self) -> u8 {
+        match self {
+            FinKind::Close => 1,
+            FinKind::Reset => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            2 => FinKind::Reset,
+            _ => FinKind::Close,
+        }
+    }
+}
+
+/// What a Fin says about the flow it ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FinPayload {
+    pub kind: FinKind,
+}
+
+impl FinPayload {
+    pub fn to_bytes(self) -> Vec<u8> {
+        vec![self.kind.code()]
+    }
+
+    /// The payload of a Fin; an empty one is a Close
+    pub fn parse(payload: &[u8]) -> Self {
+        FinPayload {
+            kind: payload
+                .first()
+                .map_or(FinKind::Close, |&code| FinKind::from_code(code)),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! GDP wire formats: the header that every GDP packet starts with and its extensions, why a
+//! packet was NACKed or how a flow ended, the messages exchanged with a node over its control
+//! socket, the usage reports that switches sign, the status objects that they serve, and the
+//! certificates and RIB messages that nodes exchange. Also the tools operators use on what
+//! switches export, such as diffing their routing tables. Shared by clients and switches,
+//! without the dependencies of either.
+
+mod certs;
+mod control;
+mod extensions;
+mod fin;
+mod nack;
+mod names;
+mod ops;
+mod rib;
+mod status;
+mod structs;
+mod usage;
+
+pub use crate::certs::{
+    AttrCert, CertContents, CertDest, Certificate, CertificateBlock, GdpMeta, RtCert,
+    SerializableSignature,
+};
+pub use crate::control::{
+    BlockedPeer, BlockedSource, BranchReport, CanaryMode, CanaryReport, ClientCommand,
+    ClientCommands, ClientFlow, ClientResponse, ClientResponses, FlowReport, NodeInfo, PortInfo,
+    ProbeResult, PuntedPacket, RouteDump, RouteSource,
+};
+pub use crate::extensions::{
+    parse_extensions, write_extensions, AdmissionDecision, AdmissionRequest, CertAttestation,
+    Fragment, HeaderExtension, SecurityLabel, SequenceNumber, EXT_ADMISSION_DECISION,
+    EXT_ADMISSION_REQUEST, EXT_CERT_ATTESTATION, EXT_FRAGMENT, EXT_SECURITY_LABEL, EXT_SEQUENCE,
+};
+pub use crate::fin::{FinKind, FinPayload};
+pub use crate::nack::{NackPayload, NackReason};
+pub use crate::names::{check_magic, name_hash, GdpName, NameType, MAGIC_NUMBERS, NAME_LEN};
+pub use crate::ops::{diff_tables, TableDiff, TableExport};
+pub use crate::rib::{
+    Registration, RegistrationContents, RegistrationPhase, RibQuery, RibResponse, TransportHint,
+    TransportProfile,
+};
+pub use crate::status::{status_name, StatusObject, SwitchStatus};
+pub use crate::structs::{
+    content_hash, new_trace_id, verify_content_hash, FlowSequences, GdpAction, GdpHeader,
+};
+pub use crate::usage::{SignedUsageReport, TenantUsage, UsageReport};
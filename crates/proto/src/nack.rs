@@ -0,0 +1,75 @@
+/*
+   A switch that bounces a data packet back to its sender as a NACK says why in the NACK's
+   payload, so that the sender can tell a name that cannot be reached from a switch that was
+   only busy:
+   - the payload starts with a reason code byte. Bytes after it are left for later fields, and
+     are skipped by nodes that do not know them
+   - NACKs from switches older than reasons have an empty payload
+   - codes this version does not know are kept as they are, rather than failing the NACK
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackReason {
+    /// No switch on the way knows where the destination is, or the way there loops
+    NoRoute,
+    /// The packet's TTL ran out on the way
+    TtlExpired,
+    /// The packet's certificates do not check out, or name someone the switch does not know yet
+    BadCert,
+    /// The packet could not be decrypted
+    DecryptFail,
+    /// A switch on the way had no room left to hold the packet
+    QueueFull,
+    /// From a newer switch, for a reason this version does not know
+    Unknown(u8),
+}
+
+impl NackReason {
+    pub fn code(self) -> u8 {
+        match self {
+            NackReason::NoRoute => 1,
+            NackReason::TtlExpired => 2,
+            NackReason::BadCert => 3,
+            NackReason::DecryptFail => 4,
+            NackReason::QueueFull => 5,
+            NackReason::Unknown(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => NackReason::NoRoute,
+            2 => NackReason::TtlExpired,
+            3 => NackReason::BadCert,
+            4 => NackReason::DecryptFail,
+            5 => NackReason::QueueFull,
+            unknown => NackReason::Unknown(unknown),
+        }
+    }
+
+    /// Whether sending the same packet again may succeed once the switch has caught up, rather
+    /// than failing the same way
+    pub fn is_transient(self) -> bool {
+        matches!(self, NackReason::QueueFull | NackReason::BadCert)
+    }
+}
+
+/// What a NACK says about the packet it bounced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NackPayload {
+    pub reason: NackReason,
+}
+
+impl NackPayload {
+    pub fn to_bytes(self) -> Vec<u8> {
+        vec![self.reason.code()]
+    }
+
+    /// The payload of a NACK, or None for one from a switch older than reasons
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let code = *payload.first()?;
+        Some(NackPayload {
+            reason: NackReason::from_code(code),
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, ensure, Result};
+use sha2::Digest;
+#[cfg(not(feature = "hash-sha512"))]
+use sha2::Sha256 as NameHasher;
+#[cfg(feature = "hash-sha512")]
+use sha2::Sha512_256 as NameHasher;
+
+/*
+   GdpNames are 256-bit SHA-256 hashes unless the proto crate is built otherwise, for experiments
+   that need other names:
+   - `name-160` truncates names (and content hashes) to their first 160 bits
+   - `hash-sha512` derives them with SHA-512/256 instead of SHA-256
+   Nodes built with different choices lay out their headers differently and cannot interoperate,
+   so every header carries the code of its sender's name type in the second byte of the magic
+   number. A mismatch is reported as such rather than as a malformed packet. The default name
+   type keeps the magic number that every node used before names were configurable.
+*/
+
+#[cfg(not(feature = "name-160"))]
+pub const NAME_LEN: usize = 32;
+#[cfg(feature = "name-160")]
+pub const NAME_LEN: usize = 20;
+
+pub type GdpName = [u8; NAME_LEN];
+
+/// First byte of the magic number, the same for every name type
+const MAGIC_MARKER: u8 = 0x26;
+
+/// How the names in a header were derived, and how wide they are
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NameType {
+    Sha256 = 0x2a,
+    Sha512_256 = 0x2b,
+    Sha256Truncated160 = 0x2c,
+    Sha512_256Truncated160 = 0x2d,
+}
+
+impl NameType {
+    /// The name type this node was built with
+    #[cfg(all(not(feature = "name-160"), not(feature = "hash-sha512")))]
+    pub const LOCAL: NameType = NameType::Sha256;
+    #[cfg(all(not(feature = "name-160"), feature = "hash-sha512"))]
+    pub const LOCAL: NameType = NameType::Sha512_256;
+    #[cfg(all(feature = "name-160", not(feature = "hash-sha512")))]
+    pub const LOCAL: NameType = NameType::Sha256Truncated160;
+    #[cfg(all(feature = "name-160", feature = "hash-sha512"))]
+    pub const LOCAL: NameType = NameType::Sha512_256Truncated160;
+
+    fn from_code(code: u8) -> Option<NameType> {
+        [
+            NameType::Sha256,
+            NameType::Sha512_256,
+            NameType::Sha256Truncated160,
+            NameType::Sha512_256Truncated160,
+        ]
+        .into_iter()
+        .find(|name_type| *name_type as u8 == code)
+    }
+}
+
+/// Identifies GDP packets, and the name type of their sender
+pub const MAGIC_NUMBERS: u16 = u16::from_be_bytes([MAGIC_MARKER, NameType::LOCAL as u8]);
+
+/// Check the magic number of a header, telling apart packets that are not GDP at all from
+/// packets of a node built with another name type
+pub fn check_magic(field: u16) -> Result<()> {
+    let [marker, code] = field.to_be_bytes();
+    ensure!(marker == MAGIC_MARKER, "not a GDP packet");
+    match NameType::from_code(code) {
+        Some(name_type) if name_type == NameType::LOCAL => Ok(()),
+        Some(name_type) => bail!(
+            "sender uses {:?} names, but this node was built for {:?}",
+            name_type,
+            NameType::LOCAL
+        ),
+        None => bail!("not a GDP packet"),
+    }
+}
+
+/// Derive a name from `data`, with the hash function and width this node was built with
+pub fn name_hash(data: &[u8]) -> GdpName {
+    let digest = NameHasher::digest(data);
+    let mut name = [0; NAME_LEN];
+    name.copy_from_slice(&digest[..NAME_LEN]);
+    name
+}
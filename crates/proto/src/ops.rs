@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::control::{RouteDump, RouteSource};
+use crate::names::{name_hash, GdpName};
+
+/*
+   Operators audit the routing state of switches against each other by exporting each switch's
+   table and comparing the exports:
+   - an export lists the routes of a switch in a canonical order (by name, then source), with a
+     digest of them, so that two switches that route alike have the same digest whatever order
+     they learned their routes in
+   - the digest covers where each name is routed and why, but not when the route expires, which
+     differs between switches that learned the same route at different times
+   - a diff of two exports lists the routes only the second has, those only the first has, and
+     those that both have (for the same name and source) but send to different places
+*/
+
+/// A switch's routes, in canonical order, with their digest
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct TableExport {
+    pub routes: Vec<RouteDump>,
+    pub digest: GdpName,
+}
+
+impl TableExport {
+    pub fn new(mut routes: Vec<RouteDump>) -> Self {
+        routes.sort_by_key(|route| (route.name, route.source as u8));
+        let digest = Self::digest_of(&routes);
+        TableExport { routes, digest }
+    }
+
+    /// The digest of `routes`, which must be in canonical order
+    pub fn digest_of(routes: &[RouteDump]) -> GdpName {
+        let mut canonical = Vec::new();
+        for route in routes {
+            canonical.extend(route.name);
+            canonical.extend(route.ip.octets());
+            canonical.push(route.source as u8);
+        }
+        name_hash(&canonical)
+    }
+
+    /// Whether the digest is the one of the routes, so the export was not altered or cut short
+    pub fn is_consistent(&self) -> bool {
+        Self::digest_of(&self.routes) == self.digest
+    }
+}
+
+/// How the routes of one export differ from those of another
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TableDiff {
+    pub added: Vec<RouteDump>,
+    pub removed: Vec<RouteDump>,
+    /// Before and after
+    pub changed: Vec<(RouteDump, RouteDump)>,
+}
+
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+fn by_key(export: &TableExport) -> BTreeMap<(GdpName, u8), &RouteDump> {
+    export
+        .routes
+        .iter()
+        .map(|route| ((route.name, route.source as u8), route))
+        .collect()
+}
+
+/// How the routes of `after` differ from those of `before`
+pub fn diff_tables(before: &TableExport, after: &TableExport) -> TableDiff {
+    let mut diff = TableDiff::default();
+    if before.digest == after.digest {
+        return diff;
+    }
+    let (before, after) = (by_key(before), by_key(after));
+    for (key, route) in &before {
+        match after.get(key) {
+            None => diff.removed.push((*route).clone()),
+            Some(other) if other.ip != route.ip => {
+                diff.changed.push(((*route).clone(), (*other).clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    diff.added = after
+        .iter()
+        .filter(|(key, _)| !before.contains_key(key))
+        .map(|(_, route)| (*route).clone())
+        .collect();
+    diff
+}
+
+struct Name<'a>(&'a GdpName);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+fn source_name(source: RouteSource) -> &'static str {
+    match source {
+        RouteSource::Pinned => "pinned",
+        RouteSource::Learned => "learned",
+        RouteSource::Delegated => "delegated",
+        RouteSource::ReturnFlow => "return flow",
+    }
+}
+
+/// One line per route: `+` added, `-` removed, `~` sent elsewhere
+impl fmt::Display for TableDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for route in &self.added {
+            let source = source_name(route.source);
+            writeln!(f, "+ {} {} ({})", Name(&route.name), route.ip, source)?;
+        }
+        for route in &self.removed {
+            let source = source_name(route.source);
+            writeln!(f, "- {} {} ({})", Name(&route.name), route.ip, source)?;
+        }
+        for (before, after) in &self.changed {
+            writeln!(
+                f,
+                "~ {} {} -> {} ({})",
+                Name(&before.name),
+                before.ip,
+                after.ip,
+                source_name(before.source)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::net::Ipv4Addr;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::certs::{Certificate, GdpMeta, SerializableSignature};
+use crate::names::GdpName;
+
+/*
+   What switches and clients ask the RIB (in a RibGet), and what it answers (in a RibReply).
+   Replies carry certificates and the metadata of their owners, but are not signed themselves:
+   whoever takes a route from one must check its certificate against its owner's metadata.
+   Nodes bind their own names in a RibRegister, whose Registration they sign as a whole
+*/
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct RibQuery {
+    pub metas_for_names: Vec<GdpName>,
+    pub ips_for_names: Vec<GdpName>,
+    pub next_hop_for_names: Vec<GdpName>,
+    /// Ignored: nodes bind their names with a signed RibRegister. Kept so that queries keep
+    /// their layout
+    pub new_nodes: Vec<GdpMeta>,
+    /// Ignored, as `new_nodes` is
+    pub new_certs: Vec<Certificate>,
+    /// Have the RIB push changes to the bindings it returns for `ips_for_names` and
+    /// `next_hop_for_names` to the sender, for as long as they would have been cached
+    pub subscribe: bool,
+}
+
+impl RibQuery {
+    pub fn next_hop_for(dst: GdpName) -> Self {
+        RibQuery {
+            ips_for_names: vec![dst],
+            next_hop_for_names: vec![dst],
+            // the answer is cached until it expires
+            subscribe: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn metas_for(names: &[GdpName]) -> Self {
+        RibQuery {
+            metas_for_names: names.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportProfile {
+    Encrypted,
+    /// For nodes without the dTLS layer, which send GDP straight over UDP
+    PlaintextLegacy,
+}
+
+impl Default for TransportProfile {
+    fn default() -> Self {
+        TransportProfile::Encrypted
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct TransportHint {
+    pub ip: Ipv4Addr,
+    pub profile: TransportProfile,
+}
+
+/// Read with `decode`, which also takes the TTLs that follow the other fields
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RibResponse {
+    pub metas: Vec<GdpMeta>,
+    pub certs: Vec<Certificate>,
+    /// Requested names for which the RIB has no route
+    pub misses: Vec<GdpName>,
+    /// Subscribed names whose route the RIB no longer has, which must not be used any more
+    pub withdrawn: Vec<GdpName>,
+    /// Nodes located by `certs` that must be reached without dTLS
+    pub transport_hints: Vec<TransportHint>,
+    /// Where names that are moving hosts are registered as pending
+    pub migrating: Vec<Certificate>,
+    /// How long to keep the routes in `certs`, in seconds; None leaves it to the switch
+    #[serde(skip_deserializing)]
+    pub route_ttl: Option<u64>,
+    /// How long to keep `misses` and `withdrawn` as names without a route, in seconds; None
+    /// leaves it to the switch
+    #[serde(skip_deserializing)]
+    pub negative_ttl: Option<u64>,
+}
+
+impl RibResponse {
+    /// Read a reply, with whichever of its trailing TTLs it has: RIBs that predate them send
+    /// none, or only `route_ttl`
+    pub fn decode(mut bytes: &[u8]) -> Result<RibResponse> {
+        let mut response: RibResponse = bincode::deserialize_from(&mut bytes)?;
+        if !bytes.is_empty() {
+            response.route_ttl = bincode::deserialize_from(&mut bytes)?;
+        }
+        if !bytes.is_empty() {
+            response.negative_ttl = bincode::deserialize_from(&mut bytes)?;
+        }
+        Ok(response)
+    }
+
+    /// Add the answers of `other`, so that one reply carries both
+    pub fn merge(&mut self, other: RibResponse) {
+        self.metas.extend(other.metas);
+        self.certs.extend(other.certs);
+        self.misses.extend(other.misses);
+        self.withdrawn.extend(other.withdrawn);
+        self.transport_hints.extend(other.transport_hints);
+        self.migrating.extend(other.migrating);
+        self.route_ttl = self.route_ttl.or(other.route_ttl);
+        self.negative_ttl = self.negative_ttl.or(other.negative_ttl);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegistrationPhase {
+    /// The certificates locate the name from now on
+    Active,
+    /// The certificates locate the host the name is moving to, which takes over once the old
+    /// registration is withdrawn
+    Pending,
+    /// The host sending it no longer serves the name; the certificates are ignored
+    Withdraw,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegistrationContents {
+    pub meta: GdpMeta,
+    pub certs: Vec<Certificate>,
+    pub phase: RegistrationPhase,
+    /// Seconds since the Unix epoch
+    pub signed_at: u64,
+}
+
+/// The payload of a RibRegister, signed by the owner of `contents.meta`, and followed by its
+/// fleet token if it has one
+#[derive(Serialize, Deserialize)]
+pub struct Registration {
+    pub contents: RegistrationContents,
+    pub signature: SerializableSignature,
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+use crate::names::{name_hash, GdpName};
+
+/*
+   Switches serve their status to the GDP clients that they route for, with nothing but GDP
+   packets:
+   - each switch reserves a name for each of its status objects, derived from its own name and
+     the object (`status_name`). Names are hashes, so the reserved names are "under" the
+     switch's name only in that nobody but the switch can claim them
+   - a Get addressed to one of them is answered by that switch, with a GetReply from the status
+     name carrying the object, as a bincode-encoded SwitchStatus. The Get must carry the
+     certificates that delegate its sender to the switch, as a Ping to it must, and the object
+     must fit in one packet
+   - the objects are refreshed about once a second off the packet path, so that answering a Get
+     costs no more than answering a Ping, and may be that old
+   - status names are not registered with the RIB: a Get reaches the switch the client sends it
+     to, and is answered there if it is for that switch's status
+*/
+
+/// What a status name stands for
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusObject {
+    /// How many routes the switch has, and the digest of its table as ExportTable gives it
+    Routes = 0,
+    /// The switch's main counters, summed over its ports
+    Stats = 1,
+    /// Whether the switch's clock is in sync with the time master
+    Health = 2,
+}
+
+impl StatusObject {
+    pub const ALL: &'static [StatusObject] = &[
+        StatusObject::Routes,
+        StatusObject::Stats,
+        StatusObject::Health,
+    ];
+
+    /// Which of `switch`'s status objects `name` stands for, if any
+    pub fn of(switch: &GdpName, name: &GdpName) -> Option<StatusObject> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|object| status_name(switch, *object) == *name)
+    }
+}
+
+/// The name that the switch named `switch` answers Gets for `object` on
+pub fn status_name(switch: &GdpName, object: StatusObject) -> GdpName {
+    let mut data = switch.to_vec();
+    data.extend(b"/status/");
+    data.push(object as u8);
+    name_hash(&data)
+}
+
+/// A status object of a switch, as the payload of a GetReply
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum SwitchStatus {
+    Routes {
+        routes: u64,
+        digest: GdpName,
+    },
+    /// Counter values by name, with the label that they are broken down by if any
+    /// (`route_cache.positive`), in name order
+    Stats {
+        counters: Vec<(String, u64)>,
+    },
+    Health {
+        clock_synced: bool,
+        clock_offset_us: i64,
+        clock_error_us: u64,
+    },
+}
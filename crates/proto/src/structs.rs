@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::mem::{size_of, transmute};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Result};
+use derivative::Derivative;
+use strum_macros::EnumIter;
+
+use crate::extensions::{parse_extensions, HeaderExtension};
+use crate::names::{check_magic, name_hash, GdpName, NAME_LEN};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, EnumIter)]
+pub enum GdpAction {
+    Noop = 0,
+    /// Data that its destination acknowledges with an Ack, routed like a Forward
+    Put = 1,
+    /// Asks for the object named as the destination; switches answer it for their status names
+    /// (see status.rs)
+    Get = 2,
+    RibGet = 3,
+    RibReply = 4,
+    Forward = 5,
+    Nack = 6,
+    Control = 7,
+    TimeGet = 8,
+    TimeReply = 9,
+    RibSearch = 10,
+    RibSearchReply = 11,
+    /// Capabilities exchanged between neighboring switches
+    Heartbeat = 12,
+    /// Asks a locator whether it can still reach the destination name
+    Echo = 13,
+    EchoReply = 14,
+    /// A node registering its own name with the RIB, signed with the name's key
+    RibRegister = 15,
+    /// Changes to the routes of names, replicated from one RIB to another
+    RibSync = 16,
+    /// Acknowledges the changes of a RibSync up to the last
+    RibSyncAck = 17,
+    /// An endpoint ending its flow to the destination, so that the switches on the way and the
+    /// destination can forget it; see FinPayload
+    Fin = 18,
+    /// Asks the switch named as the destination to echo the payload back, to measure the round
+    /// trip
+    Ping = 19,
+    /// The echo of a Ping, with the Ping's payload
+    Pong = 20,
+    /// Acknowledges a Put to its sender: the Put's SequenceNumber, with its source and destination
+    /// swapped, and the content hash of the Put's data as its data
+    Ack = 21,
+    /// The answer to a Get, from the name the Get was addressed to
+    GetReply = 22,
+    /// Names whose bindings a switch learned are dead, gossiped to its neighboring switches
+    Invalidate = 23,
+}
+
+impl GdpAction {
+    /// Whether the action carries data between endpoints, which switches route by destination
+    /// name: Forward, and Put and its Ack
+    pub fn is_data(self) -> bool {
+        matches!(self, GdpAction::Forward | GdpAction::Put | GdpAction::Ack)
+    }
+}
+
+impl Default for GdpAction {
+    fn default() -> Self {
+        GdpAction::Noop
+    }
+}
+
+impl TryFrom<u8> for GdpAction {
+    type Error = anyhow::Error;
+
+    fn try_from(v: u8) -> Result<Self> {
+        match v {
+            x if x == GdpAction::Noop as u8 => Ok(GdpAction::Noop),
+            x if x == GdpAction::Get as u8 => Ok(GdpAction::Get),
+            x if x == GdpAction::Put as u8 => Ok(GdpAction::Put),
+            x if x == GdpAction::RibGet as u8 => Ok(GdpAction::RibGet),
+            x if x == GdpAction::RibReply as u8 => Ok(GdpAction::RibReply),
+            x if x == GdpAction::Forward as u8 => Ok(GdpAction::Forward),
+            x if x == GdpAction::Nack as u8 => Ok(GdpAction::Nack),
+            x if x == GdpAction::Control as u8 => Ok(GdpAction::Control),
+            x if x == GdpAction::TimeGet as u8 => Ok(GdpAction::TimeGet),
+            x if x == GdpAction::TimeReply as u8 => Ok(GdpAction::TimeReply),
+            x if x == GdpAction::RibSearch as u8 => Ok(GdpAction::RibSearch),
+            x if x == GdpAction::RibSearchReply as u8 => Ok(GdpAction::RibSearchReply),
+            x if x == GdpAction::Heartbeat as u8 => Ok(GdpAction::Heartbeat),
+            x if x == GdpAction::Echo as u8 => Ok(GdpAction::Echo),
+            x if x == GdpAction::EchoReply as u8 => Ok(GdpAction::EchoReply),
+            x if x == GdpAction::RibRegister as u8 => Ok(GdpAction::RibRegister),
+            x if x == GdpAction::RibSync as u8 => Ok(GdpAction::RibSync),
+            x if x == GdpAction::RibSyncAck as u8 => Ok(GdpAction::RibSyncAck),
+            x if x == GdpAction::Fin as u8 => Ok(GdpAction::Fin),
+            x if x == GdpAction::Ping as u8 => Ok(GdpAction::Ping),
+            x if x == GdpAction::Pong as u8 => Ok(GdpAction::Pong),
+            x if x == GdpAction::Ack as u8 => Ok(GdpAction::Ack),
+            x if x == GdpAction::GetReply as u8 => Ok(GdpAction::GetReply),
+            x if x == GdpAction::Invalidate as u8 => Ok(GdpAction::Invalidate),
+            unknown => Err(anyhow!("Unknown action byte ({:?})", unknown)),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C, packed)]
+pub struct u16be(u16);
+
+impl From<u16> for u16be {
+    fn from(item: u16) -> Self {
+        u16be(u16::to_be(item))
+    }
+}
+
+impl From<u16be> for u16 {
+    fn from(item: u16be) -> Self {
+        u16::from_be(item.0)
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C, packed)]
+pub struct u64be(u64);
+
+impl From<u64> for u64be {
+    fn from(item: u64) -> Self {
+        u64be(u64::to_be(item))
+    }
+}
+
+impl From<u64be> for u64 {
+    fn from(item: u64be) -> Self {
+        u64::from_be(item.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Derivative)]
+#[derivative(Default)]
+#[repr(C, packed)]
+pub struct GdpHeader {
+    pub field: u16be, // magic number identifying GDP packets and the sender's name type
+    // size of this header as written by the sender, so that fields added later can be skipped
+    #[derivative(Default(value = "GdpHeader::LEN.into()"))]
+    pub header_len: u16be,
+    #[derivative(Default(value = "64"))]
+    pub ttl: u8, // number of GDP-level hops remaining before packet is dropped
+    pub action: u8,            // GDP_ACTION enum
+    pub src: GdpName,          // source (256 bits unless built with another name type)
+    pub dst: GdpName,          // destination
+    pub last_hop: GdpName,     // most recent hop (updated on forwarding)
+    pub content_hash: GdpName, // name hash of the data payload, set at origin (all zeros if unset)
+
+    // size of data payload (format is header -> data -> certs)
+    // this is so we can easily append a cert without an extra copy
+    pub data_len: u16be,
+    // size of the optional in-band telemetry section appended by switches (after the certs)
+    pub telemetry_len: u16be,
+    // assigned at origin and kept on every hop (and in NACKs), so logs from all nodes can be
+    // correlated; zero if the origin did not assign one
+    pub trace_id: u64be,
+}
+
+impl GdpHeader {
+    /// Size of the header fields this version knows about
+    pub const LEN: u16 = size_of::<GdpHeader>() as u16;
+
+    /// Split a packet into its header and everything after it (data, certs, telemetry).
+    /// Headers from newer senders may be longer than ours; the trailing bytes we don't know are skipped.
+    pub fn parse(buf: &[u8]) -> Result<(GdpHeader, &[u8])> {
+        // checked first, as a header with other names has another size
+        ensure!(buf.len() >= 2, "packet too short for a GDP header");
+        check_magic(u16::from_be_bytes([buf[0], buf[1]]))?;
+        ensure!(
+            buf.len() >= size_of::<GdpHeader>(),
+            "packet too short for a GDP header ({} bytes)",
+            buf.len()
+        );
+        let header: [u8; size_of::<GdpHeader>()] =
+            buf[..size_of::<GdpHeader>()].try_into().unwrap();
+        let header: GdpHeader = unsafe { transmute(header) };
+        let header_len = u16::from(header.header_len) as usize;
+        ensure!(
+            header_len >= size_of::<GdpHeader>() && header_len <= buf.len(),
+            "invalid GDP header length {}",
+            header_len
+        );
+        Ok((header, &buf[header_len..]))
+    }
+
+    /// The extensions that `buf` carries after the fixed header fields
+    pub fn parse_extensions(buf: &[u8]) -> Result<Vec<HeaderExtension>> {
+        let (header, _) = GdpHeader::parse(buf)?;
+        parse_extensions(&buf[size_of::<GdpHeader>()..u16::from(header.header_len) as usize])
+    }
+}
+
+/// A trace ID for a new packet: unique across nodes with high probability, never zero
+pub fn new_trace_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos() as u64);
+    // mix the seed so that nodes started at similar times don't produce overlapping sequences
+    let id = (seed ^ ((process::id() as u64) << 40))
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        .wrapping_add(NEXT.fetch_add(1, Ordering::Relaxed));
+    id.max(1)
+}
+
+/// The sequence numbers of the flows of one sender, one flow per destination
+#[derive(Default)]
+pub struct FlowSequences(HashMap<GdpName, u32>);
+
+impl FlowSequences {
+    /// The SequenceNumber of the next packet to `dst`: 1 for the first, and never zero
+    pub fn next(&mut self, dst: GdpName) -> u32 {
+        let seqno = self.0.entry(dst).or_insert(0);
+        *seqno = seqno.checked_add(1).unwrap_or(1);
+        *seqno
+    }
+}
+
+/// Hash carried in `GdpHeader::content_hash`, computed over the data payload only (not the certs)
+pub fn content_hash(data: &[u8]) -> GdpName {
+    name_hash(data)
+}
+
+/// Packets from senders that don't set a hash are let through
+pub fn verify_content_hash(expected: &GdpName, data: &[u8]) -> Result<()> {
+    ensure!(
+        *expected == [0; NAME_LEN] || *expected == content_hash(data),
+        "payload does not match its content hash"
+    );
+    Ok(())
+}
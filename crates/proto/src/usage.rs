@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+use crate::names::GdpName;
+
+/// Traffic that a switch forwarded on behalf of one tenant (the name it was sent from)
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct TenantUsage {
+    pub tenant: GdpName,
+    pub packets: u64,
+    /// GDP bytes forwarded, header and certificates included
+    pub bytes: u64,
+}
+
+/// Usage counted by one switch over one reporting period
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct UsageReport {
+    pub switch: GdpName,
+    /// Consecutive from 0 for each run of a switch; a gap means a report was lost or withheld
+    pub sequence: u64,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub tenants: Vec<TenantUsage>,
+}
+
+/// A report with the switch's Ed25519 signature over its bincode encoding
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SignedUsageReport {
+    pub report: UsageReport,
+    pub signature: ([u8; 32], [u8; 32]),
+}
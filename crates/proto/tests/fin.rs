@@ -0,0 +1,26 @@
+use gdp_proto::{FinKind, FinPayload, GdpAction};
+
+#[test]
+fn kinds_round_trip() {
+    for kind in [FinKind::Close, FinKind::Reset] {
+        let payload = FinPayload { kind }.to_bytes();
+        assert_eq!(FinPayload::parse(&payload), FinPayload { kind });
+    }
+}
+
+#[test]
+fn empty_and_unknown_payloads_close() {
+    assert_eq!(FinPayload::parse(&[]).kind, FinKind::Close);
+    assert_eq!(FinPayload::parse(&[0xee]).kind, FinKind::Close);
+}
+
+#[test]
+fn later_fields_are_skipped() {
+    assert_eq!(FinPayload::parse(&[2, 0xab]).kind, FinKind::Reset);
+}
+
+#[test]
+fn fin_action_round_trips() {
+    let action = GdpAction::try_from(GdpAction::Fin as u8).unwrap();
+    assert_eq!(action, GdpAction::Fin);
+}
@@ -0,0 +1,307 @@
+use gdp_proto::{
+    status_name, write_extensions, AdmissionDecision, AdmissionRequest, CertAttestation,
+    FlowSequences, Fragment, GdpAction, GdpHeader, HeaderExtension, NameType, SecurityLabel,
+    SequenceNumber, StatusObject, EXT_FRAGMENT, EXT_SEQUENCE, MAGIC_NUMBERS,
+};
+use gdp_testutil::{forward_header, header_bytes, name, packet_bytes};
+
+#[test]
+fn parses_header_of_known_length() {
+    let buf = packet_bytes(&forward_header(GdpHeader::LEN), b"hello");
+
+    let (header, payload) = GdpHeader::parse(&buf).unwrap();
+    assert_eq!(header.action, GdpAction::Forward as u8);
+    assert_eq!(header.dst, name(7));
+    assert_eq!(payload, b"hello");
+}
+
+#[test]
+fn default_header_len_is_own_size() {
+    let header = GdpHeader::default();
+    assert_eq!(u16::from(header.header_len), GdpHeader::LEN);
+}
+
+#[test]
+fn skips_unknown_trailing_header_bytes() {
+    let extension = [0xab; 12];
+    let mut buf = header_bytes(&forward_header(GdpHeader::LEN + extension.len() as u16));
+    buf.extend(extension);
+    buf.extend(b"hello");
+
+    let (header, payload) = GdpHeader::parse(&buf).unwrap();
+    assert_eq!(u16::from(header.data_len), 5);
+    assert_eq!(header.dst, name(7));
+    assert_eq!(payload, b"hello");
+}
+
+#[test]
+fn longer_header_with_empty_payload() {
+    let buf = packet_bytes(&forward_header(GdpHeader::LEN + 4), &[0; 4]);
+
+    let (_, payload) = GdpHeader::parse(&buf).unwrap();
+    assert!(payload.is_empty());
+}
+
+#[test]
+fn rejects_header_len_past_end_of_packet() {
+    let buf = packet_bytes(&forward_header(GdpHeader::LEN + 64), b"hello");
+
+    assert!(GdpHeader::parse(&buf).is_err());
+}
+
+#[test]
+fn rejects_header_len_shorter_than_known_fields() {
+    let buf = packet_bytes(&forward_header(GdpHeader::LEN - 1), b"hello");
+
+    assert!(GdpHeader::parse(&buf).is_err());
+}
+
+#[test]
+fn rejects_truncated_header() {
+    let buf = header_bytes(&forward_header(GdpHeader::LEN));
+
+    assert!(GdpHeader::parse(&buf[..buf.len() - 1]).is_err());
+}
+
+#[test]
+fn rejects_wrong_magic() {
+    let mut header = forward_header(GdpHeader::LEN);
+    header.field = 0.into();
+
+    assert!(GdpHeader::parse(&header_bytes(&header)).is_err());
+}
+
+#[test]
+fn parses_extensions_including_unknown_kinds() {
+    let request = AdmissionRequest {
+        rate_kbps: 20_000,
+        min_rate_kbps: 5_000,
+    };
+    let unknown = HeaderExtension {
+        kind: 0xfe,
+        value: vec![1, 2, 3],
+    };
+    let extensions = write_extensions(&[unknown.clone(), request.to_extension()]).unwrap();
+    let header_len = GdpHeader::LEN + extensions.len() as u16;
+    let mut buf = header_bytes(&forward_header(header_len));
+    buf.extend(extensions);
+    buf.extend(b"hello");
+
+    let parsed = GdpHeader::parse_extensions(&buf).unwrap();
+    assert_eq!(parsed[0], unknown);
+    assert_eq!(AdmissionRequest::find(&parsed).unwrap(), Some(request));
+    assert_eq!(AdmissionDecision::find(&parsed).unwrap(), None);
+    assert_eq!(GdpHeader::parse(&buf).unwrap().1, b"hello");
+}
+
+#[test]
+fn rejects_truncated_extension() {
+    let mut buf = header_bytes(&forward_header(GdpHeader::LEN + 3));
+    buf.extend([0x02, 0x05, 0x00]);
+
+    assert!(GdpHeader::parse_extensions(&buf).is_err());
+}
+
+#[test]
+fn fragment_extension_round_trips() {
+    let fragment = Fragment {
+        id: 0xdead_beef,
+        offset: 1400,
+        more: true,
+    };
+    let extensions = write_extensions(&[fragment.to_extension()]).unwrap();
+    let header_len = GdpHeader::LEN + extensions.len() as u16;
+    let mut buf = header_bytes(&forward_header(header_len));
+    buf.extend(extensions);
+    buf.extend(b"hello");
+
+    let parsed = GdpHeader::parse_extensions(&buf).unwrap();
+    assert_eq!(Fragment::find(&parsed).unwrap(), Some(fragment));
+    assert_eq!(GdpHeader::parse(&buf).unwrap().1, b"hello");
+}
+
+#[test]
+fn rejects_short_fragment_extension() {
+    let short = HeaderExtension {
+        kind: EXT_FRAGMENT,
+        value: vec![0; 6],
+    };
+
+    assert!(Fragment::find(&[short]).is_err());
+    assert_eq!(Fragment::find(&[]).unwrap(), None);
+}
+
+#[test]
+fn sequence_numbers_round_trip_as_extensions() {
+    let extensions = write_extensions(&[
+        Fragment {
+            id: 7,
+            offset: 0,
+            more: true,
+        }
+        .to_extension(),
+        SequenceNumber(0x0102_0304).to_extension(),
+    ])
+    .unwrap();
+    let mut buf = header_bytes(&forward_header(GdpHeader::LEN + extensions.len() as u16));
+    buf.extend(extensions);
+    buf.extend(b"hello");
+
+    let parsed = GdpHeader::parse_extensions(&buf).unwrap();
+    assert_eq!(
+        SequenceNumber::find(&parsed).unwrap(),
+        Some(SequenceNumber(0x0102_0304))
+    );
+    assert_eq!(GdpHeader::parse(&buf).unwrap().1, b"hello");
+
+    let short = HeaderExtension {
+        kind: EXT_SEQUENCE,
+        value: vec![0; 3],
+    };
+    assert!(SequenceNumber::find(&[short]).is_err());
+    assert_eq!(SequenceNumber::find(&[]).unwrap(), None);
+}
+
+#[test]
+fn security_label_round_trips_alongside_fragment() {
+    let label = SecurityLabel {
+        level: 3,
+        compartments: 0b1010,
+    };
+    let fragment = Fragment {
+        id: 7,
+        offset: 0,
+        more: true,
+    };
+    let extensions = write_extensions(&[label.to_extension(), fragment.to_extension()]).unwrap();
+    let header_len = GdpHeader::LEN + extensions.len() as u16;
+    let mut buf = header_bytes(&forward_header(header_len));
+    buf.extend(extensions);
+
+    let parsed = GdpHeader::parse_extensions(&buf).unwrap();
+    assert_eq!(SecurityLabel::find(&parsed).unwrap(), Some(label));
+    assert_eq!(Fragment::find(&parsed).unwrap(), Some(fragment));
+}
+
+#[test]
+fn cert_attestation_round_trips_alongside_security_label() {
+    let label = SecurityLabel {
+        level: 1,
+        compartments: 0b1,
+    };
+    let attestation = CertAttestation {
+        attester: name(3),
+        src: name(4),
+        expiration_time: 1_700_000_000,
+        signature: [9; 64],
+    };
+    let extensions = write_extensions(&[label.to_extension(), attestation.to_extension()]).unwrap();
+    let header_len = GdpHeader::LEN + extensions.len() as u16;
+    let mut buf = header_bytes(&forward_header(header_len));
+    buf.extend(extensions);
+
+    let parsed = GdpHeader::parse_extensions(&buf).unwrap();
+    assert_eq!(CertAttestation::find(&parsed).unwrap(), Some(attestation));
+    assert_eq!(SecurityLabel::find(&parsed).unwrap(), Some(label));
+}
+
+#[test]
+fn security_labels_dominate_by_level_and_compartments() {
+    let secret = SecurityLabel {
+        level: 2,
+        compartments: 0b11,
+    };
+    let lower = SecurityLabel {
+        level: 1,
+        compartments: 0b01,
+    };
+    let other_compartment = SecurityLabel {
+        level: 1,
+        compartments: 0b100,
+    };
+
+    assert!(secret.dominates(lower));
+    assert!(secret.dominates(SecurityLabel::UNCLASSIFIED));
+    assert!(!lower.dominates(secret));
+    assert!(!secret.dominates(other_compartment));
+}
+
+#[test]
+fn reports_name_type_mismatch() {
+    let mut header = forward_header(GdpHeader::LEN);
+    let other = if NameType::LOCAL == NameType::Sha256 {
+        NameType::Sha256Truncated160
+    } else {
+        NameType::Sha256
+    };
+    header.field = u16::from_be_bytes([MAGIC_NUMBERS.to_be_bytes()[0], other as u8]).into();
+    let buf = packet_bytes(&header, b"hello");
+
+    let err = GdpHeader::parse(&buf).unwrap_err().to_string();
+    assert!(err.contains("names"), "{}", err);
+    assert!(GdpHeader::parse(&[0x45, 0x00, 0x00]).is_err());
+}
+
+#[test]
+fn rib_register_action_round_trips() {
+    let action = GdpAction::try_from(GdpAction::RibRegister as u8).unwrap();
+    assert_eq!(action, GdpAction::RibRegister);
+}
+
+#[test]
+fn rib_sync_actions_round_trip() {
+    for action in [GdpAction::RibSync, GdpAction::RibSyncAck] {
+        assert_eq!(GdpAction::try_from(action as u8).unwrap(), action);
+    }
+}
+
+#[test]
+fn ping_actions_round_trip() {
+    for action in [GdpAction::Ping, GdpAction::Pong] {
+        assert_eq!(GdpAction::try_from(action as u8).unwrap(), action);
+    }
+}
+
+#[test]
+fn put_actions_round_trip() {
+    for action in [GdpAction::Put, GdpAction::Ack] {
+        assert_eq!(GdpAction::try_from(action as u8).unwrap(), action);
+    }
+}
+
+#[test]
+fn get_actions_round_trip() {
+    for action in [GdpAction::Get, GdpAction::GetReply] {
+        assert_eq!(GdpAction::try_from(action as u8).unwrap(), action);
+    }
+}
+
+#[test]
+fn invalidate_action_round_trips() {
+    assert_eq!(
+        GdpAction::try_from(GdpAction::Invalidate as u8).unwrap(),
+        GdpAction::Invalidate
+    );
+    assert!(GdpAction::try_from(GdpAction::Invalidate as u8 + 1).is_err());
+}
+
+#[test]
+fn status_names_are_distinct_per_switch_and_object() {
+    let (a, b) = (name(1), name(2));
+    for object in StatusObject::ALL {
+        let reserved = status_name(&a, *object);
+        assert_eq!(StatusObject::of(&a, &reserved), Some(*object));
+        assert_eq!(StatusObject::of(&b, &reserved), None);
+        assert_ne!(reserved, a);
+    }
+    assert_eq!(StatusObject::of(&a, &a), None);
+}
+
+#[test]
+fn flow_sequences_number_each_destination_from_one() {
+    let mut sequences = FlowSequences::default();
+    assert_eq!(sequences.next(name(1)), 1);
+    assert_eq!(sequences.next(name(1)), 2);
+    assert_eq!(sequences.next(name(2)), 1);
+    assert_eq!(sequences.next(name(1)), 3);
+}
@@ -0,0 +1,135 @@
+use gdp_proto::{
+    parse_extensions, write_extensions, GdpHeader, GdpName, HeaderExtension, MAGIC_NUMBERS,
+    NAME_LEN,
+};
+use gdp_testutil::header_bytes;
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+/// A packet as a sender lays it out: header, extensions, data, certificates, telemetry
+#[derive(Debug)]
+struct Sections {
+    header: GdpHeader,
+    extensions: Vec<HeaderExtension>,
+    data: Vec<u8>,
+    certs: Vec<u8>,
+    telemetry: Vec<u8>,
+}
+
+impl Sections {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = header_bytes(&self.header);
+        buf.extend(write_extensions(&self.extensions).unwrap());
+        buf.extend(&self.data);
+        buf.extend(&self.certs);
+        buf.extend(&self.telemetry);
+        buf
+    }
+}
+
+fn arb_name() -> impl Strategy<Value = GdpName> {
+    vec(any::<u8>(), NAME_LEN).prop_map(|name| name.try_into().unwrap())
+}
+
+fn arb_extension() -> impl Strategy<Value = HeaderExtension> {
+    (any::<u8>(), vec(any::<u8>(), 0..=255))
+        .prop_map(|(kind, value)| HeaderExtension { kind, value })
+}
+
+prop_compose! {
+    fn arb_sections()(
+        ttl in any::<u8>(),
+        action in any::<u8>(),
+        src in arb_name(),
+        dst in arb_name(),
+        last_hop in arb_name(),
+        content_hash in arb_name(),
+        trace_id in any::<u64>(),
+        extensions in vec(arb_extension(), 0..4),
+        data in vec(any::<u8>(), 0..2048),
+        certs in vec(any::<u8>(), 0..512),
+        telemetry in vec(any::<u8>(), 0..256),
+    ) -> Sections {
+        let extensions_len = write_extensions(&extensions).unwrap().len() as u16;
+        let header = GdpHeader {
+            field: MAGIC_NUMBERS.into(),
+            header_len: (GdpHeader::LEN + extensions_len).into(),
+            ttl,
+            action,
+            src,
+            dst,
+            last_hop,
+            content_hash,
+            data_len: (data.len() as u16).into(),
+            telemetry_len: (telemetry.len() as u16).into(),
+            trace_id: trace_id.into(),
+        };
+        Sections { header, extensions, data, certs, telemetry }
+    }
+}
+
+proptest! {
+    #[test]
+    fn parse_round_trips_header_fields(sections in arb_sections()) {
+        let buf = sections.to_bytes();
+
+        let (header, _) = GdpHeader::parse(&buf).unwrap();
+        prop_assert_eq!(header_bytes(&header), header_bytes(&sections.header));
+        prop_assert_eq!(GdpHeader::parse_extensions(&buf).unwrap(), sections.extensions);
+    }
+
+    #[test]
+    fn sections_partition_the_packet(sections in arb_sections()) {
+        let buf = sections.to_bytes();
+
+        let (header, rest) = GdpHeader::parse(&buf).unwrap();
+        let header_len = u16::from(header.header_len) as usize;
+        let data_len = u16::from(header.data_len) as usize;
+        let telemetry_len = u16::from(header.telemetry_len) as usize;
+        // header -> data -> certs -> telemetry, each starting where the last one ends
+        let cert_start = header_len + data_len;
+        let telemetry_start = buf.len() - telemetry_len;
+        prop_assert!(header_len >= GdpHeader::LEN as usize);
+        prop_assert!(cert_start <= telemetry_start);
+        prop_assert_eq!(rest.len(), buf.len() - header_len);
+        prop_assert_eq!(&buf[header_len..cert_start], &sections.data[..]);
+        prop_assert_eq!(&buf[cert_start..telemetry_start], &sections.certs[..]);
+        prop_assert_eq!(&buf[telemetry_start..], &sections.telemetry[..]);
+    }
+
+    #[test]
+    fn reserializing_a_parsed_packet_is_stable(sections in arb_sections()) {
+        let buf = sections.to_bytes();
+
+        let (header, rest) = GdpHeader::parse(&buf).unwrap();
+        let extensions = GdpHeader::parse_extensions(&buf).unwrap();
+        let mut rebuilt = header_bytes(&header);
+        rebuilt.extend(write_extensions(&extensions).unwrap());
+        rebuilt.extend(rest);
+        prop_assert_eq!(rebuilt, buf);
+    }
+
+    #[test]
+    fn extensions_round_trip(extensions in vec(arb_extension(), 0..8)) {
+        let written = write_extensions(&extensions).unwrap();
+
+        prop_assert_eq!(parse_extensions(&written).unwrap(), extensions);
+    }
+
+    #[test]
+    fn accepted_headers_stay_within_the_packet(
+        with_magic in any::<bool>(),
+        mut buf in vec(any::<u8>(), 2..512),
+    ) {
+        // arbitrary bytes must never panic, and whatever is accepted must be self-consistent
+        if with_magic {
+            buf[..2].copy_from_slice(&MAGIC_NUMBERS.to_be_bytes());
+        }
+        if let Ok((header, rest)) = GdpHeader::parse(&buf) {
+            let header_len = u16::from(header.header_len) as usize;
+            prop_assert!(header_len >= GdpHeader::LEN as usize && header_len <= buf.len());
+            prop_assert_eq!(rest.len(), buf.len() - header_len);
+        }
+        let _ = GdpHeader::parse_extensions(&buf);
+    }
+}
@@ -0,0 +1,33 @@
+use gdp_proto::{NackPayload, NackReason};
+
+#[test]
+fn reasons_round_trip() {
+    for reason in [
+        NackReason::NoRoute,
+        NackReason::TtlExpired,
+        NackReason::BadCert,
+        NackReason::DecryptFail,
+        NackReason::QueueFull,
+    ] {
+        let payload = NackPayload { reason }.to_bytes();
+        assert_eq!(NackPayload::parse(&payload), Some(NackPayload { reason }));
+    }
+}
+
+#[test]
+fn empty_payload_is_from_an_older_switch() {
+    assert_eq!(NackPayload::parse(&[]), None);
+}
+
+#[test]
+fn unknown_codes_are_kept() {
+    let payload = NackPayload::parse(&[0xee]).unwrap();
+    assert_eq!(payload.reason, NackReason::Unknown(0xee));
+    assert_eq!(payload.to_bytes(), vec![0xee]);
+}
+
+#[test]
+fn later_fields_are_skipped() {
+    let payload = NackPayload::parse(&[2, 0xab, 0xcd]).unwrap();
+    assert_eq!(payload.reason, NackReason::TtlExpired);
+}
@@ -0,0 +1,67 @@
+use std::net::Ipv4Addr;
+
+use gdp_proto::{diff_tables, RouteDump, RouteSource, TableExport};
+use gdp_testutil::name;
+
+fn route(index: u8, ip: [u8; 4], source: RouteSource, expiration_time: u64) -> RouteDump {
+    RouteDump {
+        name: name(index),
+        ip: Ipv4Addr::from(ip),
+        expiration_time,
+        source,
+    }
+}
+
+#[test]
+fn digest_ignores_order_and_expiration() {
+    let a = TableExport::new(vec![
+        route(1, [10, 0, 0, 1], RouteSource::Learned, 100),
+        route(2, [10, 0, 0, 2], RouteSource::Delegated, 100),
+    ]);
+    let b = TableExport::new(vec![
+        route(2, [10, 0, 0, 2], RouteSource::Delegated, 250),
+        route(1, [10, 0, 0, 1], RouteSource::Learned, 300),
+    ]);
+
+    assert_eq!(a.digest, b.digest);
+    assert!(a.is_consistent());
+    assert!(diff_tables(&a, &b).is_empty());
+}
+
+#[test]
+fn diff_lists_added_removed_and_changed_routes() {
+    let before = TableExport::new(vec![
+        route(1, [10, 0, 0, 1], RouteSource::Learned, 100),
+        route(2, [10, 0, 0, 2], RouteSource::Learned, 100),
+        route(3, [10, 0, 0, 3], RouteSource::Pinned, 100),
+    ]);
+    let after = TableExport::new(vec![
+        route(1, [10, 0, 0, 1], RouteSource::Learned, 100),
+        route(2, [10, 0, 0, 9], RouteSource::Learned, 100),
+        route(4, [10, 0, 0, 4], RouteSource::Delegated, 100),
+    ]);
+
+    let diff = diff_tables(&before, &after);
+    assert_ne!(before.digest, after.digest);
+    assert_eq!(diff.added, vec![after.routes[2].clone()]);
+    assert_eq!(diff.removed, vec![before.routes[2].clone()]);
+    assert_eq!(
+        diff.changed,
+        vec![(before.routes[1].clone(), after.routes[1].clone())]
+    );
+    let printed = diff.to_string();
+    assert_eq!(printed.lines().count(), 3);
+    assert!(
+        printed.contains("10.0.0.2 -> 10.0.0.9 (learned)"),
+        "{}",
+        printed
+    );
+}
+
+#[test]
+fn altered_export_is_inconsistent() {
+    let mut export = TableExport::new(vec![route(1, [10, 0, 0, 1], RouteSource::Learned, 100)]);
+    export.routes[0].ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    assert!(!export.is_consistent());
+}
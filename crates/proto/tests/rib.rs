@@ -0,0 +1,60 @@
+use gdp_proto::{RibResponse, SerializableSignature};
+use gdp_testutil::name;
+
+#[test]
+fn reads_replies_with_and_without_ttls() {
+    let response = RibResponse {
+        misses: vec![name(1)],
+        route_ttl: Some(600),
+        negative_ttl: Some(30),
+        ..Default::default()
+    };
+    let bytes = bincode::serialize(&response).unwrap();
+    let decoded = RibResponse::decode(&bytes).unwrap();
+    assert_eq!(decoded.misses, response.misses);
+    assert_eq!(
+        (decoded.route_ttl, decoded.negative_ttl),
+        (Some(600), Some(30))
+    );
+
+    // from a RIB that only sends the route TTL, and from one that sends neither
+    let without_negative = &bytes[..bytes.len() - 9];
+    let decoded = RibResponse::decode(without_negative).unwrap();
+    assert_eq!((decoded.route_ttl, decoded.negative_ttl), (Some(600), None));
+    let without_either = &bytes[..bytes.len() - 18];
+    let decoded = RibResponse::decode(without_either).unwrap();
+    assert_eq!(decoded.misses, response.misses);
+    assert_eq!((decoded.route_ttl, decoded.negative_ttl), (None, None));
+}
+
+#[test]
+fn merged_replies_keep_the_first_ttls() {
+    let mut response = RibResponse {
+        misses: vec![name(1)],
+        route_ttl: Some(600),
+        ..Default::default()
+    };
+    response.merge(RibResponse {
+        misses: vec![name(2)],
+        route_ttl: Some(60),
+        negative_ttl: Some(30),
+        ..Default::default()
+    });
+    assert_eq!(response.misses, vec![name(1), name(2)]);
+    assert_eq!(
+        (response.route_ttl, response.negative_ttl),
+        (Some(600), Some(30))
+    );
+}
+
+#[test]
+fn signatures_keep_their_bytes() {
+    let mut bytes = [0; 64];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let signature = SerializableSignature::from(bytes);
+    assert_eq!(<[u8; 64]>::from(signature), bytes);
+    // laid out as the two halves, one after the other
+    assert_eq!(bincode::serialize(&signature).unwrap(), bytes.to_vec());
+}
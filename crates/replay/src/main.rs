@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+use std::process;
+
+use anyhow::Result;
+use clap::{clap_app, value_t};
+use gdp_router::replay;
+
+/// Re-decides every packet in a recording made by a switch with `sample_one_in` set in
+/// record.toml, against the store that was recorded with it, and reports the decisions that
+/// differ. Exits with status 1 if any do.
+fn main() -> Result<()> {
+    let matches = clap_app!(gdp_replay =>
+        (@arg recording: * +takes_value "The recording to replay (decisions.rec by default on the switch)")
+        (@arg verbose: -v --verbose !takes_value "Show every decision, not only those that differ")
+    )
+    .get_matches();
+
+    let path = value_t!(matches, "recording", PathBuf).unwrap_or_else(|e| e.exit());
+    let verbose = matches.is_present("verbose");
+
+    if replay(&path, verbose)? > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+#![feature(array_methods)]
+
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{clap_app, value_t};
+use gdp_router::{
+    export_rib_dump, import_rib_dump, issue_fleet_tokens, load_flags, load_secrets,
+    parse_metrics_addr, set_key, start_rib_server, Env,
+};
+use tracing::Level;
+use tracing_subscriber::fmt;
+
+/// Runs the RIB on its own, for nodes that should not carry the switch.
+/// Reads the same configuration files as `gdp --mode router`, from the working directory.
+fn main() -> Result<()> {
+    let subscriber = fmt::Subscriber::builder()
+        .with_max_level(Level::WARN)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let envs = Env::variants().map(|s| s.to_lowercase());
+    let envs = &envs.each_ref().map(|env| &(env[..]));
+
+    let matches = clap_app!(gdp_rib =>
+        (@arg env: -e --env * +takes_value possible_values(&envs[..]) "The environment in which this node is running")
+        (@arg ip: --ip * +takes_value "The IP address of this node")
+        (@arg use_default: --default !takes_value "Send default response even when GDP Name is invalid")
+        (@arg debug: -d --debug !takes_value "Show detailed debugging messages")
+        (@arg control: --control +takes_value "The localhost UDP port on which to accept control commands")
+        (@arg metrics: --metrics +takes_value "The TCP port on localhost (or address and port) on which to serve metrics to Prometheus")
+        (@setting SubcommandsNegateReqs)
+        (@subcommand token =>
+            (about: "Sign a fleet token for each device key with the operator_key in secrets.toml, and print them for the devices' registration.toml")
+            (@arg prefix: --prefix * +takes_value "The start of the names the tokens cover, in hex")
+            (@arg bits: --bits * +takes_value "How many bits of the prefix the names must start with")
+            (@arg devices: --devices * +takes_value "A file of the public keys of the devices, in hex, one per line")
+            (@arg days: --days +takes_value default_value("30") "How long the token is valid for")
+        )
+        (@subcommand export =>
+            (about: "Write the tables of the rib_storage.toml backend to a dump file, while the RIB is stopped")
+            (@arg file: * "The dump to write")
+        )
+        (@subcommand import =>
+            (about: "Replace the tables of the rib_storage.toml backend with a dump file, while the RIB is stopped")
+            (@arg file: * "The dump to read")
+        )
+    )
+    .get_matches();
+
+    if let Some(token) = matches.subcommand_matches("token") {
+        let bits = value_t!(token, "bits", u16).unwrap_or_else(|e| e.exit());
+        let days = value_t!(token, "days", u64).unwrap_or_else(|e| e.exit());
+        let valid_for = Duration::from_secs(days * 24 * 60 * 60);
+        print!(
+            "{}",
+            issue_fleet_tokens(
+                token.value_of("prefix").unwrap(),
+                bits,
+                token.value_of("devices").unwrap(),
+                valid_for
+            )?
+        );
+        return Ok(());
+    }
+    if let Some(export) = matches.subcommand_matches("export") {
+        let file = export.value_of("file").unwrap();
+        let routes = export_rib_dump(Path::new(file))?;
+        println!("exported {} routes to {}", routes, file);
+        return Ok(());
+    }
+    if let Some(import) = matches.subcommand_matches("import") {
+        let file = import.value_of("file").unwrap();
+        let routes = import_rib_dump(Path::new(file))?;
+        println!("imported {} routes from {}", routes, file);
+        return Ok(());
+    }
+
+    let env = value_t!(matches, "env", Env).unwrap_or_else(|e| e.exit());
+    let path = match env {
+        Env::Local => "conf.toml",
+        Env::Aws => "ec2.toml",
+        Env::Nuc => "nuc.toml",
+    };
+
+    let content = fs::read_to_string(path)?;
+    let config = toml::from_str(&content)?;
+
+    let ip_addr = value_t!(matches, "ip", Ipv4Addr).unwrap_or_else(|e| e.exit());
+    let use_default = matches.is_present("use_default");
+    let debug = matches.is_present("debug");
+    let control_port = value_t!(matches, "control", u16).ok();
+    let metrics_addr = matches
+        .value_of("metrics")
+        .map(parse_metrics_addr)
+        .transpose()?;
+    let flags = load_flags()?;
+
+    // a broken secrets file is reported along with everything else before the ports are bound
+    if let Some(key) = load_secrets().ok().and_then(|secrets| secrets.dtls_key) {
+        set_key(key)?;
+    }
+
+    start_rib_server(
+        config,
+        env,
+        ip_addr,
+        use_default,
+        flags,
+        control_port,
+        metrics_addr,
+        debug,
+    )
+}
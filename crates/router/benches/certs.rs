@@ -0,0 +1,86 @@
+use std::net::Ipv4Addr;
+
+use capsule::net::MacAddr;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::Mbuf;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use gdp_proto::GdpAction;
+use gdp_router::bench::{
+    create_control_request, gdp_name_of_index, metadata_of_index, private_key_of_index, CertDest,
+    Certificate, CertificateBlock, DTls, Gdp, NewRtCert, RtCert,
+};
+
+/// A typical forwarded payload
+const DATA_LEN: usize = 512;
+/// Certificates on a packet by the time a switch adds its own: the client's and one switch's
+const CARRIED: usize = 2;
+const BATCH_SIZE: usize = 128;
+
+/// A certificate from the node with GDP index `index`, delegating to the one with index `to`
+fn certificate(index: u8, to: u8) -> Certificate {
+    RtCert::new_wrapped(
+        metadata_of_index(index),
+        private_key_of_index(index),
+        CertDest::GdpName(gdp_name_of_index(to)),
+        false,
+    )
+    .unwrap()
+}
+
+fn forwarded_packet() -> Gdp<DTls<Ipv4>> {
+    let mut packet = create_control_request(
+        Mbuf::new().unwrap(),
+        GdpAction::Forward,
+        &[0x42; DATA_LEN],
+        MacAddr::broadcast(),
+        Ipv4Addr::new(10, 100, 1, 11),
+        gdp_name_of_index(1),
+        Ipv4Addr::new(10, 100, 1, 12),
+    )
+    .unwrap();
+    packet
+        .set_certs(&CertificateBlock {
+            certificates: (0..CARRIED).map(|_| certificate(1, 2)).collect(),
+        })
+        .unwrap();
+    packet
+}
+
+/// What a switch pays to add its forwarding certificate to a packet: decoding the block and
+/// encoding it again with one more (as it used to), or writing the one it adds in place
+#[capsule::bench(mempool_capacity = 511)]
+fn certificate_append(c: &mut Criterion) {
+    let added = certificate(2, 3);
+    let mut group = c.benchmark_group("certificate_append");
+
+    group.bench_function("decode_and_encode_block", |b| {
+        b.iter_batched(
+            forwarded_packet,
+            |mut packet| {
+                let mut certificates = packet.get_certs().unwrap().certificates.clone();
+                certificates.push(added.clone());
+                packet
+                    .set_certs(&CertificateBlock { certificates })
+                    .unwrap();
+                packet
+            },
+            BatchSize::NumIterations(BATCH_SIZE as u64),
+        )
+    });
+
+    group.bench_function("append_in_place", |b| {
+        b.iter_batched(
+            forwarded_packet,
+            |mut packet| {
+                packet.append_cert(&added).unwrap();
+                packet
+            },
+            BatchSize::NumIterations(BATCH_SIZE as u64),
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, certificate_append);
+criterion_main!(benches);
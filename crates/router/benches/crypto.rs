@@ -0,0 +1,56 @@
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::{Ethernet, Packet, Udp};
+use capsule::Mbuf;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use gdp_router::bench::{decrypt_gdp, encrypt_gdp, DTls};
+
+/// A typical forwarded payload: GDP header, data and a couple of certificates
+const PAYLOAD_LEN: usize = 800;
+const BATCH_SIZE: usize = 128;
+
+fn encrypted_packet() -> DTls<Ipv4> {
+    let packet = Mbuf::new().unwrap();
+    let packet = packet.push::<Ethernet>().unwrap();
+    let packet = packet.push::<Ipv4>().unwrap();
+    let packet = packet.push::<Udp<Ipv4>>().unwrap();
+    let mut packet = packet.push::<DTls<Ipv4>>().unwrap();
+    let offset = packet.payload_offset();
+    packet.mbuf_mut().extend(offset, PAYLOAD_LEN).unwrap();
+    packet
+        .mbuf_mut()
+        .write_data_slice(offset, &[0x42; PAYLOAD_LEN][..])
+        .unwrap();
+    encrypt_gdp(packet).unwrap()
+}
+
+/// What a forwarded packet costs between the RX parse and the TX send, with the envelopes
+/// reconciled on the way in as well as on the way out (as they used to be), or only on the way out
+#[capsule::bench(mempool_capacity = 511)]
+fn forward_round_trip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forward_round_trip");
+
+    group.bench_function("reconcile_on_decrypt_and_encrypt", |b| {
+        b.iter_batched(
+            encrypted_packet,
+            |packet| {
+                let mut packet = decrypt_gdp(packet).unwrap();
+                packet.reconcile_all();
+                encrypt_gdp(packet).unwrap()
+            },
+            BatchSize::NumIterations(BATCH_SIZE as u64),
+        )
+    });
+
+    group.bench_function("reconcile_on_encrypt", |b| {
+        b.iter_batched(
+            encrypted_packet,
+            |packet| encrypt_gdp(decrypt_gdp(packet).unwrap()).unwrap(),
+            BatchSize::NumIterations(BATCH_SIZE as u64),
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, forward_round_trip);
+criterion_main!(benches);
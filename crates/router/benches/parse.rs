@@ -0,0 +1,83 @@
+use std::net::Ipv4Addr;
+
+use capsule::net::MacAddr;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::Mbuf;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use gdp_proto::{
+    parse_extensions, AdmissionRequest, CertAttestation, Fragment, GdpAction, GdpHeader,
+    SecurityLabel,
+};
+use gdp_router::bench::{create_control_request, gdp_name_of_index, DTls, Gdp};
+
+/// A typical forwarded payload
+const DATA_LEN: usize = 512;
+const BATCH_SIZE: usize = 128;
+
+fn labelled_packet() -> Gdp<DTls<Ipv4>> {
+    let mut packet = create_control_request(
+        Mbuf::new().unwrap(),
+        GdpAction::Forward,
+        &[0x42; DATA_LEN],
+        MacAddr::broadcast(),
+        Ipv4Addr::new(10, 100, 1, 11),
+        gdp_name_of_index(1),
+        Ipv4Addr::new(10, 100, 1, 12),
+    )
+    .unwrap();
+    let label = SecurityLabel {
+        level: 2,
+        compartments: 0b101,
+    };
+    packet.set_extensions(&[label.to_extension()]).unwrap();
+    packet
+}
+
+/// What the extension lookups of a forwarded packet cost, with the extensions decoded by
+/// every stage that looks (as they used to be), or once and handed on with the packet
+#[capsule::bench(mempool_capacity = 511)]
+fn extension_lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extension_lookups");
+
+    group.bench_function("decode_per_stage", |b| {
+        b.iter_batched(
+            labelled_packet,
+            |packet| {
+                let header = packet
+                    .mbuf()
+                    .read_data_slice::<u8>(packet.offset(), packet.header_len())
+                    .unwrap();
+                let header = unsafe { header.as_ref() };
+                let decode = || parse_extensions(&header[GdpHeader::LEN as usize..]).unwrap();
+                Fragment::find(&decode()).unwrap();
+                AdmissionRequest::find(&decode()).unwrap();
+                SecurityLabel::find(&decode()).unwrap();
+                CertAttestation::find(&decode()).unwrap();
+                CertAttestation::find(&decode()).unwrap();
+                packet
+            },
+            BatchSize::NumIterations(BATCH_SIZE as u64),
+        )
+    });
+
+    group.bench_function("decode_once", |b| {
+        b.iter_batched(
+            labelled_packet,
+            |packet| {
+                Fragment::find(packet.extensions().unwrap()).unwrap();
+                AdmissionRequest::find(packet.extensions().unwrap()).unwrap();
+                SecurityLabel::find(packet.extensions().unwrap()).unwrap();
+                CertAttestation::find(packet.extensions().unwrap()).unwrap();
+                CertAttestation::find(packet.extensions().unwrap()).unwrap();
+                packet
+            },
+            BatchSize::NumIterations(BATCH_SIZE as u64),
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, extension_lookups);
+criterion_main!(benches);
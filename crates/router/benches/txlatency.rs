@@ -0,0 +1,83 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use capsule::batch::{PacketRx, PacketTx, Pipeline, Poll};
+use capsule::Mbuf;
+use criterion::{criterion_group, criterion_main, Criterion};
+use gdp_router::bench::{LowLatency, PriorityConfig, SendBatched, TxConfig};
+
+/// A heartbeat-sized frame
+const PACKET_LEN: usize = 100;
+
+/// Stands in for an RX queue that a low-rate flow delivers one packet to at a time
+#[derive(Clone, Default)]
+struct Trickle(Rc<RefCell<Vec<Mbuf>>>);
+
+impl PacketRx for Trickle {
+    fn receive(&mut self) -> Vec<Mbuf> {
+        self.0.borrow_mut().drain(..).collect()
+    }
+}
+
+/// Stands in for a TX queue, counting the packets handed to it
+#[derive(Clone, Default)]
+struct Wire(Rc<Cell<usize>>);
+
+impl PacketTx for Wire {
+    fn transmit(&mut self, packets: Vec<Mbuf>) {
+        self.0.set(self.0.get() + packets.len());
+    }
+}
+
+fn small_packet() -> Mbuf {
+    let mut packet = Mbuf::new().unwrap();
+    packet.extend(0, PACKET_LEN).unwrap();
+    packet
+}
+
+/// Time from a lone packet being polled to it being handed to the TX queue
+fn time_to_wire(low_latency: LowLatency, iters: u64) -> Duration {
+    let rx = Trickle::default();
+    let wire = Wire::default();
+    let config = TxConfig {
+        low_latency,
+        ..Default::default()
+    };
+    let mut send = Poll::new(rx.clone()).send_batched(
+        wire.clone(),
+        "bench",
+        config,
+        PriorityConfig::default(),
+        |_| false,
+    );
+    let mut total = Duration::default();
+    for sent in 1..=iters as usize {
+        rx.0.borrow_mut().push(small_packet());
+        let start = Instant::now();
+        while wire.0.get() < sent {
+            send.run_once();
+        }
+        total += start.elapsed();
+    }
+    total
+}
+
+/// What a packet of a low-rate flow waits for its TX burst, coalesced or on the low-latency path
+#[capsule::bench(mempool_capacity = 511)]
+fn lone_packet_latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lone_packet_latency");
+
+    group.bench_function("coalesced", |b| {
+        b.iter_custom(|iters| time_to_wire(LowLatency::Off, iters))
+    });
+
+    group.bench_function("low_latency", |b| {
+        b.iter_custom(|iters| time_to_wire(LowLatency::All, iters))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, lone_packet_latency);
+criterion_main!(benches);
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use capsule::batch::{Either, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{metrics, PortQueue};
+use gdp_proto::{AdmissionDecision, AdmissionRequest, GdpName};
+use metrics_runtime::data::Counter;
+use rand::Rng;
+use tokio_timer::delay_for;
+
+use crate::certificates::GdpMeta;
+use crate::conntrack::FlowKey;
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::kvs::Store;
+use crate::probe::{send_echo, EchoAnswer};
+use crate::schedule::Schedule;
+use crate::switch::{add_forwarding_cert, bounce_udp, forward_gdp};
+
+/*
+   Clients ask before starting a high-rate flow, so that the path is not pushed into collapse:
+   - the first packet of the flow carries an admission request extension with the rate the client
+     wants and the least it can use; the switch it is attached to answers it in place of
+     forwarding it, with a decision extension
+   - the first request for a flow is answered Pending, and the path to the destination's locator
+     is probed: PROBE_DURATION of Echoes, padded to PROBE_PACKET_SIZE and paced at the
+     requested rate, each answered by the locator
+   - the rate at which answers come back is what the path sustained: the flow is admitted if that
+     is close enough to what was asked for, downgraded to it if it is at least the minimum,
+     and rejected otherwise
+   - the client asks again until it gets a decision, which is kept for DECISION_TTL
+*/
+
+/// How often the schedule sends the next slice of each burst
+const PACING_TICK: Duration = Duration::from_millis(1);
+const PROBE_DURATION: Duration = Duration::from_millis(20);
+const PROBE_PACKET_SIZE: usize = 1000;
+/// Bounds the cost of a probe, however high the requested rate
+const MAX_PROBE_PACKETS: usize = 256;
+/// Answers that arrive later than this after the last probe was sent are not counted
+const ANSWER_TIMEOUT: Duration = Duration::from_millis(200);
+/// Flows are admitted if the path sustained this share of the requested rate
+const ADMIT_SHARE: f64 = 0.9;
+/// After this, the next request for the flow probes the path again
+const DECISION_TTL: Duration = Duration::from_secs(30);
+
+struct Burst {
+    locator: Ipv4Addr,
+    total: usize,
+    sent: usize,
+    started: Option<Instant>,
+    answered: usize,
+    last_answer: Option<Instant>,
+}
+
+enum FlowAdmission {
+    Probing {
+        request: AdmissionRequest,
+        burst: Burst,
+    },
+    Decided {
+        decision: AdmissionDecision,
+        at: Instant,
+    },
+}
+
+struct AdmissionState {
+    flows: Mutex<HashMap<FlowKey, FlowAdmission>>,
+    /// The flow that each outstanding probe packet belongs to
+    nonces: Mutex<HashMap<u64, FlowKey>>,
+    admitted: Counter,
+    downgraded: Counter,
+    rejected: Counter,
+}
+
+/// Decides whether to admit flows by probing their paths. Shared by all cores of a switch.
+#[derive(Clone, Copy)]
+pub struct Admission(&'static AdmissionState);
+
+impl Admission {
+    pub fn new(nic_name: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter =
+            |name: &'static str| sink.counter_with_labels(name, vec![("nic", nic_name)]);
+        Admission(Box::leak(Box::new(AdmissionState {
+            flows: Mutex::new(HashMap::new()),
+            nonces: Mutex::new(HashMap::new()),
+            admitted: counter("admission.admitted"),
+            downgraded: counter("admission.downgraded"),
+            rejected: counter("admission.rejected"),
+        })))
+    }
+
+    /// The decision for a flow whose packets go to `locator`, probing the path if there is none yet
+    pub fn decide(
+        &self,
+        flow: FlowKey,
+        locator: Ipv4Addr,
+        request: AdmissionRequest,
+    ) -> AdmissionDecision {
+        let mut flows = self.0.flows.lock().recover();
+        match flows.get(&flow) {
+            Some(FlowAdmission::Decided { decision, at }) if at.elapsed() < DECISION_TTL => {
+                return *decision
+            }
+            Some(FlowAdmission::Probing { .. }) => return AdmissionDecision::Pending,
+            _ => {}
+        }
+        // kbit/s for a number of milliseconds is that many bits
+        let bits = request.rate_kbps as u64 * PROBE_DURATION.as_millis() as u64;
+        let total = (bits / (PROBE_PACKET_SIZE as u64 * 8)).clamp(1, MAX_PROBE_PACKETS as u64);
+        flows.insert(
+            flow,
+            FlowAdmission::Probing {
+                request,
+                burst: Burst {
+                    locator,
+                    total: total as usize,
+                    sent: 0,
+                    started: None,
+                    answered: 0,
+                    last_answer: None,
+                },
+            },
+        );
+        AdmissionDecision::Pending
+    }
+
+    /// Forget what was decided about `flow`, which its endpoint ended, along with its probes
+    pub fn forget(&self, flow: FlowKey) {
+        if self.0.flows.lock().recover().remove(&flow).is_some() {
+            self.0
+                .nonces
+                .lock()
+                .recover()
+                .retain(|_, probed| *probed != flow);
+        }
+    }
+
+    /// Count the answer to an Echo, if it was one of our probes
+    pub fn handle_answer(&self, answer: EchoAnswer) {
+        let flow = match self.0.nonces.lock().recover().remove(&answer.nonce) {
+            Some(flow) => flow,
+            None => return,
+        };
+        if let Some(FlowAdmission::Probing { burst, .. }) =
+            self.0.flows.lock().recover().get_mut(&flow)
+        {
+            // the destination's locator no longer reaching it counts as a lost probe
+            if answer.reachable {
+                burst.answered += 1;
+                burst.last_answer = Some(Instant::now());
+            }
+        }
+    }
+
+    fn conclude(&self, request: AdmissionRequest, burst: &Burst) -> AdmissionDecision {
+        let measured_kbps = match (burst.started, burst.last_answer) {
+            (Some(started), Some(last_answer)) => {
+                // the first answer cannot come back before the burst has been fully sent
+                let elapsed = (last_answer - started).max(PROBE_DURATION);
+                (burst.answered * PROBE_PACKET_SIZE * 8) as f64 / elapsed.as_secs_f64() / 1000.0
+            }
+            _ => 0.0,
+        };
+        if measured_kbps >= request.rate_kbps as f64 * ADMIT_SHARE {
+            self.0.admitted.increment();
+            AdmissionDecision::Admit {
+                rate_kbps: request.rate_kbps,
+            }
+        } else if measured_kbps >= request.min_rate_kbps as f64 && measured_kbps >= 1.0 {
+            self.0.downgraded.increment();
+            AdmissionDecision::Downgrade {
+                rate_kbps: measured_kbps as u32,
+            }
+        } else {
+            self.0.rejected.increment();
+            AdmissionDecision::Reject
+        }
+    }
+
+    /// Send the next slice of each burst, and decide on the flows whose probing is over
+    fn run_probes(&self, q: &PortQueue, src: PortIdentity, src_gdp_name: GdpName, debug: bool) {
+        let ticks = (PROBE_DURATION.as_micros() / PACING_TICK.as_micros()) as usize;
+        let mut flows = self.0.flows.lock().recover();
+        for (flow, admission) in flows.iter_mut() {
+            let (request, burst) = match admission {
+                FlowAdmission::Probing { request, burst } => (*request, burst),
+                _ => continue,
+            };
+            if burst.sent < burst.total {
+                let slice = ((burst.total + ticks - 1) / ticks).min(burst.total - burst.sent);
+                for _ in 0..slice {
+                    let nonce = rand::thread_rng().gen();
+                    self.0.nonces.lock().recover().insert(nonce, *flow);
+                    send_echo(
+                        q,
+                        src,
+                        src_gdp_name,
+                        flow.1,
+                        burst.locator,
+                        nonce,
+                        PROBE_PACKET_SIZE,
+                    );
+                }
+                burst.started.get_or_insert_with(Instant::now);
+                burst.sent += slice;
+            } else if burst.started.map_or(false, |started| {
+                started.elapsed() >= PROBE_DURATION + ANSWER_TIMEOUT
+            }) {
+                let decision = self.conclude(request, burst);
+                if debug {
+                    println!(
+                        "admission for {:?} -> {:?}: {:?} ({} of {} probes answered)",
+                        flow.0, flow.1, decision, burst.answered, burst.total
+                    );
+                }
+                *admission = FlowAdmission::Decided {
+                    decision,
+                    at: Instant::now(),
+                };
+            }
+        }
+        flows.retain(|_, admission| match admission {
+            FlowAdmission::Decided { at, .. } => at.elapsed() < DECISION_TTL,
+            FlowAdmission::Probing { .. } => true,
+        });
+        let mut nonces = self.0.nonces.lock().recover();
+        nonces.retain(|_, flow| matches!(flows.get(flow), Some(FlowAdmission::Probing { .. })));
+    }
+}
+
+/// Answer an admission request in place of forwarding it: the packet goes back to the client,
+/// without its data and with our decision
+#[allow(clippy::too_many_arguments)]
+pub fn answer_admission(
+    mut packet: Gdp<DTls<Ipv4>>,
+    decision: AdmissionDecision,
+    gdp_name: GdpName,
+    meta: GdpMeta,
+    private_key: [u8; 32],
+    store: Store,
+    identity: PortIdentity,
+) -> Result<Either<Gdp<DTls<Ipv4>>>> {
+    let client_ip = packet.envelope().envelope().envelope().src();
+    let client = packet.src();
+    packet.remove_payload()?;
+    packet.set_extensions(&[decision.to_extension()])?;
+    packet.set_data_len(0);
+    packet.set_telemetry_len(0);
+    packet.seal_content()?;
+    packet.set_src(gdp_name);
+    packet.set_dst(client);
+    // the certificate that lets the client's sidecar accept a packet from us
+    add_forwarding_cert(&mut packet, store, meta, private_key)?;
+    bounce_udp(packet.envelope_mut().envelope_mut());
+    forward_gdp(packet, client_ip, identity, gdp_name)
+}
+
+/// Probe the paths of flows that asked to be admitted, in the background of the GDP pipeline
+pub fn admission_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    admission: Admission,
+    debug: bool,
+) -> impl Pipeline {
+    Schedule::new("admission", async move {
+        loop {
+            admission.run_probes(&q, src, src_gdp_name, debug);
+            delay_for(PACING_TICK).await;
+        }
+    })
+}
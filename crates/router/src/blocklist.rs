@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::iter;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::{BlockedPeer, BlockedSource, GdpName};
+use once_cell::sync::Lazy;
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+
+/*
+   Senders that misbehave are dropped before their packets cost much:
+   - stages that catch a packet misbehaving report its source name and the previous hop it came
+     through, with the kind of abuse, which adds to the score of that pair; scores halve every
+     SCORE_HALF_LIFE, so that the odd bad packet from a well-behaved source never adds up to a
+     block. Keying on the pair rather than the previous hop means that a neighbor switch is never
+     blocked for what it forwards, and a forged source name can only cost the traffic that came
+     through the hop that forged it
+   - a pair whose score reaches BLOCK_THRESHOLD is blocked for BASE_BLOCK_SECS, doubled for each
+     time it was blocked before (up to MAX_BLOCK_SECS); offences are forgotten once a pair goes
+     OFFENCE_MEMORY_SECS without being blocked. Packets of blocked pairs are dropped right after
+     GDP parsing, ahead of the certificate checks
+   - operators can block peers by IP address, which are dropped right after IP parsing, ahead of
+     decryption, or lift blocks and exempt peers from automatic blocking, over the control socket
+   - at most MAX_OFFENDERS sources and peers are remembered; once that many have something worth
+     remembering, reports against new ones are dropped until some are forgotten
+*/
+
+const SCORE_HALF_LIFE: Duration = Duration::from_secs(60);
+const BLOCK_THRESHOLD: f64 = 100.0;
+const BASE_BLOCK_SECS: u64 = 60;
+const MAX_BLOCK_SECS: u64 = 24 * 60 * 60;
+const OFFENCE_MEMORY_SECS: u64 = 24 * 60 * 60;
+/// How long an operator's block lasts if the operator does not say
+const DEFAULT_OPERATOR_BLOCK_SECS: u64 = 60 * 60;
+const MAX_OFFENDERS: usize = 1 << 16;
+
+/// Misbehavior that counts toward blocking the source that sent it
+#[derive(Clone, Copy, Debug)]
+pub enum Abuse {
+    /// A certificate block larger than the switch is willing to verify
+    OversizedCerts,
+    /// A payload that does not match its content hash
+    BadContentHash,
+}
+
+impl Abuse {
+    fn score(self) -> f64 {
+        match self {
+            Abuse::OversizedCerts => 25.0,
+            Abuse::BadContentHash => 10.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Offender {
+    /// Blocked by an operator, or exempted from automatic blocks
+    Peer(Ipv4Addr),
+    /// A source name, as it arrives through the previous hop `via`
+    Source { name: GdpName, via: Ipv4Addr },
+}
+
+#[derive(Default)]
+struct Record {
+    score: f64,
+    scored_at: Option<Instant>,
+    offences: u32,
+    last_blocked: u64,
+    blocked_until: u64,
+    by_operator: bool,
+    exempt_until: u64,
+}
+
+impl Record {
+    fn worth_keeping(&self, now: u64) -> bool {
+        self.blocked_until > now
+            || self.exempt_until > now
+            || self.score >= 1.0
+            || (self.offences > 0 && now.saturating_sub(self.last_blocked) <= OFFENCE_MEMORY_SECS)
+    }
+}
+
+pub struct Blocklist {
+    records: Mutex<HashMap<Offender, Record>>,
+    /// When the block on each blocked offender ends, read for every packet
+    blocked: RwLock<HashMap<Offender, u64>>,
+    /// Entries in `blocked`, so that packets skip the lock while nobody is blocked
+    blocked_count: AtomicUsize,
+}
+
+// shared by every pipeline and the control socket
+static BLOCKLIST: Lazy<Blocklist> = Lazy::new(Blocklist::new);
+
+pub fn blocklist() -> &'static Blocklist {
+    &BLOCKLIST
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// Forget what has nothing left to remember
+fn forget(records: &mut HashMap<Offender, Record>, now: u64) {
+    records.retain(|_, record| record.worth_keeping(now));
+}
+
+impl Blocklist {
+    fn new() -> Self {
+        Blocklist {
+            records: Mutex::new(HashMap::new()),
+            blocked: RwLock::new(HashMap::new()),
+            blocked_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_offender_blocked(&self, offender: Offender) -> bool {
+        if self.blocked_count.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        self.blocked
+            .read()
+            .recover()
+            .get(&offender)
+            .map_or(false, |until| *until > now_secs())
+    }
+
+    /// Whether an operator blocked the peer at `ip`
+    pub fn is_blocked(&self, ip: Ipv4Addr) -> bool {
+        self.is_offender_blocked(Offender::Peer(ip))
+    }
+
+    /// Whether packets from `source` that come through `via` are blocked
+    pub fn is_source_blocked(&self, source: GdpName, via: Ipv4Addr) -> bool {
+        self.is_offender_blocked(Offender::Source { name: source, via })
+    }
+
+    fn set_blocked(&self, blocks: impl IntoIterator<Item = (Offender, Option<u64>)>) {
+        let now = now_secs();
+        let mut blocked = self.blocked.write().recover();
+        for (offender, until) in blocks {
+            match until {
+                Some(until) => blocked.insert(offender, until),
+                None => blocked.remove(&offender),
+            };
+        }
+        blocked.retain(|_, until| *until > now);
+        self.blocked_count.store(blocked.len(), Ordering::Relaxed);
+    }
+
+    /// `source` was caught at `abuse` in a packet that came through `via`, which gets the pair
+    /// blocked if it keeps at it
+    pub fn report(&self, source: GdpName, via: Ipv4Addr, abuse: Abuse) {
+        let now = now_secs();
+        let mut records = self.records.lock().recover();
+        let exempt = records
+            .get(&Offender::Peer(via))
+            .map_or(false, |peer| peer.exempt_until > now);
+        if exempt {
+            return;
+        }
+        let offender = Offender::Source { name: source, via };
+        if !records.contains_key(&offender) && records.len() >= MAX_OFFENDERS {
+            forget(&mut records, now);
+            if records.len() >= MAX_OFFENDERS {
+                return;
+            }
+        }
+        let record = records.entry(offender).or_default();
+        if record.blocked_until > now {
+            return;
+        }
+        if let Some(scored_at) = record.scored_at {
+            let half_lives = scored_at.elapsed().as_secs_f64() / SCORE_HALF_LIFE.as_secs_f64();
+            record.score *= 0.5f64.powf(half_lives);
+        }
+        record.score += abuse.score();
+        record.scored_at = Some(Instant::now());
+        if record.score < BLOCK_THRESHOLD {
+            return;
+        }
+        if now.saturating_sub(record.last_blocked) > OFFENCE_MEMORY_SECS {
+            record.offences = 0;
+        }
+        let duration = BASE_BLOCK_SECS
+            .saturating_mul(1 << record.offences.min(31))
+            .min(MAX_BLOCK_SECS);
+        record.offences += 1;
+        record.score = 0.0;
+        record.last_blocked = now;
+        record.blocked_until = now + duration;
+        println!(
+            "blocklist: blocking {:?} through {} for {}s after {:?} (offence {})",
+            source, via, duration, abuse, record.offences
+        );
+        self.set_blocked(iter::once((offender, Some(record.blocked_until))));
+    }
+
+    /// Block `ip` on an operator's behalf, returning when the block ends
+    pub fn block(&self, ip: Ipv4Addr, duration_secs: Option<u64>) -> u64 {
+        let until = now_secs() + duration_secs.unwrap_or(DEFAULT_OPERATOR_BLOCK_SECS);
+        let mut records = self.records.lock().recover();
+        // operators are few, so their blocks are kept past MAX_OFFENDERS
+        let peer = records.entry(Offender::Peer(ip)).or_default();
+        peer.blocked_until = until;
+        peer.by_operator = true;
+        self.set_blocked(iter::once((Offender::Peer(ip), Some(until))));
+        until
+    }
+
+    /// Lift the block on `ip` and on the sources that came through it, if any, returning whether
+    /// there was one
+    pub fn unblock(&self, ip: Ipv4Addr, exempt_secs: Option<u64>) -> bool {
+        let now = now_secs();
+        let mut records = self.records.lock().recover();
+        let lifted = records
+            .drain_filter(|offender, _| match offender {
+                Offender::Peer(peer) => *peer == ip,
+                Offender::Source { via, .. } => *via == ip,
+            })
+            .collect::<Vec<_>>();
+        if let Some(exempt_secs) = exempt_secs {
+            records.insert(
+                Offender::Peer(ip),
+                Record {
+                    exempt_until: now + exempt_secs,
+                    ..Default::default()
+                },
+            );
+        }
+        let was_blocked = lifted.iter().any(|(_, record)| record.blocked_until > now);
+        self.set_blocked(lifted.into_iter().map(|(offender, _)| (offender, None)));
+        was_blocked
+    }
+
+    /// The peers that operators blocked, and are blocked right now
+    pub fn dump(&self) -> Vec<BlockedPeer> {
+        let now = now_secs();
+        let mut records = self.records.lock().recover();
+        forget(&mut records, now);
+        records
+            .iter()
+            .filter(|(_, record)| record.blocked_until > now)
+            .filter_map(|(offender, record)| match offender {
+                Offender::Peer(ip) => Some(BlockedPeer {
+                    ip: *ip,
+                    expiration_time: record.blocked_until,
+                    offences: record.offences,
+                    by_operator: record.by_operator,
+                }),
+                Offender::Source { .. } => None,
+            })
+            .collect()
+    }
+
+    /// The sources blocked for abuse right now, with the hops they are blocked through
+    pub fn dump_sources(&self) -> Vec<BlockedSource> {
+        let now = now_secs();
+        let records = self.records.lock().recover();
+        records
+            .iter()
+            .filter(|(_, record)| record.blocked_until > now)
+            .filter_map(|(offender, record)| match offender {
+                Offender::Source { name, via } => Some(BlockedSource {
+                    source: *name,
+                    via: *via,
+                    expiration_time: record.blocked_until,
+                    offences: record.offences,
+                }),
+                Offender::Peer(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Report the source of `packet` at `abuse`, as it arrived through its previous hop
+pub fn report_packet(packet: &Gdp<DTls<Ipv4>>, abuse: Abuse) {
+    blocklist().report(
+        packet.src(),
+        packet.envelope().envelope().envelope().src(),
+        abuse,
+    );
+}
+
+/// Whether the source of `packet` is blocked through the hop it arrived from
+pub fn is_packet_blocked(packet: &Gdp<DTls<Ipv4>>) -> bool {
+    blocklist().is_source_blocked(packet.src(), packet.envelope().envelope().envelope().src())
+}
+
+/// Check a packet's content hash, reporting its source if it does not match
+pub fn verify_content_or_report(packet: &Gdp<DTls<Ipv4>>) -> Result<()> {
+    let verified = packet.verify_content();
+    if verified.is_err() {
+        report_packet(packet, Abuse::BadContentHash);
+    }
+    verified
+}
+
+#[cfg(test)]
+mod tests {
+    use gdp_testutil::name;
+
+    use super::*;
+
+    const NEIGHBOR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OTHER_NEIGHBOR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+    fn report_times(blocklist: &Blocklist, source: GdpName, via: Ipv4Addr, times: usize) {
+        for _ in 0..times {
+            blocklist.report(source, via, Abuse::OversizedCerts);
+        }
+    }
+
+    #[test]
+    fn blocks_only_sources_that_keep_at_it() {
+        let blocklist = Blocklist::new();
+        report_times(&blocklist, name(1), NEIGHBOR, 3);
+        assert!(!blocklist.is_source_blocked(name(1), NEIGHBOR));
+        report_times(&blocklist, name(1), NEIGHBOR, 2);
+        assert!(blocklist.is_source_blocked(name(1), NEIGHBOR));
+    }
+
+    #[test]
+    fn blocks_the_source_not_the_hop_it_came_through() {
+        let blocklist = Blocklist::new();
+        report_times(&blocklist, name(1), NEIGHBOR, 5);
+        assert!(blocklist.is_source_blocked(name(1), NEIGHBOR));
+        // the neighbor that forwarded the abuse, and the others it forwards, still get through
+        assert!(!blocklist.is_blocked(NEIGHBOR));
+        assert!(!blocklist.is_source_blocked(name(2), NEIGHBOR));
+        // as does the source through its other hops, so that a forged name costs nobody else
+        assert!(!blocklist.is_source_blocked(name(1), OTHER_NEIGHBOR));
+        let sources = blocklist.dump_sources();
+        assert_eq!(sources.len(), 1);
+        assert_eq!((sources[0].source, sources[0].via), (name(1), NEIGHBOR));
+        assert!(blocklist.dump().is_empty());
+    }
+
+    #[test]
+    fn repeat_offences_block_for_longer() {
+        let blocklist = Blocklist::new();
+        report_times(&blocklist, name(1), NEIGHBOR, 5);
+        let first = blocklist.dump_sources()[0].expiration_time;
+        // let the first block run out
+        let offender = Offender::Source {
+            name: name(1),
+            via: NEIGHBOR,
+        };
+        blocklist
+            .records
+            .lock()
+            .recover()
+            .get_mut(&offender)
+            .unwrap()
+            .blocked_until = 0;
+        report_times(&blocklist, name(1), NEIGHBOR, 5);
+        let second = blocklist.dump_sources().remove(0);
+        assert_eq!(second.offences, 2);
+        assert!(second.expiration_time >= first + BASE_BLOCK_SECS);
+    }
+
+    #[test]
+    fn operators_block_and_unblock_peers() {
+        let blocklist = Blocklist::new();
+        blocklist.block(NEIGHBOR, Some(60));
+        assert!(blocklist.is_blocked(NEIGHBOR));
+        assert!(blocklist.dump()[0].by_operator);
+        report_times(&blocklist, name(1), NEIGHBOR, 5);
+        // lifting the peer's block lifts those of the sources that came through it
+        assert!(blocklist.unblock(NEIGHBOR, Some(60)));
+        assert!(!blocklist.is_blocked(NEIGHBOR));
+        assert!(!blocklist.is_source_blocked(name(1), NEIGHBOR));
+        // and the exemption keeps them from being blocked again
+        report_times(&blocklist, name(1), NEIGHBOR, 5);
+        assert!(!blocklist.is_source_blocked(name(1), NEIGHBOR));
+        assert!(!blocklist.unblock(OTHER_NEIGHBOR, None));
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_offenders() {
+        let blocklist = Blocklist::new();
+        for i in 0..MAX_OFFENDERS as u32 + 16 {
+            blocklist.report(name(1), Ipv4Addr::from(i), Abuse::BadContentHash);
+        }
+        assert_eq!(blocklist.records.lock().recover().len(), MAX_OFFENDERS);
+        // scores fade, and then make room
+        for record in blocklist.records.lock().recover().values_mut() {
+            record.score = 0.0;
+        }
+        blocklist.report(name(2), NEIGHBOR, Abuse::BadContentHash);
+        assert_eq!(blocklist.records.lock().recover().len(), 1);
+    }
+}
@@ -0,0 +1,325 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use gdp_proto::{BranchReport, GdpAction};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::isolation::Recover;
+
+/*
+   Where the pipelines branch, each arm counts the packets that take it, so that the checks
+   ahead of the busy arms can be ordered to suit the traffic:
+   - every profiled branch has a counter per arm, shared by the pipelines of every core. Each
+     pipeline counts in its own cells and adds them to the shared counters every FLUSH_INTERVAL
+     packets, so that cores do not contend for them on every packet
+   - DumpBranches over the control socket lists each branch's arms, busiest first: the order to
+     hard-code for a production build, once a workload has run for a while
+   - the action byte of every GDP packet is decoded through a table of all 256 values, whichever
+     actions are known and however they are numbered, so its cost does not depend on the
+     traffic. With `adaptive` in branches.toml, each pipeline also ranks the actions by its own
+     counts every `reorder_interval` packets, and DumpBranches shows the latest ranking, for
+     builds that hard-code the checks in that order
+   - the arms of a group_by are looked up by key, not tried in turn, so they are only counted
+*/
+
+/// Pipelines add their counts to the shared ones this often, in packets
+const FLUSH_INTERVAL: u64 = 1024;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct BranchConfig {
+    /// Check the actions busiest first, rather than in a fixed order
+    #[serde(default)]
+    pub adaptive: bool,
+    /// How many packets a pipeline decodes between sorting its actions again
+    #[serde(default = "default_reorder_interval")]
+    pub reorder_interval: u64,
+}
+
+fn default_reorder_interval() -> u64 {
+    1 << 16
+}
+
+impl Default for BranchConfig {
+    fn default() -> Self {
+        BranchConfig {
+            adaptive: false,
+            reorder_interval: default_reorder_interval(),
+        }
+    }
+}
+
+pub fn load_branch_config() -> Result<BranchConfig> {
+    let content = fs::read_to_string("branches.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// The arms of one branch on one port, with the hits of all of its pipelines
+pub struct Branch {
+    branch: &'static str,
+    nic_name: &'static str,
+    arms: Vec<(&'static str, AtomicU64)>,
+    /// The arms as the last pipeline to rank them ranked them, busiest first, if they adapt
+    adaptive_order: Mutex<Option<Vec<usize>>>,
+}
+
+impl Branch {
+    fn report(&self) -> BranchReport {
+        let mut arms: Vec<_> = self
+            .arms
+            .iter()
+            .map(|(arm, hits)| (arm.to_string(), hits.load(Ordering::Relaxed)))
+            .collect();
+        arms.sort_by_key(|(_, hits)| Reverse(*hits));
+        let adaptive_order = self.adaptive_order.lock().recover().as_ref().map(|order| {
+            order
+                .iter()
+                .map(|&arm| self.arms[arm].0.to_string())
+                .collect()
+        });
+        BranchReport {
+            branch: self.branch.to_owned(),
+            nic: self.nic_name.to_owned(),
+            arms,
+            adaptive_order,
+        }
+    }
+}
+
+pub struct Branches {
+    branches: Mutex<Vec<&'static Branch>>,
+}
+
+// created on first use, shared by every pipeline and the control socket
+static BRANCHES: Lazy<Branches> = Lazy::new(|| Branches {
+    branches: Mutex::new(Vec::new()),
+});
+
+pub fn branches() -> &'static Branches {
+    &BRANCHES
+}
+
+impl Branches {
+    /// Counts for a pipeline on `nic_name` of the branch with `arms`, which is registered by the
+    /// first pipeline to ask and shared with the others
+    pub fn counts(
+        &self,
+        branch: &'static str,
+        nic_name: &'static str,
+        arms: &[&'static str],
+    ) -> ArmCounts {
+        let shared = {
+            let mut branches = self.branches.lock().recover();
+            match branches
+                .iter()
+                .find(|known| known.branch == branch && known.nic_name == nic_name)
+            {
+                Some(known) => *known,
+                None => {
+                    let fresh: &'static Branch = Box::leak(Box::new(Branch {
+                        branch,
+                        nic_name,
+                        arms: arms.iter().map(|arm| (*arm, AtomicU64::new(0))).collect(),
+                        adaptive_order: Mutex::new(None),
+                    }));
+                    branches.push(fresh);
+                    fresh
+                }
+            }
+        };
+        let cells = || {
+            let cells = arms.iter().map(|_| Cell::new(0)).collect::<Vec<_>>();
+            &*Box::leak(cells.into_boxed_slice())
+        };
+        ArmCounts {
+            shared,
+            local: cells(),
+            flushed: cells(),
+            unflushed: Box::leak(Box::new(Cell::new(0))),
+        }
+    }
+
+    /// Every branch, with its arms busiest first
+    pub fn report(&self) -> Vec<BranchReport> {
+        self.branches
+            .lock()
+            .recover()
+            .iter()
+            .map(|branch| branch.report())
+            .collect()
+    }
+}
+
+/// One pipeline's hits on the arms of a branch
+#[derive(Clone, Copy)]
+pub struct ArmCounts {
+    shared: &'static Branch,
+    /// Since the pipeline started
+    local: &'static [Cell<u64>],
+    /// How much of `local` the shared counters have
+    flushed: &'static [Cell<u64>],
+    unflushed: &'static Cell<u64>,
+}
+
+impl ArmCounts {
+    #[inline]
+    pub fn hit(&self, arm: usize) {
+        let hits = &self.local[arm];
+        hits.set(hits.get() + 1);
+        let unflushed = self.unflushed.get() + 1;
+        if unflushed < FLUSH_INTERVAL {
+            self.unflushed.set(unflushed);
+            return;
+        }
+        self.unflushed.set(0);
+        self.flush();
+    }
+
+    fn flush(&self) {
+        let arms = self.shared.arms.iter().zip(self.local).zip(self.flushed);
+        for (((_, shared), local), flushed) in arms {
+            shared.fetch_add(local.get() - flushed.get(), Ordering::Relaxed);
+            flushed.set(local.get());
+        }
+    }
+}
+
+/// Decodes the action bytes of a pipeline's packets, counting the actions as it goes
+pub struct ActionDecoder {
+    counts: ArmCounts,
+    /// The arm of every action byte: its action's, or the last one for the bytes this version
+    /// does not know
+    arms: [u16; 256],
+    /// The action of each arm but the last
+    actions: Vec<GdpAction>,
+    config: BranchConfig,
+    decoded: Cell<u64>,
+}
+
+impl ActionDecoder {
+    pub fn new(config: BranchConfig, nic_name: &'static str) -> Self {
+        let actions = (0..=u8::MAX)
+            .filter_map(|raw| GdpAction::try_from(raw).ok())
+            .collect::<Vec<_>>();
+        let mut arms = [actions.len() as u16; 256];
+        for (arm, action) in actions.iter().enumerate() {
+            arms[*action as usize] = arm as u16;
+        }
+        let mut names = actions
+            .iter()
+            .map(|action| &*Box::leak(format!("{:?}", action).into_boxed_str()))
+            .collect::<Vec<_>>();
+        names.push("unknown");
+        ActionDecoder {
+            counts: branches().counts("action", nic_name, &names),
+            arms,
+            actions,
+            config,
+            decoded: Cell::new(0),
+        }
+    }
+
+    /// The action of `raw`, or None if this version does not know it
+    #[inline]
+    pub fn decode(&self, raw: u8) -> Option<GdpAction> {
+        let arm = self.arms[raw as usize] as usize;
+        self.counts.hit(arm);
+        if self.config.adaptive {
+            let decoded = self.decoded.get() + 1;
+            self.decoded.set(decoded);
+            if decoded % self.config.reorder_interval.max(1) == 0 {
+                self.rank();
+            }
+        }
+        self.actions.get(arm).copied()
+    }
+
+    /// Rank the actions by how many of this pipeline's packets had them, busiest first
+    fn rank(&self) {
+        let local = self.counts.local;
+        let mut order = (0..self.actions.len()).collect::<Vec<_>>();
+        order.sort_by_key(|arm| Reverse(local[*arm].get()));
+        *self.counts.shared.adaptive_order.lock().recover() = Some(order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report_of(branch: &str, nic_name: &str) -> BranchReport {
+        branches()
+            .report()
+            .into_iter()
+            .find(|report| report.branch == branch && report.nic == nic_name)
+            .unwrap()
+    }
+
+    fn hits_of(report: &BranchReport, arm: &str) -> u64 {
+        report
+            .arms
+            .iter()
+            .find(|(name, _)| name == arm)
+            .map(|(_, hits)| *hits)
+            .unwrap()
+    }
+
+    #[test]
+    fn pipelines_share_their_counts_once_they_flush() {
+        let first = branches().counts("test", "counts", &["left", "right"]);
+        let second = branches().counts("test", "counts", &["left", "right"]);
+        for _ in 0..FLUSH_INTERVAL - 1 {
+            first.hit(1);
+        }
+        second.hit(0);
+        assert_eq!(hits_of(&report_of("test", "counts"), "right"), 0);
+
+        first.hit(1);
+        let report = report_of("test", "counts");
+        assert_eq!(hits_of(&report, "right"), FLUSH_INTERVAL);
+        assert_eq!(hits_of(&report, "left"), 0);
+        // busiest first
+        assert_eq!(report.arms[0].0, "right");
+    }
+
+    #[test]
+    fn decodes_every_action_byte() {
+        let decoder = ActionDecoder::new(BranchConfig::default(), "decode");
+        for raw in 0..=u8::MAX {
+            assert_eq!(decoder.decode(raw), GdpAction::try_from(raw).ok());
+        }
+        for _ in 0..FLUSH_INTERVAL {
+            decoder.decode(u8::MAX);
+        }
+        let report = report_of("action", "decode");
+        assert_eq!(report.arms[0].0, "unknown");
+        assert!(hits_of(&report, "Forward") > 0);
+        assert_eq!(report.adaptive_order, None);
+    }
+
+    #[test]
+    fn ranks_the_actions_by_their_packets() {
+        let config = BranchConfig {
+            adaptive: true,
+            reorder_interval: 16,
+        };
+        let decoder = ActionDecoder::new(config, "rank");
+        for _ in 0..8 {
+            decoder.decode(GdpAction::Ack as u8);
+        }
+        for _ in 0..4 {
+            decoder.decode(GdpAction::Forward as u8);
+        }
+        for _ in 0..4 {
+            decoder.decode(u8::MAX);
+        }
+        let order = report_of("action", "rank").adaptive_order.unwrap();
+        assert_eq!(order[..2], ["Ack".to_owned(), "Forward".to_owned()]);
+        // bytes this version does not know are counted, but have no action to rank
+        assert!(!order.contains(&"unknown".to_owned()));
+    }
+}
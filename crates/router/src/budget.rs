@@ -0,0 +1,86 @@
+use std::fs;
+use std::time::Duration;
+
+use anyhow::Result;
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use metrics_runtime::data::Counter;
+use serde::Deserialize;
+
+use crate::blocklist::{report_packet, Abuse};
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+
+/// Limits on how much work a single packet may cost the poll loop; unset limits are not enforced
+#[derive(Clone, Copy, Default, Deserialize)]
+pub struct BudgetConfig {
+    /// Shed packets that have already spent this long in the pipeline before an expensive stage
+    pub per_packet_us: Option<u64>,
+    /// Shed packets whose certificate block is larger than this, without verifying any of it
+    pub max_cert_bytes: Option<usize>,
+}
+
+pub fn load_budget_config() -> Result<BudgetConfig> {
+    let content = fs::read_to_string("budget.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// Guards the expensive stages of a pipeline, counting shed packets by reason
+#[derive(Clone, Copy)]
+pub struct Budget {
+    config: BudgetConfig,
+    deadline: &'static Counter,
+    cert_size: &'static Counter,
+}
+
+impl Budget {
+    pub fn new(config: BudgetConfig, nic_name: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter = |reason: &'static str| -> &'static Counter {
+            Box::leak(Box::new(sink.counter_with_labels(
+                "budget.dropped",
+                vec![("nic", nic_name), ("reason", reason)],
+            )))
+        };
+        Budget {
+            config,
+            deadline: counter("deadline"),
+            cert_size: counter("cert_size"),
+        }
+    }
+
+    /// Whether the packet may go through the next expensive stage
+    pub fn admit(&self, packet: &Gdp<DTls<Ipv4>>, stage: &str, debug: bool) -> bool {
+        let reason =
+            if self
+                .config
+                .max_cert_bytes
+                .map_or(false, |max| packet.cert_len() > max)
+            {
+                self.cert_size.increment();
+                // nobody sends chains this long by accident
+                report_packet(packet, Abuse::OversizedCerts);
+                "certificate block too large"
+            } else if self.config.per_packet_us.zip(packet.rx_timestamp()).map_or(
+                false,
+                |(budget_us, rx_timestamp)| {
+                    rx_timestamp.elapsed() > Duration::from_micros(budget_us)
+                },
+            ) {
+                self.deadline.increment();
+                "processing deadline exceeded"
+            } else {
+                return true;
+            };
+        if debug {
+            println!(
+                "dropping packet {:016x} before {}: {}",
+                packet.trace_id(),
+                stage,
+                reason
+            );
+        }
+        false
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use arc_swap::ArcSwap;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::{CanaryMode, CanaryReport, GdpName};
+use once_cell::sync::Lazy;
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+
+/*
+   Operators migrating a service try the new backend on a fraction of a name's traffic first,
+   with a canary rule set over the control socket:
+   - a rule picks `percent` of the senders to its name by the sender's own name, which is a hash,
+     so every sender stays with one backend for as long as the rule lasts
+   - the packets of picked senders go to the rule's locator instead of the name's route (Divert),
+     or to both (Mirror). Mirrored backends answer the sender too, alongside the baseline
+   - only packets routed to the name are steered, not those returning along a client's flow;
+     mirrored copies are held to the next hop's clearance like any packet (see labels.rs)
+   - each rule counts the packets and bytes that went to the canary and along the route, which
+     DumpCanaries reports to compare the two by. Setting a rule again starts its counts over
+   - rules lapse after their duration, DEFAULT_CANARY_SECS unless the operator says
+*/
+
+/// How long a canary rule lasts if the operator does not say
+const DEFAULT_CANARY_SECS: u64 = 60 * 60;
+
+#[derive(Default)]
+struct Tally {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Tally {
+    fn add(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> (u64, u64) {
+        (
+            self.packets.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+struct Rule {
+    ip: Ipv4Addr,
+    percent: f64,
+    /// Senders whose key is below this are picked, out of 2^32
+    threshold: u64,
+    mode: CanaryMode,
+    expiration_time: u64,
+    canary: Tally,
+    baseline: Tally,
+}
+
+/// The threshold that picks `percent` of the senders
+fn threshold(percent: f64) -> u64 {
+    (percent / 100.0 * (1u64 << 32) as f64) as u64
+}
+
+impl Rule {
+    fn picks(&self, src: GdpName) -> bool {
+        let key = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+        (key as u64) < self.threshold
+    }
+}
+
+pub struct Canaries {
+    /// Replaced whole by the control socket, so that packets read the rules without a lock
+    rules: ArcSwap<HashMap<GdpName, Arc<Rule>>>,
+    /// Entries in `rules`, so that packets skip them while there are none
+    count: AtomicUsize,
+    /// Held while the rules are replaced, so that no update is lost
+    updating: Mutex<()>,
+}
+
+// created on first use, shared by every switch pipeline and the control socket
+static CANARIES: Lazy<Canaries> = Lazy::new(Canaries::new);
+
+pub fn canaries() -> &'static Canaries {
+    &CANARIES
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+impl Canaries {
+    fn new() -> Self {
+        Canaries {
+            rules: ArcSwap::from_pointee(HashMap::new()),
+            count: AtomicUsize::new(0),
+            updating: Mutex::new(()),
+        }
+    }
+
+    /// The rule for `name`, if it is in force at `now`, in seconds since the epoch
+    fn with_rule<T>(&self, name: GdpName, now: u64, f: impl FnOnce(&Rule) -> T) -> Option<T> {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let rules = self.rules.load();
+        rules
+            .get(&name)
+            .filter(|rule| rule.expiration_time > now)
+            .map(|rule| f(rule))
+    }
+
+    /// Where a packet routed to `ip` goes, counting it for its name's rule if there is one.
+    /// `now` is the store's clock
+    pub fn steer(&self, packet: &Gdp<DTls<Ipv4>>, ip: Ipv4Addr, now: u64) -> Ipv4Addr {
+        self.with_rule(packet.dst(), now, |rule| {
+            if rule.mode == CanaryMode::Divert && rule.picks(packet.src()) {
+                rule.canary.add(packet.len());
+                rule.ip
+            } else {
+                rule.baseline.add(packet.len());
+                ip
+            }
+        })
+        .unwrap_or(ip)
+    }
+
+    /// Where to send a copy of a packet that was just forwarded, if a Mirror rule picked it
+    pub fn mirror_to(&self, packet: &Gdp<DTls<Ipv4>>, now: u64) -> Option<Ipv4Addr> {
+        self.with_rule(packet.dst(), now, |rule| {
+            let picked = rule.mode == CanaryMode::Mirror && rule.picks(packet.src());
+            Some(rule.ip).filter(|_| picked)
+        })
+        .flatten()
+    }
+
+    /// Count a copy that `mirror_to` asked for, once it is on its way
+    pub fn record_mirrored(&self, copy: &Gdp<DTls<Ipv4>>, now: u64) {
+        self.with_rule(copy.dst(), now, |rule| rule.canary.add(copy.len()));
+    }
+
+    /// Replace the rules with what `f` makes of them, less those that lapsed by `now`
+    fn update(&self, now: u64, f: impl FnOnce(&mut HashMap<GdpName, Arc<Rule>>)) {
+        let _updating = self.updating.lock().recover();
+        let mut rules = HashMap::clone(&self.rules.load());
+        f(&mut rules);
+        rules.retain(|_, rule| rule.expiration_time > now);
+        self.count.store(rules.len(), Ordering::Relaxed);
+        self.rules.store(Arc::new(rules));
+    }
+
+    /// Set the rule for `name`, returning when it lapses
+    pub fn set(
+        &self,
+        name: GdpName,
+        ip: Ipv4Addr,
+        percent: f64,
+        mode: CanaryMode,
+        duration_secs: Option<u64>,
+    ) -> Result<u64> {
+        ensure!(
+            (0.0..=100.0).contains(&percent),
+            "canary percentage {} is not between 0 and 100",
+            percent
+        );
+        let now = now_secs();
+        let expiration_time = now.saturating_add(duration_secs.unwrap_or(DEFAULT_CANARY_SECS));
+        let rule = Rule {
+            ip,
+            percent,
+            threshold: threshold(percent),
+            mode,
+            expiration_time,
+            canary: Tally::default(),
+            baseline: Tally::default(),
+        };
+        self.update(now, |rules| {
+            rules.insert(name, Arc::new(rule));
+        });
+        Ok(expiration_time)
+    }
+
+    /// Remove the rule for `name`, returning whether there was one in force
+    pub fn clear(&self, name: GdpName) -> bool {
+        let now = now_secs();
+        let mut existed = false;
+        self.update(now, |rules| {
+            existed = rules
+                .remove(&name)
+                .map_or(false, |rule| rule.expiration_time > now);
+        });
+        existed
+    }
+
+    pub fn dump(&self) -> Vec<CanaryReport> {
+        self.update(now_secs(), |_| {});
+        let rules = self.rules.load();
+        rules
+            .iter()
+            .map(|(name, rule)| {
+                let (canary_packets, canary_bytes) = rule.canary.get();
+                let (baseline_packets, baseline_bytes) = rule.baseline.get();
+                CanaryReport {
+                    name: *name,
+                    ip: rule.ip,
+                    percent: rule.percent,
+                    mode: rule.mode,
+                    expiration_time: rule.expiration_time,
+                    canary_packets,
+                    canary_bytes,
+                    baseline_packets,
+                    baseline_bytes,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender(key: u32) -> GdpName {
+        let mut name = [0xff; 32];
+        name[..4].copy_from_slice(&key.to_be_bytes());
+        name
+    }
+
+    fn rule(percent: f64) -> Rule {
+        Rule {
+            ip: Ipv4Addr::new(10, 0, 0, 9),
+            percent,
+            threshold: threshold(percent),
+            mode: CanaryMode::Divert,
+            expiration_time: u64::MAX,
+            canary: Tally::default(),
+            baseline: Tally::default(),
+        }
+    }
+
+    #[test]
+    fn picks_senders_below_the_threshold() {
+        assert_eq!(threshold(0.0), 0);
+        assert_eq!(threshold(50.0), 1 << 31);
+        assert_eq!(threshold(100.0), 1 << 32);
+
+        let half = rule(50.0);
+        assert!(half.picks(sender(0)));
+        assert!(half.picks(sender((1 << 31) - 1)));
+        assert!(!half.picks(sender(1 << 31)));
+        assert!(!rule(0.0).picks(sender(0)));
+        assert!(rule(100.0).picks(sender(u32::MAX)));
+    }
+
+    #[test]
+    fn picks_the_same_senders_every_time() {
+        let rule = rule(25.0);
+        let picked = (0..1000u32)
+            .map(|i| sender(i.wrapping_mul(2654435761)))
+            .filter(|src| rule.picks(*src))
+            .count();
+        assert!((200..300).contains(&picked), "picked {} of 1000", picked);
+        let again = (0..1000u32)
+            .map(|i| sender(i.wrapping_mul(2654435761)))
+            .filter(|src| rule.picks(*src))
+            .count();
+        assert_eq!(picked, again);
+    }
+
+    #[test]
+    fn rules_lapse_at_their_expiration() {
+        let canaries = Canaries::new();
+        let name = [1; 32];
+        let until = canaries
+            .set(
+                name,
+                Ipv4Addr::new(10, 0, 0, 9),
+                10.0,
+                CanaryMode::Mirror,
+                Some(60),
+            )
+            .unwrap();
+        assert!(canaries.with_rule(name, until - 1, |_| ()).is_some());
+        assert!(canaries.with_rule(name, until, |_| ()).is_none());
+        assert!(canaries.with_rule([2; 32], until - 1, |_| ()).is_none());
+
+        // lapsed rules are dropped with the next update, and no longer count as in force
+        canaries.update(until, |_| {});
+        assert_eq!(canaries.count.load(Ordering::Relaxed), 0);
+        assert!(canaries.dump().is_empty());
+        assert!(!canaries.clear(name));
+    }
+
+    #[test]
+    fn refuses_percentages_out_of_range() {
+        let canaries = Canaries::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 9);
+        assert!(canaries
+            .set([1; 32], ip, 100.5, CanaryMode::Divert, None)
+            .is_err());
+        assert!(canaries
+            .set([1; 32], ip, -1.0, CanaryMode::Divert, None)
+            .is_err());
+        assert!(canaries
+            .set([1; 32], ip, 100.0, CanaryMode::Divert, None)
+            .is_ok());
+        assert!(canaries.clear([1; 32]));
+    }
+}
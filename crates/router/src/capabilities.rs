@@ -0,0 +1,321 @@
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpName};
+use serde::{Deserialize, Serialize};
+use tokio_timer::delay_for;
+
+use crate::certificates::attesters;
+use crate::dtls::session::sessions;
+use crate::dtls::{encrypt_gdp, CipherSuite, DTls};
+use crate::flags::{FeatureFlags, Flag};
+use crate::gdp::Gdp;
+use crate::identity::PortIdentity;
+use crate::kvs::{SharedStore, Store};
+use crate::l2filter::{egress_of, VlanTx};
+use crate::packet_ops::get_payload;
+use crate::rib::{create_control_request, create_reply, Routes};
+use crate::schedule::Schedule;
+use crate::FwdTableEntry;
+
+/*
+   Switches tell their neighbors what they support in heartbeats:
+   - every HEARTBEAT_INTERVAL, each next hop in our forwarding table, and our RIB, is sent our
+     capabilities, asking for its own in return if we have not heard from it recently
+   - capabilities are a bitmap of optional features, plus TLVs for everything that is a value
+     (wire format version, MTU, cipher suites); TLVs of unknown kinds are skipped,
+     so newer switches can advertise more without confusing older ones
+   - neighbors are keyed by the IP address they send from, which is also the IP we forward to,
+     and by the VLAN they are on, as segments trunked to one port may reuse addresses
+   - neighbors we have no capabilities for are assumed to support what we do,
+     as every switch did before heartbeats, except for features that packets cannot do without
+     at the neighbor (see peer_advertises)
+*/
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Capabilities are forgotten once a neighbor misses this many heartbeats
+const MISSED_HEARTBEATS: u64 = 3;
+
+/// Version of the GDP header layout that we send (2: explicit header_len and trace_id)
+pub const WIRE_VERSION: u8 = 2;
+/// The largest IP packet we accept, unless configured otherwise
+pub const DEFAULT_MAX_MTU: u16 = 1500;
+/// Added to each packet by the cipher when it is encrypted on the way out
+pub const CIPHER_TAG_LEN: usize = 16;
+
+const TLV_WIRE_VERSION: u8 = 1;
+const TLV_MAX_MTU: u8 = 2;
+const TLV_CIPHER_SUITES: u8 = 3;
+
+/// Optional forwarding features that a neighbor may or may not understand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    InbandTelemetry = 0,
+    ForwardingCerts = 1,
+    /// RibReplies that answer several queries at once (see ribreplies.rs)
+    AggregatedRibReplies = 2,
+    /// Certificate attestations in place of the chains they vouch for (see certificates.rs)
+    CertAttestations = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    features: u32,
+    pub wire_version: u8,
+    pub max_mtu: u16,
+    /// Bit n is set if cipher suite n is supported (see CipherSuite)
+    cipher_suites: u16,
+}
+
+impl Capabilities {
+    /// What we support, given the features currently enabled
+    pub fn local(flags: FeatureFlags) -> Self {
+        let mut capabilities = Capabilities {
+            features: 0,
+            wire_version: WIRE_VERSION,
+            max_mtu: DEFAULT_MAX_MTU,
+            cipher_suites: sessions().suite_bitmap(),
+        };
+        for (feature, flag) in [
+            (Feature::InbandTelemetry, Flag::InbandTelemetry),
+            (Feature::ForwardingCerts, Flag::ForwardingCerts),
+        ] {
+            if flags.is_enabled(flag) {
+                capabilities.features |= 1 << feature as u32;
+            }
+        }
+        // the parts of a RibReply are applied one by one, however many queries they answer
+        capabilities.features |= 1 << Feature::AggregatedRibReplies as u32;
+        // a switch that trusts no attesters would refuse every attested packet
+        if !attesters().is_empty() {
+            capabilities.features |= 1 << Feature::CertAttestations as u32;
+        }
+        capabilities
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u32) != 0
+    }
+
+    pub fn supports_cipher(&self, suite: u16) -> bool {
+        suite < 16 && self.cipher_suites & (1 << suite) != 0
+    }
+
+    fn to_heartbeat(self, want_reply: bool) -> Heartbeat {
+        let cipher_suites = (0..16)
+            .filter(|suite| self.supports_cipher(*suite))
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        Heartbeat {
+            features: self.features,
+            tlvs: vec![
+                Tlv {
+                    kind: TLV_WIRE_VERSION,
+                    value: vec![self.wire_version],
+                },
+                Tlv {
+                    kind: TLV_MAX_MTU,
+                    value: self.max_mtu.to_be_bytes().to_vec(),
+                },
+                Tlv {
+                    kind: TLV_CIPHER_SUITES,
+                    value: cipher_suites,
+                },
+            ],
+            want_reply,
+        }
+    }
+
+    fn from_heartbeat(heartbeat: &Heartbeat) -> Result<Self> {
+        // neighbors that leave out a TLV get the values every switch had before heartbeats
+        let mut capabilities = Capabilities {
+            features: heartbeat.features,
+            wire_version: 1,
+            max_mtu: DEFAULT_MAX_MTU,
+            cipher_suites: CipherSuite::Aes256Gcm.bit(),
+        };
+        for tlv in &heartbeat.tlvs {
+            match tlv.kind {
+                TLV_WIRE_VERSION => {
+                    ensure!(tlv.value.len() == 1, "bad wire version TLV");
+                    capabilities.wire_version = tlv.value[0];
+                }
+                TLV_MAX_MTU => {
+                    ensure!(tlv.value.len() == 2, "bad MTU TLV");
+                    capabilities.max_mtu = u16::from_be_bytes([tlv.value[0], tlv.value[1]]);
+                }
+                TLV_CIPHER_SUITES => {
+                    ensure!(tlv.value.len() % 2 == 0, "bad cipher suites TLV");
+                    capabilities.cipher_suites = tlv
+                        .value
+                        .chunks(2)
+                        .map(|suite| u16::from_be_bytes([suite[0], suite[1]]))
+                        .filter(|suite| *suite < 16)
+                        .fold(0, |suites, suite| suites | 1 << suite);
+                }
+                _ => {}
+            }
+        }
+        Ok(capabilities)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Tlv {
+    kind: u8,
+    value: Vec<u8>,
+}
+
+/// A neighbor, by the VLAN and IP address we reach it at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Neighbor {
+    pub vlan: Option<u16>,
+    pub ip: Ipv4Addr,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Heartbeat {
+    features: u32,
+    tlvs: Vec<Tlv>,
+    /// Set by a sender that does not know our capabilities yet
+    want_reply: bool,
+}
+
+/// Whether `feature` may be used toward `neighbor`
+pub fn peer_supports(store: Store, neighbor: Neighbor, feature: Feature) -> bool {
+    match store.peer_capabilities.get(&neighbor) {
+        Some(FwdTableEntry { val, .. }) => val.supports(feature),
+        None => true,
+    }
+}
+
+/// Whether `neighbor` told us that it supports `feature`, for features that a neighbor which does
+/// not would refuse packets over, rather than ignore
+pub fn peer_advertises(store: Store, neighbor: Neighbor, feature: Feature) -> bool {
+    store
+        .peer_capabilities
+        .get(&neighbor)
+        .map_or(false, |FwdTableEntry { val, .. }| val.supports(feature))
+}
+
+/// The MTU that `neighbor` told us it takes, if it has
+pub fn peer_max_mtu(store: Store, neighbor: Neighbor) -> Option<u16> {
+    store
+        .peer_capabilities
+        .get(&neighbor)
+        .map(|FwdTableEntry { val, .. }| val.max_mtu)
+}
+
+/// Whether `packet` will still fit in the neighbor's MTU once it is encrypted
+pub fn fits_peer_mtu(packet: &Gdp<DTls<Ipv4>>, store: Store, neighbor: Neighbor) -> bool {
+    let max_mtu = match peer_max_mtu(store, neighbor) {
+        Some(max_mtu) => max_mtu,
+        None => return true,
+    };
+    packet.envelope().envelope().envelope().len() + CIPHER_TAG_LEN <= max_mtu as usize
+}
+
+/// The capabilities in a heartbeat, and whether its sender asked for ours in return
+pub fn read_heartbeat(packet: &Gdp<DTls<Ipv4>>) -> Result<(Capabilities, bool)> {
+    let heartbeat: Heartbeat = bincode::deserialize(get_payload(packet)?)?;
+    Ok((
+        Capabilities::from_heartbeat(&heartbeat)?,
+        heartbeat.want_reply,
+    ))
+}
+
+/// Until when capabilities heard now are kept, in seconds since the Unix epoch
+pub fn capabilities_expiration() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+        + HEARTBEAT_INTERVAL.as_secs() * MISSED_HEARTBEATS)
+}
+
+/// Record a neighbor's capabilities, returning whether it asked for ours in return
+pub fn handle_heartbeat(packet: &Gdp<DTls<Ipv4>>, store: Store, debug: bool) -> Result<bool> {
+    let (capabilities, want_reply) = read_heartbeat(packet)?;
+    let peer = Neighbor {
+        vlan: packet.rx_meta().and_then(|rx_meta| rx_meta.vlan),
+        ip: packet.envelope().envelope().envelope().src(),
+    };
+    if debug {
+        println!("neighbor {:?} supports {:?}", peer, capabilities);
+    }
+    store.peer_capabilities.update(
+        peer,
+        FwdTableEntry::new(capabilities, capabilities_expiration()?),
+    );
+    Ok(want_reply)
+}
+
+/// Answer a neighbor that asked for our capabilities
+pub fn heartbeat_reply(packet: &Gdp<DTls<Ipv4>>, flags: FeatureFlags) -> Result<Gdp<DTls<Ipv4>>> {
+    let heartbeat = Capabilities::local(flags).to_heartbeat(false);
+    create_reply(
+        packet,
+        GdpAction::Heartbeat,
+        &bincode::serialize(&heartbeat)?,
+    )
+}
+
+fn send_heartbeat(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    peer: Ipv4Addr,
+    heartbeat: &Heartbeat,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            create_control_request(
+                packet,
+                GdpAction::Heartbeat,
+                &bincode::serialize(heartbeat)?,
+                src.mac,
+                src.ip,
+                src_gdp_name,
+                peer,
+            )
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q))
+        .run_once();
+}
+
+/// Periodically advertise our capabilities to each of our next hops and to the RIB of `routes`
+pub fn heartbeat_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    routes: &'static Routes,
+    store: SharedStore,
+    flags: FeatureFlags,
+) -> impl Pipeline {
+    let local = store.sync();
+    Schedule::new("heartbeat", async move {
+        loop {
+            let capabilities = Capabilities::local(flags);
+            let egress = egress_of(src.mac);
+            let mut peers = store.next_hop_ips();
+            let rib_ip = routes.rib().ip;
+            if !peers.contains(&rib_ip) {
+                peers.push(rib_ip);
+            }
+            for peer in peers {
+                let neighbor = Neighbor {
+                    vlan: egress.and_then(|egress| egress.vlan_to(peer, None)),
+                    ip: peer,
+                };
+                let want_reply = local.peer_capabilities.get(&neighbor).is_none();
+                let heartbeat = capabilities.to_heartbeat(want_reply);
+                send_heartbeat(q.clone(), src, src_gdp_name, peer, &heartbeat);
+            }
+            delay_for(HEARTBEAT_INTERVAL).await;
+        }
+    })
+}
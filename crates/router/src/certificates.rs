@@ -1,102 +1,108 @@
-use std::mem;
-use std::net::Ipv4Addr;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
 use capsule::packets::Packet;
-use gdp_client::GdpName;
+use gdp_proto::{name_hash, CertAttestation, GdpName};
+pub use gdp_proto::{
+    AttrCert, CertContents, CertDest, Certificate, GdpMeta, RtCert, SerializableSignature,
+};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use signatory::ed25519::{Signature, SigningKey, VerifyingKey, ALGORITHM_ID};
 use signatory::pkcs8::{FromPrivateKey, PrivateKeyInfo};
 use signatory::signature::{Signer, Verifier};
 
-use crate::gdp::Gdp;
+use crate::dtls::DTls;
+use crate::gdp::{CertificateBlock, Gdp};
 use crate::kvs::Store;
+use crate::secrets::decode_hex;
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
-pub struct GdpMeta {
-    pub pub_key: [u8; 32], // TODO: compute hash on initialization
-}
+/// How long the certificates we sign are valid for
+const CERT_LIFETIME_SECS: u64 = 4 * 60 * 60;
 
-impl GdpMeta {
-    pub fn hash(&self) -> GdpName {
-        let mut hasher = Sha256::new();
-        hasher.update(self.pub_key);
-        hasher.finalize().into()
-    }
+/// The GdpName of whoever holds the private half of the ed25519 `pub_key`: its hash (SHA-256
+/// unless gdp-proto is built otherwise), so that only the key's owner can sign for the name
+pub fn name_of_key(pub_key: &[u8; 32]) -> GdpName {
+    name_hash(pub_key)
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Certificate {
-    pub contents: CertContents,
-    signature: SerializableSignature,
+/// Check that `meta` carries the key that `gdp_name` was derived from, so that signatures checked
+/// against `meta` prove they came from the owner of `gdp_name`
+pub fn check_owner(gdp_name: GdpName, meta: &GdpMeta) -> Result<()> {
+    ensure!(
+        meta.hash() == gdp_name,
+        "public key does not match gdpname {:?}",
+        gdp_name
+    );
+    Ok(())
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
-pub struct SerializableSignature([u8; 32], [u8; 32]);
-
-impl From<[u8; 64]> for SerializableSignature {
-    fn from(x: [u8; 64]) -> SerializableSignature {
-        unsafe { mem::transmute(x) }
-    }
+/// Checking the signature of a certificate, which gdp-proto leaves to the crates with the crypto
+pub trait VerifyCert {
+    /// Check that the certificate is owned by `meta`'s name and signed with its key
+    fn verify(&self, meta: &GdpMeta) -> Result<()>;
 }
 
-impl From<SerializableSignature> for [u8; 64] {
-    fn from(x: SerializableSignature) -> [u8; 64] {
-        unsafe { mem::transmute(x) }
+impl VerifyCert for Certificate {
+    fn verify(&self, meta: &GdpMeta) -> Result<()> {
+        check_owner(*self.contents.owner(), meta)?;
+        verify_data(&self.contents, self.signature, meta)
     }
 }
 
-impl Certificate {
-    pub fn verify(&self, meta: &GdpMeta) -> Result<()> {
-        if meta.hash() != *self.contents.owner() {
-            return Err(anyhow!("public key does not match gdpname"));
-        }
-        let verifying_key = VerifyingKey::from_bytes(&meta.pub_key)?;
-        Ok(verifying_key.verify(
-            &self.contents.serialized()?,
-            &Signature::new(self.signature.into()),
-        )?)
-    }
+fn signing_key(private_key: [u8; 32]) -> Result<SigningKey> {
+    Ok(SigningKey::from_pkcs8_private_key_info(
+        PrivateKeyInfo::new(ALGORITHM_ID, &private_key),
+    )?)
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub enum CertContents {
-    RtCert(RtCert),
+/// The metadata of whoever holds `private_key`
+pub fn meta_of_key(private_key: [u8; 32]) -> Result<GdpMeta> {
+    Ok(GdpMeta {
+        pub_key: signing_key(private_key)?.verifying_key().to_bytes(),
+    })
 }
 
-impl CertContents {
-    fn serialized(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(&self)?)
-    }
-
-    fn sign(&self, signing_key: SigningKey) -> Result<[u8; 64]> {
-        Ok(signing_key.sign(&bincode::serialize(&self)?).to_bytes())
-    }
-
-    pub fn owner(&self) -> &GdpName {
-        match *self {
-            CertContents::RtCert(RtCert { ref base, .. }) => base,
-        }
-    }
+/// Sign arbitrary serializable data, e.g. a response from the RIB
+pub fn sign_data<T: Serialize>(data: &T, private_key: [u8; 32]) -> Result<SerializableSignature> {
+    let signature: [u8; 64] = signing_key(private_key)?
+        .sign(&bincode::serialize(data)?)
+        .to_bytes();
+    Ok(signature.into())
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct RtCert {
-    pub base: GdpName,
-    pub proxy: CertDest,
-    pub expiration_time: u64,
+pub fn verify_data<T: Serialize>(
+    data: &T,
+    signature: SerializableSignature,
+    meta: &GdpMeta,
+) -> Result<()> {
+    let verifying_key = VerifyingKey::from_bytes(&meta.pub_key)?;
+    Ok(verifying_key.verify(
+        &bincode::serialize(data)?,
+        &Signature::new(signature.into()),
+    )?)
+}
 
-    /*
-        Whether we can send messages to the base via the proxy,
-        or if we should only accept messages *from* the proxy as being via the base
-    */
-    pub bidirectional: bool,
+/// Signing route certificates, valid for CERT_LIFETIME_SECS
+pub trait NewRtCert {
+    fn new_wrapped(
+        base: GdpMeta,
+        private_key: [u8; 32],
+        proxy: CertDest,
+        bidirectional: bool,
+    ) -> Result<Certificate>;
 }
 
-impl RtCert {
-    pub fn new_wrapped(
+impl NewRtCert for RtCert {
+    fn new_wrapped(
         base: GdpMeta,
         private_key: [u8; 32],
         proxy: CertDest,
@@ -105,14 +111,32 @@ impl RtCert {
         let contents = CertContents::RtCert(RtCert {
             base: base.hash(),
             proxy,
-            expiration_time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + 4 * 60 * 60,
+            expiration_time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+                + CERT_LIFETIME_SECS,
             bidirectional,
         });
-        let signing_key = SigningKey::from_pkcs8_private_key_info(PrivateKeyInfo::new(
-            ALGORITHM_ID,
-            &private_key,
-        ))?;
-        let signature = contents.sign(signing_key)?.into();
+        let signature = sign_data(&contents, private_key)?;
+        Ok(Certificate {
+            contents,
+            signature,
+        })
+    }
+}
+
+/// Signing attribute certificates, valid for CERT_LIFETIME_SECS
+pub trait NewAttrCert {
+    fn new_wrapped(base: GdpMeta, private_key: [u8; 32], tags: Vec<String>) -> Result<Certificate>;
+}
+
+impl NewAttrCert for AttrCert {
+    fn new_wrapped(base: GdpMeta, private_key: [u8; 32], tags: Vec<String>) -> Result<Certificate> {
+        let contents = CertContents::AttrCert(AttrCert {
+            base: base.hash(),
+            tags,
+            expiration_time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+                + CERT_LIFETIME_SECS,
+        });
+        let signature = sign_data(&contents, private_key)?;
         Ok(Certificate {
             contents,
             signature,
@@ -120,59 +144,770 @@ impl RtCert {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub enum CertDest {
-    GdpName(GdpName),
-    IpAddr(Ipv4Addr),
+/// Check that `certs` delegate, one after another, from `src` to `target`: each must be a route
+/// certificate owned by the name the previous one delegated to (the first by `src`), signed by
+/// its owner, and unexpired. Owners whose metadata the store lacks are added to `unknown_metas`,
+/// and the chain is refused. Returns when the first of the certificates expires.
+pub fn validate_chain(
+    src: GdpName,
+    target: GdpName,
+    certs: &[Certificate],
+    store: &Store,
+    unknown_metas: &mut Vec<GdpName>,
+) -> Result<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let known = unknown_metas.len();
+    let mut pos = src;
+    let mut expiration_time = u64::MAX;
+    for cert in certs {
+        let owner = *cert.contents.owner();
+        ensure!(
+            owner == pos,
+            "certificate owned by {:?}, where {:?} was expected",
+            owner,
+            pos
+        );
+        match store.gdp_metadata.get_unchecked(&pos) {
+            Some(meta) => cert
+                .verify(&meta)
+                .with_context(|| format!("certificate of {:?} is not signed by it", pos))?,
+            None => unknown_metas.push(pos),
+        }
+        ensure!(
+            cert.contents.expiration_time() > now,
+            "certificate of {:?} expired",
+            pos
+        );
+        expiration_time = expiration_time.min(cert.contents.expiration_time());
+        pos = match cert.contents {
+            CertContents::RtCert(RtCert {
+                proxy: CertDest::GdpName(proxy),
+                ..
+            }) => proxy,
+            _ => bail!("certificate of {:?} does not delegate to a name", pos),
+        };
+    }
+    ensure!(
+        pos == target,
+        "chain ends at {:?}, rather than at {:?}",
+        pos,
+        target
+    );
+    ensure!(
+        unknown_metas.len() == known,
+        "metadata unknown for {:?}",
+        &unknown_metas[known..]
+    );
+    Ok(expiration_time)
+}
+
+/*
+   Edge switches may spare the switches past them the certificate chains they checked: with
+   `attest` in certs.toml, a switch replaces a chain that leads from the sender to it with an
+   attestation, signed by the switch, that it did, and the certificates that follow are checked
+   from the switch on:
+   - only toward next hops that advertised CertAttestations in their heartbeats, which switches
+     do once they list attesters; sidecars and older switches keep getting the whole chain
+   - an attestation is taken from the attesters listed in certs.toml only, is for the packet's
+     source, and expires with the first certificate of the chain it stands in for
+   - chains that do not check out at the edge keep their certificates, for the next hop to judge
+*/
+
+/// Check the attestation that stands in for the start of `packet`'s chain, if it carries one:
+/// it must be by one of `attesters`, for the packet's source, unexpired and signed by the
+/// attester. Returns where the rest of the chain starts, and when the attestation expires.
+fn chain_start<T: Packet>(
+    packet: &Gdp<T>,
+    attesters: &[GdpName],
+    store: &Store,
+    unknown_metas: &mut Vec<GdpName>,
+) -> Result<(GdpName, u64)> {
+    let attestation = match CertAttestation::find(packet.extensions()?)? {
+        Some(attestation) => attestation,
+        None => return Ok((packet.src(), u64::MAX)),
+    };
+    let attester = attestation.attester;
+    ensure!(
+        attesters.contains(&attester),
+        "attestation by {:?}, which is not a trusted attester",
+        attester
+    );
+    ensure!(
+        attestation.src == packet.src(),
+        "attestation for {:?}, rather than for the packet's source",
+        attestation.src
+    );
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    ensure!(
+        attestation.expiration_time > now,
+        "attestation by {:?} expired",
+        attester
+    );
+    let meta = match store.gdp_metadata.get_unchecked(&attester) {
+        Some(meta) => meta,
+        None => {
+            unknown_metas.push(attester);
+            bail!("metadata unknown for {:?}", attester);
+        }
+    };
+    VerifyingKey::from_bytes(&meta.pub_key)?
+        .verify(
+            &attestation.signed_bytes(),
+            &Signature::new(attestation.signature),
+        )
+        .with_context(|| format!("attestation is not signed by {:?}", attester))?;
+    Ok((attester, attestation.expiration_time))
 }
 
 pub fn check_packet_certificates<T: Packet>(
     gdp_name: GdpName,
     packet: &Gdp<T>,
     store: &Store,
-    mut needed_metas: Option<&mut Vec<GdpName>>,
+    needed_metas: Option<&mut Vec<GdpName>>,
     nic_name: &str,
     debug: bool,
 ) -> bool {
-    let mut ok = true;
-    if let Ok(certs) = packet.get_certs() {
-        if debug {
-            println!("{} received packet with certificates {:?}", nic_name, certs);
+    let certs = match packet.get_certs() {
+        Ok(certs) => certs,
+        Err(_) => return false,
+    };
+    if debug {
+        println!("{} received packet with certificates {:?}", nic_name, certs);
+    }
+    let mut unknown_metas = Vec::new();
+    let verdict =
+        chain_start(packet, attesters(), store, &mut unknown_metas).and_then(|(start, _)| {
+            validate_chain(
+                start,
+                gdp_name,
+                &certs.certificates,
+                store,
+                &mut unknown_metas,
+            )
+        });
+    match verdict {
+        Ok(_) => true,
+        Err(err) => {
+            // missing metadata is asked of the RIB, rather than being anything wrong
+            if debug || unknown_metas.is_empty() {
+                println!(
+                    "{} refused certificates from {:?}: {:#}",
+                    nic_name,
+                    packet.src(),
+                    err
+                );
+            }
+            if let Some(needed_metas) = needed_metas {
+                needed_metas.extend(unknown_metas);
+            }
+            false
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CertMode {
+    /// Packets whose chain does not check out are not forwarded
+    Enforce,
+    /// Packets whose chain does not check out are logged, and forwarded anyway
+    LogOnly,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CertConfig {
+    #[serde(default = "default_cert_mode")]
+    pub mode: CertMode,
+    /// How many validated chains each pipeline remembers
+    #[serde(default = "default_chain_cache_entries")]
+    pub cache_entries: usize,
+    /// Edge mode: replace the chains that check out with an attestation to them, for next hops
+    /// that take attestations
+    #[serde(default)]
+    pub attest: bool,
+    /// The switches whose attestations are taken in place of the chains they checked, in hex
+    #[serde(default)]
+    pub attesters: Vec<String>,
+}
+
+fn default_cert_mode() -> CertMode {
+    CertMode::Enforce
+}
+
+fn default_chain_cache_entries() -> usize {
+    4096
+}
+
+impl Default for CertConfig {
+    fn default() -> Self {
+        CertConfig {
+            mode: default_cert_mode(),
+            cache_entries: default_chain_cache_entries(),
+            attest: false,
+            attesters: Vec::new(),
         }
-        let mut pos = packet.src();
-        for cert in certs.certificates {
-            if *cert.contents.owner() != pos {
-                println!("owner mismatch {:?} {:?}", cert.contents.owner(), pos);
-                return false;
+    }
+}
+
+impl CertConfig {
+    pub fn trusted_attesters(&self) -> Result<Vec<GdpName>> {
+        self.attesters
+            .iter()
+            .map(|attester| {
+                decode_hex(attester)?
+                    .try_into()
+                    .map_err(|_| anyhow!("attester {} is not a GdpName", attester))
+            })
+            .collect()
+    }
+}
+
+pub fn load_cert_config() -> Result<CertConfig> {
+    parse_cert_config(&fs::read_to_string("certs.toml")?)
+}
+
+fn parse_cert_config(content: &str) -> Result<CertConfig> {
+    let config: CertConfig = toml::from_str(content)?;
+    // so that a bad name is reported, rather than its attestations refused
+    config.trusted_attesters()?;
+    Ok(config)
+}
+
+// read on first use, shared by every pipeline, so that a certs.toml that does not parse is
+// reported once rather than taken for the defaults in silence. The defaults are the strictest
+// settings: every chain is checked in full, and no attestation is made or taken
+static CERT_CONFIG: Lazy<CertConfig> = Lazy::new(|| {
+    load_cert_config().unwrap_or_else(|err| {
+        let missing = err
+            .downcast_ref::<io::Error>()
+            .map_or(false, |err| err.kind() == io::ErrorKind::NotFound);
+        if !missing {
+            println!(
+                "certs.toml: {:#}; enforcing every chain in full, with no attestations",
+                err
+            );
+        }
+        CertConfig::default()
+    })
+});
+
+// decoded on first use, shared by every pipeline, as check_packet_certificates has no
+// ChainChecker to hold them
+static ATTESTERS: Lazy<Vec<GdpName>> = Lazy::new(|| {
+    // checked as the config was loaded, so a config with bad names is not in use
+    cert_config().trusted_attesters().unwrap_or_default()
+});
+
+/// The certificate settings of every pipeline, from certs.toml
+pub fn cert_config() -> &'static CertConfig {
+    &CERT_CONFIG
+}
+
+/// The switches whose attestations are taken, from certs.toml
+pub fn attesters() -> &'static [GdpName] {
+    &ATTESTERS
+}
+
+/// Make room for one more entry in a cache of chains that holds at most `capacity`, dropping
+/// those that `live` says expired
+fn make_room<V>(cache: &mut HashMap<u64, V>, capacity: usize, live: impl Fn(&V) -> bool) {
+    if cache.len() >= capacity {
+        cache.retain(|_, value| live(value));
+        // still full of live chains, so start over rather than track which are in use
+        if cache.len() >= capacity {
+            cache.clear();
+        }
+    }
+}
+
+/// Checks the certificate chains of the packets that one pipeline forwards. Chains that check out
+/// are remembered until they expire, so that the packets after the first of a flow, which carry
+/// the same chain, skip the signature checks.
+pub struct ChainChecker {
+    gdp_name: GdpName,
+    nic_name: &'static str,
+    config: CertConfig,
+    hasher: RandomState,
+    /// When each validated chain expires, by a hash of its sender and certificates
+    valid: RefCell<HashMap<u64, u64>>,
+    validated: Counter,
+    cached: Counter,
+    refused: Counter,
+    logged: Counter,
+}
+
+impl ChainChecker {
+    pub fn new(gdp_name: GdpName, nic_name: &'static str) -> Self {
+        ChainChecker::with_config(gdp_name, nic_name, cert_config().clone())
+    }
+
+    fn with_config(gdp_name: GdpName, nic_name: &'static str, config: CertConfig) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter = |result: &'static str| {
+            sink.counter_with_labels("cert_chains", vec![("nic", nic_name), ("result", result)])
+        };
+        ChainChecker {
+            gdp_name,
+            nic_name,
+            config,
+            hasher: RandomState::new(),
+            valid: RefCell::new(HashMap::new()),
+            validated: counter("validated"),
+            cached: counter("cached"),
+            refused: counter("refused"),
+            logged: counter("logged"),
+        }
+    }
+
+    /// Whether `packet` may be forwarded
+    pub fn check<T: Packet>(&self, packet: &Gdp<T>, store: &Store, debug: bool) -> bool {
+        let verdict = self.chain_key(packet).and_then(|key| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if matches!(self.valid.borrow().get(&key), Some(&expiration_time) if expiration_time > now)
+            {
+                self.cached.increment();
+                return Ok(());
             }
-            if let Some(metadata) = store.gdp_metadata.get_unchecked(&pos) {
-                if cert.verify(&metadata).is_err() {
-                    println!("incorrect signature");
-                    return false;
+            let mut unknown_metas = Vec::new();
+            let (start, attested_until) = chain_start(packet, store, &mut unknown_metas)?;
+            let certs = packet.get_certs()?;
+            let expiration_time = validate_chain(
+                start,
+                self.gdp_name,
+                &certs.certificates,
+                store,
+                &mut unknown_metas,
+            )?
+            .min(attested_until);
+            self.validated.increment();
+            self.remember(key, expiration_time, now);
+            Ok(())
+        });
+        let err = match verdict {
+            Ok(()) => return true,
+            Err(err) => err,
+        };
+        match self.config.mode {
+            CertMode::Enforce => {
+                self.refused.increment();
+                if debug {
+                    println!(
+                        "{} refused certificates from {:?}: {:#}",
+                        self.nic_name,
+                        packet.src(),
+                        err
+                    );
                 }
-            } else if let Some(ref mut needed_metas) = needed_metas {
-                needed_metas.push(pos);
-                ok = false;
-            } else {
-                return false;
+                false
+            }
+            CertMode::LogOnly => {
+                self.logged.increment();
+                println!(
+                    "{} forwarding packet {:016x} from {:?} despite its certificates: {:#}",
+                    self.nic_name,
+                    packet.trace_id(),
+                    packet.src(),
+                    err
+                );
+                true
             }
-            match cert.contents {
-                CertContents::RtCert(RtCert {
-                    proxy: CertDest::GdpName(proxy),
-                    ..
-                }) => pos = proxy,
-                _ => {
-                    println!("not a gdpname rtcert");
-                    return false;
+        }
+    }
+
+    fn chain_key<T: Packet>(&self, packet: &Gdp<T>) -> Result<u64> {
+        let mut hasher = self.hasher.build_hasher();
+        packet.src().hash(&mut hasher);
+        packet.cert_bytes()?.hash(&mut hasher);
+        // a chain is only as good as the attestation to its start
+        CertAttestation::find(packet.extensions()?)?.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    fn remember(&self, key: u64, expiration_time: u64, now: u64) {
+        let mut valid = self.valid.borrow_mut();
+        make_room(&mut valid, self.config.cache_entries, |expiration_time| {
+            *expiration_time > now
+        });
+        if self.config.cache_entries > 0 {
+            valid.insert(key, expiration_time);
+        }
+    }
+}
+
+/// In edge mode, replaces the chains of the packets that one pipeline forwards with its
+/// attestation to them. The attester checks each chain itself rather than take the
+/// ChainChecker's word, which is given for chains that failed when it only logs, and not asked
+/// for when certificate checks are off.
+pub struct Attester {
+    gdp_name: GdpName,
+    private_key: [u8; 32],
+    config: CertConfig,
+    hasher: RandomState,
+    /// The attestation to each chain that checked out, by a hash of its sender and certificates
+    attested: RefCell<HashMap<u64, CertAttestation>>,
+    attestations: Counter,
+}
+
+impl Attester {
+    pub fn new(gdp_name: GdpName, private_key: [u8; 32], nic_name: &'static str) -> Self {
+        Attester {
+            gdp_name,
+            private_key,
+            config: cert_config().clone(),
+            hasher: RandomState::new(),
+            attested: RefCell::new(HashMap::new()),
+            attestations: metrics::global().sink().counter_with_labels(
+                "cert_chains",
+                vec![("nic", nic_name), ("result", "attested")],
+            ),
+        }
+    }
+
+    /// Replace the certificates of `packet` with an attestation to them. Packets without
+    /// certificates or attested to already are left as they are, and so are chains that do not
+    /// check out, for the next hop to judge.
+    pub fn attest(&self, packet: &mut Gdp<DTls<Ipv4>>, store: &Store) -> Result<()> {
+        if !self.config.attest || packet.cert_len() == 0 {
+            return Ok(());
+        }
+        let mut extensions = packet.extensions()?.to_vec();
+        if CertAttestation::find(&extensions)?.is_some() {
+            return Ok(());
+        }
+        let mut hasher = self.hasher.build_hasher();
+        packet.src().hash(&mut hasher);
+        packet.cert_bytes()?.hash(&mut hasher);
+        let key = hasher.finish();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let cached = self.attested.borrow().get(&key).copied();
+        let attestation = match cached {
+            Some(attestation) if attestation.expiration_time > now => attestation,
+            _ => {
+                let certs = packet.get_certs()?;
+                let expiration_time = match validate_chain(
+                    packet.src(),
+                    self.gdp_name,
+                    &certs.certificates,
+                    store,
+                    &mut Vec::new(),
+                ) {
+                    Ok(expiration_time) => expiration_time,
+                    Err(_) => return Ok(()),
+                };
+                let mut attestation = CertAttestation {
+                    attester: self.gdp_name,
+                    src: packet.src(),
+                    expiration_time,
+                    signature: [0; 64],
+                };
+                attestation.signature = signing_key(self.private_key)?
+                    .sign(&attestation.signed_bytes())
+                    .to_bytes();
+                let mut attested = self.attested.borrow_mut();
+                make_room(&mut attested, self.config.cache_entries, |attestation| {
+                    attestation.expiration_time > now
+                });
+                if self.config.cache_entries > 0 {
+                    attested.insert(key, attestation);
                 }
+                attestation
             }
+        };
+        extensions.push(attestation.to_extension());
+        packet.set_extensions(&extensions)?;
+        packet.set_certs(&CertificateBlock {
+            certificates: Vec::new(),
+        })?;
+        self.attestations.increment();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use capsule::net::MacAddr;
+    use capsule::Mbuf;
+    use gdp_proto::GdpAction;
+
+    use super::*;
+    use crate::hardcoded_routes::{metadata_of_index, private_key_of_index};
+    use crate::kvs::SharedStore;
+    use crate::rib::create_control_request;
+
+    /// Index 3 is the switch doing the checks
+    const SWITCH: u8 = 3;
+
+    fn name_of(index: u8) -> GdpName {
+        metadata_of_index(index).hash()
+    }
+
+    /// `index`'s certificate that delegates it to `proxy`
+    fn delegation(index: u8, proxy: u8) -> Certificate {
+        RtCert::new_wrapped(
+            metadata_of_index(index),
+            private_key_of_index(index),
+            CertDest::GdpName(name_of(proxy)),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// A store that knows the metadata of `indices`
+    fn store_knowing(indices: &[u8]) -> Store {
+        let store = SharedStore::new().sync();
+        store.transaction(|| {
+            for &index in indices {
+                store
+                    .gdp_metadata
+                    .put(name_of(index), metadata_of_index(index));
+            }
+        });
+        store
+    }
+
+    fn validate(certs: &[Certificate], store: &Store) -> (Result<u64>, Vec<GdpName>) {
+        let mut unknown_metas = Vec::new();
+        let verdict = validate_chain(
+            name_of(1),
+            name_of(SWITCH),
+            certs,
+            store,
+            &mut unknown_metas,
+        );
+        (verdict, unknown_metas)
+    }
+
+    #[test]
+    fn accepts_a_chain_from_the_source_to_us() {
+        let store = store_knowing(&[1, 2]);
+        let chain = [delegation(1, 2), delegation(2, SWITCH)];
+        let (verdict, unknown_metas) = validate(&chain, &store);
+        let expected = chain[0]
+            .contents
+            .expiration_time()
+            .min(chain[1].contents.expiration_time());
+        assert_eq!(verdict.unwrap(), expected);
+        assert!(unknown_metas.is_empty());
+    }
+
+    #[test]
+    fn refuses_chains_that_do_not_lead_from_the_source_to_us() {
+        let store = store_knowing(&[1, 2]);
+        // not starting at the source
+        assert!(validate(&[delegation(2, SWITCH)], &store).0.is_err());
+        // ending elsewhere
+        assert!(validate(&[delegation(1, 2)], &store).0.is_err());
+        // with a gap
+        assert!(validate(&[delegation(1, 4), delegation(2, SWITCH)], &store)
+            .0
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_forged_and_expired_certificates() {
+        let store = store_knowing(&[1, 2]);
+        let forged = RtCert::new_wrapped(
+            metadata_of_index(1),
+            private_key_of_index(2),
+            CertDest::GdpName(name_of(SWITCH)),
+            true,
+        )
+        .unwrap();
+        assert!(validate(&[forged], &store).0.is_err());
+
+        let mut expired = delegation(1, SWITCH);
+        if let CertContents::RtCert(rt_cert) = &mut expired.contents {
+            rt_cert.expiration_time = 1;
         }
-        if pos != gdp_name {
-            println!("didn't end up at target");
-            return false;
+        expired.signature = sign_data(&expired.contents, private_key_of_index(1)).unwrap();
+        assert!(validate(&[expired], &store).0.is_err());
+    }
+
+    #[test]
+    fn asks_for_the_metadata_it_lacks() {
+        let store = store_knowing(&[1]);
+        let (verdict, unknown_metas) = validate(&[delegation(1, 2), delegation(2, SWITCH)], &store);
+        assert!(verdict.is_err());
+        assert_eq!(unknown_metas, vec![name_of(2)]);
+    }
+
+    fn packet_with(certs: Vec<Certificate>) -> Gdp<DTls<Ipv4>> {
+        let mut packet = create_control_request(
+            Mbuf::new().unwrap(),
+            GdpAction::Forward,
+            b"data",
+            MacAddr::broadcast(),
+            Ipv4Addr::new(10, 0, 0, 1),
+            name_of(1),
+            Ipv4Addr::new(10, 0, 0, SWITCH),
+        )
+        .unwrap();
+        packet
+            .set_certs(&CertificateBlock {
+                certificates: certs,
+            })
+            .unwrap();
+        packet
+    }
+
+    fn checker(mode: CertMode, cache_entries: usize) -> ChainChecker {
+        let config = CertConfig {
+            mode,
+            cache_entries,
+            ..CertConfig::default()
+        };
+        ChainChecker::with_config(name_of(SWITCH), "test", config)
+    }
+
+    #[capsule::test]
+    fn remembers_chains_that_checked_out() {
+        let store = store_knowing(&[1, 2]);
+        let checker = checker(CertMode::Enforce, 16);
+        let packet = packet_with(vec![delegation(1, 2), delegation(2, SWITCH)]);
+        assert!(checker.check(&packet, &store, false));
+        assert_eq!(checker.valid.borrow().len(), 1);
+        // the same chain again, from the cache
+        assert!(checker.check(&packet, &store, false));
+        assert_eq!(checker.valid.borrow().len(), 1);
+
+        // a chain that does not check out is not remembered
+        assert!(!checker.check(&packet_with(vec![delegation(1, 2)]), &store, false));
+        assert_eq!(checker.valid.borrow().len(), 1);
+    }
+
+    #[capsule::test]
+    fn logs_rather_than_refuses_when_only_logging() {
+        let store = store_knowing(&[1, 2]);
+        let packet = packet_with(vec![delegation(1, 2)]);
+        assert!(!checker(CertMode::Enforce, 16).check(&packet, &store, false));
+        let logging = checker(CertMode::LogOnly, 16);
+        assert!(logging.check(&packet, &store, false));
+        assert!(logging.valid.borrow().is_empty());
+    }
+
+    #[capsule::test]
+    fn remembers_nothing_without_a_cache() {
+        let store = store_knowing(&[1, 2]);
+        let checker = checker(CertMode::Enforce, 0);
+        let packet = packet_with(vec![delegation(1, 2), delegation(2, SWITCH)]);
+        assert!(checker.check(&packet, &store, false));
+        assert!(checker.valid.borrow().is_empty());
+    }
+
+    #[test]
+    fn makes_room_by_dropping_expired_chains_first() {
+        let mut cache = HashMap::from([(1, 10), (2, 20)]);
+        make_room(&mut cache, 2, |&expiration_time| expiration_time > 15);
+        assert_eq!(cache, HashMap::from([(2, 20)]));
+        make_room(&mut cache, 1, |&expiration_time| expiration_time > 15);
+        assert!(cache.is_empty());
+    }
+
+    /// `attester`'s attestation to the chain from `src`, expiring at `expiration_time`, signed by
+    /// `signer`
+    fn attestation(attester: u8, src: u8, expiration_time: u64, signer: u8) -> CertAttestation {
+        let mut attestation = CertAttestation {
+            attester: name_of(attester),
+            src: name_of(src),
+            expiration_time,
+            signature: [0; 64],
+        };
+        attestation.signature = signing_key(private_key_of_index(signer))
+            .unwrap()
+            .sign(&attestation.signed_bytes())
+            .to_bytes();
+        attestation
+    }
+
+    fn start_of(
+        attestation: Option<CertAttestation>,
+        store: &Store,
+    ) -> (Result<(GdpName, u64)>, Vec<GdpName>) {
+        let mut packet = packet_with(Vec::new());
+        if let Some(attestation) = attestation {
+            packet
+                .set_extensions(&[attestation.to_extension()])
+                .unwrap();
         }
-        ok
-    } else {
-        false
+        let mut unknown_metas = Vec::new();
+        let start = chain_start(&packet, &[name_of(2)], store, &mut unknown_metas);
+        (start, unknown_metas)
+    }
+
+    fn in_an_hour() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60 * 60
+    }
+
+    #[capsule::test]
+    fn chains_start_at_the_source_or_at_a_trusted_attester() {
+        let store = store_knowing(&[1, 2]);
+        assert_eq!(start_of(None, &store).0.unwrap(), (name_of(1), u64::MAX));
+        let expiration_time = in_an_hour();
+        let attested = start_of(Some(attestation(2, 1, expiration_time, 2)), &store);
+        assert_eq!(attested.0.unwrap(), (name_of(2), expiration_time));
+    }
+
+    #[capsule::test]
+    fn refuses_attestations_that_do_not_check_out() {
+        let store = store_knowing(&[1, 2, 4]);
+        let expiration_time = in_an_hour();
+        // by a switch that is not listed, however well signed
+        assert!(
+            start_of(Some(attestation(4, 1, expiration_time, 4)), &store)
+                .0
+                .is_err()
+        );
+        // for another source
+        assert!(
+            start_of(Some(attestation(2, 4, expiration_time, 2)), &store)
+                .0
+                .is_err()
+        );
+        // expired
+        assert!(start_of(Some(attestation(2, 1, 1, 2)), &store).0.is_err());
+        // signed by someone else than the attester
+        assert!(
+            start_of(Some(attestation(2, 1, expiration_time, 4)), &store)
+                .0
+                .is_err()
+        );
+    }
+
+    #[capsule::test]
+    fn asks_for_the_metadata_of_attesters_it_lacks() {
+        let store = store_knowing(&[1]);
+        let (start, unknown_metas) = start_of(Some(attestation(2, 1, in_an_hour(), 2)), &store);
+        assert!(start.is_err());
+        assert_eq!(unknown_metas, vec![name_of(2)]);
+    }
+
+    #[test]
+    fn reads_configs_and_refuses_bad_attesters() {
+        let config = parse_cert_config("").unwrap();
+        assert_eq!(config.mode, CertMode::Enforce);
+        assert!(!config.attest);
+        assert!(config.trusted_attesters().unwrap().is_empty());
+
+        let attester = name_of(2)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        let config = parse_cert_config(&format!(
+            "mode = \"log_only\"\nattesters = [\"{}\"]",
+            attester
+        ))
+        .unwrap();
+        assert_eq!(config.mode, CertMode::LogOnly);
+        assert_eq!(config.trusted_attesters().unwrap(), vec![name_of(2)]);
+
+        assert!(parse_cert_config("attesters = [\"00ff\"]").is_err());
+        assert!(parse_cert_config("attesters = [\"not hex\"]").is_err());
+        assert!(parse_cert_config("mode = \"lenient\"").is_err());
     }
 }
@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use capsule::batch::Pipeline;
+use capsule::metrics;
+use capsule::packets::Packet;
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+use tokio_timer::delay_for;
+
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+use crate::kvs::Store;
+use crate::ribpayload::{process_rib_response, RibResponse};
+use crate::schedule::Schedule;
+
+/*
+   Failures injected on demand through the control socket, so that failover can be drilled on
+   production-like switches without unplugging anything:
+   - failed next hops and blackholed names are store tables, so that every core sees them
+     and they expire on their own if the drill is abandoned
+   - delayed RIB replies are held here, and applied by `chaos_schedule` once they are due
+*/
+
+pub struct ChaosState {
+    rib_reply_delay_ms: AtomicU64,
+    delayed: Mutex<VecDeque<(Instant, RibResponse)>>,
+}
+
+// created on first use, shared by every pipeline and the control socket
+static CHAOS: Lazy<ChaosState> = Lazy::new(|| ChaosState {
+    rib_reply_delay_ms: AtomicU64::new(0),
+    delayed: Mutex::new(VecDeque::new()),
+});
+
+pub fn chaos() -> &'static ChaosState {
+    &CHAOS
+}
+
+impl ChaosState {
+    /// Applies to replies already being held too, so setting it to 0 releases them all
+    pub fn set_rib_reply_delay(&self, delay_ms: u64) {
+        self.rib_reply_delay_ms.store(delay_ms, Ordering::Relaxed);
+    }
+
+    fn rib_reply_delay(&self) -> Duration {
+        Duration::from_millis(self.rib_reply_delay_ms.load(Ordering::Relaxed))
+    }
+
+    /// Hold on to a RIB response if replies are being delayed, otherwise hand it back to be applied
+    pub fn defer_rib_response(&self, response: RibResponse) -> Option<RibResponse> {
+        if self.rib_reply_delay() == Duration::ZERO {
+            return Some(response);
+        }
+        self.delayed
+            .lock()
+            .recover()
+            .push_back((Instant::now(), response));
+        None
+    }
+
+    fn take_due(&self) -> Vec<RibResponse> {
+        let delay = self.rib_reply_delay();
+        let mut delayed = self.delayed.lock().recover();
+        let due = delayed
+            .iter()
+            .take_while(|(received, _)| received.elapsed() >= delay)
+            .count();
+        delayed.drain(..due).map(|(_, response)| response).collect()
+    }
+}
+
+/// Apply delayed RIB replies once they are due, in the background of the GDP pipeline
+pub fn chaos_schedule(store: Store, debug: bool) -> impl Pipeline {
+    Schedule::new("chaos", async move {
+        loop {
+            for response in chaos().take_due() {
+                if let Err(err) = process_rib_response(response, store, debug) {
+                    println!("delayed RIB reply failed: {:#}", err);
+                }
+            }
+            delay_for(Duration::from_millis(1)).await;
+        }
+    })
+}
+
+/// Checks packets against the failures injected into the store, counting what they cost us
+#[derive(Clone, Copy)]
+pub struct Chaos {
+    blackholed: &'static Counter,
+    failed_next_hop: &'static Counter,
+}
+
+impl Chaos {
+    pub fn new(nic_name: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter = |reason: &'static str| -> &'static Counter {
+            Box::leak(Box::new(sink.counter_with_labels(
+                "chaos.dropped",
+                vec![("nic", nic_name), ("reason", reason)],
+            )))
+        };
+        Chaos {
+            blackholed: counter("blackhole"),
+            failed_next_hop: counter("next_hop"),
+        }
+    }
+
+    pub fn is_blackholed<T: Packet>(&self, packet: &Gdp<T>, store: Store) -> bool {
+        let blackholed = store.blackholed_names.get(&packet.dst()).is_some();
+        if blackholed {
+            self.blackholed.increment();
+        }
+        blackholed
+    }
+
+    pub fn is_failed_next_hop(&self, ip: Ipv4Addr, store: Store) -> bool {
+        let failed = store.failed_next_hops.get(&ip).is_some();
+        if failed {
+            self.failed_next_hop.increment();
+        }
+        failed
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use capsule::metrics;
+use gdp_proto::{ClientFlow, FlowReport, GdpName};
+use metrics_runtime::data::{Counter, Gauge};
+use once_cell::sync::Lazy;
+
+use crate::isolation::Recover;
+
+/*
+   Clients report how their flows are faring to their sidecar (see ReportFlow, and
+   ReportPacedFlow for Puts), so that a slow flow can be told apart from a congested path:
+   - each client's latest window and smoothed RTT are exported as gauges, and its loss events and
+     paced stalls as counters, labelled with the client's address. A client whose stalls climb
+     while its losses stay flat is held back by its receiver's pace rather than by the network
+   - the latest report on each flow is kept for the control socket until the flow finishes, or
+     until its client has not reported on it for FLOW_IDLE_TIMEOUT
+*/
+
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct ClientMetrics {
+    window: Gauge,
+    srtt_us: Gauge,
+    loss_events: Counter,
+    paced_stalls: Counter,
+}
+
+impl ClientMetrics {
+    fn new(client: SocketAddrV4) -> Self {
+        let mut sink = metrics::global().sink();
+        let labels = || vec![("client", client.to_string())];
+        ClientMetrics {
+            window: sink.gauge_with_labels("client.window", labels()),
+            srtt_us: sink.gauge_with_labels("client.srtt_us", labels()),
+            loss_events: sink.counter_with_labels("client.loss_events", labels()),
+            paced_stalls: sink.counter_with_labels("client.paced_stalls", labels()),
+        }
+    }
+}
+
+struct TrackedFlow {
+    report: FlowReport,
+    paced_stalls: u64,
+    updated: Instant,
+}
+
+pub struct ClientFlows {
+    flows: Mutex<HashMap<(SocketAddrV4, GdpName, u32), TrackedFlow>>,
+    metrics: Mutex<HashMap<SocketAddrV4, ClientMetrics>>,
+}
+
+// created on first use, shared by the sidecar pipelines and the control socket
+static CLIENT_FLOWS: Lazy<ClientFlows> = Lazy::new(|| ClientFlows {
+    flows: Mutex::new(HashMap::new()),
+    metrics: Mutex::new(HashMap::new()),
+});
+
+pub fn client_flows() -> &'static ClientFlows {
+    &CLIENT_FLOWS
+}
+
+impl ClientFlows {
+    /// Record the latest `report` on a flow of `client`, with its paced stalls if it is a Put
+    pub fn record(&self, client: SocketAddrV4, report: FlowReport, paced_stalls: u64) {
+        let mut flows = self.flows.lock().recover();
+        flows.retain(|_, flow| flow.updated.elapsed() < FLOW_IDLE_TIMEOUT);
+        let key = (client, report.peer, report.stream_id);
+        // reports carry the losses and stalls since the flow started
+        let (previous_losses, previous_stalls) = flows
+            .get(&key)
+            .map_or((0, 0), |flow| (flow.report.loss_events, flow.paced_stalls));
+
+        let mut metrics = self.metrics.lock().recover();
+        let client_metrics = metrics
+            .entry(client)
+            .or_insert_with(|| ClientMetrics::new(client));
+        client_metrics.window.record(report.window as i64);
+        if let Some(srtt_us) = report.srtt_us {
+            client_metrics.srtt_us.record(srtt_us as i64);
+        }
+        client_metrics
+            .loss_events
+            .record(report.loss_events.saturating_sub(previous_losses));
+        client_metrics
+            .paced_stalls
+            .record(paced_stalls.saturating_sub(previous_stalls));
+
+        if report.finished {
+            flows.remove(&key);
+        } else {
+            flows.insert(
+                key,
+                TrackedFlow {
+                    report,
+                    paced_stalls,
+                    updated: Instant::now(),
+                },
+            );
+        }
+    }
+
+    pub fn dump(&self) -> Vec<ClientFlow> {
+        let mut flows = self.flows.lock().recover();
+        flows.retain(|_, flow| flow.updated.elapsed() < FLOW_IDLE_TIMEOUT);
+        flows
+            .iter()
+            .map(|((client, _, _), flow)| ClientFlow {
+                client: *client,
+                report: flow.report,
+                age_ms: flow.updated.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use gdp_testutil::name;
+
+    use super::*;
+
+    const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 5), 31415);
+
+    fn flows() -> ClientFlows {
+        ClientFlows {
+            flows: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn report(stream_id: u32, loss_events: u64, finished: bool) -> FlowReport {
+        FlowReport {
+            peer: name(1),
+            stream_id,
+            window: 16,
+            srtt_us: Some(250),
+            loss_events,
+            delivered_bytes: 4096,
+            finished,
+        }
+    }
+
+    #[capsule::test]
+    fn keeps_the_latest_report_of_each_flow() {
+        let flows = flows();
+        flows.record(CLIENT, report(1, 0, false), 0);
+        flows.record(CLIENT, report(1, 3, false), 0);
+        flows.record(CLIENT, report(2, 0, false), 0);
+        let mut dumped = flows.dump();
+        dumped.sort_by_key(|flow| flow.report.stream_id);
+        assert_eq!(dumped.len(), 2);
+        assert_eq!(dumped[0].client, CLIENT);
+        assert_eq!(dumped[0].report, report(1, 3, false));
+        assert_eq!(dumped[1].report.stream_id, 2);
+    }
+
+    #[capsule::test]
+    fn forgets_finished_flows() {
+        let flows = flows();
+        flows.record(CLIENT, report(1, 0, false), 0);
+        flows.record(CLIENT, report(1, 0, true), 0);
+        assert!(flows.dump().is_empty());
+    }
+
+    #[capsule::test]
+    fn forgets_flows_whose_client_went_quiet() {
+        let flows = flows();
+        flows.record(CLIENT, report(1, 0, false), 0);
+        let long_ago = Instant::now().checked_sub(FLOW_IDLE_TIMEOUT);
+        if let Some(long_ago) = long_ago {
+            for flow in flows.flows.lock().recover().values_mut() {
+                flow.updated = long_ago;
+            }
+            assert!(flows.dump().is_empty());
+        }
+    }
+}
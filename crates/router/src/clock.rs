@@ -0,0 +1,201 @@
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Result};
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpName};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio_timer::delay_for;
+
+use crate::dtls::{encrypt_gdp, DTls};
+use crate::gdp::Gdp;
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::l2filter::VlanTx;
+use crate::packet_ops::get_payload;
+use crate::rib::{create_control_request, create_reply, Routes};
+use crate::schedule::Schedule;
+
+/// Our local oscillator is assumed to drift by at most this many parts per million
+const MAX_DRIFT_PPM: u64 = 100;
+/// The clock is reported unhealthy once the error bound exceeds this
+const MAX_HEALTHY_ERROR_US: u64 = 10_000;
+/// Queries left unanswered for this long are forgotten
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn now_us() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as i64)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimeQuery {
+    nonce: u64,
+    sent_us: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimeResponse {
+    nonce: u64,
+    sent_us: i64,
+    master_us: i64,
+}
+
+struct Sample {
+    offset_us: i64,
+    error_us: u64,
+    taken_at: Instant,
+}
+
+impl Sample {
+    // the error bound grows as our clock drifts away from the time of the exchange
+    fn error_now(&self) -> u64 {
+        self.error_us + self.taken_at.elapsed().as_micros() as u64 * MAX_DRIFT_PPM / 1_000_000
+    }
+}
+
+struct ClockState {
+    outstanding: Option<(u64, Instant)>,
+    best: Option<Sample>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClockHealth {
+    pub synced: bool,
+    pub offset_us: i64,
+    pub error_us: u64,
+}
+
+/// Estimate of the offset between our clock and the time master's
+pub struct Clock(Mutex<ClockState>);
+
+impl Clock {
+    pub fn new() -> &'static Clock {
+        Box::leak(Box::new(Clock(Mutex::new(ClockState {
+            outstanding: None,
+            best: None,
+        }))))
+    }
+
+    fn new_query(&self) -> Result<TimeQuery> {
+        let nonce = rand::thread_rng().gen();
+        let mut state = self.0.lock().map_err(|_| anyhow!("clock lock poisoned"))?;
+        state.outstanding = Some((nonce, Instant::now()));
+        Ok(TimeQuery {
+            nonce,
+            sent_us: now_us()?,
+        })
+    }
+
+    fn handle_response(&self, response: &TimeResponse) -> Result<()> {
+        let received_us = now_us()?;
+        let mut state = self.0.lock().map_err(|_| anyhow!("clock lock poisoned"))?;
+        // only accept answers to the query we are waiting on, so responses cannot be replayed
+        match state.outstanding {
+            Some((nonce, sent_at))
+                if nonce == response.nonce && sent_at.elapsed() < QUERY_TIMEOUT => {}
+            _ => return Err(anyhow!("unexpected time response")),
+        }
+        state.outstanding = None;
+        ensure!(received_us >= response.sent_us, "time went backwards");
+
+        // the master stamped its time somewhere within our round trip
+        let round_trip_us = (received_us - response.sent_us) as u64;
+        let sample = Sample {
+            offset_us: response.master_us - (response.sent_us + received_us) / 2,
+            error_us: round_trip_us / 2,
+            taken_at: Instant::now(),
+        };
+        match state.best {
+            Some(ref best) if best.error_now() <= sample.error_us => {}
+            _ => state.best = Some(sample),
+        }
+        Ok(())
+    }
+
+    pub fn health(&self) -> ClockHealth {
+        let state = self.0.lock().recover();
+        match state.best {
+            Some(ref best) => {
+                let error_us = best.error_now();
+                ClockHealth {
+                    synced: error_us <= MAX_HEALTHY_ERROR_US,
+                    offset_us: best.offset_us,
+                    error_us,
+                }
+            }
+            None => ClockHealth {
+                synced: false,
+                offset_us: 0,
+                error_us: u64::MAX,
+            },
+        }
+    }
+}
+
+/// Answer a time query on behalf of the time master
+pub fn handle_time_query(packet: &Gdp<DTls<Ipv4>>) -> Result<Gdp<DTls<Ipv4>>> {
+    let query: TimeQuery = bincode::deserialize(get_payload(packet)?)?;
+    let response = TimeResponse {
+        nonce: query.nonce,
+        sent_us: query.sent_us,
+        master_us: now_us()?,
+    };
+    create_reply(
+        packet,
+        GdpAction::TimeReply,
+        &bincode::serialize(&response)?,
+    )
+}
+
+pub fn handle_time_reply(packet: &Gdp<DTls<Ipv4>>, clock: &Clock) -> Result<()> {
+    let response: TimeResponse = bincode::deserialize(get_payload(packet)?)?;
+    clock.handle_response(&response)
+}
+
+fn send_time_query(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    master_ip: Ipv4Addr,
+    clock: &'static Clock,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            let query = bincode::serialize(&clock.new_query()?)?;
+            create_control_request(
+                packet,
+                GdpAction::TimeGet,
+                &query,
+                src.mac,
+                src.ip,
+                src_gdp_name,
+                master_ip,
+            )
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q))
+        .run_once();
+}
+
+/// Periodically query the time master; its replies are consumed by the GDP pipeline
+pub fn time_sync_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    routes: &'static Routes,
+    clock: &'static Clock,
+) -> impl Pipeline {
+    Schedule::new("time_sync", async move {
+        loop {
+            let master_ip = routes.time_master().ip;
+            send_time_query(q.clone(), src, src_gdp_name, master_ip, clock);
+            delay_for(Duration::from_secs(10)).await;
+        }
+    })
+}
@@ -0,0 +1,140 @@
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::GdpName;
+use metrics_runtime::data::Counter;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::kvs::{FwdTableEntry, Store};
+
+/*
+   A flow ends one of two ways, counted in `flows.ended` by `how`:
+   - an endpoint ends it with a Fin (`fin` for a Close, `reset` for a Reset). The switches on the
+     way forget the flow in both directions, and what they decided about admitting it. On a
+     Reset they also drop the flow's packets that they hold while the RIB is asked for a route;
+     those of a Close are still delivered
+   - no packet is seen in either direction for FLOW_IDLE_TIMEOUT (`timeout`). Flows are swept
+     fully by the store's maintenance, so that every flow that times out is counted
+   dTLS sessions and RIB subscriptions are between switches and carry many flows, so they are
+   not ended with any one of them.
+*/
+
+/// Flows are forgotten once no packet has been seen in either direction for this long
+const FLOW_IDLE_TIMEOUT: u64 = 60;
+
+/// (initiator, peer) pair of GdpNames identifying a flow
+pub type FlowKey = (GdpName, GdpName);
+
+/// The 5-tuple (UDP is implied) and port on which a local endpoint opened a flow
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct FlowEntry {
+    pub src_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_ip: Ipv4Addr,
+    pub dst_port: u16,
+    #[serde(deserialize_with = "leak_nic_name")]
+    pub nic_name: &'static str,
+}
+
+/// Flows are only deserialized when a switch hands its state over, once per process
+fn leak_nic_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static str, D::Error> {
+    Ok(Box::leak(
+        String::deserialize(deserializer)?.into_boxed_str(),
+    ))
+}
+
+fn idle_deadline() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + FLOW_IDLE_TIMEOUT)
+}
+
+/// Record (or keep alive) a flow opened by an endpoint that delegated directly to us
+pub fn track_outbound(
+    packet: &Gdp<DTls<Ipv4>>,
+    store: Store,
+    nic_name: &'static str,
+) -> Result<()> {
+    // endpoints in our local domain present a single certificate naming us as their proxy
+    if packet.get_certs()?.certificates.len() != 1 {
+        return Ok(());
+    }
+    let udp = packet.envelope().envelope();
+    let ipv4 = udp.envelope();
+    let key = (packet.src(), packet.dst());
+    let deadline = idle_deadline()?;
+    match store.flows.get(&key) {
+        // only rewrite the entry once it is halfway to expiring, to keep the global lock cold
+        Some(entry) if entry.expiration_time > deadline - FLOW_IDLE_TIMEOUT / 2 => {}
+        _ => store.flows.update(
+            key,
+            FwdTableEntry::new(
+                FlowEntry {
+                    src_ip: ipv4.src(),
+                    src_port: udp.src_port(),
+                    dst_ip: ipv4.dst(),
+                    dst_port: udp.dst_port(),
+                    nic_name,
+                },
+                deadline,
+            ),
+        ),
+    }
+    Ok(())
+}
+
+/// Forget the flow between `src` and `dst`, in both directions, returning whether we knew it
+pub fn end_flow(src: GdpName, dst: GdpName, store: Store) -> bool {
+    let known = store.flows.get(&(src, dst)).is_some() || store.flows.get(&(dst, src)).is_some();
+    store.flows.remove(&(src, dst));
+    store.flows.remove(&(dst, src));
+    known
+}
+
+/// Counts the flows that ended `how`: "fin", "reset" or "timeout"
+pub fn flows_ended(how: &'static str) -> Counter {
+    metrics::global()
+        .sink()
+        .counter_with_labels("flows.ended", vec![("how", how)])
+}
+
+/// The flows that endpoints ended with a Fin, by its kind
+pub struct FinCounters {
+    pub fin: Counter,
+    pub reset: Counter,
+}
+
+impl FinCounters {
+    pub fn new() -> &'static Self {
+        Box::leak(Box::new(FinCounters {
+            fin: flows_ended("fin"),
+            reset: flows_ended("reset"),
+        }))
+    }
+}
+
+/// The flow that a packet from `src` to `dst` replies to, as opened by `dst`. Only looks; the
+/// packet's switch keeps the flow alive with `keep_flow_alive`
+pub fn lookup_return_flow(
+    src: GdpName,
+    dst: GdpName,
+    store: Store,
+) -> Option<FwdTableEntry<FlowEntry>> {
+    store.flows.get(&(dst, src))
+}
+
+/// Refresh the idle timer of the flow that `lookup_return_flow` found for a packet from `src` to
+/// `dst`
+pub fn keep_flow_alive(src: GdpName, dst: GdpName, entry: FwdTableEntry<FlowEntry>, store: Store) {
+    if let Ok(deadline) = idle_deadline() {
+        if entry.expiration_time <= deadline - FLOW_IDLE_TIMEOUT / 2 {
+            store
+                .flows
+                .update((dst, src), FwdTableEntry::new(entry.val, deadline));
+        }
+    }
+}
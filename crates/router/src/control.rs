@@ -0,0 +1,294 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use gdp_proto::{
+    ClientCommand, ClientCommands, ClientResponse, ClientResponses, GdpName, NodeInfo, TableExport,
+};
+
+use crate::blocklist::blocklist;
+use crate::branches::branches;
+use crate::canary::canaries;
+use crate::chaos::chaos;
+use crate::clientflows::client_flows;
+use crate::clock::Clock;
+use crate::flags::{FeatureFlags, Flag};
+use crate::info::flag_states;
+use crate::kvs::SharedStore;
+#[cfg(feature = "switch")]
+use crate::probe::Prober;
+use crate::unknown_action::punt_queue;
+#[cfg(feature = "switch")]
+use crate::usage::UsageMeter;
+
+/// How long a pinned route lasts if the operator does not say
+const DEFAULT_PIN_DURATION: u64 = 60 * 60;
+/// How long an injected failure lasts if the operator does not say, so abandoned drills heal
+const DEFAULT_CHAOS_DURATION: u64 = 5 * 60;
+
+/// State that the operator can inspect and modify through the control socket
+#[derive(Copy, Clone)]
+pub struct ControlState {
+    pub flags: FeatureFlags,
+    /// What the node runs, as it was at startup
+    pub info: &'static NodeInfo,
+    pub clock: &'static Clock,
+    pub store: SharedStore,
+    /// None on nodes that do not forward, and so have no routes to probe
+    #[cfg(feature = "switch")]
+    pub prober: Option<Prober>,
+    /// None on nodes that do not forward, and so have no tenants to bill
+    #[cfg(feature = "switch")]
+    pub usage: Option<UsageMeter>,
+}
+
+fn pin_route(
+    name: GdpName,
+    ip: Ipv4Addr,
+    duration_secs: Option<u64>,
+    store: SharedStore,
+) -> Result<ClientResponse<'static>> {
+    // an operator may pin a route for good with a huge duration, which must not wrap around
+    let expiration_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .saturating_add(duration_secs.unwrap_or(DEFAULT_PIN_DURATION));
+    store.pin_route(name, ip, expiration_time);
+    println!(
+        "control: pinned {:?} to {} until {}",
+        name, ip, expiration_time
+    );
+    Ok(ClientResponse::RoutePinned {
+        name,
+        ip,
+        expiration_time,
+    })
+}
+
+fn inject_chaos(
+    duration_secs: Option<u64>,
+    inject: impl FnOnce(u64),
+) -> Result<ClientResponse<'static>> {
+    let expiration_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .saturating_add(duration_secs.unwrap_or(DEFAULT_CHAOS_DURATION));
+    inject(expiration_time);
+    Ok(ClientResponse::ChaosInjected { expiration_time })
+}
+
+fn execute_command(command: &ClientCommand, state: ControlState) -> ClientResponse<'static> {
+    match command {
+        ClientCommand::SetFlag { name, enabled } => match Flag::from_name(name) {
+            Ok(flag) => {
+                state.flags.set(flag, *enabled);
+                println!("control: set flag {} to {}", flag.name(), enabled);
+                ClientResponse::FlagSet {
+                    name: flag.name().into(),
+                    enabled: *enabled,
+                }
+            }
+            Err(err) => ClientResponse::Error {
+                msg: err.to_string().into(),
+            },
+        },
+        ClientCommand::ListFlags => ClientResponse::Flags {
+            flags: state
+                .flags
+                .list()
+                .into_iter()
+                .map(|(flag, enabled)| (flag.name().into(), enabled))
+                .collect(),
+        },
+        ClientCommand::Health => {
+            let clock = state.clock.health();
+            ClientResponse::Health {
+                clock_synced: clock.synced,
+                clock_offset_us: clock.offset_us,
+                clock_error_us: clock.error_us,
+            }
+        }
+        ClientCommand::PinRoute {
+            name,
+            ip,
+            duration_secs,
+        } => pin_route(*name, *ip, *duration_secs, state.store).unwrap_or_else(|err| {
+            ClientResponse::Error {
+                msg: err.to_string().into(),
+            }
+        }),
+        ClientCommand::UnpinRoute { name } => {
+            let existed = state.store.unpin_route(*name);
+            println!("control: unpinned {:?}", name);
+            ClientResponse::RouteUnpinned {
+                name: *name,
+                existed,
+            }
+        }
+        ClientCommand::DumpRoutes => ClientResponse::Routes {
+            routes: state.store.dump_routes(),
+        },
+        ClientCommand::ExportTable => ClientResponse::Table {
+            export: TableExport::new(state.store.dump_routes()),
+        },
+        #[cfg(feature = "switch")]
+        ClientCommand::DumpProbes if state.prober.is_some() => ClientResponse::Probes {
+            results: state.prober.unwrap().results(),
+        },
+        ClientCommand::DumpProbes => ClientResponse::Error {
+            msg: "DumpProbes is only supported by the switch".into(),
+        },
+        #[cfg(feature = "switch")]
+        ClientCommand::UsageReports { after_sequence } if state.usage.is_some() => {
+            let (reports, more) = state.usage.unwrap().reports_after(*after_sequence);
+            ClientResponse::UsageReports { reports, more }
+        }
+        ClientCommand::UsageReports { .. } => ClientResponse::Error {
+            msg: "UsageReports is only supported by the switch".into(),
+        },
+        ClientCommand::TakePunted { max } => {
+            let (packets, dropped) = punt_queue().take(*max);
+            ClientResponse::Punted { packets, dropped }
+        }
+        ClientCommand::FailNextHop { ip, duration_secs } => {
+            println!("control: failing next hop {}", ip);
+            inject_chaos(*duration_secs, |expiration_time| {
+                state.store.fail_next_hop(*ip, expiration_time)
+            })
+            .unwrap_or_else(|err| ClientResponse::Error {
+                msg: err.to_string().into(),
+            })
+        }
+        ClientCommand::BlackholeName {
+            name,
+            duration_secs,
+        } => {
+            println!("control: blackholing {:?}", name);
+            inject_chaos(*duration_secs, |expiration_time| {
+                state.store.blackhole_name(*name, expiration_time)
+            })
+            .unwrap_or_else(|err| ClientResponse::Error {
+                msg: err.to_string().into(),
+            })
+        }
+        ClientCommand::DelayRibReplies { delay_ms } => {
+            chaos().set_rib_reply_delay(*delay_ms);
+            println!("control: delaying RIB replies by {}ms", delay_ms);
+            ClientResponse::RibRepliesDelayed {
+                delay_ms: *delay_ms,
+            }
+        }
+        ClientCommand::ClearChaos => {
+            state.store.clear_chaos();
+            chaos().set_rib_reply_delay(0);
+            println!("control: cleared injected failures");
+            ClientResponse::ChaosCleared
+        }
+        ClientCommand::BlockPeer { ip, duration_secs } => {
+            let expiration_time = blocklist().block(*ip, *duration_secs);
+            println!("control: blocking {} until {}", ip, expiration_time);
+            ClientResponse::PeerBlocked {
+                ip: *ip,
+                expiration_time,
+            }
+        }
+        ClientCommand::UnblockPeer { ip, exempt_secs } => {
+            let was_blocked = blocklist().unblock(*ip, *exempt_secs);
+            println!("control: unblocking {}", ip);
+            ClientResponse::PeerUnblocked {
+                ip: *ip,
+                was_blocked,
+            }
+        }
+        ClientCommand::DumpBlocklist => ClientResponse::Blocklist {
+            peers: blocklist().dump(),
+        },
+        ClientCommand::DumpBlockedSources => ClientResponse::BlockedSources {
+            sources: blocklist().dump_sources(),
+        },
+        ClientCommand::DumpClientFlows => ClientResponse::ClientFlows {
+            flows: client_flows().dump(),
+        },
+        ClientCommand::DumpBranches => ClientResponse::Branches {
+            branches: branches().report(),
+        },
+        ClientCommand::SetCanary {
+            name,
+            ip,
+            percent,
+            mode,
+            duration_secs,
+        } => match canaries().set(*name, *ip, *percent, *mode, *duration_secs) {
+            Ok(expiration_time) => {
+                println!(
+                    "control: {:?} {}% of {:?} to {} until {}",
+                    mode, percent, name, ip, expiration_time
+                );
+                ClientResponse::CanarySet {
+                    name: *name,
+                    expiration_time,
+                }
+            }
+            Err(err) => ClientResponse::Error {
+                msg: err.to_string().into(),
+            },
+        },
+        ClientCommand::ClearCanary { name } => {
+            let existed = canaries().clear(*name);
+            println!("control: cleared canary of {:?}", name);
+            ClientResponse::CanaryCleared {
+                name: *name,
+                existed,
+            }
+        }
+        ClientCommand::DumpCanaries => ClientResponse::Canaries {
+            canaries: canaries().dump(),
+        },
+        ClientCommand::Info => ClientResponse::Info {
+            info: NodeInfo {
+                flags: flag_states(state.flags),
+                ..state.info.clone()
+            },
+        },
+        ClientCommand::ReportFlow { .. } | ClientCommand::ReportPacedFlow { .. } => {
+            ClientResponse::Error {
+                msg: "ReportFlow is only supported by the sidecar".into(),
+            }
+        }
+        ClientCommand::ResolveName { name } => ClientResponse::Binding {
+            name: *name,
+            pub_key: state.store.metadata(name).map(|meta| meta.pub_key),
+        },
+        ClientCommand::SetPort { .. } => ClientResponse::Error {
+            msg: "SetPort is only supported by the sidecar".into(),
+        },
+    }
+}
+
+fn handle_request(socket: &UdpSocket, state: ControlState) -> Result<()> {
+    let mut buf = [0u8; 1 << 16];
+    let (size, from) = socket.recv_from(&mut buf)?;
+    let ClientCommands { messages } =
+        bincode::deserialize(&buf[..size]).context("failed to deserialize control commands")?;
+    let response = bincode::serialize(&ClientResponses {
+        messages: messages
+            .iter()
+            .map(|msg| execute_command(msg, state))
+            .collect(),
+    })?;
+    socket.send_to(&response, from)?;
+    Ok(())
+}
+
+/// Listen for control commands on localhost, on a thread separate from the packet cores
+pub fn start_control_socket(port: u16, state: ControlState) -> Result<()> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        .context("failed to bind control socket")?;
+    thread::spawn(move || loop {
+        if let Err(err) = handle_request(&socket, state) {
+            println!("control: failed to handle request: {:#}", err);
+        }
+    });
+    Ok(())
+}
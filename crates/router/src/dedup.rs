@@ -0,0 +1,70 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use capsule::packets::ip::v4::Ipv4;
+use gdp_proto::{GdpAction, GdpName};
+use once_cell::sync::Lazy;
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::kvs::Store;
+
+/*
+   Switches drop the copies of Forwards they already forwarded, which loops and duplicated
+   datagrams would otherwise have them forward again and again:
+   - senders number the packets of each flow (source to destination) from 1, in a SequenceNumber
+     extension. A packet sent again, like a stream's retransmission, is a new packet with a new
+     number, as the first one may have been lost past a switch that saw it
+   - a switch remembers each Forward it sends on for at least DEDUP_WINDOW, in the store's
+     `forwarded` cache, and drops the Forwards it remembers as it is about to send them on. The
+     drops are counted in `dedup.duplicates`. One lookup both checks and remembers a packet
+   - numbers are not signed, so a packet is remembered by its source, destination and number
+     along with a digest of its data: a sender that reuses a number, or anyone forging one, only
+     shuts out byte-for-byte copies of what it sent, never another packet. The digest is keyed
+     on startup, so that no one can pick data whose digest matches another packet's
+   - packets are remembered once they passed their checks, not when they arrive, so that
+     packets held for a route (see missbuffer.rs) pass again when they are released, and packets
+     that fail their checks do not count
+   - the cache keeps at most DEDUP_MAX_KEYS packets per window, and starts a new window early
+     once it is full: a flood of numbered packets shortens how long packets are remembered
+     rather than growing the cache
+   - the copies that a switch makes itself, for names that are moving and Mirror canaries, are
+     not numbered, so that they are not taken for the packet they copy where their paths meet.
+     Packets that are not numbered, from senders before numbering too, are never dropped
+   - Puts and their Acks are never dropped: a Put that is sent again keeps its number, for its
+     destination to acknowledge it by (see puts.rs), and it is sent again because the first one
+     or its Ack was lost past a switch that saw it
+*/
+
+/// How long a switch remembers a packet it forwarded, at least
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(2);
+/// How many forwarded packets a switch remembers per window, at most
+pub const DEDUP_MAX_KEYS: usize = 1 << 17;
+
+/// A packet's source, destination and number in its flow
+pub type SeqKey = (GdpName, GdpName, u32);
+/// What a forwarded packet is remembered by: its SeqKey and the digest of its data
+pub type ForwardedKey = (SeqKey, u64);
+
+// keyed on first use, shared by every core so that they agree on the digests
+static DIGESTS: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+fn key(packet: &Gdp<DTls<Ipv4>>) -> Option<ForwardedKey> {
+    if !matches!(packet.action(), Ok(GdpAction::Forward)) {
+        return None;
+    }
+    let seqno = match packet.seqno() {
+        0 => return None,
+        seqno => seqno,
+    };
+    let mut digest = DIGESTS.build_hasher();
+    packet.data().ok()?.hash(&mut digest);
+    Some(((packet.src(), packet.dst(), seqno), digest.finish()))
+}
+
+/// Remember that `packet` is being forwarded, returning false if the switch recently forwarded
+/// the packet it is a copy of
+pub fn first_forward(packet: &Gdp<DTls<Ipv4>>, store: Store) -> bool {
+    key(packet).map_or(true, |key| store.forwarded.insert(key, Instant::now()))
+}
@@ -4,31 +4,84 @@ use std::time::Duration;
 use anyhow::Result;
 use capsule::config::RuntimeConfig;
 
-use crate::certificates::{CertDest, RtCert};
+use crate::admission::{admission_schedule, Admission};
+use crate::capabilities::heartbeat_schedule;
+use crate::certificates::{CertDest, NewRtCert, RtCert};
+use crate::clock::Clock;
+use crate::conntrack::flows_ended;
+use crate::dtls::session::session_schedule;
+use crate::flags::FeatureFlags;
 use crate::gdp_pipeline::install_gdp_pipeline;
 use crate::hardcoded_routes::{
-    gdp_name_of_index, load_routes, metadata_of_index, private_key_of_index,
+    gdp_name_of_index, load_routes, metadata_of_index, private_key_of_index, RIB_INDEX,
 };
-use crate::kvs::SharedStore;
-use crate::rib::{rib_pipeline, send_rib_query, Routes};
-use crate::ribpayload::RibQuery;
+use crate::identity::{load_port_identities, PortIdentities};
+use crate::invalidation::{gossip_schedule, Gossip};
+use crate::isolation::Recover;
+use crate::kvs::{SharedStore, PUBLISH_INTERVAL};
+use crate::labels::{flush_label_audit, AUDIT_FLUSH_INTERVAL};
+use crate::prefetch::{prefetch_schedule, Prefetcher, SequentialPredictor, DEFAULT_PREFETCH_DEPTH};
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::probe::{probe_schedule, Prober};
+use crate::registration::register_node;
+use crate::rib::{rib_pipeline, Routes};
+use crate::ribreplies::{load_rib_reply_config, reply_schedule, RibReplies};
+use crate::ribsync::{RibSync, RibSyncConfig};
 use crate::runtime::build_runtime;
 use crate::statistics::{dump_history, make_print_stats};
+use crate::status::{StatusBoard, STATUS_INTERVAL};
 use crate::switch::switch_pipeline;
+use crate::usage::{UsageMeter, REPORT_INTERVAL};
 use crate::workloads::dev_schedule;
 use crate::Env;
 
-pub fn start_dev_server(config: RuntimeConfig) -> Result<()> {
+pub fn start_dev_server(config: RuntimeConfig, flags: FeatureFlags) -> Result<()> {
+    preflight(
+        &config,
+        Env::Local,
+        &Requirements {
+            ports: &["eth1", "eth2", "eth3", "eth4"],
+            cores: &[0],
+            node_addr: None,
+            rib: RibUse::Queries,
+            workload: false,
+        },
+    )?;
     let store1 = SharedStore::new();
     let store2 = SharedStore::new();
     let store3 = SharedStore::new();
     let store4 = SharedStore::new();
 
     let routes: &'static Routes = Box::leak(Box::new(load_routes(Env::Local)?));
+    let identities: &'static PortIdentities = Box::leak(Box::new(load_port_identities()?));
 
     let (_print_stats, history_map) = make_print_stats();
 
     let rib_ip = Ipv4Addr::new(10, 100, 1, 10);
+    let switch_routes: &'static Routes = Box::leak(Box::new(Routes::fixed(rib_ip)));
+    let clock = Clock::new();
+    let switch_prefetcher =
+        Prefetcher::new(SequentialPredictor::new(DEFAULT_PREFETCH_DEPTH), "switch");
+    let target_prefetcher =
+        Prefetcher::new(SequentialPredictor::new(DEFAULT_PREFETCH_DEPTH), "target");
+    let switch_prober = Prober::new("switch");
+    let target_prober = Prober::new("target");
+    let switch_usage = UsageMeter::new(gdp_name_of_index(2), private_key_of_index(2))?;
+    let target_usage = UsageMeter::new(gdp_name_of_index(3), private_key_of_index(3))?;
+    let switch_admission = Admission::new("switch");
+    let target_admission = Admission::new("target");
+    let switch_status = StatusBoard::new(gdp_name_of_index(2));
+    let target_status = StatusBoard::new(gdp_name_of_index(3));
+    let switch_gossip = Gossip::new("switch", private_key_of_index(2));
+    let target_gossip = Gossip::new("target", private_key_of_index(3));
+    let timed_out_flows = flows_ended("timeout");
+    let rib_replies = RibReplies::new(load_rib_reply_config().unwrap_or_default());
+    // the only RIB of the run, so it has no peers to replicate with
+    let rib_sync = RibSync::new(
+        RibSyncConfig::default(),
+        metadata_of_index(RIB_INDEX),
+        private_key_of_index(RIB_INDEX),
+    );
 
     const DEBUG: bool = true;
 
@@ -39,12 +92,19 @@ pub fn start_dev_server(config: RuntimeConfig) -> Result<()> {
             let node_addr = Ipv4Addr::new(10, 100, 1, 10);
             install_gdp_pipeline(
                 q,
-                rib_pipeline(name, routes, false, DEBUG),
+                rib_pipeline(name, routes, rib_replies, rib_sync, false, flags, DEBUG),
                 name,
                 node_addr,
+                flags,
                 DEBUG,
             )
         })?
+        .add_pipeline_to_core(0, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, rib_ip).unwrap();
+            reply_schedule(q, identity, rib_replies, routes, DEBUG)
+        })?
+        .add_pipeline_to_port("eth1", session_schedule)?
         // GDP index = 1
         .add_pipeline_to_port("eth2", move |q| dev_schedule(q, "client"))?
         // GDP index = 2
@@ -54,16 +114,17 @@ pub fn start_dev_server(config: RuntimeConfig) -> Result<()> {
             let meta = metadata_of_index(2);
             let private_key = private_key_of_index(2);
             let node_addr = Ipv4Addr::new(10, 100, 1, 12);
-            send_rib_query(
+            let identity = identities.resolve("eth3", &q, node_addr).unwrap();
+            register_node(
                 q.clone(),
-                node_addr,
-                gdp_name_of_index(2),
-                rib_ip,
-                &RibQuery::announce_route(
-                    meta,
+                identity,
+                meta,
+                vec![
                     RtCert::new_wrapped(meta, private_key, CertDest::IpAddr(node_addr), true)
                         .unwrap(),
-                ),
+                ],
+                private_key,
+                rib_ip,
                 name,
             );
             install_gdp_pipeline(
@@ -74,14 +135,84 @@ pub fn start_dev_server(config: RuntimeConfig) -> Result<()> {
                     private_key,
                     store3_local,
                     name,
-                    rib_ip,
+                    identity,
+                    switch_routes,
+                    flags,
+                    clock,
+                    switch_prefetcher,
+                    switch_prober,
+                    switch_usage,
+                    switch_admission,
+                    switch_status,
+                    switch_gossip,
                     DEBUG,
                 ),
                 name,
-                node_addr,
+                identity.ip,
+                flags,
                 DEBUG,
             )
         })?
+        .add_pipeline_to_port("eth3", move |q| {
+            let identity = identities
+                .resolve("eth3", &q, Ipv4Addr::new(10, 100, 1, 12))
+                .unwrap();
+            prefetch_schedule(
+                q,
+                identity,
+                gdp_name_of_index(2),
+                switch_routes,
+                store3.sync(),
+                switch_prefetcher,
+            )
+        })?
+        .add_pipeline_to_port("eth3", move |q| {
+            let identity = identities
+                .resolve("eth3", &q, Ipv4Addr::new(10, 100, 1, 12))
+                .unwrap();
+            heartbeat_schedule(
+                q,
+                identity,
+                gdp_name_of_index(2),
+                switch_routes,
+                store3,
+                flags,
+            )
+        })?
+        .add_pipeline_to_port("eth3", move |q| {
+            let identity = identities
+                .resolve("eth3", &q, Ipv4Addr::new(10, 100, 1, 12))
+                .unwrap();
+            probe_schedule(
+                q,
+                identity,
+                gdp_name_of_index(2),
+                switch_routes,
+                store3.sync(),
+                switch_prober,
+                flags,
+            )
+        })?
+        .add_pipeline_to_port("eth3", move |q| {
+            let identity = identities
+                .resolve("eth3", &q, Ipv4Addr::new(10, 100, 1, 12))
+                .unwrap();
+            admission_schedule(q, identity, gdp_name_of_index(2), switch_admission, DEBUG)
+        })?
+        .add_pipeline_to_port("eth3", move |q| {
+            let identity = identities
+                .resolve("eth3", &q, Ipv4Addr::new(10, 100, 1, 12))
+                .unwrap();
+            gossip_schedule(
+                q,
+                identity,
+                gdp_name_of_index(2),
+                switch_routes,
+                store3,
+                switch_gossip,
+            )
+        })?
+        .add_pipeline_to_port("eth3", session_schedule)?
         // GDP index = 3
         .add_pipeline_to_port("eth4", move |q| {
             let name = "target";
@@ -89,16 +220,17 @@ pub fn start_dev_server(config: RuntimeConfig) -> Result<()> {
             let meta = metadata_of_index(3);
             let private_key = private_key_of_index(3);
             let node_addr = Ipv4Addr::new(10, 100, 1, 13);
-            send_rib_query(
+            let identity = identities.resolve("eth4", &q, node_addr).unwrap();
+            register_node(
                 q.clone(),
-                node_addr,
-                gdp_name_of_index(3),
-                rib_ip,
-                &RibQuery::announce_route(
-                    meta,
+                identity,
+                meta,
+                vec![
                     RtCert::new_wrapped(meta, private_key, CertDest::IpAddr(node_addr), true)
                         .unwrap(),
-                ),
+                ],
+                private_key,
+                rib_ip,
                 name,
             );
             install_gdp_pipeline(
@@ -109,26 +241,129 @@ pub fn start_dev_server(config: RuntimeConfig) -> Result<()> {
                     private_key,
                     store4_local,
                     name,
-                    rib_ip,
+                    identity,
+                    switch_routes,
+                    flags,
+                    clock,
+                    target_prefetcher,
+                    target_prober,
+                    target_usage,
+                    target_admission,
+                    target_status,
+                    target_gossip,
                     DEBUG,
                 ),
                 name,
-                node_addr,
+                identity.ip,
+                flags,
                 DEBUG,
             )
         })?
+        .add_pipeline_to_port("eth4", move |q| {
+            let identity = identities
+                .resolve("eth4", &q, Ipv4Addr::new(10, 100, 1, 13))
+                .unwrap();
+            prefetch_schedule(
+                q,
+                identity,
+                gdp_name_of_index(3),
+                switch_routes,
+                store4.sync(),
+                target_prefetcher,
+            )
+        })?
+        .add_pipeline_to_port("eth4", move |q| {
+            let identity = identities
+                .resolve("eth4", &q, Ipv4Addr::new(10, 100, 1, 13))
+                .unwrap();
+            heartbeat_schedule(
+                q,
+                identity,
+                gdp_name_of_index(3),
+                switch_routes,
+                store4,
+                flags,
+            )
+        })?
+        .add_pipeline_to_port("eth4", move |q| {
+            let identity = identities
+                .resolve("eth4", &q, Ipv4Addr::new(10, 100, 1, 13))
+                .unwrap();
+            probe_schedule(
+                q,
+                identity,
+                gdp_name_of_index(3),
+                switch_routes,
+                store4.sync(),
+                target_prober,
+                flags,
+            )
+        })?
+        .add_pipeline_to_port("eth4", move |q| {
+            let identity = identities
+                .resolve("eth4", &q, Ipv4Addr::new(10, 100, 1, 13))
+                .unwrap();
+            admission_schedule(q, identity, gdp_name_of_index(3), target_admission, DEBUG)
+        })?
+        .add_pipeline_to_port("eth4", move |q| {
+            let identity = identities
+                .resolve("eth4", &q, Ipv4Addr::new(10, 100, 1, 13))
+                .unwrap();
+            gossip_schedule(
+                q,
+                identity,
+                gdp_name_of_index(3),
+                switch_routes,
+                store4,
+                target_gossip,
+            )
+        })?
+        .add_pipeline_to_port("eth4", session_schedule)?
         // .add_periodic_task_to_core(0, print_stats, Duration::from_secs(1))?
         .add_periodic_task_to_core(
             0,
             move || {
-                [store1, store2, store3]
+                [store1, store2, store3, store4]
                     .iter()
-                    .for_each(|store| store.run_active_expire())
+                    .for_each(|store| store.publish())
+            },
+            PUBLISH_INTERVAL,
+        )?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                for store in [store1, store2, store3] {
+                    store.run_active_expire();
+                    timed_out_flows.record(store.take_timed_out_flows());
+                }
             },
             Duration::from_secs(1),
         )?
+        .add_periodic_task_to_core(0, flush_label_audit, AUDIT_FLUSH_INTERVAL)?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                for usage in [switch_usage, target_usage] {
+                    if let Err(err) = usage.report() {
+                        println!("failed to make usage report: {:#}", err);
+                    }
+                }
+            },
+            REPORT_INTERVAL,
+        )?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                for (status, store) in [(switch_status, store3), (target_status, store4)] {
+                    if let Err(err) = status.refresh(store, clock) {
+                        println!("failed to refresh a switch's status: {:#}", err);
+                    }
+                }
+            },
+            STATUS_INTERVAL,
+        )?
         .execute()?;
 
-    let x = dump_history(&(*history_map.lock().unwrap()));
+    let x = dump_history(&(*history_map.lock().recover()));
     x
 }
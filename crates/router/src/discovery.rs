@@ -0,0 +1,104 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use capsule::packets::ip::v4::Ipv4;
+use gdp_proto::{GdpAction, GdpName};
+use serde::{Deserialize, Serialize};
+
+use crate::certificates::{
+    sign_data, verify_data, AttrCert, CertContents, GdpMeta, SerializableSignature,
+};
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+use crate::kvs::Expirable;
+use crate::packet_ops::get_payload;
+use crate::rib::{create_reply, Routes};
+
+/// Upper bound on names per reply, so that a page always fits in a single packet
+const MAX_PAGE_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+pub struct RibSearchQuery {
+    /// A name matches if it advertises every one of these tags
+    pub tags: Vec<String>,
+    /// Resume after this name (the `next` cursor of the previous page)
+    pub after: Option<GdpName>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RibSearchResults {
+    pub tags: Vec<String>,
+    pub after: Option<GdpName>,
+    pub names: Vec<GdpName>,
+    /// Cursor for the next page, if there are more matches
+    pub next: Option<GdpName>,
+    pub generated_at: u64,
+}
+
+/// Search results signed by the RIB, so that they can be checked by anyone holding its metadata
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RibSearchResponse {
+    pub results: RibSearchResults,
+    pub signature: SerializableSignature,
+}
+
+fn search_attributes(query: RibSearchQuery, routes: &Routes) -> Result<RibSearchResults> {
+    let dynamic_routes = routes.dynamic_routes.read().recover();
+    let mut names = dynamic_routes
+        .attributes
+        .iter()
+        .filter(|(_, cert)| !cert.is_expired())
+        .filter(|(_, cert)| match cert.contents {
+            CertContents::AttrCert(AttrCert { ref tags, .. }) => {
+                query.tags.iter().all(|tag| tags.contains(tag))
+            }
+            _ => false,
+        })
+        .map(|(gdp_name, _)| *gdp_name)
+        .filter(|gdp_name| query.after.map_or(true, |after| *gdp_name > after))
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+
+    let limit = query.limit.clamp(1, MAX_PAGE_SIZE);
+    let next = if names.len() > limit {
+        names.truncate(limit);
+        names.last().copied()
+    } else {
+        None
+    };
+
+    Ok(RibSearchResults {
+        tags: query.tags,
+        after: query.after,
+        names,
+        next,
+        generated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    })
+}
+
+pub fn handle_rib_search(
+    packet: &Gdp<DTls<Ipv4>>,
+    routes: &Routes,
+    private_key: [u8; 32],
+    debug: bool,
+) -> Result<Gdp<DTls<Ipv4>>> {
+    let query: RibSearchQuery = bincode::deserialize(get_payload(packet)?)?;
+    let results = search_attributes(query, routes)?;
+    if debug {
+        println!("RIB search results: {:?}", results);
+    }
+    let signature = sign_data(&results, private_key)?;
+    let response = RibSearchResponse { results, signature };
+    create_reply(
+        packet,
+        GdpAction::RibSearchReply,
+        &bincode::serialize(&response)?,
+    )
+}
+
+pub fn verify_rib_search_reply(packet: &Gdp<DTls<Ipv4>>, rib_meta: &GdpMeta) -> Result<()> {
+    let response: RibSearchResponse = bincode::deserialize(get_payload(packet)?)?;
+    verify_data(&response.results, response.signature, rib_meta)
+}
@@ -1,15 +1,154 @@
 use std::fmt;
+use std::net::IpAddr;
 use std::ptr::NonNull;
+use std::sync::Arc;
 
 use aes_gcm::aead::{Aead, NewAead};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
 use capsule::packets::ip::IpPacket;
-use capsule::packets::{Internal, Packet, Udp};
+use capsule::packets::{Ethernet, Internal, Packet, Udp};
 use capsule::{debug, SizeOf};
-use rand::Rng;
+use chacha20poly1305::ChaCha20Poly1305;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use self::padding::{padding, unpad};
+use self::replay::{is_control_nonce, set_control_class, static_nonces};
+use self::session::{sessions, Session};
+use self::transport::transports;
+use crate::priority::is_control_payload;
+use crate::txbatch::{udp_checksums, UdpChecksums};
+
+pub mod padding;
+pub mod replay;
+pub mod session;
+pub mod transport;
+
+/// Packets encrypted with the static key, rather than a session's
+pub const STATIC_SESSION: u32 = 0;
+/// Packets that carry a handshake message, in the clear
+pub const HANDSHAKE_SESSION: u32 = u32::MAX;
+/// Packets from plaintext peers, whose dTLS header we added ourselves
+pub const PLAINTEXT_SESSION: u32 = u32::MAX - 1;
+
+const DEFAULT_KEY: &[u8; 32] = b"an example very very secret key.";
+/// Length of the tag appended to every encrypted payload, whatever the cipher suite
+const TAG_LEN: usize = 16;
+/// Where the length of a UDP header sits, just before its checksum
+const UDP_LENGTH_OFFSET: usize = 4;
+
+/// The AEADs that payloads can be encrypted with. Sessions negotiate one in their handshake
+/// (see session.rs); the static key is always used with AES-256-GCM, which every node has.
+/// Numbered as in the cipher suites that heartbeats advertise.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CipherSuite {
+    #[serde(rename = "aes_256_gcm")]
+    Aes256Gcm = 0,
+    /// For targets without AES instructions
+    #[serde(rename = "chacha20_poly1305")]
+    ChaCha20Poly1305 = 1,
+    /// No encryption or authentication at all, for benchmarking: unlike turning the crypto flag
+    /// off, packets keep their dTLS header and a (zeroed) tag, so they are the size they would
+    /// be. Only used with peers that list it too
+    #[serde(rename = "null")]
+    Null = 2,
+}
+
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::Aes256Gcm,
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::Null,
+    ];
+
+    /// The suites set in a bitmap of the kind that heartbeats and handshakes carry
+    pub fn in_bitmap(bitmap: u16) -> impl Iterator<Item = CipherSuite> {
+        Self::ALL
+            .into_iter()
+            .filter(move |suite| bitmap & suite.bit() != 0)
+    }
+
+    pub fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A key for one of the cipher suites, dispatching to its AEAD
+pub enum Cipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+    Null,
+}
+
+impl Cipher {
+    pub fn new(suite: CipherSuite, key: &[u8; 32]) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => {
+                Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(Key::from_slice(key))))
+            }
+            CipherSuite::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(Box::new(
+                ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key)),
+            )),
+            CipherSuite::Null => Cipher::Null,
+        }
+    }
+
+    pub fn encrypt(&self, nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        let encrypted = match self {
+            Cipher::Aes256Gcm(cipher) => cipher.encrypt(nonce, data),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.encrypt(nonce, data),
+            Cipher::Null => {
+                let mut encrypted = Vec::with_capacity(data.len() + TAG_LEN);
+                encrypted.extend_from_slice(data);
+                encrypted.resize(data.len() + TAG_LEN, 0);
+                Ok(encrypted)
+            }
+        };
+        encrypted.map_err(|_| anyhow!("encrypt failed"))
+    }
+
+    pub fn decrypt(&self, nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(nonce);
+        let decrypted = match self {
+            Cipher::Aes256Gcm(cipher) => cipher.decrypt(nonce, data),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce, data),
+            Cipher::Null => {
+                ensure!(data.len() >= TAG_LEN, "payload shorter than its tag");
+                Ok(data[..data.len() - TAG_LEN].to_vec())
+            }
+        };
+        decrypted.map_err(|_| {
+            debug!("decrypt failed");
+            anyhow!("decrypt failed")
+        })
+    }
+}
 
-use crate::Ipv4;
+// set once at startup from the secrets file, before any pipeline runs
+static KEY: OnceCell<[u8; 32]> = OnceCell::new();
+
+pub fn set_key(key: [u8; 32]) -> Result<()> {
+    KEY.set(key)
+        .map_err(|_| anyhow!("the static key was already set"))
+}
+
+/// The static key, which also keys the handshakes; only ever logged as a fingerprint
+#[inline]
+pub fn key() -> &'static [u8; 32] {
+    KEY.get().unwrap_or(DEFAULT_KEY)
+}
+
+/// Bytes the dTLS layer adds to a GDP packet: its header, and the tag on the payload
+pub fn dtls_overhead() -> usize {
+    DTlsHeader::size_of() + TAG_LEN
+}
+
+/// Whether the built-in example key is in use, because no key was set
+pub fn using_default_key() -> bool {
+    KEY.get().is_none()
+}
 
 pub struct DTls<T: IpPacket> {
     envelope: Udp<T>,
@@ -20,7 +159,8 @@ pub struct DTls<T: IpPacket> {
 #[derive(Clone, Copy, Debug, Default, SizeOf)]
 #[repr(C)]
 struct DTlsHeader {
-    nonce: [u8; 12], // 96-bit nonce used to decrypt the payload
+    session: [u8; 4], // the session whose keys encrypt the payload, big-endian
+    nonce: [u8; 12],  // a sender prefix and a counter, see replay.rs
 }
 
 impl<T: IpPacket> DTls<T> {
@@ -34,6 +174,16 @@ impl<T: IpPacket> DTls<T> {
         unsafe { self.header.as_mut() }
     }
 
+    #[inline]
+    pub fn session(&self) -> u32 {
+        u32::from_be_bytes(self.header().session)
+    }
+
+    #[inline]
+    pub fn set_session(&mut self, session: u32) {
+        self.header_mut().session = session.to_be_bytes();
+    }
+
     #[inline]
     pub fn nonce(&self) -> [u8; 12] {
         self.header().nonce
@@ -48,6 +198,7 @@ impl<T: IpPacket> DTls<T> {
 impl<T: IpPacket> fmt::Debug for DTls<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("dtls")
+            .field("session", &self.session())
             .field("nonce", &self.nonce())
             .finish()
     }
@@ -121,24 +272,26 @@ impl<T: IpPacket> Packet for DTls<T> {
     }
 }
 
-pub fn decrypt_gdp(mut dtls_packet: DTls<Ipv4>) -> Result<DTls<Ipv4>> {
-    let key = Key::from_slice(b"an example very very secret key.");
-    let cipher = Aes256Gcm::new(key);
+fn decrypt_payload(nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>> {
+    // 96-bit nonces, unique per message
+    Cipher::new(CipherSuite::Aes256Gcm, key()).decrypt(nonce, data)
+}
 
-    let raw_nonce = dtls_packet.nonce();
-    let nonce = Nonce::from_slice(&raw_nonce); // 96-bits; unique per message
+fn encrypt_payload(nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>> {
+    Cipher::new(CipherSuite::Aes256Gcm, key()).encrypt(nonce, data)
+}
 
-    // decrypt the packet
+pub fn read_payload<T: IpPacket>(dtls_packet: &DTls<T>) -> Result<&[u8]> {
     let data_slice = dtls_packet
         .mbuf()
         .read_data_slice(dtls_packet.payload_offset(), dtls_packet.payload_len())?;
-    let data_slice_ref = unsafe { data_slice.as_ref() };
-
-    let decrypted = cipher.decrypt(nonce, data_slice_ref).map_err(|_| {
-        debug!("decrypt failed");
-        anyhow!("decrypt failed")
-    })?;
+    Ok(unsafe { data_slice.as_ref() })
+}
 
+/// Replace the payload with its plaintext. The envelopes are left unreconciled: decrypted packets
+/// only travel through our pipelines, and are reconciled once on their way out (after encryption,
+/// or when the dTLS layer is stripped), rather than walking every envelope twice per packet.
+pub fn write_decrypted<T: IpPacket>(mut dtls_packet: DTls<T>, decrypted: &[u8]) -> Result<DTls<T>> {
     // AES generally adds padding. To prevent buffer size creep we must truncate.
     let payload_offset = dtls_packet.payload_offset();
     let decrypted_len = decrypted.len();
@@ -149,45 +302,170 @@ pub fn decrypt_gdp(mut dtls_packet: DTls<Ipv4>) -> Result<DTls<Ipv4>> {
     let write_offset = dtls_packet.payload_offset();
     dtls_packet
         .mbuf_mut()
-        .write_data_slice(write_offset, &decrypted)?;
-
-    dtls_packet.reconcile_all();
+        .write_data_slice(write_offset, decrypted)?;
 
     Ok(dtls_packet)
 }
 
-pub fn encrypt_gdp(mut dtls_packet: DTls<Ipv4>) -> Result<DTls<Ipv4>> {
-    let key = Key::from_slice(b"an example very very secret key.");
-    let cipher = Aes256Gcm::new(key);
-
-    let nonce = rand::thread_rng().gen::<[u8; 12]>(); // 96-bits; unique per message
-    dtls_packet.set_nonce(nonce);
-    let nonce = Nonce::from_slice(&nonce);
-
-    // encrypt the packet
-    let data_slice = dtls_packet
-        .mbuf()
-        .read_data_slice(dtls_packet.payload_offset(), dtls_packet.payload_len())?;
-    let data_slice_ref = unsafe { data_slice.as_ref() };
-
-    let encrypted = cipher.encrypt(nonce, data_slice_ref).map_err(|_| {
-        debug!("encrypt failed");
-        anyhow!("encrypt failed")
-    })?;
-
-    // rewrite the mbuf with the encrypted packlet
-    // AES usually adds a few bytes of padding
-    let length_delta = encrypted.len() - dtls_packet.payload_len();
-    let end_offset = dtls_packet.payload_offset() + dtls_packet.payload_len();
-    if length_delta > 0 {
+pub fn write_encrypted<T: IpPacket>(mut dtls_packet: DTls<T>, encrypted: &[u8]) -> Result<DTls<T>> {
+    // rewrite the mbuf with the encrypted packet, which the tag makes longer than the plaintext
+    let payload_offset = dtls_packet.payload_offset();
+    let end_offset = payload_offset + dtls_packet.payload_len();
+    if encrypted.len() > dtls_packet.payload_len() {
+        let length_delta = encrypted.len() - dtls_packet.payload_len();
         dtls_packet.mbuf_mut().extend(end_offset, length_delta)?;
+    } else {
+        dtls_packet
+            .mbuf_mut()
+            .truncate(payload_offset + encrypted.len())?;
     }
 
     let write_offset = dtls_packet.payload_offset();
     dtls_packet
         .mbuf_mut()
-        .write_data_slice(write_offset, &encrypted)?;
+        .write_data_slice(write_offset, encrypted)?;
 
-    dtls_packet.reconcile_all();
+    // the only place a forwarded packet's checksums are computed, in software, as capsule cannot
+    // offload them to the NIC (see UdpChecksums)
+    match udp_checksums() {
+        UdpChecksums::Always => dtls_packet.reconcile_all(),
+        UdpChecksums::Plaintext => reconcile_without_checksum(dtls_packet.envelope_mut())?,
+    }
     Ok(dtls_packet)
 }
+
+/// Reconcile `udp` and its envelopes as `reconcile_all` does, but leave the datagram without a
+/// checksum rather than sum it all over again, for a payload that its tag covers already
+fn reconcile_without_checksum<T: IpPacket>(udp: &mut Udp<T>) -> Result<()> {
+    let (offset, len) = (udp.offset(), udp.len());
+    let mut length_and_checksum = [0; 4];
+    length_and_checksum[..2].copy_from_slice(&(len as u16).to_be_bytes());
+    udp.mbuf_mut()
+        .write_data_slice(offset + UDP_LENGTH_OFFSET, &length_and_checksum)?;
+    udp.envelope_mut().reconcile_all();
+    Ok(())
+}
+
+/// The key and nonce for one packet's payload. They are picked on the polling core, which
+/// knows the packet's peer, so that the AES itself can run anywhere.
+pub struct PacketKey {
+    session: Option<Arc<Session>>,
+    nonce: [u8; 12],
+    /// Where an incoming packet came from, or an outgoing one goes, for errors
+    peer: IpAddr,
+}
+
+impl PacketKey {
+    /// Pick the key for an outgoing packet and a fresh nonce, and note both in its header
+    pub fn outgoing<T: IpPacket<Envelope = Ethernet>>(dtls_packet: &mut DTls<T>) -> Result<Self> {
+        let session = sessions().outgoing(dtls_packet)?;
+        let mut nonce = match &session {
+            Some(session) => session.next_nonce()?,
+            None => static_nonces().next_nonce(),
+        };
+        if read_payload(dtls_packet).map_or(false, is_control_payload) {
+            set_control_class(&mut nonce);
+        }
+        dtls_packet.set_session(
+            session
+                .as_ref()
+                .map_or(STATIC_SESSION, |session| session.id()),
+        );
+        dtls_packet.set_nonce(nonce);
+        Ok(PacketKey {
+            session,
+            nonce,
+            peer: dtls_packet.envelope().envelope().dst(),
+        })
+    }
+
+    /// The key that an incoming packet's header says it was encrypted with
+    pub fn incoming<T: IpPacket>(dtls_packet: &DTls<T>) -> Result<Self> {
+        let session = match dtls_packet.session() {
+            STATIC_SESSION => {
+                sessions().check_static(dtls_packet)?;
+                None
+            }
+            HANDSHAKE_SESSION => return Err(anyhow!("handshake messages are not encrypted")),
+            id => Some(sessions().incoming(dtls_packet, id)?),
+        };
+        Ok(PacketKey {
+            session,
+            nonce: dtls_packet.nonce(),
+            peer: dtls_packet.envelope().envelope().src(),
+        })
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let padded = padding().pad(data);
+        let data = padded.as_deref().unwrap_or(data);
+        match &self.session {
+            Some(session) => session.encrypt(&self.nonce, data),
+            None => encrypt_payload(&self.nonce, data),
+        }
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let decrypted = match &self.session {
+            Some(session) => session.decrypt(&self.nonce, data)?,
+            None => {
+                let decrypted = decrypt_payload(&self.nonce, data)?;
+                static_nonces().accept(self.peer, &self.nonce)?;
+                decrypted
+            }
+        };
+        let decrypted = unpad(decrypted)?;
+        ensure!(
+            !is_control_nonce(&self.nonce) || is_control_payload(&decrypted),
+            "data packet from {} classed as control traffic",
+            self.peer
+        );
+        Ok(decrypted)
+    }
+}
+
+/// Whether a packet travels without encryption, to or from a plaintext peer
+pub fn in_clear<T: IpPacket>(dtls_packet: &DTls<T>, outgoing: bool) -> bool {
+    if outgoing {
+        transports().is_plaintext(dtls_packet.envelope().envelope().dst())
+    } else {
+        dtls_packet.session() == PLAINTEXT_SESSION
+    }
+}
+
+/// Whether an incoming packet came over a session negotiated with its sender, rather than under
+/// the static key or in the clear. Decrypting it checked the session, so its id says as much
+pub fn over_session<T: IpPacket>(dtls_packet: &DTls<T>) -> bool {
+    !matches!(
+        dtls_packet.session(),
+        STATIC_SESSION | HANDSHAKE_SESSION | PLAINTEXT_SESSION
+    )
+}
+
+/// Drop the dTLS header of a packet to a plaintext peer. The packet keeps its type, so that it
+/// shares the send stages with every other packet, but its dTLS header must not be read again.
+fn strip_dtls<T: IpPacket>(dtls_packet: DTls<T>) -> Result<DTls<T>> {
+    let mut udp = dtls_packet.remove()?;
+    udp.reconcile_all();
+    udp.parse::<DTls<T>>()
+}
+
+pub fn decrypt_gdp<T: IpPacket>(dtls_packet: DTls<T>) -> Result<DTls<T>> {
+    if in_clear(&dtls_packet, false) {
+        // the peer may have written the marker itself, to get past decryption
+        transports().check_plaintext(dtls_packet.envelope().envelope().src())?;
+        return Ok(dtls_packet);
+    }
+    let key = PacketKey::incoming(&dtls_packet)?;
+    let decrypted = key.decrypt(read_payload(&dtls_packet)?)?;
+    write_decrypted(dtls_packet, &decrypted)
+}
+
+pub fn encrypt_gdp<T: IpPacket<Envelope = Ethernet>>(mut dtls_packet: DTls<T>) -> Result<DTls<T>> {
+    if in_clear(&dtls_packet, true) {
+        return strip_dtls(dtls_packet);
+    }
+    let key = PacketKey::outgoing(&mut dtls_packet)?;
+    let encrypted = key.encrypt(read_payload(&dtls_packet)?)?;
+    write_encrypted(dtls_packet, &encrypted)
+}
@@ -0,0 +1,253 @@
+use std::fs;
+
+use anyhow::{ensure, Result};
+use capsule::{metrics, SizeOf};
+use gdp_proto::{GdpAction, GdpHeader};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use super::{DTlsHeader, TAG_LEN};
+use crate::priority::is_control;
+
+/*
+   Where the size of a packet would give away what it carries, encrypted payloads can be padded
+   up to a few fixed sizes:
+   - padding.toml lists the sizes (`buckets`, of the encrypted payload with its tag), and which
+     classes of traffic are padded: control packets (see priority.rs), data packets, or both
+   - a padded plaintext starts with PADDING_MARKER and the length of the padding, which follows
+     the packet as zeros. Both are encrypted along with the packet, so they cannot be tampered
+     with, and the dTLS header is the same as for any other packet
+   - receivers strip padding whatever their own padding.toml says, so that it can be turned on
+     one sender at a time. Nodes that predate padding drop padded packets, as they are not GDP
+   - packets too large for every bucket are sent as they are
+   - the padding added, and the payload it was added to, are counted by class, so that what it
+     costs in bandwidth shows in the metrics
+*/
+
+/// Starts padded plaintexts, which GDP packets (starting with their magic number) never do
+const PADDING_MARKER: u16 = 0xa5a5;
+/// The marker and the length of the padding
+const PADDING_HEADER_LEN: usize = 4;
+
+#[derive(Deserialize)]
+pub struct PaddingConfig {
+    /// Sizes that encrypted payloads are rounded up to, tag included
+    #[serde(default = "default_buckets")]
+    pub buckets: Vec<usize>,
+    /// Whether control packets are padded
+    #[serde(default)]
+    pub control: bool,
+    /// Whether data packets are padded
+    #[serde(default)]
+    pub data: bool,
+}
+
+/// The largest fills a 1500-byte MTU once the IP, UDP and dTLS headers are added
+fn default_buckets() -> Vec<usize> {
+    vec![128, 512, 1456]
+}
+
+impl Default for PaddingConfig {
+    fn default() -> Self {
+        PaddingConfig {
+            buckets: default_buckets(),
+            control: false,
+            data: false,
+        }
+    }
+}
+
+impl PaddingConfig {
+    /// The size of the largest dTLS record that padding produces
+    pub fn largest_record(&self) -> usize {
+        self.buckets
+            .last()
+            .map_or(0, |bucket| DTlsHeader::size_of() + bucket)
+    }
+}
+
+pub fn load_padding_config() -> Result<PaddingConfig> {
+    let content = fs::read_to_string("padding.toml")?;
+    let mut config: PaddingConfig = toml::from_str(&content)?;
+    for &bucket in &config.buckets {
+        ensure!(
+            bucket <= u16::MAX as usize,
+            "bucket {} is larger than any packet",
+            bucket
+        );
+    }
+    config.buckets.sort_unstable();
+    Ok(config)
+}
+
+/// What padding costs one class of traffic
+struct ClassCounters {
+    payload: Counter,
+    padding: Counter,
+}
+
+impl ClassCounters {
+    fn new(class: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter = |kind: &'static str| {
+            sink.counter_with_labels("padding.bytes", vec![("class", class), ("kind", kind)])
+        };
+        ClassCounters {
+            payload: counter("payload"),
+            padding: counter("padding"),
+        }
+    }
+}
+
+pub struct Padding {
+    config: PaddingConfig,
+    control: ClassCounters,
+    data: ClassCounters,
+}
+
+// created on first use, shared by every pipeline and crypto worker that encrypts
+static PADDING: Lazy<Padding> = Lazy::new(|| Padding {
+    config: load_padding_config().unwrap_or_default(),
+    control: ClassCounters::new("control"),
+    data: ClassCounters::new("data"),
+});
+
+pub fn padding() -> &'static Padding {
+    &PADDING
+}
+
+impl Padding {
+    /// The plaintext of a GDP packet padded to the smallest bucket that fits it, or None if its
+    /// class is not padded or it fits none
+    pub fn pad(&self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let (header, _) = GdpHeader::parse(plaintext).ok()?;
+        let (enabled, counters) = if is_control(GdpAction::try_from(header.action).ok()?) {
+            (self.config.control, &self.control)
+        } else {
+            (self.config.data, &self.data)
+        };
+        if !enabled {
+            return None;
+        }
+        let unpadded = PADDING_HEADER_LEN + plaintext.len() + TAG_LEN;
+        let bucket = *self
+            .config
+            .buckets
+            .iter()
+            .find(|&&bucket| bucket >= unpadded)?;
+        let padding_len = bucket - unpadded;
+
+        let mut padded = Vec::with_capacity(bucket - TAG_LEN);
+        padded.extend_from_slice(&PADDING_MARKER.to_be_bytes());
+        padded.extend_from_slice(&(padding_len as u16).to_be_bytes());
+        padded.extend_from_slice(plaintext);
+        padded.resize(bucket - TAG_LEN, 0);
+        counters.payload.record(plaintext.len() as u64);
+        counters
+            .padding
+            .record((PADDING_HEADER_LEN + padding_len) as u64);
+        Some(padded)
+    }
+}
+
+/// Strip the padding from a decrypted payload, if it was padded
+pub fn unpad(mut plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    if plaintext.len() < 2 || plaintext[..2] != PADDING_MARKER.to_be_bytes() {
+        return Ok(plaintext);
+    }
+    ensure!(
+        plaintext.len() >= PADDING_HEADER_LEN,
+        "padded payload is too short for its padding header"
+    );
+    let padding_len = u16::from_be_bytes([plaintext[2], plaintext[3]]) as usize;
+    ensure!(
+        PADDING_HEADER_LEN + padding_len <= plaintext.len(),
+        "padding of {} bytes is longer than the {}-byte payload",
+        padding_len,
+        plaintext.len()
+    );
+    plaintext.truncate(plaintext.len() - padding_len);
+    plaintext.drain(..PADDING_HEADER_LEN);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use std::slice;
+
+    use gdp_proto::MAGIC_NUMBERS;
+
+    use super::*;
+
+    fn padding(control: bool, data: bool) -> Padding {
+        Padding {
+            config: PaddingConfig {
+                buckets: default_buckets(),
+                control,
+                data,
+            },
+            control: ClassCounters::new("control"),
+            data: ClassCounters::new("data"),
+        }
+    }
+
+    /// A GDP packet with `data_len` bytes of data
+    fn plaintext(action: GdpAction, data_len: usize) -> Vec<u8> {
+        let header = GdpHeader {
+            field: MAGIC_NUMBERS.into(),
+            action: action as u8,
+            data_len: (data_len as u16).into(),
+            ..Default::default()
+        };
+        let header = unsafe {
+            slice::from_raw_parts(&header as *const _ as *const u8, size_of::<GdpHeader>())
+        };
+        [header, &vec![7; data_len]].concat()
+    }
+
+    #[capsule::test]
+    fn pads_to_the_smallest_bucket_that_fits() {
+        let padding = padding(false, true);
+        for (data_len, bucket) in [(10, 128), (200, 512), (1000, 1456)] {
+            let plaintext = plaintext(GdpAction::Forward, data_len);
+            let padded = padding.pad(&plaintext).unwrap();
+            assert_eq!(padded.len() + TAG_LEN, bucket);
+            assert_eq!(unpad(padded).unwrap(), plaintext);
+        }
+    }
+
+    #[capsule::test]
+    fn pads_only_the_configured_classes() {
+        let padding = padding(true, false);
+        assert!(padding.pad(&plaintext(GdpAction::Forward, 10)).is_none());
+        assert!(padding.pad(&plaintext(GdpAction::RibGet, 10)).is_some());
+    }
+
+    #[capsule::test]
+    fn sends_packets_larger_than_every_bucket_as_they_are() {
+        let padding = padding(true, true);
+        assert!(padding.pad(&plaintext(GdpAction::Forward, 1456)).is_none());
+        // nor does it pad what is not GDP
+        assert!(padding.pad(&[0; 64]).is_none());
+    }
+
+    #[test]
+    fn leaves_unpadded_payloads_alone() {
+        let plaintext = plaintext(GdpAction::Forward, 10);
+        assert_eq!(unpad(plaintext.clone()).unwrap(), plaintext);
+        assert!(unpad(vec![]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_padding_longer_than_the_payload() {
+        let mut padded = PADDING_MARKER.to_be_bytes().to_vec();
+        assert!(unpad(padded.clone()).is_err());
+        padded.extend_from_slice(&5u16.to_be_bytes());
+        padded.extend_from_slice(&[0; 4]);
+        assert!(unpad(padded.clone()).is_err());
+        padded.push(0);
+        assert!(unpad(padded).unwrap().is_empty());
+    }
+}
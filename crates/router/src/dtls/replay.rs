@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use once_cell::sync::Lazy;
+use rand::Rng;
+
+use crate::isolation::Recover;
+
+/*
+   Nonces are never reused under a key, and packets are only accepted once:
+   - every nonce is a 4-byte prefix followed by an 8-byte counter. Under a session's keys, the
+     prefix is the salt of the direction; under the static key, it is a sender id that each
+     process picks at random when it starts, so that nodes sharing the key do not collide
+   - the top bit of the counter is the sender's class of the packet, set for control traffic, so
+     that receivers can police control traffic before decrypting it (see priority.rs). It is not
+     part of the counter that replay windows keep
+   - receivers keep a window of the last REPLAY_WINDOW counters from each sender, and drop
+     packets whose counter was seen before or is older than the window. Counters are only
+     recorded once the packet has been authenticated, so forgeries cannot use them up
+   - session windows are kept with their session. Static-key windows are kept per sender id:
+     the nonce is authenticated along with the packet, while the IP address it comes from is
+     not, so a window per address would take a replay from another address for a new sender.
+     The table is shared by every core, as a packet may be decrypted on any of them and a
+     per-core store would let a replay through on a core that has not seen the original. It is
+     split into STATIC_SHARDS by sender id, so that cores decrypting packets from different
+     senders rarely meet on a lock. Idle windows are forgotten after STATIC_WINDOW_IDLE
+*/
+
+/// How far behind the newest counter a packet may arrive and still be accepted
+const REPLAY_WINDOW: u64 = 128;
+/// Static-key senders we have not heard from for this long are forgotten
+const STATIC_WINDOW_IDLE: Duration = Duration::from_secs(600);
+/// Locks that the static-key windows are split across
+const STATIC_SHARDS: usize = 16;
+
+/// The bit of the counter that classes a packet as control traffic
+const CONTROL_CLASS: u64 = 1 << 63;
+
+pub fn make_nonce(prefix: [u8; 4], counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..4].copy_from_slice(&prefix);
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+pub fn split_nonce(nonce: &[u8; 12]) -> ([u8; 4], u64) {
+    let mut prefix = [0; 4];
+    let mut counter = [0; 8];
+    prefix.copy_from_slice(&nonce[..4]);
+    counter.copy_from_slice(&nonce[4..]);
+    (prefix, u64::from_be_bytes(counter) & !CONTROL_CLASS)
+}
+
+/// Class the packet sent with `nonce` as control traffic
+pub fn set_control_class(nonce: &mut [u8; 12]) {
+    nonce[4] |= (CONTROL_CLASS >> 56) as u8;
+}
+
+pub fn is_control_nonce(nonce: &[u8; 12]) -> bool {
+    nonce[4] & (CONTROL_CLASS >> 56) as u8 != 0
+}
+
+/// Seen counters, as a bitmap of the REPLAY_WINDOW counters up to the newest one
+#[derive(Default)]
+pub struct ReplayWindow {
+    newest: u64,
+    seen: u128,
+}
+
+impl ReplayWindow {
+    /// Record `counter`, false if it was seen before or is too old to tell
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if counter > self.newest {
+            let shift = counter - self.newest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.newest = counter;
+            return true;
+        }
+        let age = self.newest - counter;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+struct StaticWindow {
+    window: ReplayWindow,
+    last_heard: Instant,
+}
+
+/// The windows of the static-key senders whose ids fall in one shard
+type StaticShard = RwLock<HashMap<[u8; 4], Mutex<StaticWindow>>>;
+
+pub struct StaticNonces {
+    sender: [u8; 4],
+    /// Counter of the last nonce we sent; the first packet uses 1
+    sent: AtomicU64,
+    shards: Vec<StaticShard>,
+}
+
+// shared by every pipeline that encrypts or decrypts
+static STATIC_NONCES: Lazy<StaticNonces> = Lazy::new(StaticNonces::new);
+
+pub fn static_nonces() -> &'static StaticNonces {
+    &STATIC_NONCES
+}
+
+impl StaticNonces {
+    fn new() -> Self {
+        StaticNonces {
+            sender: rand::thread_rng().gen(),
+            sent: AtomicU64::new(0),
+            shards: (0..STATIC_SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+
+    /// The nonce for the next packet we send under the static key
+    pub fn next_nonce(&self) -> [u8; 12] {
+        make_nonce(self.sender, self.sent.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Record the nonce of an authenticated packet, unless it was seen before from any address.
+    /// `peer` is where the packet came from, for the error only.
+    pub fn accept(&self, peer: IpAddr, nonce: &[u8; 12]) -> Result<()> {
+        let (sender, counter) = split_nonce(nonce);
+        // sender ids are picked at random, so any of their bytes spreads them evenly
+        let shard = &self.shards[sender[0] as usize % STATIC_SHARDS];
+        let accepted = {
+            let windows = shard.read().recover();
+            windows.get(&sender).map(|window| {
+                let mut window = window.lock().recover();
+                window.last_heard = Instant::now();
+                window.window.accept(counter)
+            })
+        };
+        let accepted = match accepted {
+            Some(accepted) => accepted,
+            None => {
+                let mut windows = shard.write().recover();
+                windows.retain(|_, window| {
+                    window.get_mut().recover().last_heard.elapsed() < STATIC_WINDOW_IDLE
+                });
+                let window = windows.entry(sender).or_insert_with(|| {
+                    Mutex::new(StaticWindow {
+                        window: ReplayWindow::default(),
+                        last_heard: Instant::now(),
+                    })
+                });
+                window.get_mut().recover().window.accept(counter)
+            }
+        };
+        ensure!(accepted, "replayed packet from {}", peer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 100, 1, last))
+    }
+
+    #[test]
+    fn windows_take_each_counter_once() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(1));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        // late, but within the window
+        assert!(window.accept(2));
+        assert!(!window.accept(1));
+    }
+
+    #[test]
+    fn windows_refuse_counters_too_old_to_tell() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(REPLAY_WINDOW + 10));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+        // a jump past the window forgets what was seen
+        assert!(window.accept(10 * REPLAY_WINDOW));
+        assert!(!window.accept(REPLAY_WINDOW + 10));
+    }
+
+    #[test]
+    fn the_control_class_is_not_part_of_the_counter() {
+        let mut nonce = make_nonce([1, 2, 3, 4], 42);
+        set_control_class(&mut nonce);
+        assert!(is_control_nonce(&nonce));
+        assert_eq!(split_nonce(&nonce), ([1, 2, 3, 4], 42));
+        assert!(!is_control_nonce(&make_nonce([1, 2, 3, 4], 42)));
+    }
+
+    #[test]
+    fn static_replays_are_refused_from_any_address() {
+        let nonces = StaticNonces::new();
+        let nonce = make_nonce([7; 4], 1);
+        nonces.accept(peer(1), &nonce).unwrap();
+        assert!(nonces.accept(peer(1), &nonce).is_err());
+        assert!(nonces.accept(peer(2), &nonce).is_err());
+        // the class bit does not make a replay new
+        let mut control = nonce;
+        set_control_class(&mut control);
+        assert!(nonces.accept(peer(1), &control).is_err());
+    }
+
+    #[test]
+    fn static_senders_have_windows_of_their_own() {
+        let nonces = StaticNonces::new();
+        for sender in 0..=u8::MAX {
+            nonces.accept(peer(1), &make_nonce([sender; 4], 1)).unwrap();
+        }
+        nonces.accept(peer(1), &make_nonce([0; 4], 2)).unwrap();
+        assert!(nonces.accept(peer(1), &make_nonce([1; 4], 1)).is_err());
+    }
+
+    #[test]
+    fn our_nonces_count_up_from_one() {
+        let nonces = StaticNonces::new();
+        let first = split_nonce(&nonces.next_nonce());
+        let second = split_nonce(&nonces.next_nonce());
+        assert_eq!(first, (nonces.sender, 1));
+        assert_eq!(second, (nonces.sender, 2));
+    }
+}
@@ -0,0 +1,1293 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::net::MacAddr;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::ip::v6::Ipv6;
+use capsule::packets::ip::IpPacket;
+use capsule::packets::{Ethernet, Packet, Udp};
+use capsule::{Mbuf, PortQueue};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio_timer::delay_for;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::replay::{make_nonce, split_nonce, ReplayWindow};
+use super::{key, read_payload, Cipher, CipherSuite, DTls, HANDSHAKE_SESSION, STATIC_SESSION};
+use crate::isolation::Recover;
+use crate::l2filter::VlanTx;
+use crate::schedule::Schedule;
+
+/*
+   Per-peer keys for the dTLS layer, negotiated with a PSK-authenticated X25519 handshake:
+   - the first packet to a peer without a session makes us start a handshake: the initiator sends
+     a fresh ephemeral key and a timestamp, and the responder answers with an ephemeral key of its
+     own. Both messages are MACed with the pre-shared key (the dtls_key secret), so only nodes
+     holding it can take part, and handshake messages travel in the clear
+   - both ends derive a key and a nonce salt for each direction from the X25519 shared secret
+     with HKDF, salted with the pre-shared key. Ephemeral keys are never reused, so recording
+     traffic and later learning the pre-shared key does not reveal it
+   - the nonce of each packet is its direction's salt followed by a per-session counter, so
+     nonces never repeat under a key, and receivers drop replays (see replay.rs)
+   - sessions are keyed by the peer's IP address and UDP port, and our own (nodes of the dev
+     topology share a process). The responder only sends with a new session once it has
+     received a packet on it, which shows that the initiator has the keys too
+   - after REKEY_AFTER, the next packet sent starts a new handshake; the previous session keeps
+     decrypting packets in flight until it is REJECT_AFTER old
+   - until a session is up, packets use the static key, unless `require_sessions` is set in
+     sessions.toml, in which case they are dropped (and so are packets that use the static key)
+   - the Init offers the cipher suites in sessions.toml, and the responder picks the first of
+     its own that was offered. An Init that only offers AES-256-GCM is sent as before cipher
+     suites, as nodes from then ignore the kind of Init that offers them. When such an Init
+     goes unanswered, the next is the old kind, if AES-256-GCM is acceptable at all
+*/
+
+/// Sessions are renegotiated once they are this old
+const REKEY_AFTER: Duration = Duration::from_secs(120);
+/// Sessions are not used at all once they are this old
+const REJECT_AFTER: Duration = Duration::from_secs(180);
+/// Sessions are renegotiated well before their counter could wrap
+const REJECT_AFTER_PACKETS: u64 = 1 << 60;
+/// How long the initiator waits for a response before trying again
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+/// How often the session schedule sends the handshakes that the pipelines asked for
+const HANDSHAKE_POLL: Duration = Duration::from_millis(50);
+/// Inits whose timestamp is further than this from our clock are ignored, so that a recorded
+/// Init cannot be replayed to a node that restarted and forgot the newest one it accepted
+const MAX_INIT_SKEW: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Deserialize)]
+pub struct SessionConfig {
+    /// Drop packets to and from peers that we have no session with, instead of using the static key
+    #[serde(default)]
+    pub require_sessions: bool,
+    /// The cipher suites that sessions may use, most preferred first
+    #[serde(default = "default_suites")]
+    pub suites: Vec<CipherSuite>,
+    /// Peers whose sessions may use other suites than `suites`
+    #[serde(default)]
+    pub peers: Vec<PeerSuites>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PeerSuites {
+    pub ip: IpAddr,
+    pub suites: Vec<CipherSuite>,
+}
+
+fn default_suites() -> Vec<CipherSuite> {
+    vec![CipherSuite::Aes256Gcm]
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            require_sessions: false,
+            suites: default_suites(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// The suites that sessions with `peer` may use, most preferred first
+    fn suites_for(&self, peer: IpAddr) -> &[CipherSuite] {
+        self.peers
+            .iter()
+            .find(|configured| configured.ip == peer)
+            .map_or(&self.suites, |configured| &configured.suites)
+    }
+}
+
+fn suite_bitmap(suites: &[CipherSuite]) -> u16 {
+    suites.iter().fold(0, |bitmap, suite| bitmap | suite.bit())
+}
+
+pub fn load_session_config() -> Result<SessionConfig> {
+    let content = fs::read_to_string("sessions.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Handshake {
+    Init {
+        session_id: u32,
+        ephemeral: [u8; 32],
+        /// Microseconds since the epoch, which only ever grow between Inits from one node
+        timestamp: u64,
+        mac: [u8; 32],
+    },
+    Response {
+        session_id: u32,
+        ephemeral: [u8; 32],
+        mac: [u8; 32],
+    },
+    /// An Init that offers the cipher suites in the bitmap `suites`
+    InitSuites {
+        session_id: u32,
+        ephemeral: [u8; 32],
+        timestamp: u64,
+        suites: u16,
+        mac: [u8; 32],
+    },
+    /// The Response to an InitSuites, with the suite picked from those offered
+    ResponseSuite {
+        session_id: u32,
+        ephemeral: [u8; 32],
+        suite: u16,
+        mac: [u8; 32],
+    },
+}
+
+fn handshake_mac(parts: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key()).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// The MAC of an Init, which also covers the suites it offers unless it is of the old kind
+fn init_mac(
+    session_id: u32,
+    ephemeral: &[u8; 32],
+    timestamp: u64,
+    suites: Option<u16>,
+) -> Hmac<Sha256> {
+    let suites = suites.map(u16::to_be_bytes);
+    handshake_mac(&[
+        b"init",
+        &session_id.to_be_bytes(),
+        ephemeral,
+        &timestamp.to_be_bytes(),
+        suites.as_ref().map_or(&[], |suites| &suites[..]),
+    ])
+}
+
+/// The MAC of a Response, which also covers the suite it picked unless it is of the old kind
+fn response_mac(
+    session_id: u32,
+    init_ephemeral: &[u8; 32],
+    ephemeral: &[u8; 32],
+    suite: Option<u16>,
+) -> Hmac<Sha256> {
+    let suite = suite.map(u16::to_be_bytes);
+    handshake_mac(&[
+        b"response",
+        &session_id.to_be_bytes(),
+        init_ephemeral,
+        ephemeral,
+        suite.as_ref().map_or(&[], |suite| &suite[..]),
+    ])
+}
+
+/// The keys for one direction of a session
+struct DirectionKeys {
+    cipher: Cipher,
+    salt: [u8; 4],
+}
+
+impl DirectionKeys {
+    fn new(suite: CipherSuite, okm: &[u8]) -> Self {
+        let mut key = [0; 32];
+        key.copy_from_slice(&okm[..32]);
+        let mut salt = [0; 4];
+        salt.copy_from_slice(&okm[32..36]);
+        DirectionKeys {
+            cipher: Cipher::new(suite, &key),
+            salt,
+        }
+    }
+}
+
+pub struct Session {
+    id: u32,
+    established: Instant,
+    send: DirectionKeys,
+    recv: DirectionKeys,
+    /// Counter of the last nonce we sent; the first packet uses 1
+    sent: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl Session {
+    fn derive(
+        id: u32,
+        suite: CipherSuite,
+        shared: &[u8; 32],
+        init_ephemeral: &[u8; 32],
+        resp_ephemeral: &[u8; 32],
+        initiator: bool,
+        now: Instant,
+    ) -> Result<Self> {
+        // a peer that sent a low-order point would leave the shared secret all zeroes
+        ensure!(shared != &[0; 32], "degenerate key exchange");
+        let mut info = b"gdp dtls session".to_vec();
+        info.extend_from_slice(&id.to_be_bytes());
+        info.extend_from_slice(init_ephemeral);
+        info.extend_from_slice(resp_ephemeral);
+        let mut okm = [0; 72];
+        Hkdf::<Sha256>::new(Some(key()), shared)
+            .expand(&info, &mut okm)
+            .map_err(|_| anyhow!("key derivation failed"))?;
+
+        let (to_responder, to_initiator) = okm.split_at(36);
+        let (send, recv) = if initiator {
+            (to_responder, to_initiator)
+        } else {
+            (to_initiator, to_responder)
+        };
+        Ok(Session {
+            id,
+            established: now,
+            send: DirectionKeys::new(suite, send),
+            recv: DirectionKeys::new(suite, recv),
+            sent: AtomicU64::new(0),
+            replay: Mutex::new(ReplayWindow::default()),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn usable(&self, now: Instant) -> bool {
+        now.duration_since(self.established) < REJECT_AFTER
+    }
+
+    /// The nonce for the next packet sent on this session
+    pub fn next_nonce(&self) -> Result<[u8; 12]> {
+        let counter = self.sent.fetch_add(1, Ordering::Relaxed) + 1;
+        ensure!(
+            counter < REJECT_AFTER_PACKETS,
+            "session {} is used up",
+            self.id
+        );
+        Ok(make_nonce(self.send.salt, counter))
+    }
+
+    pub fn encrypt(&self, nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>> {
+        self.send.cipher.encrypt(nonce, data)
+    }
+
+    /// Decrypt a packet received on this session, unless it replays one we have already seen
+    pub fn decrypt(&self, nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>> {
+        let (salt, counter) = split_nonce(nonce);
+        ensure!(salt == self.recv.salt, "nonce from another session");
+        let decrypted = self.recv.cipher.decrypt(nonce, data)?;
+        // only once the packet is known to be genuine, so forgeries cannot use up counters
+        ensure!(
+            self.replay.lock().recover().accept(counter),
+            "replayed packet"
+        );
+        Ok(decrypted)
+    }
+}
+
+/// Our address and the peer's, as seen in the packets we exchange with it
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct PeerKey {
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl PeerKey {
+    fn outgoing<T: IpPacket>(packet: &DTls<T>) -> Self {
+        let udp = packet.envelope();
+        PeerKey {
+            local: SocketAddr::new(udp.envelope().src(), udp.src_port()),
+            peer: SocketAddr::new(udp.envelope().dst(), udp.dst_port()),
+        }
+    }
+
+    fn incoming<T: IpPacket>(packet: &DTls<T>) -> Self {
+        let udp = packet.envelope();
+        PeerKey {
+            local: SocketAddr::new(udp.envelope().dst(), udp.dst_port()),
+            peer: SocketAddr::new(udp.envelope().src(), udp.src_port()),
+        }
+    }
+
+    /// When both ends start a handshake at once, the one with the lower address gives way
+    fn gives_way(&self) -> bool {
+        (self.local.ip(), self.local.port()) < (self.peer.ip(), self.peer.port())
+    }
+}
+
+struct PendingInit {
+    session_id: u32,
+    secret: StaticSecret,
+    ephemeral: [u8; 32],
+    /// The suites offered, or None for an Init of the old kind
+    suites: Option<u16>,
+    sent: Instant,
+}
+
+#[derive(Default)]
+struct Peer {
+    current: Option<Arc<Session>>,
+    /// The session that `current` replaced, for packets that were in flight
+    previous: Option<Arc<Session>>,
+    /// A session we responded to, until the initiator uses it
+    next: Option<Arc<Session>>,
+    pending: Option<PendingInit>,
+    /// The timestamp of the newest Init we accepted from the peer
+    last_init: u64,
+}
+
+impl Peer {
+    fn install(&mut self, session: Session) {
+        self.previous = self.current.take();
+        self.current = Some(Arc::new(session));
+        self.next = None;
+    }
+
+    fn find(&self, id: u32, now: Instant) -> Option<&Arc<Session>> {
+        [&self.current, &self.previous, &self.next]
+            .into_iter()
+            .flatten()
+            .find(|session| session.id == id && session.usable(now))
+    }
+}
+
+pub struct Sessions {
+    config: SessionConfig,
+    peers: RwLock<HashMap<PeerKey, Peer>>,
+    /// Peers that packets were sent to without a usable session, and the MAC to send from
+    wanted: Mutex<HashMap<PeerKey, MacAddr>>,
+}
+
+// created on first use, shared by every pipeline that encrypts or decrypts
+static SESSIONS: Lazy<Sessions> =
+    Lazy::new(|| Sessions::new(load_session_config().unwrap_or_default()));
+
+pub fn sessions() -> &'static Sessions {
+    &SESSIONS
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_micros() as u64)
+}
+
+fn new_session_id() -> u32 {
+    rand::thread_rng().gen_range(STATIC_SESSION + 1..HANDSHAKE_SESSION)
+}
+
+fn new_ephemeral() -> (StaticSecret, [u8; 32]) {
+    // from raw bytes, as x25519-dalek wants another version of rand than ours
+    let secret = StaticSecret::from(rand::thread_rng().gen::<[u8; 32]>());
+    let public = PublicKey::from(&secret).to_bytes();
+    (secret, public)
+}
+
+impl Sessions {
+    fn new(config: SessionConfig) -> Self {
+        Sessions {
+            config,
+            peers: RwLock::new(HashMap::new()),
+            wanted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The session to encrypt a packet to its destination with, or None to use the static key.
+    /// Asks for a handshake if there is no session yet, or if it is due to be renegotiated.
+    pub fn outgoing<T: IpPacket<Envelope = Ethernet>>(
+        &self,
+        packet: &DTls<T>,
+    ) -> Result<Option<Arc<Session>>> {
+        let src_mac = packet.envelope().envelope().envelope().src();
+        self.session_to(PeerKey::outgoing(packet), src_mac, Instant::now())
+    }
+
+    fn session_to(
+        &self,
+        key: PeerKey,
+        src_mac: MacAddr,
+        now: Instant,
+    ) -> Result<Option<Arc<Session>>> {
+        let current = self
+            .peers
+            .read()
+            .recover()
+            .get(&key)
+            .and_then(|peer| peer.current.clone())
+            .filter(|session| session.usable(now));
+        if current.as_ref().map_or(true, |session| {
+            now.duration_since(session.established) >= REKEY_AFTER
+        }) {
+            self.wanted.lock().recover().insert(key, src_mac);
+        }
+        match current {
+            Some(session) => Ok(Some(session)),
+            None if self.config.require_sessions => {
+                bail!("no session with {} yet", key.peer)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The session that a packet from its source was encrypted with
+    pub fn incoming<T: IpPacket>(&self, packet: &DTls<T>, id: u32) -> Result<Arc<Session>> {
+        self.session_from(PeerKey::incoming(packet), id, Instant::now())
+    }
+
+    fn session_from(&self, key: PeerKey, id: u32, now: Instant) -> Result<Arc<Session>> {
+        let (session, confirms) = {
+            let peers = self.peers.read().recover();
+            let peer = peers
+                .get(&key)
+                .ok_or_else(|| anyhow!("no session with {}", key.peer))?;
+            let session = peer
+                .find(id, now)
+                .ok_or_else(|| anyhow!("unknown session {} with {}", id, key.peer))?;
+            let confirms = peer.next.as_ref().map_or(false, |next| next.id == id);
+            (session.clone(), confirms)
+        };
+        if confirms {
+            // strictly, only once the packet decrypts; a forgery can at worst swap in a session
+            // that the initiator already has
+            let mut peers = self.peers.write().recover();
+            if let Some(peer) = peers.get_mut(&key) {
+                if let Some(next) = peer.next.take().filter(|next| next.id == id) {
+                    peer.previous = peer.current.replace(next);
+                }
+            }
+        }
+        Ok(session)
+    }
+
+    /// Whether a packet encrypted with the static key may be decrypted
+    pub fn check_static<T: IpPacket>(&self, packet: &DTls<T>) -> Result<()> {
+        ensure!(
+            !self.config.require_sessions,
+            "{} did not use a session",
+            PeerKey::incoming(packet).peer
+        );
+        Ok(())
+    }
+
+    /// The cipher suites we accept, for heartbeats to advertise: those of sessions.toml, and
+    /// AES-256-GCM for the static key
+    pub fn suite_bitmap(&self) -> u16 {
+        suite_bitmap(&self.config.suites) | CipherSuite::Aes256Gcm.bit()
+    }
+
+    /// Handle a handshake message from the peer at `key`, returning the answer to send it if
+    /// there is one. `now_us` is the wall clock that Init timestamps are checked against
+    fn handle(
+        &self,
+        key: PeerKey,
+        handshake: Handshake,
+        now: Instant,
+        now_us: u64,
+    ) -> Result<Option<Handshake>> {
+        match handshake {
+            Handshake::Init {
+                session_id,
+                ephemeral,
+                timestamp,
+                mac,
+            } => self.handle_init(
+                key, session_id, ephemeral, timestamp, None, mac, now, now_us,
+            ),
+            Handshake::InitSuites {
+                session_id,
+                ephemeral,
+                timestamp,
+                suites,
+                mac,
+            } => self.handle_init(
+                key,
+                session_id,
+                ephemeral,
+                timestamp,
+                Some(suites),
+                mac,
+                now,
+                now_us,
+            ),
+            Handshake::Response {
+                session_id,
+                ephemeral,
+                mac,
+            } => self
+                .handle_response(key, session_id, ephemeral, None, mac, now)
+                .map(|()| None),
+            Handshake::ResponseSuite {
+                session_id,
+                ephemeral,
+                suite,
+                mac,
+            } => self
+                .handle_response(key, session_id, ephemeral, Some(suite), mac, now)
+                .map(|()| None),
+        }
+    }
+
+    /// Answer an Init, which offers the suites in `offered` unless it is of the old kind
+    #[allow(clippy::too_many_arguments)]
+    fn handle_init(
+        &self,
+        key: PeerKey,
+        session_id: u32,
+        init_ephemeral: [u8; 32],
+        timestamp: u64,
+        offered: Option<u16>,
+        mac: [u8; 32],
+        now: Instant,
+        now_us: u64,
+    ) -> Result<Option<Handshake>> {
+        init_mac(session_id, &init_ephemeral, timestamp, offered)
+            .verify_slice(&mac)
+            .map_err(|_| anyhow!("bad Init MAC from {}", key.peer))?;
+        let ours = self.config.suites_for(key.peer.ip());
+        let suite = match offered {
+            Some(offered) => ours
+                .iter()
+                .copied()
+                .find(|suite| offered & suite.bit() != 0),
+            None => Some(CipherSuite::Aes256Gcm).filter(|aes| ours.contains(aes)),
+        }
+        .ok_or_else(|| anyhow!("no cipher suite in common with {}", key.peer))?;
+        let skew = now_us.abs_diff(timestamp);
+        ensure!(
+            skew < MAX_INIT_SKEW.as_micros() as u64,
+            "stale Init from {}",
+            key.peer
+        );
+
+        let mut peers = self.peers.write().recover();
+        let peer = peers.entry(key).or_default();
+        ensure!(
+            timestamp > peer.last_init,
+            "replayed Init from {}",
+            key.peer
+        );
+        if peer.pending.is_some() {
+            if !key.gives_way() {
+                // the peer will answer our own Init instead
+                return Ok(None);
+            }
+            peer.pending = None;
+        }
+
+        let (secret, ephemeral) = new_ephemeral();
+        let shared = secret.diffie_hellman(&PublicKey::from(init_ephemeral));
+        let session = Session::derive(
+            session_id,
+            suite,
+            shared.as_bytes(),
+            &init_ephemeral,
+            &ephemeral,
+            false,
+            now,
+        )?;
+        peer.last_init = timestamp;
+        peer.next = Some(Arc::new(session));
+        // answered in kind, as a node that sent an Init of the old kind may not know the new
+        let picked = offered.map(|_| suite as u16);
+        let mac = response_mac(session_id, &init_ephemeral, &ephemeral, picked)
+            .finalize()
+            .into_bytes()
+            .into();
+        Ok(Some(match picked {
+            Some(suite) => Handshake::ResponseSuite {
+                session_id,
+                ephemeral,
+                suite,
+                mac,
+            },
+            None => Handshake::Response {
+                session_id,
+                ephemeral,
+                mac,
+            },
+        }))
+    }
+
+    /// Set up the session that a Response completes, which picked `picked` from the suites we
+    /// offered unless it is of the old kind
+    fn handle_response(
+        &self,
+        key: PeerKey,
+        session_id: u32,
+        resp_ephemeral: [u8; 32],
+        picked: Option<u16>,
+        mac: [u8; 32],
+        now: Instant,
+    ) -> Result<()> {
+        let mut peers = self.peers.write().recover();
+        let peer = peers
+            .get_mut(&key)
+            .ok_or_else(|| anyhow!("unsolicited Response from {}", key.peer))?;
+        let pending = match &peer.pending {
+            Some(pending) if pending.session_id == session_id => pending,
+            _ => bail!("unsolicited Response from {}", key.peer),
+        };
+        response_mac(session_id, &pending.ephemeral, &resp_ephemeral, picked)
+            .verify_slice(&mac)
+            .map_err(|_| anyhow!("bad Response MAC from {}", key.peer))?;
+        let suite = match (pending.suites, picked) {
+            (Some(offered), Some(picked)) => CipherSuite::in_bitmap(offered)
+                .find(|suite| *suite as u16 == picked)
+                .ok_or_else(|| anyhow!("{} picked a suite we did not offer", key.peer))?,
+            (None, None) => CipherSuite::Aes256Gcm,
+            _ => bail!("Response from {} of another kind than our Init", key.peer),
+        };
+        let shared = pending
+            .secret
+            .diffie_hellman(&PublicKey::from(resp_ephemeral));
+        let session = Session::derive(
+            session_id,
+            suite,
+            shared.as_bytes(),
+            &pending.ephemeral,
+            &resp_ephemeral,
+            true,
+            now,
+        )?;
+        peer.pending = None;
+        peer.install(session);
+        Ok(())
+    }
+
+    /// Inits for the peers that packets from `src_mac` want a session with, unless one is
+    /// already on its way
+    fn inits_due(&self, src_mac: MacAddr, now: Instant, now_us: u64) -> Vec<(PeerKey, Handshake)> {
+        let mut wanted = Vec::new();
+        self.wanted.lock().recover().retain(|key, mac| {
+            if *mac == src_mac {
+                wanted.push(*key);
+            }
+            *mac != src_mac
+        });
+        if wanted.is_empty() {
+            return Vec::new();
+        }
+        let mut peers = self.peers.write().recover();
+        wanted
+            .into_iter()
+            .filter_map(|key| {
+                let peer = peers.entry(key).or_default();
+                if let Some(pending) = &peer.pending {
+                    if now.duration_since(pending.sent) < HANDSHAKE_RETRY {
+                        return None;
+                    }
+                }
+                let ours = self.config.suites_for(key.peer.ip());
+                let aes_only = ours.iter().all(|suite| *suite == CipherSuite::Aes256Gcm);
+                let unanswered_offer = peer
+                    .pending
+                    .as_ref()
+                    .map_or(false, |pending| pending.suites.is_some());
+                let suites =
+                    if aes_only || (unanswered_offer && ours.contains(&CipherSuite::Aes256Gcm)) {
+                        None
+                    } else {
+                        Some(suite_bitmap(ours))
+                    };
+                let session_id = new_session_id();
+                let (secret, ephemeral) = new_ephemeral();
+                let timestamp = now_us;
+                let mac = init_mac(session_id, &ephemeral, timestamp, suites)
+                    .finalize()
+                    .into_bytes()
+                    .into();
+                peer.pending = Some(PendingInit {
+                    session_id,
+                    secret,
+                    ephemeral,
+                    suites,
+                    sent: now,
+                });
+                let init = match suites {
+                    Some(suites) => Handshake::InitSuites {
+                        session_id,
+                        ephemeral,
+                        timestamp,
+                        suites,
+                        mac,
+                    },
+                    None => Handshake::Init {
+                        session_id,
+                        ephemeral,
+                        timestamp,
+                        mac,
+                    },
+                };
+                Some((key, init))
+            })
+            .collect()
+    }
+}
+
+fn create_handshake<T: IpPacket<Envelope = Ethernet>>(
+    message: Mbuf,
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    key: PeerKey,
+    handshake: &Handshake,
+) -> Result<Mbuf> {
+    let content = bincode::serialize(handshake)?;
+
+    let mut message = message.push::<Ethernet>()?;
+    message.set_src(src_mac);
+    message.set_dst(dst_mac);
+
+    let mut message = message.push::<T>()?;
+    message.set_src(key.local.ip())?;
+    message.set_dst(key.peer.ip())?;
+
+    let mut message = message.push::<Udp<T>>()?;
+    message.set_src_port(key.local.port());
+    message.set_dst_port(key.peer.port());
+
+    let mut message = message.push::<DTls<T>>()?;
+    message.set_session(HANDSHAKE_SESSION);
+
+    let offset = message.payload_offset();
+    message.mbuf_mut().extend(offset, content.len())?;
+    message.mbuf_mut().write_data_slice(offset, &content)?;
+
+    message.reconcile_all();
+    Ok(message.reset())
+}
+
+fn send_handshake(
+    q: PortQueue,
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    key: PeerKey,
+    handshake: &Handshake,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| match key.peer {
+            SocketAddr::V4(_) => create_handshake::<Ipv4>(packet, src_mac, dst_mac, key, handshake),
+            SocketAddr::V6(_) => create_handshake::<Ipv6>(packet, src_mac, dst_mac, key, handshake),
+        })
+        .send(VlanTx::new(q))
+        .run_once();
+}
+
+/// Handle the packet if it is a handshake message, answering on `q` if needed.
+/// Returns whether the packet should carry on through the pipeline.
+pub fn accept_handshake<T: IpPacket<Envelope = Ethernet>>(
+    packet: &DTls<T>,
+    q: &PortQueue,
+    debug: bool,
+) -> bool {
+    if packet.session() != HANDSHAKE_SESSION {
+        return true;
+    }
+    let key = PeerKey::incoming(packet);
+    let handled = read_payload(packet)
+        .and_then(|payload| Ok(bincode::deserialize::<Handshake>(payload)?))
+        .and_then(|handshake| sessions().handle(key, handshake, Instant::now(), now_us()));
+    match handled {
+        Ok(Some(response)) => {
+            let dst_mac = packet.envelope().envelope().envelope().src();
+            send_handshake(q.clone(), q.mac_addr(), dst_mac, key, &response);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            if debug {
+                println!("dropping handshake: {:#}", err);
+            }
+        }
+    }
+    false
+}
+
+/// Start the handshakes that packets sent from this queue's port asked for
+pub fn session_schedule(q: PortQueue) -> impl Pipeline {
+    Schedule::new("dtls_sessions", async move {
+        loop {
+            for (key, init) in sessions().inits_due(q.mac_addr(), Instant::now(), now_us()) {
+                send_handshake(q.clone(), q.mac_addr(), MacAddr::broadcast(), key, &init);
+            }
+            delay_for(HANDSHAKE_POLL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Time as a test scripts it, from a fixed start
+    struct MockClock {
+        start: Instant,
+        start_us: u64,
+        elapsed: Cell<Duration>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            MockClock {
+                start: Instant::now(),
+                start_us: now_us(),
+                elapsed: Cell::new(Duration::ZERO),
+            }
+        }
+
+        fn now(&self) -> Instant {
+            self.start + self.elapsed.get()
+        }
+
+        fn now_us(&self) -> u64 {
+            self.start_us + self.elapsed.get().as_micros() as u64
+        }
+
+        fn advance(&self, by: Duration) {
+            self.elapsed.set(self.elapsed.get() + by);
+        }
+    }
+
+    /// One end of a link, with the sessions of a node that runs on the mock clock
+    struct Node {
+        sessions: Sessions,
+        /// Ourselves and the other end, as in the packets we send
+        key: PeerKey,
+        mac: MacAddr,
+    }
+
+    impl Node {
+        fn new(local: u8, peer: u8) -> Self {
+            Node::with_suites(local, peer, &default_suites())
+        }
+
+        /// A node whose sessions may use `suites`, most preferred first
+        fn with_suites(local: u8, peer: u8, suites: &[CipherSuite]) -> Self {
+            let addr = |host| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, host)), 31415);
+            Node {
+                sessions: Sessions::new(SessionConfig {
+                    suites: suites.to_vec(),
+                    ..SessionConfig::default()
+                }),
+                key: PeerKey {
+                    local: addr(local),
+                    peer: addr(peer),
+                },
+                mac: MacAddr::new(2, 0, 0, 0, 0, local),
+            }
+        }
+
+        /// Encrypt `data` to the other end, or None if it would go under the static key
+        fn seal(&self, clock: &MockClock, data: &[u8]) -> Option<Sealed> {
+            let session = self
+                .sessions
+                .session_to(self.key, self.mac, clock.now())
+                .unwrap()?;
+            let nonce = session.next_nonce().unwrap();
+            Some(Sealed {
+                session: session.id(),
+                nonce,
+                data: session.encrypt(&nonce, data).unwrap(),
+            })
+        }
+
+        fn open(&self, clock: &MockClock, sealed: &Sealed) -> Result<Vec<u8>> {
+            self.sessions
+                .session_from(self.key, sealed.session, clock.now())?
+                .decrypt(&sealed.nonce, &sealed.data)
+        }
+
+        /// The Inits this node sends now
+        fn inits(&self, clock: &MockClock) -> Vec<Handshake> {
+            self.sessions
+                .inits_due(self.mac, clock.now(), clock.now_us())
+                .into_iter()
+                .map(|(key, init)| {
+                    assert_eq!(key, self.key);
+                    init
+                })
+                .collect()
+        }
+
+        fn receive(&self, clock: &MockClock, handshake: Handshake) -> Result<Option<Handshake>> {
+            self.sessions
+                .handle(self.key, handshake, clock.now(), clock.now_us())
+        }
+
+        fn current_id(&self) -> Option<u32> {
+            let peers = self.sessions.peers.read().recover();
+            peers
+                .get(&self.key)?
+                .current
+                .as_ref()
+                .map(|session| session.id())
+        }
+
+        fn current_suite(&self) -> Option<CipherSuite> {
+            let peers = self.sessions.peers.read().recover();
+            let session = peers.get(&self.key)?.current.clone()?;
+            Some(match session.send.cipher {
+                Cipher::Aes256Gcm(_) => CipherSuite::Aes256Gcm,
+                Cipher::ChaCha20Poly1305(_) => CipherSuite::ChaCha20Poly1305,
+                Cipher::Null => CipherSuite::Null,
+            })
+        }
+    }
+
+    struct Sealed {
+        session: u32,
+        nonce: [u8; 12],
+        data: Vec<u8>,
+    }
+
+    fn link() -> (Node, Node) {
+        (Node::new(1, 2), Node::new(2, 1))
+    }
+
+    /// Run a whole handshake from `initiator`, once a packet to `responder` asked for one
+    fn handshake(clock: &MockClock, initiator: &Node, responder: &Node) {
+        let mut inits = initiator.inits(clock);
+        assert_eq!(inits.len(), 1);
+        let response = responder.receive(clock, inits.remove(0)).unwrap().unwrap();
+        assert!(initiator.receive(clock, response).unwrap().is_none());
+    }
+
+    /// A session that both ends have confirmed, started by `a`
+    fn establish(clock: &MockClock, a: &Node, b: &Node) {
+        assert!(a.seal(clock, b"hello").is_none());
+        handshake(clock, a, b);
+        let sealed = a.seal(clock, b"hello").unwrap();
+        assert_eq!(b.open(clock, &sealed).unwrap(), b"hello");
+        assert_eq!(a.current_id(), b.current_id());
+    }
+
+    #[test]
+    fn handshake_sets_up_a_session_both_ways() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+
+        establish(&clock, &a, &b);
+        let sealed = b.seal(&clock, b"back").unwrap();
+        assert_eq!(a.open(&clock, &sealed).unwrap(), b"back");
+        // nothing more is due until the session gets old
+        assert!(a.inits(&clock).is_empty());
+    }
+
+    #[test]
+    fn responder_keeps_its_old_session_until_the_initiator_uses_the_new() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        establish(&clock, &a, &b);
+        let old = a.current_id();
+
+        clock.advance(REKEY_AFTER);
+        assert!(a.seal(&clock, b"still old").is_some());
+        handshake(&clock, &a, &b);
+        assert_ne!(a.current_id(), old);
+        assert_eq!(b.current_id(), old);
+
+        // the initiator's first packet on the new session is lost, so the responder still
+        // sends on the old one, which the initiator decrypts as its previous session
+        a.seal(&clock, b"lost").unwrap();
+        let sealed = b.seal(&clock, b"on the old session").unwrap();
+        assert_eq!(Some(sealed.session), old);
+        assert!(a.open(&clock, &sealed).is_ok());
+
+        let sealed = a.seal(&clock, b"on the new session").unwrap();
+        assert!(b.open(&clock, &sealed).is_ok());
+        assert_eq!(b.current_id(), a.current_id());
+    }
+
+    #[test]
+    fn old_session_is_accepted_until_reject_after() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        establish(&clock, &a, &b);
+        let in_flight = [
+            a.seal(&clock, b"one").unwrap(),
+            a.seal(&clock, b"two").unwrap(),
+        ];
+
+        clock.advance(REKEY_AFTER);
+        a.seal(&clock, b"asks for a new session").unwrap();
+        handshake(&clock, &a, &b);
+        let sealed = a.seal(&clock, b"new").unwrap();
+        assert!(b.open(&clock, &sealed).is_ok());
+
+        assert_eq!(b.open(&clock, &in_flight[0]).unwrap(), b"one");
+        clock.advance(REJECT_AFTER - REKEY_AFTER);
+        assert!(b.open(&clock, &in_flight[1]).is_err());
+        // while the new session carries on
+        let sealed = a.seal(&clock, b"newer").unwrap();
+        assert!(b.open(&clock, &sealed).is_ok());
+    }
+
+    #[test]
+    fn session_is_dropped_at_reject_after_without_a_new_one() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        establish(&clock, &a, &b);
+        let sealed = a.seal(&clock, b"late").unwrap();
+
+        // every Init since REKEY_AFTER was lost
+        clock.advance(REJECT_AFTER);
+        assert!(a.seal(&clock, b"static").is_none());
+        assert!(b.open(&clock, &sealed).is_err());
+        assert_eq!(a.inits(&clock).len(), 1);
+    }
+
+    #[test]
+    fn handshake_is_retried_after_a_lost_response() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        assert!(a.seal(&clock, b"hello").is_none());
+        let first = a.inits(&clock).remove(0);
+        let lost = b.receive(&clock, first).unwrap().unwrap();
+
+        clock.advance(HANDSHAKE_RETRY / 2);
+        assert!(a.seal(&clock, b"hello").is_none());
+        assert!(a.inits(&clock).is_empty());
+
+        clock.advance(HANDSHAKE_RETRY / 2);
+        assert!(a.seal(&clock, b"hello").is_none());
+        handshake(&clock, &a, &b);
+        // the Response to the first Init is no use once another is pending
+        assert!(a.receive(&clock, lost).is_err());
+        let sealed = a.seal(&clock, b"hello").unwrap();
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn handshake_is_retried_after_a_lost_init() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        assert!(a.seal(&clock, b"hello").is_none());
+        assert_eq!(a.inits(&clock).len(), 1);
+
+        clock.advance(HANDSHAKE_RETRY);
+        assert!(a.seal(&clock, b"hello").is_none());
+        handshake(&clock, &a, &b);
+        let sealed = a.seal(&clock, b"hello").unwrap();
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn replayed_and_stale_inits_are_refused() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        assert!(a.seal(&clock, b"hello").is_none());
+        let init = a.inits(&clock).remove(0);
+        let replay = bincode::deserialize(&bincode::serialize(&init).unwrap()).unwrap();
+        assert!(b.receive(&clock, init).unwrap().is_some());
+        assert!(b.receive(&clock, replay).is_err());
+
+        // an Init recorded long ago, replayed to a node that has forgotten the newest one
+        let restarted = Node::new(2, 1);
+        clock.advance(HANDSHAKE_RETRY);
+        assert!(a.seal(&clock, b"hello").is_none());
+        let init = a.inits(&clock).remove(0);
+        clock.advance(MAX_INIT_SKEW);
+        assert!(restarted.receive(&clock, init).is_err());
+    }
+
+    #[test]
+    fn restarted_peer_recovers_once_it_sends_on_a_new_session() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        establish(&clock, &a, &b);
+
+        let b = Node::new(2, 1);
+        let sealed = a.seal(&clock, b"to the old b").unwrap();
+        assert!(b.open(&clock, &sealed).is_err());
+
+        assert!(b.seal(&clock, b"hello again").is_none());
+        handshake(&clock, &b, &a);
+        let sealed = b.seal(&clock, b"hello again").unwrap();
+        assert_eq!(a.open(&clock, &sealed).unwrap(), b"hello again");
+        let sealed = a.seal(&clock, b"to the new b").unwrap();
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"to the new b");
+    }
+
+    #[test]
+    fn simultaneous_inits_leave_one_session() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        assert!(a.seal(&clock, b"from a").is_none());
+        assert!(b.seal(&clock, b"from b").is_none());
+        let from_a = a.inits(&clock).remove(0);
+        let from_b = b.inits(&clock).remove(0);
+
+        // b has the higher address, so it waits for the answer to its own Init
+        assert!(b.receive(&clock, from_a).unwrap().is_none());
+        let response = a.receive(&clock, from_b).unwrap().unwrap();
+        assert!(b.receive(&clock, response).unwrap().is_none());
+
+        let sealed = b.seal(&clock, b"from b").unwrap();
+        assert_eq!(a.open(&clock, &sealed).unwrap(), b"from b");
+        let sealed = a.seal(&clock, b"from a").unwrap();
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"from a");
+        assert_eq!(a.current_id(), b.current_id());
+    }
+
+    /// A session from `a` to `b` over which data makes it both ways
+    fn round_trip(clock: &MockClock, a: &Node, b: &Node) {
+        establish(clock, a, b);
+        let sealed = b.seal(clock, b"back").unwrap();
+        assert_eq!(a.open(clock, &sealed).unwrap(), b"back");
+    }
+
+    #[test]
+    fn responder_picks_its_first_suite_that_was_offered() {
+        use CipherSuite::*;
+        let clock = MockClock::new();
+        let a = Node::with_suites(1, 2, &[Null, ChaCha20Poly1305]);
+        let b = Node::with_suites(2, 1, &[ChaCha20Poly1305, Aes256Gcm, Null]);
+        assert!(a.seal(&clock, b"hello").is_none());
+        let init = a.inits(&clock).remove(0);
+        assert!(matches!(init, Handshake::InitSuites { suites, .. } if suites == 0b110));
+        let response = b.receive(&clock, init).unwrap().unwrap();
+        assert!(matches!(
+            response,
+            Handshake::ResponseSuite { suite: 1, .. }
+        ));
+        assert!(a.receive(&clock, response).unwrap().is_none());
+
+        let sealed = a.seal(&clock, b"hello").unwrap();
+        assert_ne!(&sealed.data[..5], b"hello");
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"hello");
+        let sealed = b.seal(&clock, b"back").unwrap();
+        assert_eq!(a.open(&clock, &sealed).unwrap(), b"back");
+        assert_eq!(a.current_suite(), Some(ChaCha20Poly1305));
+        assert_eq!(b.current_suite(), Some(ChaCha20Poly1305));
+    }
+
+    #[test]
+    fn chacha_sessions_round_trip() {
+        let clock = MockClock::new();
+        let suites = [CipherSuite::ChaCha20Poly1305];
+        let (a, b) = (
+            Node::with_suites(1, 2, &suites),
+            Node::with_suites(2, 1, &suites),
+        );
+        round_trip(&clock, &a, &b);
+        assert_eq!(b.current_suite(), Some(CipherSuite::ChaCha20Poly1305));
+
+        // and still authenticate what they carry
+        let mut sealed = a.seal(&clock, b"hello").unwrap();
+        sealed.data[0] ^= 1;
+        assert!(b.open(&clock, &sealed).is_err());
+    }
+
+    #[test]
+    fn null_sessions_round_trip_in_the_clear() {
+        let clock = MockClock::new();
+        let suites = [CipherSuite::Null];
+        let (a, b) = (
+            Node::with_suites(1, 2, &suites),
+            Node::with_suites(2, 1, &suites),
+        );
+        round_trip(&clock, &a, &b);
+        assert_eq!(a.current_suite(), Some(CipherSuite::Null));
+
+        let sealed = a.seal(&clock, b"hello").unwrap();
+        assert_eq!(sealed.data.len(), 5 + crate::dtls::TAG_LEN);
+        assert_eq!(&sealed.data[..5], b"hello");
+        assert!(sealed.data[5..].iter().all(|byte| *byte == 0));
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"hello");
+        // replays are still dropped
+        assert!(b.open(&clock, &sealed).is_err());
+    }
+
+    #[test]
+    fn aes_only_nodes_send_inits_of_the_old_kind() {
+        let clock = MockClock::new();
+        let (a, b) = link();
+        assert!(a.seal(&clock, b"hello").is_none());
+        let init = a.inits(&clock).remove(0);
+        assert!(matches!(init, Handshake::Init { .. }));
+        let response = b.receive(&clock, init).unwrap().unwrap();
+        assert!(matches!(response, Handshake::Response { .. }));
+        assert!(a.receive(&clock, response).unwrap().is_none());
+        assert_eq!(a.current_suite(), Some(CipherSuite::Aes256Gcm));
+    }
+
+    #[test]
+    fn unanswered_offers_fall_back_to_the_old_init() {
+        let clock = MockClock::new();
+        let a = Node::with_suites(
+            1,
+            2,
+            &[CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm],
+        );
+        let b = Node::new(2, 1);
+        assert!(a.seal(&clock, b"hello").is_none());
+        // a node from before cipher suites ignores the kind of Init that offers them
+        let offer = a.inits(&clock).remove(0);
+        assert!(matches!(offer, Handshake::InitSuites { .. }));
+
+        clock.advance(HANDSHAKE_RETRY);
+        assert!(a.seal(&clock, b"hello").is_none());
+        let init = a.inits(&clock).remove(0);
+        assert!(matches!(init, Handshake::Init { .. }));
+        let response = b.receive(&clock, init).unwrap().unwrap();
+        assert!(a.receive(&clock, response).unwrap().is_none());
+        let sealed = a.seal(&clock, b"hello").unwrap();
+        assert_eq!(b.open(&clock, &sealed).unwrap(), b"hello");
+        assert_eq!(a.current_suite(), Some(CipherSuite::Aes256Gcm));
+
+        // without AES-256-GCM to fall back to, the offer is made again
+        let c = Node::with_suites(1, 3, &[CipherSuite::ChaCha20Poly1305]);
+        assert!(c.seal(&clock, b"hello").is_none());
+        assert_eq!(c.inits(&clock).len(), 1);
+        clock.advance(HANDSHAKE_RETRY);
+        assert!(c.seal(&clock, b"hello").is_none());
+        assert!(matches!(
+            c.inits(&clock).remove(0),
+            Handshake::InitSuites { .. }
+        ));
+    }
+
+    #[test]
+    fn suites_outside_the_offer_are_refused() {
+        let clock = MockClock::new();
+        let a = Node::with_suites(1, 2, &[CipherSuite::Null]);
+        assert!(a.seal(&clock, b"hello").is_none());
+        let init = a.inits(&clock).remove(0);
+        let (session_id, init_ephemeral) = match &init {
+            Handshake::InitSuites {
+                session_id,
+                ephemeral,
+                ..
+            } => (*session_id, *ephemeral),
+            _ => panic!("expected an offer"),
+        };
+        // a responder without the suite offered has none in common
+        assert!(Node::new(2, 1).receive(&clock, init).is_err());
+
+        // and a Response that picks another suite than those offered is refused, MAC or not
+        let (_, ephemeral) = new_ephemeral();
+        let suite = CipherSuite::Aes256Gcm as u16;
+        let mac = response_mac(session_id, &init_ephemeral, &ephemeral, Some(suite))
+            .finalize()
+            .into_bytes()
+            .into();
+        let picked_another = Handshake::ResponseSuite {
+            session_id,
+            ephemeral,
+            suite,
+            mac,
+        };
+        assert!(a.receive(&clock, picked_another).is_err());
+        assert_eq!(a.current_id(), None);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use capsule::packets::ip::IpPacket;
+use capsule::packets::{Packet, Udp};
+pub use gdp_proto::{TransportHint, TransportProfile};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use super::{DTls, PLAINTEXT_SESSION};
+use crate::isolation::Recover;
+
+/*
+   Peers that predate the dTLS layer are reached in plaintext, so that a switch can talk to old
+   and new nodes while the network migrates:
+   - every peer IP has a transport profile: encrypted (the default) or plaintext_legacy
+   - profiles come from transports.toml only; a peer that is not listed there gets the default
+     profile. Packets from an encrypted peer that claim to be plaintext are dropped
+   - once a legacy node has migrated, RIB responses that locate it may hint it as encrypted, for
+     the nodes listed as encrypted in the RIB's own transports.toml. A switch that opted in with
+     `rib_hints` then encrypts to a peer it lists as plaintext_legacy. Hints only ever upgrade a
+     listed peer: they never downgrade a peer to plaintext, nor say anything about a peer that
+     the switch does not list
+   - packets from plaintext peers are given an empty dTLS header on the way in, so that they
+     travel through the pipeline like any other, and lose it again on the way out
+   - with `encryption` off in transports.toml, to measure what the rest of the pipeline costs,
+     every peer is treated as a plaintext peer. It can only be turned off at startup, and
+     preflight reports it (outside of local runs, it stops the node)
+   - peers on IPv6 ports are known by the IPv4 addresses that theirs embed (see ipv6.rs), as
+     everything past the port is; RIB hints could not name IPv6 peers anyway
+*/
+
+/// Hinted profiles are forgotten unless the RIB repeats them within this long
+const HINT_TTL: Duration = Duration::from_secs(600);
+
+/// A peer's profile in transports.toml, which may be at either IP version
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ConfiguredTransport {
+    pub ip: IpAddr,
+    pub profile: TransportProfile,
+}
+
+#[derive(Deserialize)]
+pub struct TransportConfig {
+    /// For peers that are neither configured nor hinted
+    #[serde(default)]
+    pub default_profile: TransportProfile,
+    /// Whether to encrypt to the configured plaintext peers that the RIB hints have migrated
+    #[serde(default)]
+    pub rib_hints: bool,
+    #[serde(default)]
+    pub peers: Vec<ConfiguredTransport>,
+    /// Off, every peer is treated as a plaintext peer, for benchmarking only
+    #[serde(default = "default_encryption")]
+    pub encryption: bool,
+}
+
+fn default_encryption() -> bool {
+    true
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            default_profile: TransportProfile::Encrypted,
+            rib_hints: false,
+            peers: Vec::new(),
+            encryption: default_encryption(),
+        }
+    }
+}
+
+pub fn load_transport_config() -> Result<TransportConfig> {
+    let content = fs::read_to_string("transports.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+pub struct Transports {
+    default_profile: TransportProfile,
+    rib_hints: bool,
+    configured: HashMap<IpAddr, TransportProfile>,
+    /// When the RIB last hinted that each of the configured plaintext peers has migrated
+    upgraded: RwLock<HashMap<IpAddr, Instant>>,
+    /// Off when benchmarking without crypto, making every peer plaintext
+    encryption: bool,
+}
+
+// created on first use, shared by every pipeline that encrypts or decrypts, and the RIB
+static TRANSPORTS: Lazy<Transports> =
+    Lazy::new(|| Transports::new(load_transport_config().unwrap_or_default()));
+
+pub fn transports() -> &'static Transports {
+    &TRANSPORTS
+}
+
+impl Transports {
+    fn new(config: TransportConfig) -> Self {
+        Transports {
+            default_profile: config.default_profile,
+            rib_hints: config.rib_hints,
+            configured: config
+                .peers
+                .iter()
+                .map(|peer| (peer.ip, peer.profile))
+                .collect(),
+            upgraded: RwLock::new(HashMap::new()),
+            encryption: config.encryption,
+        }
+    }
+
+    pub fn profile(&self, peer: IpAddr) -> TransportProfile {
+        if !self.encryption {
+            return TransportProfile::PlaintextLegacy;
+        }
+        match self.configured.get(&peer) {
+            Some(TransportProfile::PlaintextLegacy) if self.is_upgraded(peer) => {
+                TransportProfile::Encrypted
+            }
+            Some(profile) => *profile,
+            None => self.default_profile,
+        }
+    }
+
+    fn is_upgraded(&self, peer: IpAddr) -> bool {
+        self.rib_hints
+            && self
+                .upgraded
+                .read()
+                .recover()
+                .get(&peer)
+                .map_or(false, |hinted| hinted.elapsed() < HINT_TTL)
+    }
+
+    pub fn is_plaintext(&self, peer: IpAddr) -> bool {
+        self.profile(peer) == TransportProfile::PlaintextLegacy
+    }
+
+    /// Take the hints of a RIB response that configured plaintext peers have migrated; any
+    /// other hint is ignored
+    pub fn learn(&self, hints: &[TransportHint]) {
+        if !self.rib_hints {
+            return;
+        }
+        let upgrades = hints
+            .iter()
+            .map(|hint| (IpAddr::V4(hint.ip), hint.profile))
+            .filter(|(ip, profile)| {
+                *profile == TransportProfile::Encrypted
+                    && self.configured.get(ip) == Some(&TransportProfile::PlaintextLegacy)
+            })
+            .collect::<Vec<_>>();
+        if upgrades.is_empty() {
+            return;
+        }
+        let mut upgraded = self.upgraded.write().recover();
+        for (ip, _) in upgrades {
+            upgraded.insert(ip, Instant::now());
+        }
+    }
+
+    /// The hints for a RIB response locating nodes at `ips`: those of our configured peers
+    /// that encrypt
+    pub fn hints_for(&self, ips: impl Iterator<Item = Ipv4Addr>) -> Vec<TransportHint> {
+        ips.filter_map(|ip| match self.configured.get(&IpAddr::V4(ip)) {
+            Some(TransportProfile::Encrypted) => Some(TransportHint {
+                ip,
+                profile: TransportProfile::Encrypted,
+            }),
+            _ => None,
+        })
+        .collect()
+    }
+
+    /// Whether a packet that arrived without dTLS may be accepted from `peer`
+    pub fn check_plaintext(&self, peer: IpAddr) -> Result<()> {
+        ensure!(
+            self.is_plaintext(peer),
+            "plaintext from {}, which must encrypt",
+            peer
+        );
+        Ok(())
+    }
+}
+
+/// Parse the dTLS layer of a packet. Packets from plaintext peers have none, and are given an
+/// empty one that marks them as plaintext.
+pub fn parse_dtls<T: IpPacket>(packet: Udp<T>) -> Result<DTls<T>> {
+    if transports().is_plaintext(packet.envelope().src()) {
+        let mut packet = packet.push::<DTls<T>>()?;
+        packet.set_session(PLAINTEXT_SESSION);
+        Ok(packet)
+    } else {
+        packet.parse::<DTls<T>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY: Ipv4Addr = Ipv4Addr::new(10, 100, 1, 20);
+    const MODERN: Ipv4Addr = Ipv4Addr::new(10, 100, 1, 12);
+    const UNLISTED: Ipv4Addr = Ipv4Addr::new(10, 100, 1, 30);
+
+    fn configured(rib_hints: bool) -> Transports {
+        Transports::new(TransportConfig {
+            default_profile: TransportProfile::Encrypted,
+            rib_hints,
+            peers: vec![
+                ConfiguredTransport {
+                    ip: IpAddr::V4(LEGACY),
+                    profile: TransportProfile::PlaintextLegacy,
+                },
+                ConfiguredTransport {
+                    ip: IpAddr::V4(MODERN),
+                    profile: TransportProfile::Encrypted,
+                },
+            ],
+            encryption: true,
+        })
+    }
+
+    fn hint(ip: Ipv4Addr, profile: TransportProfile) -> TransportHint {
+        TransportHint { ip, profile }
+    }
+
+    #[test]
+    fn hints_are_ignored_by_default() {
+        let config: TransportConfig = toml::from_str("").unwrap();
+        assert!(!config.rib_hints);
+        let transports = Transports::new(config);
+        transports.learn(&[hint(UNLISTED, TransportProfile::PlaintextLegacy)]);
+        assert!(!transports.is_plaintext(IpAddr::V4(UNLISTED)));
+    }
+
+    #[test]
+    fn hints_never_downgrade_a_peer() {
+        let transports = configured(true);
+        transports.learn(&[
+            hint(MODERN, TransportProfile::PlaintextLegacy),
+            hint(UNLISTED, TransportProfile::PlaintextLegacy),
+        ]);
+        assert!(!transports.is_plaintext(IpAddr::V4(MODERN)));
+        assert!(!transports.is_plaintext(IpAddr::V4(UNLISTED)));
+        assert!(transports.check_plaintext(IpAddr::V4(MODERN)).is_err());
+    }
+
+    #[test]
+    fn hints_say_nothing_of_unlisted_peers() {
+        let transports = Transports::new(TransportConfig {
+            default_profile: TransportProfile::PlaintextLegacy,
+            rib_hints: true,
+            ..Default::default()
+        });
+        transports.learn(&[hint(UNLISTED, TransportProfile::Encrypted)]);
+        assert!(transports.is_plaintext(IpAddr::V4(UNLISTED)));
+        assert!(transports.upgraded.read().recover().is_empty());
+    }
+
+    #[test]
+    fn hints_upgrade_listed_legacy_peers_that_migrated() {
+        let transports = configured(true);
+        assert!(transports.is_plaintext(IpAddr::V4(LEGACY)));
+        transports.learn(&[hint(LEGACY, TransportProfile::Encrypted)]);
+        assert!(!transports.is_plaintext(IpAddr::V4(LEGACY)));
+
+        let unhinted = configured(false);
+        unhinted.learn(&[hint(LEGACY, TransportProfile::Encrypted)]);
+        assert!(unhinted.is_plaintext(IpAddr::V4(LEGACY)));
+    }
+
+    #[test]
+    fn the_rib_hints_only_peers_that_encrypt() {
+        let hints = configured(false).hints_for(vec![LEGACY, MODERN, UNLISTED].into_iter());
+        assert_eq!(hints.len(), 1);
+        assert_eq!(hints[0].ip, MODERN);
+        assert_eq!(hints[0].profile, TransportProfile::Encrypted);
+    }
+}
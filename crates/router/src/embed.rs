@@ -0,0 +1,382 @@
+use std::net::Ipv4Addr;
+
+use anyhow::Result;
+use capsule::batch::Pipeline;
+use capsule::PortQueue;
+use gdp_proto::GdpName;
+
+use crate::admission::{admission_schedule, Admission};
+use crate::capabilities::heartbeat_schedule;
+use crate::certificates::{CertDest, GdpMeta, NewRtCert, RtCert};
+use crate::clock::Clock;
+use crate::dtls::session::session_schedule;
+use crate::flags::FeatureFlags;
+use crate::gdp_pipeline::install_gdp_pipeline;
+use crate::hardcoded_routes::{gdp_name_of_index, metadata_of_index, private_key_of_index};
+use crate::identity::PortIdentity;
+use crate::invalidation::{gossip_schedule, Gossip};
+use crate::kvs::SharedStore;
+use crate::prefetch::{prefetch_schedule, Prefetcher, SequentialPredictor, DEFAULT_PREFETCH_DEPTH};
+use crate::probe::{probe_schedule, Prober};
+use crate::registration::register_node;
+use crate::rib::Routes;
+use crate::status::StatusBoard;
+use crate::switch::switch_pipeline;
+use crate::usage::UsageMeter;
+
+/// Identity and addressing of an embedded switch
+#[derive(Clone, Copy)]
+pub struct SwitchConfig {
+    pub gdp_name: GdpName,
+    pub meta: GdpMeta,
+    pub private_key: [u8; 32],
+    /// The IP address that GDP traffic for this switch is sent to
+    pub node_addr: Ipv4Addr,
+    pub rib_ip: Ipv4Addr,
+    /// Label used in logs and metrics
+    pub nic_name: &'static str,
+    pub debug: bool,
+}
+
+impl SwitchConfig {
+    /// Uses one of the hardcoded identities from the deployment configuration
+    pub fn from_index(index: u8, node_addr: Ipv4Addr, rib_ip: Ipv4Addr) -> Self {
+        SwitchConfig {
+            gdp_name: gdp_name_of_index(index),
+            meta: metadata_of_index(index),
+            private_key: private_key_of_index(index),
+            node_addr,
+            rib_ip,
+            nic_name: "switch",
+            debug: false,
+        }
+    }
+}
+
+/// Builds a GDP switch pipeline that can be installed on a port queue of any Capsule runtime.
+///
+/// ```ignore
+/// let switch = GdpSwitch::new(config)
+///     .with_store(store)
+///     .with_policy(flags)
+///     .build()?;
+/// runtime.add_pipeline_to_port("eth1", move |q| switch.install(q))?;
+/// ```
+///
+/// Every queue the switch is installed on shares the same store and policy. With labels.toml
+/// enforced, the application must also write the label audit log (`flush_label_audit`, every
+/// `AUDIT_FLUSH_INTERVAL`) as a periodic task on one core.
+#[derive(Clone, Copy)]
+pub struct GdpSwitch {
+    config: SwitchConfig,
+    store: Option<SharedStore>,
+    flags: Option<FeatureFlags>,
+    clock: Option<&'static Clock>,
+    prefetcher: Option<Prefetcher>,
+    prober: Option<Prober>,
+    usage: Option<UsageMeter>,
+    admission: Option<Admission>,
+    status: Option<StatusBoard>,
+    gossip: Option<Gossip>,
+    identity: Option<PortIdentity>,
+    /// Lead to `config.rib_ip`, as an embedded switch has no routes file to reload
+    routes: Option<&'static Routes>,
+    announce: bool,
+}
+
+impl GdpSwitch {
+    pub fn new(config: SwitchConfig) -> Self {
+        GdpSwitch {
+            config,
+            store: None,
+            flags: None,
+            clock: None,
+            prefetcher: None,
+            prober: None,
+            usage: None,
+            admission: None,
+            status: None,
+            gossip: None,
+            identity: None,
+            routes: None,
+            announce: true,
+        }
+    }
+
+    /// Share forwarding state with other switches in the same process (e.g. for inspection)
+    pub fn with_store(mut self, store: SharedStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Choose which optional forwarding features are enabled (all of them by default)
+    pub fn with_policy(mut self, flags: FeatureFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Reuse a clock that the application already keeps in sync with the time master
+    pub fn with_clock(mut self, clock: &'static Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Predict upcoming lookups with something other than the default SequentialPredictor.
+    /// Predictions are only acted on if `install_prefetch` is also used.
+    pub fn with_prefetcher(mut self, prefetcher: Prefetcher) -> Self {
+        self.prefetcher = Some(prefetcher);
+        self
+    }
+
+    /// Send from these addresses rather than the port's own MAC and `node_addr`.
+    /// Checked against each queue the switch is installed on.
+    pub fn with_identity(mut self, identity: PortIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Whether to announce our route to the RIB each time the switch is installed on a queue
+    pub fn with_announce(mut self, announce: bool) -> Self {
+        self.announce = announce;
+        self
+    }
+
+    /// Fill in any defaults, so that the switch can be copied into per-queue installers
+    pub fn build(self) -> Result<Self> {
+        let config = self.config;
+        // generated up front so a bad identity is reported here, rather than on the first queue
+        RtCert::new_wrapped(
+            config.meta,
+            config.private_key,
+            CertDest::IpAddr(config.node_addr),
+            true,
+        )?;
+        Ok(GdpSwitch {
+            store: Some(self.store.unwrap_or_else(SharedStore::new)),
+            flags: Some(self.flags.unwrap_or_else(FeatureFlags::new)),
+            clock: Some(self.clock.unwrap_or_else(Clock::new)),
+            prefetcher: Some(self.prefetcher.unwrap_or_else(|| {
+                Prefetcher::new(
+                    SequentialPredictor::new(DEFAULT_PREFETCH_DEPTH),
+                    config.nic_name,
+                )
+            })),
+            prober: Some(self.prober.unwrap_or_else(|| Prober::new(config.nic_name))),
+            usage: match self.usage {
+                Some(usage) => Some(usage),
+                None => Some(UsageMeter::new(config.gdp_name, config.private_key)?),
+            },
+            admission: Some(
+                self.admission
+                    .unwrap_or_else(|| Admission::new(config.nic_name)),
+            ),
+            status: Some(
+                self.status
+                    .unwrap_or_else(|| StatusBoard::new(config.gdp_name)),
+            ),
+            gossip: Some(
+                self.gossip
+                    .unwrap_or_else(|| Gossip::new(config.nic_name, config.private_key)),
+            ),
+            routes: Some(
+                self.routes
+                    .unwrap_or_else(|| Box::leak(Box::new(Routes::fixed(config.rib_ip)))),
+            ),
+            ..self
+        })
+    }
+
+    /// The store the switch forwards from. The application must run its updater (`publish`, every
+    /// `PUBLISH_INTERVAL`) and maintenance (`run_active_expire`) as periodic tasks on one core.
+    /// Flows that maintenance times out are counted by `take_timed_out_flows`.
+    pub fn store(&self) -> Option<SharedStore> {
+        self.store
+    }
+
+    /// Counts what the switch forwards for each tenant. The application must sign a report of it
+    /// (`report`, every `REPORT_INTERVAL`) as a periodic task on one core.
+    pub fn usage(&self) -> Option<UsageMeter> {
+        self.usage
+    }
+
+    /// The status objects that the switch answers Gets for. The application must refresh them
+    /// from the switch's store and clock (`refresh`, every `STATUS_INTERVAL`) as a periodic task
+    /// on one core.
+    pub fn status(&self) -> Option<StatusBoard> {
+        self.status
+    }
+
+    /// The clock the switch keeps in sync with the time master
+    pub fn clock(&self) -> Option<&'static Clock> {
+        self.clock
+    }
+
+    fn identity_for(&self, q: &PortQueue) -> Result<PortIdentity> {
+        match self.identity {
+            Some(identity) => {
+                identity.validate(q)?;
+                Ok(identity)
+            }
+            None => Ok(PortIdentity::of_port(q, self.config.node_addr)),
+        }
+    }
+
+    pub fn install(self, q: PortQueue) -> Result<impl Pipeline> {
+        let identity = self.identity_for(&q)?;
+        let GdpSwitch {
+            config,
+            store,
+            flags,
+            clock,
+            prefetcher,
+            prober,
+            usage,
+            admission,
+            status,
+            gossip,
+            routes,
+            announce,
+            ..
+        } = self.build()?;
+        let (store, flags, clock, prefetcher, prober, usage, admission, status, gossip, routes) = (
+            store.unwrap(),
+            flags.unwrap(),
+            clock.unwrap(),
+            prefetcher.unwrap(),
+            prober.unwrap(),
+            usage.unwrap(),
+            admission.unwrap(),
+            status.unwrap(),
+            gossip.unwrap(),
+            routes.unwrap(),
+        );
+
+        if announce {
+            let cert = RtCert::new_wrapped(
+                config.meta,
+                config.private_key,
+                CertDest::IpAddr(config.node_addr),
+                true,
+            )?;
+            register_node(
+                q.clone(),
+                identity,
+                config.meta,
+                vec![cert],
+                config.private_key,
+                config.rib_ip,
+                config.nic_name,
+            );
+        }
+
+        Ok(install_gdp_pipeline(
+            q,
+            switch_pipeline(
+                config.gdp_name,
+                config.meta,
+                config.private_key,
+                store.sync(),
+                config.nic_name,
+                identity,
+                routes,
+                flags,
+                clock,
+                prefetcher,
+                prober,
+                usage,
+                admission,
+                status,
+                gossip,
+                config.debug,
+            ),
+            config.nic_name,
+            identity.ip,
+            flags,
+            config.debug,
+        ))
+    }
+
+    /// Background task sending the RIB queries the prefetcher asks for (when `rib_prefetch` is on).
+    /// Install it alongside the switch on one queue of the same port.
+    pub fn install_prefetch(self, q: PortQueue) -> Result<impl Pipeline> {
+        let switch = self.build()?;
+        let config = switch.config;
+        let identity = switch.identity_for(&q)?;
+        Ok(prefetch_schedule(
+            q,
+            identity,
+            config.gdp_name,
+            switch.routes.unwrap(),
+            switch.store.unwrap().sync(),
+            switch.prefetcher.unwrap(),
+        ))
+    }
+
+    /// Background task probing the routes of names the switch forwards to (when `route_probes` is
+    /// on). Install it alongside the switch on one queue of the same port.
+    pub fn install_probes(self, q: PortQueue) -> Result<impl Pipeline> {
+        let switch = self.build()?;
+        let config = switch.config;
+        let identity = switch.identity_for(&q)?;
+        Ok(probe_schedule(
+            q,
+            identity,
+            config.gdp_name,
+            switch.routes.unwrap(),
+            switch.store.unwrap().sync(),
+            switch.prober.unwrap(),
+            switch.flags.unwrap(),
+        ))
+    }
+
+    /// Background task probing the paths of flows that ask to be admitted (when
+    /// `admission_control` is on). Install it alongside the switch on one queue of the same port.
+    pub fn install_admission(self, q: PortQueue) -> Result<impl Pipeline> {
+        let switch = self.build()?;
+        let identity = switch.identity_for(&q)?;
+        Ok(admission_schedule(
+            q,
+            identity,
+            switch.config.gdp_name,
+            switch.admission.unwrap(),
+            switch.config.debug,
+        ))
+    }
+
+    /// Background task advertising the switch's capabilities to its next hops and its RIB, and
+    /// learning theirs. Install it alongside the switch on one queue of each port it forwards
+    /// out of.
+    pub fn install_heartbeat(self, q: PortQueue) -> Result<impl Pipeline> {
+        let switch = self.build()?;
+        let identity = switch.identity_for(&q)?;
+        Ok(heartbeat_schedule(
+            q,
+            identity,
+            switch.config.gdp_name,
+            switch.routes.unwrap(),
+            switch.store.unwrap(),
+            switch.flags.unwrap(),
+        ))
+    }
+
+    /// Background task telling the switch's neighboring switches of the bindings it learns are
+    /// dead. Install it alongside the switch on one queue of the same port.
+    pub fn install_gossip(self, q: PortQueue) -> Result<impl Pipeline> {
+        let switch = self.build()?;
+        let identity = switch.identity_for(&q)?;
+        Ok(gossip_schedule(
+            q,
+            identity,
+            switch.config.gdp_name,
+            switch.routes.unwrap(),
+            switch.store.unwrap(),
+            switch.gossip.unwrap(),
+        ))
+    }
+
+    /// Background task negotiating dTLS sessions with the peers the switch sends to.
+    /// Install it alongside the switch on one queue of each port it forwards out of.
+    pub fn install_sessions(self, q: PortQueue) -> impl Pipeline {
+        session_schedule(q)
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, ensure, Result};
+use capsule::metrics;
+use metrics_runtime::data::Gauge;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::isolation::Recover;
+
+/// Optional pipeline stages that can be switched on and off while the node is running
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Flag {
+    NackReplyCache,
+    ForwardingCerts,
+    FlowTracking,
+    RibPrefetch,
+    InbandTelemetry,
+    RouteProbes,
+    AdmissionControl,
+    /// Checking the certificates of packets that a switch forwards
+    CertVerify,
+    /// Per-packet accounting on a switch: route cache counters and usage metering
+    Stats,
+    /// The blocklist, the control-plane policer and the switch's work budget
+    Policy,
+    /// Dropping Forwards that a switch already forwarded (see dedup.rs)
+    Dedup,
+}
+
+impl Flag {
+    pub const ALL: &'static [Flag] = &[
+        Flag::NackReplyCache,
+        Flag::ForwardingCerts,
+        Flag::FlowTracking,
+        Flag::RibPrefetch,
+        Flag::InbandTelemetry,
+        Flag::RouteProbes,
+        Flag::AdmissionControl,
+        Flag::CertVerify,
+        Flag::Stats,
+        Flag::Policy,
+        Flag::Dedup,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::NackReplyCache => "nack_reply_cache",
+            Flag::ForwardingCerts => "forwarding_certs",
+            Flag::FlowTracking => "flow_tracking",
+            Flag::RibPrefetch => "rib_prefetch",
+            Flag::InbandTelemetry => "inband_telemetry",
+            Flag::RouteProbes => "route_probes",
+            Flag::AdmissionControl => "admission_control",
+            Flag::CertVerify => "cert_verify",
+            Flag::Stats => "stats",
+            Flag::Policy => "policy",
+            Flag::Dedup => "dedup",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Flag> {
+        ensure!(
+            name != "crypto",
+            "crypto is no longer a flag: encryption is turned off at startup, with `encryption` in \
+             transports.toml"
+        );
+        Flag::ALL
+            .iter()
+            .find(|flag| flag.name() == name)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown feature flag {:?}", name))
+    }
+}
+
+/// How often a sweep checks whether to move on to the next combination
+pub const SWEEP_TICK: Duration = Duration::from_secs(1);
+/// Sweeping more stages than this would take days at any useful dwell time
+const MAX_SWEEP_STAGES: usize = 8;
+
+#[derive(Deserialize, Default)]
+struct SerializedFlags {
+    #[serde(default)]
+    flags: HashMap<String, bool>,
+    sweep: Option<SerializedSweep>,
+}
+
+#[derive(Deserialize)]
+struct SerializedSweep {
+    stages: Vec<String>,
+    #[serde(default = "default_dwell_secs")]
+    dwell_secs: u64,
+}
+
+fn default_dwell_secs() -> u64 {
+    10
+}
+
+struct FlagState {
+    enabled: AtomicBool,
+    // accumulated since the last call to report()
+    packets: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// Registry of feature flags shared by all cores.
+/// Flags are plain atomics so that checking them on the fast path is cheap.
+#[derive(Copy, Clone)]
+pub struct FeatureFlags(&'static [FlagState]);
+
+impl FeatureFlags {
+    pub fn new() -> Self {
+        let states = Flag::ALL
+            .iter()
+            .map(|_| FlagState {
+                enabled: AtomicBool::new(true),
+                packets: AtomicU64::new(0),
+                nanos: AtomicU64::new(0),
+            })
+            .collect::<Vec<_>>();
+        FeatureFlags(Box::leak(states.into_boxed_slice()))
+    }
+
+    fn state(&self, flag: Flag) -> &'static FlagState {
+        &self.0[flag as usize]
+    }
+
+    #[inline]
+    pub fn is_enabled(&self, flag: Flag) -> bool {
+        self.state(flag).enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, flag: Flag, enabled: bool) {
+        self.state(flag).enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn list(&self) -> Vec<(Flag, bool)> {
+        Flag::ALL
+            .iter()
+            .map(|flag| (*flag, self.is_enabled(*flag)))
+            .collect()
+    }
+
+    /// Run `f` if the stage is enabled, recording how much time it took
+    #[inline]
+    pub fn run<T>(&self, flag: Flag, f: impl FnOnce() -> T) -> Option<T> {
+        if !self.is_enabled(flag) {
+            return None;
+        }
+        let start = Instant::now();
+        let out = f();
+        let state = self.state(flag);
+        state.packets.fetch_add(1, Ordering::Relaxed);
+        state
+            .nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        Some(out)
+    }
+
+    /// Publish per-flag state and stage cost to the metrics registry
+    pub fn report(&self) {
+        let mut sink = metrics::global().sink();
+        for flag in Flag::ALL {
+            let state = self.state(*flag);
+            let labels = || vec![("flag", flag.name())];
+            sink.gauge_with_labels("flag.enabled", labels())
+                .record(self.is_enabled(*flag) as i64);
+            sink.counter_with_labels("flag.packets", labels())
+                .record(state.packets.swap(0, Ordering::Relaxed));
+            sink.counter_with_labels("flag.nanos", labels())
+                .record(state.nanos.swap(0, Ordering::Relaxed));
+        }
+    }
+}
+
+/// Runs every combination of some stages being on and off, each for a while, so that the
+/// throughput of each combination can be read off the metrics (flag.enabled says which is
+/// running, and flag.sweep_combination which of the combinations it is)
+pub struct StageSweep {
+    flags: FeatureFlags,
+    stages: Vec<Flag>,
+    dwell: Duration,
+    /// The combination being run (bit i set: stages[i] is off), and since when
+    current: Mutex<Option<(u32, Instant)>>,
+    /// The combination being run, counting from 1
+    combination: Gauge,
+}
+
+impl StageSweep {
+    /// Move on to the next combination once the current one has run for long enough
+    pub fn tick(&self) {
+        if self.stages.is_empty() {
+            return;
+        }
+        let combinations = 1 << self.stages.len();
+        let mut current = self.current.lock().recover();
+        let combination = match *current {
+            Some((_, since)) if since.elapsed() < self.dwell => return,
+            Some((combination, _)) => (combination + 1) % combinations,
+            None => 0,
+        };
+        *current = Some((combination, Instant::now()));
+        self.combination.record(combination as i64 + 1);
+        let settings: Vec<String> = self
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(i, stage)| {
+                let enabled = combination & (1 << i) == 0;
+                self.flags.set(*stage, enabled);
+                format!("{}={}", stage.name(), if enabled { "on" } else { "off" })
+            })
+            .collect();
+        info!(
+            "stage sweep: combination {} of {}: {}",
+            combination + 1,
+            combinations,
+            settings.join(" ")
+        );
+    }
+}
+
+fn read_flags_file() -> Result<SerializedFlags> {
+    match fs::read_to_string("flags.toml") {
+        Ok(content) => Ok(toml::from_str(&content)?),
+        Err(_) => Ok(SerializedFlags::default()),
+    }
+}
+
+pub fn load_flags() -> Result<FeatureFlags> {
+    let flags = FeatureFlags::new();
+    for (name, enabled) in read_flags_file()?.flags {
+        flags.set(Flag::from_name(&name)?, enabled);
+    }
+    Ok(flags)
+}
+
+/// The sweep in the [sweep] section of flags.toml; without one, the sweep does nothing
+pub fn load_stage_sweep(flags: FeatureFlags) -> Result<StageSweep> {
+    let (stages, dwell_secs) = match read_flags_file()?.sweep {
+        Some(sweep) => (sweep.stages, sweep.dwell_secs),
+        None => (Vec::new(), 0),
+    };
+    ensure!(
+        stages.len() <= MAX_SWEEP_STAGES,
+        "cannot sweep more than {} stages",
+        MAX_SWEEP_STAGES
+    );
+    Ok(StageSweep {
+        flags,
+        stages: stages
+            .iter()
+            .map(|name| Flag::from_name(name))
+            .collect::<Result<_>>()?,
+        dwell: Duration::from_secs(dwell_secs),
+        current: Mutex::new(None),
+        combination: metrics::global().sink().gauge("flag.sweep_combination"),
+    })
+}
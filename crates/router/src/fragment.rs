@@ -0,0 +1,311 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+use anyhow::{ensure, Result};
+use capsule::batch::{Batch, Disposition};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::{Ethernet, Packet, Udp};
+use capsule::{metrics, Mbuf};
+use gdp_proto::{new_trace_id, Fragment, GdpHeader, EXT_FRAGMENT};
+use metrics_runtime::data::Counter;
+use serde::Deserialize;
+
+use crate::capabilities::{CIPHER_TAG_LEN, DEFAULT_MAX_MTU};
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::ipv6::IPV6_OVERHEAD;
+use crate::kvs::{Reassembled, Reassembly, ReassemblyLimits};
+use crate::packet_ops::{get_payload, set_payload};
+
+/*
+   GDP packets larger than the MTU of a port are split into fragments on their way out, and put
+   back together by the next node before its pipeline sees them:
+   - a packet is split once its IP packet would not fit the `mtu` of fragment.toml once
+     encrypted. Each fragment has the headers of the whole packet (so the data and telemetry
+     lengths and the content hash are those of the whole), a piece of its payload, and a fragment
+     extension with the packet's fragment ID and where the piece goes
+   - fragments are encrypted and sent like any other packet, one by one
+   - fragments are reassembled right after they are decrypted and parsed, so that the actions,
+     certificates and counters of the pipeline only ever see whole packets. Forwarded packets are
+     split again on the way out, to fit the MTU of the port they leave by
+   - the fragments of one packet share their addresses and ports, so RSS hands them all to one
+     core. Each pipeline reassembles in its own buffer, within the limits of fragment.toml's
+     `[reassembly]`
+   - a whole packet must still fit one mbuf; those that do not are dropped and counted
+*/
+
+type GdpPacket = Gdp<DTls<Ipv4>>;
+
+/// Bytes that the fragment extension adds to a header: its kind, length and value
+const FRAGMENT_EXTENSION_LEN: usize = 2 + 7;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct FragmentConfig {
+    /// Packets are split so that each IP packet is at most this long once encrypted
+    #[serde(default = "default_mtu")]
+    pub mtu: u16,
+    #[serde(default)]
+    pub reassembly: ReassemblyLimits,
+}
+
+fn default_mtu() -> u16 {
+    DEFAULT_MAX_MTU
+}
+
+impl FragmentConfig {
+    /// The MTU that packets leaving a port are split to fit: `mtu`, less what the IPv6 header
+    /// adds on ports that carry IPv6 (see ipv6.rs)
+    pub fn port_mtu(&self, ipv6: bool) -> u16 {
+        if ipv6 {
+            self.mtu.saturating_sub(IPV6_OVERHEAD)
+        } else {
+            self.mtu
+        }
+    }
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        FragmentConfig {
+            mtu: default_mtu(),
+            reassembly: ReassemblyLimits::default(),
+        }
+    }
+}
+
+pub fn load_fragment_config() -> Result<FragmentConfig> {
+    let content = fs::read_to_string("fragment.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+thread_local! {
+    /// The ID of the next packet this core splits. Starts at random, so that cores (and
+    /// restarts) do not reuse the IDs of each other's packets
+    static NEXT_ID: Cell<u32> = Cell::new(new_trace_id() as u32);
+}
+
+fn next_id() -> u32 {
+    NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id.wrapping_add(1));
+        id
+    })
+}
+
+/// A packet with the headers of `packet`, from Ethernet to GDP, and no payload
+fn copy_headers(packet: &GdpPacket) -> Result<GdpPacket> {
+    let headers = packet
+        .mbuf()
+        .read_data_slice::<u8>(0, packet.payload_offset())?;
+    let headers = unsafe { headers.as_ref() };
+    let mut copy = Mbuf::new()?;
+    copy.extend(0, headers.len())?;
+    copy.write_data_slice(0, headers)?;
+    let mut copy = copy
+        .parse::<Ethernet>()?
+        .parse::<Ipv4>()?
+        .parse::<Udp<Ipv4>>()?
+        .parse::<DTls<Ipv4>>()?
+        .parse::<GdpPacket>()?;
+    if let Some(rx_meta) = packet.rx_meta() {
+        copy.set_rx_meta(*rx_meta);
+    }
+    Ok(copy)
+}
+
+fn is_fragment(packet: &GdpPacket) -> Result<bool> {
+    if packet.header_len() == GdpHeader::LEN as usize {
+        return Ok(false);
+    }
+    Ok(Fragment::find(packet.extensions()?)?.is_some())
+}
+
+/// The fragments of `packet` that fit `mtu` once encrypted, or the packet itself if it fits
+fn split(packet: GdpPacket, mtu: usize) -> Result<Vec<GdpPacket>> {
+    let ip_len = packet.envelope().envelope().envelope().len();
+    // fragments are never split again
+    if ip_len + CIPHER_TAG_LEN <= mtu || is_fragment(&packet)? {
+        return Ok(vec![packet]);
+    }
+    let headers_len = ip_len - packet.payload_len() + FRAGMENT_EXTENSION_LEN + CIPHER_TAG_LEN;
+    ensure!(
+        headers_len < mtu,
+        "the headers of packet {:016x} do not fit the MTU of {}",
+        packet.trace_id(),
+        mtu
+    );
+    let room = mtu - headers_len;
+    let payload = get_payload(&packet)?.to_vec();
+    let extensions = packet.extensions()?.to_vec();
+    let id = next_id();
+
+    let mut fragments = payload
+        .chunks(room)
+        .skip(1)
+        .map(|_| copy_headers(&packet))
+        .collect::<Result<Vec<_>>>()?;
+    fragments.insert(0, packet);
+    let count = fragments.len();
+    for (index, (fragment, piece)) in fragments.iter_mut().zip(payload.chunks(room)).enumerate() {
+        set_payload(fragment, piece)?;
+        let mut with_fragment = extensions.clone();
+        with_fragment.push(
+            Fragment {
+                id,
+                offset: u16::try_from(index * room)?,
+                more: index + 1 < count,
+            }
+            .to_extension(),
+        );
+        fragment.set_extensions(&with_fragment)?;
+    }
+    Ok(fragments)
+}
+
+/// Splits the packets that do not fit the MTU into fragments, which follow each other
+pub struct Fragmenter<B: Batch> {
+    batch: B,
+    mtu: usize,
+    /// The fragments of the last packet split, after the first
+    ready: VecDeque<GdpPacket>,
+    split: Counter,
+}
+
+impl<B: Batch<Item = GdpPacket>> Batch for Fragmenter<B> {
+    type Item = GdpPacket;
+
+    #[inline]
+    fn replenish(&mut self) {
+        self.batch.replenish();
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        if let Some(fragment) = self.ready.pop_front() {
+            return Some(Disposition::Act(fragment));
+        }
+        self.batch.next().map(|disp| match disp {
+            Disposition::Act(packet) => match split(packet, self.mtu) {
+                Ok(fragments) => {
+                    if fragments.len() > 1 {
+                        self.split.increment();
+                    }
+                    let mut fragments = fragments.into_iter();
+                    let first = fragments.next().unwrap();
+                    self.ready.extend(fragments);
+                    Disposition::Act(first)
+                }
+                Err(err) => Disposition::Abort(err),
+            },
+            other => other,
+        })
+    }
+}
+
+/// Holds fragments until their packet is whole, and passes on the whole packet in their place
+pub struct Reassemble<B: Batch> {
+    batch: B,
+    /// By the address that sent the fragments, and their ID
+    buffer: Reassembly<(Ipv4Addr, u32), GdpPacket>,
+    reassembled: Counter,
+    refused: Counter,
+    timed_out: Counter,
+    too_large: Counter,
+}
+
+impl<B: Batch<Item = GdpPacket>> Reassemble<B> {
+    /// The packet to pass on in place of `packet`, if any
+    fn take(&mut self, packet: GdpPacket) -> Result<Option<GdpPacket>> {
+        if packet.header_len() == GdpHeader::LEN as usize {
+            return Ok(Some(packet));
+        }
+        let mut extensions = packet.extensions()?.to_vec();
+        let fragment = match Fragment::find(&extensions)? {
+            Some(fragment) => fragment,
+            None => return Ok(Some(packet)),
+        };
+        let key = (packet.envelope().envelope().envelope().src(), fragment.id);
+        let piece = get_payload(&packet)?.to_vec();
+        let offset = fragment.offset as usize;
+        match self
+            .buffer
+            .insert(key, offset, fragment.more, piece, packet, Instant::now())
+        {
+            Reassembled::Waiting => Ok(None),
+            Reassembled::Refused => {
+                self.refused.increment();
+                Ok(None)
+            }
+            Reassembled::Whole(mut packet, payload) => {
+                extensions.retain(|extension| extension.kind != EXT_FRAGMENT);
+                packet.set_extensions(&extensions)?;
+                if let Err(err) = set_payload(&mut packet, &payload) {
+                    self.too_large.increment();
+                    return Err(err);
+                }
+                self.reassembled.increment();
+                Ok(Some(packet))
+            }
+        }
+    }
+}
+
+impl<B: Batch<Item = GdpPacket>> Batch for Reassemble<B> {
+    type Item = GdpPacket;
+
+    #[inline]
+    fn replenish(&mut self) {
+        self.batch.replenish();
+        let timed_out = self.buffer.expire(Instant::now());
+        self.timed_out.record(timed_out as u64);
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        loop {
+            match self.batch.next()? {
+                Disposition::Act(packet) => match self.take(packet) {
+                    Ok(Some(packet)) => return Some(Disposition::Act(packet)),
+                    Ok(None) => continue,
+                    Err(err) => return Some(Disposition::Abort(err)),
+                },
+                other => return Some(other),
+            }
+        }
+    }
+}
+
+fn fragment_counter(nic_name: &'static str, result: &'static str) -> Counter {
+    metrics::global()
+        .sink()
+        .counter_with_labels("fragments", vec![("nic", nic_name), ("result", result)])
+}
+
+pub trait FragmentBatch: Batch<Item = GdpPacket> + Sized {
+    /// Split the packets that would not fit `mtu` once encrypted
+    fn fragment(self, nic_name: &'static str, mtu: u16) -> Fragmenter<Self> {
+        Fragmenter {
+            batch: self,
+            mtu: mtu as usize,
+            ready: VecDeque::new(),
+            split: fragment_counter(nic_name, "split"),
+        }
+    }
+
+    /// Put fragments back together into the packets they were split from
+    fn reassemble(self, nic_name: &'static str, limits: ReassemblyLimits) -> Reassemble<Self> {
+        Reassemble {
+            batch: self,
+            buffer: Reassembly::new(limits),
+            reassembled: fragment_counter(nic_name, "reassembled"),
+            refused: fragment_counter(nic_name, "refused"),
+            timed_out: fragment_counter(nic_name, "timed_out"),
+            too_large: fragment_counter(nic_name, "too_large"),
+        }
+    }
+}
+
+impl<T: Batch<Item = GdpPacket>> FragmentBatch for T {}
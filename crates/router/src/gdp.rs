@@ -1,21 +1,41 @@
 use std::fmt;
 use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
+use std::time::Instant;
 
 use anyhow::{anyhow, Result};
-use capsule::packets::ip::v4::Ipv4;
-use capsule::packets::{Internal, Packet};
+use capsule::packets::ip::IpPacket;
+use capsule::packets::{Ethernet, Internal, Packet};
 use capsule::{ensure, SizeOf};
-use gdp_client::{GdpAction, GdpHeader, GdpName, MAGIC_NUMBERS};
-use serde::{Deserialize, Serialize};
-
-use crate::certificates::Certificate;
-use crate::DTls;
+pub use gdp_proto::CertificateBlock;
+use gdp_proto::{
+    check_magic, content_hash, new_trace_id, parse_extensions, verify_content_hash,
+    write_extensions, GdpAction, GdpHeader, GdpName, HeaderExtension, SequenceNumber, EXT_SEQUENCE,
+    MAGIC_NUMBERS,
+};
+use once_cell::unsync::OnceCell;
+
+use crate::certificates::{CertContents, CertDest, Certificate};
+use crate::dtls::DTls;
+use crate::rxmeta::RxMeta;
+use crate::scratch::with_serialized;
+use crate::telemetry::TelemetryHop;
 
 pub struct Gdp<T: Packet> {
     envelope: T,
     header: NonNull<SizedGdpHeader>,
     offset: usize,
+    rx_meta: Option<RxMeta>,
+    decoded: Decoded,
+}
+
+/// The parts of a packet that are costly to decode, decoded by the first stage that asks for
+/// them and handed to the stages after it along with the packet. Dropped whenever the packet is
+/// changed, through `header_mut` or `envelope_mut` (which `mbuf_mut` goes through)
+#[derive(Default)]
+struct Decoded {
+    extensions: OnceCell<Vec<HeaderExtension>>,
+    certs: OnceCell<CertificateBlock>,
 }
 
 impl<T: Packet> Gdp<T> {
@@ -26,6 +46,7 @@ impl<T: Packet> Gdp<T> {
 
     #[inline]
     fn header_mut(&mut self) -> &mut GdpHeader {
+        self.decoded = Decoded::default();
         unsafe { self.header.as_mut() }
     }
 
@@ -34,6 +55,12 @@ impl<T: Packet> Gdp<T> {
         self.header().action.try_into()
     }
 
+    /// The action byte as sent, including values this version does not know
+    #[inline]
+    pub fn raw_action(&self) -> u8 {
+        self.header().action
+    }
+
     #[inline]
     pub fn set_action(&mut self, action: GdpAction) {
         self.header_mut().action = action as u8;
@@ -69,6 +96,16 @@ impl<T: Packet> Gdp<T> {
         self.header_mut().dst = dst;
     }
 
+    #[inline]
+    pub fn last_hop(&self) -> GdpName {
+        self.header().last_hop
+    }
+
+    #[inline]
+    pub fn set_last_hop(&mut self, last_hop: GdpName) {
+        self.header_mut().last_hop = last_hop;
+    }
+
     #[inline]
     pub fn data_len(&self) -> usize {
         u16::from(self.header().data_len) as usize
@@ -80,51 +117,359 @@ impl<T: Packet> Gdp<T> {
     }
 
     #[inline]
-    pub fn get_certs(&self) -> Result<CertificateBlock> {
-        if self.payload_len() - self.data_len() == 0 {
-            Ok(CertificateBlock {
-                certificates: vec![],
-            })
-        } else {
-            Ok(bincode::deserialize(unsafe {
-                self.mbuf()
-                    .read_data_slice(
-                        self.payload_offset() + self.data_len(),
-                        self.payload_len() - self.data_len(),
-                    )?
-                    .as_ref()
-            })?)
+    pub fn content_hash(&self) -> GdpName {
+        self.header().content_hash
+    }
+
+    /// The data payload, without the certificates and telemetry after it
+    pub fn data(&self) -> Result<&[u8]> {
+        let data = self
+            .mbuf()
+            .read_data_slice(self.payload_offset(), self.data_len())?;
+        Ok(unsafe { data.as_ref() })
+    }
+
+    /// Hash the data payload into the header, so that later hops can check it was not altered
+    pub fn seal_content(&mut self) -> Result<()> {
+        let hash = content_hash(self.data()?);
+        self.header_mut().content_hash = hash;
+        Ok(())
+    }
+
+    pub fn verify_content(&self) -> Result<()> {
+        verify_content_hash(&self.header().content_hash, self.data()?)
+    }
+
+    /// Underlay metadata, if this packet was received from a port (rather than created locally)
+    #[inline]
+    pub fn rx_meta(&self) -> Option<&RxMeta> {
+        self.rx_meta.as_ref()
+    }
+
+    #[inline]
+    pub fn set_rx_meta(&mut self, rx_meta: RxMeta) {
+        self.rx_meta = Some(rx_meta);
+    }
+
+    #[inline]
+    pub fn ingress_port(&self) -> Option<(&'static str, u16)> {
+        self.rx_meta.map(|meta| (meta.port, meta.queue))
+    }
+
+    #[inline]
+    pub fn rx_timestamp(&self) -> Option<Instant> {
+        self.rx_meta.map(|meta| meta.rx_timestamp)
+    }
+
+    #[inline]
+    pub fn burst_len(&self) -> Option<u16> {
+        self.rx_meta.map(|meta| meta.burst_len)
+    }
+
+    #[inline]
+    pub fn rss_hash(&self) -> Option<u32> {
+        self.rx_meta.map(|meta| meta.rss_hash)
+    }
+
+    /// Zero if the origin did not assign one
+    #[inline]
+    pub fn trace_id(&self) -> u64 {
+        u64::from(self.header().trace_id)
+    }
+
+    /// The packet's number in its flow, zero if the origin did not number it or numbered it
+    /// badly
+    pub fn seqno(&self) -> u32 {
+        if self.header_len() == GdpHeader::LEN as usize {
+            return 0;
+        }
+        self.extensions()
+            .ok()
+            .and_then(|extensions| SequenceNumber::find(extensions).ok().flatten())
+            .map_or(0, |number| number.0)
+    }
+
+    /// Leave the packet unnumbered
+    pub fn clear_seqno(&mut self) -> Result<()> {
+        if self.seqno() == 0 {
+            return Ok(());
+        }
+        let extensions = self
+            .extensions()?
+            .iter()
+            .filter(|extension| extension.kind != EXT_SEQUENCE)
+            .cloned()
+            .collect::<Vec<_>>();
+        self.set_extensions(&extensions)
+    }
+
+    /// The header extensions, which follow the fixed header fields
+    pub fn extensions(&self) -> Result<&[HeaderExtension]> {
+        let extensions = self.decoded.extensions.get_or_try_init(|| {
+            let offset = self.offset() + GdpHeader::LEN as usize;
+            let extensions = self
+                .mbuf()
+                .read_data_slice(offset, self.header_len() - GdpHeader::LEN as usize)?;
+            parse_extensions(unsafe { extensions.as_ref() })
+        })?;
+        Ok(extensions)
+    }
+
+    /// Replace the header extensions, growing or shrinking the header to fit
+    pub fn set_extensions(&mut self, extensions: &[HeaderExtension]) -> Result<()> {
+        let written = write_extensions(extensions)?;
+        let offset = self.offset() + GdpHeader::LEN as usize;
+        let old_len = self.header_len() - GdpHeader::LEN as usize;
+        if written.len() > old_len {
+            self.mbuf_mut()
+                .extend(offset + old_len, written.len() - old_len)?;
+        } else if written.len() < old_len {
+            self.mbuf_mut().shrink(offset, old_len - written.len())?;
+        }
+        self.mbuf_mut().write_data_slice(offset, &written)?;
+        self.header_mut().header_len = (GdpHeader::LEN + written.len() as u16).into();
+        Ok(())
+    }
+
+    /// Size of the in-band telemetry section, which comes last in the payload (after the certs)
+    #[inline]
+    pub fn telemetry_len(&self) -> usize {
+        u16::from(self.header().telemetry_len) as usize
+    }
+
+    #[inline]
+    pub fn set_telemetry_len(&mut self, telemetry_len: usize) {
+        self.header_mut().telemetry_len = (telemetry_len as u16).into();
+    }
+
+    /// Size of the serialized certificate block trailing the data. The lengths are the sender's,
+    /// so a packet whose data and telemetry do not fit in its payload has no certificates, and
+    /// fails when either is read
+    #[inline]
+    pub fn cert_len(&self) -> usize {
+        self.payload_len()
+            .saturating_sub(self.data_len() + self.telemetry_len())
+    }
+
+    /// Where the telemetry section starts, if the payload is long enough to hold it
+    fn telemetry_offset(&self) -> Result<usize> {
+        let before = self
+            .payload_len()
+            .checked_sub(self.telemetry_len())
+            .ok_or_else(|| anyhow!("telemetry is longer than the payload"))?;
+        Ok(self.payload_offset() + before)
+    }
+
+    /// The serialized certificate block, empty if the packet has none
+    #[inline]
+    pub fn cert_bytes(&self) -> Result<&[u8]> {
+        if self.cert_len() == 0 {
+            return Ok(&[]);
         }
+        let bytes = self
+            .mbuf()
+            .read_data_slice(self.payload_offset() + self.data_len(), self.cert_len())?;
+        Ok(unsafe { bytes.as_ref() })
+    }
+
+    #[inline]
+    pub fn get_certs(&self) -> Result<&CertificateBlock> {
+        self.decoded.certs.get_or_try_init(|| {
+            if self.cert_len() == 0 {
+                Ok(CertificateBlock {
+                    certificates: vec![],
+                })
+            } else {
+                self.cert_count()?;
+                Ok(bincode::deserialize(self.cert_bytes()?)?)
+            }
+        })
     }
 
     #[inline]
     pub fn set_certs(&mut self, certificates: &CertificateBlock) -> Result<()> {
-        let serialized = bincode::serialize(certificates)?; // todo: avoid allocation, write straight into mbuf!
+        let telemetry = self.get_telemetry()?;
         let cert_offset = self.payload_offset() + self.data_len();
         if self.mbuf().data_len() != cert_offset {
             self.mbuf_mut().truncate(cert_offset)?;
         }
-        if !serialized.is_empty() {
-            self.mbuf_mut().extend(cert_offset, serialized.len())?;
+        self.set_telemetry_len(0);
+        for cert in &certificates.certificates {
+            self.push_cert(cert)?;
+        }
+        if certificates.certificates.is_empty() {
+            // an empty block is still a block, as it was when the whole block was serialized
+            self.write_cert_count(0)?;
+        }
+        if let Some(hops) = telemetry {
+            self.set_telemetry(&hops)?;
+        }
+        Ok(())
+    }
+
+    /// Add `cert` to the end of the certificate block, leaving the certificates already there as
+    /// they are rather than decoding and encoding them again
+    pub fn append_cert(&mut self, cert: &Certificate) -> Result<()> {
+        let telemetry = self.take_telemetry()?;
+        self.push_cert(cert)?;
+        if let Some(hops) = telemetry {
+            self.set_telemetry(&hops)?;
+        }
+        Ok(())
+    }
+
+    /// Append to a certificate block that nothing follows, starting one if there is none
+    fn push_cert(&mut self, cert: &Certificate) -> Result<()> {
+        let count = if self.cert_len() == 0 {
+            self.write_cert_count(0)?;
+            0
+        } else {
+            self.cert_count()?
+        };
+        self.write_cert(cert)?;
+        let count = count
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("certificate block cannot count another certificate"))?;
+        self.write_cert_count(count)
+    }
+
+    /// The count at the start of the certificate block, which cannot be more than the block
+    /// has room for
+    fn cert_count(&self) -> Result<u64> {
+        if self.cert_len() == 0 {
+            return Ok(0);
+        }
+        ensure!(
+            self.cert_len() >= CertBlockPrefix::size_of(),
+            anyhow!("certificate block of {} bytes", self.cert_len())
+        );
+        let prefix = self
+            .mbuf()
+            .read_data::<CertBlockPrefix>(self.payload_offset() + self.data_len())?;
+        let count = u64::from_le_bytes(unsafe { prefix.as_ref() }.count);
+        let room = (self.cert_len() - CertBlockPrefix::size_of()) / MIN_CERT_LEN;
+        ensure!(
+            count <= room as u64,
+            anyhow!(
+                "certificate block counts {} certificates but has room for {}",
+                count,
+                room
+            )
+        );
+        Ok(count)
+    }
+
+    /// Write `cert` at the end of the mbuf, laid out as bincode lays it out. Route certificates
+    /// are written field by field; attestations, whose tags are of any length, are serialized
+    fn write_cert(&mut self, cert: &Certificate) -> Result<()> {
+        let offset = self.mbuf().data_len();
+        let rt_cert = match &cert.contents {
+            CertContents::RtCert(rt_cert) => rt_cert,
+            CertContents::AttrCert(_) => {
+                return with_serialized(cert, |serialized| {
+                    self.mbuf_mut().extend(offset, serialized.len())?;
+                    self.mbuf_mut().write_data_slice(offset, serialized)?;
+                    Ok(())
+                });
+            }
+        };
+        let octets;
+        let (proxy_variant, proxy): (u32, &[u8]) = match &rt_cert.proxy {
+            CertDest::GdpName(name) => (NAME_PROXY_VARIANT, name),
+            CertDest::IpAddr(ip) => {
+                octets = ip.octets();
+                (IP_PROXY_VARIANT, &octets)
+            }
+        };
+        let head = RtCertHead {
+            contents_variant: RT_CERT_VARIANT.to_le_bytes(),
+            base: rt_cert.base,
+            proxy_variant: proxy_variant.to_le_bytes(),
+        };
+        let tail = RtCertTail {
+            expiration_time: rt_cert.expiration_time.to_le_bytes(),
+            bidirectional: rt_cert.bidirectional as u8,
+            signature: cert.signature.into(),
+        };
+        let proxy_offset = offset + RtCertHead::size_of();
+        let tail_offset = proxy_offset + proxy.len();
+        self.mbuf_mut()
+            .extend(offset, tail_offset - offset + RtCertTail::size_of())?;
+        self.mbuf_mut().write_data(offset, &head)?;
+        self.mbuf_mut().write_data_slice(proxy_offset, proxy)?;
+        self.mbuf_mut().write_data(tail_offset, &tail)?;
+        Ok(())
+    }
+
+    /// Write the count at the start of the certificate block, which must start with one already
+    /// unless it is empty
+    fn write_cert_count(&mut self, count: u64) -> Result<()> {
+        let offset = self.payload_offset() + self.data_len();
+        if self.cert_len() == 0 {
+            self.mbuf_mut().extend(offset, CertBlockPrefix::size_of())?;
+        }
+        self.mbuf_mut().write_data(
+            offset,
+            &CertBlockPrefix {
+                count: count.to_le_bytes(),
+            },
+        )?;
+        Ok(())
+    }
+
+    /// The hops recorded so far, if any switch on the path has added telemetry
+    pub fn get_telemetry(&self) -> Result<Option<Vec<TelemetryHop>>> {
+        if self.telemetry_len() == 0 {
+            return Ok(None);
         }
-        self.mbuf_mut().write_data_slice(cert_offset, &serialized)?;
+        let offset = self.telemetry_offset()?;
+        Ok(Some(bincode::deserialize(unsafe {
+            self.mbuf()
+                .read_data_slice(offset, self.telemetry_len())?
+                .as_ref()
+        })?))
+    }
+
+    pub fn set_telemetry(&mut self, hops: &[TelemetryHop]) -> Result<()> {
+        self.take_telemetry()?;
+        let serialized = bincode::serialize(hops)?;
+        let offset = self.payload_offset() + self.payload_len();
+        self.mbuf_mut().extend(offset, serialized.len())?;
+        self.mbuf_mut().write_data_slice(offset, &serialized)?;
+        self.set_telemetry_len(serialized.len());
         Ok(())
     }
+
+    /// Remove the telemetry section, returning the hops it held
+    pub fn take_telemetry(&mut self) -> Result<Option<Vec<TelemetryHop>>> {
+        let hops = self.get_telemetry()?;
+        if hops.is_some() {
+            let offset = self.telemetry_offset()?;
+            self.mbuf_mut().truncate(offset)?;
+            self.set_telemetry_len(0);
+        }
+        Ok(hops)
+    }
 }
 
-impl fmt::Debug for Gdp<DTls<Ipv4>> {
+impl<T: IpPacket<Envelope = Ethernet> + fmt::Debug> fmt::Debug for Gdp<DTls<T>> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let udp = self.envelope().envelope();
-        let ipv4 = udp.envelope();
-        let ethernet = ipv4.envelope();
+        let ip = udp.envelope();
+        let ethernet = ip.envelope();
         f.debug_struct("gdp")
+            .field("trace_id", &format_args!("{:016x}", self.trace_id()))
+            .field("header_len", &self.header_len())
             .field("ttl", &self.ttl())
             .field("action", &self.action())
             .field("src", &self.src())
             .field("dst", &self.dst())
+            .field("last_hop", &self.last_hop())
             .field("data_len", &self.data_len())
+            .field("content_hash", &self.content_hash())
+            .field("telemetry_len", &self.telemetry_len())
             .field("udp_frame", udp)
-            .field("ipv4_frame", ipv4)
+            .field("ip_frame", ip)
             .field("eth_frame", ethernet)
             .finish()
     }
@@ -140,6 +485,7 @@ impl<T: Packet> Packet for Gdp<T> {
 
     #[inline]
     fn envelope_mut(&mut self) -> &mut Self::Envelope {
+        self.decoded = Decoded::default();
         &mut self.envelope
     }
 
@@ -150,7 +496,8 @@ impl<T: Packet> Packet for Gdp<T> {
 
     #[inline]
     fn header_len(&self) -> usize {
-        SizedGdpHeader::size_of()
+        // may be longer than the fields we know about, if the sender is newer than us
+        u16::from(self.header().header_len) as usize
     }
 
     #[inline]
@@ -159,6 +506,8 @@ impl<T: Packet> Packet for Gdp<T> {
             envelope: self.envelope.clone(internal),
             header: self.header,
             offset: self.offset,
+            rx_meta: self.rx_meta,
+            decoded: Decoded::default(),
         }
     }
 
@@ -172,11 +521,15 @@ impl<T: Packet> Packet for Gdp<T> {
             envelope,
             header,
             offset,
+            rx_meta: None,
+            decoded: Decoded::default(),
         };
 
+        check_magic(out.header().field.into())?;
+        let header_len = out.header_len();
         ensure!(
-            u16::from(out.header().field) == MAGIC_NUMBERS,
-            anyhow!("not a GDP packet.")
+            header_len >= SizedGdpHeader::size_of() && offset + header_len <= out.mbuf().data_len(),
+            anyhow!("invalid GDP header length {}", header_len)
         );
 
         Ok(out)
@@ -190,11 +543,16 @@ impl<T: Packet> Packet for Gdp<T> {
         mbuf.extend(offset, SizedGdpHeader::size_of())?;
         let header = mbuf.write_data(offset, &SizedGdpHeader::default())?;
 
-        Ok(Gdp {
+        let mut out = Gdp {
             envelope,
             header,
             offset,
-        })
+            rx_meta: None,
+            decoded: Decoded::default(),
+        };
+        // packets we create are traced from here; forwarded packets keep the ID from their origin
+        out.header_mut().trace_id = new_trace_id().into();
+        Ok(out)
     }
 
     #[inline]
@@ -224,7 +582,140 @@ impl DerefMut for SizedGdpHeader {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct CertificateBlock {
-    pub certificates: Vec<Certificate>,
+/// The start of a certificate block
+#[derive(Clone, Copy, Debug, Default, SizeOf)]
+#[repr(C)]
+struct CertBlockPrefix {
+    count: [u8; 8],
+}
+
+/// The indices that bincode writes for the variants of CertContents and CertDest
+const RT_CERT_VARIANT: u32 = 0;
+const NAME_PROXY_VARIANT: u32 = 0;
+const IP_PROXY_VARIANT: u32 = 1;
+
+/// The fewest bytes a certificate takes in a block: an attestation without tags
+const MIN_CERT_LEN: usize = 4 + 32 + 8 + 8 + 64;
+
+/// A route certificate up to its proxy, which is a GdpName or the octets of an IPv4 address
+#[derive(Clone, Copy, Debug, SizeOf)]
+#[repr(C)]
+struct RtCertHead {
+    contents_variant: [u8; 4],
+    base: GdpName,
+    proxy_variant: [u8; 4],
+}
+
+/// A route certificate after its proxy
+#[derive(Clone, Copy, SizeOf)]
+#[repr(C)]
+struct RtCertTail {
+    expiration_time: [u8; 8],
+    bidirectional: u8,
+    signature: [u8; 64],
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use capsule::net::MacAddr;
+    use capsule::packets::ip::v4::Ipv4;
+    use capsule::Mbuf;
+
+    use super::*;
+    use crate::certificates::{AttrCert, NewAttrCert, NewRtCert, RtCert};
+    use crate::hardcoded_routes::{metadata_of_index, private_key_of_index};
+    use crate::rib::create_control_request;
+
+    fn packet() -> Gdp<DTls<Ipv4>> {
+        create_control_request(
+            Mbuf::new().unwrap(),
+            GdpAction::Forward,
+            b"data",
+            MacAddr::broadcast(),
+            Ipv4Addr::new(10, 0, 0, 1),
+            metadata_of_index(1).hash(),
+            Ipv4Addr::new(10, 0, 0, 2),
+        )
+        .unwrap()
+    }
+
+    fn certs() -> Vec<Certificate> {
+        let meta = metadata_of_index(1);
+        let key = private_key_of_index(1);
+        vec![
+            RtCert::new_wrapped(
+                meta,
+                key,
+                CertDest::GdpName(metadata_of_index(2).hash()),
+                true,
+            )
+            .unwrap(),
+            RtCert::new_wrapped(
+                meta,
+                key,
+                CertDest::IpAddr(Ipv4Addr::new(10, 0, 0, 2)),
+                false,
+            )
+            .unwrap(),
+            AttrCert::new_wrapped(meta, key, vec!["camera".to_owned()]).unwrap(),
+        ]
+    }
+
+    fn block(certificates: Vec<Certificate>) -> CertificateBlock {
+        CertificateBlock { certificates }
+    }
+
+    #[capsule::test]
+    fn writes_certificates_as_bincode_does() {
+        let mut packet = packet();
+        packet.set_certs(&block(certs())).unwrap();
+        assert_eq!(
+            packet.cert_bytes().unwrap(),
+            bincode::serialize(&block(certs())).unwrap()
+        );
+        assert_eq!(packet.get_certs().unwrap().certificates.len(), 3);
+
+        let mut empty = packet();
+        empty.set_certs(&block(vec![])).unwrap();
+        assert_eq!(
+            empty.cert_bytes().unwrap(),
+            bincode::serialize(&block(vec![])).unwrap()
+        );
+    }
+
+    #[capsule::test]
+    fn appends_the_bytes_that_setting_writes() {
+        let mut set = packet();
+        set.set_certs(&block(certs())).unwrap();
+
+        let mut appended = packet();
+        for cert in certs() {
+            appended.append_cert(&cert).unwrap();
+        }
+        assert_eq!(appended.cert_bytes().unwrap(), set.cert_bytes().unwrap());
+
+        let mut after_some = packet();
+        after_some.set_certs(&block(certs()[..1].to_vec())).unwrap();
+        for cert in &certs()[1..] {
+            after_some.append_cert(cert).unwrap();
+        }
+        assert_eq!(after_some.cert_bytes().unwrap(), set.cert_bytes().unwrap());
+    }
+
+    #[capsule::test]
+    fn refuses_counts_beyond_the_block() {
+        let mut packet = packet();
+        packet.set_certs(&block(certs())).unwrap();
+        let offset = packet.payload_offset() + packet.data_len();
+        for count in [4, u64::MAX] {
+            packet
+                .mbuf_mut()
+                .write_data_slice(offset, &count.to_le_bytes())
+                .unwrap();
+            assert!(packet.get_certs().is_err());
+            assert!(packet.append_cert(&certs()[0]).is_err());
+        }
+    }
 }
@@ -1,45 +1,211 @@
+use std::iter;
 use std::net::Ipv4Addr;
 
-use capsule::batch::{Batch, Either, Pipeline, Poll};
+use capsule::batch::{Batch, Pipeline, Poll};
 use capsule::packets::ip::v4::Ipv4;
 use capsule::packets::{Ethernet, Packet, Udp};
-use capsule::PortQueue;
-use gdp_client::GdpAction;
+use capsule::{metrics, PortQueue};
+use gdp_proto::GdpAction;
+use metrics_runtime::data::Counter;
 
-use crate::dtls::{decrypt_gdp, encrypt_gdp, DTls};
+use crate::blocklist::{blocklist, is_packet_blocked};
+use crate::branches::{load_branch_config, ActionDecoder};
+use crate::dtls::session::accept_handshake;
+use crate::dtls::transport::parse_dtls;
+use crate::dtls::DTls;
+use crate::flags::{FeatureFlags, Flag};
+use crate::fragment::{load_fragment_config, FragmentBatch};
 use crate::gdp::Gdp;
+use crate::isolation::CatchPanics;
+use crate::l2filter::{load_l2_config, vlan_of, L2Filter, VlanTx};
+use crate::latency::TimeStage;
+use crate::loopback::LoopBackBatch;
+use crate::missbuffer::MissBufferBatch;
+use crate::offload::{load_crypto_config, AdaptiveCryptoBatch};
 use crate::packet_logging::{LogArrive, LogFail};
 use crate::pipeline::GdpPipeline;
+use crate::priority::{
+    is_marked_control, load_priority_config, mark_priority, ControlPlanePolicer,
+};
+use crate::rxmeta::{next_queue_id, rss_hash, RxClock, RxMeta, StampRx};
+use crate::scratch::ScratchBatch;
+use crate::statistics::{load_sampling_config, StatsSampler};
+use crate::txbatch::{load_tx_config, SendBatched};
+use crate::unknown_action::{load_unknown_action_policy, UnknownActions};
+
+/// Packets and bytes that a pipeline received or sent, by GDP action
+struct ActionCounters {
+    /// Indexed by the action byte; the last entry counts the action bytes this version does not know
+    counters: Vec<(Counter, Counter)>,
+    sampler: StatsSampler,
+}
+
+impl ActionCounters {
+    fn new(nic_name: &'static str, dir: &'static str, sampler: StatsSampler) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counters = |action: String| {
+            let labels = || {
+                vec![
+                    ("nic", nic_name.to_owned()),
+                    ("dir", dir.to_owned()),
+                    ("action", action.clone()),
+                ]
+            };
+            (
+                sink.counter_with_labels("gdp.packets", labels()),
+                sink.counter_with_labels("gdp.bytes", labels()),
+            )
+        };
+        let known = (0..=u8::MAX).map_while(|raw| GdpAction::try_from(raw).ok());
+        let names = known.map(|action| format!("{:?}", action));
+        ActionCounters {
+            counters: names
+                .chain(iter::once("unknown".to_owned()))
+                .map(&mut counters)
+                .collect(),
+            sampler,
+        }
+    }
+
+    #[inline]
+    fn record(&self, packet: &Gdp<DTls<Ipv4>>) {
+        let weight = self.sampler.weight();
+        if weight == 0 {
+            return;
+        }
+        let index = (packet.raw_action() as usize).min(self.counters.len() - 1);
+        let (packets, bytes) = &self.counters[index];
+        packets.record(weight);
+        bytes.record(packet.mbuf().data_len() as u64 * weight);
+    }
+}
 
 pub fn install_gdp_pipeline(
     q: PortQueue,
     gdp_pipeline: impl GdpPipeline,
     nic_name: &'static str,
     node_addr: Ipv4Addr,
+    flags: FeatureFlags,
     debug: bool,
 ) -> impl Pipeline {
+    let tx_config = load_tx_config(nic_name).unwrap_or_default();
+    let crypto_config = load_crypto_config().unwrap_or_default();
+    let priority = load_priority_config().unwrap_or_default();
+    let fragment_config = load_fragment_config().unwrap_or_default();
+    let sampling = load_sampling_config().unwrap_or_default();
+    let queue = next_queue_id();
+    let unknown_actions =
+        UnknownActions::new(load_unknown_action_policy().unwrap_or_default(), nic_name);
+    let actions = ActionDecoder::new(load_branch_config().unwrap_or_default(), nic_name);
+    let rx_clock = RxClock::new();
+    let burst_clock = rx_clock.clone();
+    let policer = ControlPlanePolicer::new(priority, rx_clock.clone(), nic_name);
+    let l2_config = load_l2_config(nic_name).unwrap_or_default();
+    let ipv6_prefix = l2_config.ipv6_prefix;
+    let mtu = fragment_config.port_mtu(ipv6_prefix.is_some());
+    let l2 = L2Filter::new(l2_config, &q, nic_name);
+    let handshake_q = q.clone();
+    let sampler = StatsSampler::new(sampling, rx_clock.clone(), nic_name);
+    let tx_counters = ActionCounters::new(nic_name, "tx", sampler.share());
+    let rx_counters = ActionCounters::new(nic_name, "rx", sampler);
+    let blocked = metrics::global()
+        .sink()
+        .counter_with_labels("blocklist.dropped", vec![("nic", nic_name)]);
+    let blocked_sources = blocked.clone();
     Poll::new(q.clone())
+        .stamp_rx(rx_clock)
+        // the last burst has been sent, so nothing refers to its scratch any more
+        .reset_scratch()
+        .filter(move |packet| l2.admit(packet))
+        // what this core sent to itself, which is ours whatever its L2 addresses
+        .loop_back(nic_name, q.mac_addr(), node_addr)
+        // on IPv6 ports, everything after sees the IPv4 packets the addresses embed
+        .map(move |packet| match ipv6_prefix {
+            Some(prefix) => prefix.receive(packet),
+            None => Ok(packet),
+        })
         .map(|packet| packet.parse::<Ethernet>()?.parse::<Ipv4>())
         .filter(move |packet| packet.dst() == node_addr)
-        .map(|packet| packet.parse::<Udp<Ipv4>>()?.parse::<DTls<Ipv4>>())
-        .map(decrypt_gdp)
-        .map(|packet| packet.parse::<Gdp<DTls<Ipv4>>>())
-        .logarrive(nic_name, "prod", debug)
-        .filter_map(|mut packet| {
-            // Drop if TTL <= 1, otherwise decrement and keep forwarding
-            if packet.ttl() <= 1 {
-                Ok(Either::Drop(packet.reset()))
-            } else {
-                packet.set_ttl(packet.ttl() - 1);
-                Ok(Either::Keep(packet))
+        // before any crypto is spent on them
+        .filter(move |packet| {
+            let blocked_src = flags
+                .run(Flag::Policy, || blocklist().is_blocked(packet.src()))
+                .unwrap_or(false);
+            if blocked_src {
+                blocked.increment();
             }
+            !blocked_src
+        })
+        .map(|packet| parse_dtls(packet.parse::<Udp<Ipv4>>()?))
+        .filter(move |packet| accept_handshake(packet, &handshake_q, debug))
+        .filter(move |packet| {
+            flags
+                .run(Flag::Policy, || policer.admit(packet, debug))
+                .unwrap_or(true)
         })
+        // leaves the envelopes as parsed; they are only reconciled once, by encrypt_adaptive
+        .decrypt_adaptive(crypto_config)
+        .map(move |packet| {
+            let rss_hash = rss_hash(packet.envelope());
+            let vlan = vlan_of(packet.mbuf());
+            let mut packet = packet.parse::<Gdp<DTls<Ipv4>>>()?;
+            packet.set_rx_meta(RxMeta {
+                port: nic_name,
+                queue,
+                rx_timestamp: burst_clock.burst_time(),
+                burst_len: burst_clock.burst_len(),
+                rss_hash,
+                vlan,
+            });
+            Ok(packet)
+        })
+        // so that nothing after sees a fragment
+        .reassemble(nic_name, fragment_config.reassembly)
+        // sources blocked for abuse, before their certificates cost anything
+        .filter(move |packet| {
+            let blocked_src = flags
+                .run(Flag::Policy, || is_packet_blocked(packet))
+                .unwrap_or(false);
+            if blocked_src {
+                blocked_sources.increment();
+            }
+            !blocked_src
+        })
+        .logarrive(nic_name, "prod", debug)
+        .catch_panics(nic_name, "rx")
+        .for_each(move |packet| {
+            rx_counters.record(packet);
+            Ok(())
+        })
+        .filter_map(move |packet| unknown_actions.filter(packet))
+        // already admitted when they first arrived
+        .release_held(node_addr)
+        .time_stage(nic_name, "rx")
         .group_by(
-            |packet| packet.action().unwrap_or(GdpAction::Noop),
+            move |packet| match unknown_actions.action_of(actions.decode(packet.raw_action())) {
+                // data all the same to the switches on the way; see GdpAction::is_data
+                GdpAction::Put | GdpAction::Ack => GdpAction::Forward,
+                action => action,
+            },
             gdp_pipeline,
         )
+        .catch_panics(nic_name, "gdp")
+        .time_stage(nic_name, "gdp")
+        .for_each(move |packet| {
+            tx_counters.record(packet);
+            Ok(())
+        })
+        .fragment(nic_name, mtu)
+        .map(mark_priority)
         .map(|packet| Ok(packet.deparse()))
-        .map(encrypt_gdp)
+        .encrypt_adaptive(crypto_config)
+        .catch_panics(nic_name, "tx")
         .logfail(nic_name, "prod", debug)
-        .send(q)
+        .send_batched(
+            VlanTx::new(q),
+            nic_name,
+            tx_config,
+            priority,
+            is_marked_control,
+        )
 }
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use capsule::batch::Batch;
 
-use crate::inject::Inject;
+use crate::inject::{Inject, InjectIf};
 
 pub trait GdpBatch: Batch {
     fn inject<F>(self, f: F) -> Inject<Self, F>
@@ -11,6 +11,14 @@ pub trait GdpBatch: Batch {
     {
         Inject::new(self, f)
     }
+
+    fn inject_if<F>(self, f: F) -> InjectIf<Self, F>
+    where
+        F: FnMut(&Self::Item) -> Result<Option<Self::Item>>,
+        Self: Sized,
+    {
+        InjectIf::new(self, f)
+    }
 }
 
 impl<T: Batch> GdpBatch for T {}
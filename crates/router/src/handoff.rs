@@ -0,0 +1,86 @@
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::{fs, thread};
+
+use anyhow::{Context, Result};
+use signal_hook::consts::SIGTERM;
+use signal_hook::low_level::raise;
+
+use crate::kvs::{SharedStore, StoreSnapshot};
+
+/*
+   A switch is upgraded by starting the new binary next to the running one, which hands over what
+   it has learned rather than have the new process relearn it from the RIB:
+   - a switch started with a handoff socket first connects to it; a switch already listening there
+     sends a snapshot of its store (routes, next hops, certificates, the flows of local endpoints,
+     operator pins and neighbor capabilities) and stops its runtime, which drains the pipelines
+   - the old process holds the connection open until it exits, so the new one knows that the
+     ports are free once it reads to the end of the snapshot
+   - the new process publishes the snapshot to its store before its runtime starts, then listens
+     on the same socket for the next upgrade
+   Packets that arrive in between, while the new process initializes its ports, are lost.
+*/
+
+/// Take over from the switch listening at `path`, if there is one, once it has exited
+pub fn take_over(path: &Path) -> Result<Option<StoreSnapshot>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(err) => return Err(err).context("failed to connect to the running switch"),
+    };
+    println!("handoff: waiting for the running switch to hand over and exit");
+    let mut snapshot = Vec::new();
+    stream
+        .read_to_end(&mut snapshot)
+        .context("failed to receive state from the running switch")?;
+    let snapshot = StoreSnapshot::decode(&snapshot)
+        .context("the running switch did not hand over its state")?;
+    println!(
+        "handoff: took over {} routes and {} flows",
+        snapshot.routes(),
+        snapshot.flows()
+    );
+    Ok(Some(snapshot))
+}
+
+fn hand_over(mut stream: UnixStream, store: SharedStore) -> Result<()> {
+    let snapshot = bincode::serialize(&store.snapshot())?;
+    stream.write_all(&snapshot)?;
+    println!("handoff: handed over to a new switch, draining");
+    raise(SIGTERM)?;
+    // the successor starts once the connection closes, as this process exits
+    loop {
+        thread::park();
+    }
+}
+
+/// Hand `store` over to the first switch that connects to `path`, then shut down
+pub fn listen_for_successor(path: PathBuf, store: SharedStore) -> Result<()> {
+    // left behind by the process we took over from, or one that crashed
+    if let Err(err) = fs::remove_file(&path) {
+        if err.kind() != ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    let listener = UnixListener::bind(&path).context("failed to bind handoff socket")?;
+    thread::spawn(move || {
+        // hand_over only returns if it failed, in which case we keep forwarding
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(Into::into)
+                .and_then(|stream| hand_over(stream, store));
+            if let Err(err) = result {
+                println!("handoff: failed to hand over: {:#}", err);
+            }
+        }
+    });
+    Ok(())
+}
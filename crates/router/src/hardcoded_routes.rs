@@ -1,21 +1,25 @@
 use std::fs;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Result;
 use capsule::net::MacAddr;
-use gdp_client::GdpName;
+use gdp_proto::GdpName;
 use serde::Deserialize;
 use signatory::ed25519::{SigningKey, VerifyingKey, ALGORITHM_ID};
 use signatory::pkcs8::{FromPrivateKey, PrivateKeyInfo};
 
 use crate::certificates::GdpMeta;
-use crate::rib::{DynamicRoutes, Route, Routes};
+use crate::isolation::Recover;
+use crate::rib::{Route, Routes, StaticRoutes};
 use crate::Env;
 
 #[derive(Deserialize)]
 struct SerializedRoutes {
     rib: Route,
     default: Route,
+    time_master: Option<Route>,
 }
 
 pub trait WithBroadcast<T> {
@@ -28,21 +32,101 @@ impl WithBroadcast<MacAddr> for MacAddr {
     }
 }
 
-pub fn load_routes(env: Env) -> Result<Routes> {
-    let content = fs::read_to_string(match env {
+/*
+   A switch reloads its routes file when it changes, without a restart:
+   - the file's modification time is polled every ROUTES_POLL_INTERVAL, which needs nothing of
+     the platform and is soon enough for a file an operator edits
+   - the new routes replace the old ones whole, so that no core ever sees half of each, and the
+     pipelines use them from their next packet or tick. The routes learned from the RIB and from
+     endpoints are kept
+   - a file that fails to parse is reported and the routes stay as they were, until it changes
+     again
+*/
+
+/// How often the routes file is checked for changes
+pub const ROUTES_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn routes_file(env: Env) -> &'static str {
+    match env {
         Env::Local => "routes.toml",
         Env::Aws => "routes.toml",
         Env::Nuc => "nuc_routes.toml",
-    })?;
+    }
+}
+
+fn read_static_routes(file: &Path) -> Result<StaticRoutes> {
+    let content = fs::read_to_string(file)?;
     let serialized: SerializedRoutes = toml::from_str(&content)?;
 
-    Ok(Routes {
+    Ok(StaticRoutes {
         rib: serialized.rib,
         default: serialized.default,
-        dynamic_routes: RwLock::new(DynamicRoutes::new()),
+        time_master: serialized.time_master.unwrap_or(serialized.rib),
     })
 }
 
+pub fn load_routes(env: Env) -> Result<Routes> {
+    Ok(Routes::new(read_static_routes(Path::new(routes_file(
+        env,
+    )))?))
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|meta| meta.modified()).ok()
+}
+
+/// Reloads the routes file of a node into its routes when the file changes
+pub struct RoutesWatcher {
+    routes: &'static Routes,
+    file: PathBuf,
+    /// The modification time of the file when it was last read
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl RoutesWatcher {
+    /// Watch the file that `routes` were loaded from, which is taken to be unchanged since
+    pub fn new(routes: &'static Routes, env: Env) -> &'static Self {
+        Self::watching(routes, PathBuf::from(routes_file(env)))
+    }
+
+    fn watching(routes: &'static Routes, file: PathBuf) -> &'static Self {
+        let modified = Mutex::new(modified(&file));
+        Box::leak(Box::new(RoutesWatcher {
+            routes,
+            file,
+            modified,
+        }))
+    }
+
+    /// Reload the file if it changed since it was last read
+    pub fn poll(&self) {
+        let mut last = self.modified.lock().recover();
+        let now = modified(&self.file);
+        if now == *last {
+            return;
+        }
+        *last = now;
+        let file = self.file.display();
+        match read_static_routes(&self.file) {
+            Ok(statics) if statics == self.routes.statics() => {}
+            Ok(statics) => {
+                println!(
+                    "reloaded {}: rib {}, default {}, time master {}",
+                    file, statics.rib.ip, statics.default.ip, statics.time_master.ip
+                );
+                self.routes.swap_statics(statics);
+            }
+            Err(err) => println!(
+                "ignoring {}, keeping the routes as they were: {:#}",
+                file, err
+            ),
+        }
+    }
+}
+
+/// The GDP index whose keypair the RIB signs its responses with
+pub const RIB_INDEX: u8 = 4;
+
 pub fn gdp_name_of_index(index: u8) -> GdpName {
     let (_, verify_key) = gen_keypair_u8(index).unwrap();
     GdpMeta {
@@ -74,3 +158,90 @@ fn gen_keypair(seed: &[u8; 32]) -> Result<([u8; 32], VerifyingKey)> {
     let verifying_key = signing_key.verifying_key();
     Ok((seed.to_owned(), verifying_key))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::Ipv4Addr;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn route(ip: [u8; 4]) -> Route {
+        Route {
+            ip: Ipv4Addr::from(ip),
+        }
+    }
+
+    fn routes_toml(rib: [u8; 4], default: [u8; 4]) -> String {
+        format!(
+            "[rib]\nip = \"{}\"\n\n[default]\nip = \"{}\"\n",
+            Ipv4Addr::from(rib),
+            Ipv4Addr::from(default)
+        )
+    }
+
+    /// A watcher of a routes file of its own holding `content`, and the routes it reloads
+    fn watched(test: &str, content: &str) -> (&'static RoutesWatcher, &'static Routes) {
+        let file = env::temp_dir().join(format!(
+            "gdp-routes-test-{}-{}.toml",
+            std::process::id(),
+            test
+        ));
+        fs::write(&file, content).unwrap();
+        let routes = Box::leak(Box::new(Routes::new(read_static_routes(&file).unwrap())));
+        (RoutesWatcher::watching(routes, file), routes)
+    }
+
+    /// Rewrite the watched file, taking it to be newer than when it was read whatever the
+    /// resolution of the filesystem's clock
+    fn edit(watcher: &RoutesWatcher, content: &str) {
+        fs::write(&watcher.file, content).unwrap();
+        *watcher.modified.lock().unwrap() = Some(UNIX_EPOCH);
+    }
+
+    #[test]
+    fn reloads_the_routes_when_the_file_changes() {
+        let (watcher, routes) = watched("reload", &routes_toml([10, 0, 0, 1], [10, 0, 0, 2]));
+        assert!(routes.rib() == route([10, 0, 0, 1]));
+        // the RIB answers time queries unless told otherwise
+        assert!(routes.time_master() == route([10, 0, 0, 1]));
+
+        edit(watcher, &routes_toml([10, 0, 0, 3], [10, 0, 0, 2]));
+        watcher.poll();
+        assert!(routes.rib() == route([10, 0, 0, 3]));
+        assert!(routes.default() == route([10, 0, 0, 2]));
+        fs::remove_file(&watcher.file).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_routes_while_the_file_does_not_parse() {
+        let (watcher, routes) = watched("broken", &routes_toml([10, 0, 0, 1], [10, 0, 0, 2]));
+        edit(watcher, "[rib]\nip = \"not an address\"\n");
+        watcher.poll();
+        assert!(routes.rib() == route([10, 0, 0, 1]));
+        assert!(routes.default() == route([10, 0, 0, 2]));
+
+        // until it is fixed
+        edit(watcher, &routes_toml([10, 0, 0, 1], [10, 0, 0, 4]));
+        watcher.poll();
+        assert!(routes.default() == route([10, 0, 0, 4]));
+        fs::remove_file(&watcher.file).unwrap();
+    }
+
+    #[test]
+    fn reads_the_file_only_once_it_changes() {
+        let (watcher, routes) = watched("unchanged", &routes_toml([10, 0, 0, 1], [10, 0, 0, 2]));
+        let read_at = *watcher.modified.lock().unwrap();
+        routes.swap_statics(StaticRoutes {
+            rib: route([10, 0, 0, 5]),
+            default: route([10, 0, 0, 5]),
+            time_master: route([10, 0, 0, 5]),
+        });
+        // the file is as it was read, so it is not read again
+        watcher.poll();
+        assert_eq!(*watcher.modified.lock().unwrap(), read_at);
+        assert!(routes.rib() == route([10, 0, 0, 5]));
+        fs::remove_file(&watcher.file).unwrap();
+    }
+}
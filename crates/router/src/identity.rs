@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use capsule::net::MacAddr;
+use capsule::PortQueue;
+use serde::Deserialize;
+
+/*
+    Each port can be given the addresses it sends from in ports.toml:
+
+        [eth1]
+        ip = "172.31.14.201"
+        mac = "06:4b:03:8b:83:3b"
+
+    They are used for every packet leaving the port, whether forwarded or generated locally,
+    instead of reflecting whatever destination the incoming packet happened to carry.
+    Ports that are not listed use their own MAC and the node's IP address.
+*/
+const PORTS_FILE: &str = "ports.toml";
+
+#[derive(Deserialize)]
+struct SerializedIdentity {
+    ip: Ipv4Addr,
+    mac: String,
+}
+
+/// The addresses a port sends from
+#[derive(Clone, Copy, Debug)]
+pub struct PortIdentity {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+}
+
+impl PortIdentity {
+    /// The port's own MAC, with the IP address that the node was started with
+    pub fn of_port(q: &PortQueue, ip: Ipv4Addr) -> Self {
+        PortIdentity {
+            ip,
+            mac: q.mac_addr(),
+        }
+    }
+
+    /// Check that we would not be sending from addresses the port does not own
+    pub fn validate(&self, q: &PortQueue) -> Result<()> {
+        ensure!(
+            self.mac == q.mac_addr(),
+            "configured MAC {} does not match the port's MAC {}",
+            self.mac,
+            q.mac_addr()
+        );
+        ensure!(
+            !self.ip.is_unspecified() && !self.ip.is_broadcast() && !self.ip.is_multicast(),
+            "configured IP {} is not a unicast address",
+            self.ip
+        );
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct PortIdentities(HashMap<String, PortIdentity>);
+
+impl PortIdentities {
+    /// The identity to use for `port_name`, validated against the queue it will send from
+    pub fn resolve(
+        &self,
+        port_name: &str,
+        q: &PortQueue,
+        node_addr: Ipv4Addr,
+    ) -> Result<PortIdentity> {
+        let identity = match self.0.get(port_name) {
+            Some(identity) => *identity,
+            None => PortIdentity::of_port(q, node_addr),
+        };
+        identity
+            .validate(q)
+            .with_context(|| format!("invalid identity for port {}", port_name))?;
+        Ok(identity)
+    }
+}
+
+pub fn load_port_identities() -> Result<PortIdentities> {
+    let serialized: HashMap<String, SerializedIdentity> = match fs::read_to_string(PORTS_FILE) {
+        Ok(content) => toml::from_str(&content)?,
+        Err(_) => return Ok(PortIdentities::default()),
+    };
+    serialized
+        .into_iter()
+        .map(|(port_name, identity)| {
+            let mac = identity
+                .mac
+                .parse()
+                .map_err(|_| anyhow!("invalid MAC {} for port {}", identity.mac, port_name))?;
+            Ok((
+                port_name,
+                PortIdentity {
+                    ip: identity.ip,
+                    mac,
+                },
+            ))
+        })
+        .collect::<Result<_>>()
+        .map(PortIdentities)
+}
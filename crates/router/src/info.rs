@@ -0,0 +1,193 @@
+use capsule::config::RuntimeConfig;
+use gdp_proto::{GdpName, NodeInfo, PortInfo};
+use sha2::{Digest, Sha256};
+
+use crate::capabilities::WIRE_VERSION;
+use crate::certificates::GdpMeta;
+use crate::dtls::session::sessions;
+use crate::dtls::{key, using_default_key, CipherSuite};
+use crate::flags::FeatureFlags;
+
+/*
+   Nodes say what they are running, so that an operator can tell at a glance whether two nodes
+   run the same thing: once at startup, as a block of lines in the log, and to the Info command
+   of the control socket, which also reports the flags as they are now. Keys are only ever shown
+   as the first FINGERPRINT_LEN bytes of their SHA-256.
+*/
+
+/// Bytes of a key's SHA-256 that its fingerprint shows
+const FINGERPRINT_LEN: usize = 8;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn fingerprint(key: &[u8]) -> String {
+    hex(&Sha256::digest(key)[..FINGERPRINT_LEN])
+}
+
+fn build_features() -> Vec<String> {
+    [
+        ("switch", cfg!(feature = "switch")),
+        ("catch-panics", cfg!(feature = "catch-panics")),
+        ("sim", cfg!(feature = "sim")),
+        ("latency", cfg!(feature = "latency")),
+        ("name-160", cfg!(feature = "name-160")),
+        ("hash-sha512", cfg!(feature = "hash-sha512")),
+        ("rib-sled", cfg!(feature = "rib-sled")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature.to_owned())
+    .collect()
+}
+
+/// The flags as they are now, by name
+pub fn flag_states(flags: FeatureFlags) -> Vec<(String, bool)> {
+    flags
+        .list()
+        .into_iter()
+        .map(|(flag, enabled)| (flag.name().to_owned(), enabled))
+        .collect()
+}
+
+pub fn node_info(
+    mode: &str,
+    name: GdpName,
+    meta: GdpMeta,
+    config: &RuntimeConfig,
+    flags: FeatureFlags,
+) -> NodeInfo {
+    let version = match option_env!("GDP_BUILD_COMMIT") {
+        Some(commit) => format!("{} ({})", env!("CARGO_PKG_VERSION"), commit),
+        None => env!("CARGO_PKG_VERSION").to_owned(),
+    };
+    let dtls_key = if using_default_key() {
+        "the built-in example key".to_owned()
+    } else {
+        fingerprint(key())
+    };
+    NodeInfo {
+        version,
+        mode: mode.to_owned(),
+        name,
+        build_features: build_features(),
+        flags: flag_states(flags),
+        wire_version: WIRE_VERSION,
+        cipher_suites: CipherSuite::in_bitmap(sessions().suite_bitmap())
+            .map(|suite| format!("{:?}", suite))
+            .collect(),
+        ports: config
+            .ports
+            .iter()
+            .map(|port| PortInfo {
+                name: port.name.clone(),
+                device: port.device.clone(),
+                cores: port.cores.iter().map(|core| core.raw()).collect(),
+                rxd: port.rxd,
+                txd: port.txd,
+            })
+            .collect(),
+        key_fingerprints: vec![
+            ("signing".to_owned(), fingerprint(&meta.pub_key)),
+            ("dtls".to_owned(), dtls_key),
+        ],
+    }
+}
+
+/// Log what the node is running, a line each
+pub fn print_banner(info: &NodeInfo) {
+    println!("node info:");
+    println!("  version: {}", info.version);
+    println!("  mode: {}", info.mode);
+    println!("  name: {}", hex(&info.name));
+    println!("  build features: {}", info.build_features.join(", "));
+    let enabled: Vec<&str> = info
+        .flags
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(flag, _)| &flag[..])
+        .collect();
+    println!("  flags enabled: {}", enabled.join(", "));
+    println!("  wire version: {}", info.wire_version);
+    println!("  cipher suites: {}", info.cipher_suites.join(", "));
+    for port in &info.ports {
+        println!(
+            "  port {}: device {}, cores {:?}, {} rx / {} tx descriptors",
+            port.name, port.device, port.cores, port.rxd, port.txd
+        );
+    }
+    for (key, fingerprint) in &info.key_fingerprints {
+        println!("  {} key: {}", key, fingerprint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::hardcoded_routes::{gdp_name_of_index, metadata_of_index, RIB_INDEX};
+
+    const CONFIG: &str = r#"
+        app_name = "gdp"
+        master_core = 0
+
+        [mempool]
+            capacity = 255
+            cache_size = 16
+
+        [[ports]]
+            name = "eth1"
+            device = "0000:00:08.0"
+            cores = [0, 1]
+            rxd = 512
+            txd = 256
+    "#;
+
+    #[test]
+    fn reports_what_the_node_runs() {
+        let config: RuntimeConfig = toml::from_str(CONFIG).unwrap();
+        let meta = metadata_of_index(RIB_INDEX);
+        let flags = FeatureFlags::new();
+        let info = node_info("switch", gdp_name_of_index(RIB_INDEX), meta, &config, flags);
+
+        assert!(info.version.starts_with(env!("CARGO_PKG_VERSION")));
+        assert_eq!(info.mode, "switch");
+        assert_eq!(info.name, gdp_name_of_index(RIB_INDEX));
+        assert_eq!(info.wire_version, WIRE_VERSION);
+        assert_eq!(info.flags.len(), Flag::ALL.len());
+        assert!(info
+            .flags
+            .iter()
+            .all(|(name, _)| Flag::from_name(name).is_ok()));
+        assert_eq!(info.ports.len(), 1);
+        let port = &info.ports[0];
+        assert_eq!((&port.name[..], &port.device[..]), ("eth1", "0000:00:08.0"));
+        assert_eq!(
+            (&port.cores[..], port.rxd, port.txd),
+            (&[0, 1][..], 512, 256)
+        );
+    }
+
+    #[test]
+    fn shows_keys_only_by_their_fingerprint() {
+        let config: RuntimeConfig = toml::from_str(CONFIG).unwrap();
+        let meta = metadata_of_index(RIB_INDEX);
+        let info = node_info("rib", [0; 32], meta, &config, FeatureFlags::new());
+
+        let fingerprints: Vec<&str> = info
+            .key_fingerprints
+            .iter()
+            .map(|(key, fingerprint)| {
+                // not even the start of a key
+                assert!(!hex(&meta.pub_key).starts_with(&fingerprint[..]));
+                assert!(!hex(key()).starts_with(&fingerprint[..]));
+                &key[..]
+            })
+            .collect();
+        assert_eq!(fingerprints, ["signing", "dtls"]);
+        let signing = &info.key_fingerprints[0].1;
+        assert_eq!(signing.len(), 2 * FINGERPRINT_LEN);
+        assert_eq!(*signing, fingerprint(&meta.pub_key));
+    }
+}
@@ -55,3 +55,59 @@ where
         }
     }
 }
+
+/// Like `Inject`, but `f` may decline to inject anything for a packet
+#[allow(missing_debug_implementations)]
+pub struct InjectIf<B: Batch, F>
+where
+    F: FnMut(&B::Item) -> Result<Option<B::Item>>,
+{
+    batch: B,
+    f: F,
+    slot: Option<B::Item>,
+}
+
+impl<B: Batch, F> InjectIf<B, F>
+where
+    F: FnMut(&B::Item) -> Result<Option<B::Item>>,
+{
+    #[inline]
+    pub fn new(batch: B, f: F) -> Self {
+        InjectIf {
+            batch,
+            f,
+            slot: None,
+        }
+    }
+}
+
+impl<B: Batch, F> Batch for InjectIf<B, F>
+where
+    F: FnMut(&B::Item) -> Result<Option<B::Item>>,
+{
+    type Item = B::Item;
+
+    #[inline]
+    fn replenish(&mut self) {
+        self.batch.replenish();
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<B::Item>> {
+        if let Some(pkt) = self.slot.take() {
+            Some(Disposition::Act(pkt))
+        } else {
+            self.batch.next().map(|disp| match disp {
+                Disposition::Act(packet) => {
+                    (self.f)(&packet).map_or_else(Disposition::Abort, |new| {
+                        self.slot = new;
+                        Disposition::Act(packet)
+                    })
+                }
+                Disposition::Emit => Disposition::Emit,
+                Disposition::Drop(mbuf) => Disposition::Drop(mbuf),
+                Disposition::Abort(err) => Disposition::Abort(err),
+            })
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{metrics, Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpName, NackPayload, NackReason, NAME_LEN};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio_timer::delay_for;
+
+use crate::capabilities::Neighbor;
+use crate::certificates::{sign_data, verify_data, SerializableSignature};
+use crate::dtls::{encrypt_gdp, over_session, DTls};
+use crate::gdp::Gdp;
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::kvs::{SharedStore, Store};
+use crate::l2filter::{egress_of, VlanTx};
+use crate::packet_ops::get_payload;
+use crate::rib::{create_control_request, Routes};
+use crate::schedule::Schedule;
+use crate::FwdTableEntry;
+
+/*
+   A switch that learns that a binding is dead tells its neighboring switches, so that they stop
+   forwarding into it well before their copy of the route expires:
+   - a binding is dead once the RIB withdraws it in a push, or once the switch that a learned
+     route leads to NACKs a packet for the name as having no route, over a dTLS session
+     negotiated with it: under the static key, anyone holding it could forge the NACK. The NACK
+     drops our own copy too, as a withdrawal already does
+   - the dead names are sent in an Invalidate to each next hop that we hear heartbeats from (our
+     neighboring switches), except the RIB and the neighbor we heard them from, batched every
+     GOSSIP_INTERVAL
+   - a neighbor drops its copy only of routes it learned from the RIB, and does not cache the
+     name as having no route: its next packet for the name asks the RIB, which has the last word.
+     Invalidates are signed by the switch sending them. Those from senders that we do not hear
+     heartbeats from are refused before anything else, those longer than MAX_PER_PACKET
+     invalidations take before their signature is checked, and those whose signature the sender's
+     metadata does not check after. A neighbor whose metadata we lack is asked of the RIB, as for
+     Pings, at most once per META_RETRY, and its Invalidates are dropped meanwhile
+   - each invalidation carries the switch that learned it, how long ago (not counting the time
+     on the wire) and how many more hops it may travel, starting from `max_hops` of
+     invalidation.toml. A neighbor passes it on only if it dropped a route for it, it has hops
+     left and it is younger than `max_age_ms`, and takes each origin and name once per
+     `max_age_ms`, so that invalidations do not loop. Beyond MAX_SEEN origins and names taken
+     within `max_age_ms`, further invalidations are ignored until the oldest age out
+*/
+
+/// How often the invalidations learned meanwhile are sent
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(50);
+/// Invalidations waiting to be sent beyond this many are dropped, and left to expire
+const MAX_PENDING: usize = 4096;
+/// Invalidations per Invalidate, so that it fits in a packet
+const MAX_PER_PACKET: usize = 16;
+/// Origins and names remembered as taken, at most
+const MAX_SEEN: usize = 16384;
+/// How often the metadata of a neighbor whose Invalidates we cannot check is asked of the RIB
+const META_RETRY: Duration = Duration::from_secs(1);
+/// Neighbors remembered as having had their metadata asked for, at most
+const MAX_ASKED: usize = 1024;
+
+/// The longest encoded Invalidate, of MAX_PER_PACKET invalidations
+static MAX_INVALIDATE_LEN: Lazy<usize> = Lazy::new(|| {
+    let invalidation = Invalidation {
+        name: [0; NAME_LEN],
+        origin: [0; NAME_LEN],
+        age_ms: 0,
+        hops_left: 0,
+    };
+    let invalidate = Invalidate {
+        invalidations: vec![invalidation; MAX_PER_PACKET],
+        signature: [0; 64].into(),
+    };
+    bincode::serialized_size(&invalidate).map_or(usize::MAX, |len| len as usize)
+});
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct InvalidationConfig {
+    /// How far invalidations travel: 1 tells the switch's neighbors only, 0 no one
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u8,
+    /// Invalidations older than this are neither applied nor passed on
+    #[serde(default = "default_max_age_ms")]
+    pub max_age_ms: u64,
+}
+
+fn default_max_hops() -> u8 {
+    1
+}
+
+fn default_max_age_ms() -> u64 {
+    2000
+}
+
+impl Default for InvalidationConfig {
+    fn default() -> Self {
+        InvalidationConfig {
+            max_hops: default_max_hops(),
+            max_age_ms: default_max_age_ms(),
+        }
+    }
+}
+
+pub fn load_invalidation_config() -> Result<InvalidationConfig> {
+    let content = fs::read_to_string("invalidation.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Invalidation {
+    name: GdpName,
+    /// The switch that learned that the binding is dead
+    origin: GdpName,
+    age_ms: u64,
+    hops_left: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Invalidate {
+    invalidations: Vec<Invalidation>,
+    /// By the switch sending it, over `invalidations`
+    signature: SerializableSignature,
+}
+
+struct Pending {
+    invalidation: Invalidation,
+    queued: Instant,
+    /// The neighbor we heard it from, which is not told again
+    from: Option<Ipv4Addr>,
+}
+
+struct GossipState {
+    config: InvalidationConfig,
+    /// The switch's, which its Invalidates are signed with
+    private_key: [u8; 32],
+    pending: Mutex<Vec<Pending>>,
+    /// When each origin and name was last taken, to take it once per `max_age_ms`
+    seen: Mutex<HashMap<(GdpName, GdpName), Instant>>,
+    /// When the metadata of each sender we could not check was last asked of the RIB
+    asked: Mutex<HashMap<GdpName, Instant>>,
+    learned: Counter,
+    sent: Counter,
+    applied: Counter,
+    ignored: Counter,
+    refused: Counter,
+}
+
+/// Gossips dead bindings to the neighboring switches, and applies theirs. Shared by all cores of
+/// a switch.
+#[derive(Clone, Copy)]
+pub struct Gossip(&'static GossipState);
+
+/// Drop a route learned from the RIB, returning whether there was one; operator pins and
+/// registered endpoints are left alone
+fn forget_learned_route(name: &GdpName, store: Store) -> bool {
+    if store.route_lifetimes.get(name).is_none() {
+        return false;
+    }
+    store.forwarding_table.remove(name);
+    store.next_hops.remove(name);
+    store.route_lifetimes.remove(name);
+    true
+}
+
+impl Gossip {
+    pub fn new(nic_name: &'static str, private_key: [u8; 32]) -> Self {
+        let config = load_invalidation_config().unwrap_or_default();
+        Self::with_config(nic_name, private_key, config)
+    }
+
+    fn with_config(
+        nic_name: &'static str,
+        private_key: [u8; 32],
+        config: InvalidationConfig,
+    ) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut outcomes = |outcome: &'static str| {
+            sink.counter_with_labels(
+                "invalidations",
+                vec![("nic", nic_name), ("outcome", outcome)],
+            )
+        };
+        Gossip(Box::leak(Box::new(GossipState {
+            config,
+            private_key,
+            pending: Mutex::new(Vec::new()),
+            seen: Mutex::new(HashMap::new()),
+            asked: Mutex::new(HashMap::new()),
+            learned: outcomes("learned"),
+            sent: outcomes("sent"),
+            applied: outcomes("applied"),
+            ignored: outcomes("ignored"),
+            refused: outcomes("refused"),
+        })))
+    }
+
+    /// Whether `invalidation` is taken for the first time in `max_age_ms`, remembering it if so.
+    /// Those taken earlier are pruned only once MAX_SEEN are remembered
+    fn first_seen(&self, invalidation: &Invalidation) -> bool {
+        let max_age = Duration::from_millis(self.0.config.max_age_ms);
+        let key = (invalidation.origin, invalidation.name);
+        let mut seen = self.0.seen.lock().recover();
+        if seen
+            .get(&key)
+            .map_or(false, |taken| taken.elapsed() < max_age)
+        {
+            return false;
+        }
+        if seen.len() >= MAX_SEEN && !seen.contains_key(&key) {
+            seen.retain(|_, taken| taken.elapsed() < max_age);
+            if seen.len() >= MAX_SEEN {
+                return false;
+            }
+        }
+        seen.insert(key, Instant::now());
+        true
+    }
+
+    /// Whether `packet` comes from a neighbor that we hear heartbeats from. Those that do not are
+    /// refused, before anything is asked about their sender
+    pub fn from_neighbor(&self, packet: &Gdp<DTls<Ipv4>>, store: Store) -> bool {
+        let from = Neighbor {
+            vlan: packet.rx_meta().and_then(|rx_meta| rx_meta.vlan),
+            ip: packet.envelope().envelope().envelope().src(),
+        };
+        let known = store.peer_capabilities.get(&from).is_some();
+        if !known {
+            self.0.refused.increment();
+        }
+        known
+    }
+
+    /// Whether the metadata of `sender` may be asked of the RIB: once per META_RETRY, remembering
+    /// it if so. Those asked earlier are pruned only once MAX_ASKED are remembered
+    pub fn should_ask_meta(&self, sender: GdpName) -> bool {
+        let mut asked = self.0.asked.lock().recover();
+        if asked
+            .get(&sender)
+            .map_or(false, |at| at.elapsed() < META_RETRY)
+        {
+            return false;
+        }
+        if asked.len() >= MAX_ASKED && !asked.contains_key(&sender) {
+            asked.retain(|_, at| at.elapsed() < META_RETRY);
+            if asked.len() >= MAX_ASKED {
+                return false;
+            }
+        }
+        asked.insert(sender, Instant::now());
+        true
+    }
+
+    fn queue(&self, invalidation: Invalidation, from: Option<Ipv4Addr>) {
+        let mut pending = self.0.pending.lock().recover();
+        if pending.len() < MAX_PENDING {
+            pending.push(Pending {
+                invalidation,
+                queued: Instant::now(),
+                from,
+            });
+        }
+    }
+
+    /// We, `gdp_name`, learned that the bindings of `names` are dead: tell our neighbors
+    pub fn learned(&self, gdp_name: GdpName, names: &[GdpName]) {
+        if self.0.config.max_hops == 0 {
+            return;
+        }
+        for name in names {
+            let invalidation = Invalidation {
+                name: *name,
+                origin: gdp_name,
+                age_ms: 0,
+                hops_left: self.0.config.max_hops,
+            };
+            if self.first_seen(&invalidation) {
+                self.0.learned.increment();
+                self.queue(invalidation, None);
+            }
+        }
+    }
+
+    /// A NACK is on its way back to its sender: if it says that the switch our learned route for
+    /// its destination leads to has no route, over a session with that switch, the binding is dead
+    pub fn handle_nack(
+        &self,
+        packet: &Gdp<DTls<Ipv4>>,
+        gdp_name: GdpName,
+        store: Store,
+        debug: bool,
+    ) -> Result<()> {
+        let reason = NackPayload::parse(get_payload(packet)?).map(|nack| nack.reason);
+        if reason != Some(NackReason::NoRoute) || !over_session(packet.envelope()) {
+            return Ok(());
+        }
+        let name = packet.dst();
+        let from = packet.envelope().envelope().envelope().src();
+        match store.forwarding_table.get(&name) {
+            Some(FwdTableEntry { val: ip, .. }) if ip == from => {}
+            _ => return Ok(()),
+        }
+        if forget_learned_route(&name, store) {
+            if debug {
+                println!("{} has no route for {:?}, dropping ours", from, name);
+            }
+            self.learned(gdp_name, &[name]);
+        }
+        Ok(())
+    }
+
+    /// Apply the invalidations a neighbor sent us, passing on those that still may travel. The
+    /// sender must be a neighbor (see from_neighbor), whose metadata is known
+    pub fn handle_invalidate(
+        &self,
+        packet: &Gdp<DTls<Ipv4>>,
+        store: Store,
+        debug: bool,
+    ) -> Result<()> {
+        let from = Neighbor {
+            vlan: packet.rx_meta().and_then(|rx_meta| rx_meta.vlan),
+            ip: packet.envelope().envelope().envelope().src(),
+        };
+        let payload = get_payload(packet)?;
+        ensure!(
+            payload.len() <= *MAX_INVALIDATE_LEN,
+            "Invalidate from {:?} takes {} bytes",
+            from,
+            payload.len()
+        );
+        let Invalidate {
+            invalidations,
+            signature,
+        } = bincode::deserialize(payload)?;
+        ensure!(
+            invalidations.len() <= MAX_PER_PACKET,
+            "Invalidate from {:?} carries {} invalidations",
+            from,
+            invalidations.len()
+        );
+        let signed = store.gdp_metadata.get(&packet.src()).map_or(false, |meta| {
+            verify_data(&invalidations, signature, &meta).is_ok()
+        });
+        if !signed {
+            self.0.refused.increment();
+            return Ok(());
+        }
+        for invalidation in invalidations {
+            if invalidation.age_ms > self.0.config.max_age_ms || !self.first_seen(&invalidation) {
+                self.0.ignored.increment();
+                continue;
+            }
+            if !forget_learned_route(&invalidation.name, store) {
+                self.0.ignored.increment();
+                continue;
+            }
+            if debug {
+                println!(
+                    "{:?} told us the binding of {:?} is dead, dropping ours",
+                    from, invalidation.name
+                );
+            }
+            self.0.applied.increment();
+            if invalidation.hops_left > 1 {
+                let invalidation = Invalidation {
+                    hops_left: invalidation.hops_left - 1,
+                    ..invalidation
+                };
+                self.queue(invalidation, Some(from.ip));
+            }
+        }
+        Ok(())
+    }
+
+    /// The invalidations queued since the last call that are still young enough to send
+    fn take_pending(&self) -> Vec<(Invalidation, Option<Ipv4Addr>)> {
+        let pending = std::mem::take(&mut *self.0.pending.lock().recover());
+        pending
+            .into_iter()
+            .map(|pending| {
+                let held_ms = pending.queued.elapsed().as_millis() as u64;
+                let invalidation = Invalidation {
+                    age_ms: pending.invalidation.age_ms + held_ms,
+                    ..pending.invalidation
+                };
+                (invalidation, pending.from)
+            })
+            .filter(|(invalidation, _)| invalidation.age_ms <= self.0.config.max_age_ms)
+            .collect()
+    }
+}
+
+fn send_invalidate(
+    q: &PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    peer: Ipv4Addr,
+    invalidate: &Invalidate,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            create_control_request(
+                packet,
+                GdpAction::Invalidate,
+                &bincode::serialize(invalidate)?,
+                src.mac,
+                src.ip,
+                src_gdp_name,
+                peer,
+            )
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q.clone()))
+        .run_once();
+}
+
+/// Send the invalidations learned meanwhile to our neighboring switches, in the background of
+/// the GDP pipeline
+pub fn gossip_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    routes: &'static Routes,
+    store: SharedStore,
+    gossip: Gossip,
+) -> impl Pipeline {
+    let local = store.sync();
+    Schedule::new("invalidation_gossip", async move {
+        loop {
+            let pending = gossip.take_pending();
+            if !pending.is_empty() {
+                let egress = egress_of(src.mac);
+                let rib_ip = routes.rib().ip;
+                for peer in store.next_hop_ips() {
+                    let neighbor = Neighbor {
+                        vlan: egress.and_then(|egress| egress.vlan_to(peer, None)),
+                        ip: peer,
+                    };
+                    // endpoints and the RIB keep no routes to drop
+                    if peer == rib_ip || local.peer_capabilities.get(&neighbor).is_none() {
+                        continue;
+                    }
+                    let invalidations = pending
+                        .iter()
+                        .filter(|(_, from)| *from != Some(peer))
+                        .map(|(invalidation, _)| *invalidation)
+                        .collect::<Vec<_>>();
+                    for chunk in invalidations.chunks(MAX_PER_PACKET) {
+                        let signature = match sign_data(&chunk, gossip.0.private_key) {
+                            Ok(signature) => signature,
+                            Err(err) => {
+                                println!("could not sign an Invalidate: {:#}", err);
+                                continue;
+                            }
+                        };
+                        let invalidate = Invalidate {
+                            invalidations: chunk.to_vec(),
+                            signature,
+                        };
+                        send_invalidate(&q, src, src_gdp_name, peer, &invalidate);
+                        gossip.0.sent.record(chunk.len() as u64);
+                    }
+                }
+            }
+            delay_for(GOSSIP_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use capsule::net::MacAddr;
+
+    use super::*;
+    use crate::capabilities::Capabilities;
+    use crate::flags::FeatureFlags;
+    use crate::hardcoded_routes::{
+        gdp_name_of_index, metadata_of_index, private_key_of_index, WithBroadcast,
+    };
+
+    const NEIGHBOR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn gossip_with(max_hops: u8, max_age_ms: u64) -> Gossip {
+        let config = InvalidationConfig {
+            max_hops,
+            max_age_ms,
+        };
+        Gossip::with_config("test", private_key_of_index(2), config)
+    }
+
+    fn invalidation(index: u8, age_ms: u64, hops_left: u8) -> Invalidation {
+        Invalidation {
+            name: gdp_name_of_index(index),
+            origin: gdp_name_of_index(1),
+            age_ms,
+            hops_left,
+        }
+    }
+
+    #[test]
+    fn takes_each_origin_and_name_once_per_max_age() {
+        let gossip = gossip_with(1, 50);
+        assert!(gossip.first_seen(&invalidation(5, 0, 1)));
+        assert!(!gossip.first_seen(&invalidation(5, 0, 1)));
+        // another name from the same origin is taken
+        assert!(gossip.first_seen(&invalidation(6, 0, 1)));
+
+        // seeing it again does not put off when it is taken again
+        thread::sleep(Duration::from_millis(30));
+        assert!(!gossip.first_seen(&invalidation(5, 0, 1)));
+        thread::sleep(Duration::from_millis(30));
+        assert!(gossip.first_seen(&invalidation(5, 0, 1)));
+    }
+
+    /// An invalidation from index 1 of a name made up from `index`
+    fn of_name(index: usize) -> Invalidation {
+        let mut name = [0; NAME_LEN];
+        name[..8].copy_from_slice(&(index as u64).to_be_bytes());
+        Invalidation {
+            name,
+            ..invalidation(5, 0, 1)
+        }
+    }
+
+    #[test]
+    fn ignores_new_invalidations_while_too_many_are_remembered() {
+        let gossip = gossip_with(1, 60_000);
+        for index in 0..MAX_SEEN {
+            assert!(gossip.first_seen(&of_name(index)));
+        }
+        assert!(!gossip.first_seen(&of_name(MAX_SEEN)));
+
+        // once they have aged out, there is room again
+        let gossip = gossip_with(1, 0);
+        for index in 0..MAX_SEEN {
+            assert!(gossip.first_seen(&of_name(index)));
+        }
+        assert!(gossip.first_seen(&of_name(MAX_SEEN)));
+    }
+
+    #[test]
+    fn sends_pending_invalidations_aged_by_how_long_they_waited() {
+        let gossip = gossip_with(1, 1000);
+        gossip.queue(invalidation(5, 100, 1), Some(NEIGHBOR));
+        // too old by the time it would be sent
+        gossip.queue(invalidation(6, 995, 1), None);
+        thread::sleep(Duration::from_millis(10));
+
+        let pending = gossip.take_pending();
+        assert_eq!(pending.len(), 1);
+        let (taken, from) = pending[0];
+        assert_eq!(taken.name, gdp_name_of_index(5));
+        assert!(taken.age_ms >= 110);
+        assert_eq!(from, Some(NEIGHBOR));
+        // taken once
+        assert!(gossip.take_pending().is_empty());
+    }
+
+    #[test]
+    fn tells_neighbors_only_within_max_hops() {
+        assert!(learned_by(gossip_with(0, 1000)).is_empty());
+        let learned = learned_by(gossip_with(2, 1000));
+        assert_eq!(learned.len(), 1);
+        assert_eq!(learned[0].0.hops_left, 2);
+        assert_eq!(learned[0].0.origin, gdp_name_of_index(2));
+        assert_eq!(learned[0].1, None);
+    }
+
+    /// Have `gossip`, of the switch of index 2, learn that the binding of index 5 is dead, and
+    /// take what it would send
+    fn learned_by(gossip: Gossip) -> Vec<(Invalidation, Option<Ipv4Addr>)> {
+        gossip.learned(gdp_name_of_index(2), &[gdp_name_of_index(5)]);
+        gossip.take_pending()
+    }
+
+    /// A store with a learned route for index 5, that hears heartbeats from NEIGHBOR and knows
+    /// the metadata of index 1
+    fn store_with_route() -> Store {
+        let store = SharedStore::new().sync();
+        let meta = metadata_of_index(1);
+        let neighbor = Neighbor {
+            vlan: None,
+            ip: NEIGHBOR,
+        };
+        let capabilities = Capabilities::local(FeatureFlags::new());
+        store.transaction(|| {
+            store.gdp_metadata.put(meta.hash(), meta);
+            store
+                .peer_capabilities
+                .put(neighbor, FwdTableEntry::new(capabilities, u64::MAX));
+            let name = gdp_name_of_index(5);
+            store.forwarding_table.put(
+                name,
+                FwdTableEntry::new(Ipv4Addr::new(10, 0, 0, 9), u64::MAX),
+            );
+            store
+                .route_lifetimes
+                .put(name, FwdTableEntry::new(60, u64::MAX));
+        });
+        store
+    }
+
+    fn invalidate_from_neighbor(
+        invalidations: Vec<Invalidation>,
+        private_key: [u8; 32],
+    ) -> Gdp<DTls<Ipv4>> {
+        let signature = sign_data(&invalidations, private_key).unwrap();
+        let invalidate = Invalidate {
+            invalidations,
+            signature,
+        };
+        create_control_request(
+            Mbuf::new().unwrap(),
+            GdpAction::Invalidate,
+            &bincode::serialize(&invalidate).unwrap(),
+            MacAddr::broadcast(),
+            NEIGHBOR,
+            gdp_name_of_index(1),
+            Ipv4Addr::new(10, 0, 0, 2),
+        )
+        .unwrap()
+    }
+
+    fn has_route(store: Store) -> bool {
+        let _view = store.pin();
+        store.forwarding_table.get(&gdp_name_of_index(5)).is_some()
+    }
+
+    #[capsule::test]
+    fn passes_on_invalidations_with_hops_left() {
+        let store = store_with_route();
+        let gossip = gossip_with(2, 1000);
+        let packet =
+            invalidate_from_neighbor(vec![invalidation(5, 10, 2)], private_key_of_index(1));
+        gossip.handle_invalidate(&packet, store, false).unwrap();
+        assert!(!has_route(store));
+        let pending = gossip.take_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0.hops_left, 1);
+        // not back to where it came from
+        assert_eq!(pending[0].1, Some(NEIGHBOR));
+    }
+
+    #[capsule::test]
+    fn applies_invalidations_on_their_last_hop_without_passing_them_on() {
+        let store = store_with_route();
+        let gossip = gossip_with(2, 1000);
+        let packet =
+            invalidate_from_neighbor(vec![invalidation(5, 10, 1)], private_key_of_index(1));
+        gossip.handle_invalidate(&packet, store, false).unwrap();
+        assert!(!has_route(store));
+        assert!(gossip.take_pending().is_empty());
+    }
+
+    #[capsule::test]
+    fn ignores_invalidations_older_than_max_age() {
+        let store = store_with_route();
+        let gossip = gossip_with(2, 1000);
+        let packet =
+            invalidate_from_neighbor(vec![invalidation(5, 1001, 2)], private_key_of_index(1));
+        gossip.handle_invalidate(&packet, store, false).unwrap();
+        assert!(has_route(store));
+        assert!(gossip.take_pending().is_empty());
+    }
+
+    #[capsule::test]
+    fn refuses_invalidates_not_signed_by_their_sender() {
+        let store = store_with_route();
+        let gossip = gossip_with(2, 1000);
+        let packet =
+            invalidate_from_neighbor(vec![invalidation(5, 10, 2)], private_key_of_index(3));
+        gossip.handle_invalidate(&packet, store, false).unwrap();
+        assert!(has_route(store));
+        assert!(gossip.take_pending().is_empty());
+    }
+
+    #[capsule::test]
+    fn refuses_invalidates_from_others_than_neighbors() {
+        let gossip = gossip_with(2, 1000);
+        let packet =
+            invalidate_from_neighbor(vec![invalidation(5, 10, 2)], private_key_of_index(1));
+        assert!(gossip.from_neighbor(&packet, store_with_route()));
+        assert!(!gossip.from_neighbor(&packet, SharedStore::new().sync()));
+    }
+
+    #[test]
+    fn asks_for_the_metadata_of_each_sender_once_per_retry() {
+        let gossip = gossip_with(1, 1000);
+        assert!(gossip.should_ask_meta(gdp_name_of_index(1)));
+        assert!(!gossip.should_ask_meta(gdp_name_of_index(1)));
+        // another sender is asked for
+        assert!(gossip.should_ask_meta(gdp_name_of_index(3)));
+    }
+
+    #[capsule::test]
+    fn refuses_invalidates_too_long_before_checking_their_signature() {
+        let store = store_with_route();
+        let gossip = gossip_with(2, 1000);
+        // not signed by their sender either, which would only have them ignored
+        let too_many = (0..=MAX_PER_PACKET).map(of_name).collect();
+        let packet = invalidate_from_neighbor(too_many, private_key_of_index(3));
+        assert!(gossip.handle_invalidate(&packet, store, false).is_err());
+
+        let full = (0..MAX_PER_PACKET).map(of_name).collect();
+        let packet = invalidate_from_neighbor(full, private_key_of_index(1));
+        assert!(gossip.handle_invalidate(&packet, store, false).is_ok());
+    }
+}
@@ -0,0 +1,358 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, ensure, Result};
+use capsule::Mbuf;
+
+use crate::l2filter::ip_header_of;
+
+/*
+   A port with a GDP pipeline can face an IPv6-only network, given an `ipv6_prefix` in its
+   section of l2.toml:
+   - nodes keep their IPv4 addresses as locators. Certificates, routes, the RIB and everything
+     above the port name next hops by IPv4 address, as their wire formats do. On an IPv6 port,
+     each IPv4 address stands for the IPv6 address that embeds it in the last 32 bits of the
+     port's /96 prefix (as in RFC 6052), and the port translates between the two
+   - a frame received in IPv6, from and to addresses under the prefix, has its header replaced by
+     the IPv4 header with the embedded addresses, before anything parses it. Other IPv6 frames
+     (from other prefixes, with extension headers, or not carrying UDP) are dropped. Frames that
+     already are IPv4, such as those the node sends itself, are left alone
+   - everything the port sends has its IPv4 header replaced by the IPv6 one on the way out, after
+     its VLAN tag, with the UDP checksum that IPv6 requires. A checksum computed under IPv4 is
+     translated, which only takes the addresses, and packets sent without one (see txbatch.rs)
+     are summed in full
+   - neighbor discovery is not done: next hops are reached at the MACs they would be over IPv4
+   - the IPv6 header is IPV6_OVERHEAD bytes longer, so GDP packets are fragmented that much
+     short of the `mtu` of fragment.toml
+*/
+
+/// How much longer packets are on IPv6 ports
+pub const IPV6_OVERHEAD: u16 = (IPV6_HEADER_LEN - IPV4_HEADER_LEN) as u16;
+
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const PROTOCOL_UDP: u8 = 17;
+/// Where the checksum is in a UDP header
+const UDP_CHECKSUM_OFFSET: usize = 6;
+/// The fragment offset and more-fragments flag of an IPv4 header
+const IPV4_FRAGMENT_MASK: u16 = 0x3fff;
+/// The don't-fragment flag, as IPv6 routers do not fragment either
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+
+/// The /96 that the IPv4 addresses of an IPv6 port are embedded in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv6Prefix([u8; 12]);
+
+impl Ipv6Prefix {
+    pub fn new(prefix: Ipv6Addr) -> Result<Self> {
+        let octets = prefix.octets();
+        ensure!(
+            octets[12..] == [0; 4],
+            "{} is not a /96 prefix: its last 32 bits are set",
+            prefix
+        );
+        let mut embedding = [0; 12];
+        embedding.copy_from_slice(&octets[..12]);
+        Ok(Ipv6Prefix(embedding))
+    }
+
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = [0; 16];
+        octets[..12].copy_from_slice(&self.0);
+        octets[12..].copy_from_slice(&ip.octets());
+        Ipv6Addr::from(octets)
+    }
+
+    /// The IPv4 address that `ip` embeds, if it is under the prefix
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        let octets = ip.octets();
+        (octets[..12] == self.0)
+            .then(|| Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    }
+
+    /// Turn the IPv6 frame in `mbuf` into the IPv4 frame that stands for it
+    pub fn receive(&self, mut mbuf: Mbuf) -> Result<Mbuf> {
+        let (offset, ether_type) = ip_header_of(&mbuf).ok_or_else(|| anyhow!("runt frame"))?;
+        if ether_type != ETHER_TYPE_IPV6 {
+            return Ok(mbuf);
+        }
+        let ipv6 = unsafe {
+            mbuf.read_data_slice::<u8>(offset, IPV6_HEADER_LEN)?
+                .as_ref()
+        };
+        let header = to_ipv4_header(ipv6, self)?;
+        mbuf.shrink(offset, IPV6_HEADER_LEN - IPV4_HEADER_LEN)?;
+        mbuf.write_data_slice(offset, &header)?;
+        mbuf.write_data_slice(offset - 2, &ETHER_TYPE_IPV4.to_be_bytes())?;
+        // the checksum covered the IPv6 addresses, and IPv4 lets UDP go without one
+        mbuf.write_data_slice(offset + IPV4_HEADER_LEN + UDP_CHECKSUM_OFFSET, &[0u8; 2])?;
+        Ok(mbuf)
+    }
+
+    /// Turn the IPv4 frame in `mbuf` into the IPv6 frame that stands for it
+    pub fn transmit(&self, mbuf: &mut Mbuf) -> Result<()> {
+        let (offset, ether_type) = ip_header_of(mbuf).ok_or_else(|| anyhow!("runt frame"))?;
+        if ether_type != ETHER_TYPE_IPV4 {
+            return Ok(());
+        }
+        let ipv4 = unsafe {
+            mbuf.read_data_slice::<u8>(offset, IPV4_HEADER_LEN)?
+                .as_ref()
+        };
+        let (header, ipv4_len) = to_ipv6_header(ipv4, self)?;
+        // all the pseudo-header under IPv4 has that the one under IPv6 has not
+        let ipv4_addresses = sum(&ipv4[12..20]);
+        if ipv4_len < IPV6_HEADER_LEN {
+            mbuf.extend(offset, IPV6_HEADER_LEN - ipv4_len)?;
+        } else {
+            // options, which IPv6 has no place for
+            mbuf.shrink(offset, ipv4_len - IPV6_HEADER_LEN)?;
+        }
+        mbuf.write_data_slice(offset, &header)?;
+        mbuf.write_data_slice(offset - 2, &ETHER_TYPE_IPV6.to_be_bytes())?;
+
+        let udp_offset = offset + IPV6_HEADER_LEN;
+        let udp_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let sent = unsafe {
+            mbuf.read_data_slice::<u8>(udp_offset + UDP_CHECKSUM_OFFSET, 2)?
+                .as_ref()
+        };
+        let checksum = match u16::from_be_bytes([sent[0], sent[1]]) {
+            0 => {
+                let udp = unsafe { mbuf.read_data_slice::<u8>(udp_offset, udp_len)?.as_ref() };
+                udp_checksum(&header, udp)
+            }
+            ipv4_checksum => translate_udp_checksum(ipv4_checksum, ipv4_addresses, &header),
+        };
+        mbuf.write_data_slice(udp_offset + UDP_CHECKSUM_OFFSET, &checksum.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+/// The one's complement sum of `bytes` as 16-bit words, not yet folded
+fn sum(bytes: &[u8]) -> u64 {
+    bytes
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u64)
+        .sum()
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The checksum of `udp`, a UDP header (with its checksum zeroed) and payload, under `ipv6`
+fn udp_checksum(ipv6: &[u8; IPV6_HEADER_LEN], udp: &[u8]) -> u16 {
+    // the pseudo-header: both addresses, the length and the next header
+    let pseudo = sum(&ipv6[8..40]) + udp.len() as u64 + PROTOCOL_UDP as u64;
+    match !fold(pseudo + sum(udp)) {
+        // zero is for UDP without a checksum, which IPv6 does not allow
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+/// The checksum under `ipv6` of a UDP datagram whose checksum was `checksum` under an IPv4
+/// header whose addresses sum to `ipv4_addresses`. Only the addresses of the pseudo-header
+/// differ, so the datagram is not summed again (RFC 1624)
+fn translate_udp_checksum(checksum: u16, ipv4_addresses: u64, ipv6: &[u8; IPV6_HEADER_LEN]) -> u16 {
+    let datagram = !checksum as u64 + !fold(ipv4_addresses) as u64;
+    match !fold(datagram + sum(&ipv6[8..40])) {
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+fn address(header: &[u8], at: usize) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&header[at..at + 16]);
+    Ipv6Addr::from(octets)
+}
+
+/// The IPv4 header that stands for `ipv6`, an IPv6 header carrying UDP
+fn to_ipv4_header(ipv6: &[u8], prefix: &Ipv6Prefix) -> Result<[u8; IPV4_HEADER_LEN]> {
+    ensure!(
+        ipv6.len() >= IPV6_HEADER_LEN && ipv6[0] >> 4 == 6,
+        "not an IPv6 header"
+    );
+    ensure!(
+        ipv6[6] == PROTOCOL_UDP,
+        "IPv6 next header {} is not UDP",
+        ipv6[6]
+    );
+    let embedded = |at: usize| {
+        let ip = address(ipv6, at);
+        prefix
+            .extract(ip)
+            .ok_or_else(|| anyhow!("{} is not under the port's prefix", ip))
+    };
+    let (src, dst) = (embedded(8)?, embedded(24)?);
+    let payload_len = u16::from_be_bytes([ipv6[4], ipv6[5]]);
+    let total_len = payload_len
+        .checked_add(IPV4_HEADER_LEN as u16)
+        .ok_or_else(|| anyhow!("{} bytes are too many for an IPv4 packet", payload_len))?;
+
+    let mut header = [0; IPV4_HEADER_LEN];
+    header[0] = 0x45;
+    // the traffic class
+    header[1] = (ipv6[0] << 4) | (ipv6[1] >> 4);
+    header[2..4].copy_from_slice(&total_len.to_be_bytes());
+    header[6..8].copy_from_slice(&IPV4_DONT_FRAGMENT.to_be_bytes());
+    // the hop limit
+    header[8] = ipv6[7];
+    header[9] = PROTOCOL_UDP;
+    header[12..16].copy_from_slice(&src.octets());
+    header[16..20].copy_from_slice(&dst.octets());
+    let checksum = !fold(sum(&header));
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    Ok(header)
+}
+
+/// The IPv6 header that stands for `ipv4`, an IPv4 header carrying UDP, and how long `ipv4` is
+/// with its options
+fn to_ipv6_header(ipv4: &[u8], prefix: &Ipv6Prefix) -> Result<([u8; IPV6_HEADER_LEN], usize)> {
+    ensure!(
+        ipv4.len() >= IPV4_HEADER_LEN && ipv4[0] >> 4 == 4,
+        "not an IPv4 header"
+    );
+    let header_len = (ipv4[0] & 0x0f) as usize * 4;
+    ensure!(
+        header_len >= IPV4_HEADER_LEN,
+        "IPv4 header of {} bytes",
+        header_len
+    );
+    ensure!(
+        ipv4[9] == PROTOCOL_UDP,
+        "IPv4 protocol {} is not UDP",
+        ipv4[9]
+    );
+    ensure!(
+        u16::from_be_bytes([ipv4[6], ipv4[7]]) & IPV4_FRAGMENT_MASK == 0,
+        "IPv4 fragments cannot be translated"
+    );
+    let total_len = u16::from_be_bytes([ipv4[2], ipv4[3]]);
+    let payload_len = total_len.checked_sub(header_len as u16).ok_or_else(|| {
+        anyhow!(
+            "IPv4 packet of {} bytes is shorter than its header",
+            total_len
+        )
+    })?;
+    let src = Ipv4Addr::new(ipv4[12], ipv4[13], ipv4[14], ipv4[15]);
+    let dst = Ipv4Addr::new(ipv4[16], ipv4[17], ipv4[18], ipv4[19]);
+
+    let mut header = [0; IPV6_HEADER_LEN];
+    // the version, and the type of service as the traffic class
+    header[0] = 0x60 | (ipv4[1] >> 4);
+    header[1] = ipv4[1] << 4;
+    header[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    header[6] = PROTOCOL_UDP;
+    // the time to live
+    header[7] = ipv4[8];
+    header[8..24].copy_from_slice(&prefix.embed(src).octets());
+    header[24..40].copy_from_slice(&prefix.embed(dst).octets());
+    Ok((header, header_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix() -> Ipv6Prefix {
+        Ipv6Prefix::new("64:ff9b::".parse().unwrap()).unwrap()
+    }
+
+    fn ipv4_header() -> [u8; IPV4_HEADER_LEN] {
+        let mut header = [
+            0x45, 0xb8, 0x00, 0x40, 0x12, 0x34, 0x40, 0x00, 0x3f, 17, 0, 0, 10, 100, 1, 12, 10,
+            100, 1, 13,
+        ];
+        let checksum = !fold(sum(&header));
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn prefixes_are_96_bits() {
+        assert!(Ipv6Prefix::new("64:ff9b::1".parse().unwrap()).is_err());
+        let ip = Ipv4Addr::new(10, 100, 1, 12);
+        let embedded = prefix().embed(ip);
+        assert_eq!(embedded, "64:ff9b::a64:10c".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(prefix().extract(embedded), Some(ip));
+        assert_eq!(prefix().extract("fd00::a64:10c".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn headers_translate_both_ways() {
+        let ipv4 = ipv4_header();
+        let (ipv6, ipv4_len) = to_ipv6_header(&ipv4, &prefix()).unwrap();
+        assert_eq!(ipv4_len, IPV4_HEADER_LEN);
+        // the payload length, the hop limit and the traffic class
+        assert_eq!(u16::from_be_bytes([ipv6[4], ipv6[5]]), 0x40 - 20);
+        assert_eq!(ipv6[7], 0x3f);
+        assert_eq!((ipv6[0] << 4) | (ipv6[1] >> 4), 0xb8);
+        assert_eq!(
+            address(&ipv6, 24),
+            "64:ff9b::a64:10d".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let back = to_ipv4_header(&ipv6, &prefix()).unwrap();
+        // all but the identification
+        assert_eq!(back[..4], ipv4[..4]);
+        assert_eq!(back[6..10], ipv4[6..10]);
+        assert_eq!(back[12..], ipv4[12..]);
+        assert_eq!(fold(sum(&back)), 0xffff);
+    }
+
+    #[test]
+    fn refuses_what_cannot_be_translated() {
+        let (ipv6, _) = to_ipv6_header(&ipv4_header(), &prefix()).unwrap();
+        let other = Ipv6Prefix::new("fd00::".parse().unwrap()).unwrap();
+        assert!(to_ipv4_header(&ipv6, &other).is_err());
+        let mut tcp = ipv6;
+        tcp[6] = 6;
+        assert!(to_ipv4_header(&tcp, &prefix()).is_err());
+
+        let mut fragment = ipv4_header();
+        fragment[6] = 0x20;
+        assert!(to_ipv6_header(&fragment, &prefix()).is_err());
+        let mut short = ipv4_header();
+        short[2..4].copy_from_slice(&10u16.to_be_bytes());
+        assert!(to_ipv6_header(&short, &prefix()).is_err());
+        assert!(to_ipv6_header(&ipv4_header()[..12], &prefix()).is_err());
+    }
+
+    #[test]
+    fn udp_checksums_verify() {
+        let (ipv6, _) = to_ipv6_header(&ipv4_header(), &prefix()).unwrap();
+        // ports 5000 to 31415, a length of 11, no checksum yet, and an odd-length payload
+        let mut udp = vec![0x13, 0x88, 0x7a, 0xb7, 0x00, 0x0b, 0x00, 0x00, 1, 2, 3];
+        let checksum = udp_checksum(&ipv6, &udp);
+        udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+        // a receiver sums everything, checksum included, to all ones
+        let pseudo = sum(&ipv6[8..40]) + udp.len() as u64 + PROTOCOL_UDP as u64;
+        assert_eq!(fold(pseudo + sum(&udp)), 0xffff);
+    }
+
+    #[test]
+    fn translates_udp_checksums_to_those_it_would_compute() {
+        let ipv4 = ipv4_header();
+        let (ipv6, _) = to_ipv6_header(&ipv4, &prefix()).unwrap();
+        for payload in [&[1u8, 2, 3][..], &[0xff; 64], &[]] {
+            let mut udp = vec![0x13, 0x88, 0x7a, 0xb7, 0x00, 0x00, 0x00, 0x00];
+            udp.extend(payload);
+            let len = udp.len() as u16;
+            udp[4..6].copy_from_slice(&len.to_be_bytes());
+            // as the IPv4 packet was checksummed
+            let pseudo = sum(&ipv4[12..20]) + len as u64 + PROTOCOL_UDP as u64;
+            let ipv4_checksum = !fold(pseudo + sum(&udp));
+            assert_eq!(
+                translate_udp_checksum(ipv4_checksum, sum(&ipv4[12..20]), &ipv6),
+                udp_checksum(&ipv6, &udp)
+            );
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::{LockResult, PoisonError};
+
+use capsule::batch::Batch;
+
+/*
+   A panic in one of a pipeline's closures would otherwise unwind through the runtime and take
+   every port down with it. With the `catch-panics` feature, stages are wrapped so that:
+   - the panic is caught where the stage hands its packet to the next one, and the packet that
+     caused it is dropped (its buffer is freed as the closure holding it unwinds)
+   - the panic and a backtrace are logged, and counted by port and stage
+   - the pipeline carries on with the next packet
+   - locks that the panicking stage held are poisoned, and are taken all the same afterwards (see
+     `Recover`): the packet is gone, and what it was changing is left no worse than a lost packet
+     leaves it. Otherwise every packet after it would panic on the lock
+   Without the feature, which is off by default, the wrappers compile away and a panic takes the
+   runtime down.
+*/
+
+#[cfg(feature = "catch-panics")]
+mod catching {
+    use std::any::Any;
+    use std::backtrace::Backtrace;
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Once;
+
+    use anyhow::anyhow;
+    use capsule::batch::{Batch, Disposition};
+    use capsule::metrics;
+    use metrics_runtime::data::Counter;
+    use tracing::error;
+
+    thread_local! {
+        /// How many boundaries the current thread is inside of
+        static CATCHING: Cell<u32> = Cell::new(0);
+    }
+
+    /// Panics caught at a boundary are logged with a backtrace; any others are left to the default hook
+    fn install_panic_hook() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            let default_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if CATCHING.with(|depth| depth.get()) > 0 {
+                    error!("{}\n{}", info, Backtrace::force_capture());
+                } else {
+                    default_hook(info);
+                }
+            }));
+        });
+    }
+
+    fn catching<T>(f: impl FnOnce() -> T) -> Result<T, Box<dyn Any + Send>> {
+        CATCHING.with(|depth| depth.set(depth.get() + 1));
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        CATCHING.with(|depth| depth.set(depth.get() - 1));
+        result
+    }
+
+    fn panic_message(payload: &(dyn Any + Send)) -> &str {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message
+        } else {
+            "unknown panic"
+        }
+    }
+
+    pub struct PanicBoundary<B: Batch> {
+        batch: B,
+        name: &'static str,
+        stage: &'static str,
+        panics: Counter,
+    }
+
+    impl<B: Batch> PanicBoundary<B> {
+        pub fn new(batch: B, name: &'static str, stage: &'static str) -> Self {
+            install_panic_hook();
+            let panics = metrics::global()
+                .sink()
+                .counter_with_labels("pipeline.panics", vec![("nic", name), ("stage", stage)]);
+            PanicBoundary {
+                batch,
+                name,
+                stage,
+                panics,
+            }
+        }
+
+        fn caught(&self, payload: Box<dyn Any + Send>) -> anyhow::Error {
+            self.panics.increment();
+            anyhow!(
+                "{} ({}) panicked: {}",
+                self.name,
+                self.stage,
+                panic_message(&*payload)
+            )
+        }
+    }
+
+    impl<B: Batch> Batch for PanicBoundary<B> {
+        type Item = B::Item;
+
+        #[inline]
+        fn replenish(&mut self) {
+            let batch = &mut self.batch;
+            if let Err(payload) = catching(|| batch.replenish()) {
+                error!("{}", self.caught(payload));
+            }
+        }
+
+        #[inline]
+        fn next(&mut self) -> Option<Disposition<Self::Item>> {
+            let batch = &mut self.batch;
+            match catching(|| batch.next()) {
+                Ok(next) => next,
+                Err(payload) => Some(Disposition::Abort(self.caught(payload))),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "catch-panics")]
+pub use catching::PanicBoundary;
+
+pub trait CatchPanics: Batch + Sized {
+    /// Drop the packet that panics in this stage, or any before it, rather than the runtime
+    #[cfg(feature = "catch-panics")]
+    fn catch_panics(self, name: &'static str, stage: &'static str) -> PanicBoundary<Self> {
+        PanicBoundary::new(self, name, stage)
+    }
+
+    #[cfg(not(feature = "catch-panics"))]
+    #[inline]
+    fn catch_panics(self, _name: &'static str, _stage: &'static str) -> Self {
+        self
+    }
+}
+
+impl<T: Batch> CatchPanics for T {}
+
+/// Take a lock whether or not a panic poisoned it, for state shared across panic boundaries
+pub trait Recover<G> {
+    fn recover(self) -> G;
+}
+
+impl<G> Recover<G> for LockResult<G> {
+    #[inline]
+    fn recover(self) -> G {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -1,21 +1,29 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::net::Ipv4Addr;
 use std::ops::Add;
-use std::sync::RwLock;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use gdp_client::GdpName;
-use lru::LruCache;
+use gdp_proto::{GdpName, RouteDump, RouteSource};
+use serde::{Deserialize, Serialize};
+
+use crate::capabilities::{Capabilities, Neighbor};
+use crate::certificates::{AttrCert, CertContents, Certificate, GdpMeta, RtCert};
+use crate::conntrack::{FlowEntry, FlowKey};
+use crate::dedup::{ForwardedKey, DEDUP_MAX_KEYS, DEDUP_WINDOW};
+use crate::isolation::Recover;
+use crate::rehash::IncrementalMap;
 
-use crate::certificates::{CertContents, Certificate, GdpMeta, RtCert};
 pub trait Expirable {
     fn is_expired(&self) -> bool;
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct FwdTableEntry<T> {
     pub val: T,
     pub expiration_time: u64,
@@ -51,11 +59,189 @@ impl Expirable for Certificate {
                         .unwrap()
                         .add(Duration::from_secs(60 * 60))
             }
+            CertContents::AttrCert(AttrCert {
+                expiration_time, ..
+            }) => {
+                Duration::from_secs(expiration_time)
+                    < SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            }
+        }
+    }
+}
+
+/*
+   Tables are replicated to every core, so that lookups on the packet path never take a lock:
+   - each core reads from its own immutable snapshots of the tables, swapping in the latest ones
+     whenever the store's published epoch moves on (one atomic load per lookup in the steady state)
+   - writes from any core are queued, then applied and published by a single updater task
+     (`SharedStore::publish`), which is the only code that touches the master copies
+   - until its writes have been published, a core overlays them on its snapshot
+     so that it always sees its own updates
+   - every table is published under the same epoch, and a core swaps all of its snapshots at once,
+     so the writes made in a `Store::transaction` are seen together or not at all
+   - `Store::pin` holds a core's snapshots still, for code that reads several tables
+     and must not see a publish land in between
+   - refreshes that lookups make (`SyncCache::refresh`) are not even queued right away: a core
+     keeps them with its snapshots and hands them over when it next swaps them, under the lock it
+     takes for that anyway. The updater publishes a new epoch when any are waiting, so that this
+     happens within a publish
+   - the packet path reads the time from the store (`Store::now_secs`), which the updater sets as
+     it publishes, rather than asking the system clock
+   - the master copies and the overlays grow a few entries at a time (see rehash.rs), so that a
+     route flood never has the updater or a core stop to rehash a whole table
+   - each table is split into SHARDS shards, which the master copy and the snapshots share. A
+     publish copies only the shards that its writes land in, and only if a snapshot still holds
+     them, so that publishing never copies a whole table either
+*/
+
+/// How often the updater publishes queued writes to the per-core replicas
+pub const PUBLISH_INTERVAL: Duration = Duration::from_millis(1);
+/// How many delegations a route dump follows from a name to the route it leads to
+const MAX_DELEGATIONS: usize = 8;
+/// Entries of a master copy that the updater moves to its new table per publish, on top of those
+/// that writes move
+const IDLE_MIGRATE_STEP: usize = 256;
+/// The shards of every table
+const SHARDS: usize = 64;
+
+/// Publication state shared by every table of a store
+struct Generation {
+    epoch: AtomicU64,
+    /// Held by the updater while it publishes, by cores while they swap in new snapshots,
+    /// and by transactions while they queue their writes
+    lock: Mutex<()>,
+    /// Seconds since the epoch, as of the last publish
+    now_secs: AtomicU64,
+    /// Whether a core holds refreshes that it hands over at its next swap
+    refreshed: AtomicBool,
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+trait Swap {
+    fn swap(&self, epoch: u64);
+}
+
+/// A core's snapshots of every table in a store
+struct View {
+    epoch: Cell<u64>,
+    pins: Cell<u32>,
+    tables: RefCell<Vec<Box<dyn Swap>>>,
+}
+
+impl View {
+    fn refresh(&self, generation: &Generation) {
+        if self.pins.get() > 0 || generation.epoch.load(Ordering::Acquire) == self.epoch.get() {
+            return;
+        }
+        let _published = generation.lock.lock().recover();
+        let epoch = generation.epoch.load(Ordering::Acquire);
+        for table in self.tables.borrow().iter() {
+            table.swap(epoch);
+        }
+        self.epoch.set(epoch);
+    }
+}
+
+/// Keeps a core's snapshots from changing until dropped
+pub struct ViewPin(&'static View);
+
+impl Drop for ViewPin {
+    fn drop(&mut self) {
+        self.0.pins.set(self.0.pins.get() - 1);
+    }
+}
+
+enum Op<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+/// A table split into shards by the hash of their keys. Cloning it only shares the shards; a
+/// write copies the shard it lands in if anything else still holds that shard
+struct ShardedTable<K, V> {
+    shards: Vec<Arc<IncrementalMap<K, V>>>,
+}
+
+impl<K, V> Clone for ShardedTable<K, V> {
+    fn clone(&self) -> Self {
+        ShardedTable {
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Copy, V: Clone> ShardedTable<K, V> {
+    fn new() -> Self {
+        ShardedTable {
+            shards: (0..SHARDS)
+                .map(|_| Arc::new(IncrementalMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard_of(k: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        hasher.finish() as usize % SHARDS
+    }
+
+    fn get(&self, k: &K) -> Option<&V> {
+        self.shards[Self::shard_of(k)].get(k)
+    }
+
+    fn insert(&mut self, k: K, v: V) -> Option<V> {
+        Arc::make_mut(&mut self.shards[Self::shard_of(&k)]).insert(k, v)
+    }
+
+    fn remove(&mut self, k: &K) -> Option<V> {
+        let shard = &mut self.shards[Self::shard_of(k)];
+        if shard.get(k).is_none() {
+            return None;
+        }
+        Arc::make_mut(shard).remove(k)
+    }
+
+    /// Keep the entries that `keep` picks, copying only the shards that lose some
+    fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        for shard in &mut self.shards {
+            if shard.iter().all(|(k, v)| keep(k, v)) {
+                continue;
+            }
+            Arc::make_mut(shard).retain(|k, v| keep(k, v));
         }
     }
+
+    /// Move along the migrations under way, `budget` entries per shard
+    fn migrate(&mut self, budget: usize) {
+        for shard in &mut self.shards {
+            if shard.is_migrating() {
+                Arc::make_mut(shard).migrate(budget);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
 }
 
-pub struct SharedCache<K, V>(&'static RwLock<HashMap<K, V>>)
+struct Replicated<K, V> {
+    generation: &'static Generation,
+    published: Mutex<ShardedTable<K, V>>,
+    pending: Mutex<Vec<Op<K, V>>>,
+    master: Mutex<ShardedTable<K, V>>,
+}
+
+pub struct SharedCache<K, V>(&'static Replicated<K, V>)
 where
     K: 'static,
     V: 'static;
@@ -67,12 +253,101 @@ impl<K, V> Clone for SharedCache<K, V> {
     }
 }
 
+impl<K: Eq + Hash + Copy, V: Clone> SharedCache<K, V> {
+    fn new(generation: &'static Generation) -> Self {
+        Self(Box::leak(Box::new(Replicated {
+            generation,
+            published: Mutex::new(ShardedTable::new()),
+            pending: Mutex::new(Vec::new()),
+            master: Mutex::new(ShardedTable::new()),
+        })))
+    }
+
+    fn queue(&self, op: Op<K, V>) {
+        self.0.pending.lock().recover().push(op);
+    }
+}
+
+impl<K, V> SharedCache<K, V>
+where
+    K: Eq + Hash + Copy + Debug,
+    V: Clone + Debug,
+{
+    /// Must be called with the generation lock held
+    fn sync(&self, view: &'static View) -> SyncCache<K, V> {
+        let cache = SyncCache {
+            replica: Box::leak(Box::new(RefCell::new(Replica {
+                table: self.0.published.lock().recover().clone(),
+                overlay: IncrementalMap::new(),
+                refreshed: Vec::new(),
+            }))),
+            shared: self.0,
+            view,
+        };
+        view.tables.borrow_mut().push(Box::new(cache));
+        cache
+    }
+}
+
 impl<K, V> SharedCache<K, V>
 where
     K: Eq + Hash + Copy,
-    V: Expirable,
+    V: Clone,
 {
-    fn run_active_expire(&self) {
+    /// Apply queued writes to the master copy and publish a snapshot of it, if anything changed.
+    /// Must only be called from the updater task, with the generation lock held.
+    fn publish(&self) -> bool {
+        let ops = std::mem::take(&mut *self.0.pending.lock().recover());
+        let mut master = self.0.master.lock().recover();
+        // a migration of the master copy also moves along between writes, so that it ends
+        master.migrate(IDLE_MIGRATE_STEP);
+        if ops.is_empty() {
+            return false;
+        }
+        for op in ops {
+            match op {
+                Op::Insert(k, v) => {
+                    master.insert(k, v);
+                }
+                Op::Remove(k) => {
+                    master.remove(&k);
+                }
+            }
+        }
+        *self.0.published.lock().recover() = master.clone();
+        true
+    }
+
+    /// Every published entry, expired or not
+    fn snapshot(&self) -> Vec<(K, V)> {
+        let published = self.0.published.lock().recover();
+        published.iter().map(|(k, v)| (*k, v.clone())).collect()
+    }
+
+    fn restore(&self, entries: Vec<(K, V)>) {
+        for (k, v) in entries {
+            self.queue(Op::Insert(k, v));
+        }
+    }
+
+    fn publish_master(&self) {
+        let master = self.0.master.lock().recover();
+        *self.0.published.lock().recover() = master.clone();
+    }
+
+    /// The value as last published, for code outside the packet path that has no replica
+    pub fn get_unchecked(&self, k: &K) -> Option<V> {
+        self.0.published.lock().recover().get(k).cloned()
+    }
+}
+
+impl<K, V> SharedCache<K, V>
+where
+    K: Eq + Hash + Copy,
+    V: Clone + Expirable,
+{
+    /// Must be called with the generation lock held; returns whether anything was removed
+    fn run_active_expire(&self) -> bool {
         /* This actively expires keys using a probabilistic algorithm used by Redis.
            "Specifically this is what Redis does 10 times per second:
             - Test 20 random keys from the set of keys with an associated expire.
@@ -84,65 +359,121 @@ where
         */
         const ACTIVE_EXPIRE_CUTOFF: usize = 20;
         let mut expired_proportion = 1.0;
+        let mut any_removed = false;
 
-        let mut global_table = self.0.write().unwrap();
-        while expired_proportion > 0.25 {
-            // println!(
-            //     "{:?} running active expire on table {:?}",
-            //     SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
-            //     global_table
-            // );
-            let initial_len = global_table.len();
-            if initial_len <= ACTIVE_EXPIRE_CUTOFF {
-                return;
-            }
+        {
+            let mut global_table = self.0.master.lock().recover();
+            while expired_proportion > 0.25 {
+                let initial_len = global_table.len();
+                if initial_len <= ACTIVE_EXPIRE_CUTOFF {
+                    break;
+                }
 
-            let sampled_expired_keys = global_table
-                .iter()
-                .take(ACTIVE_EXPIRE_CUTOFF)
-                .filter(|(_, v)| v.is_expired())
-                .map(|(k, _)| *k)
-                .collect::<Vec<_>>();
+                let sampled_expired_keys = global_table
+                    .iter()
+                    .take(ACTIVE_EXPIRE_CUTOFF)
+                    .filter(|(_, v)| v.is_expired())
+                    .map(|(k, _)| *k)
+                    .collect::<Vec<_>>();
+
+                let removed_count = sampled_expired_keys.len();
+                any_removed |= removed_count > 0;
 
-            let removed_count = sampled_expired_keys.len();
+                for key in sampled_expired_keys {
+                    global_table.remove(&key);
+                }
 
-            for key in sampled_expired_keys {
-                global_table.remove_entry(&key);
+                expired_proportion = removed_count as f64 / initial_len as f64;
             }
+        }
 
-            expired_proportion = removed_count as f64 / initial_len as f64;
+        if any_removed {
+            self.publish_master();
         }
+        any_removed
     }
+
+    /// Must be called with the generation lock held; returns how many entries were removed
+    fn remove_expired(&self) -> usize {
+        let removed = {
+            let mut master = self.0.master.lock().recover();
+            let initial_len = master.len();
+            master.retain(|_, v| !v.is_expired());
+            initial_len - master.len()
+        };
+        if removed > 0 {
+            self.publish_master();
+        }
+        removed
+    }
+
+    /// Queues a write, which every core sees once the updater has published it
+    pub fn insert(&self, k: K, v: V) {
+        self.queue(Op::Insert(k, v));
+    }
+
+    /// Queues a removal, returning the value as last published
+    pub fn remove(&self, k: &K) -> Option<V> {
+        let current = self.get_unchecked(k);
+        self.queue(Op::Remove(*k));
+        current
+    }
+
+    pub fn entries(&self) -> Vec<(K, V)> {
+        let published = self.0.published.lock().recover();
+        published
+            .iter()
+            .filter(|(_, v)| !v.is_expired())
+            .map(|(k, v)| (*k, v.clone()))
+            .collect()
+    }
+}
+
+struct Replica<K, V> {
+    table: ShardedTable<K, V>,
+    /// Our own writes (None for removals), with the epoch that was current when we made them
+    overlay: IncrementalMap<K, (Option<V>, u64)>,
+    /// Refreshes not yet handed to the updater, which are in the overlay too
+    refreshed: Vec<(K, V)>,
 }
 
+/// A core's read-mostly view of a SharedCache
 pub struct SyncCache<K, V>
 where
     K: 'static,
     V: 'static,
 {
-    local: &'static RefCell<LruCache<K, V>>,
-    global: &'static RwLock<HashMap<K, V>>,
+    replica: &'static RefCell<Replica<K, V>>,
+    shared: &'static Replicated<K, V>,
+    view: &'static View,
 }
 
 impl<K, V> Copy for SyncCache<K, V> {}
 impl<K, V> Clone for SyncCache<K, V> {
     fn clone(&self) -> Self {
         SyncCache {
-            local: self.local,
-            global: self.global,
+            replica: self.replica,
+            shared: self.shared,
+            view: self.view,
         }
     }
 }
 
-impl<K: Eq + Hash, V> SharedCache<K, V> {
-    fn new() -> Self {
-        Self(Box::leak(Box::new(RwLock::new(HashMap::new()))))
-    }
-
-    fn sync(&self) -> SyncCache<K, V> {
-        SyncCache {
-            local: Box::leak(Box::new(RefCell::new(LruCache::new(500)))),
-            global: self.0,
+impl<K, V> Swap for SyncCache<K, V> {
+    fn swap(&self, epoch: u64) {
+        let mut replica = self.replica.borrow_mut();
+        replica.table = self.shared.published.lock().recover().clone();
+        // a write queued during epoch n is applied by the updater no later than epoch n + 2
+        replica
+            .overlay
+            .retain(|_, (_, written)| *written + 2 > epoch);
+        if !replica.refreshed.is_empty() {
+            let refreshed = std::mem::take(&mut replica.refreshed);
+            let mut pending = self.shared.pending.lock().recover();
+            for (k, v) in refreshed {
+                replica.overlay.insert(k, (Some(v.clone()), epoch));
+                pending.push(Op::Insert(k, v));
+            }
         }
     }
 }
@@ -153,29 +484,65 @@ where
     V: Clone + Debug,
 {
     pub fn get_unchecked(&self, k: &K) -> Option<V> {
-        let mut m = self.local.borrow_mut();
-        if m.contains(k) {
-            return m.get(k).cloned();
+        self.view.refresh(self.shared.generation);
+        let replica = self.replica.borrow();
+        match replica.overlay.get(k) {
+            Some((v, _)) => v.clone(),
+            None => replica.table.get(k).cloned(),
         }
+    }
 
-        // Otherwise must hit global cache and update local
-        let g_opt = self.global.read().unwrap().get(k).cloned();
-        if let Some(ref o) = g_opt {
-            m.put(*k, o.clone());
-        }
-        g_opt
+    fn write(&self, k: K, v: Option<V>) {
+        let epoch = self.shared.generation.epoch.load(Ordering::Acquire);
+        self.replica
+            .borrow_mut()
+            .overlay
+            .insert(k, (v.clone(), epoch));
+        let op = match v {
+            Some(v) => Op::Insert(k, v),
+            None => Op::Remove(k),
+        };
+        self.shared.pending.lock().recover().push(op);
     }
 
     pub fn put(&self, k: K, v: V) {
-        if !self.local.borrow().contains(&k) {
-            self.local.borrow_mut().put(k, v.clone());
-            self.global.write().unwrap().insert(k, v);
+        if self.get_unchecked(&k).is_none() {
+            self.write(k, Some(v));
         }
     }
 
-    fn remove(&self, &k: &K) {
-        self.local.borrow_mut().pop(&k);
-        self.global.write().unwrap().remove_entry(&k);
+    /// Unlike put, always overwrites an existing entry (e.g. to extend its expiration time)
+    pub fn update(&self, k: K, v: V) {
+        self.write(k, Some(v));
+    }
+
+    pub fn remove(&self, &k: &K) {
+        self.write(k, None);
+    }
+
+    /// Like update, but for the packet path: takes no lock, as the write reaches the updater
+    /// with this core's next swap. Not part of any transaction.
+    pub fn refresh(&self, k: K, v: V) {
+        let epoch = self.shared.generation.epoch.load(Ordering::Acquire);
+        let mut replica = self.replica.borrow_mut();
+        replica.overlay.insert(k, (Some(v.clone()), epoch));
+        replica.refreshed.push((k, v));
+        self.shared
+            .generation
+            .refreshed
+            .store(true, Ordering::Release);
+    }
+
+    fn capture(&self) -> CapturedTable<K, V> {
+        let replica = self.replica.borrow();
+        CapturedTable {
+            table: replica.table.clone(),
+            overlay: replica
+                .overlay
+                .iter()
+                .map(|(k, (v, _))| (*k, v.clone()))
+                .collect(),
+        }
     }
 }
 
@@ -194,6 +561,157 @@ where
         }
     }
 }
+
+/// The contents of every table in a store, as handed from one switch process to the next
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoreSnapshot {
+    forwarding_table: Vec<(GdpName, FwdTableEntry<Ipv4Addr>)>,
+    next_hops: Vec<(GdpName, FwdTableEntry<GdpName>)>,
+    nack_reply_cache: Vec<(GdpName, FwdTableEntry<Ipv4Addr>)>,
+    gdp_metadata: Vec<(GdpName, GdpMeta)>,
+    route_certs: Vec<(GdpName, Certificate)>,
+    flows: Vec<(FlowKey, FwdTableEntry<FlowEntry>)>,
+    negative_routes: Vec<(GdpName, FwdTableEntry<()>)>,
+    prefetched: Vec<(GdpName, FwdTableEntry<()>)>,
+    pinned_routes: Vec<(GdpName, FwdTableEntry<Ipv4Addr>)>,
+    failed_next_hops: Vec<(Ipv4Addr, FwdTableEntry<()>)>,
+    blackholed_names: Vec<(GdpName, FwdTableEntry<()>)>,
+    peer_capabilities: Vec<(Neighbor, FwdTableEntry<Capabilities>)>,
+    migrations: Vec<(GdpName, FwdTableEntry<Ipv4Addr>)>,
+    /// Last, and read by `decode` only if present, so that snapshots from switches that predate
+    /// it still load
+    #[serde(skip_deserializing)]
+    route_lifetimes: Vec<(GdpName, FwdTableEntry<u64>)>,
+}
+
+impl StoreSnapshot {
+    pub fn decode(mut bytes: &[u8]) -> bincode::Result<StoreSnapshot> {
+        let mut snapshot: StoreSnapshot = bincode::deserialize_from(&mut bytes)?;
+        if !bytes.is_empty() {
+            snapshot.route_lifetimes = bincode::deserialize(bytes)?;
+        }
+        Ok(snapshot)
+    }
+
+    pub fn routes(&self) -> usize {
+        self.forwarding_table.len()
+    }
+
+    pub fn flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Move every expiration time `secs` later, so that what was live when the snapshot was
+    /// taken is live now (certificates keep theirs, as no lookup expires them, and so do route
+    /// lifetimes, which end when their certificates do)
+    pub fn postpone_expirations(&mut self, secs: u64) {
+        fn postpone<K, T>(entries: &mut [(K, FwdTableEntry<T>)], secs: u64) {
+            for (_, entry) in entries {
+                entry.expiration_time = entry.expiration_time.saturating_add(secs);
+            }
+        }
+        postpone(&mut self.forwarding_table, secs);
+        postpone(&mut self.next_hops, secs);
+        postpone(&mut self.nack_reply_cache, secs);
+        postpone(&mut self.flows, secs);
+        postpone(&mut self.negative_routes, secs);
+        postpone(&mut self.prefetched, secs);
+        postpone(&mut self.pinned_routes, secs);
+        postpone(&mut self.failed_next_hops, secs);
+        postpone(&mut self.blackholed_names, secs);
+        postpone(&mut self.peer_capabilities, secs);
+        postpone(&mut self.migrations, secs);
+    }
+}
+
+/// A core's view of a table at one instant: the snapshot it reads, and its unpublished writes
+struct CapturedTable<K, V> {
+    table: ShardedTable<K, V>,
+    overlay: Vec<(K, Option<V>)>,
+}
+
+impl<K: Eq + Hash + Copy, V: Clone> CapturedTable<K, V> {
+    fn entries(self) -> Vec<(K, V)> {
+        let mut table = self.table;
+        for (k, v) in self.overlay {
+            match v {
+                Some(v) => table.insert(k, v),
+                None => table.remove(&k),
+            };
+        }
+        table.iter().map(|(k, v)| (*k, v.clone())).collect()
+    }
+}
+
+/// Every table of a store as one core saw it, see `Store::capture`
+pub struct StoreCapture {
+    epoch: u64,
+    forwarding_table: CapturedTable<GdpName, FwdTableEntry<Ipv4Addr>>,
+    next_hops: CapturedTable<GdpName, FwdTableEntry<GdpName>>,
+    nack_reply_cache: CapturedTable<GdpName, FwdTableEntry<Ipv4Addr>>,
+    gdp_metadata: CapturedTable<GdpName, GdpMeta>,
+    route_certs: CapturedTable<GdpName, Certificate>,
+    flows: CapturedTable<FlowKey, FwdTableEntry<FlowEntry>>,
+    negative_routes: CapturedTable<GdpName, FwdTableEntry<()>>,
+    prefetched: CapturedTable<GdpName, FwdTableEntry<()>>,
+    pinned_routes: CapturedTable<GdpName, FwdTableEntry<Ipv4Addr>>,
+    failed_next_hops: CapturedTable<Ipv4Addr, FwdTableEntry<()>>,
+    blackholed_names: CapturedTable<GdpName, FwdTableEntry<()>>,
+    peer_capabilities: CapturedTable<Neighbor, FwdTableEntry<Capabilities>>,
+    migrations: CapturedTable<GdpName, FwdTableEntry<Ipv4Addr>>,
+    route_lifetimes: CapturedTable<GdpName, FwdTableEntry<u64>>,
+}
+
+impl StoreCapture {
+    /// The publish that the captured tables come from
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Whether the core had no writes of its own pending, so that the capture is the store
+    /// exactly as published at its epoch
+    pub fn is_published(&self) -> bool {
+        [
+            self.forwarding_table.overlay.is_empty(),
+            self.next_hops.overlay.is_empty(),
+            self.nack_reply_cache.overlay.is_empty(),
+            self.gdp_metadata.overlay.is_empty(),
+            self.route_certs.overlay.is_empty(),
+            self.flows.overlay.is_empty(),
+            self.negative_routes.overlay.is_empty(),
+            self.prefetched.overlay.is_empty(),
+            self.pinned_routes.overlay.is_empty(),
+            self.failed_next_hops.overlay.is_empty(),
+            self.blackholed_names.overlay.is_empty(),
+            self.peer_capabilities.overlay.is_empty(),
+            self.migrations.overlay.is_empty(),
+            self.route_lifetimes.overlay.is_empty(),
+        ]
+        .iter()
+        .all(|empty| *empty)
+    }
+
+    /// Copy the captured tables out, which takes as long as a snapshot of the whole store
+    pub fn into_snapshot(self) -> StoreSnapshot {
+        StoreSnapshot {
+            forwarding_table: self.forwarding_table.entries(),
+            next_hops: self.next_hops.entries(),
+            nack_reply_cache: self.nack_reply_cache.entries(),
+            gdp_metadata: self.gdp_metadata.entries(),
+            route_certs: self.route_certs.entries(),
+            flows: self.flows.entries(),
+            negative_routes: self.negative_routes.entries(),
+            prefetched: self.prefetched.entries(),
+            pinned_routes: self.pinned_routes.entries(),
+            failed_next_hops: self.failed_next_hops.entries(),
+            blackholed_names: self.blackholed_names.entries(),
+            peer_capabilities: self.peer_capabilities.entries(),
+            migrations: self.migrations.entries(),
+            route_lifetimes: self.route_lifetimes.entries(),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct SharedStore {
     forwarding_table: SharedCache<GdpName, FwdTableEntry<Ipv4Addr>>,
@@ -201,33 +719,291 @@ pub struct SharedStore {
     nack_reply_cache: SharedCache<GdpName, FwdTableEntry<Ipv4Addr>>,
     gdp_metadata: SharedCache<GdpName, GdpMeta>,
     route_certs: SharedCache<GdpName, Certificate>,
+    flows: SharedCache<FlowKey, FwdTableEntry<FlowEntry>>,
+    negative_routes: SharedCache<GdpName, FwdTableEntry<()>>,
+    prefetched: SharedCache<GdpName, FwdTableEntry<()>>,
+    pinned_routes: SharedCache<GdpName, FwdTableEntry<Ipv4Addr>>,
+    failed_next_hops: SharedCache<Ipv4Addr, FwdTableEntry<()>>,
+    blackholed_names: SharedCache<GdpName, FwdTableEntry<()>>,
+    peer_capabilities: SharedCache<Neighbor, FwdTableEntry<Capabilities>>,
+    migrations: SharedCache<GdpName, FwdTableEntry<Ipv4Addr>>,
+    route_lifetimes: SharedCache<GdpName, FwdTableEntry<u64>>,
+    forwarded: DedupCache<ForwardedKey>,
+    generation: &'static Generation,
+    /// Flows that maintenance found idle since the last `take_timed_out_flows`
+    timed_out_flows: &'static AtomicU64,
 }
 
 impl SharedStore {
     pub fn new() -> SharedStore {
+        let generation = Box::leak(Box::new(Generation {
+            epoch: AtomicU64::new(0),
+            lock: Mutex::new(()),
+            now_secs: AtomicU64::new(unix_secs()),
+            refreshed: AtomicBool::new(false),
+        }));
         SharedStore {
-            forwarding_table: SharedCache::new(),
-            next_hops: SharedCache::new(),
-            nack_reply_cache: SharedCache::new(),
-            gdp_metadata: SharedCache::new(),
-            route_certs: SharedCache::new(),
+            forwarding_table: SharedCache::new(generation),
+            next_hops: SharedCache::new(generation),
+            nack_reply_cache: SharedCache::new(generation),
+            gdp_metadata: SharedCache::new(generation),
+            route_certs: SharedCache::new(generation),
+            flows: SharedCache::new(generation),
+            negative_routes: SharedCache::new(generation),
+            prefetched: SharedCache::new(generation),
+            pinned_routes: SharedCache::new(generation),
+            failed_next_hops: SharedCache::new(generation),
+            blackholed_names: SharedCache::new(generation),
+            peer_capabilities: SharedCache::new(generation),
+            migrations: SharedCache::new(generation),
+            route_lifetimes: SharedCache::new(generation),
+            forwarded: DedupCache::new(DEDUP_WINDOW, DEDUP_MAX_KEYS),
+            generation,
+            timed_out_flows: Box::leak(Box::new(AtomicU64::new(0))),
         }
     }
 
     pub fn sync(&self) -> Store {
+        let view: &'static View = Box::leak(Box::new(View {
+            epoch: Cell::new(0),
+            pins: Cell::new(0),
+            tables: RefCell::new(Vec::new()),
+        }));
+        // the snapshots must all come from the same epoch
+        let _published = self.generation.lock.lock().recover();
+        view.epoch
+            .set(self.generation.epoch.load(Ordering::Acquire));
         Store {
-            forwarding_table: self.forwarding_table.sync(),
-            next_hops: self.next_hops.sync(),
-            nack_reply_cache: self.nack_reply_cache.sync(),
-            gdp_metadata: self.gdp_metadata.sync(),
-            route_certs: self.route_certs.sync(),
+            forwarding_table: self.forwarding_table.sync(view),
+            next_hops: self.next_hops.sync(view),
+            nack_reply_cache: self.nack_reply_cache.sync(view),
+            gdp_metadata: self.gdp_metadata.sync(view),
+            route_certs: self.route_certs.sync(view),
+            flows: self.flows.sync(view),
+            negative_routes: self.negative_routes.sync(view),
+            prefetched: self.prefetched.sync(view),
+            pinned_routes: self.pinned_routes.sync(view),
+            failed_next_hops: self.failed_next_hops.sync(view),
+            blackholed_names: self.blackholed_names.sync(view),
+            peer_capabilities: self.peer_capabilities.sync(view),
+            migrations: self.migrations.sync(view),
+            route_lifetimes: self.route_lifetimes.sync(view),
+            forwarded: self.forwarded,
+            generation: self.generation,
+            view,
+        }
+    }
+
+    /// The updater task: make writes queued by any core visible to all of them
+    pub fn publish(&self) {
+        let _publishing = self.generation.lock.lock().recover();
+        self.generation
+            .now_secs
+            .store(unix_secs(), Ordering::Relaxed);
+        // cores hand over their refreshes when they next swap, so give them a reason to
+        let refreshed = self.generation.refreshed.swap(false, Ordering::AcqRel);
+        let changed = [
+            self.forwarding_table.publish(),
+            self.next_hops.publish(),
+            self.nack_reply_cache.publish(),
+            self.gdp_metadata.publish(),
+            self.route_certs.publish(),
+            self.flows.publish(),
+            self.negative_routes.publish(),
+            self.prefetched.publish(),
+            self.pinned_routes.publish(),
+            self.failed_next_hops.publish(),
+            self.blackholed_names.publish(),
+            self.peer_capabilities.publish(),
+            self.migrations.publish(),
+            self.route_lifetimes.publish(),
+        ];
+        if changed.contains(&true) || refreshed {
+            self.generation.epoch.fetch_add(1, Ordering::Release);
         }
     }
 
+    /// Must run on the same core as `publish`
     pub fn run_active_expire(&self) {
-        self.forwarding_table.run_active_expire();
-        self.nack_reply_cache.run_active_expire();
-        self.route_certs.run_active_expire();
+        let _publishing = self.generation.lock.lock().recover();
+        // flows are swept fully, so that every flow that times out is counted
+        let timed_out_flows = self.flows.remove_expired();
+        self.timed_out_flows
+            .fetch_add(timed_out_flows as u64, Ordering::Relaxed);
+        let removed = [
+            self.forwarding_table.run_active_expire(),
+            self.nack_reply_cache.run_active_expire(),
+            self.route_certs.run_active_expire(),
+            timed_out_flows > 0,
+            self.negative_routes.run_active_expire(),
+            self.prefetched.run_active_expire(),
+            self.route_lifetimes.run_active_expire(),
+            // there are few pins, injected failures, neighbors and migrations: always swept fully
+            self.pinned_routes.remove_expired() > 0,
+            self.failed_next_hops.remove_expired() > 0,
+            self.blackholed_names.remove_expired() > 0,
+            self.peer_capabilities.remove_expired() > 0,
+            self.migrations.remove_expired() > 0,
+        ];
+        if removed.contains(&true) {
+            self.generation.epoch.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// How many flows timed out since the last call, for the `flows.ended` counter
+    pub fn take_timed_out_flows(&self) -> u64 {
+        self.timed_out_flows.swap(0, Ordering::Relaxed)
+    }
+
+    /// Everything published so far, taken under the generation lock so the tables agree
+    pub fn snapshot(&self) -> StoreSnapshot {
+        let _published = self.generation.lock.lock().recover();
+        StoreSnapshot {
+            forwarding_table: self.forwarding_table.snapshot(),
+            next_hops: self.next_hops.snapshot(),
+            nack_reply_cache: self.nack_reply_cache.snapshot(),
+            gdp_metadata: self.gdp_metadata.snapshot(),
+            route_certs: self.route_certs.snapshot(),
+            flows: self.flows.snapshot(),
+            negative_routes: self.negative_routes.snapshot(),
+            prefetched: self.prefetched.snapshot(),
+            pinned_routes: self.pinned_routes.snapshot(),
+            failed_next_hops: self.failed_next_hops.snapshot(),
+            blackholed_names: self.blackholed_names.snapshot(),
+            peer_capabilities: self.peer_capabilities.snapshot(),
+            migrations: self.migrations.snapshot(),
+            route_lifetimes: self.route_lifetimes.snapshot(),
+        }
+    }
+
+    /// Load a snapshot taken by another process and publish it. Entries that expired in transit
+    /// are swept by `run_active_expire` as usual.
+    pub fn restore(&self, snapshot: StoreSnapshot) {
+        self.forwarding_table.restore(snapshot.forwarding_table);
+        self.next_hops.restore(snapshot.next_hops);
+        self.nack_reply_cache.restore(snapshot.nack_reply_cache);
+        self.gdp_metadata.restore(snapshot.gdp_metadata);
+        self.route_certs.restore(snapshot.route_certs);
+        self.flows.restore(snapshot.flows);
+        self.negative_routes.restore(snapshot.negative_routes);
+        self.prefetched.restore(snapshot.prefetched);
+        self.pinned_routes.restore(snapshot.pinned_routes);
+        self.failed_next_hops.restore(snapshot.failed_next_hops);
+        self.blackholed_names.restore(snapshot.blackholed_names);
+        self.peer_capabilities.restore(snapshot.peer_capabilities);
+        self.migrations.restore(snapshot.migrations);
+        self.route_lifetimes.restore(snapshot.route_lifetimes);
+        self.publish();
+    }
+
+    pub fn pin_route(&self, gdp_name: GdpName, ip: Ipv4Addr, expiration_time: u64) {
+        self.pinned_routes
+            .insert(gdp_name, FwdTableEntry::new(ip, expiration_time));
+    }
+
+    pub fn unpin_route(&self, gdp_name: GdpName) -> bool {
+        self.pinned_routes.remove(&gdp_name).is_some()
+    }
+
+    pub fn fail_next_hop(&self, ip: Ipv4Addr, expiration_time: u64) {
+        self.failed_next_hops
+            .insert(ip, FwdTableEntry::new((), expiration_time));
+    }
+
+    pub fn blackhole_name(&self, gdp_name: GdpName, expiration_time: u64) {
+        self.blackholed_names
+            .insert(gdp_name, FwdTableEntry::new((), expiration_time));
+    }
+
+    /// Restore every next hop and name that failures were injected for
+    pub fn clear_chaos(&self) {
+        for (ip, _) in self.failed_next_hops.entries() {
+            self.failed_next_hops.remove(&ip);
+        }
+        for (gdp_name, _) in self.blackholed_names.entries() {
+            self.blackholed_names.remove(&gdp_name);
+        }
+    }
+
+    /// Every IP address we currently forward to, each listed once
+    pub fn next_hop_ips(&self) -> Vec<Ipv4Addr> {
+        let mut ips = self
+            .pinned_routes
+            .entries()
+            .into_iter()
+            .chain(self.forwarding_table.entries())
+            .map(|(_, entry)| entry.val)
+            .collect::<Vec<_>>();
+        ips.sort_unstable();
+        ips.dedup();
+        ips
+    }
+
+    pub fn metadata(&self, gdp_name: &GdpName) -> Option<GdpMeta> {
+        self.gdp_metadata.get_unchecked(gdp_name)
+    }
+
+    /// All live forwarding entries, pinned ones first, then the names delegated to them
+    pub fn dump_routes(&self) -> Vec<RouteDump> {
+        let pinned = self
+            .pinned_routes
+            .entries()
+            .into_iter()
+            .map(|entry| (entry, RouteSource::Pinned));
+        let learned = self
+            .forwarding_table
+            .entries()
+            .into_iter()
+            .map(|entry| (entry, RouteSource::Learned));
+        let mut routes: Vec<RouteDump> = pinned
+            .chain(learned)
+            .map(|((name, entry), source)| RouteDump {
+                name,
+                ip: entry.val,
+                expiration_time: entry.expiration_time,
+                source,
+            })
+            .collect();
+        let delegated = self
+            .next_hops
+            .entries()
+            .into_iter()
+            .filter_map(|(name, entry)| {
+                let (ip, expiration_time) = self.locate(entry.val)?;
+                Some(RouteDump {
+                    name,
+                    ip,
+                    // the delegation is only as good as the route it leads to
+                    expiration_time: expiration_time.min(entry.expiration_time),
+                    source: RouteSource::Delegated,
+                })
+            })
+            .collect::<Vec<_>>();
+        routes.extend(delegated);
+        routes
+    }
+
+    /// Where a delegation to `name` leads, and until when, as the switch resolves it
+    fn locate(&self, mut name: GdpName) -> Option<(Ipv4Addr, u64)> {
+        let mut expiration_time = u64::MAX;
+        for _ in 0..MAX_DELEGATIONS {
+            if let Some(entry) = self
+                .pinned_routes
+                .get_unchecked(&name)
+                .filter(|entry| !entry.is_expired())
+                .or_else(|| self.forwarding_table.get_unchecked(&name))
+                .filter(|entry| !entry.is_expired())
+            {
+                return Some((entry.val, expiration_time.min(entry.expiration_time)));
+            }
+            let entry = self
+                .next_hops
+                .get_unchecked(&name)
+                .filter(|entry| !entry.is_expired())?;
+            expiration_time = expiration_time.min(entry.expiration_time);
+            name = entry.val;
+        }
+        None
     }
 }
 #[derive(Copy, Clone)]
@@ -244,4 +1020,958 @@ pub struct Store {
     pub gdp_metadata: SyncCache<GdpName, GdpMeta>,
     /// Route certs we have issued delegating our representation to another GdpName
     pub route_certs: SyncCache<GdpName, Certificate>,
+    /// Flows initiated by local endpoints, so that return traffic can skip the RIB
+    pub flows: SyncCache<FlowKey, FwdTableEntry<FlowEntry>>,
+    /// GdpNames that the RIB recently told us it could not resolve
+    pub negative_routes: SyncCache<GdpName, FwdTableEntry<()>>,
+    /// GdpNames we asked the RIB about ahead of time, and have not yet seen a lookup for
+    pub prefetched: SyncCache<GdpName, FwdTableEntry<()>>,
+    /// Routes forced by the operator, which take precedence over everything above
+    pub pinned_routes: SyncCache<GdpName, FwdTableEntry<Ipv4Addr>>,
+    /// Next hops the operator is simulating a failure of, which we drop all traffic for
+    pub failed_next_hops: SyncCache<Ipv4Addr, FwdTableEntry<()>>,
+    /// GdpNames the operator is simulating a blackhole for
+    pub blackholed_names: SyncCache<GdpName, FwdTableEntry<()>>,
+    /// What our neighbors told us they support in heartbeats, by the VLAN and IP they send from
+    pub peer_capabilities: SyncCache<Neighbor, FwdTableEntry<Capabilities>>,
+    /// Where GdpNames that are moving hosts are registered as pending, until their old
+    /// registration is withdrawn; packets for them are delivered to both hosts meanwhile
+    pub migrations: SyncCache<GdpName, FwdTableEntry<Ipv4Addr>>,
+    /// The TTL (in seconds) of each route learned from a RibReply, which its forwarding entry is
+    /// refreshed to on use, and the expiration time of its certificate, which it never outlives
+    pub route_lifetimes: SyncCache<GdpName, FwdTableEntry<u64>>,
+    /// The Forwards sent on recently, by source, destination, sequence number and data (see
+    /// dedup.rs). Shared by every core rather than replicated, and neither snapshotted nor captured, as its keys only matter
+    /// for a few seconds
+    pub forwarded: DedupCache<ForwardedKey>,
+    generation: &'static Generation,
+    view: &'static View,
+}
+
+impl Store {
+    /// Keep this core's snapshots of every table as they are until the pin is dropped,
+    /// so that a sequence of lookups sees the store as of a single publish
+    pub fn pin(&self) -> ViewPin {
+        self.view.refresh(self.generation);
+        self.view.pins.set(self.view.pins.get() + 1);
+        ViewPin(self.view)
+    }
+
+    /// Seconds since the epoch, as of the updater's last publish: cheap enough for the packet
+    /// path, and at most a publish behind
+    pub fn now_secs(&self) -> u64 {
+        self.generation.now_secs.load(Ordering::Relaxed)
+    }
+
+    /// Every table as this core's lookups see it, cheap enough for the packet path: the tables
+    /// are shared with the core's snapshots rather than copied. Pin the view first, to capture
+    /// what a sequence of lookups saw.
+    pub fn capture(&self) -> StoreCapture {
+        StoreCapture {
+            epoch: self.view.epoch.get(),
+            forwarding_table: self.forwarding_table.capture(),
+            next_hops: self.next_hops.capture(),
+            nack_reply_cache: self.nack_reply_cache.capture(),
+            gdp_metadata: self.gdp_metadata.capture(),
+            route_certs: self.route_certs.capture(),
+            flows: self.flows.capture(),
+            negative_routes: self.negative_routes.capture(),
+            prefetched: self.prefetched.capture(),
+            pinned_routes: self.pinned_routes.capture(),
+            failed_next_hops: self.failed_next_hops.capture(),
+            blackholed_names: self.blackholed_names.capture(),
+            peer_capabilities: self.peer_capabilities.capture(),
+            migrations: self.migrations.capture(),
+            route_lifetimes: self.route_lifetimes.capture(),
+        }
+    }
+
+    /// Make several writes that every core sees at once, rather than one table at a time.
+    /// Reads inside `apply` see the writes made so far; it should be quick,
+    /// as the updater (and any core picking up a publish) waits for it.
+    pub fn transaction<R>(&self, apply: impl FnOnce() -> R) -> R {
+        let _pin = self.pin();
+        let _queueing = self.generation.lock.lock().recover();
+        apply()
+    }
+}
+
+/*
+   Packets waiting for a route are queued in a PendingQueue, whose memory is bounded so that a
+   flood of unroutable traffic cannot drain the mempools:
+   - the queue holds at most `max_packets` items and `max_bytes` of them, and each item for at
+     most `ttl_ms`
+   - once it is full, the policy picks what makes room: the items queued longest ago (`oldest`),
+     or the item arriving (`incoming`). An item larger than the whole byte budget is never queued
+   - evicted and expired items are handed back to the caller, which decides what becomes of them
+     (and counts them), as the queue knows nothing of packets
+*/
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    Oldest,
+    Incoming,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct PendingLimits {
+    #[serde(default = "default_max_pending_packets")]
+    pub max_packets: usize,
+    #[serde(default = "default_max_pending_bytes")]
+    pub max_bytes: usize,
+    /// How long an item may wait before it is handed back as expired
+    #[serde(default = "default_pending_ttl_ms")]
+    pub ttl_ms: u64,
+    #[serde(default = "default_eviction")]
+    pub eviction: EvictionPolicy,
+}
+
+fn default_max_pending_packets() -> usize {
+    1024
+}
+
+fn default_max_pending_bytes() -> usize {
+    1 << 20
+}
+
+fn default_pending_ttl_ms() -> u64 {
+    250
+}
+
+fn default_eviction() -> EvictionPolicy {
+    EvictionPolicy::Oldest
+}
+
+impl Default for PendingLimits {
+    fn default() -> Self {
+        PendingLimits {
+            max_packets: default_max_pending_packets(),
+            max_bytes: default_max_pending_bytes(),
+            ttl_ms: default_pending_ttl_ms(),
+            eviction: default_eviction(),
+        }
+    }
+}
+
+struct PendingItem<T> {
+    item: T,
+    len: usize,
+    until: Instant,
+}
+
+/// Items waiting for something, in the order they were queued
+pub struct PendingQueue<T> {
+    limits: PendingLimits,
+    items: VecDeque<PendingItem<T>>,
+    bytes: usize,
+}
+
+impl<T> PendingQueue<T> {
+    pub fn new(limits: PendingLimits) -> Self {
+        PendingQueue {
+            limits,
+            items: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        self.items.len() < self.limits.max_packets && self.bytes + len <= self.limits.max_bytes
+    }
+
+    /// Queue `item`, which takes `len` bytes of the budget. Returns the items evicted to make
+    /// room for it, or the item itself if it was not queued.
+    pub fn push(&mut self, item: T, len: usize, now: Instant) -> Vec<T> {
+        let mut evicted = Vec::new();
+        if len > self.limits.max_bytes || self.limits.max_packets == 0 {
+            evicted.push(item);
+            return evicted;
+        }
+        if !self.fits(len) && self.limits.eviction == EvictionPolicy::Incoming {
+            evicted.push(item);
+            return evicted;
+        }
+        while !self.fits(len) {
+            let oldest = self.items.pop_front().unwrap();
+            self.bytes -= oldest.len;
+            evicted.push(oldest.item);
+        }
+        self.bytes += len;
+        self.items.push_back(PendingItem {
+            item,
+            len,
+            until: now + Duration::from_millis(self.limits.ttl_ms),
+        });
+        evicted
+    }
+
+    /// Take out the items that `ready` accepts, and those that waited past their time, each in
+    /// the order they were queued
+    pub fn drain(&mut self, now: Instant, mut ready: impl FnMut(&T) -> bool) -> (Vec<T>, Vec<T>) {
+        let (mut released, mut expired) = (Vec::new(), Vec::new());
+        for pending in std::mem::take(&mut self.items) {
+            if ready(&pending.item) {
+                self.bytes -= pending.len;
+                released.push(pending.item);
+            } else if now >= pending.until {
+                self.bytes -= pending.len;
+                expired.push(pending.item);
+            } else {
+                self.items.push_back(pending);
+            }
+        }
+        (released, expired)
+    }
+
+    /// Take out the items that `unwanted` picks, whether or not their time is up, in the order
+    /// they were queued
+    pub fn remove_if(&mut self, mut unwanted: impl FnMut(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        for pending in std::mem::take(&mut self.items) {
+            if unwanted(&pending.item) {
+                self.bytes -= pending.len;
+                removed.push(pending.item);
+            } else {
+                self.items.push_back(pending);
+            }
+        }
+        removed
+    }
+}
+
+/*
+   Fragments of a packet are put back together in a Reassembly, which is bounded like a
+   PendingQueue:
+   - at most `max_packets` packets are partly received at once. The fragments of a packet that
+     would be one more are refused, rather than evicting packets that may be about to complete
+   - a packet whose last fragment has not arrived `timeout_ms` after its first is given up on
+   - the packet keeps the item of the first of its fragments to arrive, so that the caller can
+     reuse it for the whole packet; the items of the others are dropped once their bytes are copied
+   - fragments repeated by the sender are ignored; fragments that overlap others, or run past the
+     end of the packet, have the whole packet given up on, as its bytes cannot be trusted
+*/
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ReassemblyLimits {
+    #[serde(default = "default_max_reassembling_packets")]
+    pub max_packets: usize,
+    /// How long after its first fragment a packet is given up on
+    #[serde(default = "default_reassembly_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_max_reassembling_packets() -> usize {
+    256
+}
+
+fn default_reassembly_timeout_ms() -> u64 {
+    500
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        ReassemblyLimits {
+            max_packets: default_max_reassembling_packets(),
+            timeout_ms: default_reassembly_timeout_ms(),
+        }
+    }
+}
+
+/// What became of a fragment handed to a Reassembly
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembled<T> {
+    /// More of its packet is still to come
+    Waiting,
+    /// It completed its packet: the item the packet kept, and the packet's bytes
+    Whole(T, Vec<u8>),
+    /// It was not taken, and neither is the rest of its packet if it was inconsistent
+    Refused,
+}
+
+struct PartialPacket<T> {
+    item: T,
+    /// The bytes received, by their offset in the packet
+    pieces: BTreeMap<usize, Vec<u8>>,
+    /// Known once the last fragment has arrived
+    total_len: Option<usize>,
+    until: Instant,
+}
+
+impl<T> PartialPacket<T> {
+    /// Whether `len` bytes at `offset` are consistent with what was received so far
+    fn accepts(&self, offset: usize, len: usize, more: bool) -> bool {
+        let end = offset + len;
+        let before = self.pieces.range(..offset).next_back();
+        let after = self.pieces.range(offset..).next();
+        let overlaps = matches!(before, Some((start, piece)) if start + piece.len() > offset)
+            || matches!(after, Some((start, _)) if *start < end);
+        let past_end = match (self.total_len, more) {
+            (Some(total), true) => end > total,
+            // only one fragment can be the last
+            (Some(_), false) => true,
+            (None, true) => false,
+            (None, false) => self
+                .pieces
+                .iter()
+                .any(|(start, piece)| start + piece.len() > end),
+        };
+        !overlaps && !past_end
+    }
+
+    fn is_whole(&self) -> bool {
+        // the pieces never overlap, so they cover the packet once they add up to it
+        self.total_len == Some(self.pieces.values().map(Vec::len).sum())
+    }
+}
+
+/// Packets whose fragments are arriving, by the key that the fragments of one packet share
+pub struct Reassembly<K, T> {
+    limits: ReassemblyLimits,
+    packets: HashMap<K, PartialPacket<T>>,
+}
+
+impl<K: Eq + Hash, T> Reassembly<K, T> {
+    pub fn new(limits: ReassemblyLimits) -> Self {
+        Reassembly {
+            limits,
+            packets: HashMap::new(),
+        }
+    }
+
+    /// The packets partly received
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Add the `bytes` of a fragment that go at `offset` of the packet with `key`; `more` is
+    /// false for the last fragment of the packet
+    pub fn insert(
+        &mut self,
+        key: K,
+        offset: usize,
+        more: bool,
+        bytes: Vec<u8>,
+        item: T,
+        now: Instant,
+    ) -> Reassembled<T> {
+        let mut packet = match self.packets.remove(&key) {
+            Some(packet) => packet,
+            None if self.packets.len() >= self.limits.max_packets => return Reassembled::Refused,
+            None => PartialPacket {
+                item,
+                pieces: BTreeMap::new(),
+                total_len: None,
+                until: now + Duration::from_millis(self.limits.timeout_ms),
+            },
+        };
+        let repeated = packet.pieces.get(&offset).map(Vec::len) == Some(bytes.len());
+        if !repeated {
+            if !packet.accepts(offset, bytes.len(), more) {
+                return Reassembled::Refused;
+            }
+            if !more {
+                packet.total_len = Some(offset + bytes.len());
+            }
+            packet.pieces.insert(offset, bytes);
+        }
+        if packet.is_whole() {
+            let bytes = packet.pieces.into_values().flatten().collect();
+            return Reassembled::Whole(packet.item, bytes);
+        }
+        self.packets.insert(key, packet);
+        Reassembled::Waiting
+    }
+
+    /// Give up on the packets that did not complete in time, returning how many there were
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.packets.len();
+        self.packets.retain(|_, packet| now < packet.until);
+        before - self.packets.len()
+    }
+}
+
+/*
+   Keys seen recently, such as those of the packets a switch forwarded (see dedup.rs), in a
+   DedupCache:
+   - it is shared by every core rather than replicated, as it is written for every packet. Keys
+     are spread over DEDUP_SHARDS locks, so that cores seldom wait on each other
+   - each shard keeps the keys inserted in its current window and in the one before, and forgets
+     the older window's keys at once when a new window starts: a key is remembered for between
+     one and two windows, with no expiration times to keep or sweep
+   - a shard whose window is full starts a new one early, so that it never holds more than twice
+     its share of the cache's capacity
+*/
+
+const DEDUP_SHARDS: usize = 16;
+
+struct DedupShard<K> {
+    /// When `current` started
+    started: Instant,
+    current: HashSet<K>,
+    previous: HashSet<K>,
+}
+
+pub struct DedupCache<K: 'static> {
+    window: Duration,
+    /// Keys each shard holds per window, at most
+    per_shard: usize,
+    shards: &'static [Mutex<DedupShard<K>>],
+}
+
+impl<K> Copy for DedupCache<K> {}
+impl<K> Clone for DedupCache<K> {
+    fn clone(&self) -> Self {
+        DedupCache {
+            window: self.window,
+            per_shard: self.per_shard,
+            shards: self.shards,
+        }
+    }
+}
+
+impl<K: Eq + Hash> DedupCache<K> {
+    /// A cache that remembers keys for `window` to twice that, up to `capacity` of them per
+    /// window
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        let now = Instant::now();
+        let shards = (0..DEDUP_SHARDS)
+            .map(|_| {
+                Mutex::new(DedupShard {
+                    started: now,
+                    current: HashSet::new(),
+                    previous: HashSet::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+        DedupCache {
+            window,
+            per_shard: (capacity / DEDUP_SHARDS).max(1),
+            shards: Box::leak(shards.into_boxed_slice()),
+        }
+    }
+
+    /// The shard that holds `key`, moved on to the window that `now` falls in
+    fn shard(&self, key: &K, now: Instant) -> MutexGuard<'static, DedupShard<K>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % DEDUP_SHARDS]
+            .lock()
+            .recover();
+        let elapsed = now.saturating_duration_since(shard.started);
+        if elapsed >= self.window || shard.current.len() >= self.per_shard {
+            let shard = &mut *shard;
+            // the sets are reused, so that a busy shard does not grow them again every window
+            std::mem::swap(&mut shard.current, &mut shard.previous);
+            shard.current.clear();
+            if elapsed >= 2 * self.window {
+                shard.previous.clear();
+            }
+            shard.started = now;
+        }
+        shard
+    }
+
+    /// Remember `key`, returning false if it was remembered already
+    pub fn insert(&self, key: K, now: Instant) -> bool {
+        let mut shard = self.shard(&key, now);
+        !shard.previous.contains(&key) && shard.current.insert(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use gdp_testutil::name;
+
+    use super::*;
+
+    const FOREVER: u64 = u64::MAX / 2;
+
+    fn insert_pair(store: Store) {
+        store.transaction(|| {
+            store
+                .forwarding_table
+                .update(name(1), FwdTableEntry::new(Ipv4Addr::LOCALHOST, FOREVER));
+            store
+                .next_hops
+                .update(name(2), FwdTableEntry::new(name(1), FOREVER));
+        });
+    }
+
+    fn remove_pair(store: Store) {
+        store.transaction(|| {
+            store.forwarding_table.remove(&name(1));
+            store.next_hops.remove(&name(2));
+        });
+    }
+
+    fn has_pair(store: Store) -> (bool, bool) {
+        let _view = store.pin();
+        (
+            store.forwarding_table.get(&name(1)).is_some(),
+            store.next_hops.get(&name(2)).is_some(),
+        )
+    }
+
+    #[test]
+    fn transaction_is_visible_to_other_cores_once_published() {
+        let shared = SharedStore::new();
+        let writer = shared.sync();
+        let reader = shared.sync();
+
+        insert_pair(writer);
+        assert_eq!(has_pair(writer), (true, true));
+        assert_eq!(has_pair(reader), (false, false));
+
+        shared.publish();
+        assert_eq!(has_pair(reader), (true, true));
+    }
+
+    #[test]
+    fn refreshes_reach_other_cores_once_handed_over() {
+        let shared = SharedStore::new();
+        let writer = shared.sync();
+        let reader = shared.sync();
+
+        writer
+            .forwarding_table
+            .refresh(name(1), FwdTableEntry::new(Ipv4Addr::LOCALHOST, u64::MAX));
+        assert!(writer.forwarding_table.get(&name(1)).is_some());
+        // the first publish only has the writer hand its refresh over, which the next applies
+        shared.publish();
+        assert!(reader.forwarding_table.get(&name(1)).is_none());
+        assert!(writer.forwarding_table.get(&name(1)).is_some());
+        shared.publish();
+        assert!(reader.forwarding_table.get(&name(1)).is_some());
+    }
+
+    #[test]
+    fn pinned_view_ignores_publishes() {
+        let shared = SharedStore::new();
+        let writer = shared.sync();
+        let reader = shared.sync();
+
+        let view = reader.pin();
+        insert_pair(writer);
+        shared.publish();
+        assert!(reader.forwarding_table.get(&name(1)).is_none());
+        assert!(reader.next_hops.get(&name(2)).is_none());
+
+        drop(view);
+        assert_eq!(has_pair(reader), (true, true));
+    }
+
+    #[test]
+    fn nested_pins_release_together() {
+        let shared = SharedStore::new();
+        let writer = shared.sync();
+        let reader = shared.sync();
+
+        let outer = reader.pin();
+        let inner = reader.pin();
+        insert_pair(writer);
+        shared.publish();
+        drop(inner);
+        assert!(reader.forwarding_table.get(&name(1)).is_none());
+        drop(outer);
+        assert!(reader.forwarding_table.get(&name(1)).is_some());
+    }
+
+    #[test]
+    fn restored_snapshot_is_visible_to_every_core() {
+        let old = SharedStore::new();
+        insert_pair(old.sync());
+        old.publish();
+
+        let snapshot =
+            StoreSnapshot::decode(&bincode::serialize(&old.snapshot()).unwrap()).unwrap();
+        let new = SharedStore::new();
+        let reader = new.sync();
+        new.restore(snapshot);
+        assert_eq!(has_pair(reader), (true, true));
+        assert_eq!(has_pair(new.sync()), (true, true));
+    }
+
+    #[test]
+    fn takes_over_from_switches_without_route_lifetimes() {
+        let old = SharedStore::new();
+        let writer = old.sync();
+        writer.transaction(|| {
+            writer
+                .route_lifetimes
+                .put(name(1), FwdTableEntry::new(300, u64::MAX))
+        });
+        old.publish();
+        let bytes = bincode::serialize(&old.snapshot()).unwrap();
+        assert_eq!(
+            StoreSnapshot::decode(&bytes).unwrap().route_lifetimes.len(),
+            1
+        );
+
+        // an older switch ends its snapshot before the lifetimes: a u64 length and one entry
+        let entry_len =
+            bincode::serialized_size(&(name(1), FwdTableEntry::new(300u64, 0))).unwrap();
+        let older = &bytes[..bytes.len() - 8 - entry_len as usize];
+        assert!(StoreSnapshot::decode(older)
+            .unwrap()
+            .route_lifetimes
+            .is_empty());
+    }
+
+    #[test]
+    fn capture_includes_unpublished_writes() {
+        let shared = SharedStore::new();
+        let writer = shared.sync();
+        let reader = shared.sync();
+        insert_pair(writer);
+        shared.publish();
+        writer.forwarding_table.remove(&name(1));
+
+        let _view = reader.pin();
+        assert!(reader.capture().is_published());
+        let capture = writer.capture();
+        assert!(!capture.is_published());
+        let replayed = SharedStore::new();
+        replayed.restore(capture.into_snapshot());
+        assert_eq!(has_pair(replayed.sync()), (false, true));
+    }
+
+    #[test]
+    fn dump_lists_delegated_names_with_the_route_they_lead_to() {
+        let shared = SharedStore::new();
+        let store = shared.sync();
+        insert_pair(store);
+        let soon = FOREVER / 2;
+        store
+            .next_hops
+            .update(name(3), FwdTableEntry::new(name(2), soon));
+        // delegated to each other, so leading nowhere
+        store
+            .next_hops
+            .update(name(4), FwdTableEntry::new(name(5), FOREVER));
+        store
+            .next_hops
+            .update(name(5), FwdTableEntry::new(name(4), FOREVER));
+        shared.publish();
+
+        let mut routes = shared.dump_routes();
+        routes.sort_by_key(|route| route.name);
+        let routes = routes
+            .iter()
+            .map(|route| (route.name, route.ip, route.expiration_time, route.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            routes,
+            vec![
+                (name(1), Ipv4Addr::LOCALHOST, FOREVER, RouteSource::Learned),
+                (
+                    name(2),
+                    Ipv4Addr::LOCALHOST,
+                    FOREVER,
+                    RouteSource::Delegated
+                ),
+                (name(3), Ipv4Addr::LOCALHOST, soon, RouteSource::Delegated),
+            ]
+        );
+    }
+
+    fn limits(eviction: EvictionPolicy) -> PendingLimits {
+        PendingLimits {
+            max_packets: 3,
+            max_bytes: 100,
+            ttl_ms: 10,
+            eviction,
+        }
+    }
+
+    #[test]
+    fn pending_queue_evicts_to_stay_within_both_budgets() {
+        let now = Instant::now();
+        let mut oldest = PendingQueue::new(limits(EvictionPolicy::Oldest));
+        for item in 0..3 {
+            assert!(oldest.push(item, 10, now).is_empty());
+        }
+        assert_eq!(oldest.push(3, 10, now), vec![0]);
+        // makes room by bytes as well as by count
+        assert_eq!(oldest.push(4, 80, now), vec![1]);
+        assert_eq!((oldest.len(), oldest.bytes()), (3, 100));
+        // larger than the whole budget, so never queued
+        assert_eq!(oldest.push(5, 101, now), vec![5]);
+
+        let mut incoming = PendingQueue::new(limits(EvictionPolicy::Incoming));
+        for item in 0..3 {
+            assert!(incoming.push(item, 10, now).is_empty());
+        }
+        assert_eq!(incoming.push(3, 10, now), vec![3]);
+        assert_eq!(incoming.len(), 3);
+    }
+
+    #[test]
+    fn pending_queue_drains_ready_and_expired_items() {
+        let now = Instant::now();
+        let mut queue = PendingQueue::new(limits(EvictionPolicy::Oldest));
+        queue.push(1, 10, now);
+        queue.push(2, 10, now + Duration::from_millis(5));
+        queue.push(3, 10, now + Duration::from_millis(5));
+
+        let (released, expired) = queue.drain(now + Duration::from_millis(10), |item| *item == 3);
+        assert_eq!((released, expired), (vec![3], vec![1]));
+        assert_eq!((queue.len(), queue.bytes()), (1, 10));
+
+        let (released, expired) = queue.drain(now + Duration::from_millis(15), |_| false);
+        assert_eq!((released, expired), (vec![], vec![2]));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pending_queue_removes_the_items_picked() {
+        let now = Instant::now();
+        let mut queue = PendingQueue::new(limits(EvictionPolicy::Oldest));
+        queue.push(1, 10, now);
+        queue.push(2, 20, now);
+        queue.push(3, 30, now);
+
+        assert_eq!(queue.remove_if(|item| item % 2 == 1), vec![1, 3]);
+        assert_eq!((queue.len(), queue.bytes()), (1, 20));
+        assert!(queue.remove_if(|_| false).is_empty());
+    }
+
+    fn reassembly() -> Reassembly<u32, &'static str> {
+        Reassembly::new(ReassemblyLimits {
+            max_packets: 2,
+            timeout_ms: 10,
+        })
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let now = Instant::now();
+        let mut packets = reassembly();
+        assert_eq!(
+            packets.insert(1, 4, false, b"ef".to_vec(), "a", now),
+            Reassembled::Waiting
+        );
+        assert_eq!(
+            packets.insert(1, 0, true, b"ab".to_vec(), "b", now),
+            Reassembled::Waiting
+        );
+        // sent twice by the sender
+        assert_eq!(
+            packets.insert(1, 0, true, b"ab".to_vec(), "c", now),
+            Reassembled::Waiting
+        );
+        assert_eq!(
+            packets.insert(2, 0, false, b"xyz".to_vec(), "x", now),
+            Reassembled::Whole("x", b"xyz".to_vec())
+        );
+        assert_eq!(
+            packets.insert(1, 2, true, b"cd".to_vec(), "d", now),
+            Reassembled::Whole("a", b"abcdef".to_vec())
+        );
+        assert!(packets.is_empty());
+
+        // past the end of the packet
+        packets.insert(1, 2, false, b"cd".to_vec(), "a", now);
+        assert_eq!(
+            packets.insert(1, 4, true, b"ef".to_vec(), "b", now),
+            Reassembled::Refused
+        );
+        assert!(packets.is_empty());
+    }
+
+    #[test]
+    fn reassembly_refuses_overlaps_and_packets_past_its_limits() {
+        let now = Instant::now();
+        let mut packets = reassembly();
+        packets.insert(1, 0, true, b"abcd".to_vec(), "a", now);
+        assert_eq!(
+            packets.insert(1, 2, false, b"cdef".to_vec(), "b", now),
+            Reassembled::Refused
+        );
+        assert!(packets.is_empty());
+
+        packets.insert(1, 0, true, b"ab".to_vec(), "a", now);
+        packets.insert(
+            2,
+            0,
+            true,
+            b"ab".to_vec(),
+            "b",
+            now + Duration::from_millis(5),
+        );
+        assert_eq!(
+            packets.insert(3, 0, true, b"ab".to_vec(), "c", now),
+            Reassembled::Refused
+        );
+        assert_eq!(packets.expire(now + Duration::from_millis(10)), 1);
+        assert_eq!(
+            packets.insert(3, 0, true, b"ab".to_vec(), "c", now),
+            Reassembled::Waiting
+        );
+        assert_eq!(packets.len(), 2);
+    }
+
+    #[test]
+    fn dedup_cache_remembers_keys_for_one_to_two_windows() {
+        let window = Duration::from_millis(100);
+        let cache = DedupCache::new(window, 1024);
+        let start = Instant::now();
+        assert!(cache.insert(1, start));
+        assert!(!cache.insert(1, start + window / 2));
+
+        // in the window before, which is still remembered
+        assert!(!cache.insert(1, start + window * 3 / 2));
+        assert!(cache.insert(2, start + window * 3 / 2));
+        // two windows on, only the key of the window before is left
+        assert!(cache.insert(1, start + window * 5 / 2));
+        assert!(!cache.insert(2, start + window * 5 / 2));
+        // and nothing, once a whole window passes without a lookup
+        assert!(cache.insert(2, start + window * 5));
+    }
+
+    #[test]
+    fn full_dedup_caches_start_their_windows_early() {
+        let capacity = 4 * DEDUP_SHARDS;
+        let cache = DedupCache::new(Duration::from_secs(60), capacity);
+        let now = Instant::now();
+        for key in 0..10 * capacity {
+            assert!(cache.insert(key, now));
+        }
+        let held: usize = cache
+            .shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.current.len() + shard.previous.len()
+            })
+            .sum();
+        assert!(held <= 2 * capacity, "{} keys held", held);
+        // the latest keys are still remembered
+        assert!(!cache.insert(10 * capacity - 1, now));
+    }
+
+    #[test]
+    fn concurrent_readers_never_see_half_a_transaction() {
+        let shared = SharedStore::new();
+        let done: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+
+        let readers = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    let store = shared.sync();
+                    while !done.load(Ordering::Relaxed) {
+                        let (route, next_hop) = has_pair(store);
+                        assert_eq!(route, next_hop, "observed half of a transaction");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let publisher = thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                shared.publish();
+            }
+        });
+
+        let writer = shared.sync();
+        for i in 0..2000 {
+            if i % 2 == 0 {
+                insert_pair(writer);
+            } else {
+                remove_pair(writer);
+            }
+            if i % 100 == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+
+        publisher.join().unwrap();
+        for reader in readers {
+            // panics from failed assertions in the readers are re-raised here
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn writes_copy_only_the_shards_they_land_in() {
+        let mut table = ShardedTable::new();
+        for k in 0..1000u64 {
+            table.insert(k, k);
+        }
+        let snapshot = table.clone();
+        let copied = |table: &ShardedTable<u64, u64>| {
+            table
+                .shards
+                .iter()
+                .zip(&snapshot.shards)
+                .filter(|(ours, theirs)| !Arc::ptr_eq(ours, theirs))
+                .count()
+        };
+        table.insert(5, 0);
+        assert_eq!(copied(&table), 1);
+        assert_eq!(table.get(&5), Some(&0));
+        assert_eq!(snapshot.get(&5), Some(&5));
+
+        // nor do writes that change nothing
+        table.remove(&5000);
+        table.retain(|k, _| *k < 2000);
+        table.migrate(IDLE_MIGRATE_STEP);
+        assert_eq!(copied(&table), 1);
+        assert_eq!(table.len(), 1000);
+
+        table.retain(|k, _| k % 2 == 0);
+        assert_eq!(table.len(), 500);
+        assert_eq!(snapshot.len(), 1000);
+    }
+
+    #[test]
+    fn incremental_map_agrees_with_hash_map_while_it_grows_and_churns() {
+        let mut map = IncrementalMap::new();
+        let mut model = HashMap::new();
+        let mut seed = 1u64;
+        for round in 0..50_000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            // the key range widens, so that the map grows through several migrations
+            let key = (seed >> 33) % (round / 4 + 16);
+            if seed % 4 == 0 {
+                assert_eq!(map.remove(&key), model.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, round), model.insert(key, round));
+            }
+            if round % 997 == 0 {
+                map.retain(|k, _| k % 7 != 0);
+                model.retain(|k, _| k % 7 != 0);
+            }
+        }
+        assert_eq!(map.len(), model.len());
+        for (k, v) in &model {
+            assert_eq!(map.get(k), Some(v));
+        }
+        let mut entries = map.into_iter().collect::<Vec<_>>();
+        entries.sort_unstable();
+        let mut expected = model.into_iter().collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(entries, expected);
+    }
 }
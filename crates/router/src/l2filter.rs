@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use capsule::batch::PacketTx;
+use capsule::net::MacAddr;
+use capsule::{metrics, Mbuf, PortQueue};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::hardcoded_routes::WithBroadcast;
+use crate::ipv6::Ipv6Prefix;
+use crate::isolation::Recover;
+use crate::loopback::divert_local;
+
+/*
+    Frames that are not addressed to a port are dropped before any GDP work is spent on them,
+    whatever the NIC lets through:
+    - a frame is kept if its destination MAC is the port's own (or the `mac` set for it in
+      l2.toml), broadcast, or one of the multicast groups listed for the port. The NIC only
+      passes multicast frames up if its port has `multicast = true` in the runtime config, which
+      preflight checks
+    - a port with `vlan` or `vlans` is a trunk for those 802.1Q VLANs, and only keeps frames
+      tagged with one of them, and untagged frames if `untagged = true`. Other ports only keep
+      untagged frames
+    - `promiscuous = true` keeps every frame, as before this filter existed
+    - dropped frames are counted by port and by what did not match
+
+    One switch can serve every segment trunked to a port. The VLAN a packet arrived on is in its
+    RxMeta, and everything a port sends is tagged on its way out (VlanTx) for the segment of the
+    next hop it is addressed to:
+    - the VLAN listed for the next hop's IP in `next_hops`, where 0 means untagged
+    - otherwise the segment the packet arrived on, so replies and NACKs go back where they came
+      from. Next hops on other segments must be listed
+    - otherwise (packets we generate), untagged if the port keeps untagged frames, or else the
+      first of its VLANs
+
+        [eth1]
+        vlans = [12, 13]
+        multicast = ["01:00:5e:00:01:81"]
+        next_hops = { "10.0.13.2" = 13 }
+
+    Ports that are not listed keep and send untagged frames for their own MAC and broadcast.
+    A port with `ipv6_prefix` faces an IPv6-only network; see ipv6.rs.
+*/
+
+const L2_FILE: &str = "l2.toml";
+const ETHERNET_HEADER_LEN: usize = 14;
+/// The TPID and TCI that a tag inserts after the source MAC
+const VLAN_TAG_LEN: usize = 4;
+/// The TPID of an 802.1Q tag, where untagged frames have their EtherType
+const VLAN_TPID: u16 = 0x8100;
+const VLAN_ID_MASK: u16 = 0x0fff;
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+/// Where the destination address is in an IPv4 header
+const IPV4_DST_OFFSET: usize = 16;
+
+#[derive(Default, Deserialize)]
+struct SerializedL2Config {
+    mac: Option<String>,
+    vlan: Option<u16>,
+    #[serde(default)]
+    vlans: Vec<u16>,
+    untagged: Option<bool>,
+    #[serde(default)]
+    next_hops: HashMap<Ipv4Addr, u16>,
+    #[serde(default)]
+    multicast: Vec<String>,
+    #[serde(default)]
+    promiscuous: bool,
+    ipv6_prefix: Option<Ipv6Addr>,
+}
+
+#[derive(Clone)]
+pub struct L2Config {
+    /// Kept in place of the port's own MAC
+    pub mac: Option<MacAddr>,
+    /// The segments the port is on: `None` for untagged frames, or a VLAN ID. Packets that no
+    /// other rule places are sent on the first
+    pub vlans: Vec<Option<u16>>,
+    /// The segment that leads to each next hop
+    pub next_hops: HashMap<Ipv4Addr, Option<u16>>,
+    /// Multicast groups the port belongs to
+    pub multicast: Vec<MacAddr>,
+    pub promiscuous: bool,
+    /// Set for ports on IPv6-only networks
+    pub ipv6_prefix: Option<Ipv6Prefix>,
+}
+
+impl Default for L2Config {
+    fn default() -> Self {
+        L2Config {
+            mac: None,
+            vlans: vec![None],
+            next_hops: HashMap::new(),
+            multicast: Vec::new(),
+            promiscuous: false,
+            ipv6_prefix: None,
+        }
+    }
+}
+
+fn parse_mac(mac: &str, port_name: &str) -> Result<MacAddr> {
+    mac.parse()
+        .map_err(|_| anyhow!("invalid MAC {} for port {}", mac, port_name))
+}
+
+/// The L2 filtering of `port_name`; ports that are not listed get the defaults
+pub fn load_l2_config(port_name: &str) -> Result<L2Config> {
+    let content = fs::read_to_string(L2_FILE)?;
+    let mut ports: HashMap<String, SerializedL2Config> = toml::from_str(&content)?;
+    let config = ports.remove(port_name).unwrap_or_default();
+    let tagged: Vec<u16> = config.vlan.into_iter().chain(config.vlans).collect();
+    for &vlan in &tagged {
+        ensure!(
+            (1..VLAN_ID_MASK).contains(&vlan),
+            "VLAN {} for port {} is not a VLAN ID (1 to 4094)",
+            vlan,
+            port_name
+        );
+    }
+    let untagged = config.untagged.unwrap_or(tagged.is_empty());
+    let vlans: Vec<Option<u16>> = untagged
+        .then(|| None)
+        .into_iter()
+        .chain(tagged.into_iter().map(Some))
+        .collect();
+    ensure!(
+        !vlans.is_empty(),
+        "port {} keeps neither untagged frames nor any VLAN",
+        port_name
+    );
+    let next_hops = config
+        .next_hops
+        .into_iter()
+        .map(|(ip, vlan)| {
+            let vlan = Some(vlan).filter(|&vlan| vlan != 0);
+            ensure!(
+                vlans.contains(&vlan),
+                "next hop {} of port {} is on a segment the port is not on",
+                ip,
+                port_name
+            );
+            Ok((ip, vlan))
+        })
+        .collect::<Result<_>>()?;
+    let multicast = config
+        .multicast
+        .iter()
+        .map(|group| {
+            let mac = parse_mac(group, port_name)?;
+            ensure!(
+                mac.octets()[0] & 1 == 1,
+                "{} for port {} is not a multicast MAC",
+                group,
+                port_name
+            );
+            Ok(mac)
+        })
+        .collect::<Result<_>>()?;
+    Ok(L2Config {
+        mac: config
+            .mac
+            .map(|mac| parse_mac(&mac, port_name))
+            .transpose()?,
+        vlans,
+        next_hops,
+        multicast,
+        promiscuous: config.promiscuous,
+        ipv6_prefix: config
+            .ipv6_prefix
+            .map(Ipv6Prefix::new)
+            .transpose()
+            .with_context(|| format!("bad ipv6_prefix for port {}", port_name))?,
+    })
+}
+
+/// The VLAN that the frame in `mbuf` is tagged with, if any
+#[inline]
+pub fn vlan_of(mbuf: &Mbuf) -> Option<u16> {
+    let header = mbuf
+        .read_data_slice::<u8>(0, ETHERNET_HEADER_LEN + 2)
+        .ok()?;
+    let header = unsafe { header.as_ref() };
+    let tagged = u16::from_be_bytes([header[12], header[13]]) == VLAN_TPID;
+    tagged.then(|| u16::from_be_bytes([header[14], header[15]]) & VLAN_ID_MASK)
+}
+
+/// The 802.1Q TCI of the frame in `mbuf`, if it is tagged, and its IPv4 destination, if it is
+/// an IPv4 packet
+fn tci_and_ipv4_dst(mbuf: &Mbuf) -> Option<(Option<u16>, Ipv4Addr)> {
+    let len = mbuf
+        .data_len()
+        .min(ETHERNET_HEADER_LEN + VLAN_TAG_LEN + IPV4_DST_OFFSET + 4);
+    let frame = unsafe { mbuf.read_data_slice::<u8>(0, len).ok()?.as_ref() };
+    if frame.len() < ETHERNET_HEADER_LEN + VLAN_TAG_LEN {
+        return None;
+    }
+    let tci = match u16::from_be_bytes([frame[12], frame[13]]) {
+        VLAN_TPID => Some(u16::from_be_bytes([frame[14], frame[15]])),
+        _ => None,
+    };
+    let ip_header = ETHERNET_HEADER_LEN + tci.map_or(0, |_| VLAN_TAG_LEN);
+    let ether_type = u16::from_be_bytes([frame[ip_header - 2], frame[ip_header - 1]]);
+    match frame.get(ip_header + IPV4_DST_OFFSET..ip_header + IPV4_DST_OFFSET + 4) {
+        Some(&[a, b, c, d]) if ether_type == ETHER_TYPE_IPV4 => {
+            Some((tci, Ipv4Addr::new(a, b, c, d)))
+        }
+        _ => None,
+    }
+}
+
+/// Where the IP header of the frame in `mbuf` starts, tagged or not, and its EtherType
+pub fn ip_header_of(mbuf: &Mbuf) -> Option<(usize, u16)> {
+    let len = mbuf.data_len().min(ETHERNET_HEADER_LEN + VLAN_TAG_LEN);
+    let frame = unsafe { mbuf.read_data_slice::<u8>(0, len).ok()?.as_ref() };
+    let ip_header = match u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]) {
+        VLAN_TPID => ETHERNET_HEADER_LEN + VLAN_TAG_LEN,
+        _ => ETHERNET_HEADER_LEN,
+    };
+    let ether_type = frame.get(ip_header - 2..ip_header)?;
+    Some((
+        ip_header,
+        u16::from_be_bytes([ether_type[0], ether_type[1]]),
+    ))
+}
+
+/// The IPv4 destination of the frame in `mbuf`, tagged or not
+pub fn ipv4_dst_of(mbuf: &Mbuf) -> Option<Ipv4Addr> {
+    tci_and_ipv4_dst(mbuf).map(|(_, dst)| dst)
+}
+
+/// Which segment a port sends each packet out on
+pub struct Egress {
+    vlans: Vec<Option<u16>>,
+    next_hops: HashMap<Ipv4Addr, Option<u16>>,
+    ipv6_prefix: Option<Ipv6Prefix>,
+}
+
+impl Egress {
+    pub fn new(config: &L2Config) -> Self {
+        Egress {
+            vlans: config.vlans.clone(),
+            next_hops: config.next_hops.clone(),
+            ipv6_prefix: config.ipv6_prefix,
+        }
+    }
+
+    /// The VLAN to reach `ip` on, for a packet that arrived on `arrival`
+    pub fn vlan_to(&self, ip: Ipv4Addr, arrival: Option<u16>) -> Option<u16> {
+        match self.next_hops.get(&ip) {
+            Some(&vlan) => vlan,
+            None if self.vlans.contains(&arrival) => arrival,
+            None => self.vlans[0],
+        }
+    }
+
+    /// Tag, retag or untag the frame in `mbuf` for the segment of its IPv4 destination
+    fn tag(&self, mbuf: &mut Mbuf) -> Result<()> {
+        let (tci, dst) = match tci_and_ipv4_dst(mbuf) {
+            Some(frame) => frame,
+            // nothing we send is anything else
+            None => return Ok(()),
+        };
+        let current = tci.map(|tci| tci & VLAN_ID_MASK);
+        match (tci, self.vlan_to(dst, current)) {
+            (_, wanted) if wanted == current => {}
+            (Some(tci), Some(vlan)) => {
+                // keeping the priority bits of the tag
+                let tci = (tci & !VLAN_ID_MASK) | vlan;
+                mbuf.write_data_slice(ETHERNET_HEADER_LEN, &tci.to_be_bytes())?;
+            }
+            (None, Some(vlan)) => {
+                mbuf.extend(12, VLAN_TAG_LEN)?;
+                let tag = [VLAN_TPID.to_be_bytes(), vlan.to_be_bytes()].concat();
+                mbuf.write_data_slice(12, &tag)?;
+            }
+            (Some(_), None) => mbuf.shrink(12, VLAN_TAG_LEN)?,
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    /// Make the frame in `mbuf` ready for the wire: tagged for its segment, and in IPv6 on IPv6
+    /// ports
+    fn prepare(&self, mbuf: &mut Mbuf) -> Result<()> {
+        self.tag(mbuf)?;
+        match self.ipv6_prefix {
+            Some(prefix) => prefix.transmit(mbuf),
+            None => Ok(()),
+        }
+    }
+}
+
+// filled in as the GDP pipeline of each port is installed, by the port's MAC
+static EGRESS: Lazy<Mutex<HashMap<[u8; 6], &'static Egress>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn egress_ports() -> &'static Mutex<HashMap<[u8; 6], &'static Egress>> {
+    &EGRESS
+}
+
+/// How the port with MAC `mac` tags what it sends, if it has a GDP pipeline
+pub fn egress_of(mac: MacAddr) -> Option<&'static Egress> {
+    egress_ports().lock().recover().get(&mac.octets()).copied()
+}
+
+/// Sends through a port queue, tagging each frame for the segment of its next hop (and turning
+/// it into IPv6 on IPv6 ports). Frames for the node itself go to its own GDP pipeline instead
+/// (see loopback.rs)
+pub struct VlanTx {
+    q: PortQueue,
+    /// Looked up when the first frames are sent, once every port has its pipeline
+    egress: Option<Option<&'static Egress>>,
+}
+
+impl VlanTx {
+    pub fn new(q: PortQueue) -> Self {
+        VlanTx { q, egress: None }
+    }
+}
+
+impl PacketTx for VlanTx {
+    fn transmit(&mut self, packets: Vec<Mbuf>) {
+        let mac = self.q.mac_addr();
+        // addressed to ourselves, these never reach the wire to be tagged
+        let packets = divert_local(mac, packets);
+        let packets = match self.egress.get_or_insert_with(|| egress_of(mac)) {
+            Some(egress) => packets
+                .into_iter()
+                .filter_map(|mut packet| egress.prepare(&mut packet).ok().map(|()| packet))
+                .collect(),
+            None => packets,
+        };
+        self.q.transmit(packets);
+    }
+}
+
+/// Drops the frames of one pipeline that are not addressed to its port
+pub struct L2Filter {
+    mac: MacAddr,
+    vlans: Vec<Option<u16>>,
+    multicast: Vec<MacAddr>,
+    promiscuous: bool,
+    wrong_mac: Counter,
+    wrong_vlan: Counter,
+    runt: Counter,
+}
+
+impl L2Filter {
+    pub fn new(config: L2Config, q: &PortQueue, nic_name: &'static str) -> Self {
+        egress_ports()
+            .lock()
+            .recover()
+            .entry(q.mac_addr().octets())
+            .or_insert_with(|| Box::leak(Box::new(Egress::new(&config))));
+        let mut sink = metrics::global().sink();
+        let mut counter = |reason: &'static str| {
+            sink.counter_with_labels("l2.filtered", vec![("nic", nic_name), ("reason", reason)])
+        };
+        L2Filter {
+            mac: config.mac.unwrap_or_else(|| q.mac_addr()),
+            vlans: config.vlans,
+            multicast: config.multicast,
+            promiscuous: config.promiscuous,
+            wrong_mac: counter("mac"),
+            wrong_vlan: counter("vlan"),
+            runt: counter("runt"),
+        }
+    }
+
+    /// Whether the frame in `mbuf` is addressed to this port
+    #[inline]
+    pub fn admit(&self, mbuf: &Mbuf) -> bool {
+        if self.promiscuous {
+            return true;
+        }
+        let len = mbuf.data_len().min(ETHERNET_HEADER_LEN + 2);
+        let header = match mbuf.read_data_slice::<u8>(0, len) {
+            Ok(header) if len >= ETHERNET_HEADER_LEN => unsafe { header.as_ref() },
+            _ => {
+                self.runt.increment();
+                return false;
+            }
+        };
+        let dst = MacAddr::new(
+            header[0], header[1], header[2], header[3], header[4], header[5],
+        );
+        if dst != self.mac && dst != MacAddr::broadcast() && !self.multicast.contains(&dst) {
+            self.wrong_mac.increment();
+            return false;
+        }
+        let tagged = u16::from_be_bytes([header[12], header[13]]) == VLAN_TPID;
+        let vlan = match header.get(ETHERNET_HEADER_LEN..) {
+            Some([high, low]) if tagged => Some(u16::from_be_bytes([*high, *low]) & VLAN_ID_MASK),
+            _ if tagged => {
+                self.runt.increment();
+                return false;
+            }
+            _ => None,
+        };
+        if !self.vlans.contains(&vlan) {
+            self.wrong_vlan.increment();
+            return false;
+        }
+        true
+    }
+}
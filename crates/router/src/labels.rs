@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use gdp_proto::{GdpHeader, GdpName, SecurityLabel, EXT_SECURITY_LABEL};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+use crate::secrets::decode_hex;
+
+/*
+   Packets may carry a security label: a classification level and compartment bits, set by the
+   application that sends them. Switches never remove it, so it stays with the packet on every
+   hop, and with every fragment of it. With `enforce` in labels.toml, a switch holds labelled
+   packets to the clearances of its next hops, whatever its feature flags, and so do the copies
+   it makes for migrating names and canaries:
+   - a packet only goes to a next hop whose clearance dominates its label. Next hops without a
+     clearance are cleared for unlabelled packets only, so nothing flows from high to low that
+     labels.toml does not allow
+   - a downgrade rule allows packets of one level (to one destination, if it names one) to be
+     relabelled lower, keeping only some of their compartments. A packet that its next hop is not
+     cleared for leaves relabelled by the first rule that lets it go there
+   - a packet that may not go to its next hop is dropped without a NACK, which would tell the
+     sender where the route leads, and counted
+   - every decision on a labelled packet is written to the audit log, a line each, by a task on
+     core 0. A packet whose decision cannot be audited, as the task is MAX_PENDING lines behind,
+     is dropped and counted, so that nothing crosses unaudited
+   Unlabelled packets are unclassified, which every next hop is cleared for.
+*/
+
+/// How often the audit log is written out
+pub const AUDIT_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+/// Audit lines waiting to be written at most
+const MAX_PENDING: usize = 4096;
+
+#[derive(Deserialize)]
+struct SerializedLabelConfig {
+    #[serde(default)]
+    enforce: bool,
+    #[serde(default)]
+    clearances: Vec<Clearance>,
+    #[serde(default)]
+    downgrades: Vec<SerializedDowngrade>,
+    #[serde(default = "default_audit_path")]
+    audit_path: PathBuf,
+}
+
+fn default_audit_path() -> PathBuf {
+    "labels.audit".into()
+}
+
+#[derive(Deserialize)]
+struct Clearance {
+    ip: Ipv4Addr,
+    level: u8,
+    #[serde(default)]
+    compartments: u32,
+}
+
+#[derive(Deserialize)]
+struct SerializedDowngrade {
+    from_level: u8,
+    to_level: u8,
+    #[serde(default)]
+    keep_compartments: u32,
+    /// In hex
+    dst: Option<String>,
+}
+
+/// Packets of `from_level` may leave relabelled as `to_level`
+struct Downgrade {
+    from_level: u8,
+    to_level: u8,
+    /// The compartments the relabelled packet keeps, of those it had
+    keep_compartments: u32,
+    /// Only for packets to this name, if set
+    dst: Option<GdpName>,
+}
+
+impl Downgrade {
+    fn apply(&self, label: SecurityLabel, dst: GdpName) -> Option<SecurityLabel> {
+        if label.level != self.from_level || self.dst.map_or(false, |name| name != dst) {
+            return None;
+        }
+        Some(SecurityLabel {
+            level: self.to_level,
+            compartments: label.compartments & self.keep_compartments,
+        })
+    }
+}
+
+pub struct LabelPolicy {
+    pub enforce: bool,
+    clearances: HashMap<Ipv4Addr, SecurityLabel>,
+    downgrades: Vec<Downgrade>,
+    audit_path: PathBuf,
+}
+
+impl Default for LabelPolicy {
+    fn default() -> Self {
+        LabelPolicy {
+            enforce: false,
+            clearances: HashMap::new(),
+            downgrades: Vec::new(),
+            audit_path: default_audit_path(),
+        }
+    }
+}
+
+impl LabelPolicy {
+    fn clearance(&self, next_hop: Ipv4Addr) -> SecurityLabel {
+        self.clearances
+            .get(&next_hop)
+            .copied()
+            .unwrap_or(SecurityLabel::UNCLASSIFIED)
+    }
+
+    /// The label a packet with `label` leaves with for a next hop cleared for `clearance`, if
+    /// it may go there at all
+    fn relabel(
+        &self,
+        label: SecurityLabel,
+        dst: GdpName,
+        clearance: SecurityLabel,
+    ) -> Option<SecurityLabel> {
+        if clearance.dominates(label) {
+            return Some(label);
+        }
+        self.downgrades
+            .iter()
+            .filter_map(|downgrade| downgrade.apply(label, dst))
+            .find(|lower| clearance.dominates(*lower))
+    }
+}
+
+pub fn load_label_policy() -> Result<LabelPolicy> {
+    parse_label_policy(&fs::read_to_string("labels.toml")?)
+}
+
+fn parse_label_policy(content: &str) -> Result<LabelPolicy> {
+    let config: SerializedLabelConfig = toml::from_str(content)?;
+    let downgrades = config
+        .downgrades
+        .into_iter()
+        .map(|downgrade| {
+            ensure!(
+                downgrade.to_level <= downgrade.from_level,
+                "downgrade from level {} to {} raises the level",
+                downgrade.from_level,
+                downgrade.to_level
+            );
+            let dst = downgrade
+                .dst
+                .map(|dst| {
+                    decode_hex(&dst)?
+                        .try_into()
+                        .map_err(|_| anyhow!("downgrade destination {} is not a GdpName", dst))
+                })
+                .transpose()?;
+            Ok(Downgrade {
+                from_level: downgrade.from_level,
+                to_level: downgrade.to_level,
+                keep_compartments: downgrade.keep_compartments,
+                dst,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(LabelPolicy {
+        enforce: config.enforce,
+        clearances: config
+            .clearances
+            .into_iter()
+            .map(|clearance| {
+                let label = SecurityLabel {
+                    level: clearance.level,
+                    compartments: clearance.compartments,
+                };
+                (clearance.ip, label)
+            })
+            .collect(),
+        downgrades,
+        audit_path: config.audit_path,
+    })
+}
+
+struct Audit {
+    policy: LabelPolicy,
+    pending: Mutex<Vec<String>>,
+    writer: Mutex<Option<BufWriter<File>>>,
+    dropped: Counter,
+}
+
+// created on first use, shared by every switch pipeline and the flush task
+static AUDIT: Lazy<Audit> = Lazy::new(|| Audit {
+    policy: load_label_policy().unwrap_or_default(),
+    pending: Mutex::new(Vec::new()),
+    writer: Mutex::new(None),
+    dropped: metrics::global().sink().counter("labels.audit_dropped"),
+});
+
+fn audit() -> &'static Audit {
+    &AUDIT
+}
+
+fn hex(name: &GdpName) -> String {
+    name.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn describe(label: SecurityLabel) -> String {
+    format!("{}/{:#x}", label.level, label.compartments)
+}
+
+/// Holds the labelled packets of one pipeline to the clearances of their next hops
+#[derive(Clone)]
+pub struct LabelGuard {
+    nic_name: &'static str,
+    audit: &'static Audit,
+    downgraded: Counter,
+    refused: Counter,
+}
+
+impl LabelGuard {
+    pub fn new(nic_name: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut decisions = |decision: &'static str| {
+            sink.counter_with_labels(
+                "labels.decisions",
+                vec![("nic", nic_name), ("decision", decision)],
+            )
+        };
+        let (downgraded, refused) = (decisions("downgraded"), decisions("refused"));
+        LabelGuard {
+            nic_name,
+            audit: audit(),
+            downgraded,
+            refused,
+        }
+    }
+
+    /// Whether `packet` may go to `next_hop`, relabelling it if a downgrade rule lets it
+    pub fn permit(
+        &self,
+        packet: &mut Gdp<DTls<Ipv4>>,
+        next_hop: Ipv4Addr,
+        debug: bool,
+    ) -> Result<bool> {
+        let policy = &self.audit.policy;
+        if !policy.enforce || packet.header_len() == GdpHeader::LEN as usize {
+            return Ok(true);
+        }
+        let mut extensions = packet.extensions()?.to_vec();
+        let label = match SecurityLabel::find(&extensions)? {
+            Some(label) => label,
+            None => return Ok(true),
+        };
+        let relabelled = policy.relabel(label, packet.dst(), policy.clearance(next_hop));
+        let decision = match relabelled {
+            Some(lower) if lower != label => {
+                self.downgraded.increment();
+                for extension in extensions.iter_mut() {
+                    if extension.kind == EXT_SECURITY_LABEL {
+                        *extension = lower.to_extension();
+                    }
+                }
+                packet.set_extensions(&extensions)?;
+                format!("downgraded to {}", describe(lower))
+            }
+            Some(_) => "forwarded".to_owned(),
+            None => {
+                self.refused.increment();
+                "refused".to_owned()
+            }
+        };
+        let line = format!(
+            "{} {} {:016x} src={} dst={} next_hop={} label={} {}",
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros(),
+            self.nic_name,
+            packet.trace_id(),
+            hex(&packet.src()),
+            hex(&packet.dst()),
+            next_hop,
+            describe(label),
+            decision
+        );
+        if debug {
+            println!("{} label audit: {}", self.nic_name, line);
+        }
+        let mut pending = self.audit.pending.lock().recover();
+        if pending.len() >= MAX_PENDING {
+            self.audit.dropped.increment();
+            return Ok(false);
+        }
+        pending.push(line);
+        Ok(relabelled.is_some())
+    }
+}
+
+/// Append the audit lines of the decisions since the last call to the audit log
+pub fn flush_label_audit() {
+    let audit = audit();
+    if !audit.policy.enforce {
+        return;
+    }
+    let pending = std::mem::take(&mut *audit.pending.lock().recover());
+    if pending.is_empty() {
+        return;
+    }
+    let mut writer = audit.writer.lock().recover();
+    if let Err(err) = write_pending(&mut writer, &audit.policy.audit_path, pending) {
+        println!("failed to write the label audit log: {:#}", err);
+    }
+}
+
+fn write_pending(
+    writer: &mut Option<BufWriter<File>>,
+    path: &Path,
+    pending: Vec<String>,
+) -> Result<()> {
+    if writer.is_none() {
+        // appended to, so that restarts never lose what was audited before them
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        *writer = Some(BufWriter::new(file));
+    }
+    let writer = writer.as_mut().unwrap();
+    for line in pending {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: u8 = 3;
+    const CONFIDENTIAL: u8 = 2;
+
+    fn label(level: u8, compartments: u32) -> SecurityLabel {
+        SecurityLabel {
+            level,
+            compartments,
+        }
+    }
+
+    fn downgrade(dst: Option<GdpName>) -> Downgrade {
+        Downgrade {
+            from_level: SECRET,
+            to_level: CONFIDENTIAL,
+            keep_compartments: 0b01,
+            dst,
+        }
+    }
+
+    #[test]
+    fn downgrades_apply_to_their_level_and_destination() {
+        let (dst, other) = ([1; 32], [2; 32]);
+        assert_eq!(
+            downgrade(None).apply(label(SECRET, 0b11), dst),
+            Some(label(CONFIDENTIAL, 0b01))
+        );
+        assert_eq!(downgrade(None).apply(label(CONFIDENTIAL, 0b11), dst), None);
+        assert_eq!(
+            downgrade(Some(dst)).apply(label(SECRET, 0b10), dst),
+            Some(label(CONFIDENTIAL, 0))
+        );
+        assert_eq!(downgrade(Some(dst)).apply(label(SECRET, 0b11), other), None);
+    }
+
+    #[test]
+    fn relabels_only_as_far_as_a_rule_allows() {
+        let dst = [1; 32];
+        let policy = LabelPolicy {
+            downgrades: vec![downgrade(Some(dst))],
+            ..LabelPolicy::default()
+        };
+        let cleared = label(CONFIDENTIAL, 0b01);
+        // what the next hop is cleared for goes as it is
+        assert_eq!(
+            policy.relabel(label(CONFIDENTIAL, 0b01), dst, cleared),
+            Some(label(CONFIDENTIAL, 0b01))
+        );
+        assert_eq!(
+            policy.relabel(label(SECRET, 0b11), dst, cleared),
+            Some(label(CONFIDENTIAL, 0b01))
+        );
+        // no rule for other destinations, nor for compartments the next hop lacks
+        assert_eq!(policy.relabel(label(SECRET, 0b11), [2; 32], cleared), None);
+        assert_eq!(
+            policy.relabel(label(CONFIDENTIAL, 0b10), dst, cleared),
+            None
+        );
+        // next hops without a clearance take unlabelled packets only
+        assert_eq!(
+            policy.relabel(
+                label(SECRET, 0),
+                dst,
+                policy.clearance(Ipv4Addr::new(10, 0, 0, 1))
+            ),
+            None
+        );
+        assert_eq!(
+            policy.relabel(
+                SecurityLabel::UNCLASSIFIED,
+                dst,
+                SecurityLabel::UNCLASSIFIED
+            ),
+            Some(SecurityLabel::UNCLASSIFIED)
+        );
+    }
+
+    #[test]
+    fn reads_policies() {
+        let policy = parse_label_policy(&format!(
+            r#"
+            enforce = true
+            audit_path = "/var/log/labels.audit"
+
+            [[clearances]]
+            ip = "10.0.0.1"
+            level = 3
+            compartments = 5
+
+            [[downgrades]]
+            from_level = 3
+            to_level = 2
+            keep_compartments = 1
+            dst = "{}"
+            "#,
+            "01".repeat(32)
+        ))
+        .unwrap();
+        assert!(policy.enforce);
+        assert_eq!(policy.clearance(Ipv4Addr::new(10, 0, 0, 1)), label(3, 5));
+        assert_eq!(
+            policy.clearance(Ipv4Addr::new(10, 0, 0, 2)),
+            SecurityLabel::UNCLASSIFIED
+        );
+        assert_eq!(policy.downgrades.len(), 1);
+        assert_eq!(policy.downgrades[0].dst, Some([1; 32]));
+        assert_eq!(policy.audit_path, Path::new("/var/log/labels.audit"));
+
+        let defaults = parse_label_policy("").unwrap();
+        assert!(!defaults.enforce);
+        assert_eq!(defaults.audit_path, default_audit_path());
+    }
+
+    #[test]
+    fn refuses_downgrades_that_raise_or_misname() {
+        let raising = "[[downgrades]]\nfrom_level = 1\nto_level = 2\n";
+        assert!(parse_label_policy(raising).is_err());
+        let misnamed = "[[downgrades]]\nfrom_level = 2\nto_level = 1\ndst = \"0102\"\n";
+        assert!(parse_label_policy(misnamed).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use capsule::batch::Batch;
+use capsule::packets::ip::v4::Ipv4;
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+
+/*
+   With the `latency` feature, a pipeline times its packets at the end of some of its stages:
+   how long after its burst was pulled from the RX queue each packet got there goes into an HDR
+   histogram of the pipeline, stage and GDP action. Every stage is timed from that same arrival,
+   so the time spent in a stage is the difference to the one before it:
+   - each stats dump prints the percentiles of every histogram since the dump before, and keeps
+     their p50 and p99 in the history written to statistics.tsv (see statistics.rs)
+   - a histogram is only created once its pipeline, stage and action sees a packet, as most
+     actions never pass most stages
+   - packets without an arrival, which a pipeline made itself, are not timed
+   Without the feature, the timing compiles away.
+*/
+
+#[cfg(feature = "latency")]
+mod timing {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use capsule::batch::{Batch, Disposition};
+    use capsule::packets::ip::v4::Ipv4;
+    use gdp_proto::GdpAction;
+    use hdrhistogram::Histogram;
+    use once_cell::sync::Lazy;
+
+    use crate::dtls::DTls;
+    use crate::gdp::Gdp;
+
+    /// Latencies above this are recorded as this
+    const MAX_LATENCY_NS: u64 = 1_000_000_000;
+    /// Precision of the histograms, which cost memory by the power of ten
+    const SIGNIFICANT_DIGITS: u8 = 2;
+    /// Entries of `StageHistograms::by_action`: one per action byte, and the last for the action
+    /// bytes this version does not know
+    const ACTIONS: usize = u8::MAX as usize + 2;
+
+    /// The latencies of one stage of one pipeline, by action
+    struct StageHistograms {
+        nic_name: &'static str,
+        stage: &'static str,
+        by_action: Mutex<Vec<Option<Histogram<u64>>>>,
+    }
+
+    impl StageHistograms {
+        fn record(&self, packet: &Gdp<DTls<Ipv4>>) {
+            let arrival = match packet.rx_meta() {
+                Some(rx_meta) => rx_meta.rx_timestamp,
+                None => return,
+            };
+            let latency_ns = arrival.elapsed().as_nanos() as u64;
+            let index = match GdpAction::try_from(packet.raw_action()) {
+                Ok(_) => packet.raw_action() as usize,
+                Err(_) => ACTIONS - 1,
+            };
+            let mut by_action = self.by_action.lock().recover();
+            by_action[index]
+                .get_or_insert_with(|| {
+                    Histogram::new_with_bounds(1, MAX_LATENCY_NS, SIGNIFICANT_DIGITS)
+                        .expect("constant bounds are valid")
+                })
+                .saturating_record(latency_ns.max(1));
+        }
+    }
+
+    // created on first use, and shared by every pipeline and the stats dump
+    static TIMED: Lazy<Mutex<Vec<&'static StageHistograms>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+    fn timed() -> &'static Mutex<Vec<&'static StageHistograms>> {
+        &TIMED
+    }
+
+    fn action_name(index: usize) -> String {
+        match GdpAction::try_from(index as u8) {
+            Ok(action) if index < ACTIONS - 1 => format!("{:?}", action),
+            _ => "unknown".to_owned(),
+        }
+    }
+
+    /// Print the percentiles of the latencies since the last call, which are then forgotten,
+    /// and add their p50 and p99 to `history`
+    pub fn print_latencies(history: &mut HashMap<String, Vec<u64>>) {
+        for stage in timed().lock().recover().iter() {
+            let mut by_action = stage.by_action.lock().recover();
+            for (index, histogram) in by_action.iter_mut().enumerate() {
+                let histogram = match histogram {
+                    Some(histogram) => histogram,
+                    None => continue,
+                };
+                let labels = format!(
+                    "nic={},stage={},action={}",
+                    stage.nic_name,
+                    stage.stage,
+                    action_name(index)
+                );
+                // pushed even when empty, so that the history's columns stay aligned
+                let (p50, p99) = match histogram.len() {
+                    0 => (0, 0),
+                    _ => (
+                        histogram.value_at_quantile(0.5),
+                        histogram.value_at_quantile(0.99),
+                    ),
+                };
+                if histogram.len() > 0 {
+                    let us = |ns: u64| ns as f64 / 1000.0;
+                    println!(
+                        "latency {}: {} packets, p50/p90/p99/max {:.1}/{:.1}/{:.1}/{:.1} us",
+                        labels,
+                        histogram.len(),
+                        us(p50),
+                        us(histogram.value_at_quantile(0.9)),
+                        us(p99),
+                        us(histogram.max())
+                    );
+                }
+                for (quantile, value) in [("p50", p50), ("p99", p99)] {
+                    history
+                        .entry(format!("latency.{}_ns {}", quantile, labels))
+                        .or_insert_with(Vec::new)
+                        .push(value);
+                }
+                histogram.reset();
+            }
+        }
+    }
+
+    pub struct StageTimer<B: Batch> {
+        batch: B,
+        histograms: &'static StageHistograms,
+    }
+
+    impl<B: Batch> StageTimer<B> {
+        pub fn new(batch: B, nic_name: &'static str, stage: &'static str) -> Self {
+            let histograms: &'static StageHistograms = Box::leak(Box::new(StageHistograms {
+                nic_name,
+                stage,
+                by_action: Mutex::new((0..ACTIONS).map(|_| None).collect()),
+            }));
+            timed().lock().recover().push(histograms);
+            StageTimer { batch, histograms }
+        }
+    }
+
+    impl<B: Batch<Item = Gdp<DTls<Ipv4>>>> Batch for StageTimer<B> {
+        type Item = B::Item;
+
+        #[inline]
+        fn replenish(&mut self) {
+            self.batch.replenish();
+        }
+
+        #[inline]
+        fn next(&mut self) -> Option<Disposition<Self::Item>> {
+            let next = self.batch.next();
+            if let Some(Disposition::Act(packet)) = &next {
+                self.histograms.record(packet);
+            }
+            next
+        }
+    }
+}
+
+#[cfg(feature = "latency")]
+pub use timing::{print_latencies, StageTimer};
+
+pub trait TimeStage: Batch<Item = Gdp<DTls<Ipv4>>> + Sized {
+    /// Record how long after their arrival the packets get through this stage, and the ones
+    /// before it
+    #[cfg(feature = "latency")]
+    fn time_stage(self, nic_name: &'static str, stage: &'static str) -> StageTimer<Self> {
+        StageTimer::new(self, nic_name, stage)
+    }
+
+    #[cfg(not(feature = "latency"))]
+    #[inline]
+    fn time_stage(self, _nic_name: &'static str, _stage: &'static str) -> Self {
+        self
+    }
+}
+
+impl<T: Batch<Item = Gdp<DTls<Ipv4>>>> TimeStage for T {}
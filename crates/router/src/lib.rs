@@ -0,0 +1,172 @@
+//! GDP routing on Capsule.
+//!
+//! The `gdp` binary runs the nodes of our deployments; other Capsule applications
+//! can embed GDP forwarding in their own runtime through [`GdpSwitch`].
+//!
+//! Everything that only switches, sidecars and clients need is behind the default `switch`
+//! feature. Without it, the crate has just what the RIB runs (see the `gdp-rib` binary).
+//! The single-host dev topology is behind the `sim` feature, for the `gdp-sim` binary.
+
+#![feature(type_alias_impl_trait)]
+#![feature(drain_filter)]
+#![cfg_attr(feature = "catch-panics", feature(backtrace))]
+// without the switch, some of the shared packet handling has no callers
+#![cfg_attr(not(feature = "switch"), allow(dead_code))]
+
+use clap::arg_enum;
+
+pub use crate::certificates::GdpMeta;
+pub use crate::clock::Clock;
+#[cfg(feature = "sim")]
+pub use crate::devsetup::start_dev_server;
+pub use crate::dtls::set_key;
+#[cfg(feature = "switch")]
+pub use crate::embed::{GdpSwitch, SwitchConfig};
+pub use crate::flags::{load_flags, FeatureFlags, Flag};
+pub use crate::identity::PortIdentity;
+use crate::kvs::FwdTableEntry;
+pub use crate::kvs::{SharedStore, PUBLISH_INTERVAL};
+#[cfg(feature = "switch")]
+pub use crate::labels::{flush_label_audit, AUDIT_FLUSH_INTERVAL};
+#[cfg(feature = "switch")]
+pub use crate::observer::start_observer;
+#[cfg(feature = "switch")]
+pub use crate::ping::start_ping_client;
+use crate::pipeline::GdpPipeline;
+#[cfg(feature = "switch")]
+pub use crate::prefetch::{PrefetchPredictor, Prefetcher, SequentialPredictor};
+#[cfg(feature = "switch")]
+pub use crate::prodsetup::start_switch_server;
+pub use crate::prometheus::parse_metrics_addr;
+#[cfg(feature = "switch")]
+pub use crate::recorder::replay;
+pub use crate::registration::issue_fleet_tokens;
+pub use crate::ribdb::{export_rib_dump, import_rib_dump};
+pub use crate::ribsetup::start_rib_server;
+pub use crate::secrets::load_secrets;
+#[cfg(feature = "switch")]
+pub use crate::sidecar::start_sidecar_listener;
+#[cfg(feature = "switch")]
+use crate::statistics::dump_history;
+#[cfg(feature = "switch")]
+pub use crate::status::{StatusBoard, STATUS_INTERVAL};
+#[cfg(feature = "switch")]
+pub use crate::usage::{UsageMeter, REPORT_INTERVAL};
+#[cfg(feature = "switch")]
+pub use crate::workloads::start_client_server;
+
+#[cfg(feature = "switch")]
+mod admission;
+mod blocklist;
+mod branches;
+#[cfg(feature = "switch")]
+mod budget;
+mod canary;
+mod capabilities;
+mod certificates;
+mod chaos;
+mod clientflows;
+mod clock;
+mod conntrack;
+mod control;
+mod dedup;
+#[cfg(feature = "sim")]
+mod devsetup;
+mod discovery;
+mod dtls;
+#[cfg(feature = "switch")]
+mod embed;
+mod flags;
+mod fragment;
+mod gdp;
+mod gdp_pipeline;
+#[cfg(feature = "switch")]
+mod gdpbatch;
+#[cfg(feature = "switch")]
+mod handoff;
+mod hardcoded_routes;
+mod identity;
+mod info;
+#[cfg(feature = "switch")]
+mod inject;
+#[cfg(feature = "switch")]
+mod invalidation;
+mod ipv6;
+mod isolation;
+mod kvs;
+mod l2filter;
+#[cfg(feature = "switch")]
+mod labels;
+mod latency;
+mod loopback;
+mod missbuffer;
+#[cfg(feature = "switch")]
+mod observer;
+mod offload;
+mod packet_logging;
+mod packet_ops;
+#[cfg(feature = "switch")]
+mod ping;
+mod pipeline;
+#[cfg(feature = "switch")]
+mod prefetch;
+mod preflight;
+mod priority;
+#[cfg(feature = "switch")]
+mod probe;
+#[cfg(feature = "switch")]
+mod prodsetup;
+mod prometheus;
+#[cfg(feature = "switch")]
+mod puts;
+#[cfg(feature = "switch")]
+mod recorder;
+mod registration;
+mod rehash;
+mod rib;
+mod ribdb;
+mod ribpayload;
+mod ribreplies;
+mod ribsetup;
+mod ribsync;
+mod runtime;
+mod rxmeta;
+mod schedule;
+mod scratch;
+mod secrets;
+#[cfg(feature = "switch")]
+mod sidecar;
+#[cfg(feature = "switch")]
+mod statistics;
+#[cfg(feature = "switch")]
+mod status;
+#[cfg(feature = "switch")]
+mod switch;
+mod telemetry;
+mod txbatch;
+mod unknown_action;
+#[cfg(feature = "switch")]
+mod usage;
+#[cfg(feature = "switch")]
+mod workloads;
+
+/// Packet handling internals, exposed for the benchmarks in `benches/`
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::certificates::{CertDest, Certificate, NewRtCert, RtCert};
+    pub use crate::dtls::{decrypt_gdp, encrypt_gdp, DTls};
+    pub use crate::gdp::{CertificateBlock, Gdp};
+    pub use crate::hardcoded_routes::{gdp_name_of_index, metadata_of_index, private_key_of_index};
+    pub use crate::priority::PriorityConfig;
+    pub use crate::rib::create_control_request;
+    pub use crate::txbatch::{LowLatency, SendBatched, TxConfig};
+}
+
+arg_enum! {
+    #[derive(PartialEq, Copy, Clone)]
+    pub enum Env {
+        Local,
+        Aws,
+        Nuc,
+    }
+}
@@ -0,0 +1,124 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+
+use capsule::batch::{Batch, Disposition};
+use capsule::net::MacAddr;
+use capsule::{metrics, Mbuf};
+use metrics_runtime::data::Counter;
+
+use crate::l2filter::ipv4_dst_of;
+
+/*
+   Packets that a node sends to itself never go on the wire, where they would have to come back
+   through the NIC (if it hairpins them at all):
+   - whatever is sent from a port to the port's own IP address is taken out at egress (VlanTx),
+     so it does not matter whether a schedule addressed it there or it was routed there because
+     the next hop of a name resolved to us
+   - it is handed to the GDP pipeline of the same port on the same core, after the frames of its
+     next burst, and goes through the pipeline as if it had arrived: decrypted, parsed and
+     dispatched to the handler for its action
+   - a core without a GDP pipeline for the port sends it on the wire, as before
+   - at most LOOPBACK_LIMIT packets wait for their pipeline; more than that are dropped and
+     counted
+*/
+
+/// The most packets that wait to be delivered locally on one port of one core
+const LOOPBACK_LIMIT: usize = 1024;
+
+struct LocalPort {
+    addr: Ipv4Addr,
+    waiting: VecDeque<Mbuf>,
+    delivered: Counter,
+    dropped: Counter,
+}
+
+thread_local! {
+    /// The ports whose GDP pipeline runs on this core, by their MAC
+    static LOCAL: RefCell<HashMap<[u8; 6], LocalPort>> = RefCell::new(HashMap::new());
+}
+
+/// Take the packets addressed to the port with MAC `mac` itself out of `packets`, and queue them
+/// for its GDP pipeline on this core, if it has one
+pub fn divert_local(mac: MacAddr, packets: Vec<Mbuf>) -> Vec<Mbuf> {
+    LOCAL.with(|local| match local.borrow_mut().get_mut(&mac.octets()) {
+        Some(port) => packets
+            .into_iter()
+            .filter_map(|packet| {
+                if ipv4_dst_of(&packet) != Some(port.addr) {
+                    return Some(packet);
+                }
+                if port.waiting.len() < LOOPBACK_LIMIT {
+                    port.delivered.increment();
+                    port.waiting.push_back(packet);
+                } else {
+                    port.dropped.increment();
+                }
+                None
+            })
+            .collect(),
+        None => packets,
+    })
+}
+
+/// Follows each burst of a port with the packets this core sent to the port itself
+pub struct LoopBack<B: Batch> {
+    batch: B,
+    nic_name: &'static str,
+    mac: [u8; 6],
+    addr: Ipv4Addr,
+    registered: bool,
+}
+
+impl<B: Batch<Item = Mbuf>> Batch for LoopBack<B> {
+    type Item = Mbuf;
+
+    #[inline]
+    fn replenish(&mut self) {
+        if !self.registered {
+            // only now are we on the core that the pipeline runs on
+            let mut sink = metrics::global().sink();
+            let mut counter = |result: &'static str| {
+                sink.counter_with_labels(
+                    "loopback",
+                    vec![("nic", self.nic_name), ("result", result)],
+                )
+            };
+            let port = LocalPort {
+                addr: self.addr,
+                waiting: VecDeque::new(),
+                delivered: counter("delivered"),
+                dropped: counter("dropped"),
+            };
+            LOCAL.with(|local| local.borrow_mut().insert(self.mac, port));
+            self.registered = true;
+        }
+        self.batch.replenish();
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        self.batch.next().or_else(|| {
+            LOCAL.with(|local| {
+                let mut local = local.borrow_mut();
+                let packet = local.get_mut(&self.mac)?.waiting.pop_front()?;
+                Some(Disposition::Act(packet))
+            })
+        })
+    }
+}
+
+pub trait LoopBackBatch: Batch<Item = Mbuf> + Sized {
+    /// Deliver what this core sends to `addr` from the port with MAC `mac` to this batch
+    fn loop_back(self, nic_name: &'static str, mac: MacAddr, addr: Ipv4Addr) -> LoopBack<Self> {
+        LoopBack {
+            batch: self,
+            nic_name,
+            mac: mac.octets(),
+            addr,
+            registered: false,
+        }
+    }
+}
+
+impl<T: Batch<Item = Mbuf>> LoopBackBatch for T {}
@@ -1,64 +1,29 @@
 #![feature(array_methods)]
-#![feature(type_alias_impl_trait)]
-#![feature(drain_filter)]
 
 use std::fs;
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
 
 use anyhow::Result;
-use capsule::packets::ip::v4::Ipv4;
 use clap::{arg_enum, clap_app, value_t};
-use sidecar::start_sidecar_listener;
+use gdp_router::{
+    load_flags, load_secrets, parse_metrics_addr, set_key, start_client_server, start_observer,
+    start_ping_client, start_rib_server, start_sidecar_listener, start_switch_server, Env,
+};
 use tracing::Level;
 use tracing_subscriber::fmt;
 
-use crate::devsetup::start_dev_server;
-use crate::dtls::DTls;
-use crate::kvs::FwdTableEntry;
-use crate::pipeline::GdpPipeline;
-use crate::prodsetup::{start_rib_server, start_switch_server};
-use crate::statistics::dump_history;
-use crate::workloads::start_client_server;
-
-mod certificates;
-mod devsetup;
-mod dtls;
-mod gdp;
-mod gdp_pipeline;
-mod gdpbatch;
-mod hardcoded_routes;
-mod inject;
-mod kvs;
-mod packet_logging;
-mod packet_ops;
-mod pipeline;
-mod prodsetup;
-mod rib;
-mod ribpayload;
-mod runtime;
-mod schedule;
-mod sidecar;
-mod statistics;
-mod switch;
-mod workloads;
-
 arg_enum! {
     #[derive(PartialEq)]
     enum Mode {
-        Dev,
         Client,
         Sidecar,
         Router,
+        // the same as Router
+        Rib,
         Switch,
-    }
-}
-
-arg_enum! {
-    #[derive(PartialEq, Copy, Clone)]
-    pub enum Env {
-        Local,
-        Aws,
-        Nuc,
+        Observer,
+        Ping,
     }
 }
 
@@ -77,25 +42,24 @@ fn main() -> Result<()> {
     let matches = clap_app!(capsule =>
         (@arg mode: -m --mode * +takes_value possible_values(&modes[..]) "The type of this node")
         (@arg env: -e --env * +takes_value possible_values(&envs[..]) "The environment in which this node is running")
-        (@arg name: -n --name +takes_value "The GDPName of this node (used for packet filtering)")
+        (@arg name: -n --name +takes_value visible_alias("gdp-index") "The GDPName of this node (used for packet filtering), or for Ping mode of the switch to ping")
         (@arg ip: --ip +takes_value "The IP address of this node")
         (@arg switch: -s --switch +takes_value "The IP address of the local switch")
-        (@arg use_default: --default !takes_value "For Router mode, send default response even when GDP Name is invalid")
+        (@arg use_default: --default !takes_value visible_alias("use-default-routes") "For Router mode, send default response even when GDP Name is invalid")
         (@arg debug: -d --debug !takes_value "Show detailed debugging messages")
+        (@arg control: --control +takes_value "The localhost UDP port on which to accept control commands")
+        (@arg metrics: --metrics +takes_value "For Switch and Router mode, the TCP port on localhost (or address and port) on which to serve metrics to Prometheus")
+        (@arg handoff: --handoff +takes_value "For Switch mode, the Unix socket through which to take over from a running switch, and hand over to the next")
     )
     .get_matches();
 
     let mode = value_t!(matches, "mode", Mode).unwrap_or_else(|e| e.exit());
     let env = value_t!(matches, "env", Env).unwrap_or_else(|e| e.exit());
 
-    let path = if mode == Mode::Dev {
-        "conf.toml"
-    } else {
-        match env {
-            Env::Local => "conf.toml",
-            Env::Aws => "ec2.toml",
-            Env::Nuc => "nuc.toml",
-        }
+    let path = match env {
+        Env::Local => "conf.toml",
+        Env::Aws => "ec2.toml",
+        Env::Nuc => "nuc.toml",
     };
 
     let content = fs::read_to_string(path)?;
@@ -107,11 +71,41 @@ fn main() -> Result<()> {
 
     let use_default = matches.is_present("use_default");
     let debug = matches.is_present("debug");
+    let control_port = value_t!(matches, "control", u16).ok();
+    let metrics_addr = matches
+        .value_of("metrics")
+        .map(parse_metrics_addr)
+        .transpose()?;
+    let handoff = matches.value_of("handoff").map(PathBuf::from);
+    let flags = load_flags()?;
+
+    // a broken secrets file is reported along with everything else before the ports are bound
+    if let Some(key) = load_secrets().ok().and_then(|secrets| secrets.dtls_key) {
+        set_key(key)?;
+    }
 
     match mode {
-        Mode::Dev => start_dev_server(config),
-        Mode::Router => start_rib_server(config, env, ip_addr?, use_default, debug),
-        Mode::Switch => start_switch_server(config, env, gdp_name?, ip_addr?, debug),
+        Mode::Router | Mode::Rib => start_rib_server(
+            config,
+            env,
+            ip_addr?,
+            use_default,
+            flags,
+            control_port,
+            metrics_addr,
+            debug,
+        ),
+        Mode::Switch => start_switch_server(
+            config,
+            env,
+            gdp_name?,
+            ip_addr?,
+            flags,
+            control_port,
+            metrics_addr,
+            handoff,
+            debug,
+        ),
         Mode::Client => start_client_server(config, ip_addr?, switch_addr?, env),
         Mode::Sidecar => start_sidecar_listener(
             config,
@@ -119,8 +113,14 @@ fn main() -> Result<()> {
             ip_addr?,
             switch_addr?,
             "sidecar",
+            flags,
+            control_port,
             debug,
             env,
         ),
+        Mode::Observer => start_observer(config, env, debug),
+        Mode::Ping => {
+            start_ping_client(config, gdp_name?, ip_addr?, switch_addr?, flags, env, debug)
+        }
     }
 }
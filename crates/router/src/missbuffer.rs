@@ -0,0 +1,243 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use capsule::batch::{Batch, Disposition};
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::GdpAction;
+use metrics_runtime::data::Counter;
+
+use crate::conntrack::FlowKey;
+use crate::dtls::DTls;
+use crate::flags::FeatureFlags;
+use crate::gdp::Gdp;
+use crate::kvs::{PendingLimits, PendingQueue, Store};
+
+/*
+   A packet that a switch has no route for is held while the RIB is asked, rather than NACKed
+   straight away:
+   - the switch sends its RibGet as before, and holds the packet on the core that received it,
+     so that its buffer never changes cores
+   - whenever that core has pulled a burst, and at most every RELEASE_INTERVAL, the packets it
+     holds are checked against the store. Those that can now be routed, or that the RIB answered
+     it has no route for, are put back in front of the GDP pipeline, behind the burst, and go
+     through it again: certificates, budget, stats and all. A RibReply handled on another core
+     reaches this one with the next publish
+   - packets that are still unroutable after the `ttl_ms` of pending.toml are NACKed, as they
+     used to be at once
+   - each switch holds packets on each core in a PendingQueue, within the limits in pending.toml;
+     the packets evicted to stay within them are NACKed at once
+   - a Reset of a flow drops the packets of the flow that are held, without a NACK. The Reset
+     comes from the same address and port as the flow's packets, so RSS hands it to the core
+     that holds them
+*/
+
+/// How often the held packets are checked against the store
+const RELEASE_INTERVAL: Duration = Duration::from_millis(1);
+
+type GdpPacket = Gdp<DTls<Ipv4>>;
+
+pub fn load_pending_limits() -> Result<PendingLimits> {
+    let content = fs::read_to_string("pending.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+struct HeldPackets {
+    packets: PendingQueue<GdpPacket>,
+    last_check: Instant,
+    /// Whether a held packet may go through the pipeline again
+    settled: fn(&GdpPacket, Store, FeatureFlags) -> bool,
+    nack: fn(GdpPacket) -> Result<GdpPacket>,
+    store: Store,
+    flags: FeatureFlags,
+    held: Counter,
+    released: Counter,
+    timed_out: Counter,
+    evicted: Counter,
+    discarded: Counter,
+}
+
+impl HeldPackets {
+    fn new(nic_name: &'static str, miss: &HoldMisses<impl Batch>) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter = |result: &'static str| {
+            sink.counter_with_labels("misses", vec![("nic", nic_name), ("result", result)])
+        };
+        HeldPackets {
+            packets: PendingQueue::new(load_pending_limits().unwrap_or_default()),
+            last_check: Instant::now(),
+            settled: miss.settled,
+            nack: miss.nack,
+            store: miss.store,
+            flags: miss.flags,
+            held: counter("held"),
+            released: counter("released"),
+            timed_out: counter("timed_out"),
+            evicted: counter("evicted"),
+            discarded: counter("discarded"),
+        }
+    }
+
+    fn release(&mut self, now: Instant, out: &mut VecDeque<Disposition<GdpPacket>>) {
+        self.last_check = now;
+        let (settled, store, flags) = (self.settled, self.store, self.flags);
+        let (released, expired) = self
+            .packets
+            .drain(now, |packet| settled(packet, store, flags));
+        self.released.record(released.len() as u64);
+        self.timed_out.record(expired.len() as u64);
+        out.extend(released.into_iter().map(Disposition::Act));
+        out.extend(
+            expired.into_iter().map(|packet| {
+                (self.nack)(packet).map_or_else(Disposition::Abort, Disposition::Act)
+            }),
+        );
+    }
+}
+
+thread_local! {
+    /// The packets held on this core, by the address of the switch holding them
+    static HELD: RefCell<HashMap<Ipv4Addr, HeldPackets>> = RefCell::new(HashMap::new());
+}
+
+/// Drop the packets of `flow` that the switch at `switch_addr` holds on this core, as the flow
+/// was reset; returns how many there were
+pub fn discard_held(switch_addr: Ipv4Addr, (src, dst): FlowKey) -> usize {
+    HELD.with(|held| match held.borrow_mut().get_mut(&switch_addr) {
+        Some(held) => {
+            let discarded = held
+                .packets
+                .remove_if(|packet| packet.src() == src && packet.dst() == dst);
+            held.discarded.record(discarded.len() as u64);
+            discarded.len()
+        }
+        None => 0,
+    })
+}
+
+/// Holds the Forward packets of a switch's miss branch; anything else passes through
+pub struct HoldMisses<B: Batch> {
+    batch: B,
+    nic_name: &'static str,
+    switch_addr: Ipv4Addr,
+    settled: fn(&GdpPacket, Store, FeatureFlags) -> bool,
+    nack: fn(GdpPacket) -> Result<GdpPacket>,
+    store: Store,
+    flags: FeatureFlags,
+    /// Packets evicted from the switch's queue, or refused by it, on their way to be NACKed
+    evicted: VecDeque<GdpPacket>,
+}
+
+impl<B: Batch<Item = GdpPacket>> HoldMisses<B> {
+    fn hold(&mut self, packet: GdpPacket) {
+        let evicted = HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            let held = held
+                .entry(self.switch_addr)
+                .or_insert_with(|| HeldPackets::new(self.nic_name, self));
+            held.held.increment();
+            let len = packet.mbuf().data_len();
+            let evicted = held.packets.push(packet, len, Instant::now());
+            held.evicted.record(evicted.len() as u64);
+            evicted
+        });
+        self.evicted.extend(evicted);
+    }
+}
+
+impl<B: Batch<Item = GdpPacket>> Batch for HoldMisses<B> {
+    type Item = GdpPacket;
+
+    #[inline]
+    fn replenish(&mut self) {
+        self.batch.replenish();
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        if let Some(packet) = self.evicted.pop_front() {
+            return Some(Disposition::Act(packet));
+        }
+        match self.batch.next() {
+            Some(Disposition::Act(packet)) if packet.action().map_or(false, GdpAction::is_data) => {
+                self.hold(packet);
+                // evicted packets go on to be NACKed
+                Some(
+                    self.evicted
+                        .pop_front()
+                        .map_or(Disposition::Emit, Disposition::Act),
+                )
+            }
+            disp => disp,
+        }
+    }
+}
+
+/// Puts the packets held by the switch at `switch_addr` back, once they are settled or timed out
+pub struct ReleaseHeld<B: Batch> {
+    batch: B,
+    switch_addr: Ipv4Addr,
+    ready: VecDeque<Disposition<GdpPacket>>,
+}
+
+impl<B: Batch<Item = GdpPacket>> Batch for ReleaseHeld<B> {
+    type Item = GdpPacket;
+
+    #[inline]
+    fn replenish(&mut self) {
+        self.batch.replenish();
+        let now = Instant::now();
+        HELD.with(|held| match held.borrow_mut().get_mut(&self.switch_addr) {
+            Some(held) if !held.packets.is_empty() && now - held.last_check >= RELEASE_INTERVAL => {
+                held.release(now, &mut self.ready)
+            }
+            _ => {}
+        });
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        self.batch.next().or_else(|| self.ready.pop_front())
+    }
+}
+
+pub trait MissBufferBatch: Batch<Item = GdpPacket> + Sized {
+    /// Hold packets for the switch at `switch_addr` until `settled` says they may go through the
+    /// pipeline again, or NACK them with `nack` once MISS_TIMEOUT has passed
+    fn hold_misses(
+        self,
+        nic_name: &'static str,
+        switch_addr: Ipv4Addr,
+        settled: fn(&GdpPacket, Store, FeatureFlags) -> bool,
+        nack: fn(GdpPacket) -> Result<GdpPacket>,
+        store: Store,
+        flags: FeatureFlags,
+    ) -> HoldMisses<Self> {
+        HoldMisses {
+            batch: self,
+            nic_name,
+            switch_addr,
+            settled,
+            nack,
+            store,
+            flags,
+            evicted: VecDeque::new(),
+        }
+    }
+
+    /// Follow each burst with the packets the switch at `switch_addr` held that are now ready
+    fn release_held(self, switch_addr: Ipv4Addr) -> ReleaseHeld<Self> {
+        ReleaseHeld {
+            batch: self,
+            switch_addr,
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Batch<Item = GdpPacket>> MissBufferBatch for T {}
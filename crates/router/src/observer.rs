@@ -0,0 +1,334 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll as TaskPoll};
+use std::time::Duration;
+
+use anyhow::Result;
+use capsule::batch::{Batch, Disposition, PacketRx, Pipeline, Poll};
+use capsule::config::RuntimeConfig;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::{Ethernet, Packet, Udp};
+use capsule::{metrics, Mbuf, PortQueue};
+use gdp_proto::GdpAction;
+use metrics_runtime::data::Counter;
+
+use crate::certificates::{CertContents, CertDest, RtCert, VerifyCert};
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::{CatchPanics, Recover};
+use crate::kvs::{SharedStore, Store, PUBLISH_INTERVAL};
+use crate::offload::{load_crypto_config, AdaptiveCryptoBatch};
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::rib::handle_rib_reply;
+use crate::runtime::build_runtime;
+use crate::statistics::{dump_history, make_print_stats};
+use crate::telemetry::TelemetryExport;
+use crate::Env;
+
+/*
+   An observer watches the GDP traffic on a mirrored port (e.g. from a passive tap) without
+   taking part in it:
+   - every packet is parsed and decrypted as a switch would, its content hash and certificate
+     chain are checked, and it is counted by action along with its in-band telemetry
+   - the metadata needed to check certificates is learned from the RIB replies that go past;
+     chains through names not seen yet are counted as unverified rather than invalid
+   - it cannot send anything, whatever its flags or config: the pipeline only gets the receiving
+     half of the queue (RxOnly) and ends in Observe, which drops every packet it is handed.
+     No schedules run beside it, so nothing is forwarded, answered, NACKed or asked of the RIB
+*/
+
+/// The receiving half of a port queue
+struct RxOnly(PortQueue);
+
+impl PacketRx for RxOnly {
+    fn receive(&mut self) -> Vec<Mbuf> {
+        self.0.receive()
+    }
+}
+
+struct ActionCounters {
+    packets: Counter,
+    bytes: Counter,
+}
+
+enum CertCheck {
+    Valid,
+    /// Some certificate is owned by a name whose metadata we have not seen
+    Unverified,
+    Invalid,
+}
+
+/// What an observer has seen. Counters are per action, with actions we don't know by number.
+struct ObserverStats {
+    nic_name: &'static str,
+    actions: RefCell<HashMap<u8, ActionCounters>>,
+    malformed: Counter,
+    bad_content: Counter,
+    bad_certs: Counter,
+    unverified_certs: Counter,
+}
+
+impl ObserverStats {
+    fn new(nic_name: &'static str) -> &'static Self {
+        let mut sink = metrics::global().sink();
+        let mut counter =
+            |name: &'static str| sink.counter_with_labels(name, vec![("nic", nic_name)]);
+        Box::leak(Box::new(ObserverStats {
+            nic_name,
+            actions: RefCell::new(HashMap::new()),
+            malformed: counter("observer.malformed"),
+            bad_content: counter("observer.bad_content"),
+            bad_certs: counter("observer.bad_certs"),
+            unverified_certs: counter("observer.unverified_certs"),
+        }))
+    }
+
+    fn count_action(&self, packet: &Gdp<DTls<Ipv4>>) {
+        let mut actions = self.actions.borrow_mut();
+        let counters = actions.entry(packet.raw_action()).or_insert_with(|| {
+            let action = match packet.action() {
+                Ok(action) => format!("{:?}", action),
+                Err(_) => packet.raw_action().to_string(),
+            };
+            let mut sink = metrics::global().sink();
+            let mut counter = |name: &'static str| {
+                sink.counter_with_labels(
+                    name,
+                    vec![
+                        ("nic", self.nic_name.to_string()),
+                        ("action", action.clone()),
+                    ],
+                )
+            };
+            ActionCounters {
+                packets: counter("observer.packets"),
+                bytes: counter("observer.bytes"),
+            }
+        });
+        counters.packets.increment();
+        counters.bytes.record(packet.len() as u64);
+    }
+
+    fn record(
+        &self,
+        packet: &Gdp<DTls<Ipv4>>,
+        store: Store,
+        telemetry: &TelemetryExport,
+        debug: bool,
+    ) -> Result<()> {
+        self.count_action(packet);
+        if packet.verify_content().is_err() {
+            self.bad_content.increment();
+            return Ok(());
+        }
+        match check_certs(packet, store)? {
+            CertCheck::Valid => {}
+            CertCheck::Unverified => self.unverified_certs.increment(),
+            CertCheck::Invalid => {
+                if debug {
+                    println!(
+                        "{} saw packet {:016x} with invalid certificates",
+                        self.nic_name,
+                        packet.trace_id()
+                    );
+                }
+                self.bad_certs.increment();
+            }
+        }
+        if packet.action().ok() == Some(GdpAction::RibReply) {
+            handle_rib_reply(packet, store, debug)?;
+        }
+        telemetry.observe(packet)
+    }
+}
+
+/// Check the chain of certificates that a packet carries, starting from its source
+fn check_certs(packet: &Gdp<DTls<Ipv4>>, store: Store) -> Result<CertCheck> {
+    let mut check = CertCheck::Valid;
+    let mut pos = packet.src();
+    for cert in &packet.get_certs()?.certificates {
+        if *cert.contents.owner() != pos {
+            return Ok(CertCheck::Invalid);
+        }
+        match store.gdp_metadata.get_unchecked(&pos) {
+            Some(metadata) if cert.verify(&metadata).is_err() => return Ok(CertCheck::Invalid),
+            Some(_) => {}
+            None => check = CertCheck::Unverified,
+        }
+        match &cert.contents {
+            CertContents::RtCert(RtCert {
+                proxy: CertDest::GdpName(proxy),
+                ..
+            }) => pos = *proxy,
+            _ => return Ok(CertCheck::Invalid),
+        }
+    }
+    Ok(check)
+}
+
+/// Ends an observer pipeline: every packet is dropped, whatever the stages before it decided
+struct Observe<B: Batch> {
+    name: &'static str,
+    batch: B,
+    malformed: Counter,
+}
+
+impl<B: Batch + Unpin> Pipeline for Observe<B> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run_once(&mut self) {
+        self.batch.replenish();
+        while let Some(disp) = self.batch.next() {
+            match disp {
+                Disposition::Act(packet) => drop(packet.reset()),
+                Disposition::Drop(mbuf) => drop(mbuf),
+                // not GDP, not decryptable, or from a node with another name type
+                Disposition::Abort(_) => self.malformed.increment(),
+                Disposition::Emit => {}
+            }
+        }
+    }
+}
+
+impl<B: Batch + Unpin> Future for Observe<B> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> TaskPoll<Self::Output> {
+        self.get_mut().run_once();
+        cx.waker().wake_by_ref();
+        TaskPoll::Pending
+    }
+}
+
+fn observer_pipeline(
+    q: PortQueue,
+    store: Store,
+    nic_name: &'static str,
+    debug: bool,
+) -> impl Pipeline {
+    let crypto_config = load_crypto_config().unwrap_or_default();
+    let stats = ObserverStats::new(nic_name);
+    let telemetry = TelemetryExport::new(nic_name, debug);
+    let batch = Poll::new(RxOnly(q))
+        .map(|packet| packet.parse::<Ethernet>()?.parse::<Ipv4>())
+        .map(|packet| packet.parse::<Udp<Ipv4>>()?.parse::<DTls<Ipv4>>())
+        .decrypt_adaptive(crypto_config)
+        .map(|packet| packet.parse::<Gdp<DTls<Ipv4>>>())
+        .for_each(move |packet| stats.record(packet, store, telemetry, debug))
+        .catch_panics(nic_name, "observer");
+    Observe {
+        name: nic_name,
+        batch,
+        malformed: stats.malformed.clone(),
+    }
+}
+
+pub fn start_observer(config: RuntimeConfig, env: Env, debug: bool) -> Result<()> {
+    preflight(
+        &config,
+        env,
+        &Requirements {
+            ports: &["eth1"],
+            cores: &[0],
+            node_addr: None,
+            rib: RibUse::None,
+            workload: false,
+        },
+    )?;
+    let store = SharedStore::new();
+    let (print_stats, history_map) = make_print_stats();
+
+    build_runtime(config, env)?
+        .add_pipeline_to_port("eth1", move |q| {
+            observer_pipeline(q, store.sync(), "observer", debug)
+        })?
+        .add_periodic_task_to_core(0, print_stats, Duration::from_secs(1))?
+        .add_periodic_task_to_core(0, move || store.publish(), PUBLISH_INTERVAL)?
+        .add_periodic_task_to_core(0, move || store.run_active_expire(), Duration::from_secs(1))?
+        .execute()?;
+    dump_history(&(*history_map.lock().recover()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use capsule::net::MacAddr;
+
+    use super::*;
+    use crate::certificates::{Certificate, NewRtCert};
+    use crate::gdp::CertificateBlock;
+    use crate::hardcoded_routes::{gdp_name_of_index, metadata_of_index, private_key_of_index};
+    use crate::rib::create_control_request;
+
+    /// A certificate for the node with GDP index `owner`, signed with the key of `signer`,
+    /// delegating to the node with index `to`
+    fn certificate(owner: u8, signer: u8, to: u8) -> Certificate {
+        RtCert::new_wrapped(
+            metadata_of_index(owner),
+            private_key_of_index(signer),
+            CertDest::GdpName(gdp_name_of_index(to)),
+            false,
+        )
+        .unwrap()
+    }
+
+    fn packet_from(src: u8, certificates: Vec<Certificate>) -> Gdp<DTls<Ipv4>> {
+        let mut packet = create_control_request(
+            Mbuf::new().unwrap(),
+            GdpAction::Forward,
+            &[0x42; 16],
+            MacAddr::broadcast(),
+            Ipv4Addr::new(10, 100, 1, 11),
+            gdp_name_of_index(src),
+            Ipv4Addr::new(10, 100, 1, 12),
+        )
+        .unwrap();
+        packet
+            .set_certs(&CertificateBlock { certificates })
+            .unwrap();
+        packet
+    }
+
+    fn store_knowing(indices: &[u8]) -> Store {
+        let store = SharedStore::new().sync();
+        for index in indices {
+            store
+                .gdp_metadata
+                .put(gdp_name_of_index(*index), metadata_of_index(*index));
+        }
+        store
+    }
+
+    #[capsule::test]
+    fn chains_of_known_names_are_valid() {
+        let packet = packet_from(1, vec![certificate(1, 1, 2), certificate(2, 2, 3)]);
+        let check = check_certs(&packet, store_knowing(&[1, 2])).unwrap();
+        assert!(matches!(check, CertCheck::Valid));
+    }
+
+    #[capsule::test]
+    fn chains_through_unseen_names_are_unverified() {
+        let packet = packet_from(1, vec![certificate(1, 1, 2), certificate(2, 2, 3)]);
+        let check = check_certs(&packet, store_knowing(&[1])).unwrap();
+        assert!(matches!(check, CertCheck::Unverified));
+    }
+
+    #[capsule::test]
+    fn broken_chains_are_invalid() {
+        // the second certificate does not start where the first one leads
+        let packet = packet_from(1, vec![certificate(1, 1, 2), certificate(3, 3, 4)]);
+        let check = check_certs(&packet, store_knowing(&[1, 2, 3])).unwrap();
+        assert!(matches!(check, CertCheck::Invalid));
+
+        // signed by someone else than its owner
+        let packet = packet_from(1, vec![certificate(1, 2, 2)]);
+        let check = check_certs(&packet, store_knowing(&[1, 2])).unwrap();
+        assert!(matches!(check, CertCheck::Invalid));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Instant;
+use std::{fs, thread};
+
+use anyhow::{anyhow, Result};
+use capsule::batch::{Batch, Disposition};
+use capsule::metrics;
+use capsule::packets::ip::IpPacket;
+use capsule::packets::Ethernet;
+use metrics_runtime::data::Counter;
+use serde::Deserialize;
+
+use crate::dtls::{
+    decrypt_gdp, encrypt_gdp, in_clear, read_payload, write_decrypted, write_encrypted, DTls,
+    PacketKey,
+};
+
+/// Weight of the newest observation in the moving averages
+const EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct CryptoConfig {
+    /// Number of worker threads available for offloaded crypto (0 disables offloading)
+    pub workers: usize,
+    /// Switch to offloading once the average number of packets per poll exceeds this
+    pub high_watermark: f64,
+    /// Switch back to inline crypto once it drops below this
+    pub low_watermark: f64,
+    /// Offload if doing a batch's crypto inline is expected to take longer than this
+    pub budget_ns: u64,
+}
+
+impl Default for CryptoConfig {
+    fn default() -> Self {
+        CryptoConfig {
+            workers: 2,
+            high_watermark: 24.0,
+            low_watermark: 8.0,
+            budget_ns: 20_000,
+        }
+    }
+}
+
+pub fn load_crypto_config() -> Result<CryptoConfig> {
+    let content = fs::read_to_string("crypto.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+struct Job {
+    index: usize,
+    direction: Direction,
+    key: PacketKey,
+    data: Vec<u8>,
+    reply: Sender<(usize, Result<Vec<u8>>)>,
+}
+
+/// Threads that run AES on copies of packet payloads, off the polling core
+struct CryptoWorkers {
+    queues: Vec<Sender<Job>>,
+}
+
+impl CryptoWorkers {
+    fn new(workers: usize) -> Self {
+        let queues = (0..workers)
+            .map(|_| {
+                let (tx, rx): (Sender<Job>, Receiver<Job>) = mpsc::channel();
+                thread::spawn(move || {
+                    for job in rx {
+                        // a packet that panics the crypto fails alone, rather than taking the
+                        // worker and every packet queued behind it
+                        let result =
+                            panic::catch_unwind(AssertUnwindSafe(|| match job.direction {
+                                Direction::Encrypt => job.key.encrypt(&job.data),
+                                Direction::Decrypt => job.key.decrypt(&job.data),
+                            }))
+                            .unwrap_or_else(|_| Err(anyhow!("crypto panicked")));
+                        // the pipeline may have given up on this batch
+                        let _ = job.reply.send((job.index, result));
+                    }
+                });
+                tx
+            })
+            .collect();
+        CryptoWorkers { queues }
+    }
+}
+
+/// Decides per batch whether to run crypto inline or on the workers.
+/// Uses separate watermarks for entering and leaving offload mode so it doesn't flap.
+struct AdaptivePolicy {
+    config: CryptoConfig,
+    offloading: bool,
+    depth: f64,
+    ns_per_packet: f64,
+}
+
+impl AdaptivePolicy {
+    fn should_offload(&mut self, batch_len: usize) -> bool {
+        self.depth = EWMA_ALPHA * batch_len as f64 + (1.0 - EWMA_ALPHA) * self.depth;
+        let projected_ns = self.ns_per_packet * batch_len as f64;
+        let budget_ns = self.config.budget_ns as f64;
+        self.offloading = if self.offloading {
+            self.depth > self.config.low_watermark || projected_ns > budget_ns / 2.0
+        } else {
+            self.depth > self.config.high_watermark || projected_ns > budget_ns
+        };
+        self.offloading && self.config.workers > 0 && batch_len > 1
+    }
+
+    fn record_inline(&mut self, elapsed_ns: u64, packets: usize) {
+        if packets > 0 {
+            let sample = elapsed_ns as f64 / packets as f64;
+            self.ns_per_packet = EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * self.ns_per_packet;
+        }
+    }
+}
+
+/// Encrypts or decrypts every packet of a batch, inline or on worker threads depending on load
+pub struct AdaptiveCrypto<T: IpPacket<Envelope = Ethernet>, B: Batch<Item = DTls<T>>> {
+    batch: B,
+    direction: Direction,
+    workers: CryptoWorkers,
+    policy: AdaptivePolicy,
+    ready: VecDeque<Disposition<DTls<T>>>,
+    /// Packets that could not be encrypted or decrypted, e.g. for a bad tag or an unknown session
+    failures: Counter,
+}
+
+impl<T: IpPacket<Envelope = Ethernet>, B: Batch<Item = DTls<T>>> AdaptiveCrypto<T, B> {
+    fn new(batch: B, direction: Direction, config: CryptoConfig) -> Self {
+        let direction_name = match direction {
+            Direction::Decrypt => "decrypt",
+            Direction::Encrypt => "encrypt",
+        };
+        let failures = metrics::global()
+            .sink()
+            .counter_with_labels("crypto.failures", vec![("direction", direction_name)]);
+        AdaptiveCrypto {
+            batch,
+            direction,
+            workers: CryptoWorkers::new(config.workers),
+            policy: AdaptivePolicy {
+                config,
+                offloading: false,
+                depth: 0.0,
+                ns_per_packet: 0.0,
+            },
+            ready: VecDeque::new(),
+            failures,
+        }
+    }
+
+    fn failed(&self, err: anyhow::Error) -> Disposition<DTls<T>> {
+        self.failures.increment();
+        Disposition::Abort(err)
+    }
+
+    fn run_inline(&self, packet: DTls<T>) -> Result<DTls<T>> {
+        match self.direction {
+            Direction::Decrypt => decrypt_gdp(packet),
+            Direction::Encrypt => encrypt_gdp(packet),
+        }
+    }
+
+    /// Packets to and from plaintext peers have no crypto to offload
+    fn in_clear(&self, packet: &DTls<T>) -> bool {
+        in_clear(packet, matches!(self.direction, Direction::Encrypt))
+    }
+
+    fn dispatch(
+        &self,
+        index: usize,
+        packet: &mut DTls<T>,
+        reply: &Sender<(usize, Result<Vec<u8>>)>,
+    ) -> Result<()> {
+        let key = match self.direction {
+            Direction::Decrypt => PacketKey::incoming(packet)?,
+            Direction::Encrypt => PacketKey::outgoing(packet)?,
+        };
+        let job = Job {
+            index,
+            direction: self.direction,
+            key,
+            data: read_payload(packet)?.to_vec(),
+            reply: reply.clone(),
+        };
+        self.workers.queues[index % self.workers.queues.len()]
+            .send(job)
+            .map_err(|_| anyhow!("crypto worker exited"))
+    }
+
+    fn run_offloaded(&mut self, packets: Vec<Disposition<DTls<T>>>) {
+        let (reply, results) = mpsc::channel();
+        let mut pending = packets
+            .into_iter()
+            .enumerate()
+            .map(|(index, disp)| match disp {
+                Disposition::Act(packet) if self.in_clear(&packet) => {
+                    let disp = self
+                        .run_inline(packet)
+                        .map_or_else(|err| self.failed(err), Disposition::Act);
+                    (None, Some(disp))
+                }
+                Disposition::Act(mut packet) => match self.dispatch(index, &mut packet, &reply) {
+                    Ok(()) => (Some(packet), None),
+                    Err(err) => (None, Some(self.failed(err))),
+                },
+                disp => (None, Some(disp)),
+            })
+            .collect::<Vec<_>>();
+        drop(reply);
+
+        let mut outputs = vec![Vec::new(); pending.len()];
+        for (index, result) in results {
+            outputs[index] = match result {
+                Ok(output) => output,
+                Err(err) => {
+                    pending[index] = (None, Some(self.failed(err)));
+                    continue;
+                }
+            };
+        }
+
+        for ((packet, done), output) in pending.into_iter().zip(outputs) {
+            let disp = match (packet, done) {
+                (_, Some(disp)) => disp,
+                (Some(packet), None) => {
+                    let written = match self.direction {
+                        Direction::Decrypt => write_decrypted(packet, &output),
+                        Direction::Encrypt => write_encrypted(packet, &output),
+                    };
+                    written.map_or_else(|err| self.failed(err), Disposition::Act)
+                }
+                (None, None) => unreachable!(),
+            };
+            self.ready.push_back(disp);
+        }
+    }
+}
+
+impl<T: IpPacket<Envelope = Ethernet>, B: Batch<Item = DTls<T>>> Batch for AdaptiveCrypto<T, B> {
+    type Item = DTls<T>;
+
+    fn replenish(&mut self) {
+        self.batch.replenish();
+        let mut packets = Vec::new();
+        while let Some(disp) = self.batch.next() {
+            packets.push(disp);
+        }
+
+        if self.policy.should_offload(packets.len()) {
+            self.run_offloaded(packets);
+        } else {
+            let start = Instant::now();
+            let count = packets.len();
+            for disp in packets {
+                let disp = match disp {
+                    Disposition::Act(packet) => self
+                        .run_inline(packet)
+                        .map_or_else(|err| self.failed(err), Disposition::Act),
+                    disp => disp,
+                };
+                self.ready.push_back(disp);
+            }
+            self.policy
+                .record_inline(start.elapsed().as_nanos() as u64, count);
+        }
+    }
+
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        self.ready.pop_front()
+    }
+}
+
+pub trait AdaptiveCryptoBatch<T: IpPacket<Envelope = Ethernet>>:
+    Batch<Item = DTls<T>> + Sized
+{
+    fn decrypt_adaptive(self, config: CryptoConfig) -> AdaptiveCrypto<T, Self> {
+        AdaptiveCrypto::new(self, Direction::Decrypt, config)
+    }
+
+    fn encrypt_adaptive(self, config: CryptoConfig) -> AdaptiveCrypto<T, Self> {
+        AdaptiveCrypto::new(self, Direction::Encrypt, config)
+    }
+}
+
+impl<T: IpPacket<Envelope = Ethernet>, B: Batch<Item = DTls<T>>> AdaptiveCryptoBatch<T> for B {}
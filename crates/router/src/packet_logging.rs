@@ -1,4 +1,5 @@
 use capsule::batch::{Batch, Disposition};
+use capsule::metrics;
 use capsule::packets::ip::v4::Ipv4;
 use capsule::packets::{Packet, Udp};
 
@@ -10,6 +11,8 @@ pub trait LogArrive: Batch + Sized {
     fn logarrive(self, name: &'static str, details: &'static str, debug: bool) -> Self::OutBatch;
 }
 
+/// Counts the packets that a pipeline gave up on with an error, whichever stage failed them,
+/// in `pipeline.aborted`, and logs why when debugging
 pub trait LogFail: Batch + Sized {
     type OutBatch: Batch;
     fn logfail(self, name: &'static str, details: &'static str, debug: bool) -> Self::OutBatch;
@@ -41,12 +44,15 @@ where
         self.for_each(move |packet| {
             if debug {
                 println!(
-                    "handling packet in {} ({}) : src: {:?}, dst: {:?}, type: {:?}",
+                    "handling packet {:016x} in {} ({}) : src: {:?}, dst: {:?}, type: {:?}, port: {:?}, rss: {:?}",
+                    packet.trace_id(),
                     name,
                     details,
                     packet.envelope().udp().envelope().src(),
                     packet.envelope().udp().envelope().dst(),
-                    packet.action()?
+                    packet.action()?,
+                    packet.ingress_port(),
+                    packet.rss_hash(),
                 );
             }
             Ok(())
@@ -58,11 +64,15 @@ impl<T: Batch + Sized> LogFail for T {
     type OutBatch = impl Batch;
 
     fn logfail(self, name: &'static str, details: &'static str, debug: bool) -> Self::OutBatch {
+        let aborted = metrics::global()
+            .sink()
+            .counter_with_labels("pipeline.aborted", vec![("nic", name), ("path", details)]);
         self.inspect(move |disp| {
-            if debug {
-                if let Disposition::Abort(err) = disp {
+            if let Disposition::Abort(err) = disp {
+                aborted.increment();
+                if debug {
                     println!(
-                        "Packet aborted by {} ({}) with error {}",
+                        "Packet aborted by {} ({}) with error {:#}",
                         name, details, err
                     );
                 }
@@ -0,0 +1,299 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::config::RuntimeConfig;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpName};
+use serde::{Deserialize, Serialize};
+use tokio_timer::delay_for;
+
+use crate::certificates::{CertDest, Certificate, NewRtCert, RtCert};
+use crate::dtls::{encrypt_gdp, DTls};
+use crate::flags::FeatureFlags;
+use crate::gdp::Gdp;
+use crate::gdp_pipeline::install_gdp_pipeline;
+use crate::hardcoded_routes::{gdp_name_of_index, metadata_of_index, private_key_of_index};
+use crate::identity::PortIdentity;
+use crate::l2filter::VlanTx;
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::rib::{create_control_request, create_reply};
+use crate::runtime::build_runtime;
+use crate::schedule::Schedule;
+use crate::statistics::RttStats;
+use crate::workloads::register_client;
+use crate::{pipeline, Env};
+
+/*
+   Pings measure round trips through the network, to debug it:
+   - a Ping is addressed to a switch by name, and carries a sequence number and the time it was
+     sent, padded to the size in ping.toml. The switch it is addressed to answers with a Pong
+     that carries the payload back; other switches forward both to the name like a RIB reply
+   - the switch only answers a Ping whose certificates delegate its source to the switch, as
+     for data packets, so that pings under a forged source do not turn it into a reflector. The
+     ping client signs one from its name to the target, and signs it again before it expires
+   - `--mode ping` sends `rate` pings a second to the switch named by `--name`, through the
+     local switch, as the test client. Each Pong brings back the time its Ping was sent, so
+     nothing is kept per ping
+   - every second it prints the percentiles of the round trips since the last printout, and how
+     many pings are unanswered so far; once stopped, the percentiles of the whole run
+*/
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Our certificate to the target is signed again once it has less than this left
+const CERT_RENEWAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct PingConfig {
+    /// Pings sent a second
+    #[serde(default = "default_rate")]
+    pub rate: u32,
+    /// Bytes of payload in each Ping; the sequence number and timestamp take the first 16
+    #[serde(default = "default_size")]
+    pub size: usize,
+}
+
+fn default_rate() -> u32 {
+    10
+}
+
+fn default_size() -> usize {
+    64
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig {
+            rate: default_rate(),
+            size: default_size(),
+        }
+    }
+}
+
+pub fn load_ping_config() -> Result<PingConfig> {
+    let content = fs::read_to_string("ping.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PingPayload {
+    seq: u64,
+    sent_us: u64,
+}
+
+fn now_us() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
+
+/// Answer a Ping addressed to us with its payload
+pub fn answer_ping(packet: &Gdp<DTls<Ipv4>>) -> Result<Gdp<DTls<Ipv4>>> {
+    create_reply(packet, GdpAction::Pong, packet.data()?)
+}
+
+struct PingState {
+    sent: AtomicU64,
+    answered: AtomicU64,
+    /// Since the last report
+    recent: RttStats,
+    all: RttStats,
+}
+
+/// The pings of a ping client and their round trips
+#[derive(Clone, Copy)]
+pub struct Pinger(&'static PingState);
+
+impl Pinger {
+    pub fn new() -> Result<Self> {
+        Ok(Pinger(Box::leak(Box::new(PingState {
+            sent: AtomicU64::new(0),
+            answered: AtomicU64::new(0),
+            recent: RttStats::new()?,
+            all: RttStats::new()?,
+        }))))
+    }
+
+    fn next_payload(&self, size: usize) -> Result<Vec<u8>> {
+        let mut payload = bincode::serialize(&PingPayload {
+            seq: self.0.sent.fetch_add(1, Ordering::Relaxed),
+            sent_us: now_us()?,
+        })?;
+        // trailing bytes are ignored when the payload is deserialized
+        payload.resize(payload.len().max(size), 0);
+        Ok(payload)
+    }
+
+    pub fn handle_pong(&self, packet: &Gdp<DTls<Ipv4>>) -> Result<()> {
+        let payload: PingPayload = bincode::deserialize(packet.data()?)?;
+        let now = now_us()?;
+        ensure!(
+            now >= payload.sent_us,
+            "pong {} from before its ping",
+            payload.seq
+        );
+        self.0.recent.record(now - payload.sent_us);
+        self.0.all.record(now - payload.sent_us);
+        self.0.answered.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn report(&self) {
+        let sent = self.0.sent.load(Ordering::Relaxed);
+        let unanswered = sent.saturating_sub(self.0.answered.load(Ordering::Relaxed));
+        match self.0.recent.take_summary() {
+            Some(summary) => println!("{} ({} of {} unanswered)", summary, unanswered, sent),
+            None => println!("no pongs ({} of {} unanswered)", unanswered, sent),
+        }
+    }
+}
+
+/// Our certificate to `target`, signed again if there is none yet or it is about to expire
+fn renew_certificate(cert: &mut Option<Certificate>, target: GdpName) -> Result<Certificate> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    match cert.as_ref() {
+        Some(current) if current.contents.expiration_time() > now + CERT_RENEWAL.as_secs() => {
+            Ok(current.clone())
+        }
+        _ => {
+            let fresh = RtCert::new_wrapped(
+                metadata_of_index(1),
+                private_key_of_index(1),
+                CertDest::GdpName(target),
+                false,
+            )?;
+            *cert = Some(fresh.clone());
+            Ok(fresh)
+        }
+    }
+}
+
+fn send_ping(
+    q: &PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    target: GdpName,
+    switch_ip: Ipv4Addr,
+    payload: Vec<u8>,
+    cert: Certificate,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            let mut packet = create_control_request(
+                packet,
+                GdpAction::Ping,
+                &payload,
+                src.mac,
+                src.ip,
+                src_gdp_name,
+                switch_ip,
+            )?;
+            packet.set_dst(target);
+            packet.append_cert(&cert)?;
+            packet.reconcile_all();
+            Ok(packet)
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q.clone()))
+        .run_once();
+}
+
+fn ping_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    target: GdpName,
+    switch_ip: Ipv4Addr,
+    config: PingConfig,
+    pinger: Pinger,
+) -> impl Pipeline {
+    let interval = Duration::from_secs(1) / config.rate.max(1);
+    let mut cert = None;
+    Schedule::new("ping", async move {
+        loop {
+            let ping = pinger
+                .next_payload(config.size)
+                .and_then(|payload| Ok((payload, renew_certificate(&mut cert, target)?)));
+            match ping {
+                Ok((payload, cert)) => send_ping(
+                    &q,
+                    src,
+                    gdp_name_of_index(1),
+                    target,
+                    switch_ip,
+                    payload,
+                    cert,
+                ),
+                Err(err) => println!("failed to make ping: {:#}", err),
+            }
+            delay_for(interval).await;
+        }
+    })
+}
+
+/// Ping the switch with GDP index `target` through the switch at `switch_addr`, until stopped
+pub fn start_ping_client(
+    config: RuntimeConfig,
+    target: u8,
+    node_addr: Ipv4Addr,
+    switch_addr: Ipv4Addr,
+    flags: FeatureFlags,
+    env: Env,
+    debug: bool,
+) -> Result<()> {
+    preflight(
+        &config,
+        env,
+        &Requirements {
+            ports: &["eth1"],
+            cores: &[0],
+            node_addr: Some(node_addr),
+            rib: RibUse::None,
+            workload: false,
+        },
+    )?;
+    let ping_config = load_ping_config().unwrap_or_default();
+    let pinger = Pinger::new()?;
+    let target = gdp_name_of_index(target);
+    println!(
+        "pinging {:02x?} {} times a second with {} bytes",
+        &target[..4],
+        ping_config.rate,
+        ping_config.size
+    );
+
+    build_runtime(config, env)?
+        .add_pipeline_to_port("eth1", move |q| {
+            install_gdp_pipeline(
+                q,
+                pipeline! {
+                    GdpAction::Pong => |group| {
+                        group
+                            .for_each(move |packet| pinger.handle_pong(packet))
+                            .filter(|_| false)
+                    }
+                },
+                "ping",
+                node_addr,
+                flags,
+                debug,
+            )
+        })?
+        .add_pipeline_to_core(0, move |q| {
+            let q = q["eth1"].clone();
+            register_client(&q, node_addr, switch_addr);
+            let src = PortIdentity::of_port(&q, node_addr);
+            ping_schedule(q, src, target, switch_addr, ping_config, pinger)
+        })?
+        .add_periodic_task_to_core(0, move || pinger.report(), REPORT_INTERVAL)?
+        .execute()?;
+
+    match pinger.0.all.summary() {
+        Some(summary) => println!("overall: {}", summary),
+        None => println!("overall: no pongs"),
+    }
+    Ok(())
+}
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use capsule::batch::GroupByBatchBuilder;
 use capsule::packets::ip::v4::Ipv4;
-use gdp_client::GdpAction;
+use gdp_proto::GdpAction;
 
 use crate::dtls::DTls;
 use crate::gdp::Gdp;
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use capsule::batch::Pipeline;
+use capsule::{metrics, PortQueue};
+use gdp_proto::GdpName;
+use lru::LruCache;
+use metrics_runtime::data::Counter;
+use tokio_timer::delay_for;
+
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::kvs::{FwdTableEntry, Store};
+use crate::rib::{send_rib_request, Routes};
+use crate::ribpayload::RibQuery;
+use crate::schedule::Schedule;
+
+/// Prefetched bindings that go unused for this long are no longer counted as hits
+const PREFETCH_TTL: u64 = 30;
+/// Predictions beyond this are dropped rather than queued, so a runaway predictor can't build a backlog
+const MAX_PENDING: usize = 256;
+/// How far ahead SequentialPredictor reads by default
+pub const DEFAULT_PREFETCH_DEPTH: usize = 4;
+/// Queries sent per run of the prefetch schedule
+const QUERIES_PER_RUN: usize = 16;
+
+/// Guesses which names will be looked up next, given the lookups the route cache could not serve
+pub trait PrefetchPredictor: Send {
+    fn observe(&mut self, src: GdpName, dst: GdpName) -> Vec<GdpName>;
+}
+
+/// The name that follows `name` when read as a big-endian integer (wrapping around)
+pub fn successor(name: GdpName) -> GdpName {
+    let mut next = name;
+    for byte in next.iter_mut().rev() {
+        let (sum, carry) = byte.overflowing_add(1);
+        *byte = sum;
+        if !carry {
+            break;
+        }
+    }
+    next
+}
+
+/// Detects sources walking through consecutive names (e.g. chunks of an object),
+/// and predicts the next `depth` names of the walk
+pub struct SequentialPredictor {
+    depth: usize,
+    last_dst: LruCache<GdpName, GdpName>,
+}
+
+impl SequentialPredictor {
+    pub fn new(depth: usize) -> Self {
+        SequentialPredictor {
+            depth,
+            last_dst: LruCache::new(1024),
+        }
+    }
+}
+
+impl PrefetchPredictor for SequentialPredictor {
+    fn observe(&mut self, src: GdpName, dst: GdpName) -> Vec<GdpName> {
+        let sequential = self
+            .last_dst
+            .put(src, dst)
+            .map_or(false, |last| successor(last) == dst);
+        if !sequential {
+            return Vec::new();
+        }
+        let mut predictions = Vec::with_capacity(self.depth);
+        let mut name = dst;
+        for _ in 0..self.depth {
+            name = successor(name);
+            predictions.push(name);
+        }
+        predictions
+    }
+}
+
+struct PrefetchState {
+    predictor: Mutex<Box<dyn PrefetchPredictor>>,
+    pending: Mutex<VecDeque<GdpName>>,
+    issued: Counter,
+    hits: Counter,
+    misses: Counter,
+}
+
+/// Turns route cache misses into background RIB queries for the names a predictor expects next.
+/// Shared by all cores of a switch; `hits / (hits + misses)` is the fraction of lookups
+/// that prefetching saved from a round trip to the RIB.
+#[derive(Clone, Copy)]
+pub struct Prefetcher(&'static PrefetchState);
+
+impl Prefetcher {
+    pub fn new(predictor: impl PrefetchPredictor + 'static, nic_name: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter =
+            |name: &'static str| sink.counter_with_labels(name, vec![("nic", nic_name)]);
+        Prefetcher(Box::leak(Box::new(PrefetchState {
+            predictor: Mutex::new(Box::new(predictor)),
+            pending: Mutex::new(VecDeque::new()),
+            issued: counter("prefetch.issued"),
+            hits: counter("prefetch.hits"),
+            misses: counter("prefetch.misses"),
+        })))
+    }
+
+    fn observe(&self, src: GdpName, dst: GdpName) {
+        let predictions = self.0.predictor.lock().recover().observe(src, dst);
+        if predictions.is_empty() {
+            return;
+        }
+        let mut pending = self.0.pending.lock().recover();
+        for name in predictions {
+            if pending.len() >= MAX_PENDING {
+                break;
+            }
+            if !pending.contains(&name) {
+                pending.push_back(name);
+            }
+        }
+    }
+
+    /// A lookup the route cache could not serve
+    pub fn record_miss(&self, src: GdpName, dst: GdpName) {
+        self.0.misses.increment();
+        self.observe(src, dst);
+    }
+
+    /// A lookup the route cache served; counted if the binding is there because we prefetched it
+    pub fn record_hit(&self, src: GdpName, dst: GdpName, store: Store) {
+        if store.prefetched.get(&dst).is_some() {
+            store.prefetched.remove(&dst);
+            self.0.hits.increment();
+            // the walk continues past the names we prefetched
+            self.observe(src, dst);
+        }
+    }
+
+    fn take_pending(&self, max: usize) -> Vec<GdpName> {
+        let mut pending = self.0.pending.lock().recover();
+        let count = pending.len().min(max);
+        pending.drain(..count).collect()
+    }
+}
+
+fn run_prefetch(
+    q: &PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    rib_ip: Ipv4Addr,
+    store: Store,
+    prefetcher: Prefetcher,
+) -> Result<()> {
+    let expiration_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + PREFETCH_TTL;
+    for name in prefetcher.take_pending(QUERIES_PER_RUN) {
+        let known = store.forwarding_table.get(&name).is_some()
+            || store.negative_routes.get(&name).is_some();
+        if known {
+            continue;
+        }
+        store
+            .prefetched
+            .update(name, FwdTableEntry::new((), expiration_time));
+        send_rib_request(
+            q.clone(),
+            src,
+            src_gdp_name,
+            rib_ip,
+            &RibQuery::next_hop_for(name),
+        );
+        prefetcher.0.issued.increment();
+    }
+    Ok(())
+}
+
+/// Send the RIB queries queued up by the prefetcher, in the background of the GDP pipeline
+pub fn prefetch_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    routes: &'static Routes,
+    store: Store,
+    prefetcher: Prefetcher,
+) -> impl Pipeline {
+    Schedule::new("rib_prefetch", async move {
+        loop {
+            let rib_ip = routes.rib().ip;
+            if let Err(err) = run_prefetch(&q, src, src_gdp_name, rib_ip, store, prefetcher) {
+                println!("prefetch failed: {:#}", err);
+            }
+            delay_for(Duration::from_millis(1)).await;
+        }
+    })
+}
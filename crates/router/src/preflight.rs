@@ -0,0 +1,367 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use capsule::config::RuntimeConfig;
+#[cfg(feature = "switch")]
+use gdp_proto::GdpHeader;
+
+use crate::branches::load_branch_config;
+#[cfg(feature = "switch")]
+use crate::budget::load_budget_config;
+use crate::capabilities::DEFAULT_MAX_MTU;
+use crate::certificates::load_cert_config;
+#[cfg(feature = "switch")]
+use crate::dtls::dtls_overhead;
+use crate::dtls::padding::load_padding_config;
+use crate::dtls::session::load_session_config;
+use crate::dtls::transport::load_transport_config;
+use crate::dtls::using_default_key;
+use crate::fragment::load_fragment_config;
+use crate::hardcoded_routes::load_routes;
+use crate::identity::load_port_identities;
+#[cfg(feature = "switch")]
+use crate::invalidation::load_invalidation_config;
+use crate::l2filter::load_l2_config;
+#[cfg(feature = "switch")]
+use crate::labels::load_label_policy;
+use crate::missbuffer::load_pending_limits;
+use crate::offload::load_crypto_config;
+#[cfg(feature = "switch")]
+use crate::ping::load_ping_config;
+use crate::priority::load_priority_config;
+#[cfg(feature = "switch")]
+use crate::recorder::load_record_config;
+use crate::registration::load_registration_config;
+use crate::ribdb::load_rib_storage_config;
+use crate::ribpayload::load_route_ttl_config;
+use crate::ribreplies::load_rib_reply_config;
+use crate::ribsync::load_rib_sync_config;
+use crate::secrets::load_secrets;
+use crate::statistics::load_sampling_config;
+use crate::txbatch::load_tx_config;
+use crate::unknown_action::load_unknown_action_policy;
+#[cfg(feature = "switch")]
+use crate::workloads::load_test_config;
+use crate::Env;
+
+/*
+    Before a node builds its runtime, everything it is about to depend on is checked together:
+    - every config file that is present must parse. Files that are missing fall back to their
+      defaults, as they always have, but a typo no longer silently does the same
+    - secrets.toml must resolve, and outside of local runs a dTLS key must be set, since the
+      built-in example key is public, and encryption must not be turned off in transports.toml
+    - the ports and cores that the node installs pipelines on must be in the runtime config,
+      and PCI devices must exist and be bound to a driver that DPDK can use
+    - packets must fit the MTU once the IP, UDP, dTLS and GDP headers are added, and so must
+      the largest size that payloads are padded to
+    - ports that join multicast groups in l2.toml must have multicast on in the runtime config
+    - the routes file must name a RIB that is not the node itself. Whether the RIB answers can
+      only be seen once the ports are up, so that is left to the first RIB query
+    Every problem is collected before any is reported, so that one run shows all that is wrong.
+    Warnings are printed but do not stop the node.
+*/
+
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const UDP_HEADER_LEN: usize = 8;
+/// Drivers that give DPDK the device; Mellanox NICs are driven through their kernel driver
+const DPDK_DRIVERS: &[&str] = &["vfio-pci", "igb_uio", "uio_pci_generic", "mlx5_core"];
+
+/// What a node is about to bind, and what it depends on
+pub struct Requirements {
+    /// Ports that pipelines are installed on by name
+    pub ports: &'static [&'static str],
+    /// Cores that pipelines are installed on by number
+    pub cores: &'static [usize],
+    /// The address the node was started with
+    pub node_addr: Option<Ipv4Addr>,
+    pub rib: RibUse,
+    /// Whether the node generates the test workload in test.toml
+    pub workload: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RibUse {
+    /// The node does not read the routes file
+    None,
+    /// The node sends queries to the RIB in the routes file
+    Queries,
+    /// The node is the RIB
+    Serves,
+}
+
+#[derive(Default)]
+struct Report {
+    problems: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    fn problem(&mut self, problem: String) {
+        // files that are loaded once per port would report the same problem for each
+        if !self.problems.contains(&problem) {
+            self.problems.push(problem);
+        }
+    }
+
+    fn warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    fn check<T>(&mut self, what: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.problem(format!("{}: {:#}", what, err));
+                None
+            }
+        }
+    }
+
+    /// Load a config file that falls back to defaults when it is missing
+    fn optional_file<T>(&mut self, path: &str, load: impl FnOnce() -> Result<T>) -> Option<T> {
+        if !Path::new(path).exists() {
+            return None;
+        }
+        self.check(path, load())
+    }
+}
+
+/// Check that the node can run as configured, reporting every problem found at once
+pub fn preflight(config: &RuntimeConfig, env: Env, requirements: &Requirements) -> Result<()> {
+    let mut report = Report::default();
+    check_config_files(&mut report, env, requirements);
+    check_secrets(&mut report, env);
+    check_ports(&mut report, config, requirements);
+    check_l2(&mut report, config, requirements);
+    check_routes(&mut report, env, requirements);
+
+    for warning in &report.warnings {
+        println!("preflight: {}", warning);
+    }
+    if report.problems.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<String> = report
+        .problems
+        .iter()
+        .map(|problem| format!("  - {}", problem))
+        .collect();
+    Err(anyhow!(
+        "{} problem(s) found before starting:\n{}",
+        report.problems.len(),
+        list.join("\n")
+    ))
+}
+
+fn check_config_files(report: &mut Report, env: Env, requirements: &Requirements) {
+    for port in requirements.ports {
+        report.optional_file("tx.toml", || load_tx_config(port));
+    }
+    report.optional_file("crypto.toml", load_crypto_config);
+    report.optional_file("priority.toml", load_priority_config);
+    #[cfg(feature = "switch")]
+    report.optional_file("budget.toml", load_budget_config);
+    report.optional_file("actions.toml", load_unknown_action_policy);
+    report.optional_file("branches.toml", load_branch_config);
+    report.optional_file("sessions.toml", load_session_config);
+    check_transports(report, env);
+    report.optional_file("ports.toml", load_port_identities);
+    report.optional_file("pending.toml", load_pending_limits);
+    report.optional_file("fragment.toml", load_fragment_config);
+    report.optional_file("sampling.toml", load_sampling_config);
+    report.optional_file("route_ttl.toml", load_route_ttl_config);
+    report.optional_file("certs.toml", load_cert_config);
+    report.optional_file("registration.toml", load_registration_config);
+    report.optional_file("rib_storage.toml", load_rib_storage_config);
+    report.optional_file("rib_replies.toml", load_rib_reply_config);
+    report.optional_file("rib_sync.toml", load_rib_sync_config);
+    check_padding(report);
+    #[cfg(feature = "switch")]
+    report.optional_file("record.toml", load_record_config);
+    #[cfg(feature = "switch")]
+    report.optional_file("ping.toml", load_ping_config);
+    #[cfg(feature = "switch")]
+    report.optional_file("labels.toml", load_label_policy);
+    #[cfg(feature = "switch")]
+    report.optional_file("invalidation.toml", load_invalidation_config);
+    #[cfg(feature = "switch")]
+    if requirements.workload {
+        check_workload(report);
+    }
+}
+
+/// The generated packets must fit the MTU once the headers are added
+#[cfg(feature = "switch")]
+fn check_workload(report: &mut Report) {
+    let test = match report.optional_file("test.toml", load_test_config) {
+        Some(test) => test,
+        None => return,
+    };
+    let overhead = IPV4_HEADER_LEN + UDP_HEADER_LEN + dtls_overhead() + GdpHeader::LEN as usize;
+    let mtu = DEFAULT_MAX_MTU as usize;
+    if test.payload_size + overhead > mtu {
+        report.problem(format!(
+            "test.toml: payload_size {} does not fit the MTU of {} with {} bytes of headers",
+            test.payload_size, mtu, overhead
+        ));
+    }
+}
+
+/// Padded packets must fit the MTU once the IP and UDP headers are added
+fn check_padding(report: &mut Report) {
+    let padding = match report.optional_file("padding.toml", load_padding_config) {
+        Some(padding) => padding,
+        None => return,
+    };
+    let largest = IPV4_HEADER_LEN + UDP_HEADER_LEN + padding.largest_record();
+    let mtu = DEFAULT_MAX_MTU as usize;
+    if largest > mtu {
+        report.problem(format!(
+            "padding.toml: the largest bucket makes {}-byte packets, larger than the MTU of {}",
+            largest, mtu
+        ));
+    }
+}
+
+/// Turning encryption off is for benchmarks, which run locally
+fn check_transports(report: &mut Report, env: Env) {
+    let transports = match report.optional_file("transports.toml", load_transport_config) {
+        Some(transports) => transports,
+        None => return,
+    };
+    if transports.encryption {
+        return;
+    }
+    let message = "encryption is off in transports.toml: every peer is sent to and accepted from \
+                   in the clear";
+    if env == Env::Local {
+        report.warning(message.to_owned());
+    } else {
+        report.problem(message.to_owned());
+    }
+}
+
+fn check_secrets(report: &mut Report, env: Env) {
+    report.check("secrets.toml", load_secrets());
+    if !using_default_key() {
+        return;
+    }
+    let message = "no dtls_key is set in secrets.toml, so the public example key is used";
+    if env == Env::Local {
+        report.warning(message.to_owned());
+    } else {
+        report.problem(message.to_owned());
+    }
+}
+
+fn check_ports(report: &mut Report, config: &RuntimeConfig, requirements: &Requirements) {
+    for name in requirements.ports {
+        match config.ports.iter().find(|port| port.name == *name) {
+            Some(port) if port.cores.is_empty() => {
+                report.problem(format!("port {} has no cores to poll it", name))
+            }
+            Some(port) => check_device(report, name, &port.device),
+            None => report.problem(format!("port {} is not in the runtime config", name)),
+        }
+    }
+
+    let configured = |core: usize| {
+        config.master_core.raw() == core
+            || config.cores.iter().any(|id| id.raw() == core)
+            || config
+                .ports
+                .iter()
+                .any(|port| port.cores.iter().any(|id| id.raw() == core))
+    };
+    for &core in requirements.cores {
+        if !configured(core) {
+            report.problem(format!("core {} is not in the runtime config", core));
+        }
+    }
+}
+
+/// Multicast groups are only of use on ports whose NIC passes multicast frames up
+fn check_l2(report: &mut Report, config: &RuntimeConfig, requirements: &Requirements) {
+    for name in requirements.ports {
+        let l2 = match report.optional_file("l2.toml", || load_l2_config(name)) {
+            Some(l2) => l2,
+            None => continue,
+        };
+        let multicast = config
+            .ports
+            .iter()
+            .any(|port| port.name == *name && port.multicast);
+        if !l2.multicast.is_empty() && !multicast {
+            report.problem(format!(
+                "l2.toml: port {} joins multicast groups, but multicast is off for it in the runtime config",
+                name
+            ));
+        }
+    }
+}
+
+/// PCI devices must exist and be bound for DPDK; virtual devices (e.g. net_tap0) are created by it
+fn check_device(report: &mut Report, port: &str, device: &str) {
+    if device.starts_with("net_") {
+        return;
+    }
+    let path = Path::new("/sys/bus/pci/devices").join(device);
+    if !path.exists() {
+        report.problem(format!("port {}: there is no PCI device {}", port, device));
+        return;
+    }
+    let driver = fs::read_link(path.join("driver"))
+        .ok()
+        .and_then(|driver| Some(driver.file_name()?.to_string_lossy().into_owned()));
+    match driver {
+        Some(driver) if DPDK_DRIVERS.contains(&driver.as_str()) => {}
+        Some(driver) => report.problem(format!(
+            "port {}: {} is bound to {}, not a DPDK driver ({})",
+            port,
+            device,
+            driver,
+            DPDK_DRIVERS.join(", ")
+        )),
+        None => report.problem(format!(
+            "port {}: {} is not bound to any driver",
+            port, device
+        )),
+    }
+}
+
+fn check_routes(report: &mut Report, env: Env, requirements: &Requirements) {
+    if requirements.rib == RibUse::None {
+        return;
+    }
+    let routes = match report.check("routes", load_routes(env)) {
+        Some(routes) => routes,
+        None => return,
+    };
+    for (what, ip) in [
+        ("rib", routes.rib().ip),
+        ("default", routes.default().ip),
+        ("time_master", routes.time_master().ip),
+    ] {
+        if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+            report.problem(format!("routes: {} is {}, not a unicast address", what, ip));
+        }
+    }
+    let node_addr = match requirements.node_addr {
+        Some(node_addr) => node_addr,
+        None => return,
+    };
+    match requirements.rib {
+        RibUse::Queries if routes.rib().ip == node_addr => report.problem(format!(
+            "routes: the RIB is at {}, which is this node's own address",
+            node_addr
+        )),
+        RibUse::Serves if routes.rib().ip != node_addr => report.warning(format!(
+            "routes: the RIB is at {}, but this RIB was started as {}",
+            routes.rib().ip,
+            node_addr
+        )),
+        _ => {}
+    }
+}
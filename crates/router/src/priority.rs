@@ -0,0 +1,182 @@
+use std::cell::Cell;
+use std::fs;
+use std::time::Instant;
+
+use anyhow::Result;
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::{GdpAction, GdpHeader};
+use metrics_runtime::data::Counter;
+use serde::Deserialize;
+
+use crate::dtls::replay::is_control_nonce;
+use crate::dtls::{in_clear, read_payload, DTls};
+use crate::gdp::Gdp;
+use crate::rxmeta::RxClock;
+
+/*
+   The control plane must keep working while the data plane is overwhelmed, or the network
+   cannot heal itself:
+   - while control packets are arriving (in the current RX burst or the one before), a share of
+     every RX burst is held back for them; data packets beyond the rest of the burst are shed
+     before they are decrypted. Without control traffic, nothing is shed
+   - packets are classed before decryption by the class their sender put in the nonce (see
+     replay.rs), which the sender derives from the GDP action. The nonce is authenticated, and a
+     data packet that its sender classed as control is dropped once it is decrypted. Packets in
+     the clear are classed by their action. Senders older than the class bit have all their
+     packets classed as data
+   - control packets we send are marked with DSCP network control, so the underlay can favor
+     them too, and go to the front of each TX burst, where they are the last to be dropped if the
+     TX queue is full; a share of every TX burst is kept free for them. The DSCP that packets
+     arrive with is not trusted: forwarded data that arrived marked as network control leaves
+     unmarked
+   - feedback on data is as urgent as the data: NACKs and the RibGets that a route miss sends
+     are control traffic, whatever data they answer, and an Ack takes the DSCP of its Put at
+     every switch that forwarded both (see puts.rs), as the Put's destination never saw it
+*/
+
+/// Packets pulled from the RX queue per poll, as done by capsule's `Poll`
+const RX_BURST: usize = 32;
+/// Class selector 6 (network control)
+const DSCP_NETWORK_CONTROL: u8 = 48;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct PriorityConfig {
+    /// Fraction of each RX and TX burst reserved for control traffic (0 disables the reservation)
+    pub control_reserve: f64,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        PriorityConfig {
+            control_reserve: 0.125,
+        }
+    }
+}
+
+impl PriorityConfig {
+    /// How many of `burst` packets may be data, leaving the rest to control traffic
+    pub fn data_slots(&self, burst: usize) -> usize {
+        let reserved = (burst as f64 * self.control_reserve.clamp(0.0, 1.0)).ceil() as usize;
+        burst - reserved.min(burst - 1)
+    }
+}
+
+pub fn load_priority_config() -> Result<PriorityConfig> {
+    let content = fs::read_to_string("priority.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// Traffic that the network needs to heal itself: routing, neighbor liveness and NACKs
+pub fn is_control(action: GdpAction) -> bool {
+    matches!(
+        action,
+        GdpAction::RibGet
+            | GdpAction::RibReply
+            | GdpAction::RibSearch
+            | GdpAction::RibSearchReply
+            | GdpAction::RibRegister
+            | GdpAction::RibSync
+            | GdpAction::RibSyncAck
+            | GdpAction::Heartbeat
+            | GdpAction::Invalidate
+            | GdpAction::Nack
+    )
+}
+
+/// Whether a plaintext GDP payload is control traffic
+pub fn is_control_payload(payload: &[u8]) -> bool {
+    GdpHeader::parse(payload).map_or(false, |(header, _)| {
+        GdpAction::try_from(header.action).map_or(false, is_control)
+    })
+}
+
+/// Mark control packets on their way out, so that they are prioritized from here on, and
+/// unmark data that was marked before it reached us
+pub fn mark_priority(mut packet: Gdp<DTls<Ipv4>>) -> Result<Gdp<DTls<Ipv4>>> {
+    let control = packet.action().map_or(false, is_control);
+    let ipv4 = packet.envelope_mut().envelope_mut().envelope_mut();
+    if control {
+        ipv4.set_dscp(DSCP_NETWORK_CONTROL);
+    } else if ipv4.dscp() == DSCP_NETWORK_CONTROL {
+        ipv4.set_dscp(0);
+    }
+    Ok(packet)
+}
+
+/// Whether an outgoing packet was marked by `mark_priority`
+pub fn is_marked_control(packet: &DTls<Ipv4>) -> bool {
+    packet.envelope().envelope().dscp() == DSCP_NETWORK_CONTROL
+}
+
+/// Whether an incoming packet is control traffic, as far as can be told before decrypting it
+fn is_control_class(packet: &DTls<Ipv4>) -> bool {
+    if in_clear(packet, false) {
+        return read_payload(packet).map_or(false, is_control_payload);
+    }
+    is_control_nonce(&packet.nonce())
+}
+
+/// Sheds data packets beyond their share of each RX burst, while control packets are arriving.
+/// One per pipeline.
+pub struct ControlPlanePolicer {
+    clock: RxClock,
+    data_slots: usize,
+    burst: Cell<Instant>,
+    /// The burst before the current one
+    previous_burst: Cell<Option<Instant>>,
+    /// The last burst that brought a control packet
+    control_burst: Cell<Option<Instant>>,
+    data_admitted: Cell<usize>,
+    shed: Counter,
+}
+
+impl ControlPlanePolicer {
+    pub fn new(config: PriorityConfig, clock: RxClock, nic_name: &'static str) -> Self {
+        let shed = metrics::global()
+            .sink()
+            .counter_with_labels("priority.shed", vec![("nic", nic_name)]);
+        ControlPlanePolicer {
+            burst: Cell::new(clock.burst_time()),
+            previous_burst: Cell::new(None),
+            control_burst: Cell::new(None),
+            clock,
+            data_slots: config.data_slots(RX_BURST),
+            data_admitted: Cell::new(0),
+            shed,
+        }
+    }
+
+    /// Runs before decryption, so that what is shed costs no crypto
+    pub fn admit(&self, packet: &DTls<Ipv4>, debug: bool) -> bool {
+        let burst = self.clock.burst_time();
+        if self.burst.get() != burst {
+            self.previous_burst.set(Some(self.burst.get()));
+            self.burst.set(burst);
+            self.data_admitted.set(0);
+        }
+        if is_control_class(packet) {
+            self.control_burst.set(Some(burst));
+            return true;
+        }
+        let control_arriving = match self.control_burst.get() {
+            Some(control_burst) => {
+                control_burst == burst || Some(control_burst) == self.previous_burst.get()
+            }
+            None => false,
+        };
+        if !control_arriving || self.data_admitted.get() < self.data_slots {
+            self.data_admitted.set(self.data_admitted.get() + 1);
+            return true;
+        }
+        self.shed.increment();
+        if debug {
+            println!(
+                "shedding packet from {}: data share of the RX burst used up",
+                packet.envelope().envelope().src()
+            );
+        }
+        false
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{metrics, Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpName, ProbeResult};
+use lru::LruCache;
+use metrics_runtime::data::Counter;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio_timer::delay_for;
+
+use crate::dtls::{encrypt_gdp, DTls};
+use crate::flags::{FeatureFlags, Flag};
+use crate::gdp::Gdp;
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::kvs::Store;
+use crate::l2filter::VlanTx;
+use crate::packet_ops::get_payload;
+use crate::rib::{create_control_request, create_reply, send_rib_request, Routes};
+use crate::ribpayload::RibQuery;
+use crate::schedule::Schedule;
+use crate::FwdTableEntry;
+
+/*
+   Routes learned from the RIB are checked before applications notice that they went stale:
+   - names that traffic is forwarded to are remembered, most recently forwarded first
+   - every PROBE_INTERVAL, up to PROBES_PER_RUN of them are sent an Echo at the locator that the
+     forwarding table has for them, which answers whether it can still reach the name
+   - a probe that goes unanswered for PROBE_TIMEOUT, or is answered as unreachable, drops the route
+     and asks the RIB for a fresh one
+*/
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Bounds the probe rate to this many per PROBE_INTERVAL, however much traffic we forward
+const PROBES_PER_RUN: usize = 8;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// A name is not probed again until this long after its last probe
+const REPROBE_AFTER: Duration = Duration::from_secs(30);
+/// Names beyond this many are forgotten, least recently forwarded first
+const MAX_ACTIVE_NAMES: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EchoProbe {
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct EchoAnswer {
+    pub nonce: u64,
+    pub reachable: bool,
+}
+
+struct Outstanding {
+    name: GdpName,
+    locator: Ipv4Addr,
+    sent: Instant,
+}
+
+struct ProbeState {
+    active: Mutex<LruCache<GdpName, ()>>,
+    outstanding: Mutex<HashMap<u64, Outstanding>>,
+    last_probed: Mutex<HashMap<GdpName, Instant>>,
+    results: Mutex<HashMap<GdpName, ProbeResult>>,
+    /// Names whose probe was answered as unreachable, to be refreshed by the next run
+    unreachable: Mutex<Vec<GdpName>>,
+    sent: Counter,
+    failed: Counter,
+}
+
+/// Probes the routes of the names we forward to. Shared by all cores of a switch.
+#[derive(Clone, Copy)]
+pub struct Prober(&'static ProbeState);
+
+impl Prober {
+    pub fn new(nic_name: &'static str) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut counter =
+            |name: &'static str| sink.counter_with_labels(name, vec![("nic", nic_name)]);
+        Prober(Box::leak(Box::new(ProbeState {
+            active: Mutex::new(LruCache::new(MAX_ACTIVE_NAMES)),
+            outstanding: Mutex::new(HashMap::new()),
+            last_probed: Mutex::new(HashMap::new()),
+            results: Mutex::new(HashMap::new()),
+            unreachable: Mutex::new(Vec::new()),
+            sent: counter("probe.sent"),
+            failed: counter("probe.failed"),
+        })))
+    }
+
+    /// A packet was forwarded to `dst`. Best effort: skipped if another core is recording too.
+    pub fn record_forward(&self, dst: GdpName) {
+        if let Ok(mut active) = self.0.active.try_lock() {
+            active.put(dst, ());
+        }
+    }
+
+    /// The latest result for each name that has been probed
+    pub fn results(&self) -> Vec<ProbeResult> {
+        self.0.results.lock().recover().values().cloned().collect()
+    }
+
+    fn record_result(&self, probe: Outstanding, reachable: bool) -> Result<()> {
+        let probe_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let rtt_us = reachable.then(|| probe.sent.elapsed().as_micros() as u64);
+        let mut results = self.0.results.lock().recover();
+        let result = results.entry(probe.name).or_insert(ProbeResult {
+            name: probe.name,
+            locator: probe.locator,
+            probes: 0,
+            failures: 0,
+            reachable,
+            rtt_us,
+            probe_time,
+        });
+        result.locator = probe.locator;
+        result.probes += 1;
+        result.reachable = reachable;
+        result.rtt_us = rtt_us.or(result.rtt_us);
+        result.probe_time = probe_time;
+        if !reachable {
+            result.failures += 1;
+            self.0.failed.increment();
+        }
+        Ok(())
+    }
+
+    /// Consume the answer to an Echo, if it was one of our probes
+    pub fn handle_answer(&self, answer: EchoAnswer) -> Result<()> {
+        let probe = self.0.outstanding.lock().recover().remove(&answer.nonce);
+        // None if answered after we gave up on it
+        if let Some(probe) = probe {
+            let name = probe.name;
+            self.record_result(probe, answer.reachable)?;
+            if !answer.reachable {
+                self.0.unreachable.lock().recover().push(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Names whose probe failed since the last call, either unanswered or unreachable
+    fn take_failed(&self) -> Result<Vec<GdpName>> {
+        let timed_out = {
+            let mut outstanding = self.0.outstanding.lock().recover();
+            let nonces = outstanding
+                .iter()
+                .filter(|(_, probe)| probe.sent.elapsed() >= PROBE_TIMEOUT)
+                .map(|(nonce, _)| *nonce)
+                .collect::<Vec<_>>();
+            nonces
+                .into_iter()
+                .filter_map(|nonce| outstanding.remove(&nonce))
+                .collect::<Vec<_>>()
+        };
+        let mut failed = std::mem::take(&mut *self.0.unreachable.lock().recover());
+        for probe in timed_out {
+            failed.push(probe.name);
+            self.record_result(probe, false)?;
+        }
+        Ok(failed)
+    }
+
+    /// Up to PROBES_PER_RUN of the most recently forwarded names that are due a probe
+    fn names_due(&self) -> Vec<GdpName> {
+        let active = self
+            .0
+            .active
+            .lock()
+            .recover()
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        let mut last_probed = self.0.last_probed.lock().recover();
+        last_probed.retain(|_, probed| probed.elapsed() < REPROBE_AFTER);
+        let due = active
+            .into_iter()
+            .filter(|name| !last_probed.contains_key(name))
+            .take(PROBES_PER_RUN)
+            .collect::<Vec<_>>();
+        for name in &due {
+            last_probed.insert(*name, Instant::now());
+        }
+        due
+    }
+}
+
+pub fn read_echo_answer(packet: &Gdp<DTls<Ipv4>>) -> Result<EchoAnswer> {
+    Ok(bincode::deserialize(get_payload(packet)?)?)
+}
+
+/// Answer a probe for the name `packet` is addressed to
+pub fn answer_echo(packet: &Gdp<DTls<Ipv4>>, reachable: bool) -> Result<Gdp<DTls<Ipv4>>> {
+    let probe: EchoProbe = bincode::deserialize(get_payload(packet)?)?;
+    let answer = EchoAnswer {
+        nonce: probe.nonce,
+        reachable,
+    };
+    create_reply(packet, GdpAction::EchoReply, &bincode::serialize(&answer)?)
+}
+
+/// Ask `locator` whether it can reach `name`. The probe is padded to at least `size` bytes
+/// of data, which the answer leaves out.
+pub fn send_echo(
+    q: &PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    name: GdpName,
+    locator: Ipv4Addr,
+    nonce: u64,
+    size: usize,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            let mut probe = bincode::serialize(&EchoProbe { nonce })?;
+            // trailing bytes are ignored when the probe is deserialized
+            probe.resize(probe.len().max(size), 0);
+            let mut packet = create_control_request(
+                packet,
+                GdpAction::Echo,
+                &probe,
+                src.mac,
+                src.ip,
+                src_gdp_name,
+                locator,
+            )?;
+            packet.set_dst(name);
+            Ok(packet)
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q.clone()))
+        .run_once();
+}
+
+fn run_probes(
+    q: &PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    rib_ip: Ipv4Addr,
+    store: Store,
+    prober: Prober,
+) -> Result<()> {
+    for name in prober.take_failed()? {
+        println!(
+            "route probe for {:?} failed, refreshing it from the RIB",
+            name
+        );
+        store.forwarding_table.remove(&name);
+        send_rib_request(
+            q.clone(),
+            src,
+            src_gdp_name,
+            rib_ip,
+            &RibQuery::next_hop_for(name),
+        );
+    }
+    for name in prober.names_due() {
+        // only routes learned from the RIB can go stale; operator pins are left alone
+        let locator = match store.forwarding_table.get(&name) {
+            Some(FwdTableEntry { val: ip, .. }) => ip,
+            None => continue,
+        };
+        let nonce = rand::thread_rng().gen();
+        prober.0.outstanding.lock().recover().insert(
+            nonce,
+            Outstanding {
+                name,
+                locator,
+                sent: Instant::now(),
+            },
+        );
+        send_echo(q, src, src_gdp_name, name, locator, nonce, 0);
+        prober.0.sent.increment();
+    }
+    Ok(())
+}
+
+/// Probe the routes of actively used names (when `route_probes` is on),
+/// in the background of the GDP pipeline
+pub fn probe_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    src_gdp_name: GdpName,
+    routes: &'static Routes,
+    store: Store,
+    prober: Prober,
+    flags: FeatureFlags,
+) -> impl Pipeline {
+    Schedule::new("route_probes", async move {
+        loop {
+            if flags.is_enabled(Flag::RouteProbes) {
+                let rib_ip = routes.rib().ip;
+                if let Err(err) = run_probes(&q, src, src_gdp_name, rib_ip, store, prober) {
+                    println!("route probes failed: {:#}", err);
+                }
+            }
+            delay_for(PROBE_INTERVAL).await;
+        }
+    })
+}
@@ -1,73 +1,132 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::Result;
 use capsule::config::RuntimeConfig;
 
-use crate::certificates::{CertDest, RtCert};
+use crate::admission::{admission_schedule, Admission};
+use crate::capabilities::heartbeat_schedule;
+use crate::certificates::{CertDest, NewRtCert, RtCert};
+use crate::chaos::chaos_schedule;
+use crate::clock::{time_sync_schedule, Clock};
+use crate::conntrack::flows_ended;
+use crate::control::{start_control_socket, ControlState};
+use crate::dtls::session::session_schedule;
+use crate::flags::{load_stage_sweep, FeatureFlags, SWEEP_TICK};
 use crate::gdp_pipeline::install_gdp_pipeline;
+use crate::handoff::{listen_for_successor, take_over};
 use crate::hardcoded_routes::{
-    gdp_name_of_index, load_routes, metadata_of_index, private_key_of_index,
+    gdp_name_of_index, load_routes, metadata_of_index, private_key_of_index, RoutesWatcher,
+    ROUTES_POLL_INTERVAL,
 };
-use crate::kvs::SharedStore;
-use crate::rib::{rib_pipeline, send_rib_query, Routes};
-use crate::ribpayload::RibQuery;
+use crate::identity::{load_port_identities, PortIdentities};
+use crate::info::{node_info, print_banner};
+use crate::invalidation::{gossip_schedule, Gossip};
+use crate::isolation::Recover;
+use crate::kvs::{SharedStore, PUBLISH_INTERVAL};
+use crate::labels::{flush_label_audit, AUDIT_FLUSH_INTERVAL};
+use crate::prefetch::{prefetch_schedule, Prefetcher, SequentialPredictor, DEFAULT_PREFETCH_DEPTH};
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::probe::{probe_schedule, Prober};
+use crate::prometheus::start_metrics_endpoint;
+use crate::recorder::{flush_recordings, RECORD_FLUSH_INTERVAL};
+use crate::registration::register_node;
+use crate::rib::Routes;
 use crate::runtime::build_runtime;
 use crate::statistics::{dump_history, make_print_stats};
+use crate::status::{StatusBoard, STATUS_INTERVAL};
 use crate::switch::switch_pipeline;
+use crate::usage::{UsageMeter, REPORT_INTERVAL};
 use crate::Env;
 
-pub fn start_rib_server(
-    config: RuntimeConfig,
-    env: Env,
-    node_addr: Ipv4Addr,
-    use_default: bool,
-    debug: bool,
-) -> Result<()> {
-    let routes: &'static Routes = Box::leak(Box::new(load_routes(env)?));
-
-    build_runtime(config, env)?
-        .add_pipeline_to_port("eth1", move |q| {
-            install_gdp_pipeline(
-                q,
-                rib_pipeline("rib", routes, use_default, debug),
-                "prod",
-                node_addr,
-                debug,
-            )
-        })?
-        .execute()?;
-    Ok(())
-}
-
+#[allow(clippy::too_many_arguments)]
 pub fn start_switch_server(
     config: RuntimeConfig,
     env: Env,
     gdp_index: u8,
     node_addr: Ipv4Addr,
+    flags: FeatureFlags,
+    control_port: Option<u16>,
+    metrics_addr: Option<SocketAddr>,
+    handoff: Option<PathBuf>,
     debug: bool,
 ) -> Result<()> {
+    preflight(
+        &config,
+        env,
+        &Requirements {
+            ports: &["eth1"],
+            cores: &[0],
+            node_addr: Some(node_addr),
+            rib: RibUse::Queries,
+            workload: false,
+        },
+    )?;
     let gdp_name = gdp_name_of_index(gdp_index);
     let meta = metadata_of_index(gdp_index);
     let private_key = private_key_of_index(gdp_index);
+    let info = Box::leak(Box::new(node_info(
+        "switch", gdp_name, meta, &config, flags,
+    )));
+    print_banner(info);
 
     let store = SharedStore::new();
+    if let Some(path) = &handoff {
+        if let Some(snapshot) = take_over(path)? {
+            store.restore(snapshot);
+        }
+    }
     let (_print_stats, history_map) = make_print_stats();
     let routes: &'static Routes = Box::leak(Box::new(load_routes(env)?));
+    let routes_watcher = RoutesWatcher::new(routes, env);
+    let sweep = load_stage_sweep(flags)?;
+    let identities: &'static PortIdentities = Box::leak(Box::new(load_port_identities()?));
 
     let cert = RtCert::new_wrapped(meta, private_key, CertDest::IpAddr(node_addr), true)?;
 
+    let clock = Clock::new();
+    let prefetcher = Prefetcher::new(SequentialPredictor::new(DEFAULT_PREFETCH_DEPTH), "switch");
+    let prober = Prober::new("switch");
+    let usage = UsageMeter::new(gdp_name, private_key)?;
+    let admission = Admission::new("switch");
+    let status = StatusBoard::new(gdp_name);
+    let gossip = Gossip::new("switch", private_key);
+
+    if let Some(port) = control_port {
+        start_control_socket(
+            port,
+            ControlState {
+                flags,
+                info,
+                clock,
+                store,
+                prober: Some(prober),
+                usage: Some(usage),
+            },
+        )?;
+    }
+    if let Some(addr) = metrics_addr {
+        start_metrics_endpoint(addr)?;
+    }
+    if let Some(path) = handoff {
+        listen_for_successor(path, store)?;
+    }
+
+    // every queue of eth1 runs the GDP pipeline, and RSS spreads the flows over them; the control
+    // plane runs once, on the first core that polls eth1
+    let control_core = config
+        .ports
+        .iter()
+        .find(|port| port.name == "eth1")
+        .and_then(|port| port.cores.first())
+        .map_or(0, |core| core.raw());
+    let timed_out_flows = flows_ended("timeout");
+
     build_runtime(config, env)?
         .add_pipeline_to_port("eth1", move |q| {
             let store = store.sync();
-            send_rib_query(
-                q.clone(),
-                node_addr,
-                gdp_name,
-                routes.rib.ip,
-                &RibQuery::announce_route(meta, cert.clone()),
-                "prod",
-            );
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
             install_gdp_pipeline(
                 q,
                 switch_pipeline(
@@ -76,17 +135,99 @@ pub fn start_switch_server(
                     private_key,
                     store,
                     "switch",
-                    routes.rib.ip,
+                    identity,
+                    routes,
+                    flags,
+                    clock,
+                    prefetcher,
+                    prober,
+                    usage,
+                    admission,
+                    status,
+                    gossip,
                     debug,
                 ),
                 "prod",
-                node_addr,
+                identity.ip,
+                flags,
                 debug,
             )
         })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            register_node(
+                q.clone(),
+                identity,
+                meta,
+                vec![cert.clone()],
+                private_key,
+                routes.rib().ip,
+                "prod",
+            );
+            prefetch_schedule(q, identity, gdp_name, routes, store.sync(), prefetcher)
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            heartbeat_schedule(q, identity, gdp_name, routes, store, flags)
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            probe_schedule(q, identity, gdp_name, routes, store.sync(), prober, flags)
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            gossip_schedule(q, identity, gdp_name, routes, store, gossip)
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            admission_schedule(q, identity, gdp_name, admission, debug)
+        })?
+        .add_pipeline_to_core(control_core, move |_q| chaos_schedule(store.sync(), debug))?
+        .add_pipeline_to_core(control_core, move |q| session_schedule(q["eth1"].clone()))?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            time_sync_schedule(q, identity, gdp_name, routes, clock)
+        })?
         // .add_periodic_task_to_core(0, print_stats, Duration::from_secs(1))?
-        .add_periodic_task_to_core(0, move || store.run_active_expire(), Duration::from_secs(1))?
+        .add_periodic_task_to_core(0, move || store.publish(), PUBLISH_INTERVAL)?
+        .add_periodic_task_to_core(0, move || routes_watcher.poll(), ROUTES_POLL_INTERVAL)?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                store.run_active_expire();
+                timed_out_flows.record(store.take_timed_out_flows());
+            },
+            Duration::from_secs(1),
+        )?
+        .add_periodic_task_to_core(0, move || flags.report(), Duration::from_secs(1))?
+        .add_periodic_task_to_core(0, move || sweep.tick(), SWEEP_TICK)?
+        .add_periodic_task_to_core(0, flush_recordings, RECORD_FLUSH_INTERVAL)?
+        .add_periodic_task_to_core(0, flush_label_audit, AUDIT_FLUSH_INTERVAL)?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                if let Err(err) = usage.report() {
+                    println!("failed to make usage report: {:#}", err);
+                }
+            },
+            REPORT_INTERVAL,
+        )?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                if let Err(err) = status.refresh(store, clock) {
+                    println!("failed to refresh the switch's status: {:#}", err);
+                }
+            },
+            STATUS_INTERVAL,
+        )?
         .execute()?;
-    dump_history(&(*history_map.lock().unwrap()))?;
+    dump_history(&(*history_map.lock().recover()))?;
     Ok(())
 }
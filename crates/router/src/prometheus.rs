@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use capsule::metrics;
+use metrics_runtime::Measurement;
+
+/*
+   Every counter and gauge in the metrics registry can be scraped over HTTP, in the Prometheus
+   text format, from an address given on the command line:
+   - the endpoint is served from its own thread, away from the packet cores, and answers any
+     request with the current values; there is nothing to configure but the address
+   - a bare port is served on localhost only, as anyone who can reach the endpoint sees the
+     metrics. Scrapers on other hosts need the address to listen on spelled out (`0.0.0.0:9100`)
+   - metric and label names have the dots and dashes of our names turned into underscores
+     (`route_cache`, `gdp_packets`, ...); values are as recorded, so counters only ever grow
+   - histograms are left out; the periodic printout still shows them
+   - while a port samples its per-packet counters, they grow in steps of its
+     `stats_sample_rate` gauge
+*/
+
+/// How long a scraper has to send its request
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The counters and gauges in the registry, in the Prometheus text format
+pub fn render_metrics() -> String {
+    let snapshot = metrics::global().controller().snapshot();
+    // grouped by name, as each name's samples must follow its TYPE line
+    let mut metrics: BTreeMap<String, (&str, Vec<String>)> = BTreeMap::new();
+    for (key, measurement) in snapshot.into_measurements() {
+        let (kind, value) = match measurement {
+            Measurement::Counter(value) => ("counter", value.to_string()),
+            Measurement::Gauge(value) => ("gauge", value.to_string()),
+            _ => continue,
+        };
+        let (name, labels) = key.into_parts();
+        let labels = labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{}=\"{}\"",
+                    metric_name(label.key()),
+                    label_value(label.value())
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        metrics
+            .entry(metric_name(&name))
+            .or_insert_with(|| (kind, Vec::new()))
+            .1
+            .push(format!("{{{}}} {}", labels, value));
+    }
+
+    let mut out = String::new();
+    for (name, (kind, samples)) in metrics {
+        out.push_str(&format!("# TYPE {} {}\n", name, kind));
+        for sample in samples {
+            out.push_str(&format!("{}{}\n", name, sample));
+        }
+    }
+    out
+}
+
+fn answer_scrape(mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    // every request gets the metrics, so the request itself is only read to be polite
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+    let body = render_metrics();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// Where to serve the metrics, from `--metrics`: an address and port, or a port on localhost
+pub fn parse_metrics_addr(value: &str) -> Result<SocketAddr> {
+    if let Ok(port) = value.parse::<u16>() {
+        return Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+    value
+        .parse()
+        .with_context(|| format!("{} is neither a port nor an address and port", value))
+}
+
+/// Serve the metrics to scrapers on `addr`, on a thread separate from the packet cores
+pub fn start_metrics_endpoint(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("failed to bind metrics endpoint to {}", addr))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(err) = stream.map_err(Into::into).and_then(answer_scrape) {
+                println!("metrics: failed to answer scrape: {:#}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_ports_are_served_on_localhost() {
+        assert_eq!(
+            parse_metrics_addr("9100").unwrap(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 9100))
+        );
+        assert_eq!(
+            parse_metrics_addr("0.0.0.0:9100").unwrap(),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 9100))
+        );
+        assert!(parse_metrics_addr("localhost").is_err());
+        assert!(parse_metrics_addr("70000").is_err());
+    }
+
+    #[test]
+    fn names_and_values_are_escaped() {
+        assert_eq!(metric_name("route_cache.hit-rate"), "route_cache_hit_rate");
+        assert_eq!(label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[capsule::test]
+    fn counters_are_rendered_under_their_type() {
+        let counter = metrics::global()
+            .sink()
+            .counter_with_labels("prometheus.test-scrape", vec![("case", "a\"b")]);
+        counter.record(3);
+        let gauge = metrics::global().sink().gauge("prometheus.test-gauge");
+        gauge.record(-2);
+
+        let out = render_metrics();
+        let lines = out.lines().collect::<Vec<_>>();
+        let counter = lines
+            .iter()
+            .position(|line| *line == "# TYPE prometheus_test_scrape counter")
+            .unwrap();
+        assert_eq!(
+            lines[counter + 1],
+            "prometheus_test_scrape{case=\"a\\\"b\"} 3"
+        );
+        let gauge = lines
+            .iter()
+            .position(|line| *line == "# TYPE prometheus_test_gauge gauge")
+            .unwrap();
+        assert_eq!(lines[gauge + 1], "prometheus_test_gauge{} -2");
+    }
+}
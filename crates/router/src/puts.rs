@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use gdp_proto::{content_hash, GdpAction, GdpName, NAME_LEN};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+
+use crate::dedup::SeqKey;
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+
+/*
+   Puts are data that their destination acknowledges with an Ack carrying the Put's number in
+   its flow, and that their sender sends again, with the same number, until the Ack arrives (see
+   the client's acks.rs). Switches route both like Forwards, and watch how the Puts fare:
+   - a switch remembers each Put it forwards until it forwards the Ack for it, and counts the
+     Puts it forwards again before that as retransmitted, and the Acks as acked
+   - an Ack proves that its Put was received with the content hash of the Put's data (see the
+     client's acks.rs). Acks that carry another are forwarded all the same, as the switch cannot
+     tell which Put is genuine, but settle nothing here and take no class from the Put, and are
+     counted as unproven
+   - Puts that were not acknowledged within PUT_TIMEOUT are forgotten as later Puts and Acks
+     pass, and counted as unacknowledged. An Ack that comes back by another switch leaves its
+     Put unacknowledged at this one
+   - at most MAX_TRACKED Puts are remembered; those beyond are forwarded all the same, and
+     counted as untracked
+   Switches never send a Put again themselves: its sender does already, and a Put whose Ack is
+   merely late would be copied once more by every switch on the way. An unmarked Ack leaves
+   with the DSCP its Put arrived with, which the destination never saw, so that the Acks of
+   critical data are not queued behind bulk data (see priority.rs).
+*/
+
+/// How long a switch waits for the Ack of a Put before counting it as unacknowledged
+const PUT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Puts waiting for their Ack that are remembered at most
+const MAX_TRACKED: usize = 1 << 16;
+/// How often the Puts that timed out are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+struct TrackedPut {
+    /// When the Put was last forwarded
+    sent: Instant,
+    /// The traffic class the Put arrived with
+    dscp: u8,
+    /// What the Ack must carry: the content hash of the Put's data
+    proof: GdpName,
+}
+
+struct Tracked {
+    puts: HashMap<SeqKey, TrackedPut>,
+    swept: Instant,
+}
+
+pub struct PutTracker {
+    tracked: Mutex<Tracked>,
+    forwarded: Counter,
+    retransmitted: Counter,
+    acked: Counter,
+    unacknowledged: Counter,
+    untracked: Counter,
+    unproven: Counter,
+    /// Acks that took the DSCP of their Put
+    inherited: Counter,
+}
+
+// created on first use, shared by every switch pipeline, as a Put and its Ack may arrive on
+// different ones
+static PUTS: Lazy<PutTracker> = Lazy::new(|| {
+    let mut sink = metrics::global().sink();
+    let mut outcomes =
+        |outcome: &'static str| sink.counter_with_labels("puts", vec![("outcome", outcome)]);
+    PutTracker {
+        tracked: Mutex::new(Tracked {
+            puts: HashMap::new(),
+            swept: Instant::now(),
+        }),
+        forwarded: outcomes("forwarded"),
+        retransmitted: outcomes("retransmitted"),
+        acked: outcomes("acked"),
+        unacknowledged: outcomes("unacknowledged"),
+        untracked: outcomes("untracked"),
+        unproven: outcomes("unproven"),
+        inherited: sink.counter("puts.inherited_class"),
+    }
+});
+
+pub fn puts() -> &'static PutTracker {
+    &PUTS
+}
+
+/// What the Ack of the Put `packet` must carry
+fn proof_of(packet: &Gdp<DTls<Ipv4>>) -> GdpName {
+    match packet.content_hash() {
+        // from a sender that does not hash its data, which its destination does all the same
+        unset if unset == [0; NAME_LEN] => packet.data().map_or(unset, content_hash),
+        hash => hash,
+    }
+}
+
+impl PutTracker {
+    /// Keep track of a Put or Ack that is being forwarded, marking an unmarked Ack with the
+    /// DSCP of its Put; other packets are ignored
+    pub fn watch(&self, packet: &mut Gdp<DTls<Ipv4>>) {
+        let action = match packet.action() {
+            Ok(action @ (GdpAction::Put | GdpAction::Ack)) => action,
+            _ => return,
+        };
+        let seqno = packet.seqno();
+        if seqno == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let dscp = packet.envelope().envelope().envelope().dscp();
+        let mut tracked = self.tracked.lock().recover();
+        self.sweep(&mut tracked, now);
+        if action == GdpAction::Ack {
+            // named the other way round from its Put
+            let key = (packet.dst(), packet.src(), seqno);
+            let proved = match (tracked.puts.get(&key), packet.data()) {
+                (Some(put), Ok(proof)) => put.proof[..] == *proof,
+                (Some(_), Err(_)) => false,
+                (None, _) => return,
+            };
+            if !proved {
+                self.unproven.increment();
+                return;
+            }
+            if let Some(put) = tracked.puts.remove(&key) {
+                self.acked.increment();
+                if dscp == 0 && put.dscp != 0 {
+                    let ipv4 = packet.envelope_mut().envelope_mut().envelope_mut();
+                    ipv4.set_dscp(put.dscp);
+                    self.inherited.increment();
+                }
+            }
+            return;
+        }
+        let key = (packet.src(), packet.dst(), seqno);
+        if let Some(put) = tracked.puts.get_mut(&key) {
+            put.sent = now;
+            put.dscp = dscp;
+            self.retransmitted.increment();
+        } else if tracked.puts.len() < MAX_TRACKED {
+            let proof = proof_of(packet);
+            tracked.puts.insert(
+                key,
+                TrackedPut {
+                    sent: now,
+                    dscp,
+                    proof,
+                },
+            );
+            self.forwarded.increment();
+        } else {
+            self.untracked.increment();
+        }
+    }
+
+    fn sweep(&self, tracked: &mut Tracked, now: Instant) {
+        if now.duration_since(tracked.swept) < SWEEP_INTERVAL {
+            return;
+        }
+        tracked.swept = now;
+        let before = tracked.puts.len();
+        tracked
+            .puts
+            .retain(|_, put| now.duration_since(put.sent) < PUT_TIMEOUT);
+        self.unacknowledged
+            .record((before - tracked.puts.len()) as u64);
+    }
+}
@@ -0,0 +1,310 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::GdpHeader;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::dtls::DTls;
+use crate::flags::{FeatureFlags, Flag};
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+use crate::kvs::{SharedStore, Store, StoreCapture, StoreSnapshot};
+use crate::switch::{decide_route, RouteDecision};
+
+/*
+   Switches can record their routing decisions, to debug misroutes that cannot be reproduced live:
+   - one in `sample_one_in` packets whose certificates check out in the Forward branch is recorded,
+     with its bytes (from the GDP header on), when it was seen, the flags that steer routing, and
+     the decision that the store gave for it
+   - with each packet goes the store as its core saw it when deciding: the core's snapshots of
+     every table, and its own writes that were not published yet. Capturing them on the packet
+     path only clones a few Arcs; copying them out and writing them is left to flush_recordings,
+     on core 0, which writes each published epoch's store once and points every packet at it
+   - a recording is a sequence of bincode Records, which `gdp-replay` reads back: it rebuilds each
+     store, with its expiration times moved to the present so that what was live is still live,
+     decides every packet again and reports where the decision differs from the recorded one
+   Certificate checks, admission control and the work budget are not replayed.
+*/
+
+/// How often the recorded packets are written out
+pub const RECORD_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+/// Packets are dropped from the recording, rather than queued, past this many waiting to be written
+const MAX_PENDING: usize = 1024;
+
+#[derive(Deserialize)]
+pub struct RecordConfig {
+    /// Record one in this many packets; 0 records none
+    #[serde(default)]
+    pub sample_one_in: u32,
+    #[serde(default = "default_record_path")]
+    pub path: PathBuf,
+}
+
+fn default_record_path() -> PathBuf {
+    "decisions.rec".into()
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        RecordConfig {
+            sample_one_in: 0,
+            path: default_record_path(),
+        }
+    }
+}
+
+pub fn load_record_config() -> Result<RecordConfig> {
+    let content = fs::read_to_string("record.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Record {
+    /// A store that later packets were decided against
+    Store {
+        id: u64,
+        snapshot: StoreSnapshot,
+    },
+    Packet(RecordedPacket),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecordedPacket {
+    /// The Store record this packet was decided against
+    pub store: u64,
+    /// Microseconds since the Unix epoch
+    pub timestamp_us: u64,
+    pub nic_name: String,
+    pub flow_tracking: bool,
+    pub bytes: Vec<u8>,
+    pub decision: RouteDecision,
+}
+
+struct Pending {
+    packet: RecordedPacket,
+    capture: StoreCapture,
+}
+
+struct Writer {
+    out: BufWriter<File>,
+    next_store: u64,
+    /// The last store written for a published epoch, which later captures of it can share
+    published: Option<(u64, u64)>,
+}
+
+struct Recordings {
+    config: RecordConfig,
+    pending: Mutex<Vec<Pending>>,
+    writer: Mutex<Option<Writer>>,
+}
+
+// created on first use, shared by every switch pipeline and the flush task
+static RECORDINGS: Lazy<Recordings> = Lazy::new(|| Recordings {
+    config: load_record_config().unwrap_or_default(),
+    pending: Mutex::new(Vec::new()),
+    writer: Mutex::new(None),
+});
+
+fn recordings() -> &'static Recordings {
+    &RECORDINGS
+}
+
+/// Samples the packets of one pipeline into the recording
+pub struct Recorder {
+    nic_name: &'static str,
+    sample_one_in: u32,
+    /// Packets seen since the last one recorded
+    skipped: Cell<u32>,
+}
+
+impl Recorder {
+    pub fn new(nic_name: &'static str) -> Self {
+        Recorder {
+            nic_name,
+            sample_one_in: recordings().config.sample_one_in,
+            skipped: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn record(&self, packet: &Gdp<DTls<Ipv4>>, store: Store, flags: FeatureFlags) {
+        if self.sample_one_in == 0 {
+            return;
+        }
+        if self.skipped.get() + 1 < self.sample_one_in {
+            self.skipped.set(self.skipped.get() + 1);
+            return;
+        }
+        self.skipped.set(0);
+        if let Err(err) = self.record_sampled(packet, store, flags) {
+            println!("{} failed to record packet: {:#}", self.nic_name, err);
+        }
+    }
+
+    fn record_sampled(
+        &self,
+        packet: &Gdp<DTls<Ipv4>>,
+        store: Store,
+        flags: FeatureFlags,
+    ) -> Result<()> {
+        let mut pending = recordings().pending.lock().recover();
+        if pending.len() >= MAX_PENDING {
+            return Ok(());
+        }
+        let offset = packet.offset();
+        let bytes = packet
+            .mbuf()
+            .read_data_slice::<u8>(offset, packet.mbuf().data_len() - offset)?;
+        let bytes = unsafe { bytes.as_ref() }.to_vec();
+        // the decision and the capture must see the same publish
+        let _view = store.pin();
+        let decision = decide_route(packet.src(), packet.dst(), store, flags);
+        pending.push(Pending {
+            packet: RecordedPacket {
+                store: 0,
+                timestamp_us: SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64,
+                nic_name: self.nic_name.to_owned(),
+                flow_tracking: flags.is_enabled(Flag::FlowTracking),
+                bytes,
+                decision,
+            },
+            capture: store.capture(),
+        });
+        Ok(())
+    }
+}
+
+/// Write out the packets recorded since the last call, with the stores they were decided against
+pub fn flush_recordings() {
+    let recordings = recordings();
+    if recordings.config.sample_one_in == 0 {
+        return;
+    }
+    let pending = std::mem::take(&mut *recordings.pending.lock().recover());
+    if pending.is_empty() {
+        return;
+    }
+    let mut writer = recordings.writer.lock().recover();
+    if let Err(err) = write_pending(&mut writer, &recordings.config.path, pending) {
+        println!("failed to write the recording: {:#}", err);
+    }
+}
+
+fn write_pending(writer: &mut Option<Writer>, path: &Path, pending: Vec<Pending>) -> Result<()> {
+    if writer.is_none() {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        *writer = Some(Writer {
+            out: BufWriter::new(file),
+            next_store: 0,
+            published: None,
+        });
+    }
+    let writer = writer.as_mut().unwrap();
+    for Pending {
+        mut packet,
+        capture,
+    } in pending
+    {
+        let shared = match writer.published {
+            Some((epoch, id)) if capture.is_published() && capture.epoch() == epoch => Some(id),
+            _ => None,
+        };
+        packet.store = match shared {
+            Some(id) => id,
+            None => {
+                let id = writer.next_store;
+                writer.next_store += 1;
+                if capture.is_published() {
+                    writer.published = Some((capture.epoch(), id));
+                }
+                let snapshot = capture.into_snapshot();
+                bincode::serialize_into(&mut writer.out, &Record::Store { id, snapshot })?;
+                id
+            }
+        };
+        bincode::serialize_into(&mut writer.out, &Record::Packet(packet))?;
+    }
+    writer.out.flush()?;
+    Ok(())
+}
+
+/// Decide every packet in the recording at `path` again, printing those decided differently
+/// (or every packet, with `verbose`). Returns how many were decided differently.
+pub fn replay(path: &Path, verbose: bool) -> Result<usize> {
+    let mut input = BufReader::new(
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+    );
+    let mut snapshots: HashMap<u64, StoreSnapshot> = HashMap::new();
+    // rebuilt once per recorded store, as of the first packet decided against it
+    let mut stores: HashMap<u64, Store> = HashMap::new();
+    let (mut replayed, mut differed) = (0, 0);
+    loop {
+        let record: Record = match bincode::deserialize_from(&mut input) {
+            Ok(record) => record,
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => break,
+                _ => return Err(err).context("corrupt recording"),
+            },
+        };
+        let packet = match record {
+            Record::Store { id, snapshot } => {
+                snapshots.insert(id, snapshot);
+                continue;
+            }
+            Record::Packet(packet) => packet,
+        };
+        let store = match stores.get(&packet.store) {
+            Some(store) => *store,
+            None => {
+                let mut snapshot = snapshots.remove(&packet.store).ok_or_else(|| {
+                    anyhow!(
+                        "packet refers to store {}, which was not recorded",
+                        packet.store
+                    )
+                })?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                snapshot.postpone_expirations(now.saturating_sub(packet.timestamp_us / 1_000_000));
+                let shared = SharedStore::new();
+                shared.restore(snapshot);
+                let store = shared.sync();
+                stores.insert(packet.store, store);
+                store
+            }
+        };
+        let (header, _) = GdpHeader::parse(&packet.bytes)?;
+        let flags = FeatureFlags::new();
+        flags.set(Flag::FlowTracking, packet.flow_tracking);
+        let decision = decide_route(header.src, header.dst, store, flags);
+
+        replayed += 1;
+        let same = decision == packet.decision;
+        if !same {
+            differed += 1;
+        }
+        if verbose || !same {
+            println!(
+                "{} {}us trace {:016x}: recorded {:?}, replayed {:?}",
+                packet.nic_name,
+                packet.timestamp_us,
+                u64::from(header.trace_id),
+                packet.decision,
+                decision
+            );
+        }
+    }
+    println!(
+        "replayed {} packets, {} decided differently",
+        replayed, differed
+    );
+    Ok(differed)
+}
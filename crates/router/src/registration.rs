@@ -0,0 +1,738 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use capsule::batch::{self, Batch};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{Mbuf, PortQueue};
+use gdp_proto::{
+    GdpAction, GdpName, Registration, RegistrationContents, RegistrationPhase, NAME_LEN,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::certificates::{
+    check_owner, meta_of_key, sign_data, verify_data, Certificate, GdpMeta, SerializableSignature,
+    VerifyCert,
+};
+use crate::dtls::{encrypt_gdp, DTls};
+use crate::gdp::Gdp;
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::kvs::Store;
+use crate::l2filter::VlanTx;
+use crate::packet_ops::get_payload;
+use crate::rib::{create_control_request, Routes};
+use crate::ribpayload::{complete_migration, insert_cert, process_rib_data, record_migrations};
+use crate::secrets::{decode_hex, load_secrets};
+
+/*
+   Nodes register their own name with the RIB in a RibRegister packet, sent through their switch:
+   - the payload is a Registration: the node's metadata, the certificates that locate or describe
+     it, and when it was made, signed with the node's key
+   - it is only accepted if the metadata hashes to the packet's source name, the signature checks
+     out against that metadata, and every certificate is owned by that name. Registrations older
+     than MAX_REGISTRATION_AGE (or that far in the future) are refused, so that a captured one
+     cannot be sent again later to move the name back
+   - switches on the way learn the routes in it, and forward it unchanged, since the signature
+     covers every certificate
+   - registering is the only way to bind a name. The metadata and certificates that older nodes
+     piggybacked on a RibGet were signed by nobody as a whole, so that an old announcement could
+     be sent again to move a name back; the RIB and switches now ignore them
+   - the RIB does not answer; a node that needs to know can look its own name up
+   A name moves between hosts make-before-break, in two registrations:
+   - the new host registers as Pending. The RIB keeps the current location, and tells the switches
+     subscribed to the name where it is moving; they, and the switches the registration passes
+     through, deliver a copy of every packet for the name to the new host as well, for up to
+     MIGRATION_WINDOW. Receivers see those packets twice, and must tolerate it
+   - once the new host is ready, the old one sends a Withdraw. Its switches, and the RIB, move
+     the name to where it was moving, and the RIB pushes the move to its subscribers. A name that
+     was not moving is withdrawn altogether; its switches hold packets for it while they ask again
+   - if no Withdraw comes within MIGRATION_WINDOW (the old host died, say), the RIB moves the name
+     anyway
+   Fleets of devices are provisioned without the operator taking part in each registration:
+   - the operator signs a FleetToken for each device key of the fleet in one go, covering names
+     that start with a prefix (names are hashes of keys, so devices draw keys until their name
+     falls under it) until it expires. A token names the key it was issued to, so that it is of
+     no use to anyone who copies it off a device or the wire
+   - a device sends its token after its registration, which it signs with its own key as ever.
+     Nodes from before fleet tokens ignore the trailing token, and take the registration alone
+   - a node whose registration.toml has `require_token` refuses registrations without a token from
+     one of its `operators` that covers the name and was issued to the registering key. Switches
+     check this too, so that registrations the RIB would refuse are not learned on the way.
+     Without `require_token`, tokens are ignored
+*/
+
+/// How far the time a registration was made may be from the RIB's clock
+const MAX_REGISTRATION_AGE: Duration = Duration::from_secs(60);
+/// How long a name may be registered at two hosts while it moves between them
+pub const MIGRATION_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+pub struct FleetTokenContents {
+    pub operator: GdpMeta,
+    /// The key that the device registers with
+    pub device: GdpMeta,
+    /// Names are covered if their first `prefix_bits` bits are those of `prefix`
+    pub prefix: GdpName,
+    pub prefix_bits: u16,
+    /// Seconds since the Unix epoch
+    pub expiration_time: u64,
+}
+
+/// An operator's leave for a device to register its name, if the name is under a prefix
+#[derive(Serialize, Deserialize)]
+pub struct FleetToken {
+    pub contents: FleetTokenContents,
+    pub signature: SerializableSignature,
+}
+
+fn has_prefix(name: &GdpName, prefix: &GdpName, prefix_bits: u16) -> bool {
+    let bits = (prefix_bits as usize).min(NAME_LEN * 8);
+    let (bytes, rest) = (bits / 8, bits % 8);
+    name[..bytes] == prefix[..bytes]
+        && (rest == 0 || (name[bytes] ^ prefix[bytes]) >> (8 - rest) == 0)
+}
+
+impl FleetToken {
+    pub fn issue(
+        device: GdpMeta,
+        prefix: GdpName,
+        prefix_bits: u16,
+        valid_for: Duration,
+        operator_key: [u8; 32],
+    ) -> Result<Self> {
+        let contents = FleetTokenContents {
+            operator: meta_of_key(operator_key)?,
+            device,
+            prefix,
+            prefix_bits,
+            expiration_time: (SystemTime::now().duration_since(UNIX_EPOCH)? + valid_for).as_secs(),
+        };
+        let signature = sign_data(&contents, operator_key)?;
+        Ok(FleetToken {
+            contents,
+            signature,
+        })
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        let bytes = bincode::serialize(self)?;
+        Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        Ok(bincode::deserialize(&decode_hex(hex)?)?)
+    }
+
+    /// Check that an operator `policy` trusts signed the token for `meta`, and that it covers
+    /// `gdp_name`
+    fn verify(
+        &self,
+        meta: &GdpMeta,
+        gdp_name: GdpName,
+        policy: &RegistrationPolicy,
+        now: u64,
+    ) -> Result<()> {
+        let contents = &self.contents;
+        ensure!(
+            policy
+                .operators
+                .iter()
+                .any(|operator| operator.pub_key == contents.operator.pub_key),
+            "fleet token of {:?} is from an operator this node does not trust",
+            gdp_name
+        );
+        verify_data(contents, self.signature, &contents.operator)
+            .context("fleet token is not signed by its operator")?;
+        ensure!(
+            contents.device.pub_key == meta.pub_key,
+            "fleet token of {:?} was issued to another key",
+            gdp_name
+        );
+        ensure!(
+            now <= contents.expiration_time,
+            "fleet token of {:?} expired at {}",
+            gdp_name,
+            contents.expiration_time
+        );
+        ensure!(
+            has_prefix(&gdp_name, &contents.prefix, contents.prefix_bits),
+            "fleet token does not cover {:?}",
+            gdp_name
+        );
+        Ok(())
+    }
+}
+
+/// Sign a fleet token with the operator key in secrets.toml for each of the device keys in
+/// `devices` (in hex, one per line), covering the names that start with the first `prefix_bits`
+/// bits of `prefix` (in hex). Returns a line per device, of its key and its token in hex for its
+/// registration.toml
+pub fn issue_fleet_tokens(
+    prefix: &str,
+    prefix_bits: u16,
+    devices: &str,
+    valid_for: Duration,
+) -> Result<String> {
+    let operator_key = load_secrets()?
+        .operator_key
+        .ok_or_else(|| anyhow!("secrets.toml has no operator_key"))?;
+    let bytes = decode_hex(prefix)?;
+    ensure!(
+        bytes.len() <= NAME_LEN && prefix_bits as usize <= bytes.len() * 8,
+        "a prefix of {} bits needs {} hex digits, and names have {}",
+        prefix_bits,
+        (prefix_bits as usize + 3) / 4,
+        NAME_LEN * 2
+    );
+    let mut covered = [0; NAME_LEN];
+    covered[..bytes.len()].copy_from_slice(&bytes);
+    let mut tokens = String::new();
+    for device in fs::read_to_string(devices)?.lines() {
+        let device = device.trim();
+        if device.is_empty() {
+            continue;
+        }
+        let meta = GdpMeta {
+            pub_key: parse_key(device)?,
+        };
+        ensure!(
+            has_prefix(&meta.hash(), &covered, prefix_bits),
+            "the name of device {} is not under the prefix",
+            device
+        );
+        let token = FleetToken::issue(meta, covered, prefix_bits, valid_for, operator_key)?;
+        tokens.push_str(&format!("{} {}\n", device, token.to_hex()?));
+    }
+    Ok(tokens)
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    decode_hex(hex)?
+        .try_into()
+        .map_err(|_| anyhow!("key {} is not 32 bytes", hex))
+}
+
+/// Which registrations a node accepts, beyond their being signed by the owner of the name
+#[derive(Default)]
+pub struct RegistrationPolicy {
+    /// The operators whose fleet tokens are accepted
+    pub operators: Vec<GdpMeta>,
+    /// Refuse registrations that no accepted fleet token covers
+    pub require_token: bool,
+}
+
+#[derive(Default)]
+pub struct RegistrationConfig {
+    pub policy: RegistrationPolicy,
+    /// The token this node registers with
+    pub token: Option<FleetToken>,
+}
+
+#[derive(Deserialize)]
+struct SerializedRegistrationConfig {
+    /// Public keys, in hex
+    #[serde(default)]
+    operators: Vec<String>,
+    #[serde(default)]
+    require_token: bool,
+    token: Option<String>,
+}
+
+pub fn load_registration_config() -> Result<RegistrationConfig> {
+    parse_registration_config(&fs::read_to_string("registration.toml")?)
+}
+
+/// The registration.toml of this node, or the defaults if it has none. A file that is there
+/// but cannot be loaded is an error, so that a typo does not quietly stop requiring tokens
+pub fn registration_config() -> Result<RegistrationConfig> {
+    if !Path::new("registration.toml").exists() {
+        return Ok(RegistrationConfig::default());
+    }
+    load_registration_config().context("bad registration.toml")
+}
+
+/// The registrations that `nic_name` accepts. If registration.toml cannot be loaded, it refuses
+/// them all rather than fall back to accepting them without tokens
+pub fn registration_policy(nic_name: &str) -> RegistrationPolicy {
+    registration_config()
+        .map(|config| config.policy)
+        .unwrap_or_else(|err| {
+            println!("{} refuses every registration: {:#}", nic_name, err);
+            RegistrationPolicy {
+                operators: Vec::new(),
+                require_token: true,
+            }
+        })
+}
+
+fn parse_registration_config(content: &str) -> Result<RegistrationConfig> {
+    let config: SerializedRegistrationConfig = toml::from_str(content)?;
+    let operators = config
+        .operators
+        .iter()
+        .map(|operator| {
+            Ok(GdpMeta {
+                pub_key: parse_key(operator)?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(RegistrationConfig {
+        policy: RegistrationPolicy {
+            operators,
+            require_token: config.require_token,
+        },
+        token: config
+            .token
+            .map(|token| FleetToken::from_hex(&token))
+            .transpose()
+            .context("bad fleet token")?,
+    })
+}
+
+/// Signing and checking registrations, whose layout gdp-proto defines
+trait SignedRegistration: Sized {
+    fn new(
+        meta: GdpMeta,
+        certs: Vec<Certificate>,
+        phase: RegistrationPhase,
+        private_key: [u8; 32],
+    ) -> Result<Self>;
+
+    fn verify(
+        &self,
+        token: Option<&FleetToken>,
+        gdp_name: GdpName,
+        policy: &RegistrationPolicy,
+        now: u64,
+    ) -> Result<()>;
+}
+
+impl SignedRegistration for Registration {
+    fn new(
+        meta: GdpMeta,
+        certs: Vec<Certificate>,
+        phase: RegistrationPhase,
+        private_key: [u8; 32],
+    ) -> Result<Self> {
+        let contents = RegistrationContents {
+            meta,
+            certs,
+            phase,
+            signed_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        let signature = sign_data(&contents, private_key)?;
+        Ok(Registration {
+            contents,
+            signature,
+        })
+    }
+
+    /// Check that the registration was signed by the owner of `gdp_name`, the source of its
+    /// packet, around `now`, and that `policy` accepts it with the fleet token it came with
+    fn verify(
+        &self,
+        token: Option<&FleetToken>,
+        gdp_name: GdpName,
+        policy: &RegistrationPolicy,
+        now: u64,
+    ) -> Result<()> {
+        let contents = &self.contents;
+        check_owner(gdp_name, &contents.meta).context("registration is not from its sender")?;
+        verify_data(contents, self.signature, &contents.meta)?;
+        ensure!(
+            now.abs_diff(contents.signed_at) <= MAX_REGISTRATION_AGE.as_secs(),
+            "registration from {:?} was signed at {}, too far from now ({})",
+            gdp_name,
+            contents.signed_at,
+            now
+        );
+        for cert in &contents.certs {
+            ensure!(
+                *cert.contents.owner() == gdp_name,
+                "registration from {:?} carries a certificate for {:?}",
+                gdp_name,
+                cert.contents.owner()
+            );
+            cert.verify(&contents.meta)?;
+        }
+        if policy.require_token {
+            let token = token
+                .ok_or_else(|| anyhow!("registration from {:?} has no fleet token", gdp_name))?;
+            token.verify(&contents.meta, gdp_name, policy, now)?;
+        }
+        Ok(())
+    }
+}
+
+/// A registration, and the fleet token after it if the sender has one
+fn parse_registration(mut payload: &[u8]) -> Result<(Registration, Option<FleetToken>)> {
+    let registration = bincode::deserialize_from(&mut payload)?;
+    let token = if payload.is_empty() {
+        None
+    } else {
+        Some(bincode::deserialize(payload).context("bad fleet token")?)
+    };
+    Ok((registration, token))
+}
+
+fn now_secs() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Register `meta`'s name with the RIB at `dst_ip` (through the switch there, if it is one)
+pub fn send_registration(
+    q: PortQueue,
+    src: PortIdentity,
+    meta: GdpMeta,
+    certs: Vec<Certificate>,
+    phase: RegistrationPhase,
+    token: Option<FleetToken>,
+    private_key: [u8; 32],
+    dst_ip: Ipv4Addr,
+) -> Result<()> {
+    let registration = Registration::new(meta, certs, phase, private_key)?;
+    let mut registration = bincode::serialize(&registration)?;
+    if let Some(token) = token {
+        registration.extend(bincode::serialize(&token)?);
+    }
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            create_control_request(
+                packet,
+                GdpAction::RibRegister,
+                &registration,
+                src.mac,
+                src.ip,
+                meta.hash(),
+                dst_ip,
+            )
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q))
+        .run_once();
+    Ok(())
+}
+
+/// Register this node's name with the RIB at `dst_ip` (through the switch there, if it is one),
+/// logging rather than failing if the registration cannot be made
+pub fn register_node(
+    q: PortQueue,
+    src: PortIdentity,
+    meta: GdpMeta,
+    certs: Vec<Certificate>,
+    private_key: [u8; 32],
+    dst_ip: Ipv4Addr,
+    nic_name: &str,
+) {
+    println!("Registering {} with the RIB", nic_name);
+    let sent = registration_config().and_then(|config| {
+        send_registration(
+            q,
+            src,
+            meta,
+            certs,
+            RegistrationPhase::Active,
+            config.token,
+            private_key,
+            dst_ip,
+        )
+    });
+    if let Err(err) = sent {
+        println!("{} failed to register with the RIB: {:#}", nic_name, err);
+    }
+}
+
+/// Learn the routes in a registration on its way to the RIB
+pub fn intercept_registration(
+    packet: &Gdp<DTls<Ipv4>>,
+    store: Store,
+    policy: &RegistrationPolicy,
+    debug: bool,
+) -> Result<()> {
+    let (registration, token) = parse_registration(get_payload(packet)?)?;
+    registration.verify(token.as_ref(), packet.src(), policy, now_secs()?)?;
+    let contents = registration.contents;
+    let gdp_name = contents.meta.hash();
+    store.transaction(|| match contents.phase {
+        RegistrationPhase::Active => {
+            process_rib_data(&[contents.meta], &contents.certs, None, None, store, debug)
+        }
+        RegistrationPhase::Pending => {
+            store.gdp_metadata.put(gdp_name, contents.meta);
+            record_migrations(&contents.certs, store, debug)
+        }
+        RegistrationPhase::Withdraw => {
+            complete_migration(gdp_name, store, debug);
+            Ok(())
+        }
+    })
+}
+
+/// Record the name and routes of a registration in the RIB
+pub fn handle_registration(
+    packet: &Gdp<DTls<Ipv4>>,
+    routes: &Routes,
+    policy: &RegistrationPolicy,
+    debug: bool,
+) -> Result<()> {
+    let (registration, token) = parse_registration(get_payload(packet)?)?;
+    if let Err(err) = registration.verify(token.as_ref(), packet.src(), policy, now_secs()?) {
+        println!("RIB refused registration: {:#}", err);
+        return Err(err);
+    }
+    let contents = registration.contents;
+    if debug {
+        println!(
+            "RIB registering {:?} ({:?}) with {} certificates",
+            packet.src(),
+            contents.phase,
+            contents.certs.len()
+        );
+    }
+    let gdp_name = contents.meta.hash();
+    let mut dynamic_routes = routes.dynamic_routes.write().recover();
+    dynamic_routes.metadata.insert(gdp_name, contents.meta);
+    match contents.phase {
+        RegistrationPhase::Active => {
+            for cert in contents.certs {
+                insert_cert(cert, &mut dynamic_routes)?;
+            }
+            Ok(())
+        }
+        RegistrationPhase::Pending => {
+            let deadline = SystemTime::now().duration_since(UNIX_EPOCH)? + MIGRATION_WINDOW;
+            dynamic_routes.begin_migration(contents.meta, contents.certs, deadline.as_secs())
+        }
+        RegistrationPhase::Withdraw => dynamic_routes.complete_migration(gdp_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificates::{CertDest, NewRtCert, RtCert};
+    use crate::hardcoded_routes::{metadata_of_index, private_key_of_index};
+
+    const NOW: u64 = 1_600_000_000;
+    const OPERATOR: u8 = 3;
+
+    fn registration(index: u8, certs: Vec<Certificate>) -> Registration {
+        let meta = metadata_of_index(index);
+        let mut registration = Registration::new(
+            meta,
+            certs,
+            RegistrationPhase::Active,
+            private_key_of_index(index),
+        )
+        .unwrap();
+        registration.contents.signed_at = NOW;
+        registration.signature =
+            sign_data(&registration.contents, private_key_of_index(index)).unwrap();
+        registration
+    }
+
+    fn route_of(index: u8) -> Certificate {
+        RtCert::new_wrapped(
+            metadata_of_index(index),
+            private_key_of_index(index),
+            CertDest::IpAddr(Ipv4Addr::new(10, 0, 0, index)),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// A token from `operator` for the key of `device`, covering the first 8 bits of `prefix`
+    fn token(operator: u8, device: u8, prefix: GdpName) -> FleetToken {
+        let mut token = FleetToken::issue(
+            metadata_of_index(device),
+            prefix,
+            8,
+            Duration::from_secs(60),
+            private_key_of_index(operator),
+        )
+        .unwrap();
+        token.contents.expiration_time = NOW + 60;
+        token.signature = sign_data(&token.contents, private_key_of_index(operator)).unwrap();
+        token
+    }
+
+    fn token_for_wire() -> FleetToken {
+        token(OPERATOR, 1, metadata_of_index(1).hash())
+    }
+
+    fn bytes<T: Serialize>(value: &T) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn requiring_tokens() -> RegistrationPolicy {
+        RegistrationPolicy {
+            operators: vec![metadata_of_index(OPERATOR)],
+            require_token: true,
+        }
+    }
+
+    #[test]
+    fn accepts_the_owner_registering() {
+        let registration = registration(1, vec![route_of(1)]);
+        let name = metadata_of_index(1).hash();
+        assert!(registration
+            .verify(None, name, &RegistrationPolicy::default(), NOW)
+            .is_ok());
+    }
+
+    #[test]
+    fn refuses_registrations_for_someone_else() {
+        let registration = registration(1, vec![route_of(1)]);
+        let other = metadata_of_index(2).hash();
+        assert!(registration
+            .verify(None, other, &RegistrationPolicy::default(), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_tampered_registrations() {
+        let mut registration = registration(1, vec![]);
+        registration.contents.certs.push(route_of(1));
+        let name = metadata_of_index(1).hash();
+        assert!(registration
+            .verify(None, name, &RegistrationPolicy::default(), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_registrations_signed_too_far_from_now() {
+        let registration = registration(1, vec![route_of(1)]);
+        let name = metadata_of_index(1).hash();
+        let policy = RegistrationPolicy::default();
+        let max_age = MAX_REGISTRATION_AGE.as_secs();
+        assert!(registration
+            .verify(None, name, &policy, NOW + max_age)
+            .is_ok());
+        assert!(registration
+            .verify(None, name, &policy, NOW + max_age + 1)
+            .is_err());
+        assert!(registration
+            .verify(None, name, &policy, NOW - max_age - 1)
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_certificates_of_other_names() {
+        let registration = registration(1, vec![route_of(2)]);
+        let name = metadata_of_index(1).hash();
+        assert!(registration
+            .verify(None, name, &RegistrationPolicy::default(), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn requires_a_trusted_token_covering_the_name() {
+        let name = metadata_of_index(1).hash();
+        let policy = requiring_tokens();
+        let registration = registration(1, vec![]);
+        assert!(registration.verify(None, name, &policy, NOW).is_err());
+        let valid = token(OPERATOR, 1, name);
+        assert!(registration
+            .verify(Some(&valid), name, &policy, NOW)
+            .is_ok());
+        // from an operator that is not trusted
+        assert!(registration
+            .verify(Some(&token(2, 1, name)), name, &policy, NOW)
+            .is_err());
+        // for another prefix
+        let mut other = name;
+        other[0] ^= 0xff;
+        assert!(registration
+            .verify(Some(&token(OPERATOR, 1, other)), name, &policy, NOW)
+            .is_err());
+        // once expired
+        assert!(registration
+            .verify(Some(&valid), name, &policy, NOW + 61)
+            .is_err());
+    }
+
+    #[test]
+    fn tokens_are_of_no_use_to_other_keys() {
+        let policy = requiring_tokens();
+        // device 2's name is under the prefix of device 1's token
+        let name = metadata_of_index(2).hash();
+        let copied = token(OPERATOR, 1, name);
+        assert!(registration(2, vec![])
+            .verify(Some(&copied), name, &policy, NOW)
+            .is_err());
+        // nor can the device swap the key it was issued to
+        let mut forged = token(OPERATOR, 1, name);
+        forged.contents.device = metadata_of_index(2);
+        assert!(registration(2, vec![])
+            .verify(Some(&forged), name, &policy, NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn prefixes_cover_their_bits_only() {
+        let prefix = [0b1010_0000; NAME_LEN];
+        let mut name = [0; NAME_LEN];
+        name[0] = 0b1011_1111;
+        assert!(has_prefix(&name, &prefix, 0));
+        assert!(has_prefix(&name, &prefix, 3));
+        assert!(!has_prefix(&name, &prefix, 4));
+        assert!(has_prefix(&prefix, &prefix, u16::MAX));
+    }
+
+    #[test]
+    fn tokens_follow_registrations_on_the_wire() {
+        let registration = registration(1, vec![route_of(1)]);
+        let mut payload = bincode::serialize(&registration).unwrap();
+        let (_, token) = parse_registration(&payload).unwrap();
+        assert!(token.is_none());
+
+        let sent = token_for_wire();
+        payload.extend(bincode::serialize(&sent).unwrap());
+        let (parsed, token) = parse_registration(&payload).unwrap();
+        assert_eq!(bytes(&parsed), bytes(&registration));
+        assert_eq!(bytes(&token.unwrap()), bytes(&sent));
+        // nodes from before fleet tokens take the registration alone
+        let old: Registration = bincode::deserialize(&payload).unwrap();
+        assert_eq!(bytes(&old), bytes(&registration));
+
+        payload.push(0);
+        assert!(parse_registration(&payload).is_err());
+    }
+
+    #[test]
+    fn tokens_survive_hex() {
+        let token = token_for_wire();
+        let parsed = FleetToken::from_hex(&token.to_hex().unwrap()).unwrap();
+        assert_eq!(bytes(&parsed), bytes(&token));
+    }
+
+    #[test]
+    fn loads_operators_and_tokens() {
+        let operator = metadata_of_index(OPERATOR).pub_key;
+        let operator: String = operator
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let token = token_for_wire().to_hex().unwrap();
+        let config = parse_registration_config(&format!(
+            "operators = [\"{}\"]\nrequire_token = true\ntoken = \"{}\"\n",
+            operator, token
+        ))
+        .unwrap();
+        assert!(config.policy.require_token);
+        assert_eq!(
+            config.policy.operators[0].pub_key,
+            metadata_of_index(OPERATOR).pub_key
+        );
+        assert!(config.token.is_some());
+
+        assert!(parse_registration_config("operators = [\"abcd\"]").is_err());
+        assert!(parse_registration_config("token = \"abcd\"").is_err());
+        assert!(parse_registration_config("require_token = \"yes\"").is_err());
+    }
+}
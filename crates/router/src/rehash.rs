@@ -0,0 +1,171 @@
+use std::collections::hash_map::{self, HashMap};
+use std::hash::Hash;
+use std::iter::Chain;
+use std::mem;
+
+/*
+   The store's tables grow during route floods, and a HashMap that grows rehashes all of its
+   entries at once: on the packet path for a core's own writes, and in the updater while cores
+   wait on the generation lock. An IncrementalMap grows a few entries at a time instead:
+   - entries live in one table until it is full. It then becomes the old table, and a new one
+     twice its size takes the writes; every write also moves MIGRATE_STEP entries across from the
+     old one, and the updater moves more between writes (`migrate`)
+   - a key is in one of the two tables, never both; lookups try the new table, then the old one
+   - each table logs its keys as they go in, so the migration knows what to move next without
+     scanning the old table. Keys removed since are skipped when their turn comes. A table whose
+     log is mostly such keys is moved into a fresh one of the same size, the same way, which keeps
+     the log within twice the table's capacity
+   The new table has room for every entry of the old one, and the migration is over before it
+   could fill up, so no write moves more than MIGRATE_STEP entries. Tables below MIN_CAPACITY
+   grow the ordinary way, as rehashing them is cheap.
+*/
+
+/// Entries moved from the old table with every write
+const MIGRATE_STEP: usize = 8;
+const MIN_CAPACITY: usize = 64;
+
+#[derive(Clone)]
+pub struct IncrementalMap<K, V> {
+    table: HashMap<K, V>,
+    /// Every key inserted into `table`, including some that were removed since
+    log: Vec<K>,
+    /// Empty unless a migration is under way
+    old: HashMap<K, V>,
+    /// The keys of `old` that are still to move, taken from the back
+    to_move: Vec<K>,
+}
+
+impl<K, V> Default for IncrementalMap<K, V> {
+    fn default() -> Self {
+        IncrementalMap {
+            table: HashMap::new(),
+            log: Vec::new(),
+            old: HashMap::new(),
+            to_move: Vec::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Copy, V> IncrementalMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        match self.table.get(k) {
+            Some(v) => Some(v),
+            None if !self.old.is_empty() => self.old.get(k),
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.migrate(MIGRATE_STEP);
+        if let Some(existing) = self.table.get_mut(&k) {
+            return Some(mem::replace(existing, v));
+        }
+        // left in `to_move`, where it is skipped
+        let previous = if self.old.is_empty() {
+            None
+        } else {
+            self.old.remove(&k)
+        };
+        self.make_room();
+        self.log.push(k);
+        self.table.insert(k, v);
+        previous
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.migrate(MIGRATE_STEP);
+        match self.table.remove(k) {
+            Some(v) => Some(v),
+            None if !self.old.is_empty() => self.old.remove(k),
+            None => None,
+        }
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.table.retain(&mut f);
+        self.old.retain(f);
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len() + self.old.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.table.iter().chain(self.old.iter())
+    }
+
+    /// Whether entries are still to move from the old table
+    pub fn is_migrating(&self) -> bool {
+        !self.to_move.is_empty()
+    }
+
+    /// Move up to `budget` keys across from the old table, for the updater to finish migrations
+    /// between writes
+    pub fn migrate(&mut self, budget: usize) {
+        if self.to_move.is_empty() {
+            return;
+        }
+        for _ in 0..budget {
+            let k = match self.to_move.pop() {
+                Some(k) => k,
+                None => break,
+            };
+            if let Some(v) = self.old.remove(&k) {
+                self.table.insert(k, v);
+                self.log.push(k);
+            }
+        }
+        if self.to_move.is_empty() {
+            // every key of the old table was logged, so it is empty by now; free it
+            self.old = HashMap::new();
+            self.to_move = Vec::new();
+        }
+    }
+
+    /// Start a migration if the table cannot take another key without rehashing, or its log
+    /// has grown too long
+    fn make_room(&mut self) {
+        // what the table takes before it rehashes, which counts the slots that removals left
+        // behind: a table full of them would rehash in place
+        let capacity = self.table.capacity();
+        let full = self.table.len() == capacity;
+        let stale = self.log.len() >= 2 * capacity;
+        if !(full || stale) {
+            return;
+        }
+        if capacity < MIN_CAPACITY {
+            // grows the ordinary way; only the log needs keeping short
+            if stale {
+                self.log.clear();
+                self.log.extend(self.table.keys());
+            }
+            return;
+        }
+        if self.is_migrating() {
+            // a migration ends before the table it fills can fill up (see above), so one under
+            // way means that the log grew long while entries moved in. It is cut short once the
+            // migration is over, rather than finishing the migration all at once
+            return;
+        }
+        let capacity = if 2 * self.table.len() > capacity {
+            2 * capacity
+        } else {
+            capacity
+        };
+        self.old = mem::replace(&mut self.table, HashMap::with_capacity(capacity));
+        self.to_move = mem::replace(&mut self.log, Vec::with_capacity(capacity));
+    }
+}
+
+impl<K, V> IntoIterator for IncrementalMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = Chain<hash_map::IntoIter<K, V>, hash_map::IntoIter<K, V>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.table.into_iter().chain(self.old.into_iter())
+    }
+}
@@ -1,37 +1,137 @@
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use capsule::batch::{self, Batch, Pipeline};
+use arc_swap::ArcSwap;
+use capsule::batch::{self, Batch, Either, Pipeline};
 use capsule::net::MacAddr;
 use capsule::packets::ip::v4::Ipv4;
 use capsule::packets::{Ethernet, Packet, Udp};
 use capsule::{Mbuf, PortQueue};
-use gdp_client::{GdpAction, GdpName};
+use gdp_proto::{GdpAction, GdpName};
 use serde::Deserialize;
+use tokio_timer::delay_for;
 
+use crate::blocklist::verify_content_or_report;
+use crate::capabilities::heartbeat_reply;
 use crate::certificates::{Certificate, GdpMeta};
+use crate::chaos::chaos;
+use crate::clock::handle_time_query;
+use crate::discovery::handle_rib_search;
 use crate::dtls::{encrypt_gdp, DTls};
+use crate::flags::FeatureFlags;
 use crate::gdp::Gdp;
-use crate::hardcoded_routes::WithBroadcast;
+use crate::hardcoded_routes::{gdp_name_of_index, private_key_of_index, WithBroadcast, RIB_INDEX};
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
 use crate::kvs::Store;
+use crate::l2filter::VlanTx;
 use crate::packet_ops::get_payload;
-use crate::ribpayload::{generate_rib_response, process_rib_response, RibQuery, RibResponse};
+use crate::registration::{handle_registration, registration_policy, RegistrationPolicy};
+use crate::ribpayload::{
+    advertised_negative_ttl, advertised_route_ttl, insert_cert, process_rib_response, RibQuery,
+    RibResponse,
+};
+use crate::ribreplies::RibReplies;
+use crate::ribsync::{Replica, RibSync};
+use crate::schedule::Schedule;
+use crate::scratch::with_serialized;
 use crate::{pipeline, GdpPipeline};
 
 pub const RIB_PORT: u16 = 31415;
 
+/*
+   Switches hear about changes to the routes they cached without waiting for them to expire:
+   - a switch asking for the next hop of a name subscribes to it, until the route it is given
+     expires (which is when it would have asked again anyway)
+   - when the RIB records a route that moves a subscribed name, or drops one that expired, the
+     change is queued for each subscriber and pushed within PUSH_INTERVAL, as an unsolicited
+     RibReply addressed to the subscriber's GdpName
+   - withdrawn names are removed from the subscriber's cache and negatively cached, like misses
+   - names that are moving hosts (see registration.rs) are pushed with where they are moving to,
+     and pushed again once they have moved
+*/
+
+/// Changes to subscribed routes are pushed at most this long after the RIB records them
+const PUSH_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct Routes {
+    /// The routes of the routes file, swapped whole when it is reloaded (see hardcoded_routes.rs)
+    statics: ArcSwap<StaticRoutes>,
+    pub dynamic_routes: RwLock<DynamicRoutes>,
+}
+
+/// The routes that a node is configured with, rather than learns
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StaticRoutes {
     pub rib: Route,
     pub default: Route,
-    pub dynamic_routes: RwLock<DynamicRoutes>,
+    /// Node that answers time synchronization queries (the RIB unless configured otherwise)
+    pub time_master: Route,
+}
+
+impl Routes {
+    pub fn new(statics: StaticRoutes) -> Self {
+        Routes {
+            statics: ArcSwap::from_pointee(statics),
+            dynamic_routes: RwLock::new(DynamicRoutes::new()),
+        }
+    }
+
+    /// Routes that all lead to the RIB at `rib_ip`, and are never reloaded
+    pub fn fixed(rib_ip: Ipv4Addr) -> Self {
+        let route = Route { ip: rib_ip };
+        Self::new(StaticRoutes {
+            rib: route,
+            default: route,
+            time_master: route,
+        })
+    }
+
+    /// The static routes as they are now
+    pub fn statics(&self) -> StaticRoutes {
+        **self.statics.load()
+    }
+
+    pub fn rib(&self) -> Route {
+        self.statics().rib
+    }
+
+    pub fn default(&self) -> Route {
+        self.statics().default
+    }
+
+    pub fn time_master(&self) -> Route {
+        self.statics().time_master
+    }
+
+    /// Have every core use `statics` from now on. The routes replaced are freed once no core
+    /// reads them any more
+    pub fn swap_statics(&self, statics: StaticRoutes) {
+        self.statics.store(Arc::new(statics));
+    }
 }
 
 pub struct DynamicRoutes {
     pub locations: HashMap<GdpName, Certificate>,
     pub next_hop: HashMap<GdpName, Certificate>,
     pub metadata: HashMap<GdpName, GdpMeta>,
+    /// AttrCerts advertising the tags of each GdpName, for service discovery
+    pub attributes: HashMap<GdpName, Certificate>,
+    /// Names registered as pending at the host they are moving to
+    pub migrations: HashMap<GdpName, Migration>,
+    pub subscriptions: Subscriptions,
+    /// The changes replicated with the RIB's peers (see ribsync.rs)
+    pub replica: Replica,
+}
+
+/// Where a name is moving to, and until when it may take to get there
+pub struct Migration {
+    pub certs: Vec<Certificate>,
+    /// Seconds since the Unix epoch
+    pub deadline: u64,
 }
 
 impl DynamicRoutes {
@@ -40,11 +140,175 @@ impl DynamicRoutes {
             locations: HashMap::new(),
             next_hop: HashMap::new(),
             metadata: HashMap::new(),
+            attributes: HashMap::new(),
+            migrations: HashMap::new(),
+            subscriptions: Subscriptions::default(),
+            replica: Replica::default(),
+        }
+    }
+
+    /// Start moving `meta`'s name to where `certs` locate it, once its current registration is
+    /// withdrawn or `deadline` passes. A name with no location yet has nothing to move from, so
+    /// it is located there at once.
+    pub fn begin_migration(
+        &mut self,
+        meta: GdpMeta,
+        certs: Vec<Certificate>,
+        deadline: u64,
+    ) -> Result<()> {
+        let gdp_name = meta.hash();
+        if !self.locations.contains_key(&gdp_name) {
+            for cert in certs {
+                insert_cert(cert, self)?;
+            }
+            return Ok(());
+        }
+        for cert in &certs {
+            self.subscriptions.migrating(meta, cert.clone());
+        }
+        self.migrations
+            .insert(gdp_name, Migration { certs, deadline });
+        Ok(())
+    }
+
+    /// The current registration of a name was withdrawn: move it to where it was moving, or
+    /// withdraw the name if it was not moving
+    pub fn complete_migration(&mut self, gdp_name: GdpName) -> Result<()> {
+        if let Some(migration) = self.migrations.remove(&gdp_name) {
+            for cert in migration.certs {
+                insert_cert(cert, self)?;
+            }
+            return Ok(());
+        }
+        self.withdraw(gdp_name);
+        self.replica.record(gdp_name, None);
+        Ok(())
+    }
+
+    /// Drop the route of a name, telling its subscribers
+    pub fn withdraw(&mut self, gdp_name: GdpName) {
+        self.locations.remove(&gdp_name);
+        self.next_hop.remove(&gdp_name);
+        self.subscriptions.withdrawn(gdp_name);
+    }
+
+    /// Move the names whose old registration was not withdrawn by their deadline
+    fn complete_overdue_migrations(&mut self, now: u64) {
+        let overdue: Vec<GdpName> = self
+            .migrations
+            .iter()
+            .filter(|(_, migration)| migration.deadline <= now)
+            .map(|(gdp_name, _)| *gdp_name)
+            .collect();
+        for gdp_name in overdue {
+            if let Err(err) = self.complete_migration(gdp_name) {
+                println!("RIB failed to move {:?}: {:#}", gdp_name, err);
+            }
+        }
+    }
+
+    /// Drop the routes that expired by `now`, withdrawing names that are left without one
+    fn withdraw_expired(&mut self, now: u64) {
+        let mut expired = Vec::new();
+        for table in [&mut self.locations, &mut self.next_hop] {
+            table.retain(|gdp_name, cert| {
+                let live = cert.contents.expiration_time() > now;
+                if !live {
+                    expired.push(*gdp_name);
+                }
+                live
+            });
+        }
+        for gdp_name in expired {
+            if !self.locations.contains_key(&gdp_name) && !self.next_hop.contains_key(&gdp_name) {
+                self.subscriptions.withdrawn(gdp_name);
+            }
         }
     }
 }
 
-#[derive(Clone, Copy, Deserialize)]
+/// A switch that asked to be told about changes to the routes it cached
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Subscriber {
+    pub ip: Ipv4Addr,
+    pub name: GdpName,
+}
+
+#[derive(Default)]
+pub struct Subscriptions {
+    /// The subscribers of each name, with the time until which they cache its route
+    subscribers: HashMap<GdpName, HashMap<Subscriber, u64>>,
+    /// Changes not pushed yet, as the update that each subscriber will be sent
+    pending: HashMap<Subscriber, RibResponse>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, gdp_name: GdpName, subscriber: Subscriber, until: u64) {
+        let until_ref = self
+            .subscribers
+            .entry(gdp_name)
+            .or_default()
+            .entry(subscriber)
+            .or_insert(until);
+        *until_ref = (*until_ref).max(until);
+    }
+
+    fn subscribers_of(&self, gdp_name: &GdpName) -> Vec<Subscriber> {
+        self.subscribers
+            .get(gdp_name)
+            .map(|subscribers| subscribers.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// The route of a name moved, as recorded in `cert`
+    pub fn changed(&mut self, meta: GdpMeta, cert: Certificate) {
+        for subscriber in self.subscribers_of(cert.contents.owner()) {
+            let update = self.pending.entry(subscriber).or_default();
+            update.metas.push(meta);
+            update.certs.push(cert.clone());
+        }
+    }
+
+    /// The name that `cert` locates is moving there, and should be delivered there as well
+    fn migrating(&mut self, meta: GdpMeta, cert: Certificate) {
+        for subscriber in self.subscribers_of(cert.contents.owner()) {
+            let update = self.pending.entry(subscriber).or_default();
+            update.metas.push(meta);
+            update.migrating.push(cert.clone());
+        }
+    }
+
+    /// A name no longer has a route, so there is nothing left to subscribe to
+    fn withdrawn(&mut self, gdp_name: GdpName) {
+        for subscriber in self.subscribers_of(&gdp_name) {
+            self.pending
+                .entry(subscriber)
+                .or_default()
+                .withdrawn
+                .push(gdp_name);
+        }
+        self.subscribers.remove(&gdp_name);
+    }
+
+    /// The updates to push to each subscriber, forgetting subscriptions that lapsed by `now`
+    fn take_pending(&mut self, now: u64) -> Vec<(Subscriber, RibResponse)> {
+        for subscribers in self.subscribers.values_mut() {
+            subscribers.retain(|_, until| *until > now);
+        }
+        self.subscribers
+            .retain(|_, subscribers| !subscribers.is_empty());
+        self.pending
+            .drain()
+            .map(|(subscriber, mut update)| {
+                update.route_ttl = advertised_route_ttl();
+                update.negative_ttl = advertised_negative_ttl();
+                (subscriber, update)
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
 pub struct Route {
     pub ip: Ipv4Addr,
 }
@@ -56,6 +320,28 @@ pub fn create_rib_request(
     src_ip: Ipv4Addr,
     src_gdp_name: GdpName,
     dst_ip: Ipv4Addr,
+) -> Result<Gdp<DTls<Ipv4>>> {
+    let content = bincode::serialize(query)?;
+    create_control_request(
+        message,
+        GdpAction::RibGet,
+        &content,
+        src_mac,
+        src_ip,
+        src_gdp_name,
+        dst_ip,
+    )
+}
+
+/// Build a GDP packet carrying `content`, addressed to a control-plane node such as the RIB
+pub fn create_control_request(
+    message: Mbuf,
+    action: GdpAction,
+    content: &[u8],
+    src_mac: MacAddr,
+    src_ip: Ipv4Addr,
+    src_gdp_name: GdpName,
+    dst_ip: Ipv4Addr,
 ) -> Result<Gdp<DTls<Ipv4>>> {
     let mut message = message.push::<Ethernet>()?;
     message.set_src(src_mac);
@@ -73,57 +359,79 @@ pub fn create_rib_request(
 
     let mut message = message.push::<Gdp<DTls<Ipv4>>>()?;
 
-    message.set_action(GdpAction::RibGet);
+    message.set_action(action);
     message.set_src(src_gdp_name);
 
-    let content = bincode::serialize(query).unwrap();
     let offset = message.payload_offset();
     message.mbuf_mut().extend(offset, content.len())?;
-    message.mbuf_mut().write_data_slice(offset, &content)?;
+    message.mbuf_mut().write_data_slice(offset, content)?;
 
     message.set_data_len(content.len());
+    message.seal_content()?;
 
     message.reconcile_all();
     Ok(message)
 }
 
-pub fn send_rib_query(
+/// Send a single query to the RIB, outside of any pipeline
+pub fn send_rib_request(
     q: PortQueue,
-    src_ip: Ipv4Addr,
+    src: PortIdentity,
     src_gdp_name: GdpName,
     dst_ip: Ipv4Addr,
     query: &RibQuery,
-    nic_name: &str,
 ) {
-    let src_mac = q.mac_addr();
-    println!("Sending initial RIB announcement from {}", nic_name);
     batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
-        .map(move |packet| create_rib_request(packet, query, src_mac, src_ip, src_gdp_name, dst_ip))
+        .map(move |packet| create_rib_request(packet, query, src.mac, src.ip, src_gdp_name, dst_ip))
         .map(|packet| Ok(packet.deparse()))
         .map(encrypt_gdp)
-        .send(q)
+        .send(VlanTx::new(q))
         .run_once();
 }
 
-pub fn handle_rib_reply(packet: &Gdp<DTls<Ipv4>>, store: Store, debug: bool) -> Result<()> {
+/// Apply a RibReply, returning the names whose routes it withdrew
+pub fn handle_rib_reply(
+    packet: &Gdp<DTls<Ipv4>>,
+    store: Store,
+    debug: bool,
+) -> Result<Vec<GdpName>> {
     let data_slice = packet
         .mbuf()
         .read_data_slice(packet.payload_offset(), packet.payload_len())?;
     let data_slice_ref = unsafe { data_slice.as_ref() };
-    let response: RibResponse = bincode::deserialize(data_slice_ref)?;
-    process_rib_response(response, store, debug)?;
-    Ok(())
+    let response = RibResponse::decode(data_slice_ref)?;
+    match chaos().defer_rib_response(response) {
+        Some(response) => {
+            let withdrawn = response.withdrawn.clone();
+            process_rib_response(response, store, debug)?;
+            Ok(withdrawn)
+        }
+        None => Ok(Vec::new()),
+    }
 }
 
+/// Queue a query, to be answered with the others of the moment
 fn handle_rib_query(
     packet: &Gdp<DTls<Ipv4>>,
     _nic_name: &str,
-    routes: &Routes,
+    replies: RibReplies,
     _use_default: bool,
-    debug: bool,
-) -> Result<Gdp<DTls<Ipv4>>> {
+) -> Result<()> {
     let query: RibQuery = bincode::deserialize(get_payload(packet)?)?;
+    let subscriber = Subscriber {
+        ip: packet.envelope().envelope().envelope().src(),
+        name: packet.src(),
+    };
+    replies.queue(subscriber, packet.envelope().envelope().src_port(), query);
+    Ok(())
+}
 
+/// Build a fresh packet answering `packet`, with the addressing at every layer reversed
+pub fn create_reply(
+    packet: &Gdp<DTls<Ipv4>>,
+    action: GdpAction,
+    message: &[u8],
+) -> Result<Gdp<DTls<Ipv4>>> {
     let dtls = packet.envelope();
     let udp = dtls.envelope();
     let ipv4 = udp.envelope();
@@ -147,30 +455,227 @@ fn handle_rib_query(
     let mut out = out.push::<Gdp<DTls<Ipv4>>>()?;
     out.set_src(packet.dst());
     out.set_dst(packet.src());
-    out.set_action(GdpAction::RibReply);
+    out.set_action(action);
 
-    let rib_response = generate_rib_response(query, routes, debug);
-    let message = bincode::serialize(&rib_response)?;
     let offset = out.payload_offset();
     out.mbuf_mut().extend(offset, message.len())?;
-    out.mbuf_mut().write_data_slice(offset, &message)?;
+    out.mbuf_mut().write_data_slice(offset, message)?;
 
     out.set_data_len(message.len());
+    out.seal_content()?;
 
     out.reconcile_all();
     Ok(out)
 }
 
+fn send_rib_push(
+    q: PortQueue,
+    src: PortIdentity,
+    rib_name: GdpName,
+    subscriber: Subscriber,
+    update: &RibResponse,
+) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            let mut packet = with_serialized(update, |message| {
+                create_control_request(
+                    packet,
+                    GdpAction::RibReply,
+                    message,
+                    src.mac,
+                    src.ip,
+                    rib_name,
+                    subscriber.ip,
+                )
+            })?;
+            packet.set_dst(subscriber.name);
+            Ok(packet)
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q))
+        .run_once();
+}
+
+/// Expire routes and push the changes to subscribed switches, in the background of the RIB pipeline
+pub fn subscription_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    routes: &'static Routes,
+    debug: bool,
+) -> impl Pipeline {
+    let rib_name = gdp_name_of_index(RIB_INDEX);
+    Schedule::new("rib_push", async move {
+        loop {
+            let updates = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| {
+                    let mut dynamic_routes = routes.dynamic_routes.write().recover();
+                    dynamic_routes.complete_overdue_migrations(now.as_secs());
+                    dynamic_routes.withdraw_expired(now.as_secs());
+                    dynamic_routes.subscriptions.take_pending(now.as_secs())
+                })
+                .unwrap_or_default();
+            for (subscriber, update) in updates {
+                if debug {
+                    println!(
+                        "RIB pushing {} routes, {} withdrawals and {} migrations to {}",
+                        update.certs.len(),
+                        update.withdrawn.len(),
+                        update.migrating.len(),
+                        subscriber.ip
+                    );
+                }
+                send_rib_push(q.clone(), src, rib_name, subscriber, &update);
+            }
+            delay_for(PUSH_INTERVAL).await;
+        }
+    })
+}
+
 pub fn rib_pipeline(
     nic_name: &'static str,
     routes: &'static Routes,
+    replies: RibReplies,
+    sync: RibSync,
     use_default: bool,
+    flags: FeatureFlags,
     debug: bool,
 ) -> impl GdpPipeline {
+    let private_key = private_key_of_index(RIB_INDEX);
+    let policy: &'static RegistrationPolicy = Box::leak(Box::new(registration_policy(nic_name)));
     pipeline! {
         GdpAction::RibGet => |group| {
-            group.replace(move |packet| handle_rib_query(packet, nic_name, routes, use_default, debug))
+            group
+            .for_each(verify_content_or_report)
+            .for_each(move |packet| {
+                handle_rib_query(packet, nic_name, replies, use_default)
+            })
+            .filter(|_| false)
+        },
+        GdpAction::Heartbeat => |group| {
+            group
+            .filter_map(move |packet| {
+                if replies.handle_heartbeat(&packet, debug)? {
+                    Ok(Either::Keep(packet))
+                } else {
+                    Ok(Either::Drop(packet.reset()))
+                }
+            })
+            .replace(move |packet| heartbeat_reply(packet, flags))
+        },
+        GdpAction::TimeGet => |group| {
+            group.replace(handle_time_query)
+        },
+        GdpAction::RibSearch => |group| {
+            group.replace(move |packet| handle_rib_search(packet, routes, private_key, debug))
+        },
+        GdpAction::RibRegister => |group| {
+            group
+            .for_each(verify_content_or_report)
+            .for_each(move |packet| handle_registration(packet, routes, policy, debug))
+            .filter(|_| false)
+        },
+        GdpAction::RibSync => |group| {
+            group
+            .filter_map(move |packet| match sync.handle_sync(&packet, routes, debug)? {
+                Some(ack) => Ok(Either::Keep(ack)),
+                None => Ok(Either::Drop(packet.reset())),
+            })
+        },
+        GdpAction::RibSyncAck => |group| {
+            group
+            .for_each(move |packet| sync.handle_ack(packet, routes))
+            .filter(|_| false)
         }
         _ => |group| {group.filter(|_| false)}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificates::{CertContents, CertDest, NewRtCert, RtCert};
+    use crate::hardcoded_routes::metadata_of_index;
+
+    const DEADLINE: u64 = 1_600_000_000;
+    const OLD_HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const NEW_HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn at(ip: Ipv4Addr) -> Certificate {
+        RtCert::new_wrapped(
+            metadata_of_index(1),
+            private_key_of_index(1),
+            CertDest::IpAddr(ip),
+            true,
+        )
+        .unwrap()
+    }
+
+    fn routes() -> DynamicRoutes {
+        let mut routes = DynamicRoutes::new();
+        let meta = metadata_of_index(1);
+        routes.metadata.insert(meta.hash(), meta);
+        routes
+    }
+
+    fn location(routes: &DynamicRoutes) -> Option<Ipv4Addr> {
+        match &routes.locations.get(&metadata_of_index(1).hash())?.contents {
+            CertContents::RtCert(RtCert {
+                proxy: CertDest::IpAddr(ip),
+                ..
+            }) => Some(*ip),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn locates_a_new_name_at_once() {
+        let mut routes = routes();
+        let meta = metadata_of_index(1);
+        routes
+            .begin_migration(meta, vec![at(NEW_HOST)], DEADLINE)
+            .unwrap();
+        assert_eq!(location(&routes), Some(NEW_HOST));
+        assert!(routes.migrations.is_empty());
+    }
+
+    #[test]
+    fn moves_a_name_once_its_old_registration_is_withdrawn() {
+        let mut routes = routes();
+        let meta = metadata_of_index(1);
+        insert_cert(at(OLD_HOST), &mut routes).unwrap();
+        routes
+            .begin_migration(meta, vec![at(NEW_HOST)], DEADLINE)
+            .unwrap();
+        assert_eq!(location(&routes), Some(OLD_HOST));
+
+        routes.complete_migration(meta.hash()).unwrap();
+        assert_eq!(location(&routes), Some(NEW_HOST));
+        assert!(routes.migrations.is_empty());
+    }
+
+    #[test]
+    fn withdraws_a_name_that_is_not_moving() {
+        let mut routes = routes();
+        insert_cert(at(OLD_HOST), &mut routes).unwrap();
+        routes
+            .complete_migration(metadata_of_index(1).hash())
+            .unwrap();
+        assert_eq!(location(&routes), None);
+    }
+
+    #[test]
+    fn moves_names_whose_old_host_never_withdrew_at_their_deadline() {
+        let mut routes = routes();
+        let meta = metadata_of_index(1);
+        insert_cert(at(OLD_HOST), &mut routes).unwrap();
+        routes
+            .begin_migration(meta, vec![at(NEW_HOST)], DEADLINE)
+            .unwrap();
+        routes.complete_overdue_migrations(DEADLINE - 1);
+        assert_eq!(location(&routes), Some(OLD_HOST));
+        routes.complete_overdue_migrations(DEADLINE);
+        assert_eq!(location(&routes), Some(NEW_HOST));
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+
+#[cfg(not(feature = "rib-sled"))]
+use anyhow::anyhow;
+use anyhow::{ensure, Context, Result};
+use gdp_proto::GdpName;
+use serde::Deserialize;
+
+use crate::certificates::{Certificate, GdpMeta};
+use crate::isolation::Recover;
+use crate::rib::Routes;
+
+/*
+   The RIB keeps what it has learned across restarts, in the storage backend of rib_storage.toml:
+   - `snapshot` (the default) is a database file, rib.db, holding a bincode snapshot of the
+     tables. Each snapshot is written next to the database and renamed over it, so a crash while
+     saving leaves the last complete one
+   - `sled` is an embedded database (built with the rib-sled feature), with a tree per table.
+     Saves only write the entries that changed, and sled recovers its own log when it is opened
+   - `memory` keeps nothing: the RIB starts empty every time
+   - the locations (registrations), next hops (delegations), metadata and attributes are saved
+     every SAVE_INTERVAL and when the RIB shuts down, and loaded before the pipelines start,
+     without the routes and attributes that expired while the RIB was down. Missing storage is
+     an empty RIB
+   - a snapshot file is also the dump format: `gdp-rib export` writes the tables of the
+     configured backend to one, and `gdp-rib import` replaces them with one, so a RIB moves
+     between backends by exporting, changing rib_storage.toml and importing
+   - subscriptions are not saved: switches subscribe again with their next query
+*/
+
+/// Routes learned since the last save are lost if the RIB crashes; a clean shutdown saves them all
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Bumped whenever the layout of the tables changes, so that old databases are not misread
+const DB_VERSION: u32 = 1;
+const DEFAULT_DB_FILE: &str = "rib.db";
+const DEFAULT_SLED_DIR: &str = "rib.sled";
+
+pub type Tables = (
+    HashMap<GdpName, Certificate>,
+    HashMap<GdpName, Certificate>,
+    HashMap<GdpName, GdpMeta>,
+    HashMap<GdpName, Certificate>,
+);
+
+/// The tables as they are saved, borrowed from the live RIB or from loaded tables
+pub type TableRefs<'a> = (
+    &'a HashMap<GdpName, Certificate>,
+    &'a HashMap<GdpName, Certificate>,
+    &'a HashMap<GdpName, GdpMeta>,
+    &'a HashMap<GdpName, Certificate>,
+);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Memory,
+    Snapshot,
+    Sled,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Snapshot
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct RibStorageConfig {
+    #[serde(default)]
+    pub backend: Backend,
+    /// Where the backend keeps the tables. Defaults to $GDP_RIB_DB, or else rib.db for
+    /// snapshots and rib.sled for sled
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+pub fn load_rib_storage_config() -> Result<RibStorageConfig> {
+    let content = fs::read_to_string("rib_storage.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// Where the RIB's tables are kept between runs
+pub trait RibStorage: Send + Sync {
+    /// The tables as last saved, or empty tables if nothing was
+    fn load(&self) -> Result<Tables>;
+
+    /// Replace what is saved with `tables`
+    fn save(&self, tables: TableRefs) -> Result<()>;
+
+    /// For logs
+    fn describe(&self) -> String;
+}
+
+/// Keeps nothing across restarts
+pub struct MemoryStorage;
+
+impl RibStorage for MemoryStorage {
+    fn load(&self) -> Result<Tables> {
+        Ok(Tables::default())
+    }
+
+    fn save(&self, _: TableRefs) -> Result<()> {
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        "memory".to_owned()
+    }
+}
+
+/// A bincode snapshot of the tables in one file, replaced whole on every save
+pub struct SnapshotStorage {
+    path: PathBuf,
+}
+
+impl SnapshotStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SnapshotStorage { path: path.into() }
+    }
+}
+
+impl RibStorage for SnapshotStorage {
+    fn load(&self) -> Result<Tables> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(_) => return Ok(Tables::default()),
+        };
+        let (version, tables): (u32, Tables) =
+            bincode::deserialize(&content).with_context(|| {
+                format!(
+                    "{} is corrupt; move it aside to start with an empty RIB",
+                    self.path.display()
+                )
+            })?;
+        ensure!(
+            version == DB_VERSION,
+            "{} has version {}, but this RIB reads version {}",
+            self.path.display(),
+            version,
+            DB_VERSION
+        );
+        Ok(tables)
+    }
+
+    fn save(&self, tables: TableRefs) -> Result<()> {
+        // serialized from borrowed tables, which read back as the owned Tables
+        let snapshot = bincode::serialize(&(DB_VERSION, tables))?;
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, snapshot)
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        fs::rename(&partial, &self.path)
+            .with_context(|| format!("failed to replace {}", self.path.display()))
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// A tree per table in a sled database, keyed by name
+#[cfg(feature = "rib-sled")]
+pub struct SledStorage {
+    path: PathBuf,
+    db: sled::Db,
+}
+
+#[cfg(feature = "rib-sled")]
+impl SledStorage {
+    const TREES: [&'static str; 4] = ["locations", "next_hop", "metadata", "attributes"];
+    const VERSION_KEY: &'static str = "version";
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let db = sled::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        match db.get(Self::VERSION_KEY)? {
+            Some(version) => {
+                let version: u32 = bincode::deserialize(&version)?;
+                ensure!(
+                    version == DB_VERSION,
+                    "{} has version {}, but this RIB reads version {}",
+                    path.display(),
+                    version,
+                    DB_VERSION
+                );
+            }
+            None => {
+                db.insert(Self::VERSION_KEY, bincode::serialize(&DB_VERSION)?)?;
+            }
+        }
+        Ok(SledStorage { path, db })
+    }
+
+    fn load_tree<V: serde::de::DeserializeOwned>(&self, tree: &str) -> Result<HashMap<GdpName, V>> {
+        let mut table = HashMap::new();
+        for entry in self.db.open_tree(tree)?.iter() {
+            let (key, value) = entry?;
+            let name = GdpName::try_from(&key[..])
+                .with_context(|| format!("{} has a bad name in {}", self.path.display(), tree))?;
+            table.insert(name, bincode::deserialize(&value)?);
+        }
+        Ok(table)
+    }
+
+    /// Write the entries of `table` whose value changed, and remove those it no longer has
+    fn save_tree<V: serde::Serialize>(
+        &self,
+        tree: &str,
+        table: &HashMap<GdpName, V>,
+    ) -> Result<()> {
+        let tree = self.db.open_tree(tree)?;
+        let mut batch = sled::Batch::default();
+        for key in tree.iter().keys() {
+            let key = key?;
+            if !GdpName::try_from(&key[..]).map_or(false, |name| table.contains_key(&name)) {
+                batch.remove(key);
+            }
+        }
+        for (name, value) in table {
+            let value = bincode::serialize(value)?;
+            if tree.get(name)?.map_or(true, |saved| saved[..] != value[..]) {
+                batch.insert(&name[..], value);
+            }
+        }
+        tree.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rib-sled")]
+impl RibStorage for SledStorage {
+    fn load(&self) -> Result<Tables> {
+        let [locations, next_hop, metadata, attributes] = Self::TREES;
+        Ok((
+            self.load_tree(locations)?,
+            self.load_tree(next_hop)?,
+            self.load_tree(metadata)?,
+            self.load_tree(attributes)?,
+        ))
+    }
+
+    fn save(&self, (locations, next_hop, metadata, attributes): TableRefs) -> Result<()> {
+        let [locations_tree, next_hop_tree, metadata_tree, attributes_tree] = Self::TREES;
+        self.save_tree(locations_tree, locations)?;
+        self.save_tree(next_hop_tree, next_hop)?;
+        self.save_tree(metadata_tree, metadata)?;
+        self.save_tree(attributes_tree, attributes)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("{} (sled)", self.path.display())
+    }
+}
+
+fn storage_path(config: &RibStorageConfig, default: &str) -> PathBuf {
+    config.path.clone().unwrap_or_else(|| {
+        env::var("GDP_RIB_DB")
+            .unwrap_or_else(|_| default.to_owned())
+            .into()
+    })
+}
+
+/// The backend that `config` names, opened
+pub fn open_rib_storage(config: &RibStorageConfig) -> Result<Box<dyn RibStorage>> {
+    let storage: Box<dyn RibStorage> = match config.backend {
+        Backend::Memory => Box::new(MemoryStorage),
+        Backend::Snapshot => Box::new(SnapshotStorage::new(storage_path(config, DEFAULT_DB_FILE))),
+        #[cfg(feature = "rib-sled")]
+        Backend::Sled => Box::new(SledStorage::open(storage_path(config, DEFAULT_SLED_DIR))?),
+        #[cfg(not(feature = "rib-sled"))]
+        Backend::Sled => {
+            return Err(anyhow!(
+                "rib_storage.toml asks for sled, but this RIB was built without the rib-sled \
+                 feature"
+            ))
+        }
+    };
+    Ok(storage)
+}
+
+/// The backend of rib_storage.toml, or snapshots if there is none
+pub fn configured_rib_storage() -> Result<Box<dyn RibStorage>> {
+    let config = if Path::new("rib_storage.toml").exists() {
+        load_rib_storage_config().context("rib_storage.toml")?
+    } else {
+        RibStorageConfig::default()
+    };
+    open_rib_storage(&config)
+}
+
+/// Load what `storage` holds into `routes`, returning how many routes were live
+pub fn load_rib_db(storage: &dyn RibStorage, routes: &Routes) -> Result<usize> {
+    let (mut locations, mut next_hop, metadata, mut attributes) = storage.load()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    for table in [&mut locations, &mut next_hop, &mut attributes] {
+        table.retain(|_, cert| cert.contents.expiration_time() > now);
+    }
+
+    let mut dynamic_routes = routes.dynamic_routes.write().recover();
+    let live = locations.len() + next_hop.len();
+    dynamic_routes.locations.extend(locations);
+    dynamic_routes.next_hop.extend(next_hop);
+    dynamic_routes.metadata.extend(metadata);
+    dynamic_routes.attributes.extend(attributes);
+    Ok(live)
+}
+
+/// Replace what `storage` holds with the current contents of `routes`
+pub fn save_rib_db(storage: &dyn RibStorage, routes: &Routes) -> Result<()> {
+    let dynamic_routes = routes.dynamic_routes.read().recover();
+    storage.save((
+        &dynamic_routes.locations,
+        &dynamic_routes.next_hop,
+        &dynamic_routes.metadata,
+        &dynamic_routes.attributes,
+    ))
+}
+
+/// Write the tables of the configured backend to a dump at `path`, returning how many routes
+/// it has. The RIB must not be running, or the dump misses what it has not saved yet
+pub fn export_rib_dump(path: &Path) -> Result<usize> {
+    let storage = configured_rib_storage()?;
+    let (locations, next_hop, metadata, attributes) = storage.load()?;
+    SnapshotStorage::new(path).save((&locations, &next_hop, &metadata, &attributes))?;
+    Ok(locations.len() + next_hop.len())
+}
+
+/// Replace the tables of the configured backend with the dump at `path`, returning how many
+/// routes it has. The RIB must not be running, or its next save undoes the import
+pub fn import_rib_dump(path: &Path) -> Result<usize> {
+    let storage = configured_rib_storage()?;
+    ensure!(path.exists(), "{} does not exist", path.display());
+    let (locations, next_hop, metadata, attributes) = SnapshotStorage::new(path).load()?;
+    storage.save((&locations, &next_hop, &metadata, &attributes))?;
+    Ok(locations.len() + next_hop.len())
+}
+
+#[cfg(all(test, feature = "rib-sled"))]
+mod tests {
+    use super::*;
+    use crate::hardcoded_routes::metadata_of_index;
+
+    fn metadata(indices: &[u8]) -> HashMap<GdpName, GdpMeta> {
+        indices
+            .iter()
+            .map(|&index| (metadata_of_index(index).hash(), metadata_of_index(index)))
+            .collect()
+    }
+
+    #[test]
+    fn sled_keeps_what_was_last_saved() {
+        let path = env::temp_dir().join(format!("gdp-rib-test-{}.sled", std::process::id()));
+        let empty = HashMap::new();
+        {
+            let storage = SledStorage::open(&path).unwrap();
+            storage
+                .save((&empty, &empty, &metadata(&[1, 2]), &empty))
+                .unwrap();
+            // an entry the tables no longer have is removed from its tree
+            storage
+                .save((&empty, &empty, &metadata(&[2, 3]), &empty))
+                .unwrap();
+        }
+        let (locations, _, loaded, _) = SledStorage::open(&path).unwrap().load().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+        assert!(locations.is_empty());
+        let mut names = loaded.keys().copied().collect::<Vec<_>>();
+        names.sort_unstable();
+        let mut expected = metadata(&[2, 3]).keys().copied().collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(names, expected);
+    }
+}
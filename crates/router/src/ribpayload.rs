@@ -1,87 +1,219 @@
-use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::iter::empty;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
-use gdp_client::GdpName;
-use serde::{Deserialize, Serialize};
+use gdp_proto::GdpName;
+pub use gdp_proto::{RibQuery, RibResponse};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
 
-use crate::certificates::{CertContents, CertDest, Certificate, GdpMeta, RtCert};
+use crate::certificates::{CertContents, CertDest, Certificate, GdpMeta, RtCert, VerifyCert};
+use crate::dtls::transport::transports;
+use crate::isolation::Recover;
 use crate::kvs::Store;
-use crate::rib::{DynamicRoutes, Routes};
+use crate::registration::MIGRATION_WINDOW;
+use crate::rib::{DynamicRoutes, Routes, Subscriber};
 use crate::FwdTableEntry;
 
-#[derive(Deserialize, Serialize)]
-pub struct RibQuery {
-    pub metas_for_names: Vec<GdpName>,
-    pub ips_for_names: Vec<GdpName>,
-    pub next_hop_for_names: Vec<GdpName>,
-    pub new_nodes: Vec<GdpMeta>,
-    pub new_certs: Vec<Certificate>,
-}
-
-impl RibQuery {
-    pub fn next_hop_for(dst: GdpName) -> Self {
-        RibQuery {
-            metas_for_names: Vec::new(),
-            ips_for_names: vec![dst],
-            next_hop_for_names: vec![dst],
-            new_nodes: Vec::new(),
-            new_certs: Vec::new(),
-        }
-    }
+/*
+   Routes that a switch learns from the RIB are kept for a TTL, rather than for as long as their
+   certificates are valid, so that routes nobody uses are asked for again before they are used:
+   - a RibReply may say how long its routes are to be kept (`route_ttl`, set from the
+     `advertised_ttl_secs` of the RIB's route_ttl.toml). Replies without one use the
+     `default_ttl_secs` of the switch's route_ttl.toml
+   - a route expires a TTL after it was learned, and never after its certificate does
+   - with `refresh_on_use`, a lookup that finds a route with less than half of its TTL left
+     gives it a whole TTL again, still within its certificate
+   - an expired route is dropped by the lookup that finds it, which then asks the RIB again, as
+     for any name the switch does not know
+   - routes that endpoints register, and pinned routes, keep the expiration times they have
+   - the names a RibReply says the RIB has no route for (`misses`, and the `withdrawn` names of a
+     push) are cached as such for a TTL too, during which packets for them are NACKed without
+     asking the RIB again. It is much shorter than that of routes, so that new registrations are
+     noticed quickly, and comes the same way: from the reply's `negative_ttl`, set from the
+     `advertised_negative_ttl_secs` of the RIB, or else the switch's `negative_ttl_secs`
+   - whatever a reply says, a switch keeps its routes for at most its `max_ttl_secs`, and its
+     negative answers for at most its `max_negative_ttl_secs`
+*/
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct RouteTtlConfig {
+    /// For replies from RIBs that do not say
+    #[serde(default = "default_route_ttl")]
+    pub default_ttl_secs: u64,
+    #[serde(default = "default_refresh_on_use")]
+    pub refresh_on_use: bool,
+    /// The TTL that a RIB puts in its replies; unset leaves it to each switch
+    #[serde(default)]
+    pub advertised_ttl_secs: Option<u64>,
+    /// How long a name the RIB has no route for is NACKed without asking again, for replies
+    /// from RIBs that do not say
+    #[serde(default = "default_negative_ttl")]
+    pub negative_ttl_secs: u64,
+    /// The negative TTL that a RIB puts in its replies; unset leaves it to each switch
+    #[serde(default)]
+    pub advertised_negative_ttl_secs: Option<u64>,
+    /// The longest TTL taken from a reply
+    #[serde(default = "default_max_ttl")]
+    pub max_ttl_secs: u64,
+    /// The longest negative TTL taken from a reply
+    #[serde(default = "default_max_negative_ttl")]
+    pub max_negative_ttl_secs: u64,
+}
 
-    pub fn metas_for(names: &[GdpName]) -> Self {
-        RibQuery {
-            metas_for_names: names.to_owned(),
-            ips_for_names: Vec::new(),
-            next_hop_for_names: Vec::new(),
-            new_nodes: Vec::new(),
-            new_certs: Vec::new(),
+fn default_route_ttl() -> u64 {
+    300
+}
+
+fn default_refresh_on_use() -> bool {
+    true
+}
+
+fn default_negative_ttl() -> u64 {
+    10
+}
+
+fn default_max_ttl() -> u64 {
+    3600
+}
+
+fn default_max_negative_ttl() -> u64 {
+    60
+}
+
+impl Default for RouteTtlConfig {
+    fn default() -> Self {
+        RouteTtlConfig {
+            default_ttl_secs: default_route_ttl(),
+            refresh_on_use: default_refresh_on_use(),
+            advertised_ttl_secs: None,
+            negative_ttl_secs: default_negative_ttl(),
+            advertised_negative_ttl_secs: None,
+            max_ttl_secs: default_max_ttl(),
+            max_negative_ttl_secs: default_max_negative_ttl(),
         }
     }
+}
 
-    pub fn announce_route(meta: GdpMeta, cert: Certificate) -> Self {
-        Self::announce_routes(meta, vec![cert].into())
-    }
+pub fn load_route_ttl_config() -> Result<RouteTtlConfig> {
+    let content = fs::read_to_string("route_ttl.toml")?;
+    Ok(toml::from_str(&content)?)
+}
 
-    pub fn announce_routes(meta: GdpMeta, certs: Cow<[Certificate]>) -> Self {
-        RibQuery {
-            metas_for_names: Vec::new(),
-            ips_for_names: Vec::new(),
-            next_hop_for_names: Vec::new(),
-            new_nodes: vec![meta],
-            new_certs: certs.into_owned(),
-        }
+// loaded on first use, shared by every core and the RIB
+static ROUTE_TTLS: Lazy<RouteTtlConfig> = Lazy::new(|| load_route_ttl_config().unwrap_or_default());
+
+fn route_ttls() -> &'static RouteTtlConfig {
+    &ROUTE_TTLS
+}
+
+/// The TTL for the RIB to put in its replies
+pub fn advertised_route_ttl() -> Option<u64> {
+    route_ttls().advertised_ttl_secs
+}
+
+/// The negative TTL for the RIB to put in its replies
+pub fn advertised_negative_ttl() -> Option<u64> {
+    route_ttls().advertised_negative_ttl_secs
+}
+
+/// The TTL and negative TTL that `response` asks for, within the bounds of `config`
+fn ttls_of(response: &RibResponse, config: &RouteTtlConfig) -> (u64, u64) {
+    let ttl = response.route_ttl.unwrap_or(config.default_ttl_secs);
+    let negative_ttl = response.negative_ttl.unwrap_or(config.negative_ttl_secs);
+    (
+        ttl.min(config.max_ttl_secs),
+        negative_ttl.min(config.max_negative_ttl_secs),
+    )
+}
+
+/// Give a learned route that a lookup found a whole TTL again, once it is past half of it.
+/// On the packet path, so it takes no lock: the refresh reaches other cores with the next publish.
+pub fn refresh_route(gdp_name: GdpName, entry: FwdTableEntry<Ipv4Addr>, store: Store) {
+    if !route_ttls().refresh_on_use {
+        return;
+    }
+    let lifetime = match store.route_lifetimes.get_unchecked(&gdp_name) {
+        Some(lifetime) => lifetime,
+        None => return,
+    };
+    let now = store.now_secs();
+    let ttl = lifetime.val;
+    if entry.expiration_time > now.saturating_add(ttl / 2) {
+        return;
+    }
+    let expiration_time = now.saturating_add(ttl).min(lifetime.expiration_time);
+    if expiration_time > entry.expiration_time {
+        store
+            .forwarding_table
+            .refresh(gdp_name, FwdTableEntry::new(entry.val, expiration_time));
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RibResponse {
-    pub metas: Vec<GdpMeta>,
-    pub certs: Vec<Certificate>,
+/// Record a route or attributes taken by this RIB, keeping routes for its peers to replicate
+/// (see ribsync.rs)
+pub fn insert_cert(cert: Certificate, routes: &mut DynamicRoutes) -> Result<()> {
+    let replicated = match cert.contents {
+        CertContents::RtCert(_) if routes.replica.is_enabled() => Some(cert.clone()),
+        _ => None,
+    };
+    apply_cert(cert, routes)?;
+    if let Some(cert) = replicated {
+        let gdp_name = *cert.contents.owner();
+        if let Some(meta) = routes.metadata.get(&gdp_name).copied() {
+            routes.replica.record(gdp_name, Some((meta, cert)));
+        }
+    }
+    Ok(())
 }
 
-fn insert_cert(cert: Certificate, routes: &mut DynamicRoutes) -> Result<()> {
+/// Record a route or attributes, as `insert_cert` does, without replicating them
+pub fn apply_cert(cert: Certificate, routes: &mut DynamicRoutes) -> Result<()> {
     let gdp_name = cert.contents.owner();
     let gdp_metadata = routes
         .metadata
         .get(gdp_name)
         .ok_or_else(|| anyhow!("unknown gdpname owning cert"))?;
     cert.verify(gdp_metadata)?;
+    let meta = *gdp_metadata;
+    record_cert(cert, meta, routes);
+    Ok(())
+}
+
+/// Record a route or attributes that were already checked against `meta`, the owner's metadata
+pub fn record_cert(cert: Certificate, meta: GdpMeta, routes: &mut DynamicRoutes) {
+    let gdp_name = *cert.contents.owner();
     match cert.contents {
-        CertContents::RtCert(RtCert { ref proxy, .. }) => match proxy {
-            CertDest::GdpName(_dest) => {
-                println!("RIB recording delegation");
-                routes.next_hop.insert(*gdp_name, cert);
+        CertContents::RtCert(RtCert { ref proxy, .. }) => {
+            let previous = match proxy {
+                CertDest::GdpName(_dest) => {
+                    println!("RIB recording delegation");
+                    routes.next_hop.insert(gdp_name, cert.clone())
+                }
+                CertDest::IpAddr(dest) => {
+                    println!("RIB recording node at {:?}", dest);
+                    routes.locations.insert(gdp_name, cert.clone())
+                }
+            };
+            // renewing a binding without moving it is not worth a push
+            let moved = match previous.map(|previous| previous.contents) {
+                Some(CertContents::RtCert(RtCert {
+                    proxy: previous, ..
+                })) => previous != *proxy,
+                _ => true,
+            };
+            if moved {
+                routes.subscriptions.changed(meta, cert);
             }
-            CertDest::IpAddr(dest) => {
-                println!("RIB recording node at {:?}", dest);
-                routes.locations.insert(*gdp_name, cert);
-            }
-        },
+        }
+        CertContents::AttrCert(_) => {
+            println!("RIB recording attributes");
+            routes.attributes.insert(gdp_name, cert);
+        }
     }
-    Ok(())
 }
 
 fn key_lookup<'a, T: Clone>(
@@ -101,19 +233,16 @@ fn key_lookup<'a, T: Clone>(
     })
 }
 
-pub fn generate_rib_response(query: RibQuery, routes: &Routes, debug: bool) -> RibResponse {
-    for meta in query.new_nodes {
-        routes
-            .dynamic_routes
-            .write()
-            .unwrap()
-            .metadata
-            .insert(meta.hash(), meta);
-    }
-    for cert in query.new_certs {
-        let _ = insert_cert(cert, &mut routes.dynamic_routes.write().unwrap());
+pub fn generate_rib_response(
+    query: RibQuery,
+    subscriber: Subscriber,
+    routes: &Routes,
+    debug: bool,
+) -> RibResponse {
+    if debug && (!query.new_nodes.is_empty() || !query.new_certs.is_empty()) {
+        println!("RIB ignoring an unsigned announcement; nodes register with RibRegister");
     }
-    let dynamic_routes = routes.dynamic_routes.read().unwrap();
+    let mut dynamic_routes = routes.dynamic_routes.write().recover();
 
     let certs = empty()
         .chain(key_lookup(
@@ -128,6 +257,14 @@ pub fn generate_rib_response(query: RibQuery, routes: &Routes, debug: bool) -> R
         ))
         .collect::<Vec<_>>();
 
+    // so that switches that only now ask about a moving name deliver to both of its hosts
+    let migrating = query
+        .ips_for_names
+        .iter()
+        .filter_map(|gdp_name| dynamic_routes.migrations.get(gdp_name))
+        .flat_map(|migration| migration.certs.iter().cloned())
+        .collect();
+
     let metas = empty()
         .chain(key_lookup(
             query.metas_for_names.iter(),
@@ -141,28 +278,160 @@ pub fn generate_rib_response(query: RibQuery, routes: &Routes, debug: bool) -> R
         ))
         .collect();
 
-    RibResponse { metas, certs }
+    let misses = query
+        .ips_for_names
+        .iter()
+        .chain(query.next_hop_for_names.iter())
+        .filter(|gdp_name| {
+            !dynamic_routes.locations.contains_key(*gdp_name)
+                && !dynamic_routes.next_hop.contains_key(*gdp_name)
+        })
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    if query.subscribe {
+        for cert in &certs {
+            dynamic_routes.subscriptions.subscribe(
+                *cert.contents.owner(),
+                subscriber,
+                cert.contents.expiration_time(),
+            );
+        }
+    }
+
+    let transport_hints =
+        transports().hints_for(certs.iter().filter_map(|cert| match cert.contents {
+            CertContents::RtCert(RtCert {
+                proxy: CertDest::IpAddr(ip),
+                ..
+            }) => Some(ip),
+            _ => None,
+        }));
+
+    RibResponse {
+        metas,
+        certs,
+        misses,
+        withdrawn: Vec::new(),
+        transport_hints,
+        migrating,
+        route_ttl: advertised_route_ttl(),
+        negative_ttl: advertised_negative_ttl(),
+    }
 }
 
 pub fn process_rib_response(response: RibResponse, store: Store, debug: bool) -> Result<()> {
     if debug {
         println!("{:?}", response);
     }
-    process_rib_data(&response.metas, &response.certs, None, store, debug)
+    let (ttl, negative_ttl) = ttls_of(&response, route_ttls());
+    let negative_expiration_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .saturating_add(negative_ttl);
+    transports().learn(&response.transport_hints);
+    // the data plane should never route with half of a response applied
+    store.transaction(|| {
+        for gdp_name in &response.misses {
+            if debug {
+                println!(
+                    "RIB has no route for {:?}, caching negative answer",
+                    gdp_name
+                );
+            }
+            store
+                .negative_routes
+                .put(*gdp_name, FwdTableEntry::new((), negative_expiration_time));
+        }
+        for gdp_name in &response.withdrawn {
+            if debug {
+                println!("RIB withdrew the route for {:?}", gdp_name);
+            }
+            store.forwarding_table.remove(gdp_name);
+            store.next_hops.remove(gdp_name);
+            store.route_lifetimes.remove(gdp_name);
+            store
+                .negative_routes
+                .put(*gdp_name, FwdTableEntry::new((), negative_expiration_time));
+        }
+        process_rib_data(
+            &response.metas,
+            &response.certs,
+            None,
+            Some(ttl),
+            store,
+            debug,
+        )?;
+        record_migrations(&response.migrating, store, debug)
+    })
+}
+
+/// Note where names are moving to, so that packets for them are delivered there as well as to
+/// their current location until they have moved, for at most MIGRATION_WINDOW
+pub fn record_migrations(certs: &[Certificate], store: Store, debug: bool) -> Result<()> {
+    let window_end = SystemTime::now().duration_since(UNIX_EPOCH)? + MIGRATION_WINDOW;
+    for cert in certs {
+        let meta = match store.gdp_metadata.get_unchecked(cert.contents.owner()) {
+            Some(meta) => meta,
+            None => continue,
+        };
+        cert.verify(&meta)?;
+        if let CertContents::RtCert(RtCert {
+            base,
+            proxy: CertDest::IpAddr(ip_addr),
+            expiration_time,
+            ..
+        }) = &cert.contents
+        {
+            if debug {
+                println!("{:?} is moving to {:?}", base, ip_addr);
+            }
+            let expiration_time = (*expiration_time).min(window_end.as_secs());
+            store
+                .migrations
+                .put(*base, FwdTableEntry::new(*ip_addr, expiration_time));
+        }
+    }
+    Ok(())
 }
 
+/// The old registration of a name was withdrawn: route it to where it was moving until the RIB
+/// pushes its new route, or forget its route if it was not moving
+pub fn complete_migration(gdp_name: GdpName, store: Store, debug: bool) {
+    match store.migrations.get(&gdp_name) {
+        Some(entry) => {
+            if debug {
+                println!("{:?} has moved to {:?}", gdp_name, entry.val);
+            }
+            store.forwarding_table.put(gdp_name, entry);
+            store.migrations.remove(&gdp_name);
+        }
+        None => {
+            store.forwarding_table.remove(&gdp_name);
+            store.next_hops.remove(&gdp_name);
+        }
+    }
+}
+
+/// Record the metadata and routes in `certs`. Routes learned from the RIB have a `ttl`; those
+/// registered with the switch have none, and keep the expiration times of their certificates.
 pub fn process_rib_data<'a>(
     metas: &[GdpMeta],
     certs: &'a [Certificate],
     mut out_certs: Option<&mut Vec<&'a Certificate>>,
+    ttl: Option<u64>,
     store: Store,
     debug: bool,
 ) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
     for meta in metas {
         store.gdp_metadata.put(meta.hash(), *meta);
     }
     for cert in certs {
         let owner = cert.contents.owner();
+        store.negative_routes.remove(owner);
         let meta = store.gdp_metadata.get_unchecked(owner);
         if let Some(meta) = meta {
             cert.verify(&meta)?;
@@ -189,13 +458,236 @@ pub fn process_rib_data<'a>(
                         if debug {
                             println!("Inserting mapping in switch to {:?}", ip_addr);
                         }
+                        let expiration_time = match ttl {
+                            Some(ttl) => {
+                                store
+                                    .route_lifetimes
+                                    .update(*base, FwdTableEntry::new(ttl, *expiration_time));
+                                (*expiration_time).min(now.saturating_add(ttl))
+                            }
+                            None => *expiration_time,
+                        };
                         store
                             .forwarding_table
-                            .put(*base, FwdTableEntry::new(*ip_addr, *expiration_time))
+                            .put(*base, FwdTableEntry::new(*ip_addr, expiration_time));
+                        // the name has arrived where it was moving to
+                        let arrived = store
+                            .migrations
+                            .get_unchecked(base)
+                            .map_or(false, |entry| entry.val == *ip_addr);
+                        if arrived {
+                            store.migrations.remove(base);
+                        }
                     }
                 },
+                CertContents::AttrCert(_) => {
+                    // only meaningful to the RIB, so pass it on
+                    if let Some(ref mut out_certs) = out_certs {
+                        out_certs.push(cert);
+                    }
+                }
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificates::NewRtCert;
+    use crate::hardcoded_routes::{metadata_of_index, private_key_of_index};
+    use crate::kvs::SharedStore;
+
+    const OLD_HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const NEW_HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn store_at_old_host() -> Store {
+        let store = SharedStore::new().sync();
+        let meta = metadata_of_index(1);
+        store.transaction(|| {
+            store.gdp_metadata.put(meta.hash(), meta);
+            store
+                .forwarding_table
+                .put(meta.hash(), FwdTableEntry::new(OLD_HOST, u64::MAX));
+        });
+        store
+    }
+
+    fn route_of(store: Store) -> Option<Ipv4Addr> {
+        let _view = store.pin();
+        let route = store.forwarding_table.get(&metadata_of_index(1).hash());
+        route.map(|entry| entry.val)
+    }
+
+    #[test]
+    fn delivers_to_the_new_host_once_the_old_one_withdraws() {
+        let store = store_at_old_host();
+        let gdp_name = metadata_of_index(1).hash();
+        let cert = RtCert::new_wrapped(
+            metadata_of_index(1),
+            private_key_of_index(1),
+            CertDest::IpAddr(NEW_HOST),
+            true,
+        )
+        .unwrap();
+        store.transaction(|| record_migrations(&[cert], store, false).unwrap());
+        {
+            let _view = store.pin();
+            let migration = store.migrations.get(&gdp_name).unwrap();
+            assert_eq!(migration.val, NEW_HOST);
+            // no later than the end of the window
+            let window_end =
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + MIGRATION_WINDOW;
+            assert!(migration.expiration_time <= window_end.as_secs());
+        }
+        assert_eq!(route_of(store), Some(OLD_HOST));
+
+        store.transaction(|| complete_migration(gdp_name, store, false));
+        assert_eq!(route_of(store), Some(NEW_HOST));
+        let _view = store.pin();
+        assert!(store.migrations.get(&gdp_name).is_none());
+    }
+
+    #[test]
+    fn refuses_migrations_signed_by_someone_else() {
+        let store = store_at_old_host();
+        let forged = RtCert::new_wrapped(
+            metadata_of_index(1),
+            private_key_of_index(2),
+            CertDest::IpAddr(NEW_HOST),
+            true,
+        )
+        .unwrap();
+        assert!(store
+            .transaction(|| record_migrations(&[forged], store, false))
+            .is_err());
+        let _view = store.pin();
+        assert!(store.migrations.get(&metadata_of_index(1).hash()).is_none());
+    }
+
+    #[test]
+    fn forgets_the_route_of_a_name_withdrawn_without_moving() {
+        let store = store_at_old_host();
+        store.transaction(|| complete_migration(metadata_of_index(1).hash(), store, false));
+        assert_eq!(route_of(store), None);
+    }
+
+    fn learned_route(ttl: u64) -> (Store, Certificate) {
+        let store = SharedStore::new().sync();
+        let cert = RtCert::new_wrapped(
+            metadata_of_index(1),
+            private_key_of_index(1),
+            CertDest::IpAddr(NEW_HOST),
+            true,
+        )
+        .unwrap();
+        let metas = [metadata_of_index(1)];
+        store.transaction(|| {
+            process_rib_data(&metas, &[cert.clone()], None, Some(ttl), store, false).unwrap()
+        });
+        (store, cert)
+    }
+
+    #[test]
+    fn keeps_learned_routes_for_their_ttl() {
+        let (store, cert) = learned_route(60);
+        let gdp_name = metadata_of_index(1).hash();
+        let _view = store.pin();
+        let route = store.forwarding_table.get(&gdp_name).unwrap();
+        assert_eq!(route.val, NEW_HOST);
+        assert!(route.expiration_time <= store.now_secs() + 61);
+        let lifetime = store.route_lifetimes.get(&gdp_name).unwrap();
+        assert_eq!(lifetime.val, 60);
+        assert_eq!(lifetime.expiration_time, cert.contents.expiration_time());
+    }
+
+    #[test]
+    fn refreshes_routes_past_half_of_their_ttl() {
+        let (store, _) = learned_route(60);
+        let gdp_name = metadata_of_index(1).hash();
+        let now = store.now_secs();
+
+        // more than half of the TTL left: left alone
+        let fresh = FwdTableEntry::new(NEW_HOST, now + 40);
+        store.forwarding_table.update(gdp_name, fresh);
+        refresh_route(gdp_name, fresh, store);
+        assert_eq!(
+            store
+                .forwarding_table
+                .get(&gdp_name)
+                .unwrap()
+                .expiration_time,
+            now + 40
+        );
+
+        let stale = FwdTableEntry::new(NEW_HOST, now + 10);
+        store.forwarding_table.update(gdp_name, stale);
+        refresh_route(gdp_name, stale, store);
+        assert_eq!(
+            store
+                .forwarding_table
+                .get(&gdp_name)
+                .unwrap()
+                .expiration_time,
+            now + 60
+        );
+    }
+
+    #[test]
+    fn never_refreshes_routes_past_their_certificate() {
+        let (store, _) = learned_route(60);
+        let gdp_name = metadata_of_index(1).hash();
+        let now = store.now_secs();
+        store
+            .route_lifetimes
+            .update(gdp_name, FwdTableEntry::new(60, now + 20));
+        let stale = FwdTableEntry::new(NEW_HOST, now + 10);
+        store.forwarding_table.update(gdp_name, stale);
+        refresh_route(gdp_name, stale, store);
+        assert_eq!(
+            store
+                .forwarding_table
+                .get(&gdp_name)
+                .unwrap()
+                .expiration_time,
+            now + 20
+        );
+    }
+
+    #[test]
+    fn bounds_the_ttls_that_replies_ask_for() {
+        let config = RouteTtlConfig::default();
+        let unset = RibResponse::default();
+        assert_eq!(
+            ttls_of(&unset, &config),
+            (config.default_ttl_secs, config.negative_ttl_secs)
+        );
+        let modest = RibResponse {
+            route_ttl: Some(600),
+            negative_ttl: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(ttls_of(&modest, &config), (600, 30));
+        let endless = RibResponse {
+            route_ttl: Some(u64::MAX),
+            negative_ttl: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert_eq!(
+            ttls_of(&endless, &config),
+            (config.max_ttl_secs, config.max_negative_ttl_secs)
+        );
+    }
+
+    #[test]
+    fn keeps_routes_with_endless_ttls_until_their_certificate_expires() {
+        let (store, cert) = learned_route(u64::MAX);
+        let _view = store.pin();
+        let route = store
+            .forwarding_table
+            .get(&metadata_of_index(1).hash())
+            .unwrap();
+        assert_eq!(route.expiration_time, cert.contents.expiration_time());
+    }
+}
@@ -0,0 +1,609 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{metrics, Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpHeader, GdpName};
+use metrics_runtime::data::Counter;
+use serde::Deserialize;
+
+use crate::capabilities::{capabilities_expiration, read_heartbeat, Capabilities, Feature};
+use crate::dtls::{dtls_overhead, encrypt_gdp, DTls};
+use crate::gdp::Gdp;
+use crate::hardcoded_routes::{gdp_name_of_index, RIB_INDEX};
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::l2filter::VlanTx;
+use crate::preflight::{IPV4_HEADER_LEN, UDP_HEADER_LEN};
+use crate::rib::{create_control_request, Routes, Subscriber};
+use crate::ribpayload::{generate_rib_response, RibQuery, RibResponse};
+use crate::schedule::{yield_now, Schedule};
+use crate::scratch::with_serialized;
+
+/*
+   The RIB answers queries in batches, so that a storm of them cannot take every mbuf it has:
+   - queries are queued as they come in, each core in a queue of its own so that cores do not
+     wait on each other, and are answered by a schedule on the control core, on its next turn
+   - a queue holds at most `max_queued` queries. A query that finds it full takes the place of
+     the oldest query of a lower priority, or is shed if there is none
+   - switches heartbeat the RIB like any neighbor. The answers to a querier that advertises
+     AggregatedRibReplies are merged into as few RibReplies as fit its MTU, as switches apply
+     the parts of a reply one by one. Everyone else (clients, and switches from before
+     heartbeats) gets a reply per query, as they may be waiting for the answer to each
+   - before answering, the schedule takes an mbuf for each reply it may send, at most
+     `max_replies` of them and fewer if the mempool runs short. Queries are answered highest
+     priority first (routes, then metadata), and those that would need a reply beyond the
+     mbufs taken are shed without being answered. Queriers ask again for what they still need
+*/
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct RibReplyConfig {
+    /// Merge the answers to queriers that support it
+    #[serde(default = "default_aggregate")]
+    pub aggregate: bool,
+    /// Replies sent per flush at most; queries beyond them are shed
+    #[serde(default = "default_max_replies")]
+    pub max_replies: usize,
+    /// Queries each core holds at most until the next flush
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+}
+
+fn default_aggregate() -> bool {
+    true
+}
+
+fn default_max_replies() -> usize {
+    256
+}
+
+fn default_max_queued() -> usize {
+    1024
+}
+
+impl Default for RibReplyConfig {
+    fn default() -> Self {
+        RibReplyConfig {
+            aggregate: default_aggregate(),
+            max_replies: default_max_replies(),
+            max_queued: default_max_queued(),
+        }
+    }
+}
+
+pub fn load_rib_reply_config() -> Result<RibReplyConfig> {
+    let content = fs::read_to_string("rib_replies.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// Which queries are shed last when there is no room for all of them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QueryPriority {
+    /// Only asks for metadata, which can wait
+    Metas,
+    /// Asks where names are, with packets for them likely waiting
+    Routes,
+}
+
+impl QueryPriority {
+    pub fn of(query: &RibQuery) -> Self {
+        if !query.ips_for_names.is_empty() || !query.next_hop_for_names.is_empty() {
+            QueryPriority::Routes
+        } else {
+            QueryPriority::Metas
+        }
+    }
+}
+
+struct Query {
+    querier: Subscriber,
+    /// The UDP port the query came from
+    port: u16,
+    priority: QueryPriority,
+    query: RibQuery,
+}
+
+/// One RibReply to send, answering one or more queries
+struct Reply {
+    querier: Subscriber,
+    port: u16,
+    response: RibResponse,
+    /// Serialized length of `response`
+    len: usize,
+    answers: u64,
+}
+
+/// How many queues queries are spread over; cores beyond them share
+const QUEUES: usize = 16;
+
+static NEXT_QUEUE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The queue this core puts its queries in
+    static QUEUE: usize = NEXT_QUEUE.fetch_add(1, Ordering::Relaxed) % QUEUES;
+}
+
+struct RibReplyState {
+    config: RibReplyConfig,
+    queues: Vec<Mutex<Vec<Query>>>,
+    /// Queries in all the queues, so that the schedule only looks at them when there are some
+    queued: AtomicUsize,
+    /// Capabilities of the queriers that heartbeat us, until they expire
+    queriers: Mutex<HashMap<Subscriber, (Capabilities, u64)>>,
+    merged: Counter,
+    shed_queue: Counter,
+    shed_budget: Counter,
+    shed_mbufs: Counter,
+}
+
+/// The RIB's queries waiting to be answered, shared by its pipeline and the schedule that
+/// answers them
+#[derive(Clone, Copy)]
+pub struct RibReplies(&'static RibReplyState);
+
+/// Bytes a RibReply takes beyond its payload, once encrypted
+fn reply_overhead() -> usize {
+    IPV4_HEADER_LEN + UDP_HEADER_LEN + dtls_overhead() + GdpHeader::LEN as usize
+}
+
+fn serialized_len(response: &RibResponse) -> usize {
+    bincode::serialized_size(response).map_or(usize::MAX, |len| len as usize)
+}
+
+/// The serialized length of `reply` once `answer`, of `answer_len` bytes, is merged into it:
+/// the merged response has one length for each of its lists, and the TTLs that `merge` keeps
+fn merged_len(reply: &Reply, answer: &RibResponse, answer_len: usize) -> usize {
+    let lists_and_ttls = |route_ttl, negative_ttl| {
+        serialized_len(&RibResponse {
+            route_ttl,
+            negative_ttl,
+            ..Default::default()
+        })
+    };
+    let ours = &reply.response;
+    (reply.len
+        + answer_len
+        + lists_and_ttls(
+            ours.route_ttl.or(answer.route_ttl),
+            ours.negative_ttl.or(answer.negative_ttl),
+        ))
+    .saturating_sub(lists_and_ttls(ours.route_ttl, ours.negative_ttl))
+    .saturating_sub(lists_and_ttls(answer.route_ttl, answer.negative_ttl))
+}
+
+impl RibReplies {
+    pub fn new(config: RibReplyConfig) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut shed = |reason: &'static str| {
+            sink.counter_with_labels("rib.replies_shed", vec![("reason", reason)])
+        };
+        let (shed_queue, shed_budget, shed_mbufs) = (shed("queue"), shed("budget"), shed("mbufs"));
+        RibReplies(Box::leak(Box::new(RibReplyState {
+            config,
+            queues: (0..QUEUES).map(|_| Mutex::new(Vec::new())).collect(),
+            queued: AtomicUsize::new(0),
+            queriers: Mutex::new(HashMap::new()),
+            merged: sink.counter("rib.answers_merged"),
+            shed_queue,
+            shed_budget,
+            shed_mbufs,
+        })))
+    }
+
+    /// Queue a query from `querier`, sent from UDP `port`, to be answered on the next flush
+    pub fn queue(&self, querier: Subscriber, port: u16, query: RibQuery) {
+        let priority = QueryPriority::of(&query);
+        let mut queue = self.0.queues[QUEUE.with(|queue| *queue)].lock().recover();
+        if queue.len() >= self.0.config.max_queued.max(1) {
+            self.0.shed_queue.increment();
+            // the first of the lowest priority is the oldest
+            let lowest = queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, queued)| queued.priority)
+                .filter(|(_, queued)| queued.priority < priority)
+                .map(|(index, _)| index);
+            match lowest {
+                Some(index) => drop(queue.remove(index)),
+                None => return,
+            }
+        } else {
+            self.0.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.push(Query {
+            querier,
+            port,
+            priority,
+            query,
+        });
+    }
+
+    /// Record the capabilities of a querier, returning whether it asked for ours in return
+    pub fn handle_heartbeat(&self, packet: &Gdp<DTls<Ipv4>>, debug: bool) -> Result<bool> {
+        let (capabilities, want_reply) = read_heartbeat(packet)?;
+        let querier = Subscriber {
+            ip: packet.envelope().envelope().envelope().src(),
+            name: packet.src(),
+        };
+        if debug {
+            println!("RIB querier {} supports {:?}", querier.ip, capabilities);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut queriers = self.0.queriers.lock().recover();
+        queriers.retain(|_, (_, until)| *until > now);
+        queriers.insert(querier, (capabilities, capabilities_expiration()?));
+        Ok(want_reply)
+    }
+
+    /// The largest payload that may be merged into one reply to `querier`, if it takes merged
+    /// replies at all
+    fn merge_limit(&self, querier: &Subscriber, now: u64) -> Option<usize> {
+        if !self.0.config.aggregate {
+            return None;
+        }
+        match self.0.queriers.lock().recover().get(querier) {
+            Some((capabilities, until))
+                if *until > now && capabilities.supports(Feature::AggregatedRibReplies) =>
+            {
+                Some((capabilities.max_mtu as usize).saturating_sub(reply_overhead()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Take the queued queries, highest priority first, and answer them with `answer` in at most
+    /// `budget` replies. Queries that would need a reply beyond the budget are shed, mostly
+    /// without being answered; returns the replies and how many queries were shed
+    fn take_replies(
+        &self,
+        budget: usize,
+        mut answer: impl FnMut(RibQuery, Subscriber) -> RibResponse,
+    ) -> (Vec<Reply>, u64) {
+        let mut queries = Vec::new();
+        for queue in &self.0.queues {
+            queries.append(&mut queue.lock().recover());
+        }
+        self.0.queued.fetch_sub(queries.len(), Ordering::Relaxed);
+        // stable, so that queries of the same priority keep their order
+        queries.sort_by_key(|query| Reverse(query.priority));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+
+        let mut replies: Vec<Reply> = Vec::new();
+        let mut shed = 0;
+        // the reply still being filled for each querier and port
+        let mut open: HashMap<(Subscriber, u16), usize> = HashMap::new();
+        let mut limits: HashMap<Subscriber, Option<usize>> = HashMap::new();
+        for query in queries {
+            let limit = *limits
+                .entry(query.querier)
+                .or_insert_with(|| self.merge_limit(&query.querier, now));
+            let key = (query.querier, query.port);
+            let index = limit.and(open.get(&key).copied());
+            if index.is_none() && replies.len() >= budget {
+                shed += 1;
+                continue;
+            }
+            let response = answer(query.query, query.querier);
+            let len = serialized_len(&response);
+            if let (Some(limit), Some(index)) = (limit, index) {
+                let reply = &mut replies[index];
+                let merged_len = merged_len(reply, &response, len);
+                if merged_len <= limit {
+                    reply.response.merge(response);
+                    reply.len = merged_len;
+                    reply.answers += 1;
+                    self.0.merged.increment();
+                    continue;
+                }
+                if replies.len() >= budget {
+                    shed += 1;
+                    continue;
+                }
+            }
+            if limit.is_some() {
+                open.insert(key, replies.len());
+            }
+            replies.push(Reply {
+                querier: query.querier,
+                port: query.port,
+                response,
+                len,
+                answers: 1,
+            });
+        }
+        (replies, shed)
+    }
+}
+
+/// Up to `wanted` mbufs, as many as the mempool can spare
+fn take_mbufs(wanted: usize) -> Vec<Mbuf> {
+    let mut count = wanted;
+    while count > 0 {
+        if let Ok(mbufs) = Mbuf::alloc_bulk(count) {
+            return mbufs;
+        }
+        count /= 2;
+    }
+    Vec::new()
+}
+
+fn send_reply(q: &PortQueue, src: PortIdentity, rib_name: GdpName, mbuf: Mbuf, reply: Reply) {
+    let mut mbuf = Some(mbuf);
+    batch::poll_fn(move || mbuf.take().into_iter().collect())
+        .map(move |packet| {
+            let mut packet = with_serialized(&reply.response, |message| {
+                create_control_request(
+                    packet,
+                    GdpAction::RibReply,
+                    message,
+                    src.mac,
+                    src.ip,
+                    rib_name,
+                    reply.querier.ip,
+                )
+            })?;
+            packet.set_dst(reply.querier.name);
+            packet
+                .envelope_mut()
+                .envelope_mut()
+                .set_dst_port(reply.port);
+            packet.reconcile_all();
+            Ok(packet)
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q.clone()))
+        .run_once();
+}
+
+/// Answer the RIB's queued queries, in the background of the RIB pipeline; installed once, on
+/// the control core
+pub fn reply_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    replies: RibReplies,
+    routes: &'static Routes,
+    debug: bool,
+) -> impl Pipeline {
+    let rib_name = gdp_name_of_index(RIB_INDEX);
+    Schedule::new("rib_replies", async move {
+        loop {
+            let queued = replies.0.queued.load(Ordering::Relaxed);
+            if queued > 0 {
+                let wanted = queued.min(replies.0.config.max_replies.max(1));
+                let mbufs = take_mbufs(wanted);
+                let (sent, shed) = replies.take_replies(mbufs.len(), |query, querier| {
+                    generate_rib_response(query, querier, routes, debug)
+                });
+                if shed > 0 {
+                    let out_of_mbufs = mbufs.len() < wanted;
+                    if out_of_mbufs {
+                        replies.0.shed_mbufs.record(shed);
+                    } else {
+                        replies.0.shed_budget.record(shed);
+                    }
+                    if debug {
+                        println!(
+                            "RIB shedding {} queries: {}",
+                            shed,
+                            if out_of_mbufs {
+                                "out of mbufs"
+                            } else {
+                                "over the reply budget"
+                            }
+                        );
+                    }
+                }
+                // mbufs left over go back to the mempool as they drop
+                for (reply, mbuf) in sent.into_iter().zip(mbufs) {
+                    send_reply(&q, src, rib_name, mbuf, reply);
+                }
+            }
+            yield_now().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::flags::FeatureFlags;
+    use crate::hardcoded_routes::metadata_of_index;
+
+    fn querier(index: u8) -> Subscriber {
+        Subscriber {
+            ip: Ipv4Addr::new(10, 0, 0, index),
+            name: gdp_name_of_index(index),
+        }
+    }
+
+    /// Have `querier` take merged replies, as if it had heartbeated us
+    fn aggregating(replies: RibReplies, querier: Subscriber) {
+        replies.0.queriers.lock().recover().insert(
+            querier,
+            (Capabilities::local(FeatureFlags::new()), u64::MAX),
+        );
+    }
+
+    fn metas_of(query: &RibQuery) -> RibResponse {
+        RibResponse {
+            metas: query
+                .metas_for_names
+                .iter()
+                .map(|_| metadata_of_index(1))
+                .collect(),
+            misses: query.next_hop_for_names.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn routes_query(index: u8) -> RibQuery {
+        RibQuery::next_hop_for(gdp_name_of_index(index))
+    }
+
+    fn metas_query(index: u8) -> RibQuery {
+        RibQuery::metas_for(&[gdp_name_of_index(index)])
+    }
+
+    #[test]
+    fn routes_outrank_metas() {
+        assert_eq!(QueryPriority::of(&metas_query(1)), QueryPriority::Metas);
+        assert_eq!(QueryPriority::of(&routes_query(1)), QueryPriority::Routes);
+        let ips = RibQuery {
+            ips_for_names: vec![gdp_name_of_index(1)],
+            ..Default::default()
+        };
+        assert_eq!(QueryPriority::of(&ips), QueryPriority::Routes);
+        assert!(QueryPriority::Routes > QueryPriority::Metas);
+    }
+
+    #[test]
+    fn merged_lengths_are_exact() {
+        let ttls = [None, Some(30)];
+        for (route_ttl, negative_ttl) in
+            ttls.iter().flat_map(|a| ttls.iter().map(move |b| (*a, *b)))
+        {
+            for (answer_route_ttl, answer_negative_ttl) in
+                ttls.iter().flat_map(|a| ttls.iter().map(move |b| (*a, *b)))
+            {
+                let response = RibResponse {
+                    metas: vec![metadata_of_index(1)],
+                    route_ttl,
+                    negative_ttl,
+                    ..Default::default()
+                };
+                let reply = Reply {
+                    querier: querier(1),
+                    port: 31415,
+                    len: serialized_len(&response),
+                    response,
+                    answers: 1,
+                };
+                let answer = RibResponse {
+                    metas: vec![metadata_of_index(2), metadata_of_index(3)],
+                    misses: vec![gdp_name_of_index(4)],
+                    route_ttl: answer_route_ttl,
+                    negative_ttl: answer_negative_ttl,
+                    ..Default::default()
+                };
+                let len = merged_len(&reply, &answer, serialized_len(&answer));
+                let mut merged = reply.response;
+                merged.merge(answer);
+                assert_eq!(len, serialized_len(&merged));
+            }
+        }
+    }
+
+    #[test]
+    fn merges_the_answers_to_queriers_that_take_them() {
+        let replies = RibReplies::new(RibReplyConfig::default());
+        aggregating(replies, querier(2));
+        for index in 1..4 {
+            replies.queue(querier(1), 31415, metas_query(index));
+            replies.queue(querier(2), 31415, metas_query(index));
+        }
+        // another port of the same querier gets replies of its own
+        replies.queue(querier(2), 27182, metas_query(1));
+
+        let (sent, shed) = replies.take_replies(16, |query, _| metas_of(&query));
+        assert_eq!(shed, 0);
+        let answers = |index: u8, port: u16| {
+            sent.iter()
+                .filter(|reply| reply.querier == querier(index) && reply.port == port)
+                .map(|reply| reply.answers)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(answers(1, 31415), [1, 1, 1]);
+        assert_eq!(answers(2, 31415), [3]);
+        assert_eq!(answers(2, 27182), [1]);
+        let merged = sent.iter().find(|reply| reply.answers == 3).unwrap();
+        assert_eq!(merged.response.metas.len(), 3);
+        assert_eq!(merged.len, serialized_len(&merged.response));
+        assert_eq!(replies.0.queued.load(Ordering::Relaxed), 0);
+        assert!(replies
+            .take_replies(16, |query, _| metas_of(&query))
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn merges_no_more_than_fits_the_mtu() {
+        let replies = RibReplies::new(RibReplyConfig::default());
+        aggregating(replies, querier(1));
+        let room = Capabilities::local(FeatureFlags::new()).max_mtu as usize - reply_overhead();
+        let meta_len = bincode::serialized_size(&metadata_of_index(1)).unwrap() as usize;
+        let answers = room / meta_len + 1;
+        for _ in 0..answers {
+            replies.queue(querier(1), 31415, metas_query(1));
+        }
+
+        let (sent, shed) = replies.take_replies(16, |query, _| metas_of(&query));
+        assert_eq!(shed, 0);
+        assert!(sent.len() > 1);
+        assert!(sent.iter().all(|reply| reply.len <= room));
+        assert_eq!(
+            sent.iter().map(|reply| reply.answers).sum::<u64>(),
+            answers as u64
+        );
+    }
+
+    #[test]
+    fn sheds_metas_before_routes_without_answering_them() {
+        let replies = RibReplies::new(RibReplyConfig::default());
+        replies.queue(querier(1), 31415, metas_query(1));
+        replies.queue(querier(2), 31415, routes_query(2));
+        replies.queue(querier(3), 31415, metas_query(3));
+        replies.queue(querier(4), 31415, routes_query(4));
+
+        let mut answered = Vec::new();
+        let (sent, shed) = replies.take_replies(2, |query, querier| {
+            answered.push(querier);
+            metas_of(&query)
+        });
+        assert_eq!(shed, 2);
+        assert_eq!(answered, [querier(2), querier(4)]);
+        assert!(sent.iter().all(|reply| reply.response.misses.len() == 1));
+    }
+
+    #[test]
+    fn full_queues_keep_the_highest_priorities() {
+        let replies = RibReplies::new(RibReplyConfig {
+            max_queued: 2,
+            ..Default::default()
+        });
+        replies.queue(querier(1), 31415, metas_query(1));
+        replies.queue(querier(2), 31415, metas_query(2));
+        // takes the place of the oldest metas query
+        replies.queue(querier(3), 31415, routes_query(3));
+        replies.queue(querier(4), 31415, routes_query(4));
+        // finds nothing lower to replace
+        replies.queue(querier(5), 31415, routes_query(5));
+        assert_eq!(replies.0.queued.load(Ordering::Relaxed), 2);
+
+        let mut answered = Vec::new();
+        replies.take_replies(16, |query, querier| {
+            answered.push(querier);
+            metas_of(&query)
+        });
+        assert_eq!(answered, [querier(3), querier(4)]);
+    }
+
+    #[test]
+    fn reads_configs_without_the_limits() {
+        let config: RibReplyConfig = toml::from_str("aggregate = false").unwrap();
+        assert!(!config.aggregate);
+        assert_eq!(config.max_replies, default_max_replies());
+        assert_eq!(config.max_queued, default_max_queued());
+    }
+}
@@ -0,0 +1,151 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::Result;
+use capsule::config::RuntimeConfig;
+
+use crate::clock::Clock;
+use crate::control::{start_control_socket, ControlState};
+use crate::dtls::session::session_schedule;
+use crate::flags::FeatureFlags;
+use crate::gdp_pipeline::install_gdp_pipeline;
+use crate::hardcoded_routes::{
+    gdp_name_of_index, load_routes, metadata_of_index, private_key_of_index, RIB_INDEX,
+};
+use crate::identity::{load_port_identities, PortIdentities};
+use crate::info::{node_info, print_banner};
+use crate::isolation::Recover;
+use crate::kvs::SharedStore;
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::prometheus::start_metrics_endpoint;
+use crate::rib::{rib_pipeline, subscription_schedule, Routes};
+use crate::ribdb::{
+    load_rib_db, load_rib_storage_config, open_rib_storage, save_rib_db, RibStorage, SAVE_INTERVAL,
+};
+use crate::ribreplies::{load_rib_reply_config, reply_schedule, RibReplies};
+use crate::ribsync::{load_rib_sync_config, rib_sync_schedule, RibSync};
+use crate::runtime::build_runtime;
+use crate::Env;
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_rib_server(
+    config: RuntimeConfig,
+    env: Env,
+    node_addr: Ipv4Addr,
+    use_default: bool,
+    flags: FeatureFlags,
+    control_port: Option<u16>,
+    metrics_addr: Option<SocketAddr>,
+    debug: bool,
+) -> Result<()> {
+    preflight(
+        &config,
+        env,
+        &Requirements {
+            ports: &["eth1"],
+            cores: &[0],
+            node_addr: Some(node_addr),
+            rib: RibUse::Serves,
+            workload: false,
+        },
+    )?;
+    let info = Box::leak(Box::new(node_info(
+        "rib",
+        gdp_name_of_index(RIB_INDEX),
+        metadata_of_index(RIB_INDEX),
+        &config,
+        flags,
+    )));
+    print_banner(info);
+    let routes: &'static Routes = Box::leak(Box::new(load_routes(env)?));
+    let storage: &'static dyn RibStorage = Box::leak(open_rib_storage(
+        &load_rib_storage_config().unwrap_or_default(),
+    )?);
+    let restored = load_rib_db(storage, routes)?;
+    println!(
+        "loaded {} live routes from {}",
+        restored,
+        storage.describe()
+    );
+    let identities: &'static PortIdentities = Box::leak(Box::new(load_port_identities()?));
+    let replies = RibReplies::new(load_rib_reply_config().unwrap_or_default());
+    let sync = RibSync::new(
+        load_rib_sync_config().unwrap_or_default(),
+        metadata_of_index(RIB_INDEX),
+        private_key_of_index(RIB_INDEX),
+    );
+    if sync.has_peers() {
+        routes
+            .dynamic_routes
+            .write()
+            .recover()
+            .replica
+            .enable(node_addr);
+    }
+
+    if let Some(port) = control_port {
+        start_control_socket(
+            port,
+            ControlState {
+                flags,
+                info,
+                clock: Clock::new(),
+                store: SharedStore::new(),
+                #[cfg(feature = "switch")]
+                prober: None,
+                #[cfg(feature = "switch")]
+                usage: None,
+            },
+        )?;
+    }
+    if let Some(addr) = metrics_addr {
+        start_metrics_endpoint(addr)?;
+    }
+
+    // the RIB serves on every queue of eth1; what it sends in the background is sent once, from
+    // the first core that polls eth1
+    let control_core = config
+        .ports
+        .iter()
+        .find(|port| port.name == "eth1")
+        .and_then(|port| port.cores.first())
+        .map_or(0, |core| core.raw());
+
+    build_runtime(config, env)?
+        .add_pipeline_to_port("eth1", move |q| {
+            install_gdp_pipeline(
+                q,
+                rib_pipeline("rib", routes, replies, sync, use_default, flags, debug),
+                "prod",
+                node_addr,
+                flags,
+                debug,
+            )
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            subscription_schedule(q, identity, routes, debug)
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            reply_schedule(q, identity, replies, routes, debug)
+        })?
+        .add_pipeline_to_core(control_core, move |q| {
+            let q = q["eth1"].clone();
+            let identity = identities.resolve("eth1", &q, node_addr).unwrap();
+            rib_sync_schedule(q, identity, routes, sync, debug)
+        })?
+        .add_pipeline_to_port("eth1", session_schedule)?
+        .add_periodic_task_to_core(
+            0,
+            move || {
+                if let Err(err) = save_rib_db(storage, routes) {
+                    println!("failed to save the RIB: {:#}", err);
+                }
+            },
+            SAVE_INTERVAL,
+        )?
+        .execute()?;
+    save_rib_db(storage, routes)
+}
@@ -0,0 +1,613 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use capsule::batch::{self, Batch, Pipeline};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use capsule::{metrics, Mbuf, PortQueue};
+use gdp_proto::{GdpAction, GdpName};
+use metrics_runtime::data::Counter;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio_timer::delay_for;
+
+use crate::certificates::{
+    sign_data, verify_data, Certificate, GdpMeta, SerializableSignature, VerifyCert,
+};
+use crate::dtls::{encrypt_gdp, DTls};
+use crate::gdp::Gdp;
+use crate::hardcoded_routes::{gdp_name_of_index, RIB_INDEX};
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::l2filter::VlanTx;
+use crate::packet_ops::get_payload;
+use crate::rib::{create_control_request, create_reply, DynamicRoutes, Routes};
+use crate::ribpayload::record_cert;
+use crate::schedule::Schedule;
+
+/*
+   Several RIBs can serve one deployment, each replicating the routes registered with it to the
+   others, so that no single RIB is one that every switch depends on:
+   - each change to a name's route that a RIB takes (a registration, an announcement, a
+     withdrawal) is stamped with when it took it, by its wall clock, and with the RIB's address.
+     A change with a later stamp wins wherever it arrives, ties going to the higher address (last
+     writer wins). A RIB stamps a change later than the one it replaces whatever its clock says,
+     so that every RIB agrees on the winner, but RIB clocks should still be in sync, as a RIB
+     whose clock is ahead wins against later changes
+   - a RIB keeps the latest change of each name, numbered in the order it kept them, and sends
+     each peer of rib_sync.toml the changes past the last one that the peer acknowledged, in a
+     RibSync of at most MAX_PER_SYNC, every `sync_interval_ms`. The peer applies them and answers
+     with a RibSyncAck of the last; an unacknowledged RibSync is sent again
+   - changes from a peer are kept, and numbered, as any other, so that they reach the RIBs that
+     only the peer syncs with: the peers need to be connected, not a full mesh
+   - withdrawals are kept for TOMBSTONE_TTL, so that a late RibSync does not bring a name back.
+     Routes that expire are not replicated, as every RIB drops them when their certificate does
+   - RibSyncs and their acks are signed with the RIB's key, and only taken from the peers in
+     rib_sync.toml whose signature checks out, as a source address alone is easily forged. That
+     covers withdrawals, which no certificate vouches for. The certificates in a RibSync are also
+     checked against the metadata sent along, as those of a registration are. All of it happens
+     before the routes are locked, so that a flood of RibSyncs does not hold up queries
+   - a RIB numbers its changes from a new epoch each time it starts, so that the acks to its last
+     run are not taken for acks to this one. What it missed meanwhile, its peers send it, as it
+     did not acknowledge it
+   - names that are moving hosts (see registration.rs) are replicated once they have moved
+   A RIB with no peers keeps nothing.
+*/
+
+/// Withdrawals are kept for this long, for RibSyncs that are late
+const TOMBSTONE_TTL: Duration = Duration::from_secs(3600);
+/// Changes per RibSync, so that one fits in a packet with certificates of the usual size
+const MAX_PER_SYNC: usize = 4;
+
+#[derive(Deserialize)]
+pub struct RibSyncConfig {
+    /// The RIBs to replicate routes with
+    #[serde(default)]
+    pub peers: Vec<Ipv4Addr>,
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+}
+
+fn default_sync_interval_ms() -> u64 {
+    200
+}
+
+impl Default for RibSyncConfig {
+    fn default() -> Self {
+        RibSyncConfig {
+            peers: Vec::new(),
+            sync_interval_ms: default_sync_interval_ms(),
+        }
+    }
+}
+
+pub fn load_rib_sync_config() -> Result<RibSyncConfig> {
+    let content = fs::read_to_string("rib_sync.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// A change to the route of a name, as RIBs replicate it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncedRoute {
+    pub name: GdpName,
+    /// When the RIB that took the change took it, in milliseconds since the Unix epoch
+    pub written_ms: u64,
+    /// The RIB that took the change
+    pub writer: Ipv4Addr,
+    /// The name's metadata and route, or None if its route was withdrawn
+    pub route: Option<(GdpMeta, Certificate)>,
+}
+
+impl SyncedRoute {
+    fn stamp(&self) -> (u64, Ipv4Addr) {
+        (self.written_ms, self.writer)
+    }
+}
+
+struct Kept {
+    route: SyncedRoute,
+    version: u64,
+}
+
+/// The latest change to the route of each name, as this RIB replicates them
+pub struct Replica {
+    /// Our address, which stamps the changes we take; None if we have no peers
+    writer: Option<Ipv4Addr>,
+    epoch: u64,
+    next_version: u64,
+    kept: HashMap<GdpName, Kept>,
+    by_version: BTreeMap<u64, GdpName>,
+}
+
+impl Default for Replica {
+    fn default() -> Self {
+        Replica {
+            writer: None,
+            epoch: rand::thread_rng().gen(),
+            next_version: 1,
+            kept: HashMap::new(),
+            by_version: BTreeMap::new(),
+        }
+    }
+}
+
+impl Replica {
+    /// Keep the changes we take from now on, stamped with `writer`
+    pub fn enable(&mut self, writer: Ipv4Addr) {
+        self.writer = Some(writer);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    fn keep(&mut self, route: SyncedRoute) {
+        if let Some(old) = self.kept.remove(&route.name) {
+            self.by_version.remove(&old.version);
+        }
+        let version = self.next_version;
+        self.next_version += 1;
+        self.by_version.insert(version, route.name);
+        self.kept.insert(route.name, Kept { route, version });
+    }
+
+    /// Keep a change that this RIB took, or None if its route was withdrawn
+    pub fn record(&mut self, name: GdpName, route: Option<(GdpMeta, Certificate)>) {
+        let writer = match self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        let written_ms = match self.kept.get(&name) {
+            Some(kept) => now_ms().max(kept.route.written_ms.saturating_add(1)),
+            None => now_ms(),
+        };
+        self.keep(SyncedRoute {
+            name,
+            written_ms,
+            writer,
+            route,
+        });
+    }
+
+    /// Keep a peer's change if it is later than the one kept for its name, returning whether
+    fn accept(&mut self, route: &SyncedRoute) -> bool {
+        if let Some(kept) = self.kept.get(&route.name) {
+            if kept.route.stamp() >= route.stamp() {
+                return false;
+            }
+        }
+        self.keep(route.clone());
+        true
+    }
+
+    /// Up to `max` of the changes kept after `version`, oldest first
+    fn since(&self, version: u64, max: usize) -> Vec<(u64, SyncedRoute)> {
+        self.by_version
+            .range(version + 1..)
+            .take(max)
+            .map(|(version, name)| (*version, self.kept[name].route.clone()))
+            .collect()
+    }
+
+    /// Forget the withdrawals older than TOMBSTONE_TTL, and the routes that expired
+    fn prune(&mut self, now_ms: u64) {
+        let tombstone_ttl = TOMBSTONE_TTL.as_millis() as u64;
+        let by_version = &mut self.by_version;
+        self.kept.retain(|_, kept| {
+            let live = match &kept.route.route {
+                Some((_, cert)) => cert.contents.expiration_time().saturating_mul(1000) > now_ms,
+                None => kept.route.written_ms.saturating_add(tombstone_ttl) > now_ms,
+            };
+            if !live {
+                by_version.remove(&kept.version);
+            }
+            live
+        });
+    }
+}
+
+/// Check that a peer's change carries a route of its own name, signed by it
+fn check(route: &SyncedRoute) -> Result<()> {
+    if let Some((meta, cert)) = &route.route {
+        ensure!(
+            meta.hash() == route.name && *cert.contents.owner() == route.name,
+            "change to {:?} carries the route of another name",
+            route.name
+        );
+        cert.verify(meta)?;
+    }
+    Ok(())
+}
+
+/// Apply a peer's change that passed `check` to `dynamic_routes`, if it is later than ours,
+/// returning whether
+fn apply(route: &SyncedRoute, dynamic_routes: &mut DynamicRoutes) -> bool {
+    if !dynamic_routes.replica.accept(route) {
+        return false;
+    }
+    match &route.route {
+        Some((meta, cert)) => {
+            dynamic_routes.metadata.insert(route.name, *meta);
+            record_cert(cert.clone(), *meta, dynamic_routes);
+        }
+        None => dynamic_routes.withdraw(route.name),
+    }
+    true
+}
+
+/// A RibSync or RibSyncAck, signed with the key of the RIB that sends it
+#[derive(Serialize, Deserialize)]
+struct Signed<T> {
+    message: T,
+    signature: SerializableSignature,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SyncMessage {
+    epoch: u64,
+    changes: Vec<(u64, SyncedRoute)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SyncAck {
+    /// That of the RibSync acknowledged
+    epoch: u64,
+    /// The last change of the RibSync
+    through: u64,
+}
+
+struct SyncState {
+    config: RibSyncConfig,
+    /// Our metadata and key, which are those of every RIB
+    meta: GdpMeta,
+    private_key: [u8; 32],
+    /// The last of our changes that each peer acknowledged
+    acked: Mutex<HashMap<Ipv4Addr, u64>>,
+    sent: Counter,
+    applied: Counter,
+    stale: Counter,
+    refused: Counter,
+}
+
+/// Replicates the RIB's routes with its peers. Shared by all cores of a RIB.
+#[derive(Clone, Copy)]
+pub struct RibSync(&'static SyncState);
+
+impl RibSync {
+    pub fn new(config: RibSyncConfig, meta: GdpMeta, private_key: [u8; 32]) -> Self {
+        let mut sink = metrics::global().sink();
+        let mut outcomes = |outcome: &'static str| {
+            sink.counter_with_labels("rib_sync.changes", vec![("outcome", outcome)])
+        };
+        RibSync(Box::leak(Box::new(SyncState {
+            config,
+            meta,
+            private_key,
+            acked: Mutex::new(HashMap::new()),
+            sent: outcomes("sent"),
+            applied: outcomes("applied"),
+            stale: outcomes("stale"),
+            refused: outcomes("refused"),
+        })))
+    }
+
+    pub fn has_peers(&self) -> bool {
+        !self.0.config.peers.is_empty()
+    }
+
+    fn is_peer(&self, packet: &Gdp<DTls<Ipv4>>) -> bool {
+        let src = packet.envelope().envelope().envelope().src();
+        self.0.config.peers.contains(&src)
+    }
+
+    fn sign<T: Serialize>(&self, message: T) -> Result<Signed<T>> {
+        let signature = sign_data(&message, self.0.private_key)?;
+        Ok(Signed { message, signature })
+    }
+
+    /// The message of a peer's packet, if a RIB signed it
+    fn open<T: Serialize + DeserializeOwned>(&self, packet: &Gdp<DTls<Ipv4>>) -> Result<T> {
+        let Signed { message, signature } = bincode::deserialize(get_payload(packet)?)?;
+        verify_data(&message, signature, &self.0.meta)?;
+        Ok(message)
+    }
+
+    /// Apply the changes of a peer's RibSync, answering with a RibSyncAck; None for a RibSync
+    /// from a RIB that is not our peer
+    pub fn handle_sync(
+        &self,
+        packet: &Gdp<DTls<Ipv4>>,
+        routes: &Routes,
+        debug: bool,
+    ) -> Result<Option<Gdp<DTls<Ipv4>>>> {
+        if !self.is_peer(packet) {
+            self.0.refused.increment();
+            return Ok(None);
+        }
+        let SyncMessage { epoch, changes } = match self.open(packet) {
+            Ok(message) => message,
+            Err(err) => {
+                println!("RIB refused a RibSync: {:#}", err);
+                self.0.refused.increment();
+                return Ok(None);
+            }
+        };
+        let through = match changes.last() {
+            Some((version, _)) => *version,
+            None => return Ok(None),
+        };
+        // checked before the routes are locked; those refused are acknowledged all the same, as
+        // they would be refused again
+        let checked = changes
+            .iter()
+            .map(|(_, route)| route)
+            .filter(|route| match check(route) {
+                Ok(()) => true,
+                Err(err) => {
+                    println!("RIB refused the route of {:?}: {:#}", route.name, err);
+                    self.0.refused.increment();
+                    false
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut dynamic_routes = routes.dynamic_routes.write().recover();
+        for route in checked {
+            if apply(route, &mut dynamic_routes) {
+                if debug {
+                    println!("RIB replicated the route of {:?}", route.name);
+                }
+                self.0.applied.increment();
+            } else {
+                self.0.stale.increment();
+            }
+        }
+        drop(dynamic_routes);
+        let ack = self.sign(SyncAck { epoch, through })?;
+        Ok(Some(create_reply(
+            packet,
+            GdpAction::RibSyncAck,
+            &bincode::serialize(&ack)?,
+        )?))
+    }
+
+    /// Move on past the changes a peer acknowledged
+    pub fn handle_ack(&self, packet: &Gdp<DTls<Ipv4>>, routes: &Routes) -> Result<()> {
+        if !self.is_peer(packet) {
+            return Ok(());
+        }
+        let SyncAck { epoch, through } = self.open(packet)?;
+        if epoch != routes.dynamic_routes.read().recover().replica.epoch {
+            return Ok(());
+        }
+        let peer = packet.envelope().envelope().envelope().src();
+        let mut acked = self.0.acked.lock().recover();
+        let acked = acked.entry(peer).or_insert(0);
+        *acked = (*acked).max(through);
+        Ok(())
+    }
+
+    /// The RibSync due to each peer, if it has changes to catch up on
+    fn take_due(&self, routes: &Routes) -> Vec<(Ipv4Addr, SyncMessage)> {
+        let mut dynamic_routes = routes.dynamic_routes.write().recover();
+        dynamic_routes.replica.prune(now_ms());
+        let acked = self.0.acked.lock().recover();
+        self.0
+            .config
+            .peers
+            .iter()
+            .map(|peer| {
+                let since = acked.get(peer).copied().unwrap_or(0);
+                let message = SyncMessage {
+                    epoch: dynamic_routes.replica.epoch,
+                    changes: dynamic_routes.replica.since(since, MAX_PER_SYNC),
+                };
+                (*peer, message)
+            })
+            .filter(|(_, message)| !message.changes.is_empty())
+            .collect()
+    }
+}
+
+fn send_sync(q: &PortQueue, src: PortIdentity, peer: Ipv4Addr, message: &Signed<SyncMessage>) {
+    batch::poll_fn(|| Mbuf::alloc_bulk(1).unwrap())
+        .map(move |packet| {
+            create_control_request(
+                packet,
+                GdpAction::RibSync,
+                &bincode::serialize(message)?,
+                src.mac,
+                src.ip,
+                gdp_name_of_index(RIB_INDEX),
+                peer,
+            )
+        })
+        .map(|packet| Ok(packet.deparse()))
+        .map(encrypt_gdp)
+        .send(VlanTx::new(q.clone()))
+        .run_once();
+}
+
+/// Send our peers the changes they have not acknowledged yet, in the background of the RIB
+/// pipeline
+pub fn rib_sync_schedule(
+    q: PortQueue,
+    src: PortIdentity,
+    routes: &'static Routes,
+    sync: RibSync,
+    debug: bool,
+) -> impl Pipeline {
+    let interval = Duration::from_millis(sync.0.config.sync_interval_ms);
+    Schedule::new("rib_sync", async move {
+        loop {
+            for (peer, message) in sync.take_due(routes) {
+                if debug {
+                    println!("RIB syncing {} changes to {}", message.changes.len(), peer);
+                }
+                let changes = message.changes.len() as u64;
+                match sync.sign(message) {
+                    Ok(signed) => {
+                        send_sync(&q, src, peer, &signed);
+                        sync.0.sent.record(changes);
+                    }
+                    Err(err) => println!("RIB failed to sign a RibSync: {:#}", err),
+                }
+            }
+            delay_for(interval).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificates::{CertDest, NewRtCert, RtCert};
+    use crate::hardcoded_routes::{metadata_of_index, private_key_of_index};
+
+    const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn enabled() -> Replica {
+        let mut replica = Replica::default();
+        replica.enable(US);
+        replica
+    }
+
+    fn route_of(index: u8, signer: u8) -> Option<(GdpMeta, Certificate)> {
+        let meta = metadata_of_index(index);
+        let cert = RtCert::new_wrapped(
+            meta,
+            private_key_of_index(signer),
+            CertDest::IpAddr(US),
+            true,
+        )
+        .unwrap();
+        Some((meta, cert))
+    }
+
+    fn change(index: u8, written_ms: u64, writer: Ipv4Addr) -> SyncedRoute {
+        SyncedRoute {
+            name: metadata_of_index(index).hash(),
+            written_ms,
+            writer,
+            route: route_of(index, index),
+        }
+    }
+
+    #[test]
+    fn keeps_nothing_without_peers() {
+        let mut replica = Replica::default();
+        replica.record(metadata_of_index(1).hash(), route_of(1, 1));
+        assert!(replica.since(0, MAX_PER_SYNC).is_empty());
+    }
+
+    #[test]
+    fn stamps_our_changes_after_the_ones_they_replace() {
+        let mut replica = enabled();
+        // a peer's clock is far ahead of ours
+        assert!(replica.accept(&change(1, u64::MAX - 1, PEER)));
+        replica.record(metadata_of_index(1).hash(), None);
+        let changes = replica.since(0, MAX_PER_SYNC);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].1.stamp(), (u64::MAX, US));
+        // and does not overflow when there is nothing later left
+        replica.record(metadata_of_index(1).hash(), None);
+        assert_eq!(replica.since(0, MAX_PER_SYNC)[0].1.stamp(), (u64::MAX, US));
+    }
+
+    #[test]
+    fn the_last_writer_wins() {
+        let mut replica = enabled();
+        assert!(replica.accept(&change(1, 1000, PEER)));
+        assert!(!replica.accept(&change(1, 999, PEER)));
+        assert!(!replica.accept(&change(1, 1000, PEER)));
+        // ties go to the higher address
+        assert!(!replica.accept(&change(1, 1000, US)));
+        assert!(replica.accept(&change(1, 1000, Ipv4Addr::new(10, 0, 0, 3))));
+        assert!(replica.accept(&change(1, 1001, US)));
+    }
+
+    #[test]
+    fn sends_changes_in_the_order_they_were_kept() {
+        let mut replica = enabled();
+        for index in 1..=3 {
+            assert!(replica.accept(&change(index, 1000, PEER)));
+        }
+        // a name changed again moves to the end
+        assert!(replica.accept(&change(1, 2000, PEER)));
+        let names = |changes: Vec<(u64, SyncedRoute)>| {
+            changes
+                .into_iter()
+                .map(|(_, route)| route.name)
+                .collect::<Vec<_>>()
+        };
+        let name = |index| metadata_of_index(index).hash();
+        assert_eq!(names(replica.since(0, 2)), vec![name(2), name(3)]);
+        let (through, _) = replica.since(0, 2)[1];
+        assert_eq!(names(replica.since(through, MAX_PER_SYNC)), vec![name(1)]);
+    }
+
+    #[test]
+    fn forgets_old_withdrawals_and_expired_routes() {
+        let mut replica = enabled();
+        let now_ms = now_ms();
+        let tombstone_ttl = TOMBSTONE_TTL.as_millis() as u64;
+        let mut old_withdrawal = change(1, now_ms - tombstone_ttl - 1, PEER);
+        old_withdrawal.route = None;
+        let mut recent_withdrawal = change(2, now_ms, PEER);
+        recent_withdrawal.route = None;
+        // stamped so late that the tombstone would overflow
+        let mut future_withdrawal = change(3, u64::MAX, PEER);
+        future_withdrawal.route = None;
+        for route in [&old_withdrawal, &recent_withdrawal, &future_withdrawal] {
+            assert!(replica.accept(route));
+        }
+        assert!(replica.accept(&change(4, now_ms, PEER)));
+
+        replica.prune(now_ms);
+        let mut kept = replica
+            .since(0, MAX_PER_SYNC)
+            .into_iter()
+            .map(|(_, route)| route.name)
+            .collect::<Vec<_>>();
+        kept.sort_unstable();
+        let mut expected = [2, 3, 4]
+            .map(|index| metadata_of_index(index).hash())
+            .to_vec();
+        expected.sort_unstable();
+        assert_eq!(kept, expected);
+
+        // once its certificate has expired, the route goes too
+        let expired_ms = change(4, 0, PEER)
+            .route
+            .unwrap()
+            .1
+            .contents
+            .expiration_time()
+            * 1000;
+        replica.prune(expired_ms);
+        assert!(!replica.kept.contains_key(&metadata_of_index(4).hash()));
+    }
+
+    #[test]
+    fn refuses_routes_of_other_names() {
+        assert!(check(&change(1, 0, PEER)).is_ok());
+        let mut forged = change(1, 0, PEER);
+        forged.route = route_of(1, 2);
+        assert!(check(&forged).is_err());
+        let mut misfiled = change(1, 0, PEER);
+        misfiled.name = metadata_of_index(2).hash();
+        assert!(check(&misfiled).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Instant;
+
+use capsule::batch::{Batch, Disposition};
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::{Packet, Udp};
+
+// default key used by most NICs for RSS, so our hash matches the queue the hardware picked
+const RSS_KEY: [u8; 40] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0,
+    0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c,
+    0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+];
+
+/// Underlay details of how a packet reached us
+#[derive(Clone, Copy, Debug)]
+pub struct RxMeta {
+    pub port: &'static str,
+    pub queue: u16,
+    /// When the burst containing this packet was pulled from the RX queue
+    pub rx_timestamp: Instant,
+    /// Packets pulled from the RX queue in the same burst (a lower bound on its depth at the time)
+    pub burst_len: u16,
+    /// Toeplitz hash of the IPv4/UDP 4-tuple, as computed by the NIC for RSS
+    pub rss_hash: u32,
+    /// The 802.1Q VLAN the frame was tagged with, if any
+    pub vlan: Option<u16>,
+}
+
+fn toeplitz_hash(input: &[u8]) -> u32 {
+    let mut hash = 0u32;
+    let mut window = u32::from_be_bytes([RSS_KEY[0], RSS_KEY[1], RSS_KEY[2], RSS_KEY[3]]);
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            let next_key_bit = RSS_KEY
+                .get(i + 4)
+                .map_or(0, |key_byte| (key_byte >> (7 - bit)) & 1);
+            window = (window << 1) | next_key_bit as u32;
+        }
+    }
+    hash
+}
+
+pub fn rss_hash(udp: &Udp<Ipv4>) -> u32 {
+    let ipv4 = udp.envelope();
+    let mut input = [0u8; 12];
+    input[..4].copy_from_slice(&ipv4.src().octets());
+    input[4..8].copy_from_slice(&ipv4.dst().octets());
+    input[8..10].copy_from_slice(&udp.src_port().to_be_bytes());
+    input[10..].copy_from_slice(&udp.dst_port().to_be_bytes());
+    toeplitz_hash(&input)
+}
+
+/// Queues are numbered in the order their pipelines are installed, across all ports
+pub fn next_queue_id() -> u16 {
+    static NEXT_QUEUE: AtomicU16 = AtomicU16::new(0);
+    NEXT_QUEUE.fetch_add(1, Ordering::Relaxed)
+}
+
+struct Burst {
+    time: Cell<Instant>,
+    len: Cell<u16>,
+}
+
+/// Shared with later stages of the same pipeline, which read the time and size of the current burst
+#[derive(Clone)]
+pub struct RxClock(Rc<Burst>);
+
+impl RxClock {
+    pub fn new() -> Self {
+        RxClock(Rc::new(Burst {
+            time: Cell::new(Instant::now()),
+            len: Cell::new(0),
+        }))
+    }
+
+    pub fn burst_time(&self) -> Instant {
+        self.0.time.get()
+    }
+
+    /// Packets pulled so far in the current burst
+    pub fn burst_len(&self) -> u16 {
+        self.0.len.get()
+    }
+
+    /// Start a burst of `len` packets, as RxStamp does when it pulls one
+    #[cfg(test)]
+    pub fn pull(&self, len: u16) {
+        self.0
+            .time
+            .set(self.0.time.get() + std::time::Duration::from_nanos(1));
+        self.0.len.set(len);
+    }
+}
+
+/// Records when each burst is pulled from the wrapped batch
+pub struct RxStamp<B: Batch> {
+    batch: B,
+    clock: RxClock,
+}
+
+impl<B: Batch> Batch for RxStamp<B> {
+    type Item = B::Item;
+
+    #[inline]
+    fn replenish(&mut self) {
+        self.batch.replenish();
+        self.clock.0.time.set(Instant::now());
+        self.clock.0.len.set(0);
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        let next = self.batch.next();
+        if next.is_some() {
+            let len = &self.clock.0.len;
+            len.set(len.get().saturating_add(1));
+        }
+        next
+    }
+}
+
+pub trait StampRx: Batch + Sized {
+    fn stamp_rx(self, clock: RxClock) -> RxStamp<Self> {
+        RxStamp { batch: self, clock }
+    }
+}
+
+impl<T: Batch> StampRx for T {}
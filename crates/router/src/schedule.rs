@@ -35,3 +35,23 @@ impl<T: Future<Output = ()>> Future for Schedule<'_, T> {
         self.project().future.as_mut().poll(cx)
     }
 }
+
+/// Resolves on the next poll, so that a schedule lets the core's pipelines run before it goes on
+pub fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+pub struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
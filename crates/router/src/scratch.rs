@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use std::mem;
+
+use anyhow::Result;
+use capsule::batch::{Batch, Disposition};
+use serde::Serialize;
+
+/*
+   Handlers that serialize something on the packet path (RIB replies, certificate blocks) do it
+   in a per-core scratch arena rather than a fresh Vec from the global allocator:
+   - `with_serialized` bumps the arena by the size of the value and hands the bytes to the
+     handler, which copies them into its packet
+   - the arena is only emptied when the pipeline pulls its next burst (see `reset_scratch`), by
+     which time the last one has been sent. It keeps its capacity, so once it has grown to what
+     a burst needs, serializing allocates nothing; past MAX_RETAINED it is shrunk back, so that
+     one burst of huge replies does not pin the memory forever
+   - code that runs outside a pipeline (schedules, workloads) has nothing to reset its arena,
+     so an arena that reaches MAX_RETAINED is emptied before it is used again
+   - a handler that serializes while another is still using the arena (a nested call) gets a
+     Vec of its own, as before
+*/
+
+/// The most the arena keeps allocated between bursts
+const MAX_RETAINED: usize = 256 * 1024;
+
+thread_local! {
+    /// The scratch arena of this core's pipeline
+    static SCRATCH: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// Serialize `value` into this core's scratch arena, and pass the bytes to `f`
+pub fn with_serialized<T, R>(value: &T, f: impl FnOnce(&[u8]) -> Result<R>) -> Result<R>
+where
+    T: Serialize + ?Sized,
+{
+    // taken out while in use, so that a nested call finds it empty rather than borrowed
+    let mut arena = SCRATCH.with(|scratch| mem::take(&mut *scratch.borrow_mut()));
+    if arena.len() >= MAX_RETAINED {
+        // nothing resets the arena of code outside a pipeline, and the bytes never outlive `f`
+        arena.clear();
+    }
+    let start = arena.len();
+    let result = match bincode::serialize_into(&mut arena, value) {
+        Ok(()) => f(&arena[start..]),
+        Err(err) => Err(err.into()),
+    };
+    SCRATCH.with(|scratch| *scratch.borrow_mut() = arena);
+    result
+}
+
+/// Empties this core's scratch arena whenever the wrapped batch pulls a burst
+pub struct ResetScratch<B: Batch> {
+    batch: B,
+}
+
+impl<B: Batch> Batch for ResetScratch<B> {
+    type Item = B::Item;
+
+    #[inline]
+    fn replenish(&mut self) {
+        SCRATCH.with(|scratch| {
+            let mut arena = scratch.borrow_mut();
+            arena.clear();
+            arena.shrink_to(MAX_RETAINED);
+        });
+        self.batch.replenish();
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Disposition<Self::Item>> {
+        self.batch.next()
+    }
+}
+
+pub trait ScratchBatch: Batch + Sized {
+    fn reset_scratch(self) -> ResetScratch<Self> {
+        ResetScratch { batch: self }
+    }
+}
+
+impl<T: Batch> ScratchBatch for T {}
@@ -0,0 +1,127 @@
+use std::process::Command;
+use std::{env, fs};
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::Deserialize;
+
+/*
+    Secret values in secrets.toml may be given in plaintext (hex) or encrypted
+    under a 32-byte master key, SOPS-style:
+
+        dtls_key = "ENC[AES256_GCM,data:<hex ciphertext>,iv:<hex 96-bit nonce>]"
+
+    The master key is only looked up if some value is encrypted. It is read from
+    the file named by $GDP_MASTER_KEY_FILE (default master.key), falling back to
+    the "gdp-master-key" user key in the kernel keyring.
+*/
+const SECRETS_FILE: &str = "secrets.toml";
+const DEFAULT_MASTER_KEY_FILE: &str = "master.key";
+const KEYRING_ENTRY: &str = "%user:gdp-master-key";
+
+#[derive(Deserialize, Default)]
+struct SerializedSecrets {
+    dtls_key: Option<String>,
+    operator_key: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Secrets {
+    pub dtls_key: Option<[u8; 32]>,
+    /// The key that fleet tokens are signed with, only on the operator's machine
+    pub operator_key: Option<[u8; 32]>,
+}
+
+pub fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+    ensure!(s.len() % 2 == 0, "hex string has odd length");
+    ensure!(
+        s.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        "invalid hex string"
+    );
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("invalid hex string"))
+        .collect()
+}
+
+fn to_key(bytes: Vec<u8>) -> Result<[u8; 32]> {
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow!("expected a 32-byte key, found {} bytes", bytes.len()))
+}
+
+fn master_key() -> Result<[u8; 32]> {
+    let path =
+        env::var("GDP_MASTER_KEY_FILE").unwrap_or_else(|_| DEFAULT_MASTER_KEY_FILE.to_owned());
+    if let Ok(content) = fs::read_to_string(&path) {
+        return to_key(decode_hex(&content)?).context("invalid master key file");
+    }
+    let output = Command::new("keyctl")
+        .args(["pipe", KEYRING_ENTRY])
+        .output()
+        .context("master key file not found and keyring is unavailable")?;
+    ensure!(
+        output.status.success(),
+        "master key not found in {} or the keyring",
+        path
+    );
+    to_key(output.stdout).context("invalid master key in keyring")
+}
+
+struct MasterKey(Option<[u8; 32]>);
+
+impl MasterKey {
+    fn get(&mut self) -> Result<[u8; 32]> {
+        match self.0 {
+            Some(key) => Ok(key),
+            None => {
+                let key = master_key()?;
+                self.0 = Some(key);
+                Ok(key)
+            }
+        }
+    }
+}
+
+fn parse_encrypted(value: &str) -> Option<(&str, &str)> {
+    let fields = value.strip_prefix("ENC[AES256_GCM,")?.strip_suffix(']')?;
+    let (data, iv) = fields.split_once(',')?;
+    Some((data.strip_prefix("data:")?, iv.strip_prefix("iv:")?))
+}
+
+fn resolve(value: &str, master: &mut MasterKey) -> Result<Vec<u8>> {
+    if !value.starts_with("ENC[") {
+        return decode_hex(value);
+    }
+    let (data, iv) = parse_encrypted(value).ok_or_else(|| anyhow!("malformed encrypted value"))?;
+    let iv = decode_hex(iv)?;
+    ensure!(iv.len() == 12, "encrypted value must use a 96-bit iv");
+    let key = master.get()?;
+    Aes256Gcm::new(Key::from_slice(&key))
+        .decrypt(Nonce::from_slice(&iv), decode_hex(data)?.as_ref())
+        .map_err(|_| anyhow!("failed to decrypt secret (wrong master key?)"))
+}
+
+pub fn load_secrets() -> Result<Secrets> {
+    let serialized: SerializedSecrets = match fs::read_to_string(SECRETS_FILE) {
+        Ok(content) => toml::from_str(&content)?,
+        Err(_) => return Ok(Secrets::default()),
+    };
+    let mut master = MasterKey(None);
+    let dtls_key = match serialized.dtls_key {
+        Some(value) => Some(to_key(resolve(&value, &mut master).context("dtls_key")?)?),
+        None => None,
+    };
+    let operator_key = match serialized.operator_key {
+        Some(value) => Some(to_key(
+            resolve(&value, &mut master).context("operator_key")?,
+        )?),
+        None => None,
+    };
+    Ok(Secrets {
+        dtls_key,
+        operator_key,
+    })
+}
@@ -1,4 +1,4 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
 use std::sync::{Arc, RwLock};
 
 use anyhow::{anyhow, Context, Result};
@@ -8,26 +8,39 @@ use capsule::net::MacAddr;
 use capsule::packets::ip::v4::Ipv4;
 use capsule::packets::{Ethernet, Packet, Udp};
 use capsule::{Mbuf, PortQueue};
-use gdp_client::{
-    ClientCommand, ClientCommands, ClientResponse, ClientResponses, GdpAction, GdpName,
+use gdp_proto::{
+    ClientCommand, ClientCommands, ClientResponse, ClientResponses, GdpAction, GdpName, NackReason,
 };
 use tokio::sync::Barrier;
 
-use crate::certificates::{check_packet_certificates, CertDest, GdpMeta, RtCert};
+use crate::certificates::{check_packet_certificates, CertDest, GdpMeta, NewRtCert, RtCert};
+use crate::clientflows::client_flows;
+use crate::clock::Clock;
+use crate::control::{start_control_socket, ControlState};
+use crate::dtls::session::{accept_handshake, session_schedule};
+use crate::dtls::transport::parse_dtls;
 use crate::dtls::{decrypt_gdp, encrypt_gdp, DTls};
+use crate::flags::FeatureFlags;
 use crate::gdp::{CertificateBlock, Gdp};
 use crate::gdpbatch::GdpBatch;
 use crate::hardcoded_routes::{
     gdp_name_of_index, metadata_of_index, private_key_of_index, WithBroadcast,
 };
-use crate::kvs::{SharedStore, Store};
+use crate::identity::{load_port_identities, PortIdentities, PortIdentity};
+use crate::info::{node_info, print_banner};
+use crate::isolation::CatchPanics;
+use crate::kvs::{SharedStore, Store, PUBLISH_INTERVAL};
 use crate::packet_logging::{LogArrive, LogFail};
 use crate::packet_ops::{get_payload, set_payload};
-use crate::rib::{create_rib_request, handle_rib_reply, send_rib_query, RIB_PORT};
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::probe::answer_echo;
+use crate::registration::register_node;
+use crate::rib::{create_rib_request, handle_rib_reply, send_rib_request, RIB_PORT};
 use crate::ribpayload::RibQuery;
 use crate::runtime::build_runtime;
 use crate::schedule::Schedule;
 use crate::switch::{bounce_gdp, bounce_udp, forward_gdp};
+use crate::telemetry::TelemetryExport;
 use crate::{pipeline, Env};
 
 // needed since otherwise we'd be using the broadcast IP
@@ -37,8 +50,11 @@ const INTERNAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(172, 18, 0, 254));
 fn execute_command(
     src_mac: MacAddr,
     src_ip: Ipv4Addr,
+    src_port: u16,
     command: &ClientCommand,
     state: &SidecarState,
+    store: Store,
+    request_meta: &dyn Fn(GdpName),
 ) -> ClientResponse<'static> {
     match command {
         ClientCommand::SetPort { port } => match state.listen_addr.write() {
@@ -50,6 +66,38 @@ fn execute_command(
                 msg: "port setting failed (unable to acquire lock)".into(),
             },
         },
+        ClientCommand::ResolveName { name } => {
+            let pub_key = store
+                .gdp_metadata
+                .get_unchecked(name)
+                .map(|meta| meta.pub_key);
+            if pub_key.is_none() {
+                // the client will ask again once the RIB has had a chance to reply
+                request_meta(*name);
+            }
+            ClientResponse::Binding {
+                name: *name,
+                pub_key,
+            }
+        }
+        ClientCommand::ReportFlow { report } => {
+            client_flows().record(SocketAddrV4::new(src_ip, src_port), *report, 0);
+            ClientResponse::FlowReported
+        }
+        ClientCommand::ReportPacedFlow {
+            report,
+            paced_stalls,
+        } => {
+            let client = SocketAddrV4::new(src_ip, src_port);
+            client_flows().record(client, *report, *paced_stalls);
+            ClientResponse::FlowReported
+        }
+        ClientCommand::DumpClientFlows => ClientResponse::ClientFlows {
+            flows: client_flows().dump(),
+        },
+        _ => ClientResponse::Error {
+            msg: "this command is only supported by the switch control socket".into(),
+        },
     }
 }
 
@@ -57,9 +105,28 @@ struct SidecarState {
     listen_addr: RwLock<(MacAddr, Ipv4Addr, u16)>,
 }
 
+/// Address `packet` to the client listening behind this sidecar
+fn redirect_to_listener(packet: &mut Gdp<DTls<Ipv4>>, state: &SidecarState) -> Result<()> {
+    let (mac, ip, port) = *state
+        .listen_addr
+        .read()
+        .map_err(|_| anyhow!("failed to unlock listen addr"))?;
+
+    let udp = packet.envelope_mut().envelope_mut();
+    udp.set_src_ip(INTERNAL_IP)?;
+    udp.set_dst_ip(ip.into())?;
+    udp.set_src_port(25000);
+    udp.set_dst_port(port);
+
+    let ethernet = udp.envelope_mut().envelope_mut();
+    ethernet.set_src(mac);
+    ethernet.set_dst(mac);
+    Ok(())
+}
+
 fn incoming_sidecar_pipeline(
     q: PortQueue,
-    node_addr: Ipv4Addr,
+    identity: PortIdentity,
     switch_ip: Ipv4Addr,
     gdp_name: GdpName,
     name: &'static str,
@@ -69,16 +136,23 @@ fn incoming_sidecar_pipeline(
 ) -> impl Batch {
     // our responsibility is to validate the certificates, strip GDP headers, and forward to the receiver
     // at this stage, incoming packets have been decrypted and spurious packets discarded
+    let telemetry = TelemetryExport::new(name, debug);
+    let echo_q = q.clone();
+    let handshake_q = q.clone();
     Poll::new(q.clone())
         .map(|packet| packet.parse::<Ethernet>()?.parse::<Ipv4>())
-        .filter(move |packet| packet.dst() == node_addr)
+        .filter(move |packet| packet.dst() == identity.ip)
         .map(|packet| packet.parse::<Udp<Ipv4>>())
-        .map(|packet| packet.parse::<DTls<Ipv4>>())
+        .map(parse_dtls)
+        .filter(move |packet| accept_handshake(packet, &handshake_q, debug))
         .map(decrypt_gdp)
         .map(|packet| packet.parse::<Gdp<DTls<Ipv4>>>())
         .logarrive(name, "incoming", debug)
         .group_by(
-            |packet| packet.action().unwrap_or(GdpAction::Noop),
+            |packet| match packet.action() {
+                Ok(GdpAction::Put | GdpAction::Ack) => GdpAction::Forward,
+                action => action.unwrap_or(GdpAction::Noop),
+            },
             pipeline! {
                 GdpAction::Forward => |group| {
                     group
@@ -91,21 +165,9 @@ fn incoming_sidecar_pipeline(
                             true => |group| {
                                 // certificates look good, redirect to listener
                                 group.map(move |mut packet| {
-                                    let (mac, ip, port) = *state
-                                        .listen_addr
-                                        .read()
-                                        .map_err(|_| anyhow!("failed to unlock listen addr"))?;
-
-                                    let udp = packet.envelope_mut().envelope_mut();
-                                    udp.set_src_ip(INTERNAL_IP)?;
-                                    udp.set_dst_ip(ip.into())?;
-                                    udp.set_src_port(25000);
-                                    udp.set_dst_port(port);
-
-                                    let ethernet = udp.envelope_mut().envelope_mut();
-                                    ethernet.set_src(mac);
-                                    ethernet.set_dst(mac);
-
+                                    // the client gets the packet as sent, without the path telemetry
+                                    telemetry.export(&mut packet)?;
+                                    redirect_to_listener(&mut packet, state)?;
                                     Ok(packet)
                                 })
                             },
@@ -113,16 +175,14 @@ fn incoming_sidecar_pipeline(
                                 // certificates not verified, query RIB for missing data
                                 group
                                     .inject(move |packet| {
-                                        let src_ip = packet.envelope().envelope().envelope().dst();
-                                        let src_mac = packet.envelope().envelope().envelope().envelope().dst();
                                         let mut unknown_names = Vec::new();
                                         check_packet_certificates(gdp_name, packet, &store, Some(&mut unknown_names), name, debug);
                                         if debug {
                                             println!("{} querying RIB for metas {:?}", name, packet.dst());
                                         }
-                                        create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&unknown_names), src_mac, src_ip, gdp_name, switch_ip)
+                                        create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&unknown_names), identity.mac, identity.ip, gdp_name, switch_ip)
                                     })
-                                    .map(bounce_gdp)
+                                    .map(|packet| bounce_gdp(packet, NackReason::BadCert))
                                     .map(|packet| Ok(packet.deparse()))
                                     .map(encrypt_gdp)
                                     .emit(q)
@@ -130,10 +190,39 @@ fn incoming_sidecar_pipeline(
                             }
                         })
                 },
+                GdpAction::Fin => |group| {
+                    // a peer ending its flow to the client; one that does not check out is
+                    // dropped, as it is not worth a NACK
+                    group
+                        .filter(move |packet| {
+                            check_packet_certificates(gdp_name, packet, &store, None, name, debug)
+                        })
+                        .map(move |mut packet| {
+                            redirect_to_listener(&mut packet, state)?;
+                            Ok(packet)
+                        })
+                },
+                GdpAction::GetReply => |group| {
+                    // a switch's status, which the switch does not sign, as it answers from a
+                    // name of its own (see status.rs in the proto crate)
+                    group.map(move |mut packet| {
+                        redirect_to_listener(&mut packet, state)?;
+                        Ok(packet)
+                    })
+                },
                 GdpAction::RibReply => |group| {
                     group
-                        .for_each(move |packet| handle_rib_reply(packet, store, debug))
+                        .for_each(move |packet| handle_rib_reply(packet, store, debug).map(drop))
                         .filter(move |_| false)
+                },
+                GdpAction::Echo => |group| {
+                    // a route to the client ends here, so it is reachable if the probe was for it
+                    group
+                        .replace(move |packet| answer_echo(packet, packet.dst() == gdp_name))
+                        .map(|packet| Ok(packet.deparse()))
+                        .map(encrypt_gdp)
+                        .emit(echo_q)
+                        .replace(|_| unreachable!())
                 }
             },
         )
@@ -143,6 +232,7 @@ fn incoming_sidecar_pipeline(
             packet.reconcile_all();
             Ok(packet)
         })
+        .catch_panics(name, "incoming")
 }
 
 fn outgoing_sidecar_pipeline(
@@ -151,10 +241,11 @@ fn outgoing_sidecar_pipeline(
     meta: GdpMeta,
     private_key: [u8; 32],
     name: &'static str,
-    node_ip: Ipv4Addr,
-    node_mac: MacAddr,
+    identity: PortIdentity,
     switch_ip: Ipv4Addr,
     state: &'static SidecarState,
+    store: Store,
+    rib_q: PortQueue,
     debug: bool,
 ) -> impl Batch {
     // our responsibility is to set up the certificates and forward to the switch
@@ -177,7 +268,14 @@ fn outgoing_sidecar_pipeline(
         .map(|packet| packet.parse::<Gdp<DTls<Ipv4>>>())
         .logarrive(name, "outgoing", debug)
         .group_by(
-            |packet| packet.action().unwrap_or(GdpAction::Noop),
+            // the client's Fins go to the switch like its data, to end the flow on the way, and
+            // so do its Gets, which the switch answers if they are for its status
+            |packet| match packet.action() {
+                Ok(GdpAction::Fin | GdpAction::Put | GdpAction::Ack | GdpAction::Get) => {
+                    GdpAction::Forward
+                }
+                action => action.unwrap_or(GdpAction::Noop),
+            },
             pipeline! {
                 GdpAction::Forward => |group| {
                     group
@@ -187,13 +285,8 @@ fn outgoing_sidecar_pipeline(
                             Ok(packet)
                         })
                         .filter_map(move |mut packet| {
-                            // pretend we were received by the public NIC, so we can forward with the proper outgoing IP + MAC
-                            let udp = packet.envelope_mut().envelope_mut();
-                            udp.set_dst_port(RIB_PORT);
-                            let ipv4 = udp.envelope_mut();
-                            ipv4.set_dst(node_ip);
-                            ipv4.envelope_mut().set_dst(node_mac);
-                            forward_gdp(packet, switch_ip)
+                            packet.envelope_mut().envelope_mut().set_dst_port(RIB_PORT);
+                            forward_gdp(packet, switch_ip, identity, gdp_name)
                         })
                 },
                 GdpAction::Control => |group| {
@@ -211,8 +304,17 @@ fn outgoing_sidecar_pipeline(
                                         execute_command(
                                             packet.envelope().envelope().envelope().envelope().src(),
                                             packet.envelope().envelope().envelope().src(),
+                                            packet.envelope().envelope().src_port(),
                                             msg,
                                             state,
+                                            store,
+                                            &|name| send_rib_request(
+                                                rib_q.clone(),
+                                                identity,
+                                                gdp_name,
+                                                switch_ip,
+                                                &RibQuery::metas_for(&[name]),
+                                            ),
                                         )
                                     })
                                     .collect(),
@@ -237,64 +339,92 @@ fn outgoing_sidecar_pipeline(
         )
         .map(|packet| Ok(packet.deparse()))
         .map(encrypt_gdp)
+        .catch_panics(name, "outgoing")
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn start_sidecar_listener(
     config: RuntimeConfig,
     gdp_index: u8,
     node_addr: Ipv4Addr,
     switch_addr: Ipv4Addr,
     nic_name: &'static str,
+    flags: FeatureFlags,
+    control_port: Option<u16>,
     debug: bool,
     env: Env,
 ) -> Result<()> {
+    preflight(
+        &config,
+        env,
+        &Requirements {
+            ports: &["eth1", "loc"],
+            cores: &[0, 1],
+            node_addr: Some(node_addr),
+            rib: RibUse::None,
+            workload: false,
+        },
+    )?;
     let gdp_name = gdp_name_of_index(gdp_index);
     let meta = metadata_of_index(gdp_index);
     let private_key = private_key_of_index(gdp_index);
+    let info = Box::leak(Box::new(node_info(
+        nic_name, gdp_name, meta, &config, flags,
+    )));
+    print_banner(info);
 
     let state: &SidecarState = Box::leak(Box::new(SidecarState {
         listen_addr: RwLock::new((MacAddr::broadcast(), Ipv4Addr::UNSPECIFIED, 31415)),
     }));
 
     let store = SharedStore::new();
+    let identities: &'static PortIdentities = Box::leak(Box::new(load_port_identities()?));
+
+    // mostly for the flows that clients report
+    if let Some(port) = control_port {
+        start_control_socket(
+            port,
+            ControlState {
+                flags,
+                info,
+                clock: Clock::new(),
+                store,
+                prober: None,
+                usage: None,
+            },
+        )?;
+    }
 
     let barrier1 = Arc::new(Barrier::new(2));
     let barrier2 = barrier1.clone();
 
     build_runtime(config, env)?
         .add_pipeline_to_core(0, move |q| {
+            let identity = identities.resolve("eth1", &q["eth1"], node_addr).unwrap();
             Schedule::new("incoming", async move {
-                send_rib_query(
+                register_node(
                     q["eth1"].clone(),
-                    node_addr,
-                    gdp_name,
-                    switch_addr,
-                    &RibQuery::announce_routes(
-                        meta,
-                        vec![
-                            RtCert::new_wrapped(
-                                meta,
-                                private_key,
-                                CertDest::GdpName(gdp_name_of_index(2)),
-                                true,
-                            )
-                            .unwrap(),
-                            RtCert::new_wrapped(
-                                meta,
-                                private_key,
-                                CertDest::IpAddr(node_addr),
-                                true,
-                            )
+                    identity,
+                    meta,
+                    vec![
+                        RtCert::new_wrapped(
+                            meta,
+                            private_key,
+                            CertDest::GdpName(gdp_name_of_index(2)),
+                            true,
+                        )
+                        .unwrap(),
+                        RtCert::new_wrapped(meta, private_key, CertDest::IpAddr(node_addr), true)
                             .unwrap(),
-                        ]
-                        .into(),
-                    ),
+                    ],
+                    private_key,
+                    switch_addr,
                     nic_name,
                 );
                 barrier1.wait().await;
                 incoming_sidecar_pipeline(
                     q["eth1"].clone(),
-                    node_addr,
+                    identity,
                     switch_addr,
                     gdp_name,
                     nic_name,
@@ -308,6 +438,7 @@ pub fn start_sidecar_listener(
             })
         })?
         .add_pipeline_to_core(1, move |q| {
+            let identity = identities.resolve("eth1", &q["eth1"], node_addr).unwrap();
             Schedule::new("outgoing", async move {
                 barrier2.wait().await;
                 outgoing_sidecar_pipeline(
@@ -316,10 +447,11 @@ pub fn start_sidecar_listener(
                     meta,
                     private_key,
                     nic_name,
-                    node_addr,
-                    q["eth1"].mac_addr(),
+                    identity,
                     switch_addr,
                     state,
+                    store.sync(),
+                    q["eth1"].clone(),
                     debug,
                 )
                 .logfail(nic_name, "outgoing", debug)
@@ -327,6 +459,8 @@ pub fn start_sidecar_listener(
                 .await;
             })
         })?
+        .add_pipeline_to_core(0, move |q| session_schedule(q["eth1"].clone()))?
+        .add_periodic_task_to_core(0, move || store.publish(), PUBLISH_INTERVAL)?
         .execute()?;
     Ok(())
 }
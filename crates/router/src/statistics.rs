@@ -1,14 +1,248 @@
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::fs::File;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::LineWriter;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use capsule::metrics;
+use hdrhistogram::Histogram;
 use metrics_core::{Builder, Observe};
 use metrics_observer_yaml::YamlBuilder;
+use metrics_runtime::data::{Counter as CounterHandle, Gauge};
 use metrics_runtime::Measurement::Counter;
+use serde::Deserialize;
+
+#[cfg(feature = "latency")]
+use crate::isolation::Recover;
+use crate::latency::print_latencies;
+use crate::rxmeta::RxClock;
+
+/*
+   Recording every packet in the per-packet counters costs too much at line rate, so a loaded
+   pipeline samples them instead:
+   - a pipeline's load is how many packets it pulls per poll, averaged over recent polls. Above
+     the `threshold` of sampling.toml it records one packet in `rate`, counted `rate` times
+     (packets and bytes alike), so that counters still add up to the traffic in expectation.
+     It records every packet again once the load falls below half the threshold
+   - the `stats.sample_rate` gauge of each port says how many packets one recorded packet
+     currently stands for (1 when recording everything), for dashboards to qualify the counters
+     of that port with
+*/
+
+/// Weight of the newest poll in the average load
+const LOAD_EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct SamplingConfig {
+    /// Start sampling once a pipeline averages more packets per poll than this
+    pub threshold: f64,
+    /// While sampling, one packet in this many is recorded
+    pub rate: u32,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            threshold: 32.0,
+            rate: 16,
+        }
+    }
+}
+
+pub fn load_sampling_config() -> Result<SamplingConfig> {
+    let content = fs::read_to_string("sampling.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// The load of one pipeline, shared by the samplers of its counters
+struct Load {
+    config: SamplingConfig,
+    clock: RxClock,
+    burst: Cell<Instant>,
+    /// Packets pulled in the current burst, as far as the samplers have seen
+    burst_len: Cell<u16>,
+    average: Cell<f64>,
+    sampling: Cell<bool>,
+    sample_rate: Gauge,
+}
+
+impl Load {
+    /// Whether the pipeline is sampling, once the bursts since the last call are accounted for
+    fn sampling(&self) -> bool {
+        let burst = self.clock.burst_time();
+        if burst != self.burst.get() {
+            let average = LOAD_EWMA_ALPHA * self.burst_len.get() as f64
+                + (1.0 - LOAD_EWMA_ALPHA) * self.average.get();
+            let threshold = self.config.threshold;
+            let sampling = if self.sampling.get() {
+                average > threshold / 2.0
+            } else {
+                average > threshold
+            } && self.config.rate > 1;
+            if sampling != self.sampling.get() {
+                let rate = if sampling { self.config.rate } else { 1 };
+                self.sample_rate.record(rate as i64);
+            }
+            self.average.set(average);
+            self.sampling.set(sampling);
+            self.burst.set(burst);
+            self.burst_len.set(0);
+        }
+        self.burst_len
+            .set(self.burst_len.get().max(self.clock.burst_len()));
+        self.sampling.get()
+    }
+}
+
+/// Picks the packets that one set of per-packet counters records
+pub struct StatsSampler {
+    load: Rc<Load>,
+    /// Packets to skip before the next one recorded
+    skip: Cell<u32>,
+}
+
+impl StatsSampler {
+    pub fn new(config: SamplingConfig, clock: RxClock, nic_name: &'static str) -> Self {
+        let sample_rate = metrics::global()
+            .sink()
+            .gauge_with_labels("stats.sample_rate", vec![("nic", nic_name)]);
+        sample_rate.record(1);
+        StatsSampler {
+            load: Rc::new(Load {
+                config,
+                burst: Cell::new(clock.burst_time()),
+                clock,
+                burst_len: Cell::new(0),
+                average: Cell::new(0.0),
+                sampling: Cell::new(false),
+                sample_rate,
+            }),
+            skip: Cell::new(0),
+        }
+    }
+
+    /// A sampler for other counters of the same pipeline, which follows the same load
+    pub fn share(&self) -> Self {
+        StatsSampler {
+            load: self.load.clone(),
+            skip: Cell::new(0),
+        }
+    }
+
+    /// How many packets the current one counts for: 0 if it is not recorded
+    #[inline]
+    pub fn weight(&self) -> u64 {
+        if !self.load.sampling() {
+            return 1;
+        }
+        match self.skip.get() {
+            0 => {
+                self.skip.set(self.load.config.rate - 1);
+                self.load.config.rate as u64
+            }
+            skip => {
+                self.skip.set(skip - 1);
+                0
+            }
+        }
+    }
+}
+
+/// How forwarding lookups on a switch were resolved
+pub struct RouteCacheStats {
+    pub positive: CounterHandle,
+    pub negative: CounterHandle,
+    pub miss: CounterHandle,
+    /// Forwards dropped as copies of packets already forwarded (see dedup.rs)
+    pub duplicates: CounterHandle,
+}
+
+impl RouteCacheStats {
+    pub fn new(nic_name: &'static str) -> &'static Self {
+        let mut sink = metrics::global().sink();
+        let duplicates = sink.counter_with_labels("dedup.duplicates", vec![("nic", nic_name)]);
+        let mut counter = |result: &'static str| {
+            sink.counter_with_labels("route_cache", vec![("nic", nic_name), ("result", result)])
+        };
+        Box::leak(Box::new(RouteCacheStats {
+            positive: counter("positive"),
+            negative: counter("negative"),
+            miss: counter("miss"),
+            duplicates,
+        }))
+    }
+}
+
+/// Round trips above this are recorded as this
+const MAX_RTT_US: u64 = 60_000_000;
+
+/// Round-trip times, for percentiles of them
+pub struct RttStats(Mutex<Histogram<u64>>);
+
+/// The percentiles of some round trips, in microseconds
+#[derive(Clone, Copy, Debug)]
+pub struct RttSummary {
+    pub count: u64,
+    pub min_us: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl fmt::Display for RttSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} round trips, min/p50/p90/p99/max {}/{}/{}/{}/{} us",
+            self.count, self.min_us, self.p50_us, self.p90_us, self.p99_us, self.max_us
+        )
+    }
+}
+
+impl RttStats {
+    pub fn new() -> Result<Self> {
+        let histogram = Histogram::new_with_bounds(1, MAX_RTT_US, 3)
+            .map_err(|err| anyhow!("failed to create RTT histogram: {:?}", err))?;
+        Ok(RttStats(Mutex::new(histogram)))
+    }
+
+    pub fn record(&self, rtt_us: u64) {
+        self.0.lock().recover().saturating_record(rtt_us.max(1));
+    }
+
+    /// The percentiles of the round trips recorded so far, or None if there were none
+    pub fn summary(&self) -> Option<RttSummary> {
+        summarize(&self.0.lock().recover())
+    }
+
+    /// The percentiles of the round trips recorded since the last call, which are then forgotten
+    pub fn take_summary(&self) -> Option<RttSummary> {
+        let mut histogram = self.0.lock().recover();
+        let summary = summarize(&histogram);
+        histogram.reset();
+        summary
+    }
+}
+
+fn summarize(histogram: &Histogram<u64>) -> Option<RttSummary> {
+    if histogram.len() == 0 {
+        return None;
+    }
+    Some(RttSummary {
+        count: histogram.len(),
+        min_us: histogram.min(),
+        p50_us: histogram.value_at_quantile(0.5),
+        p90_us: histogram.value_at_quantile(0.9),
+        p99_us: histogram.value_at_quantile(0.99),
+        max_us: histogram.max(),
+    })
+}
 
 fn print_stats_diff(
     current_m: &mut HashMap<String, u64>,
@@ -35,6 +269,8 @@ fn print_stats_diff(
             history_m.entry(labels).or_insert(Vec::new()).push(diff);
         }
     });
+    #[cfg(feature = "latency")]
+    print_latencies(history_m);
 }
 
 pub fn make_print_stats() -> (impl Fn(), Arc<Mutex<HashMap<String, Vec<u64>>>>) {
@@ -45,8 +281,8 @@ pub fn make_print_stats() -> (impl Fn(), Arc<Mutex<HashMap<String, Vec<u64>>>>)
     (
         move || {
             print_stats_diff(
-                &mut *stats_map_ref.lock().unwrap(),
-                &mut *history_map_ref.lock().unwrap(),
+                &mut *stats_map_ref.lock().recover(),
+                &mut *history_map_ref.lock().recover(),
             )
         },
         history_map_copy,
@@ -88,3 +324,143 @@ pub fn dump_history(map: &HashMap<String, Vec<u64>>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: SamplingConfig = SamplingConfig {
+        threshold: 32.0,
+        rate: 16,
+    };
+
+    /// Pull a burst of `len` packets, returning how many packets the ones recorded count for
+    fn burst(clock: &RxClock, sampler: &StatsSampler, len: u16) -> u64 {
+        clock.pull(len);
+        (0..len).map(|_| sampler.weight()).sum()
+    }
+
+    fn sampling(sampler: &StatsSampler) -> bool {
+        sampler.load.sampling.get()
+    }
+
+    #[capsule::test]
+    fn records_everything_below_the_threshold() {
+        let clock = RxClock::new();
+        let sampler = StatsSampler::new(CONFIG, clock.clone(), "test");
+        for _ in 0..100 {
+            assert_eq!(burst(&clock, &sampler, 16), 16);
+        }
+        assert!(!sampling(&sampler));
+    }
+
+    #[capsule::test]
+    fn samples_under_load_without_losing_count() {
+        let clock = RxClock::new();
+        let sampler = StatsSampler::new(CONFIG, clock.clone(), "test");
+        // the average takes a few bursts to climb over the threshold
+        for _ in 0..10 {
+            burst(&clock, &sampler, 64);
+        }
+        assert!(sampling(&sampler));
+        let mut weights = Vec::new();
+        clock.pull(64);
+        for _ in 0..64 {
+            weights.push(sampler.weight());
+        }
+        // one packet in `rate`, standing for `rate` packets
+        assert_eq!(weights.iter().filter(|&&weight| weight > 0).count(), 4);
+        assert!(weights.iter().all(|&weight| weight == 0 || weight == 16));
+        assert_eq!(weights.iter().sum::<u64>(), 64);
+    }
+
+    #[capsule::test]
+    fn stops_sampling_below_half_the_threshold() {
+        let clock = RxClock::new();
+        let sampler = StatsSampler::new(CONFIG, clock.clone(), "test");
+        for _ in 0..20 {
+            burst(&clock, &sampler, 64);
+        }
+        assert!(sampling(&sampler));
+        // between half the threshold and the threshold, it keeps sampling
+        for _ in 0..50 {
+            burst(&clock, &sampler, 24);
+        }
+        assert!(sampling(&sampler));
+        for _ in 0..50 {
+            burst(&clock, &sampler, 8);
+        }
+        assert!(!sampling(&sampler));
+        assert_eq!(burst(&clock, &sampler, 8), 8);
+    }
+
+    #[capsule::test]
+    fn never_samples_at_a_rate_of_one() {
+        let clock = RxClock::new();
+        let config = SamplingConfig {
+            threshold: 1.0,
+            rate: 1,
+        };
+        let sampler = StatsSampler::new(config, clock.clone(), "test");
+        for _ in 0..20 {
+            assert_eq!(burst(&clock, &sampler, 64), 64);
+        }
+        assert!(!sampling(&sampler));
+    }
+
+    #[capsule::test]
+    fn shared_samplers_follow_the_same_load() {
+        let clock = RxClock::new();
+        let rx = StatsSampler::new(CONFIG, clock.clone(), "test");
+        let tx = rx.share();
+        for _ in 0..20 {
+            burst(&clock, &rx, 64);
+        }
+        assert!(sampling(&tx));
+        // each keeps its own place in the rate
+        assert_eq!(burst(&clock, &tx, 64), 64);
+    }
+
+    #[test]
+    fn summarizes_nothing_without_round_trips() {
+        let stats = RttStats::new().unwrap();
+        assert!(stats.summary().is_none());
+        assert!(stats.take_summary().is_none());
+    }
+
+    #[test]
+    fn summarizes_the_round_trips_it_recorded() {
+        let stats = RttStats::new().unwrap();
+        for rtt_us in 1..=100 {
+            stats.record(rtt_us);
+        }
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        assert_eq!((summary.min_us, summary.max_us), (1, 100));
+        assert_eq!(summary.p50_us, 50);
+        assert_eq!(summary.p90_us, 90);
+        assert_eq!(summary.p99_us, 99);
+    }
+
+    #[test]
+    fn keeps_round_trips_outside_its_bounds() {
+        let stats = RttStats::new().unwrap();
+        // below the histogram's lowest value, and far above its highest
+        stats.record(0);
+        stats.record(u64::MAX);
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.min_us, 1);
+        assert!(summary.max_us >= MAX_RTT_US);
+    }
+
+    #[test]
+    fn forgets_what_it_summarized_last() {
+        let stats = RttStats::new().unwrap();
+        stats.record(10);
+        assert_eq!(stats.take_summary().unwrap().count, 1);
+        assert!(stats.take_summary().is_none());
+        stats.record(20);
+        assert_eq!(stats.take_summary().unwrap().min_us, 20);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Result};
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::{status_name, GdpAction, GdpName, StatusObject, SwitchStatus, TableExport};
+use metrics_runtime::Measurement;
+
+use crate::capabilities::CIPHER_TAG_LEN;
+use crate::clock::Clock;
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+use crate::kvs::SharedStore;
+use crate::rib::create_reply;
+
+/*
+   A switch answers Gets for its status names (see the proto crate's status.rs) from a board of
+   its status objects, which a periodic task on one core refreshes every STATUS_INTERVAL:
+   - the routes are those of DumpRoutes on the control socket, and their digest that of
+     ExportTable, so that a client can compare switches as an operator would
+   - the counters are those of STATUS_COUNTERS, summed over their other labels (the port, most
+     often), so that the whole object fits in a packet
+   - the objects are encoded when they are refreshed, and a Get is answered with a copy
+   - only senders whose certificates delegate them to the switch are answered, as for Pings, so
+     that Gets under a forged source cannot make it reflect its status at someone else
+   - an object whose GetReply would not fit in a packet once encrypted is not sent at all, rather
+     than fragmented or cut short. The limit is the MTU of the port the Get came in by, as
+     fragment.toml sets it, or the sender's own if it advertised a smaller one
+*/
+
+/// How often the status objects are refreshed
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The counters of the Stats object, with the label that they are broken down by if any
+const STATUS_COUNTERS: &[(&str, Option<&str>)] = &[
+    ("gdp.packets", Some("dir")),
+    ("gdp.bytes", Some("dir")),
+    ("route_cache", Some("result")),
+    ("dedup.duplicates", None),
+    ("misses", Some("result")),
+    ("puts", Some("outcome")),
+    ("priority.shed", None),
+    ("pipeline.panics", None),
+];
+
+struct BoardState {
+    /// Our status names, and what they stand for
+    names: Vec<(GdpName, StatusObject)>,
+    /// The encoded objects, once refreshed
+    encoded: Mutex<HashMap<StatusObject, Vec<u8>>>,
+}
+
+/// The status objects of one switch, as its Gets are answered with
+#[derive(Clone, Copy)]
+pub struct StatusBoard(&'static BoardState);
+
+fn counters() -> Vec<(String, u64)> {
+    let mut counters: BTreeMap<String, u64> = BTreeMap::new();
+    let snapshot = metrics::global().controller().snapshot();
+    for (key, measurement) in snapshot.into_measurements() {
+        let value = match measurement {
+            Measurement::Counter(value) => value,
+            _ => continue,
+        };
+        let (name, labels) = key.into_parts();
+        let by = match STATUS_COUNTERS.iter().find(|(counter, _)| *counter == name) {
+            Some((_, by)) => by,
+            None => continue,
+        };
+        let name = match by.and_then(|by| labels.iter().find(|label| label.key() == by)) {
+            Some(label) => format!("{}.{}", name, label.value()),
+            None => name.to_string(),
+        };
+        *counters.entry(name).or_insert(0) += value;
+    }
+    counters.into_iter().collect()
+}
+
+impl StatusBoard {
+    pub fn new(gdp_name: GdpName) -> Self {
+        StatusBoard(Box::leak(Box::new(BoardState {
+            names: StatusObject::ALL
+                .iter()
+                .map(|object| (status_name(&gdp_name, *object), *object))
+                .collect(),
+            encoded: Mutex::new(HashMap::new()),
+        })))
+    }
+
+    /// Which of our status objects `name` stands for, if any
+    pub fn object_of(&self, name: &GdpName) -> Option<StatusObject> {
+        self.0
+            .names
+            .iter()
+            .find(|(reserved, _)| reserved == name)
+            .map(|(_, object)| *object)
+    }
+
+    /// Take the objects anew from the store, the metrics registry and the clock
+    pub fn refresh(&self, store: SharedStore, clock: &Clock) -> Result<()> {
+        let table = TableExport::new(store.dump_routes());
+        let health = clock.health();
+        let objects = [
+            (
+                StatusObject::Routes,
+                SwitchStatus::Routes {
+                    routes: table.routes.len() as u64,
+                    digest: table.digest,
+                },
+            ),
+            (
+                StatusObject::Stats,
+                SwitchStatus::Stats {
+                    counters: counters(),
+                },
+            ),
+            (
+                StatusObject::Health,
+                SwitchStatus::Health {
+                    clock_synced: health.synced,
+                    clock_offset_us: health.offset_us,
+                    clock_error_us: health.error_us,
+                },
+            ),
+        ];
+        let mut encoded = HashMap::new();
+        for (object, status) in objects {
+            encoded.insert(object, bincode::serialize(&status)?);
+        }
+        *self.0.encoded.lock().recover() = encoded;
+        Ok(())
+    }
+
+    /// Answer a Get for one of our status objects with a GetReply carrying it, if that fits in
+    /// `mtu` bytes once encrypted
+    pub fn answer(
+        &self,
+        packet: &Gdp<DTls<Ipv4>>,
+        object: StatusObject,
+        mtu: u16,
+    ) -> Result<Gdp<DTls<Ipv4>>> {
+        let encoded = self.0.encoded.lock().recover().get(&object).cloned();
+        let encoded = encoded.ok_or_else(|| anyhow!("status {:?} not refreshed yet", object))?;
+        let reply = create_reply(packet, GdpAction::GetReply, &encoded)?;
+        let ipv4 = reply.envelope().envelope().envelope();
+        let sent_len = reply.mbuf().data_len() - ipv4.offset() + CIPHER_TAG_LEN;
+        ensure!(
+            sent_len <= mtu as usize,
+            "status {:?} would take {} bytes, more than the MTU of {}",
+            object,
+            sent_len,
+            mtu
+        );
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use capsule::net::MacAddr;
+    use capsule::Mbuf;
+
+    use super::*;
+    use crate::capabilities::DEFAULT_MAX_MTU;
+    use crate::fragment::FragmentConfig;
+    use crate::hardcoded_routes::{gdp_name_of_index, WithBroadcast};
+    use crate::rib::create_control_request;
+
+    fn get(board: StatusBoard, object: StatusObject) -> Gdp<DTls<Ipv4>> {
+        let mut packet = create_control_request(
+            Mbuf::new().unwrap(),
+            GdpAction::Get,
+            &[],
+            MacAddr::broadcast(),
+            Ipv4Addr::new(10, 0, 0, 1),
+            gdp_name_of_index(1),
+            Ipv4Addr::new(10, 0, 0, 2),
+        )
+        .unwrap();
+        let name = board.0.names.iter().find(|(_, named)| *named == object);
+        packet.set_dst(name.unwrap().0);
+        packet
+    }
+
+    fn board_with(object: StatusObject, encoded: Vec<u8>) -> StatusBoard {
+        let board = StatusBoard::new(gdp_name_of_index(2));
+        board.0.encoded.lock().unwrap().insert(object, encoded);
+        board
+    }
+
+    #[capsule::test]
+    fn answers_gets_with_the_object_from_its_status_name() {
+        let board = board_with(StatusObject::Health, vec![1, 2, 3]);
+        let packet = get(board, StatusObject::Health);
+        let reply = board
+            .answer(&packet, StatusObject::Health, DEFAULT_MAX_MTU)
+            .unwrap();
+        assert_eq!(reply.action().unwrap(), GdpAction::GetReply);
+        assert_eq!(reply.src(), packet.dst());
+        assert_eq!(reply.dst(), gdp_name_of_index(1));
+        assert_eq!(reply.data().unwrap(), &[1, 2, 3]);
+    }
+
+    #[capsule::test]
+    fn refuses_objects_not_refreshed_yet() {
+        let board = board_with(StatusObject::Health, vec![1, 2, 3]);
+        let packet = get(board, StatusObject::Stats);
+        assert!(board
+            .answer(&packet, StatusObject::Stats, DEFAULT_MAX_MTU)
+            .is_err());
+    }
+
+    #[capsule::test]
+    fn refuses_objects_that_would_not_fit_in_a_packet() {
+        let fits = board_with(StatusObject::Stats, vec![0; 1000]);
+        assert!(fits
+            .answer(
+                &get(fits, StatusObject::Stats),
+                StatusObject::Stats,
+                DEFAULT_MAX_MTU
+            )
+            .is_ok());
+        let too_large = board_with(StatusObject::Stats, vec![0; DEFAULT_MAX_MTU as usize]);
+        assert!(too_large
+            .answer(
+                &get(too_large, StatusObject::Stats),
+                StatusObject::Stats,
+                DEFAULT_MAX_MTU
+            )
+            .is_err());
+    }
+
+    #[capsule::test]
+    fn holds_objects_to_the_mtu_they_are_given() {
+        let board = board_with(StatusObject::Stats, vec![0; 900]);
+        let packet = get(board, StatusObject::Stats);
+        let reply = board
+            .answer(&packet, StatusObject::Stats, DEFAULT_MAX_MTU)
+            .unwrap();
+        let ipv4 = reply.envelope().envelope().envelope();
+        let sent_len = reply.mbuf().data_len() - ipv4.offset() + CIPHER_TAG_LEN;
+        // a port whose fragment.toml leaves just enough room for it, until the port carries IPv6
+        let fragment_config: FragmentConfig =
+            toml::from_str(&format!("mtu = {}", sent_len)).unwrap();
+        assert!(board
+            .answer(
+                &packet,
+                StatusObject::Stats,
+                fragment_config.port_mtu(false)
+            )
+            .is_ok());
+        assert!(board
+            .answer(&packet, StatusObject::Stats, fragment_config.port_mtu(true))
+            .is_err());
+    }
+}
@@ -5,37 +5,213 @@ use anyhow::{bail, Result};
 use capsule::batch::{Batch, Either};
 use capsule::net::MacAddr;
 use capsule::packets::ip::v4::Ipv4;
-use capsule::packets::{Packet, Udp};
+use capsule::packets::{Ethernet, Packet, Udp};
 use capsule::Mbuf;
-use gdp_client::{GdpAction, GdpName};
+use gdp_proto::{
+    AdmissionRequest, FinKind, FinPayload, GdpAction, GdpHeader, GdpName, NackPayload, NackReason,
+    RouteSource,
+};
+use serde::{Deserialize, Serialize};
 
-use crate::certificates::{check_packet_certificates, CertDest, GdpMeta, RtCert};
+use crate::admission::{answer_admission, Admission};
+use crate::blocklist::verify_content_or_report;
+use crate::branches::branches;
+use crate::budget::{load_budget_config, Budget};
+use crate::canary::canaries;
+use crate::capabilities::{
+    fits_peer_mtu, handle_heartbeat, heartbeat_reply, peer_advertises, peer_max_mtu, peer_supports,
+    Feature, Neighbor,
+};
+use crate::certificates::{
+    check_packet_certificates, Attester, CertDest, ChainChecker, GdpMeta, NewRtCert, RtCert,
+};
+use crate::chaos::Chaos;
+use crate::clock::{handle_time_reply, Clock};
+use crate::conntrack::{
+    end_flow, keep_flow_alive, lookup_return_flow, track_outbound, FinCounters, FlowEntry,
+};
+use crate::dedup::first_forward;
+use crate::discovery::verify_rib_search_reply;
 use crate::dtls::DTls;
-use crate::gdp::{CertificateBlock, Gdp};
+use crate::flags::{FeatureFlags, Flag};
+use crate::fragment::load_fragment_config;
+use crate::gdp::Gdp;
 use crate::gdpbatch::GdpBatch;
-use crate::hardcoded_routes::WithBroadcast;
+use crate::hardcoded_routes::{metadata_of_index, WithBroadcast, RIB_INDEX};
+use crate::identity::PortIdentity;
+use crate::invalidation::Gossip;
 use crate::kvs::Store;
+use crate::l2filter::{load_l2_config, Egress};
+use crate::labels::LabelGuard;
+use crate::missbuffer::{discard_held, MissBufferBatch};
 use crate::packet_ops::{get_payload, set_payload};
+use crate::ping::answer_ping;
 use crate::pipeline::GdpPipeline;
-use crate::rib::{create_rib_request, handle_rib_reply};
-use crate::ribpayload::{process_rib_data, RibQuery};
+use crate::prefetch::Prefetcher;
+use crate::probe::{answer_echo, read_echo_answer, Prober};
+use crate::puts::puts;
+use crate::recorder::Recorder;
+use crate::registration::{intercept_registration, registration_policy, RegistrationPolicy};
+use crate::rib::{create_rib_request, handle_rib_reply, Routes};
+use crate::ribpayload::{refresh_route, RibQuery};
+use crate::statistics::RouteCacheStats;
+use crate::status::StatusBoard;
+use crate::telemetry::record_hop;
+use crate::usage::UsageMeter;
 use crate::{pipeline, FwdTableEntry};
 
 enum DestResult {
-    Hit(Ipv4Addr),
+    Hit(Ipv4Addr, RouteSource),
+    Flow(FlowEntry),
     Miss(GdpName),
 }
 
-fn find_destination(dst: GdpName, store: Store) -> DestResult {
+/// A store entry that a lookup went through, which is kept alive when the route is used
+enum Used {
+    Nothing,
+    Route(GdpName, FwdTableEntry<Ipv4Addr>),
+    Flow(GdpName, GdpName, FwdTableEntry<FlowEntry>),
+}
+
+impl Used {
+    fn keep_alive(self, store: Store) {
+        match self {
+            Used::Nothing => {}
+            Used::Route(name, entry) => refresh_route(name, entry, store),
+            Used::Flow(src, dst, entry) => keep_flow_alive(src, dst, entry, store),
+        }
+    }
+}
+
+/// How many names a name may be delegated through (see next_hops) before it is treated as having
+/// no route, so that delegations that loop back on themselves end
+const MAX_DELEGATIONS: usize = 4;
+
+/// Where the store sends `dst`, without changing it
+fn lookup_destination(dst: GdpName, store: Store) -> (DestResult, Used) {
+    lookup_delegated(dst, store, MAX_DELEGATIONS)
+}
+
+/// `lookup_destination`, following at most `delegations` more delegations
+fn lookup_delegated(dst: GdpName, store: Store, delegations: usize) -> (DestResult, Used) {
+    let _view = store.pin();
+    // operator pins outrank anything learned from the RIB or configured locally
+    if let Some(FwdTableEntry { val: ip, .. }) = store.pinned_routes.get(&dst) {
+        return (DestResult::Hit(ip, RouteSource::Pinned), Used::Nothing);
+    }
     match store.forwarding_table.get(&dst) {
-        Some(FwdTableEntry { val: ip, .. }) => DestResult::Hit(ip),
+        Some(entry) => (
+            DestResult::Hit(entry.val, RouteSource::Learned),
+            Used::Route(dst, entry),
+        ),
         None => match store.next_hops.get(&dst) {
-            Some(FwdTableEntry { val: proxy, .. }) => find_destination(proxy, store),
-            None => DestResult::Miss(dst),
+            Some(FwdTableEntry { val: proxy, .. }) if delegations > 0 => {
+                match lookup_delegated(proxy, store, delegations - 1) {
+                    (DestResult::Hit(ip, _), used) => {
+                        (DestResult::Hit(ip, RouteSource::Delegated), used)
+                    }
+                    other => other,
+                }
+            }
+            _ => (DestResult::Miss(dst), Used::Nothing),
         },
     }
 }
 
+/// Where the store sends `dst`, keeping the learned route it used alive
+fn find_destination(dst: GdpName, store: Store) -> DestResult {
+    let (result, used) = lookup_destination(dst, store);
+    used.keep_alive(store);
+    result
+}
+
+fn is_negatively_cached(packet: &Gdp<DTls<Ipv4>>, store: Store) -> bool {
+    let _view = store.pin();
+    match lookup_destination(packet.dst(), store).0 {
+        DestResult::Miss(gdp_name) => store.negative_routes.get(&gdp_name).is_some(),
+        _ => false,
+    }
+}
+
+/// Where the store sends a packet from `src` to `dst`, without changing it
+fn lookup_route(
+    src: GdpName,
+    dst: GdpName,
+    store: Store,
+    flags: FeatureFlags,
+) -> (DestResult, Used) {
+    let _view = store.pin();
+    if let Some(FwdTableEntry { val: ip, .. }) = store.pinned_routes.get(&dst) {
+        return (DestResult::Hit(ip, RouteSource::Pinned), Used::Nothing);
+    }
+    // replies to flows opened by local endpoints go straight back, without consulting the RIB
+    if let Some(Some(flow)) = flags.run(Flag::FlowTracking, || lookup_return_flow(src, dst, store))
+    {
+        return (DestResult::Flow(flow.val), Used::Flow(src, dst, flow));
+    }
+    lookup_destination(dst, store)
+}
+
+/// Where the store sends a packet from `src` to `dst`, keeping the route or flow it used alive
+fn find_route(src: GdpName, dst: GdpName, store: Store, flags: FeatureFlags) -> DestResult {
+    let (result, used) = lookup_route(src, dst, store, flags);
+    used.keep_alive(store);
+    result
+}
+
+/// What the Forward branch does with a packet whose certificates check out, as decided by the store
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RouteDecision {
+    Blackholed,
+    /// To the next hop, with which of the store's routes led there
+    Forward(Ipv4Addr, RouteSource),
+    /// Back to the endpoint that opened the flow the packet replies to
+    ReturnFlow(Ipv4Addr),
+    /// Dropped, as the operator is simulating a failure of the next hop
+    FailedNextHop(Ipv4Addr),
+    /// NACKed without asking the RIB, which recently had no route for the name
+    NegativelyCached,
+    /// Held while the RIB is asked where to find the name, and NACKed if it does not answer in time
+    AskRib(GdpName),
+}
+
+/// The routing decision of the Forward branch, from the same lookups in the same order, for
+/// recording and replaying decisions (see recorder.rs). Unlike the Forward branch, it leaves the
+/// routes and flows it finds as they are, so that recording a decision, or waiting on one for a
+/// held packet, does not keep them alive
+pub fn decide_route(
+    src: GdpName,
+    dst: GdpName,
+    store: Store,
+    flags: FeatureFlags,
+) -> RouteDecision {
+    let _view = store.pin();
+    if store.blackholed_names.get(&dst).is_some() {
+        return RouteDecision::Blackholed;
+    }
+    let (ip, decision) = match lookup_route(src, dst, store, flags).0 {
+        DestResult::Hit(ip, source) => (ip, RouteDecision::Forward(ip, source)),
+        DestResult::Flow(flow) => (flow.src_ip, RouteDecision::ReturnFlow(flow.src_ip)),
+        DestResult::Miss(proxy) if store.negative_routes.get(&proxy).is_some() => {
+            return RouteDecision::NegativelyCached
+        }
+        DestResult::Miss(proxy) => return RouteDecision::AskRib(proxy),
+    };
+    if store.failed_next_hops.get(&ip).is_some() {
+        return RouteDecision::FailedNextHop(ip);
+    }
+    decision
+}
+
+/// Whether a packet held for the RIB may go through the pipeline again: it can be routed now, or
+/// the RIB answered that it has no route (see missbuffer.rs)
+fn is_settled(packet: &Gdp<DTls<Ipv4>>, store: Store, flags: FeatureFlags) -> bool {
+    !matches!(
+        decide_route(packet.src(), packet.dst(), store, flags),
+        RouteDecision::AskRib(_)
+    )
+}
+
 pub fn bounce_udp(udp: &mut Udp<Ipv4>) {
     let udp_src_port = udp.dst_port();
     let udp_dst_port = udp.src_port();
@@ -55,14 +231,12 @@ pub fn bounce_udp(udp: &mut Udp<Ipv4>) {
     ethernet.set_dst(eth_dst);
 }
 
-fn add_forwarding_cert(
+pub fn add_forwarding_cert(
     gdp: &mut Gdp<DTls<Ipv4>>,
     store: Store,
     meta: GdpMeta,
     private_key: [u8; 32],
 ) -> Result<()> {
-    let CertificateBlock { mut certificates } = gdp.get_certs()?;
-
     let cert = match store.route_certs.get(&gdp.dst()) {
         Some(cert) => cert,
         None => {
@@ -72,55 +246,174 @@ fn add_forwarding_cert(
         }
     };
 
-    certificates.push(cert);
-    gdp.set_certs(&CertificateBlock { certificates })?;
-    Ok(())
+    gdp.append_cert(&cert)
 }
 
-fn intercept_rib_insertion(packet: &mut Gdp<DTls<Ipv4>>, store: Store, debug: bool) -> Result<()> {
-    let mut query: RibQuery = bincode::deserialize(get_payload(packet)?)?;
-
-    let mut proxy_certs = vec![];
-    process_rib_data(
-        &query.new_nodes,
-        &query.new_certs,
-        Some(&mut proxy_certs),
-        store,
-        debug,
-    )?;
-    query.new_certs = proxy_certs.into_iter().cloned().collect();
-
-    set_payload(packet, &bincode::serialize(&query)?)?;
-
-    Ok(())
+/// Whether relaying `gdp` to `dst` would send it around a loop: straight back to the hop it came
+/// from, or on from us again after it already left us. The latter is seen in the path recorded
+/// in the packet's telemetry, aside from the hop we may just have recorded; loops among switches
+/// that do not record telemetry are left to the TTL
+fn is_looping(gdp: &Gdp<DTls<Ipv4>>, dst: Ipv4Addr, gdp_name: GdpName) -> bool {
+    if dst == gdp.envelope().envelope().envelope().src() {
+        return true;
+    }
+    let hops = gdp.get_telemetry().ok().flatten().unwrap_or_default();
+    hops.iter()
+        .rev()
+        .skip_while(|hop| hop.switch == gdp_name)
+        .any(|hop| hop.switch == gdp_name)
 }
 
-pub fn forward_gdp(mut gdp: Gdp<DTls<Ipv4>>, dst: Ipv4Addr) -> Result<Either<Gdp<DTls<Ipv4>>>> {
-    let dtls = gdp.envelope_mut();
-    let udp = dtls.envelope_mut();
-    let ipv4 = udp.envelope_mut();
-
-    if ipv4.dst() == dst {
+/// Send `gdp` on to `dst`, from the addresses of the port it leaves through, as the hop
+/// `gdp_name`. Data packets whose TTL runs out, or that would loop, are NACKed instead.
+pub fn forward_gdp(
+    mut gdp: Gdp<DTls<Ipv4>>,
+    dst: Ipv4Addr,
+    identity: PortIdentity,
+    gdp_name: GdpName,
+) -> Result<Either<Gdp<DTls<Ipv4>>>> {
+    if identity.ip == dst {
         // we are the destination!
         println!("packet received!");
         return Ok(Either::Drop(gdp.reset()));
     }
 
-    ipv4.set_src(ipv4.dst());
+    let nackable = is_nackable(&gdp);
+    let expired = gdp.ttl() <= 1;
+    if expired || (nackable && is_looping(&gdp, dst, gdp_name)) {
+        // data packets are NACKed back the way they came; NACKs, Acks and control packets are
+        // dropped
+        return if nackable {
+            let reason = if expired {
+                NackReason::TtlExpired
+            } else {
+                NackReason::NoRoute
+            };
+            Ok(Either::Keep(bounce_gdp(gdp, reason)?))
+        } else {
+            Ok(Either::Drop(gdp.reset()))
+        };
+    }
+    gdp.set_ttl(gdp.ttl() - 1);
+    gdp.set_last_hop(gdp_name);
+
+    let dtls = gdp.envelope_mut();
+    let udp = dtls.envelope_mut();
+    let ipv4 = udp.envelope_mut();
+    ipv4.set_src(identity.ip);
     ipv4.set_dst(dst);
 
     let ethernet = ipv4.envelope_mut();
-    ethernet.set_src(ethernet.dst());
+    ethernet.set_src(identity.mac);
     ethernet.set_dst(MacAddr::broadcast());
     // println!("outgoing: {:?}", gdp);
     Ok(Either::Keep(gdp))
 }
 
-pub fn bounce_gdp(mut gdp: Gdp<DTls<Ipv4>>) -> Result<Gdp<DTls<Ipv4>>> {
-    if gdp.action()? == GdpAction::Forward {
-        gdp.remove_payload()?;
-        gdp.set_data_len(0);
+/// A copy of a packet that was just forwarded, for the host its destination is moving to, if it
+/// is moving somewhere else (see registration.rs)
+fn copy_for_migration(
+    gdp: &Gdp<DTls<Ipv4>>,
+    store: Store,
+    labels: &LabelGuard,
+    debug: bool,
+) -> Result<Option<Gdp<DTls<Ipv4>>>> {
+    if !gdp.action().map_or(false, GdpAction::is_data) {
+        return Ok(None);
+    }
+    let dst = match store.migrations.get(&gdp.dst()) {
+        Some(FwdTableEntry { val: ip, .. }) if ip != gdp.envelope().envelope().envelope().dst() => {
+            ip
+        }
+        _ => return Ok(None),
+    };
+    let mut copy = copy_to(gdp, dst)?;
+    if !labels.permit(&mut copy, dst, debug)? {
+        return Ok(None);
+    }
+    if debug {
+        println!(
+            "also delivering packet {:016x} to {} while its destination moves",
+            gdp.trace_id(),
+            dst
+        );
+    }
+    Ok(Some(copy))
+}
+
+/// A copy of a packet that was just forwarded, for the canary of its destination if a Mirror
+/// rule picked its sender (see canary.rs)
+fn copy_for_canary(
+    gdp: &Gdp<DTls<Ipv4>>,
+    store: Store,
+    labels: &LabelGuard,
+    debug: bool,
+) -> Result<Option<Gdp<DTls<Ipv4>>>> {
+    if !gdp.action().map_or(false, GdpAction::is_data) {
+        return Ok(None);
+    }
+    let dst = match canaries().mirror_to(gdp, store.now_secs()) {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+    let mut copy = copy_to(gdp, dst)?;
+    if !labels.permit(&mut copy, dst, debug)? {
+        return Ok(None);
+    }
+    canaries().record_mirrored(&copy, store.now_secs());
+    if debug {
+        println!(
+            "also delivering packet {:016x} to canary {}",
+            gdp.trace_id(),
+            dst
+        );
+    }
+    Ok(Some(copy))
+}
+
+/// A copy of a packet, addressed to `dst` instead
+fn copy_to(gdp: &Gdp<DTls<Ipv4>>, dst: Ipv4Addr) -> Result<Gdp<DTls<Ipv4>>> {
+    let bytes = gdp.mbuf().read_data_slice::<u8>(0, gdp.mbuf().data_len())?;
+    let bytes = unsafe { bytes.as_ref() };
+    let mut copy = Mbuf::new()?;
+    copy.extend(0, bytes.len())?;
+    copy.write_data_slice(0, bytes)?;
+    let mut copy = copy
+        .parse::<Ethernet>()?
+        .parse::<Ipv4>()?
+        .parse::<Udp<Ipv4>>()?
+        .parse::<DTls<Ipv4>>()?
+        .parse::<Gdp<DTls<Ipv4>>>()?;
+    if let Some(rx_meta) = gdp.rx_meta() {
+        copy.set_rx_meta(*rx_meta);
+    }
+    // so that it is not dropped as a copy of `gdp` where their paths meet (see dedup.rs)
+    copy.clear_seqno()?;
+    copy.envelope_mut()
+        .envelope_mut()
+        .envelope_mut()
+        .set_dst(dst);
+    Ok(copy)
+}
+
+/// Whether a packet that cannot be delivered is NACKed back to its sender. An Ack is not: the
+/// retransmission of its Put asks for it again
+fn is_nackable(gdp: &Gdp<DTls<Ipv4>>) -> bool {
+    matches!(gdp.action(), Ok(GdpAction::Forward | GdpAction::Put))
+}
+
+/// NACK a data packet back to its sender, saying why in its payload; other packets are left as
+/// they are
+pub fn bounce_gdp(mut gdp: Gdp<DTls<Ipv4>>, reason: NackReason) -> Result<Gdp<DTls<Ipv4>>> {
+    if is_nackable(&gdp) {
+        let payload = NackPayload { reason }.to_bytes();
+        set_payload(&mut gdp, &payload)?;
+        gdp.set_data_len(payload.len());
+        gdp.set_telemetry_len(0);
+        gdp.seal_content()?;
         gdp.set_action(GdpAction::Nack);
+        // the NACK has the whole way back to the sender ahead of it
+        gdp.set_ttl(GdpHeader::default().ttl);
         bounce_udp(gdp.envelope_mut().envelope_mut());
         gdp.reconcile_all();
     }
@@ -133,64 +426,197 @@ pub fn switch_pipeline(
     private_key: [u8; 32],
     store: Store,
     nic_name: &'static str,
-    rib_ip: Ipv4Addr,
+    identity: PortIdentity,
+    routes: &'static Routes,
+    flags: FeatureFlags,
+    clock: &'static Clock,
+    prefetcher: Prefetcher,
+    prober: Prober,
+    usage: UsageMeter,
+    admission: Admission,
+    status: StatusBoard,
+    gossip: Gossip,
     debug: bool,
 ) -> impl GdpPipeline {
+    let route_stats = RouteCacheStats::new(nic_name);
+    let fin_counters = FinCounters::new();
+    let usage = usage.recorder();
+    let rib_meta = metadata_of_index(RIB_INDEX);
+    let budget = Budget::new(load_budget_config().unwrap_or_default(), nic_name);
+    let chaos = Chaos::new(nic_name);
+    let recorder = Recorder::new(nic_name);
+    let labels = LabelGuard::new(nic_name);
+    let (migration_labels, mirror_labels) = (labels.clone(), labels.clone());
+    let canaries = canaries();
+    let puts = puts();
+    let chains = ChainChecker::new(gdp_name, nic_name);
+    let attester = Attester::new(gdp_name, private_key, nic_name);
+    let cert_arms = branches().counts("certificates", nic_name, &["accepted", "refused"]);
+    let route_arms = branches().counts("route", nic_name, &["hit", "miss"]);
+    let miss_arms = branches().counts("negative_cache", nic_name, &["cached", "asked"]);
+    let l2_config = load_l2_config(nic_name).unwrap_or_default();
+    let egress: &'static Egress = Box::leak(Box::new(Egress::new(&l2_config)));
+    // the MTU that install_gdp_pipeline splits our packets to fit
+    let port_mtu = load_fragment_config()
+        .unwrap_or_default()
+        .port_mtu(l2_config.ipv6_prefix.is_some());
+    let registration_policy: &'static RegistrationPolicy =
+        Box::leak(Box::new(registration_policy(nic_name)));
     pipeline! {
         GdpAction::Forward => |group| {
             group
+            .filter(move |packet| !chaos.is_blackholed(packet, store))
+            .filter(move |packet| {
+                flags.run(Flag::Policy, || budget.admit(packet, "certificate checks", debug)).unwrap_or(true)
+            })
             .group_by(
                 move |packet| {
-                    check_packet_certificates(gdp_name, packet, &store, None, nic_name, debug)
+                    let accepted = flags.run(Flag::CertVerify, || chains.check(packet, &store, debug)).unwrap_or(true);
+                    cert_arms.hit(!accepted as usize);
+                    accepted
                 },
                 pipeline! {
                     true => |group| {
                         group
                         .for_each(move |packet| {
-                            // Back-cache the route for 100s to allow NACK to reflect
-                            store.nack_reply_cache.put(
-                                packet.src(),
-                                FwdTableEntry::new(
-                                    packet.envelope().envelope().envelope().src(),
-                                    SystemTime::now()
-                                        .duration_since(UNIX_EPOCH)?
-                                        .as_secs()
-                                        + 100,
-                                ),
-                            );
+                            recorder.record(packet, store, flags);
                             Ok(())
                         })
+                        .for_each(move |packet| {
+                            // Back-cache the route for 100s to allow NACK to reflect
+                            flags.run(Flag::NackReplyCache, || -> Result<()> {
+                                store.nack_reply_cache.put(
+                                    packet.src(),
+                                    FwdTableEntry::new(
+                                        packet.envelope().envelope().envelope().src(),
+                                        SystemTime::now()
+                                            .duration_since(UNIX_EPOCH)?
+                                            .as_secs()
+                                            + 100,
+                                    ),
+                                );
+                                Ok(())
+                            }).unwrap_or(Ok(()))
+                        })
+                        .for_each(move |packet| {
+                            flags.run(Flag::FlowTracking, || track_outbound(packet, store, nic_name)).unwrap_or(Ok(()))
+                        })
                         .group_by(
-                            move |packet| matches!(find_destination(packet.dst(), store), DestResult::Hit(_)),
+                            move |packet| {
+                                // the arm that takes the route keeps it alive
+                                let hit = !matches!(lookup_route(packet.src(), packet.dst(), store, flags).0, DestResult::Miss(_));
+                                route_arms.hit(!hit as usize);
+                                hit
+                            },
                             pipeline! {
                                 true => |group| {
                                     group.filter_map(move |mut packet| {
-                                        if let DestResult::Hit(ip) = find_destination(packet.dst(), store) {
+                                        let (ip, source) = match find_route(packet.src(), packet.dst(), store, flags) {
+                                            DestResult::Hit(ip, source) => (canaries.steer(&packet, ip, store.now_secs()), source),
+                                            DestResult::Flow(flow) => {
+                                                if debug {
+                                                    println!(
+                                                        "{} returning packet along flow {}:{} -> {}:{} (opened on {})",
+                                                        nic_name, flow.src_ip, flow.src_port, flow.dst_ip, flow.dst_port, flow.nic_name
+                                                    );
+                                                }
+                                                packet.envelope_mut().envelope_mut().set_dst_port(flow.src_port);
+                                                (flow.src_ip, RouteSource::ReturnFlow)
+                                            }
+                                            DestResult::Miss(_) => unreachable!(),
+                                        };
+                                        if chaos.is_failed_next_hop(ip, store) {
+                                            return Ok(Either::Drop(packet.reset()));
+                                        }
+                                        if let Some(request) = flags.run(Flag::AdmissionControl, || AdmissionRequest::find(packet.extensions()?)).transpose()?.flatten() {
+                                            let decision = admission.decide((packet.src(), packet.dst()), ip, request);
+                                            return answer_admission(packet, decision, gdp_name, meta, private_key, store, identity);
+                                        }
+                                        if !labels.permit(&mut packet, ip, debug)? {
+                                            return Ok(Either::Drop(packet.reset()));
+                                        }
+                                        if !flags.run(Flag::Dedup, || first_forward(&packet, store)).unwrap_or(true) {
+                                            route_stats.duplicates.increment();
                                             if debug {
-                                                println!("{} forwarding packet to ip {}", nic_name, ip);
+                                                println!("{} dropping packet {:016x}, a copy of one already forwarded", nic_name, packet.trace_id());
                                             }
-                                            add_forwarding_cert(&mut packet, store, meta, private_key)?;
-                                            forward_gdp(packet, ip)
+                                            return Ok(Either::Drop(packet.reset()));
+                                        }
+                                        flags.run(Flag::Stats, || {
+                                            route_stats.positive.increment();
+                                            usage.record(packet.src(), packet.len());
+                                        });
+                                        // whatever the flags, as Acks take the traffic class of their Put here
+                                        puts.watch(&mut packet);
+                                        flags.run(Flag::RibPrefetch, || prefetcher.record_hit(packet.src(), packet.dst(), store));
+                                        flags.run(Flag::RouteProbes, || prober.record_forward(packet.dst()));
+                                        if debug {
+                                            println!("{} forwarding packet {:016x} to ip {} ({:?} route)", nic_name, packet.trace_id(), ip, source);
+                                        }
+                                        // on the VLAN the packet will be tagged with on its way out
+                                        let arrival = packet.rx_meta().and_then(|rx_meta| rx_meta.vlan);
+                                        let neighbor = Neighbor { vlan: egress.vlan_to(ip, arrival), ip };
+                                        if peer_advertises(store, neighbor, Feature::CertAttestations) {
+                                            flags.run(Flag::CertVerify, || attester.attest(&mut packet, &store)).unwrap_or(Ok(()))?;
+                                        }
+                                        flags.run(Flag::ForwardingCerts, || add_forwarding_cert(&mut packet, store, meta, private_key)).unwrap_or(Ok(()))?;
+                                        if peer_supports(store, neighbor, Feature::InbandTelemetry) {
+                                            flags.run(Flag::InbandTelemetry, || record_hop(&mut packet, gdp_name)).unwrap_or(Ok(()))?;
                                         } else {
-                                            unreachable!();
+                                            packet.take_telemetry()?;
+                                        }
+                                        if !fits_peer_mtu(&packet, store, neighbor) {
+                                            // rather than have the neighbor drop the packet for its telemetry
+                                            packet.take_telemetry()?;
                                         }
+                                        forward_gdp(packet, ip, identity, gdp_name)
                                     })
+                                    // until its old registration is withdrawn, a moving name is delivered to both hosts
+                                    .inject_if(move |packet| copy_for_migration(packet, store, &migration_labels, debug))
+                                    .inject_if(move |packet| copy_for_canary(packet, store, &mirror_labels, debug))
                                 },
                                 false => |group| {
                                     group
-                                    .map(bounce_gdp)
-                                    .inject(move |packet| {
-                                        let src_ip = packet.envelope().envelope().envelope().src();
-                                        let src_mac = packet.envelope().envelope().envelope().envelope().src();
-                                        if debug {
-                                            println!("{} querying RIB for destination {:?}", nic_name, packet.dst());
-                                        }
-                                        if let DestResult::Miss(proxy) = find_destination(packet.dst(), store) {
-                                            create_rib_request(Mbuf::new()?, &RibQuery::next_hop_for(proxy), src_mac, src_ip, gdp_name, rib_ip)
-                                        } else {
-                                            unreachable!();
+                                    .group_by(
+                                        move |packet| {
+                                            let cached = is_negatively_cached(packet, store);
+                                            miss_arms.hit(!cached as usize);
+                                            cached
+                                        },
+                                        pipeline! {
+                                            true => |group| {
+                                                // the RIB recently told us there is no route, so NACK without asking again
+                                                group
+                                                .for_each(move |_| {
+                                                    flags.run(Flag::Stats, || route_stats.negative.increment());
+                                                    Ok(())
+                                                })
+                                                .map(|packet| bounce_gdp(packet, NackReason::NoRoute))
+                                            },
+                                            false => |group| {
+                                                group
+                                                .for_each(move |packet| {
+                                                    flags.run(Flag::Stats, || route_stats.miss.increment());
+                                                    flags.run(Flag::RibPrefetch, || prefetcher.record_miss(packet.src(), packet.dst()));
+                                                    Ok(())
+                                                })
+                                                .inject(move |packet| {
+                                                    if debug {
+                                                        println!("{} querying RIB for destination {:?} (packet {:016x} held)", nic_name, packet.dst(), packet.trace_id());
+                                                    }
+                                                    if let DestResult::Miss(proxy) = find_destination(packet.dst(), store) {
+                                                        create_rib_request(Mbuf::new()?, &RibQuery::next_hop_for(proxy), identity.mac, identity.ip, gdp_name, routes.rib().ip)
+                                                    } else {
+                                                        unreachable!();
+                                                    }
+                                                })
+                                                // released once the RIB answers, or NACKed if it does not in time
+                                                .hold_misses(nic_name, identity.ip, is_settled, |packet| bounce_gdp(packet, NackReason::NoRoute), store, flags)
+                                                // evicted to keep the held packets within pending.toml
+                                                .map(|packet| bounce_gdp(packet, NackReason::QueueFull))
+                                            },
                                         }
-                                    })
+                                    )
                                 },
                             }
                         )
@@ -198,49 +624,267 @@ pub fn switch_pipeline(
                     false => |group| {
                         group
                         .inject(move |packet| {
-                            let src_ip = packet.envelope().envelope().envelope().dst();
-                            let src_mac = packet.envelope().envelope().envelope().envelope().dst();
                             let mut unknown_names = Vec::new();
                             check_packet_certificates(gdp_name, packet, &store, Some(&mut unknown_names), nic_name, debug,);
                             if debug {
-                                println!("{} querying RIB for metas {:?}", nic_name, packet.dst());
+                                println!("{} querying RIB for metas {:?} (packet {:016x} NACKed)", nic_name, packet.dst(), packet.trace_id());
                             }
-                            create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&unknown_names), src_mac, src_ip, gdp_name, rib_ip)
+                            create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&unknown_names), identity.mac, identity.ip, gdp_name, routes.rib().ip)
                         })
-                        .map(bounce_gdp)
+                        .map(|packet| bounce_gdp(packet, NackReason::BadCert))
                     },
                 }
             )
         },
+        GdpAction::Fin => |group| {
+            group
+                .filter(move |packet| {
+                    flags.run(Flag::CertVerify, || check_packet_certificates(gdp_name, packet, &store, None, nic_name, debug)).unwrap_or(true)
+                })
+                .filter_map(move |mut packet| {
+                    let (src, dst) = (packet.src(), packet.dst());
+                    // routed before the flow is forgotten, as the flow may be the way back to its initiator
+                    let route = find_route(src, dst, store, flags);
+                    let kind = FinPayload::parse(get_payload(&packet)?).kind;
+                    let known = end_flow(src, dst, store);
+                    admission.forget((src, dst));
+                    match kind {
+                        FinKind::Close => fin_counters.fin.increment(),
+                        FinKind::Reset => {
+                            fin_counters.reset.increment();
+                            discard_held(identity.ip, (src, dst));
+                        }
+                    }
+                    if debug {
+                        println!("{} flow {:02x?} -> {:02x?} ended ({:?}, tracked: {})", nic_name, &src[..4], &dst[..4], kind, known);
+                    }
+                    let ip = match route {
+                        DestResult::Hit(ip, _) => ip,
+                        DestResult::Flow(flow) => {
+                            packet.envelope_mut().envelope_mut().set_dst_port(flow.src_port);
+                            flow.src_ip
+                        }
+                        // the flow is over, so there is no point asking the RIB for a route
+                        DestResult::Miss(_) => return Ok(Either::Drop(packet.reset())),
+                    };
+                    if chaos.is_failed_next_hop(ip, store) {
+                        return Ok(Either::Drop(packet.reset()));
+                    }
+                    // the next switch checks the Fin's chain as it would the flow's data
+                    flags.run(Flag::ForwardingCerts, || add_forwarding_cert(&mut packet, store, meta, private_key)).unwrap_or(Ok(()))?;
+                    forward_gdp(packet, ip, identity, gdp_name)
+                })
+        },
         GdpAction::RibReply => |group| {
             group
-                .for_each(move |packet| handle_rib_reply(packet, store, debug)) // consume data
+                .for_each(verify_content_or_report)
+                .for_each(move |packet| {
+                    // consume data, and tell our neighbors of the routes it withdrew
+                    let withdrawn = handle_rib_reply(packet, store, debug)?;
+                    gossip.learned(gdp_name, &withdrawn);
+                    Ok(())
+                })
                 .filter(move |packet| packet.dst() != gdp_name) // packets intended for a client can continue
                 .filter_map(move |packet| {
                     // TODO(rahularya) - look up route using RibQuery::next_hop_for if the route is not found
-                    if let DestResult::Hit(dest) = find_destination(packet.dst(), store) {
-                        forward_gdp(packet, dest)
+                    if let DestResult::Hit(dest, _) = find_destination(packet.dst(), store) {
+                        if chaos.is_failed_next_hop(dest, store) {
+                            return Ok(Either::Drop(packet.reset()));
+                        }
+                        forward_gdp(packet, dest, identity, gdp_name)
                     } else {
-                        bail!("unable to forward RIB reply to client")
+                        bail!("unable to forward RIB reply {:016x} to client", packet.trace_id())
                     }
                 })
         },
         GdpAction::Nack => |group| {
+            group
+                .for_each(move |packet| gossip.handle_nack(packet, gdp_name, store, debug))
+                .filter_map(move |packet| {
+                    let route = store.nack_reply_cache.get(&packet.src());
+                    match route {
+                        Some(FwdTableEntry { val: ip, .. }) if chaos.is_failed_next_hop(ip, store) => {
+                            Ok(Either::Drop(packet.reset()))
+                        }
+                        Some(FwdTableEntry { val: ip, .. }) => forward_gdp(packet, ip, identity, gdp_name),
+                        None => Ok(Either::Drop(packet.reset())),
+                    }
+                })
+        },
+        GdpAction::Heartbeat => |group| {
+            group
+                .filter_map(move |packet| {
+                    if handle_heartbeat(&packet, store, debug)? {
+                        Ok(Either::Keep(packet))
+                    } else {
+                        Ok(Either::Drop(packet.reset()))
+                    }
+                })
+                .replace(move |packet| heartbeat_reply(packet, flags))
+        },
+        GdpAction::Invalidate => |group| {
+            group
+                // only neighbors are heard, or anyone could have us ask the RIB about made-up names
+                .filter(move |packet| gossip.from_neighbor(packet, store))
+                .group_by(
+                    // Invalidates are signed by their sender, so its metadata must be known
+                    move |packet| store.gdp_metadata.get(&packet.src()).is_some(),
+                    pipeline! {
+                        true => |group| {
+                            group
+                                .for_each(move |packet| gossip.handle_invalidate(packet, store, debug))
+                                .filter(|_| false)
+                        },
+                        false => |group| {
+                            group
+                            .filter(move |packet| gossip.should_ask_meta(packet.src()))
+                            .inject(move |packet| {
+                                create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&[packet.src()]), identity.mac, identity.ip, gdp_name, routes.rib().ip)
+                            })
+                            .filter(|_| false)
+                        },
+                    }
+                )
+        },
+        GdpAction::Echo => |group| {
+            group.replace(move |packet| {
+                let reachable = packet.dst() == gdp_name
+                    || matches!(find_destination(packet.dst(), store), DestResult::Hit(..));
+                answer_echo(packet, reachable)
+            })
+        },
+        GdpAction::EchoReply => |group| {
+            group
+                .for_each(move |packet| {
+                    let answer = read_echo_answer(packet)?;
+                    admission.handle_answer(answer);
+                    prober.handle_answer(answer)
+                })
+                .filter(|_| false)
+        },
+        GdpAction::TimeReply => |group| {
+            group
+                .for_each(move |packet| handle_time_reply(packet, clock))
+                .filter(|_| false)
+        },
+        GdpAction::Ping => |group| {
+            group.group_by(
+                // answered only for senders whose certificates delegate them to us, so that pings
+                // under a forged source cannot make us reflect them
+                move |packet| {
+                    packet.dst() != gdp_name
+                        || flags.run(Flag::CertVerify, || check_packet_certificates(gdp_name, packet, &store, None, nic_name, debug)).unwrap_or(true)
+                },
+                pipeline! {
+                    true => |group| {
+                        group.filter_map(move |packet| {
+                            if packet.dst() == gdp_name {
+                                return Ok(Either::Keep(answer_ping(&packet)?));
+                            }
+                            match find_destination(packet.dst(), store) {
+                                DestResult::Hit(dest, _) if !chaos.is_failed_next_hop(dest, store) => {
+                                    forward_gdp(packet, dest, identity, gdp_name)
+                                }
+                                _ => Ok(Either::Drop(packet.reset())),
+                            }
+                        })
+                    },
+                    false => |group| {
+                        group
+                        .inject(move |packet| {
+                            let mut unknown_names = Vec::new();
+                            check_packet_certificates(gdp_name, packet, &store, Some(&mut unknown_names), nic_name, debug);
+                            create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&unknown_names), identity.mac, identity.ip, gdp_name, routes.rib().ip)
+                        })
+                        .filter(|_| false)
+                    },
+                }
+            )
+        },
+        GdpAction::Pong => |group| {
             group.filter_map(move |packet| {
-                let route = store.nack_reply_cache.get(&packet.src());
-                match route {
-                    Some(FwdTableEntry { val: ip, .. }) => forward_gdp(packet, ip),
-                    None => Ok(Either::Drop(packet.reset())),
+                match find_destination(packet.dst(), store) {
+                    DestResult::Hit(dest, _) if !chaos.is_failed_next_hop(dest, store) => {
+                        forward_gdp(packet, dest, identity, gdp_name)
+                    }
+                    _ => Ok(Either::Drop(packet.reset())),
                 }
             })
         },
-        GdpAction::RibGet => |group| {
+        GdpAction::Get => |group| {
+            group.group_by(
+                // our status only goes to senders whose certificates delegate them to us, as pongs do
+                move |packet| {
+                    status.object_of(&packet.dst()).is_none()
+                        || flags.run(Flag::CertVerify, || check_packet_certificates(gdp_name, packet, &store, None, nic_name, debug)).unwrap_or(true)
+                },
+                pipeline! {
+                    true => |group| {
+                        group.filter_map(move |packet| {
+                            if let Some(object) = status.object_of(&packet.dst()) {
+                                // the reply goes back over the port the Get came in by, to its sender
+                                let neighbor = Neighbor {
+                                    vlan: packet.rx_meta().and_then(|rx_meta| rx_meta.vlan),
+                                    ip: packet.envelope().envelope().envelope().src(),
+                                };
+                                let mtu = peer_max_mtu(store, neighbor).map_or(port_mtu, |max_mtu| max_mtu.min(port_mtu));
+                                return Ok(Either::Keep(status.answer(&packet, object, mtu)?));
+                            }
+                            // Gets for anything but our status are routed like Pings
+                            match find_destination(packet.dst(), store) {
+                                DestResult::Hit(dest, _) if !chaos.is_failed_next_hop(dest, store) => {
+                                    forward_gdp(packet, dest, identity, gdp_name)
+                                }
+                                _ => Ok(Either::Drop(packet.reset())),
+                            }
+                        })
+                    },
+                    false => |group| {
+                        group
+                        .inject(move |packet| {
+                            let mut unknown_names = Vec::new();
+                            check_packet_certificates(gdp_name, packet, &store, Some(&mut unknown_names), nic_name, debug);
+                            create_rib_request(Mbuf::new()?, &RibQuery::metas_for(&unknown_names), identity.mac, identity.ip, gdp_name, routes.rib().ip)
+                        })
+                        .filter(|_| false)
+                    },
+                }
+            )
+        },
+        GdpAction::GetReply => |group| {
+            group.filter_map(move |packet| {
+                match find_destination(packet.dst(), store) {
+                    DestResult::Hit(dest, _) if !chaos.is_failed_next_hop(dest, store) => {
+                        forward_gdp(packet, dest, identity, gdp_name)
+                    }
+                    _ => Ok(Either::Drop(packet.reset())),
+                }
+            })
+        },
+        GdpAction::RibSearch => |group| {
+            group.filter_map(move |packet| forward_gdp(packet, routes.rib().ip, identity, gdp_name))
+        },
+        GdpAction::RibSearchReply => |group| {
             group
-                .map(move |mut packet| {
-                    intercept_rib_insertion(&mut packet, store, debug)?;
-                    Ok(packet)
+                .filter(move |packet| {
+                    // don't relay search results that the RIB did not sign
+                    verify_rib_search_reply(packet, &rib_meta).is_ok()
+                })
+                .filter_map(move |packet| {
+                    if let DestResult::Hit(dest, _) = find_destination(packet.dst(), store) {
+                        forward_gdp(packet, dest, identity, gdp_name)
+                    } else {
+                        bail!("unable to forward RIB search results to client")
+                    }
                 })
-                .filter_map(move |packet| forward_gdp(packet, rib_ip))
+        },
+        GdpAction::RibGet => |group| {
+            group.filter_map(move |packet| forward_gdp(packet, routes.rib().ip, identity, gdp_name))
+        },
+        GdpAction::RibRegister => |group| {
+            group
+                .for_each(move |packet| intercept_registration(packet, store, registration_policy, debug))
+                .filter_map(move |packet| forward_gdp(packet, routes.rib().ip, identity, gdp_name))
         },
         _ => |group| {group.filter(|_| false)}
     }
@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use capsule::metrics;
+use capsule::packets::Packet;
+use gdp_proto::GdpName;
+use metrics_runtime::data::Counter;
+use serde::{Deserialize, Serialize};
+
+use crate::gdp::Gdp;
+
+/// Switches stop appending hops once this many have been recorded, to bound the packet growth
+const MAX_TELEMETRY_HOPS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TelemetryHop {
+    pub switch: GdpName,
+    /// Packets in the RX burst that this packet arrived in
+    pub queue_depth: u16,
+    /// Time between the packet being pulled from the RX queue and being forwarded
+    pub delta_us: u32,
+}
+
+/// Append our hop to the packet's in-band telemetry (starting the section if needed)
+pub fn record_hop<T: Packet>(packet: &mut Gdp<T>, gdp_name: GdpName) -> Result<()> {
+    let mut hops = packet.get_telemetry()?.unwrap_or_default();
+    if hops.len() >= MAX_TELEMETRY_HOPS {
+        return Ok(());
+    }
+    hops.push(TelemetryHop {
+        switch: gdp_name,
+        queue_depth: packet.burst_len().unwrap_or(0),
+        delta_us: packet
+            .rx_timestamp()
+            .map_or(0, |rx_timestamp| rx_timestamp.elapsed().as_micros() as u32),
+    });
+    packet.set_telemetry(&hops)
+}
+
+struct HopCounters {
+    packets: Counter,
+    queue_depth: Counter,
+    delta_us: Counter,
+}
+
+/// Publishes the telemetry collected along a path once the packet reaches its endpoint.
+/// Counters are per switch, so average delta and depth are `int.delta_us / int.packets`, etc.
+pub struct TelemetryExport {
+    nic_name: &'static str,
+    debug: bool,
+    hops: RefCell<HashMap<GdpName, HopCounters>>,
+}
+
+impl TelemetryExport {
+    pub fn new(nic_name: &'static str, debug: bool) -> &'static Self {
+        Box::leak(Box::new(TelemetryExport {
+            nic_name,
+            debug,
+            hops: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    /// Strip the telemetry from a packet about to leave the GDP network, recording it
+    pub fn export<T: Packet>(&self, packet: &mut Gdp<T>) -> Result<()> {
+        if let Some(hops) = packet.take_telemetry()? {
+            self.record(packet, hops);
+        }
+        Ok(())
+    }
+
+    /// Record the telemetry of a packet, leaving it in place
+    pub fn observe<T: Packet>(&self, packet: &Gdp<T>) -> Result<()> {
+        if let Some(hops) = packet.get_telemetry()? {
+            self.record(packet, hops);
+        }
+        Ok(())
+    }
+
+    fn record<T: Packet>(&self, packet: &Gdp<T>, hops: Vec<TelemetryHop>) {
+        if self.debug {
+            println!(
+                "{} packet {:016x} took path {:?}",
+                self.nic_name,
+                packet.trace_id(),
+                hops
+            );
+        }
+        let mut counters = self.hops.borrow_mut();
+        for hop in hops {
+            let counters = counters.entry(hop.switch).or_insert_with(|| {
+                let mut sink = metrics::global().sink();
+                // the first bytes of the name are enough to tell our switches apart
+                let switch = hop.switch[..4]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                let mut counter = |name: &'static str| {
+                    sink.counter_with_labels(
+                        name,
+                        vec![
+                            ("nic", self.nic_name.to_string()),
+                            ("switch", switch.clone()),
+                        ],
+                    )
+                };
+                HopCounters {
+                    packets: counter("int.packets"),
+                    queue_depth: counter("int.queue_depth"),
+                    delta_us: counter("int.delta_us"),
+                }
+            });
+            counters.packets.increment();
+            counters.queue_depth.record(hop.queue_depth as u64);
+            counters.delta_us.record(hop.delta_us as u64);
+        }
+    }
+}
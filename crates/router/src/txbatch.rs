@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{fs, io, mem};
+
+use anyhow::Result;
+use capsule::batch::{Batch, Disposition, PacketTx, Pipeline};
+use capsule::packets::Packet;
+use capsule::{metrics, Mbuf};
+use metrics_runtime::data::{Counter, Gauge};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::priority::PriorityConfig;
+
+/// Fits heartbeats, NACKs and small RIB queries, but not typical data packets
+const DEFAULT_LOW_LATENCY_MAX_LEN: usize = 256;
+
+/// Which packets skip coalescing, for flows that send too little to ever fill a burst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LowLatency {
+    Off,
+    /// Packets marked as control traffic
+    Control,
+    All,
+}
+
+impl Default for LowLatency {
+    fn default() -> Self {
+        LowLatency::Off
+    }
+}
+
+/// Which packets leave with a UDP checksum, which capsule computes in software over the whole
+/// datagram. This stands in for NIC TX checksum offload, which capsule 0.1 cannot enable: it
+/// configures ports without offloads and gives no access to an mbuf's offload flags. So rather
+/// than hand the sum to the NIC, the router skips it where the dTLS tag makes it redundant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpChecksums {
+    Always,
+    /// Only those sent in the clear. The dTLS tag already covers the payload of the others,
+    /// which leave without one, as IPv4 allows. IPv6 ports, where UDP must have one, still
+    /// compute it (see ipv6.rs)
+    Plaintext,
+}
+
+impl Default for UdpChecksums {
+    fn default() -> Self {
+        UdpChecksums::Always
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct TxConfig {
+    /// Transmit as soon as this many packets are buffered
+    pub max_batch: usize,
+    /// Transmit a partial batch once its oldest packet has waited this long
+    pub max_delay_us: u64,
+    /// Packets of this class are transmitted as soon as they come out of the pipeline, along
+    /// with whatever was buffered before them
+    pub low_latency: LowLatency,
+    /// Only frames up to this many bytes take the low-latency path
+    pub low_latency_max_len: usize,
+}
+
+impl Default for TxConfig {
+    fn default() -> Self {
+        TxConfig {
+            max_batch: 32,
+            max_delay_us: 50,
+            low_latency: LowLatency::Off,
+            low_latency_max_len: DEFAULT_LOW_LATENCY_MAX_LEN,
+        }
+    }
+}
+
+fn default_low_latency_max_len() -> usize {
+    DEFAULT_LOW_LATENCY_MAX_LEN
+}
+
+#[derive(Deserialize)]
+struct SerializedTxConfig {
+    max_batch: usize,
+    max_delay_us: u64,
+    #[serde(default)]
+    low_latency: LowLatency,
+    #[serde(default = "default_low_latency_max_len")]
+    low_latency_max_len: usize,
+    /// Pipelines (by the name of their NIC) whose low-latency class differs from `low_latency`
+    #[serde(default)]
+    pipelines: HashMap<String, LowLatency>,
+    #[serde(default)]
+    udp_checksums: UdpChecksums,
+}
+
+fn read_tx_config() -> Result<SerializedTxConfig> {
+    let content = fs::read_to_string("tx.toml")?;
+    Ok(toml::from_str(&content)?)
+}
+
+// read on first use, shared by every core and crypto worker, as packets are encrypted apart from
+// the pipeline of the port they leave by
+static UDP_CHECKSUMS: Lazy<UdpChecksums> = Lazy::new(|| match read_tx_config() {
+    Ok(config) => config.udp_checksums,
+    Err(err) => {
+        let missing = err
+            .downcast_ref::<io::Error>()
+            .map_or(false, |err| err.kind() == io::ErrorKind::NotFound);
+        if !missing {
+            println!(
+                "tx.toml: {:#}; sending every packet with a UDP checksum",
+                err
+            );
+        }
+        UdpChecksums::default()
+    }
+});
+
+/// Which packets leave with a UDP checksum, from tx.toml
+pub fn udp_checksums() -> UdpChecksums {
+    *UDP_CHECKSUMS
+}
+
+/// The TX settings of the pipeline for `nic_name`
+pub fn load_tx_config(nic_name: &str) -> Result<TxConfig> {
+    let config = read_tx_config()?;
+    Ok(TxConfig {
+        max_batch: config.max_batch,
+        max_delay_us: config.max_delay_us,
+        low_latency: config
+            .pipelines
+            .get(nic_name)
+            .copied()
+            .unwrap_or(config.low_latency),
+        low_latency_max_len: config.low_latency_max_len,
+    })
+}
+
+struct TxStats {
+    flushes: Counter,
+    packets: Counter,
+    control_packets: Counter,
+    /// Packets that flushed their burst as soon as they were buffered
+    low_latency_packets: Counter,
+    batch_size: Gauge,
+}
+
+/// Like `Batch::send`, but coalesces the output of several batches into a single TX burst.
+/// Control packets go first in each burst, and a share of it is kept free for them.
+pub struct BatchedSend<B: Batch, Tx: PacketTx> {
+    name: &'static str,
+    batch: B,
+    tx: Tx,
+    config: TxConfig,
+    is_control: fn(&B::Item) -> bool,
+    data_slots: usize,
+    control: Vec<Mbuf>,
+    data: Vec<Mbuf>,
+    oldest: Option<Instant>,
+    stats: TxStats,
+}
+
+impl<B: Batch, Tx: PacketTx> BatchedSend<B, Tx> {
+    pub fn new(
+        batch: B,
+        tx: Tx,
+        name: &'static str,
+        config: TxConfig,
+        priority: PriorityConfig,
+        is_control: fn(&B::Item) -> bool,
+    ) -> Self {
+        let mut sink = metrics::global().sink();
+        let stats = TxStats {
+            flushes: sink.counter_with_labels("tx.flushes", vec![("pipeline", name)]),
+            packets: sink.counter_with_labels("tx.packets", vec![("pipeline", name)]),
+            control_packets: sink
+                .counter_with_labels("tx.control_packets", vec![("pipeline", name)]),
+            low_latency_packets: sink
+                .counter_with_labels("tx.low_latency_packets", vec![("pipeline", name)]),
+            batch_size: sink.gauge_with_labels("tx.batch_size", vec![("pipeline", name)]),
+        };
+        BatchedSend {
+            name,
+            batch,
+            tx,
+            config,
+            is_control,
+            data_slots: priority.data_slots(config.max_batch),
+            control: Vec::with_capacity(config.max_batch),
+            data: Vec::with_capacity(config.max_batch),
+            oldest: None,
+            stats,
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.control.is_empty() && self.data.is_empty() {
+            return;
+        }
+        // if the TX queue cannot take the whole burst, its tail is dropped, so data goes last
+        let mut packets =
+            mem::replace(&mut self.control, Vec::with_capacity(self.config.max_batch));
+        self.stats.control_packets.record(packets.len() as u64);
+        packets.append(&mut self.data);
+        self.stats.flushes.increment();
+        self.stats.packets.record(packets.len() as u64);
+        self.stats.batch_size.record(packets.len() as i64);
+        // the whole burst goes out through a single tx call on the queue
+        self.tx.transmit(packets);
+        self.oldest = None;
+    }
+
+    fn is_full(&self) -> bool {
+        self.control.len() + self.data.len() >= self.config.max_batch
+            || self.data.len() >= self.data_slots
+    }
+
+    fn is_low_latency(&self, packet: &B::Item, is_control: bool) -> bool {
+        let in_class = match self.config.low_latency {
+            LowLatency::Off => false,
+            LowLatency::Control => is_control,
+            LowLatency::All => true,
+        };
+        in_class && packet.mbuf().data_len() <= self.config.low_latency_max_len
+    }
+
+    fn is_due(&self) -> bool {
+        self.is_full()
+            || self.oldest.map_or(false, |oldest| {
+                oldest.elapsed() >= Duration::from_micros(self.config.max_delay_us)
+            })
+    }
+}
+
+impl<B: Batch + Unpin, Tx: PacketTx + Unpin> Pipeline for BatchedSend<B, Tx> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run_once(&mut self) {
+        self.batch.replenish();
+        while let Some(disp) = self.batch.next() {
+            match disp {
+                Disposition::Act(packet) => {
+                    if self.oldest.is_none() {
+                        self.oldest = Some(Instant::now());
+                    }
+                    let is_control = (self.is_control)(&packet);
+                    let low_latency = self.is_low_latency(&packet, is_control);
+                    if is_control {
+                        self.control.push(packet.reset());
+                    } else {
+                        self.data.push(packet.reset());
+                    }
+                    // what was buffered goes out with it, so that no flow is reordered
+                    if low_latency {
+                        self.stats.low_latency_packets.increment();
+                        self.flush();
+                    } else if self.is_full() {
+                        self.flush();
+                    }
+                }
+                Disposition::Drop(mbuf) => drop(mbuf),
+                Disposition::Emit | Disposition::Abort(_) => {}
+            }
+        }
+        if self.is_due() {
+            self.flush();
+        }
+    }
+}
+
+impl<B: Batch + Unpin, Tx: PacketTx + Unpin> Future for BatchedSend<B, Tx> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().run_once();
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub trait SendBatched: Batch + Sized {
+    fn send_batched<Tx: PacketTx>(
+        self,
+        tx: Tx,
+        name: &'static str,
+        config: TxConfig,
+        priority: PriorityConfig,
+        is_control: fn(&Self::Item) -> bool,
+    ) -> BatchedSend<Self, Tx> {
+        BatchedSend::new(self, tx, name, config, priority, is_control)
+    }
+}
+
+impl<T: Batch> SendBatched for T {}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use capsule::batch::Either;
+use capsule::metrics;
+use capsule::packets::ip::v4::Ipv4;
+use capsule::packets::Packet;
+use gdp_proto::{GdpAction, PuntedPacket};
+use metrics_runtime::data::Counter;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::dtls::DTls;
+use crate::gdp::Gdp;
+use crate::isolation::Recover;
+use crate::packet_ops::get_payload;
+
+/// Punted packets are kept until collected over the control socket, up to this many
+const MAX_PUNTED: usize = 128;
+/// Only the start of a punted payload is kept
+const MAX_PUNTED_PAYLOAD: usize = 2048;
+
+/// What to do with packets whose action byte this version does not understand
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownActionPolicy {
+    /// Treat the packet as opaque and route it like a Forward, leaving the action byte unchanged
+    Forward,
+    Drop,
+    /// Hand a copy to the control plane, then drop it
+    Punt,
+}
+
+impl Default for UnknownActionPolicy {
+    fn default() -> Self {
+        UnknownActionPolicy::Drop
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct SerializedActions {
+    #[serde(default)]
+    unknown: UnknownActionPolicy,
+}
+
+pub fn load_unknown_action_policy() -> Result<UnknownActionPolicy> {
+    let content = fs::read_to_string("actions.toml")?;
+    let actions: SerializedActions = toml::from_str(&content)?;
+    Ok(actions.unknown)
+}
+
+pub struct PuntQueue {
+    packets: Mutex<VecDeque<PuntedPacket>>,
+    dropped: AtomicU64,
+}
+
+// created on first use, shared by every pipeline and the control socket
+static PUNT_QUEUE: Lazy<PuntQueue> = Lazy::new(|| PuntQueue {
+    packets: Mutex::new(VecDeque::new()),
+    dropped: AtomicU64::new(0),
+});
+
+pub fn punt_queue() -> &'static PuntQueue {
+    &PUNT_QUEUE
+}
+
+impl PuntQueue {
+    fn push(&self, packet: PuntedPacket) {
+        let mut packets = self.packets.lock().recover();
+        if packets.len() >= MAX_PUNTED {
+            packets.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        packets.push_back(packet);
+    }
+
+    /// Returns up to `max` of the oldest punted packets, and how many were discarded since last time
+    pub fn take(&self, max: usize) -> (Vec<PuntedPacket>, u64) {
+        let mut packets = self.packets.lock().recover();
+        let count = packets.len().min(max);
+        let taken = packets.drain(..count).collect();
+        (taken, self.dropped.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Applies the unknown action policy at the front of a GDP pipeline
+#[derive(Clone, Copy)]
+pub struct UnknownActions {
+    policy: UnknownActionPolicy,
+    counter: &'static Counter,
+}
+
+impl UnknownActions {
+    pub fn new(policy: UnknownActionPolicy, nic_name: &'static str) -> Self {
+        let policy_name = match policy {
+            UnknownActionPolicy::Forward => "forward",
+            UnknownActionPolicy::Drop => "drop",
+            UnknownActionPolicy::Punt => "punt",
+        };
+        let counter = metrics::global().sink().counter_with_labels(
+            "unknown_action",
+            vec![("nic", nic_name), ("policy", policy_name)],
+        );
+        UnknownActions {
+            policy,
+            counter: Box::leak(Box::new(counter)),
+        }
+    }
+
+    pub fn filter(&self, packet: Gdp<DTls<Ipv4>>) -> Result<Either<Gdp<DTls<Ipv4>>>> {
+        if packet.action().is_ok() {
+            return Ok(Either::Keep(packet));
+        }
+        self.counter.increment();
+        match self.policy {
+            UnknownActionPolicy::Forward => Ok(Either::Keep(packet)),
+            UnknownActionPolicy::Drop => Ok(Either::Drop(packet.reset())),
+            UnknownActionPolicy::Punt => {
+                let payload = get_payload(&packet)?;
+                punt_queue().push(PuntedPacket {
+                    action: packet.raw_action(),
+                    src: packet.src(),
+                    dst: packet.dst(),
+                    payload: payload[..payload.len().min(MAX_PUNTED_PAYLOAD)].to_vec(),
+                });
+                Ok(Either::Drop(packet.reset()))
+            }
+        }
+    }
+
+    /// The group that a packet with `action` is handled by, where None is an unknown action that
+    /// was let through
+    pub fn action_of(&self, action: Option<GdpAction>) -> GdpAction {
+        action.unwrap_or(match self.policy {
+            UnknownActionPolicy::Forward => GdpAction::Forward,
+            _ => GdpAction::Noop,
+        })
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use gdp_proto::{GdpName, SignedUsageReport, TenantUsage, UsageReport};
+
+use crate::certificates::sign_data;
+use crate::isolation::Recover;
+
+/*
+   Tenants are billed for the traffic that switches forward for them, so each switch accounts
+   for it in a form that neither side can quietly change:
+   - every packet forwarded is counted against its tenant, the name it was sent from
+   - every REPORT_INTERVAL, the counts are drained into a report, numbered consecutively and
+     signed with the switch's node key, so a missing report shows up as a gap in the sequence
+   - the last MAX_KEPT_REPORTS reports are kept for tenants and operators to fetch over the
+     control socket and check against the switch's public key
+*/
+
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// Reports older than this many periods are only held by whoever fetched them
+const MAX_KEPT_REPORTS: usize = 64;
+/// Keeps a reply to one control command within a single datagram
+const MAX_REPORTS_PER_REPLY: usize = 8;
+
+type TenantCounts = Mutex<HashMap<GdpName, TenantUsage>>;
+
+struct UsageState {
+    gdp_name: GdpName,
+    private_key: [u8; 32],
+    /// The counts of each pipeline, which only contend with each other when a report is made
+    cores: Mutex<Vec<&'static TenantCounts>>,
+    period_start: Mutex<u64>,
+    next_sequence: Mutex<u64>,
+    reports: Mutex<VecDeque<SignedUsageReport>>,
+}
+
+/// Accounts for the traffic that a switch forwards for each tenant. Shared by all cores.
+#[derive(Clone, Copy)]
+pub struct UsageMeter(&'static UsageState);
+
+/// Counts the traffic of one pipeline
+#[derive(Clone, Copy)]
+pub struct UsageRecorder(&'static TenantCounts);
+
+impl UsageMeter {
+    pub fn new(gdp_name: GdpName, private_key: [u8; 32]) -> Result<Self> {
+        Ok(UsageMeter(Box::leak(Box::new(UsageState {
+            gdp_name,
+            private_key,
+            cores: Mutex::new(Vec::new()),
+            period_start: Mutex::new(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+            next_sequence: Mutex::new(0),
+            reports: Mutex::new(VecDeque::new()),
+        }))))
+    }
+
+    /// A recorder for a new pipeline, whose counts go into every report from now on
+    pub fn recorder(&self) -> UsageRecorder {
+        let counts: &'static TenantCounts = Box::leak(Box::default());
+        self.0.cores.lock().recover().push(counts);
+        UsageRecorder(counts)
+    }
+
+    /// Sign the usage since the last report, and keep it for tenants to fetch
+    pub fn report(&self) -> Result<()> {
+        let mut tenants: HashMap<GdpName, TenantUsage> = HashMap::new();
+        for core in self.0.cores.lock().recover().iter() {
+            for (tenant, usage) in core.lock().recover().drain() {
+                let total = tenants.entry(tenant).or_insert(TenantUsage {
+                    tenant,
+                    packets: 0,
+                    bytes: 0,
+                });
+                total.packets += usage.packets;
+                total.bytes += usage.bytes;
+            }
+        }
+        let period_end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let period_start =
+            std::mem::replace(&mut *self.0.period_start.lock().recover(), period_end);
+        let mut next_sequence = self.0.next_sequence.lock().recover();
+        let mut tenants = tenants.into_values().collect::<Vec<_>>();
+        tenants.sort_by_key(|usage| usage.tenant);
+        // periods without traffic are reported too, so their sequence numbers are not gaps
+        let report = UsageReport {
+            switch: self.0.gdp_name,
+            sequence: *next_sequence,
+            period_start,
+            period_end,
+            tenants,
+        };
+        let signature = sign_data(&report, self.0.private_key)?;
+        let signature: [u8; 64] = signature.into();
+        let mut halves = ([0; 32], [0; 32]);
+        halves.0.copy_from_slice(&signature[..32]);
+        halves.1.copy_from_slice(&signature[32..]);
+        *next_sequence += 1;
+
+        let mut reports = self.0.reports.lock().recover();
+        reports.push_back(SignedUsageReport {
+            report,
+            signature: halves,
+        });
+        while reports.len() > MAX_KEPT_REPORTS {
+            reports.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Kept reports numbered after `after_sequence`, oldest first, and whether more are left
+    pub fn reports_after(&self, after_sequence: Option<u64>) -> (Vec<SignedUsageReport>, bool) {
+        let reports = self.0.reports.lock().recover();
+        let mut after = reports
+            .iter()
+            .filter(|signed| after_sequence.map_or(true, |seq| signed.report.sequence > seq))
+            .cloned()
+            .collect::<Vec<_>>();
+        let more = after.len() > MAX_REPORTS_PER_REPLY;
+        after.truncate(MAX_REPORTS_PER_REPLY);
+        (after, more)
+    }
+}
+
+impl UsageRecorder {
+    /// `tenant` had a packet of `bytes` forwarded
+    pub fn record(&self, tenant: GdpName, bytes: usize) {
+        let mut counts = self.0.lock().recover();
+        let usage = counts.entry(tenant).or_insert(TenantUsage {
+            tenant,
+            packets: 0,
+            bytes: 0,
+        });
+        usage.packets += 1;
+        usage.bytes += bytes as u64;
+    }
+}
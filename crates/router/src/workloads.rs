@@ -9,19 +9,21 @@ use capsule::net::MacAddr;
 use capsule::packets::ip::v4::Ipv4;
 use capsule::packets::{Ethernet, Packet, Udp};
 use capsule::{Mbuf, PortQueue};
-use gdp_client::{GdpAction, GdpName};
+use gdp_proto::{GdpAction, GdpName};
 use rand::Rng;
 use serde::Deserialize;
 use tokio_timer::delay_for;
 
-use crate::certificates::{CertDest, Certificate, RtCert};
+use crate::certificates::{AttrCert, CertDest, Certificate, NewAttrCert, NewRtCert, RtCert};
 use crate::dtls::{encrypt_gdp, DTls};
 use crate::gdp::{CertificateBlock, Gdp};
 use crate::hardcoded_routes::{
     gdp_name_of_index, metadata_of_index, private_key_of_index, WithBroadcast,
 };
-use crate::rib::send_rib_query;
-use crate::ribpayload::RibQuery;
+use crate::identity::PortIdentity;
+use crate::isolation::Recover;
+use crate::preflight::{preflight, Requirements, RibUse};
+use crate::registration::register_node;
 use crate::runtime::build_runtime;
 use crate::schedule::Schedule;
 use crate::statistics::make_print_stats;
@@ -93,6 +95,7 @@ fn prep_packet(
     reply
         .mbuf_mut()
         .write_data_slice(offset, &message[..payload_size])?;
+    reply.seal_content()?;
 
     reply.set_certs(&CertificateBlock { certificates })?;
 
@@ -149,22 +152,20 @@ pub fn dev_schedule(q: PortQueue, name: &str) -> impl Pipeline + '_ {
     let switch_ip = Ipv4Addr::new(10, 100, 1, 12);
     let meta = metadata_of_index(1);
     let private_key = private_key_of_index(1);
-    
-    send_rib_query(
+
+    register_node(
         q.clone(),
-        src_ip,
-        gdp_name_of_index(1),
-        switch_ip,
-        &RibQuery::announce_route(
+        PortIdentity::of_port(&q, src_ip),
+        meta,
+        vec![RtCert::new_wrapped(
             meta,
-            RtCert::new_wrapped(
-                meta,
-                private_key,
-                CertDest::GdpName(gdp_name_of_index(2)),
-                true,
-            )
-            .unwrap(),
-        ),
+            private_key,
+            CertDest::GdpName(gdp_name_of_index(2)),
+            true,
+        )
+        .unwrap()],
+        private_key,
+        switch_ip,
         "client",
     );
 
@@ -243,41 +244,59 @@ fn flood_single(
     })
 }
 
+/// Register the test client (GDP index 1) with the RIB, through its switch (GDP index 2)
+pub fn register_client(q: &PortQueue, node_addr: Ipv4Addr, switch_addr: Ipv4Addr) {
+    let meta = metadata_of_index(1);
+    let private_key = private_key_of_index(1);
+    register_node(
+        q.clone(),
+        PortIdentity::of_port(q, node_addr),
+        meta,
+        vec![
+            RtCert::new_wrapped(
+                meta,
+                private_key,
+                CertDest::GdpName(gdp_name_of_index(2)),
+                true,
+            )
+            .unwrap(),
+            // advertise ourselves for service discovery
+            AttrCert::new_wrapped(meta, private_key, vec!["client".to_owned()]).unwrap(),
+        ],
+        private_key,
+        switch_addr,
+        "client",
+    );
+}
+
 pub fn start_client_server(
     config: RuntimeConfig,
     node_addr: Ipv4Addr,
     switch_addr: Ipv4Addr,
     env: Env,
 ) -> Result<()> {
+    preflight(
+        &config,
+        env,
+        &Requirements {
+            ports: &["eth1"],
+            cores: &[0],
+            node_addr: Some(node_addr),
+            rib: RibUse::None,
+            workload: true,
+        },
+    )?;
     let (print_stats, history_map) = make_print_stats();
 
     build_runtime(config, env)?
         .add_pipeline_to_port("eth1", move |q| {
-            let meta = metadata_of_index(1);
-            let private_key = private_key_of_index(1);
-            send_rib_query(
-                q.clone(),
-                node_addr,
-                gdp_name_of_index(1),
-                switch_addr,
-                &RibQuery::announce_route(
-                    meta,
-                    RtCert::new_wrapped(
-                        meta,
-                        private_key,
-                        CertDest::GdpName(gdp_name_of_index(2)),
-                        true,
-                    )
-                    .unwrap(),
-                ),
-                "client",
-            );
+            register_client(&q, node_addr, switch_addr);
             client_schedule(q, "client", node_addr, switch_addr)
             // flood_single(q, "client", node_addr, switch_addr)
         })?
         .add_periodic_task_to_core(0, print_stats, Duration::from_secs(1))?
         .execute()?;
 
-    dump_history(&*history_map.lock().unwrap())?;
+    dump_history(&*history_map.lock().recover())?;
     Ok(())
 }
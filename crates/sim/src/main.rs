@@ -0,0 +1,27 @@
+use std::fs;
+
+use anyhow::Result;
+use gdp_router::{load_flags, load_secrets, set_key, start_dev_server};
+use tracing::Level;
+use tracing_subscriber::fmt;
+
+/// Runs the dev topology that `gdp --mode dev` used to: a RIB, a switch, a target switch and two
+/// clients, each on its own port of this host. Reads conf.toml and the other configuration files
+/// from the working directory.
+fn main() -> Result<()> {
+    let subscriber = fmt::Subscriber::builder()
+        .with_max_level(Level::WARN)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let content = fs::read_to_string("conf.toml")?;
+    let config = toml::from_str(&content)?;
+    let flags = load_flags()?;
+
+    // a broken secrets file is reported along with everything else before the ports are bound
+    if let Some(key) = load_secrets().ok().and_then(|secrets| secrets.dtls_key) {
+        set_key(key)?;
+    }
+
+    start_dev_server(config, flags)
+}
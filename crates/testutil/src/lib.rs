@@ -0,0 +1,35 @@
+//! Helpers shared by the tests of the GDP crates. Not for use outside of tests.
+
+use std::mem::{size_of, transmute};
+
+use gdp_proto::{GdpAction, GdpHeader, GdpName, MAGIC_NUMBERS, NAME_LEN};
+
+/// A distinct name for each `i`, recognizable in assertion failures
+pub fn name(i: u8) -> GdpName {
+    [i; NAME_LEN]
+}
+
+/// The header as it is laid out on the wire
+pub fn header_bytes(header: &GdpHeader) -> Vec<u8> {
+    let bytes: [u8; size_of::<GdpHeader>()] = unsafe { transmute(*header) };
+    bytes.to_vec()
+}
+
+/// A Forward to `name(7)` with a 5 byte payload, claiming a header of `header_len` bytes
+pub fn forward_header(header_len: u16) -> GdpHeader {
+    GdpHeader {
+        field: MAGIC_NUMBERS.into(),
+        header_len: header_len.into(),
+        action: GdpAction::Forward as u8,
+        dst: name(7),
+        data_len: 5.into(),
+        ..Default::default()
+    }
+}
+
+/// A packet of `header` followed by `rest` (header extensions, data, certificates)
+pub fn packet_bytes(header: &GdpHeader, rest: &[u8]) -> Vec<u8> {
+    let mut buf = header_bytes(header);
+    buf.extend(rest);
+    buf
+}